@@ -0,0 +1,235 @@
+//! `AcoustID` fingerprint lookup
+//!
+//! Fingerprints are computed by shelling out to chromaprint's `fpcalc`.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::process::Command;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::ratelimit::RateLimiter;
+use crate::{Error, Result};
+
+const API_BASE: &str = "https://api.acoustid.org/v2";
+const USER_AGENT: &str = "rsbts/0.1.0 (https://github.com/user/rsbts)";
+const RATE_LIMIT: Duration = Duration::from_millis(334);
+const FPCALC: &str = "fpcalc";
+
+pub struct Client {
+    http: reqwest::Client,
+    api_key: String,
+    limiter: RateLimiter,
+}
+
+/// Chromaprint fingerprint of an audio file.
+#[derive(Debug, Clone)]
+pub struct Fingerprint {
+    pub duration: u32,
+    pub fingerprint: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct LookupResponse {
+    status: String,
+    #[serde(default)]
+    results: Vec<LookupResult>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LookupResult {
+    pub id: String,
+    #[serde(default)]
+    pub score: f64,
+    #[serde(default)]
+    pub recordings: Vec<Recording>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Recording {
+    pub id: String,
+    #[serde(default)]
+    pub releases: Vec<ReleaseRef>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReleaseRef {
+    pub id: String,
+}
+
+impl Client {
+    /// Create a new `AcoustID` API client.
+    ///
+    /// # Errors
+    /// Returns an error if the HTTP client cannot be built (should never happen).
+    pub fn new(api_key: impl Into<String>) -> Result<Self> {
+        let http = reqwest::Client::builder()
+            .user_agent(USER_AGENT)
+            .timeout(Duration::from_secs(30))
+            .build()
+            .map_err(|e| Error::AcoustId(format!("Failed to create HTTP client: {e}")))?;
+
+        Ok(Self {
+            http,
+            api_key: api_key.into(),
+            limiter: RateLimiter::new(RATE_LIMIT),
+        })
+    }
+
+    /// Look up recordings matching a fingerprint.
+    ///
+    /// # Errors
+    /// Returns an error if the API request fails or reports an error status.
+    pub async fn lookup(&self, fp: &Fingerprint) -> Result<Vec<LookupResult>> {
+        self.limiter.acquire().await;
+
+        let url = format!(
+            "{API_BASE}/lookup?client={}&meta=recordings+releaseids&duration={}&fingerprint={}",
+            urlencoding::encode(&self.api_key),
+            fp.duration,
+            urlencoding::encode(&fp.fingerprint)
+        );
+
+        let response = self
+            .http
+            .get(&url)
+            .send()
+            .await
+            .map_err(|e| Error::AcoustId(e.to_string()))?;
+
+        if !response.status().is_success() {
+            return Err(Error::AcoustId(format!(
+                "API error: {}",
+                response.status()
+            )));
+        }
+
+        let result: LookupResponse = response
+            .json()
+            .await
+            .map_err(|e| Error::AcoustId(e.to_string()))?;
+
+        if result.status != "ok" {
+            return Err(Error::AcoustId(format!("API status: {}", result.status)));
+        }
+
+        Ok(result.results)
+    }
+
+    /// Fingerprint a file and return the release ID most often matched by
+    /// its recordings, weighted by `AcoustID` score.
+    ///
+    /// # Errors
+    /// Returns an error if fingerprinting or the lookup fails.
+    pub async fn identify_release(&self, path: &Path) -> Result<Option<String>> {
+        // fpcalc decodes the whole file, so keep it off the runtime's threads
+        let path = path.to_path_buf();
+        let fp = tokio::task::spawn_blocking(move || fingerprint(&path))
+            .await
+            .map_err(|e| Error::AcoustId(format!("Fingerprinting stopped: {e}")))??;
+        let results = self.lookup(&fp).await?;
+        Ok(best_release(&results))
+    }
+}
+
+/// Compute a chromaprint fingerprint by running `fpcalc`.
+///
+/// # Errors
+/// Returns an error if `fpcalc` is missing, fails, or produces unexpected output.
+pub fn fingerprint(path: &Path) -> Result<Fingerprint> {
+    let output = Command::new(FPCALC).arg(path).output().map_err(|e| {
+        if e.kind() == std::io::ErrorKind::NotFound {
+            Error::AcoustId(format!("{FPCALC} not found; install chromaprint"))
+        } else {
+            Error::AcoustId(format!("Failed to run {FPCALC}: {e}"))
+        }
+    })?;
+
+    if !output.status.success() {
+        return Err(Error::AcoustId(format!(
+            "{FPCALC} failed on {}: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    parse_fpcalc(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Parse `fpcalc`'s default `KEY=value` output.
+fn parse_fpcalc(output: &str) -> Result<Fingerprint> {
+    let mut duration = None;
+    let mut fingerprint = None;
+
+    for line in output.lines() {
+        match line.split_once('=') {
+            Some(("DURATION", value)) => {
+                duration = value
+                    .trim()
+                    .parse::<f64>()
+                    .ok()
+                    .map(|d| d.round().max(0.0) as u32);
+            }
+            Some(("FINGERPRINT", value)) => fingerprint = Some(value.trim().to_string()),
+            _ => {}
+        }
+    }
+
+    match (duration, fingerprint) {
+        (Some(duration), Some(fingerprint)) if !fingerprint.is_empty() => Ok(Fingerprint {
+            duration,
+            fingerprint,
+        }),
+        _ => Err(Error::AcoustId(format!("Unexpected {FPCALC} output"))),
+    }
+}
+
+fn best_release(results: &[LookupResult]) -> Option<String> {
+    let mut weights: HashMap<&str, f64> = HashMap::new();
+    for result in results {
+        for release in result.recordings.iter().flat_map(|r| &r.releases) {
+            *weights.entry(release.id.as_str()).or_default() += result.score;
+        }
+    }
+
+    weights
+        .into_iter()
+        .max_by(|(a_id, a), (b_id, b)| a.total_cmp(b).then_with(|| b_id.cmp(a_id)))
+        .map(|(id, _)| id.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(score: f64, releases: &[&str]) -> LookupResult {
+        LookupResult {
+            id: "track".into(),
+            score,
+            recordings: vec![Recording {
+                id: "recording".into(),
+                releases: releases
+                    .iter()
+                    .map(|id| ReleaseRef { id: (*id).into() })
+                    .collect(),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_parse_fpcalc() {
+        let fp = parse_fpcalc("FILE=a.flac\nDURATION=242.6\nFINGERPRINT=AQADtE\n").unwrap();
+        assert_eq!(fp.duration, 243);
+        assert_eq!(fp.fingerprint, "AQADtE");
+
+        assert!(parse_fpcalc("DURATION=10\n").is_err());
+    }
+
+    #[test]
+    fn test_best_release_weighs_scores() {
+        let results = vec![result(0.9, &["album", "compilation"]), result(0.5, &["album"])];
+        assert_eq!(best_release(&results).as_deref(), Some("album"));
+        assert_eq!(best_release(&[]), None);
+    }
+}
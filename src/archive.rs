@@ -0,0 +1,449 @@
+//! Verifiable library archives
+//!
+//! An archive is a gzip-compressed JSON-lines payload holding every import
+//! run, album, item and flexible attribute, one row per line in id order,
+//! plus a manifest with
+//! the payload's SHA-256, its row counts and the schema version it was
+//! written at. Archives are only ever added; the oldest are rotated out.
+//! Decompressed, two archives `diff` row by row.
+//!
+//! Restoring checks the manifest against the payload before anything is
+//! written, and refuses archives from another schema version.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::db::{Attribute, Database};
+use crate::runs::ImportRun;
+use crate::{Album, Error, Item, Result};
+
+/// Archives kept by default when a new one is written.
+pub const DEFAULT_KEEP: usize = 10;
+
+const PREFIX: &str = "rsbts-";
+const PAYLOAD_SUFFIX: &str = ".jsonl.gz";
+const MANIFEST_SUFFIX: &str = ".manifest.json";
+
+/// Describes an archive's payload.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    /// File name of the payload, in the manifest's directory.
+    pub payload: String,
+    /// Hex SHA-256 of the payload file.
+    pub sha256: String,
+    pub created: DateTime<Utc>,
+    pub runs: u64,
+    pub albums: u64,
+    pub items: u64,
+    /// Flexible attributes of items and albums; none in archives from
+    /// before they were archived.
+    #[serde(default)]
+    pub attributes: u64,
+    /// Database migration version the rows were read at.
+    pub schema_version: u32,
+    pub rsbts_version: String,
+}
+
+/// The rows of a verified archive.
+#[derive(Debug)]
+pub struct Archive {
+    pub manifest: Manifest,
+    pub runs: Vec<ImportRun>,
+    pub albums: Vec<Album>,
+    pub items: Vec<Item>,
+    pub attributes: Vec<Attribute>,
+}
+
+/// One payload line, tagged with its table.
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+enum RowRef<'a> {
+    Run(&'a ImportRun),
+    Album(&'a Album),
+    Item(&'a Item),
+    Attribute(&'a Attribute),
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Row {
+    Run(ImportRun),
+    Album(Album),
+    Item(Item),
+    Attribute(Attribute),
+}
+
+/// Write an archive of the whole library into `dir`, then delete all but
+/// the newest `keep` archives there, never the one just written. Returns
+/// the manifest's path.
+///
+/// # Errors
+/// Returns an error if the library can't be read, an archive with the same
+/// timestamp exists, or a file can't be written.
+pub fn write(db: &Database, dir: &Path, keep: usize) -> Result<(PathBuf, Manifest)> {
+    let mut runs: Vec<ImportRun> =
+        db.import_runs()?.into_iter().map(|summary| summary.run).collect();
+    runs.sort_by(|a, b| a.id.cmp(&b.id));
+    let mut albums = db.query_albums(None)?;
+    albums.sort_by_key(|album| album.id);
+    let mut items = db.query_items(None)?;
+    items.sort_by_key(|item| item.id);
+    let attributes = db.all_attributes()?;
+
+    std::fs::create_dir_all(dir)?;
+    let created = Utc::now();
+    let stem = format!("{PREFIX}{}", created.format("%Y%m%d-%H%M%S"));
+    let payload = format!("{stem}{PAYLOAD_SUFFIX}");
+    // Never replace an existing archive
+    let file = File::options().write(true).create_new(true).open(dir.join(&payload))?;
+
+    let mut gz = GzEncoder::new(
+        Hashing {
+            inner: BufWriter::new(file),
+            hasher: Sha256::new(),
+        },
+        Compression::default(),
+    );
+    let rows = runs
+        .iter()
+        .map(RowRef::Run)
+        .chain(albums.iter().map(RowRef::Album))
+        .chain(items.iter().map(RowRef::Item))
+        .chain(attributes.iter().map(RowRef::Attribute));
+    for row in rows {
+        serde_json::to_writer(&mut gz, &row).map_err(std::io::Error::from)?;
+        writeln!(gz)?;
+    }
+    let mut hashing = gz.finish()?;
+    hashing.inner.flush()?;
+
+    let manifest = Manifest {
+        payload,
+        sha256: hex(&hashing.hasher.finalize()),
+        created,
+        runs: runs.len() as u64,
+        albums: albums.len() as u64,
+        items: items.len() as u64,
+        attributes: attributes.len() as u64,
+        schema_version: db.migration_version()?,
+        rsbts_version: env!("CARGO_PKG_VERSION").to_string(),
+    };
+    // Written last, so an interrupted archive has no manifest and is refused
+    let manifest_path = dir.join(format!("{stem}{MANIFEST_SUFFIX}"));
+    let json = serde_json::to_string_pretty(&manifest).map_err(std::io::Error::from)?;
+    std::fs::write(&manifest_path, json + "\n")?;
+
+    rotate(dir, keep.max(1))?;
+    Ok((manifest_path, manifest))
+}
+
+/// Read an archive, given its manifest or payload, checking the payload's
+/// hash and row counts against the manifest.
+///
+/// # Errors
+/// Returns an error if either file is missing or unreadable, or the payload
+/// doesn't match the manifest.
+pub fn read(path: &Path) -> Result<Archive> {
+    let manifest_path = manifest_path(path)?;
+    let manifest: Manifest = serde_json::from_slice(&std::fs::read(&manifest_path)?)
+        .map_err(|e| corrupt(&manifest_path, &e))?;
+
+    // Only ever look next to the manifest, whatever it names
+    let name = Path::new(&manifest.payload)
+        .file_name()
+        .ok_or_else(|| corrupt(&manifest_path, &"no payload file name"))?;
+    let payload_path = manifest_path.with_file_name(name);
+    let payload = std::fs::read(&payload_path)?;
+    let sha256 = hex(&Sha256::digest(&payload));
+    if sha256 != manifest.sha256 {
+        return Err(corrupt(
+            &payload_path,
+            &format!("SHA-256 is {sha256}, manifest says {}", manifest.sha256),
+        ));
+    }
+
+    let mut archive = Archive {
+        manifest,
+        runs: Vec::new(),
+        albums: Vec::new(),
+        items: Vec::new(),
+        attributes: Vec::new(),
+    };
+    for line in BufReader::new(GzDecoder::new(payload.as_slice())).lines() {
+        let line = line.map_err(|e| corrupt(&payload_path, &e))?;
+        match serde_json::from_str::<Row>(&line).map_err(|e| corrupt(&payload_path, &e))? {
+            Row::Run(run) => archive.runs.push(run),
+            Row::Album(album) => archive.albums.push(album),
+            Row::Item(item) => archive.items.push(item),
+            Row::Attribute(attribute) => archive.attributes.push(attribute),
+        }
+    }
+
+    let counts = (
+        archive.runs.len() as u64,
+        archive.albums.len() as u64,
+        archive.items.len() as u64,
+        archive.attributes.len() as u64,
+    );
+    let manifest = &archive.manifest;
+    let expected = (manifest.runs, manifest.albums, manifest.items, manifest.attributes);
+    if counts != expected {
+        return Err(corrupt(
+            &payload_path,
+            &format!(
+                "holds {counts:?} runs, albums, items and attributes, manifest says \
+                 {expected:?}"
+            ),
+        ));
+    }
+    Ok(archive)
+}
+
+/// Read an archive as [`read`] does and check that it could be restored
+/// into `db`, which must be at the archive's schema version.
+///
+/// # Errors
+/// Returns an error if the archive doesn't verify or the schema versions
+/// differ.
+pub fn verify(db: &Database, path: &Path) -> Result<Archive> {
+    let archive = read(path)?;
+    let version = db.migration_version()?;
+    if archive.manifest.schema_version != version {
+        return Err(Error::Archive(format!(
+            "Archive was written at schema version {}, the library is at {version}",
+            archive.manifest.schema_version
+        )));
+    }
+    Ok(archive)
+}
+
+/// Verify an archive and load it into `db`, which must be empty and at the
+/// archive's schema version. Nothing is written unless every check passes.
+///
+/// # Errors
+/// Returns an error if the archive doesn't verify, the schema versions
+/// differ, the library isn't empty, or an insert fails.
+pub fn restore(db: &Database, path: &Path) -> Result<Manifest> {
+    let archive = verify(db, path)?;
+    db.restore(&archive.runs, &archive.albums, &archive.items, &archive.attributes)?;
+    Ok(archive.manifest)
+}
+
+/// The manifest of the archive `path` names: itself, or the manifest next
+/// to a payload.
+fn manifest_path(path: &Path) -> Result<PathBuf> {
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+    if name.ends_with(MANIFEST_SUFFIX) {
+        return Ok(path.to_path_buf());
+    }
+    name.strip_suffix(PAYLOAD_SUFFIX)
+        .map(|stem| path.with_file_name(format!("{stem}{MANIFEST_SUFFIX}")))
+        .ok_or_else(|| {
+            Error::Archive(format!(
+                "{} is neither an archive manifest ({MANIFEST_SUFFIX}) nor payload \
+                 ({PAYLOAD_SUFFIX})",
+                path.display()
+            ))
+        })
+}
+
+/// Delete all but the newest `keep` archives in `dir`. Names sort by their
+/// timestamps, so the oldest come first.
+fn rotate(dir: &Path, keep: usize) -> Result<()> {
+    let mut stems: Vec<String> = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter(|name| name.starts_with(PREFIX))
+        .filter_map(|name| name.strip_suffix(MANIFEST_SUFFIX).map(String::from))
+        .collect();
+    stems.sort();
+    let old = stems.len().saturating_sub(keep);
+    for stem in &stems[..old] {
+        std::fs::remove_file(dir.join(format!("{stem}{MANIFEST_SUFFIX}")))?;
+        let payload = dir.join(format!("{stem}{PAYLOAD_SUFFIX}"));
+        if payload.exists() {
+            std::fs::remove_file(payload)?;
+        }
+    }
+    Ok(())
+}
+
+fn corrupt(path: &Path, reason: &dyn std::fmt::Display) -> Error {
+    Error::Archive(format!("{} is damaged or incomplete: {reason}", path.display()))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Hashes everything written through it.
+struct Hashing<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> Write for Hashing<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::AttributeOwner;
+    use crate::import::Action;
+    use crate::testutil;
+    use crate::AudioFormat;
+
+    fn library() -> Database {
+        let db = Database::open_in_memory().unwrap();
+        db.migrate().unwrap();
+        db
+    }
+
+    fn fill(db: &Database) {
+        let run = ImportRun::new(Action::Copy);
+        db.record_import_run(&run).unwrap();
+        let album_id = db
+            .insert_album(&Album {
+                album: "Paranoid".into(),
+                albumartist: "Black Sabbath".into(),
+                year: Some(1970),
+                import_run: Some(run.id.clone()),
+                ..testutil::album()
+            })
+            .unwrap();
+        db.set_attribute(AttributeOwner::Album(album_id), "source", "vinyl rip").unwrap();
+        for (track, title) in ["War Pigs", "Paranoid"].iter().enumerate() {
+            let item_id = db.insert_item(&Item {
+                album_id: Some(album_id),
+                path: format!("/music/{title}.flac").into(),
+                title: (*title).into(),
+                artist: "Black Sabbath".into(),
+                album: "Paranoid".into(),
+                genre: Some("Metal".into()),
+                year: Some(1970),
+                track: Some(track as u32 + 1),
+                format: AudioFormat::Flac,
+                bitrate: 900,
+                length: 475.5,
+                size: Some(1024),
+                import_run: Some(run.id.clone()),
+                ..testutil::item()
+            })
+            .unwrap();
+            db.set_attribute(AttributeOwner::Item(item_id), "mood", "heavy").unwrap();
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rsbts-archive-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let dir = temp_dir("round-trip");
+        let db = library();
+        fill(&db);
+        let (manifest_path, manifest) = write(&db, &dir, DEFAULT_KEEP).unwrap();
+        assert_eq!((manifest.runs, manifest.albums, manifest.items), (1, 1, 2));
+        assert_eq!(manifest.attributes, 3);
+
+        let restored = library();
+        restore(&restored, &dir.join(&manifest.payload)).unwrap();
+        let items = restored.query_items(Some("title+")).unwrap();
+        let album_items = restored.album_items(items[0].album_id.unwrap()).unwrap();
+        let runs = restored.import_runs().unwrap();
+        let album_source = restored
+            .get_attribute(AttributeOwner::Album(items[0].album_id.unwrap()), "source")
+            .unwrap();
+        let mood = restored.get_attribute(AttributeOwner::Item(items[0].id.unwrap()), "mood");
+
+        // A library with rows in it is refused
+        let again = restore(&restored, &manifest_path);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(items.len(), 2);
+        assert_eq!((items[0].title.as_str(), items[0].size), ("Paranoid", Some(1024)));
+        assert_eq!(album_items.len(), 2);
+        assert_eq!((runs.len(), runs[0].items), (1, 2));
+        assert_eq!(album_source.as_deref(), Some("vinyl rip"));
+        assert_eq!(mood.unwrap().as_deref(), Some("heavy"));
+        assert!(again.is_err());
+    }
+
+    #[test]
+    fn test_corrupt_archives_are_refused() {
+        let dir = temp_dir("corrupt");
+        let db = library();
+        fill(&db);
+        let (manifest_path, manifest) = write(&db, &dir, DEFAULT_KEEP).unwrap();
+        let payload_path = dir.join(&manifest.payload);
+        let payload = std::fs::read(&payload_path).unwrap();
+
+        let mut flipped = payload.clone();
+        let middle = flipped.len() / 2;
+        flipped[middle] ^= 0x01;
+        std::fs::write(&payload_path, &flipped).unwrap();
+        let flipped_err = read(&manifest_path).unwrap_err().to_string();
+
+        std::fs::write(&payload_path, &payload[..payload.len() / 2]).unwrap();
+        let truncated_err = read(&manifest_path).unwrap_err().to_string();
+
+        std::fs::write(&payload_path, &payload).unwrap();
+        let restored = library();
+        let mut newer = manifest.clone();
+        newer.schema_version += 1;
+        std::fs::write(&manifest_path, serde_json::to_string(&newer).unwrap()).unwrap();
+        let schema_err = restore(&restored, &manifest_path).unwrap_err().to_string();
+        let verify_err = verify(&restored, &manifest_path).unwrap_err().to_string();
+        let untouched = restored.query_items(None).unwrap().is_empty();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(flipped_err.contains("SHA-256"), "{flipped_err}");
+        assert!(truncated_err.contains("SHA-256"), "{truncated_err}");
+        assert!(schema_err.contains("schema version"), "{schema_err}");
+        assert!(verify_err.contains("schema version"), "{verify_err}");
+        assert!(untouched);
+    }
+
+    #[test]
+    fn test_rotation_keeps_newest() {
+        let dir = temp_dir("rotate");
+        for stem in ["rsbts-20200101-000000", "rsbts-20210101-000000"] {
+            std::fs::write(dir.join(format!("{stem}{MANIFEST_SUFFIX}")), "{}").unwrap();
+            std::fs::write(dir.join(format!("{stem}{PAYLOAD_SUFFIX}")), "").unwrap();
+        }
+        std::fs::write(dir.join("notes.txt"), "").unwrap();
+        let db = library();
+        let (manifest_path, _) = write(&db, &dir, 2).unwrap();
+
+        let mut names: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(names.len(), 5);
+        assert_eq!(names[0], "notes.txt");
+        assert!(names[1].starts_with("rsbts-2021"));
+        assert!(manifest_path.ends_with(&names[4]));
+    }
+}
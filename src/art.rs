@@ -0,0 +1,390 @@
+//! Album art files, and their delivery at several sizes
+//!
+//! An album's art is a `cover.jpg` (or `cover.png`) in the directory holding
+//! its tracks. [`ArtCache`] turns an album's art file into the bytes, content
+//! type and `ETag` an HTTP response needs. Thumbnails are generated lazily on
+//! first request (with the `image` feature) and cached on disk, keyed by a
+//! hash of the original file and the requested size. Without the feature
+//! every size is served as the original.
+
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use tokio::sync::Semaphore;
+
+use crate::tags::fnv1a;
+use crate::{Error, Item, Result};
+
+/// Maximum number of thumbnails resized at the same time.
+pub const DEFAULT_MAX_RESIZES: usize = 4;
+
+/// Requested art size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ArtSize {
+    /// Fits in 200x200, for grid tiles.
+    Small,
+    /// Fits in 600x600, for album pages.
+    Medium,
+    /// The original file, unchanged.
+    #[default]
+    Orig,
+}
+
+impl ArtSize {
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Small => "small",
+            Self::Medium => "medium",
+            Self::Orig => "orig",
+        }
+    }
+
+    /// Longest edge in pixels, or `None` for the original.
+    #[must_use]
+    pub const fn max_edge(self) -> Option<u32> {
+        match self {
+            Self::Small => Some(200),
+            Self::Medium => Some(600),
+            Self::Orig => None,
+        }
+    }
+}
+
+impl FromStr for ArtSize {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "small" => Ok(Self::Small),
+            "medium" => Ok(Self::Medium),
+            "orig" | "original" => Ok(Self::Orig),
+            _ => Err(Error::Art(format!(
+                "Unknown size '{s}' (expected small, medium or orig)"
+            ))),
+        }
+    }
+}
+
+/// Art ready to be sent to a client.
+#[derive(Debug, Clone)]
+pub struct Art {
+    pub bytes: Vec<u8>,
+    pub content_type: &'static str,
+    /// Quoted strong entity tag, derived from the original's hash and the size.
+    pub etag: String,
+}
+
+impl Art {
+    /// Whether an `If-None-Match` header value matches this art, meaning the
+    /// client's copy is current and a `304 Not Modified` can be sent.
+    #[must_use]
+    pub fn matches(&self, if_none_match: &str) -> bool {
+        if_none_match.split(',').map(str::trim).any(|tag| {
+            tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == self.etag
+        })
+    }
+}
+
+/// On-disk cache of resized album art.
+pub struct ArtCache {
+    dir: PathBuf,
+    resizes: Semaphore,
+    /// Thumbnails generated (rather than read from the cache) by this process.
+    generated: AtomicUsize,
+}
+
+impl ArtCache {
+    /// Cache thumbnails under `dir`, resizing at most `max_resizes` at once.
+    #[must_use]
+    pub fn new(dir: PathBuf, max_resizes: usize) -> Self {
+        Self {
+            dir,
+            resizes: Semaphore::new(max_resizes.max(1)),
+            generated: AtomicUsize::new(0),
+        }
+    }
+
+    /// Number of thumbnails this cache has generated so far.
+    pub fn generated(&self) -> usize {
+        self.generated.load(Ordering::Relaxed)
+    }
+
+    /// Load the art at `original` in the requested size.
+    ///
+    /// # Errors
+    /// Returns an error if the original cannot be read or resizing fails.
+    pub async fn get(&self, original: &Path, size: ArtSize) -> Result<Art> {
+        let bytes = tokio::fs::read(original).await?;
+        let hash = fnv1a(&bytes);
+        let etag = format!("\"{hash:016x}-{}\"", size.as_str());
+
+        let Some(max_edge) = size.max_edge().filter(|_| cfg!(feature = "image")) else {
+            return Ok(Art {
+                content_type: content_type(&bytes),
+                bytes,
+                etag,
+            });
+        };
+
+        let cached = self.dir.join(format!("{hash:016x}-{}.jpg", size.as_str()));
+        if let Ok(bytes) = tokio::fs::read(&cached).await {
+            return Ok(Art {
+                bytes,
+                content_type: "image/jpeg",
+                etag,
+            });
+        }
+
+        let thumbnail = {
+            let _permit = self
+                .resizes
+                .acquire()
+                .await
+                .map_err(|e| Error::Art(e.to_string()))?;
+            tokio::task::spawn_blocking(move || resize(&bytes, max_edge))
+                .await
+                .map_err(|e| Error::Art(e.to_string()))??
+        };
+        self.generated.fetch_add(1, Ordering::Relaxed);
+
+        // A failed cache write only costs a resize next time
+        if tokio::fs::create_dir_all(&self.dir).await.is_ok() {
+            let _ = tokio::fs::write(&cached, &thumbnail).await;
+        }
+
+        Ok(Art {
+            bytes: thumbnail,
+            content_type: "image/jpeg",
+            etag,
+        })
+    }
+}
+
+/// Limits on the art embedded into tracks, so a huge scan doesn't end up in
+/// every file of an album.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ArtLimits {
+    /// Longest edge, in pixels.
+    pub max_edge: Option<u32>,
+    pub max_bytes: Option<u64>,
+}
+
+impl ArtLimits {
+    /// Why `bytes` is over the limits, or `None` if it's within them. An
+    /// image whose dimensions can't be read is over any pixel limit.
+    #[must_use]
+    pub fn exceeded(&self, bytes: &[u8]) -> Option<String> {
+        if let Some(max_bytes) = self.max_bytes.filter(|&max| bytes.len() as u64 > max) {
+            return Some(format!("{} bytes, over {max_bytes}", bytes.len()));
+        }
+        let max_edge = self.max_edge?;
+        match dimensions(bytes) {
+            Some((width, height)) if width.max(height) > max_edge => {
+                Some(format!("{width}x{height}, over {max_edge} pixels"))
+            }
+            Some(_) => None,
+            None => Some("size unknown (not a JPEG or PNG)".into()),
+        }
+    }
+}
+
+/// The name to save `bytes` under in an album's directory.
+#[must_use]
+pub fn file_name(bytes: &[u8]) -> &'static str {
+    if content_type(bytes) == "image/png" {
+        "cover.png"
+    } else {
+        "cover.jpg"
+    }
+}
+
+/// The deepest directory holding all of `items`' files, so a multi-disc
+/// album's is the one above its disc directories; `None` for no items.
+#[must_use]
+pub fn album_dir(items: &[Item]) -> Option<PathBuf> {
+    let mut dirs = items.iter().filter_map(|item| item.path.parent());
+    let first = dirs.next()?;
+    Some(dirs.fold(first.to_path_buf(), |common, dir| {
+        common
+            .components()
+            .zip(dir.components())
+            .take_while(|(a, b)| a == b)
+            .map(|(a, _)| a)
+            .collect()
+    }))
+}
+
+/// Width and height of a JPEG or PNG image, read from its header.
+#[must_use]
+pub fn dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+    let be16 = |at: usize| Some(u16::from_be_bytes([*bytes.get(at)?, *bytes.get(at + 1)?]));
+    match content_type(bytes) {
+        "image/png" => {
+            let header = bytes.get(12..24)?;
+            if !header.starts_with(b"IHDR") {
+                return None;
+            }
+            let be32 = |at: usize| {
+                u32::from_be_bytes([header[at], header[at + 1], header[at + 2], header[at + 3]])
+            };
+            Some((be32(4), be32(8)))
+        }
+        "image/jpeg" => {
+            // Walk the segments after SOI to the first start of frame
+            let mut at = 2;
+            while *bytes.get(at)? == 0xff {
+                let marker = *bytes.get(at + 1)?;
+                // 0xc4, 0xc8 and 0xcc share the range but aren't frames
+                if (0xc0..=0xcf).contains(&marker) && !matches!(marker, 0xc4 | 0xc8 | 0xcc) {
+                    return Some((be16(at + 7)?.into(), be16(at + 5)?.into()));
+                }
+                at += 2 + usize::from(be16(at + 2)?);
+            }
+            None
+        }
+        _ => None,
+    }
+}
+
+/// Content type of an image, sniffed from its magic bytes.
+pub(crate) fn content_type(bytes: &[u8]) -> &'static str {
+    if bytes.starts_with(&[0xff, 0xd8, 0xff]) {
+        "image/jpeg"
+    } else if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        "image/png"
+    } else {
+        "application/octet-stream"
+    }
+}
+
+/// Scale an image to fit in `max_edge` pixels square, encoded as JPEG.
+#[cfg(feature = "image")]
+fn resize(bytes: &[u8], max_edge: u32) -> Result<Vec<u8>> {
+    let image = image::load_from_memory(bytes).map_err(|e| Error::Art(e.to_string()))?;
+    let thumbnail = image::DynamicImage::ImageRgb8(image.thumbnail(max_edge, max_edge).to_rgb8());
+
+    let mut out = std::io::Cursor::new(Vec::new());
+    thumbnail
+        .write_to(&mut out, image::ImageFormat::Jpeg)
+        .map_err(|e| Error::Art(e.to_string()))?;
+    Ok(out.into_inner())
+}
+
+/// Without the `image` feature, [`ArtCache::get`] never asks for a resize.
+#[cfg(not(feature = "image"))]
+#[allow(clippy::unnecessary_wraps)]
+fn resize(bytes: &[u8], _max_edge: u32) -> Result<Vec<u8>> {
+    Ok(bytes.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rsbts-art-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_original_etag_and_if_none_match() {
+        let dir = temp_dir("orig");
+        let cover = dir.join("cover.png");
+        std::fs::write(&cover, b"\x89PNG\r\n\x1a\nnot a real image").unwrap();
+
+        let cache = ArtCache::new(dir.join("cache"), DEFAULT_MAX_RESIZES);
+        let first = cache.get(&cover, ArtSize::Orig).await.unwrap();
+        let second = cache.get(&cover, ArtSize::Orig).await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(first.content_type, "image/png");
+        assert_eq!(first.etag, second.etag);
+        assert!(second.matches(&first.etag));
+        assert!(second.matches(&format!("\"other\", W/{}", first.etag)));
+        assert!(!second.matches("\"other\""));
+        assert_eq!(cache.generated(), 0);
+    }
+
+    #[cfg(feature = "image")]
+    #[tokio::test]
+    async fn test_thumbnail_is_generated_once() {
+        let dir = temp_dir("thumb");
+        let cover = dir.join("cover.png");
+        image::RgbImage::new(800, 400).save(&cover).unwrap();
+
+        let cache = ArtCache::new(dir.join("cache"), DEFAULT_MAX_RESIZES);
+        let first = cache.get(&cover, ArtSize::Small).await.unwrap();
+        let second = cache.get(&cover, ArtSize::Small).await.unwrap();
+
+        let thumbnail = image::load_from_memory(&second.bytes).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // The second request is served from the cache directory
+        assert_eq!(cache.generated(), 1);
+        assert_eq!(first.content_type, "image/jpeg");
+        assert_eq!((thumbnail.width(), thumbnail.height()), (200, 100));
+        assert!(second.matches(&first.etag));
+    }
+
+    #[test]
+    fn test_dimensions_and_limits() {
+        let mut png = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+        png.extend_from_slice(&1200u32.to_be_bytes());
+        png.extend_from_slice(&900u32.to_be_bytes());
+        // SOI, an APP0 segment, then a baseline frame of 640x480
+        let jpeg = [
+            0xff, 0xd8, 0xff, 0xe0, 0x00, 0x04, 0x00, 0x00, 0xff, 0xc0, 0x00, 0x11, 0x08, 0x01,
+            0xe0, 0x02, 0x80, 0x03,
+        ];
+        assert_eq!(dimensions(&png), Some((1200, 900)));
+        assert_eq!(dimensions(&jpeg), Some((640, 480)));
+        assert_eq!(dimensions(&jpeg[..12]), None);
+        assert_eq!(dimensions(b"GIF89a"), None);
+        assert_eq!((file_name(&png), file_name(&jpeg)), ("cover.png", "cover.jpg"));
+
+        let limits = |max_edge, max_bytes| ArtLimits {
+            max_edge,
+            max_bytes,
+        };
+        assert_eq!(limits(None, None).exceeded(&png), None);
+        assert_eq!(limits(Some(1200), Some(24)).exceeded(&png), None);
+        assert_eq!(
+            limits(Some(1000), None).exceeded(&png).unwrap(),
+            "1200x900, over 1000 pixels"
+        );
+        assert_eq!(limits(None, Some(10)).exceeded(&jpeg).unwrap(), "18 bytes, over 10");
+        assert!(limits(Some(1000), None).exceeded(b"GIF89a").is_some());
+    }
+
+    #[test]
+    fn test_album_dir() {
+        let item = |path: &str| Item {
+            path: path.into(),
+            artist: "Nina Simone".into(),
+            album: "Pastel Blues".into(),
+            format: crate::AudioFormat::Flac,
+            bitrate: 900,
+            length: 600.0,
+            ..testutil::item()
+        };
+        let items = [
+            item("/music/Nina Simone/Pastel Blues/CD1/1.flac"),
+            item("/music/Nina Simone/Pastel Blues/CD2/1.flac"),
+        ];
+        let dir = |items: &[Item]| album_dir(items).unwrap();
+        assert_eq!(dir(&items), Path::new("/music/Nina Simone/Pastel Blues"));
+        assert_eq!(dir(&items[..1]), Path::new("/music/Nina Simone/Pastel Blues/CD1"));
+        assert_eq!(album_dir(&[]), None);
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!("small".parse::<ArtSize>().unwrap(), ArtSize::Small);
+        assert_eq!("orig".parse::<ArtSize>().unwrap(), ArtSize::Orig);
+        assert!("huge".parse::<ArtSize>().is_err());
+    }
+}
@@ -0,0 +1,554 @@
+//! Importing a beets library
+//!
+//! Reads a beets `library.db` (opened read-only) and adds its albums and
+//! items as they are, keeping beets' `added` dates and `MusicBrainz` IDs
+//! instead of matching the files again. beets stores paths as bytes,
+//! timestamps as Unix seconds, bitrates in bits per second and unknown
+//! numbers as 0; those are converted. Flexible attributes become rsbts
+//! ones; fields rsbts has no column for, and attributes whose names it
+//! can't take, are reported rather than imported.
+
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use rusqlite::types::Value as SqlValue;
+use rusqlite::{Connection, OpenFlags};
+
+use crate::db::{path_from_bytes, AttributeOwner, Database};
+use crate::fields::check_attribute_key;
+use crate::import::Action;
+use crate::pathformat::PathFormats;
+use crate::runs::{move_file, ImportRun, LogEntry};
+use crate::{Album, AudioFormat, Error, Item, Result};
+
+/// Columns of beets' `items` table with an rsbts equivalent. `mtime` is
+/// taken from the file instead, so `check` agrees with it.
+const ITEM_COLUMNS: &[&str] = &[
+    "id",
+    "path",
+    "album_id",
+    "title",
+    "artist",
+    "album",
+    "albumartist",
+    "artist_sort",
+    "albumartist_sort",
+    "genre",
+    "year",
+    "track",
+    "disc",
+    "format",
+    "bitrate",
+    "length",
+    "samplerate",
+    "channels",
+    "bitdepth",
+    "mb_trackid",
+    "mb_albumid",
+    "mb_releasegroupid",
+    "added",
+    "mtime",
+    "rg_track_gain",
+    "rg_track_peak",
+    "rg_album_gain",
+    "rg_album_peak",
+];
+
+/// Columns of beets' `albums` table with an rsbts equivalent.
+const ALBUM_COLUMNS: &[&str] = &[
+    "id",
+    "album",
+    "albumartist",
+    "year",
+    "artpath",
+    "mb_albumid",
+    "mb_releasegroupid",
+    "added",
+    "albumdisambig",
+];
+
+/// Where to move files while importing, for `--move-into-library`.
+#[derive(Debug, Clone, Copy)]
+pub struct Relocation<'a> {
+    pub library_dir: &'a Path,
+    pub path_formats: &'a PathFormats,
+}
+
+/// Outcome of [`import`].
+#[derive(Debug, Default)]
+pub struct BeetsReport {
+    pub albums: u64,
+    pub items: u64,
+    /// Items whose files were moved into the library.
+    pub moved: u64,
+    /// Items already in the library, which were left alone.
+    pub existing: u64,
+    /// Items whose file no longer exists, which were skipped.
+    pub missing: Vec<PathBuf>,
+    /// Items that could not be imported, with the reason.
+    pub failed: Vec<(PathBuf, String)>,
+    /// beets fields holding values rsbts has no place for; album fields are
+    /// prefixed with `album.`.
+    pub unmapped: BTreeSet<String>,
+    /// Run recorded when files were moved, so they can be moved back with
+    /// `undo`.
+    pub run: Option<String>,
+}
+
+/// A row as column name to value.
+type Row = BTreeMap<String, SqlValue>;
+
+/// Add the albums and items of the beets library at `beets_db` to `db`.
+///
+/// Files stay where they are unless `relocation` is given, in which case
+/// they are moved to their path-format location, never over an existing
+/// file. Only moves are recorded as an import run: undoing an in-place
+/// import could only delete the user's files.
+///
+/// # Errors
+/// Returns an error if `beets_db` isn't a readable beets library, the path
+/// format is invalid, or `db` can't be written.
+pub fn import(
+    db: &Database,
+    beets_db: &Path,
+    relocation: Option<Relocation<'_>>,
+) -> Result<BeetsReport> {
+    let beets = Connection::open_with_flags(beets_db, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let not_beets = |e: Error| {
+        Error::Import(format!("{} is not a beets library: {e}", beets_db.display()))
+    };
+    let albums = read_table(&beets, "albums").map_err(not_beets)?;
+    let items = read_table(&beets, "items").map_err(not_beets)?;
+
+    let mut report = BeetsReport::default();
+    for row in &albums {
+        note_unmapped(row, ALBUM_COLUMNS, "album.", &mut report.unmapped);
+    }
+    let item_attributes = flexible_attributes(&beets, "item_attributes", "", &mut report)?;
+    let album_attributes = flexible_attributes(&beets, "album_attributes", "album.", &mut report)?;
+
+    let run = relocation.map(|_| ImportRun::new(Action::Move));
+    let beets_albums: HashMap<i64, &Row> = albums
+        .iter()
+        .filter_map(|row| Some((int(row, "id")?, row)))
+        .collect();
+    let mut album_ids: HashMap<i64, i64> = HashMap::new();
+
+    for row in &items {
+        note_unmapped(row, ITEM_COLUMNS, "", &mut report.unmapped);
+        let Some(path) = blob_path(row, "path") else {
+            let id = int(row, "id").unwrap_or_default();
+            report
+                .failed
+                .push((PathBuf::new(), format!("beets item {id} has no path")));
+            continue;
+        };
+        if std::fs::metadata(&path).is_err() {
+            report.missing.push(path);
+            continue;
+        }
+
+        let mut item = item_from_row(row, path);
+        // Its album isn't in the library until the file is moved, so
+        // `%aunique{}` has nothing to tell it apart from, and `$attr_<key>`
+        // nothing to read
+        let dest = relocation
+            .map(|r| r.path_formats.destination(r.library_dir, &item, None))
+            .transpose()?;
+        let known = db.item_exists(&item.path)?
+            || dest.as_deref().map(|d| db.item_exists(d)).transpose()? == Some(true);
+        if known {
+            report.existing += 1;
+            continue;
+        }
+        let mut moved = false;
+        if let (Some(dest), Some(run)) = (dest, &run) {
+            if let Err(e) = move_file(&item.path, &dest) {
+                report.failed.push((item.path, e.to_string()));
+                continue;
+            }
+            db.record_import_run(run)?;
+            item.source_path = Some(std::mem::replace(&mut item.path, dest));
+            item.import_run = Some(run.id.clone());
+            report.moved += 1;
+            moved = true;
+        }
+        if let Ok(metadata) = std::fs::metadata(&item.path) {
+            if let Ok(mtime) = metadata.modified() {
+                item.mtime = mtime.into();
+            }
+            item.size = Some(metadata.len());
+        }
+
+        if let Some(beets_id) = int(row, "album_id") {
+            if let Entry::Vacant(entry) = album_ids.entry(beets_id) {
+                if let Some(album_row) = beets_albums.get(&beets_id) {
+                    let mut album = album_from_row(album_row);
+                    album.import_run = run.as_ref().map(|r| r.id.clone());
+                    album.source_path = item
+                        .source_path
+                        .as_deref()
+                        .and_then(Path::parent)
+                        .map(Path::to_path_buf);
+                    let album_id = db.insert_album(&album)?;
+                    let owner = AttributeOwner::Album(album_id);
+                    copy_attributes(db, owner, album_attributes.get(&beets_id))?;
+                    entry.insert(album_id);
+                    report.albums += 1;
+                }
+            }
+            // A dangling album_id leaves the item a singleton
+            item.album_id = album_ids.get(&beets_id).copied();
+        }
+
+        let item_id = db.insert_item(&item)?;
+        let attributes = int(row, "id").and_then(|id| item_attributes.get(&id));
+        copy_attributes(db, AttributeOwner::Item(item_id), attributes)?;
+        if let Some(run) = run.as_ref().filter(|_| moved) {
+            let entry = LogEntry::new(run, Some(Action::Move), item.source_path, item.path);
+            db.log_import(&LogEntry {
+                item_id: Some(item_id),
+                album_id: item.album_id,
+                ..entry
+            })?;
+        }
+        report.items += 1;
+    }
+
+    report.run = run.filter(|_| report.moved > 0).map(|r| r.id);
+    Ok(report)
+}
+
+fn item_from_row(row: &Row, path: PathBuf) -> Item {
+    let format = text(row, "format")
+        .map(|f| AudioFormat::from_extension(&f))
+        .filter(|&f| f != AudioFormat::Unknown)
+        .unwrap_or_else(|| {
+            path.extension()
+                .and_then(|e| e.to_str())
+                .map_or(AudioFormat::Unknown, AudioFormat::from_extension)
+        });
+    let title = text(row, "title").unwrap_or_else(|| {
+        path.file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or("Unknown")
+            .to_string()
+    });
+    let added = timestamp(row, "added").unwrap_or_else(Utc::now);
+
+    Item {
+        id: None,
+        album_id: None,
+        path,
+        title,
+        artist: text(row, "artist").unwrap_or_else(|| "Unknown Artist".into()),
+        album: text(row, "album").unwrap_or_else(|| "Unknown Album".into()),
+        albumartist: text(row, "albumartist"),
+        artist_sort: text(row, "artist_sort"),
+        albumartist_sort: text(row, "albumartist_sort"),
+        genre: text(row, "genre"),
+        year: positive(row, "year"),
+        track: positive(row, "track"),
+        disc: positive(row, "disc"),
+        format,
+        // beets stores bits per second
+        bitrate: int(row, "bitrate").map_or(0, |bps| u32::try_from(bps / 1000).unwrap_or(0)),
+        length: number(row, "length").unwrap_or(0.0),
+        samplerate: positive(row, "samplerate"),
+        channels: positive(row, "channels"),
+        bitdepth: positive(row, "bitdepth"),
+        mb_trackid: text(row, "mb_trackid"),
+        mb_albumid: text(row, "mb_albumid"),
+        mb_releasegroupid: text(row, "mb_releasegroupid"),
+        added,
+        mtime: timestamp(row, "mtime").unwrap_or(added),
+        size: None,
+        source_path: None,
+        import_run: None,
+        rg_track_gain: number(row, "rg_track_gain"),
+        rg_track_peak: number(row, "rg_track_peak"),
+        rg_album_gain: number(row, "rg_album_gain"),
+        rg_album_peak: number(row, "rg_album_peak"),
+        rating: None,
+        play_count: 0,
+        last_played: None,
+    }
+}
+
+fn album_from_row(row: &Row) -> Album {
+    Album {
+        id: None,
+        album: text(row, "album").unwrap_or_else(|| "Unknown Album".into()),
+        albumartist: text(row, "albumartist").unwrap_or_else(|| "Unknown Artist".into()),
+        year: positive(row, "year"),
+        artpath: blob_path(row, "artpath"),
+        mb_albumid: text(row, "mb_albumid"),
+        mb_releasegroupid: text(row, "mb_releasegroupid"),
+        added: timestamp(row, "added").unwrap_or_else(Utc::now),
+        source_path: None,
+        import_run: None,
+        disambiguation: text(row, "albumdisambig"),
+    }
+}
+
+fn read_table(conn: &Connection, table: &str) -> Result<Vec<Row>> {
+    let mut stmt = conn.prepare(&format!("SELECT * FROM {table} ORDER BY id"))?;
+    let names: Vec<String> = stmt.column_names().into_iter().map(String::from).collect();
+    let rows = stmt
+        .query_map([], |row| {
+            names
+                .iter()
+                .enumerate()
+                .map(|(i, name)| Ok((name.clone(), row.get::<_, SqlValue>(i)?)))
+                .collect()
+        })?
+        .collect::<std::result::Result<Vec<Row>, _>>()?;
+    Ok(rows)
+}
+
+/// beets' flexible attributes in `table`, which older libraries may lack,
+/// as keys and values by the beets id of what they belong to. Keys rsbts
+/// can't take are added to the report's unmapped fields, after `prefix`.
+fn flexible_attributes(
+    conn: &Connection,
+    table: &str,
+    prefix: &str,
+    report: &mut BeetsReport,
+) -> Result<HashMap<i64, Vec<(String, String)>>> {
+    let sql = format!("SELECT entity_id, key, value FROM {table} ORDER BY id");
+    let Ok(mut stmt) = conn.prepare(&sql) else {
+        return Ok(HashMap::new());
+    };
+    let rows = stmt
+        .query_map([], |row| {
+            Ok((row.get::<_, Option<i64>>(0)?, row.get::<_, String>(1)?, row.get(2)?))
+        })?
+        .collect::<std::result::Result<Vec<(Option<i64>, String, SqlValue)>, _>>()?;
+    let mut attributes: HashMap<i64, Vec<(String, String)>> = HashMap::new();
+    for (id, key, value) in rows {
+        let (Some(id), Some(value)) = (id, value_text(&value)) else {
+            continue;
+        };
+        if check_attribute_key(&key).is_err() {
+            report.unmapped.insert(format!("{prefix}{key}"));
+            continue;
+        }
+        attributes.entry(id).or_default().push((key, value));
+    }
+    Ok(attributes)
+}
+
+/// Give `owner` the flexible attributes of what it was in beets.
+fn copy_attributes(
+    db: &Database,
+    owner: AttributeOwner,
+    attributes: Option<&Vec<(String, String)>>,
+) -> Result<()> {
+    for (key, value) in attributes.into_iter().flatten() {
+        db.set_attribute(owner, key, value)?;
+    }
+    Ok(())
+}
+
+/// Add the columns of `row` outside `mapped` that hold a value to `unmapped`.
+fn note_unmapped(row: &Row, mapped: &[&str], prefix: &str, unmapped: &mut BTreeSet<String>) {
+    for (name, value) in row {
+        if !mapped.contains(&name.as_str()) && is_set(value) {
+            unmapped.insert(format!("{prefix}{name}"));
+        }
+    }
+}
+
+/// Whether a beets value is more than a default: beets fills unset text
+/// with `''` and unset numbers with 0.
+fn is_set(value: &SqlValue) -> bool {
+    match value {
+        SqlValue::Null => false,
+        SqlValue::Integer(n) => *n != 0,
+        SqlValue::Real(n) => *n != 0.0,
+        SqlValue::Text(s) => !s.is_empty(),
+        SqlValue::Blob(b) => !b.is_empty(),
+    }
+}
+
+fn text(row: &Row, name: &str) -> Option<String> {
+    value_text(row.get(name)?).filter(|s| !s.is_empty())
+}
+
+fn value_text(value: &SqlValue) -> Option<String> {
+    match value {
+        SqlValue::Text(s) => Some(s.clone()),
+        SqlValue::Blob(b) => Some(String::from_utf8_lossy(b).into_owned()),
+        SqlValue::Integer(n) => Some(n.to_string()),
+        SqlValue::Real(n) => Some(n.to_string()),
+        SqlValue::Null => None,
+    }
+}
+
+fn number(row: &Row, name: &str) -> Option<f64> {
+    match row.get(name)? {
+        SqlValue::Integer(n) => Some(*n as f64),
+        SqlValue::Real(n) => Some(*n),
+        SqlValue::Text(s) => s.trim().parse().ok(),
+        SqlValue::Null | SqlValue::Blob(_) => None,
+    }
+}
+
+fn int(row: &Row, name: &str) -> Option<i64> {
+    match row.get(name)? {
+        SqlValue::Integer(n) => Some(*n),
+        SqlValue::Real(n) => Some(*n as i64),
+        SqlValue::Text(s) => s.trim().parse().ok(),
+        SqlValue::Null | SqlValue::Blob(_) => None,
+    }
+}
+
+/// A number beets leaves at 0 when unknown.
+fn positive<T: TryFrom<i64>>(row: &Row, name: &str) -> Option<T> {
+    int(row, name)
+        .filter(|&n| n > 0)
+        .and_then(|n| T::try_from(n).ok())
+}
+
+/// A Unix timestamp in (fractional) seconds.
+fn timestamp(row: &Row, name: &str) -> Option<DateTime<Utc>> {
+    number(row, name)
+        .filter(|&secs| secs > 0.0)
+        .and_then(|secs| DateTime::from_timestamp_micros((secs * 1e6) as i64))
+}
+
+/// A path, which beets stores as the raw bytes of the file name.
+fn blob_path(row: &Row, name: &str) -> Option<PathBuf> {
+    match row.get(name)? {
+        SqlValue::Blob(bytes) if !bytes.is_empty() => Some(path_from_bytes(bytes)),
+        SqlValue::Text(s) if !s.is_empty() => Some(PathBuf::from(s)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::wav_bytes;
+    use rusqlite::params;
+
+    /// A beets library with one album of two tracks, one of whose files is
+    /// gone, and some flexible attributes.
+    fn beets_library(dir: &Path, music: &Path) -> PathBuf {
+        let path = dir.join("beets.db");
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE albums (id INTEGER PRIMARY KEY, artpath BLOB, added REAL,
+                 albumartist TEXT, album TEXT, year INTEGER, mb_albumid TEXT, label TEXT);
+             CREATE TABLE items (id INTEGER PRIMARY KEY, path BLOB, album_id INTEGER,
+                 title TEXT, artist TEXT, album TEXT, albumartist TEXT, genre TEXT,
+                 year INTEGER, track INTEGER, disc INTEGER, format TEXT, bitrate INTEGER,
+                 length REAL, mb_trackid TEXT, mb_albumid TEXT, added REAL, mtime REAL,
+                 lyrics TEXT, comments TEXT);
+             CREATE TABLE item_attributes (id INTEGER PRIMARY KEY, entity_id INTEGER,
+                 key TEXT, value TEXT);
+             CREATE TABLE album_attributes (id INTEGER PRIMARY KEY, entity_id INTEGER,
+                 key TEXT, value TEXT);
+             INSERT INTO albums VALUES (1, NULL, 1500000000.5, 'Black Sabbath', 'Paranoid',
+                 1970, 'mb-album', 'Vertigo');
+             INSERT INTO item_attributes VALUES (1, 1, 'rating', '5'),
+                 (2, 1, 'last.fm-tags', 'doom');
+             INSERT INTO album_attributes VALUES (1, 1, 'source', 'vinyl rip');",
+        )
+        .unwrap();
+        for (id, title) in [(1, "War Pigs"), (2, "Paranoid")] {
+            let file = music.join(format!("{id:02} {title}.wav"));
+            conn.execute(
+                "INSERT INTO items VALUES (?1, ?2, 1, ?3, 'Black Sabbath', 'Paranoid', '', '',
+                     1970, ?1, 0, 'WAV', 128000, 475.5, ?4, 'mb-album', 1500000000.25, 0,
+                     ?5, '')",
+                params![
+                    id,
+                    file.to_string_lossy().as_bytes(),
+                    title,
+                    format!("mb-track-{id}"),
+                    if id == 1 { "Generals gathered" } else { "" },
+                ],
+            )
+            .unwrap();
+        }
+        std::fs::write(music.join("01 War Pigs.wav"), wav_bytes(800)).unwrap();
+        path
+    }
+
+    fn temp_dirs(name: &str) -> (PathBuf, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("rsbts-beets-{name}-{}", std::process::id()));
+        let music = dir.join("beets-music");
+        std::fs::create_dir_all(&music).unwrap();
+        (dir, music)
+    }
+
+    #[test]
+    fn test_import_beets_in_place() {
+        let (dir, music) = temp_dirs("in-place");
+        let beets_db = beets_library(&dir, &music);
+        let db = Database::open_in_memory().unwrap();
+        db.migrate().unwrap();
+
+        let report = import(&db, &beets_db, None).unwrap();
+        let again = import(&db, &beets_db, None).unwrap();
+        let items = db.query_items(None).unwrap();
+        let albums = db.query_albums(None).unwrap();
+        let attributes = db.attributes(AttributeOwner::Item(items[0].id.unwrap())).unwrap();
+        let sourced = db.count_items(Some("attr.source:vinyl")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!((report.albums, report.items, report.moved), (1, 1, 0));
+        assert_eq!(report.missing, [music.join("02 Paranoid.wav")]);
+        assert!(report.run.is_none());
+        assert_eq!(
+            report.unmapped.iter().map(String::as_str).collect::<Vec<_>>(),
+            ["album.label", "last.fm-tags", "lyrics"]
+        );
+        assert_eq!((again.items, again.existing), (0, 1));
+
+        let item = &items[0];
+        assert_eq!(items.len(), 1);
+        assert_eq!(item.path, music.join("01 War Pigs.wav"));
+        assert_eq!(item.album_id, albums[0].id);
+        assert_eq!(item.added.timestamp_millis(), 1_500_000_000_250);
+        assert_eq!(item.mb_trackid.as_deref(), Some("mb-track-1"));
+        assert_eq!((item.track, item.disc, item.bitrate), (Some(1), None, 128));
+        assert_eq!((item.albumartist.as_deref(), item.genre.as_deref()), (None, None));
+        assert_eq!(item.format, AudioFormat::Wav);
+        assert_eq!(albums[0].year, Some(1970));
+        assert_eq!(albums[0].added.timestamp_millis(), 1_500_000_000_500);
+        assert_eq!(attributes, BTreeMap::from([("rating".into(), "5".into())]));
+        assert_eq!(sourced, 1);
+    }
+
+    #[test]
+    fn test_import_beets_moving_into_library() {
+        let (dir, music) = temp_dirs("move");
+        let beets_db = beets_library(&dir, &music);
+        let library = dir.join("library");
+        let db = Database::open_in_memory().unwrap();
+        db.migrate().unwrap();
+
+        let relocation = Relocation {
+            library_dir: &library,
+            path_formats: &PathFormats::new("$albumartist/$album/$track $title"),
+        };
+        let report = import(&db, &beets_db, Some(relocation)).unwrap();
+        let item = db.query_items(None).unwrap().remove(0);
+        let moved = library.join("Black Sabbath/Paranoid/01 War Pigs.wav");
+        let moved_exists = moved.exists();
+        let source_exists = music.join("01 War Pigs.wav").exists();
+        let run = db.import_runs().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!((report.items, report.moved), (1, 1));
+        assert_eq!(item.path, moved);
+        assert_eq!(item.source_path, Some(music.join("01 War Pigs.wav")));
+        assert!(moved_exists && !source_exists);
+        assert_eq!(report.run.as_deref(), Some(run[0].run.id.as_str()));
+        assert_eq!((run[0].albums, run[0].items), (1, 1));
+    }
+}
@@ -0,0 +1,379 @@
+//! Albums packed in archives
+//!
+//! Downloads such as Bandcamp purchases come as a `.zip` (or `.tar.gz`) of an
+//! album. Importing one extracts its audio files and images into a temporary
+//! directory, which is removed again with the [`Bundle`]. They go into a
+//! directory named after the archive, so an archive named "Artist - Album"
+//! holding just the tracks names them as their directory would. Other
+//! entries are skipped, as are macOS resource forks and names that would lead
+//! out of the directory. Zip archives need the `zip` feature, on by default.
+
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use flate2::read::GzDecoder;
+use log::warn;
+use walkdir::WalkDir;
+
+use crate::import::DEFAULT_ART_FILENAMES;
+use crate::tags::is_audio_file;
+use crate::{Error, Result};
+
+/// Archives extracted so far, numbering this process's temporary directories.
+static EXTRACTED: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Zip,
+    TarGz,
+}
+
+/// The kind of archive `path` is named as, and its name without the
+/// extension.
+fn kind(path: &Path) -> Option<(Kind, &str)> {
+    let name = path.file_name()?.to_str()?;
+    let lower = name.to_lowercase();
+    [(".zip", Kind::Zip), (".tar.gz", Kind::TarGz), (".tgz", Kind::TarGz)]
+        .into_iter()
+        .find(|(extension, _)| lower.ends_with(extension))
+        .map(|(extension, kind)| (kind, name.get(..name.len() - extension.len()).unwrap_or(name)))
+}
+
+/// Whether `path` is named like an archive that can be imported.
+#[must_use]
+pub fn is_bundle(path: &Path) -> bool {
+    kind(path).is_some()
+}
+
+/// All archives under `path` (or `path` itself, if it is one).
+#[must_use]
+pub fn bundles(path: &Path) -> Vec<PathBuf> {
+    WalkDir::new(path)
+        .follow_links(true)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_map(std::result::Result::ok)
+        .filter(|e| e.file_type().is_file())
+        .filter(|e| is_bundle(e.path()))
+        .map(|e| e.path().to_path_buf())
+        .collect()
+}
+
+/// The audio files and images of an archive, extracted into a temporary
+/// directory that is removed when this is dropped.
+#[derive(Debug)]
+pub struct Bundle {
+    /// The archive they came from.
+    pub path: PathBuf,
+    /// The temporary directory.
+    dir: PathBuf,
+    /// The directory in it holding the archive's contents.
+    root: PathBuf,
+    /// The extracted audio files, in archive order.
+    pub audio: Vec<PathBuf>,
+    /// The extracted image to use as cover art: the least nested one named
+    /// cover, folder or front, in that order of preference.
+    pub cover: Option<PathBuf>,
+}
+
+impl Bundle {
+    /// Extract the audio files and images of the archive at `path`, files
+    /// with `extra_extensions` counting as audio.
+    ///
+    /// # Errors
+    /// Returns an error if the archive can't be read, is corrupt or
+    /// password-protected, or its files can't be written. Nothing extracted
+    /// is left behind.
+    pub fn extract(path: &Path, extra_extensions: &[String]) -> Result<Self> {
+        let Some((kind, stem)) = kind(path) else {
+            return Err(Error::Import("Not a zip or tar.gz archive".into()));
+        };
+        let file = File::open(path)?;
+        let dir = std::env::temp_dir().join(format!(
+            "rsbts-bundle-{}-{}",
+            std::process::id(),
+            EXTRACTED.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&dir)?;
+        // Canonical, like the source directories the importer records
+        let dir = std::fs::canonicalize(&dir).unwrap_or(dir);
+        let root = dir.join(if stem.is_empty() { "archive" } else { stem });
+        // Dropped on error, taking what was extracted with it
+        let mut bundle = Self {
+            path: std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf()),
+            dir,
+            root,
+            audio: Vec::new(),
+            cover: None,
+        };
+        let files = match kind {
+            Kind::Zip => extract_zip(&file, &bundle.root, extra_extensions)?,
+            Kind::TarGz => extract_tar_gz(&file, &bundle.root, extra_extensions)?,
+        };
+        let (audio, images): (Vec<PathBuf>, Vec<PathBuf>) =
+            files.into_iter().partition(|file| is_audio_file(file, extra_extensions));
+        bundle.audio = audio;
+        bundle.cover = cover(&images);
+        Ok(bundle)
+    }
+
+    /// Where the extracted file or directory at `path` is in the archive:
+    /// its name inside the archive, under the archive's path.
+    #[must_use]
+    pub fn source_of(&self, path: &Path) -> PathBuf {
+        match path.strip_prefix(&self.root) {
+            Ok(inner) if inner.as_os_str().is_empty() => self.path.clone(),
+            Ok(inner) => self.path.join(inner),
+            Err(_) => path.to_path_buf(),
+        }
+    }
+}
+
+impl Drop for Bundle {
+    fn drop(&mut self) {
+        match std::fs::remove_dir_all(&self.dir) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                warn!("Could not remove {}: {e}", self.dir.display());
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(feature = "zip")]
+fn extract_zip(file: &File, dir: &Path, extra_extensions: &[String]) -> Result<Vec<PathBuf>> {
+    let zip_error = |e: zip::result::ZipError| Error::Import(e.to_string());
+    let mut archive = zip::ZipArchive::new(file).map_err(zip_error)?;
+    let mut files = Vec::new();
+    for i in 0..archive.len() {
+        let (name, encrypted) = {
+            let entry = archive.by_index_raw(i).map_err(zip_error)?;
+            let name = if entry.is_dir() {
+                None
+            } else {
+                inside(Path::new(entry.name()))
+            };
+            (name, entry.encrypted())
+        };
+        let Some(name) = name.filter(|name| wanted(name, extra_extensions)) else {
+            continue;
+        };
+        if encrypted {
+            return Err(Error::Import("Archive is password-protected".into()));
+        }
+        let mut entry = archive.by_index(i).map_err(zip_error)?;
+        files.push(write_entry(&mut entry, dir, &name)?);
+    }
+    Ok(files)
+}
+
+#[cfg(not(feature = "zip"))]
+fn extract_zip(_file: &File, _dir: &Path, _extra: &[String]) -> Result<Vec<PathBuf>> {
+    Err(Error::Import("Zip archives need rsbts built with the zip feature".into()))
+}
+
+fn extract_tar_gz(
+    file: &File,
+    dir: &Path,
+    extra_extensions: &[String],
+) -> Result<Vec<PathBuf>> {
+    let mut archive = tar::Archive::new(GzDecoder::new(file));
+    let mut files = Vec::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let name = inside(&entry.path()?);
+        let Some(name) = name.filter(|name| wanted(name, extra_extensions)) else {
+            continue;
+        };
+        files.push(write_entry(&mut entry, dir, &name)?);
+    }
+    Ok(files)
+}
+
+/// Write `reader` to `name` under `dir`, returning the file's path.
+fn write_entry(reader: &mut impl Read, dir: &Path, name: &Path) -> Result<PathBuf> {
+    let path = dir.join(name);
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::io::copy(reader, &mut File::create(&path)?)?;
+    Ok(path)
+}
+
+/// The entry name `name` as a relative path, or `None` if it is absolute,
+/// goes up with `..` or is empty.
+fn inside(name: &Path) -> Option<PathBuf> {
+    let mut path = PathBuf::new();
+    for component in name.components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    (!path.as_os_str().is_empty()).then_some(path)
+}
+
+/// Whether an entry is extracted: audio files and images, but not the
+/// `__MACOSX/._name` resource forks macOS adds to zips.
+fn wanted(name: &Path, extra_extensions: &[String]) -> bool {
+    let fork = name.components().any(|component| {
+        component
+            .as_os_str()
+            .to_str()
+            .is_some_and(|part| part == "__MACOSX" || part.starts_with("._"))
+    });
+    !fork && (is_audio_file(name, extra_extensions) || is_image(name))
+}
+
+fn is_image(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|ext| matches!(ext.to_lowercase().as_str(), "jpg" | "jpeg" | "png"))
+}
+
+/// The cover art among `images`: the least nested named cover, folder or
+/// front, preferring them in that order.
+fn cover(images: &[PathBuf]) -> Option<PathBuf> {
+    images
+        .iter()
+        .filter_map(|path| {
+            let stem = path.file_stem()?.to_str()?.to_lowercase();
+            let rank = DEFAULT_ART_FILENAMES.iter().position(|name| *name == stem)?;
+            Some((path.components().count(), rank, path))
+        })
+        .min()
+        .map(|(_, _, path)| path.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    fn tar_gz(path: &Path, entries: &[(&str, &[u8])]) {
+        let mut builder = tar::Builder::new(GzEncoder::new(
+            File::create(path).unwrap(),
+            Compression::default(),
+        ));
+        for (name, data) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, name, *data).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap();
+    }
+
+    #[test]
+    fn test_bundle_names() {
+        assert!(is_bundle(Path::new("/downloads/Album.zip")));
+        assert!(is_bundle(Path::new("Album.ZIP")));
+        assert!(is_bundle(Path::new("Album.tar.gz")));
+        assert!(is_bundle(Path::new("Album.tgz")));
+        assert!(!is_bundle(Path::new("Album.gz")));
+        assert!(!is_bundle(Path::new("Album.flac")));
+        let stem = |name: &str| kind(Path::new(name)).map(|(_, stem)| stem.to_string());
+        assert_eq!(stem("Artist - Album.TAR.GZ").as_deref(), Some("Artist - Album"));
+    }
+
+    #[test]
+    fn test_entry_names_stay_inside() {
+        assert_eq!(inside(Path::new("a/./b.flac")), Some("a/b.flac".into()));
+        assert_eq!(inside(Path::new("../b.flac")), None);
+        assert_eq!(inside(Path::new("a/../../b.flac")), None);
+        assert_eq!(inside(Path::new("/etc/b.flac")), None);
+        assert_eq!(inside(Path::new("./")), None);
+
+        assert!(wanted(Path::new("Album/01.flac"), &[]));
+        assert!(wanted(Path::new("Album/Cover.JPG"), &[]));
+        assert!(!wanted(Path::new("Album/notes.txt"), &[]));
+        assert!(!wanted(Path::new("__MACOSX/Album/01.flac"), &[]));
+        assert!(!wanted(Path::new("Album/._01.flac"), &[]));
+        assert!(wanted(Path::new("Album/01.SHN"), &[".shn".into()]));
+    }
+
+    #[test]
+    fn test_cover_choice() {
+        let images: Vec<PathBuf> = ["a/scan.jpg", "a/b/cover.jpg", "a/front.png", "a/folder.jpg"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        assert_eq!(cover(&images), Some("a/folder.jpg".into()));
+        assert_eq!(cover(&images[..2]), Some("a/b/cover.jpg".into()));
+        assert_eq!(cover(&images[..1]), None);
+    }
+
+    #[test]
+    fn test_extract_tar_gz() {
+        let root = std::env::temp_dir().join(format!("rsbts-bundle-test-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let archive = root.join("Album.tar.gz");
+        tar_gz(
+            &archive,
+            &[
+                ("Album/01 One.flac", b"one".as_slice()),
+                ("Album/notes.txt", b"notes".as_slice()),
+                ("Album/Cover.jpg", b"jpeg".as_slice()),
+                ("Album/02 Two.mp3", b"two".as_slice()),
+            ],
+        );
+
+        let bundle = Bundle::extract(&archive, &[]).unwrap();
+        let archive = std::fs::canonicalize(&archive).unwrap();
+        let sources: Vec<PathBuf> = bundle.audio.iter().map(|p| bundle.source_of(p)).collect();
+        assert_eq!(
+            sources,
+            [archive.join("Album/01 One.flac"), archive.join("Album/02 Two.mp3")]
+        );
+        assert_eq!(std::fs::read(&bundle.audio[0]).unwrap(), b"one");
+        let cover = bundle.cover.clone().unwrap();
+        assert_eq!(std::fs::read(&cover).unwrap(), b"jpeg");
+        assert_eq!(bundle.source_of(&bundle.root), archive);
+        assert!(bundle.audio[0].ends_with("Album/Album/01 One.flac"));
+
+        let dir = bundle.dir.clone();
+        drop(bundle);
+        assert!(!dir.exists());
+
+        std::fs::write(root.join("Broken.tar.gz"), b"not gzip").unwrap();
+        assert!(Bundle::extract(&root.join("Broken.tar.gz"), &[]).is_err());
+        std::fs::write(root.join("Broken.zip"), b"not a zip").unwrap();
+        assert!(Bundle::extract(&root.join("Broken.zip"), &[]).is_err());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(feature = "zip")]
+    #[test]
+    fn test_extract_zip() {
+        use std::io::Write;
+
+        let root = std::env::temp_dir().join(format!("rsbts-bundle-zip-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let archive = root.join("Album.zip");
+        let mut writer = zip::ZipWriter::new(File::create(&archive).unwrap());
+        let options = zip::write::SimpleFileOptions::default();
+        for (name, data) in [
+            ("01 One.flac", b"one".as_slice()),
+            ("__MACOSX/._01 One.flac", b"fork".as_slice()),
+            ("folder.png", b"png".as_slice()),
+        ] {
+            writer.start_file(name, options).unwrap();
+            writer.write_all(data).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let bundle = Bundle::extract(&archive, &[]).unwrap();
+        assert_eq!(bundle.audio.len(), 1);
+        assert_eq!(std::fs::read(&bundle.audio[0]).unwrap(), b"one");
+        assert!(bundle.cover.as_ref().is_some_and(|c| c.ends_with("folder.png")));
+        drop(bundle);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,269 @@
+//! What importing an album changes in its tracks, worked out before it's
+//! applied so it can be shown first
+//!
+//! The importer places an album's items on the tracks of the release it
+//! matched as a list of [`TrackMatch`]es. [`AlbumChanges`] compares each
+//! item with the track it goes on, and notes what is left over on either
+//! side.
+
+use std::path::PathBuf;
+
+use serde::Serialize;
+
+use crate::musicbrainz::{Release, Track};
+use crate::Item;
+
+/// An item placed on a release track.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct TrackMatch {
+    /// The item's index among the album's items.
+    pub item: usize,
+    /// The track's index among the release's tracks, as
+    /// [`Release::disc_tracks`] lists them.
+    pub track: usize,
+    /// How alike the item's title and the track's are, from 0 to 1.
+    pub similarity: f64,
+}
+
+/// A disc and track number, either of which may be unknown.
+pub type Position = (Option<u32>, Option<u32>);
+
+/// Where `item` ends up when placed on `track` of disc `disc`: the release's
+/// positions beat tagged ones, which may be placeholders, unless they're 0.
+#[must_use]
+pub fn placed_position(item: &Item, disc: u32, track: &Track) -> Position {
+    (
+        if disc > 0 { Some(disc) } else { item.disc },
+        if track.position > 0 { Some(track.position) } else { item.track },
+    )
+}
+
+/// What placing one item on its track changes.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TrackChange {
+    pub path: PathBuf,
+    pub old_title: String,
+    pub new_title: String,
+    pub old_position: Position,
+    pub new_position: Position,
+    /// As in [`TrackMatch::similarity`].
+    pub similarity: f64,
+}
+
+impl TrackChange {
+    #[must_use]
+    pub fn title_changed(&self) -> bool {
+        self.old_title != self.new_title
+    }
+
+    #[must_use]
+    pub fn position_changed(&self) -> bool {
+        self.old_position != self.new_position
+    }
+}
+
+/// A release track no item was placed on.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MissingTrack {
+    pub position: Position,
+    pub title: String,
+}
+
+/// What importing an album's items as a release changes in them.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AlbumChanges {
+    /// The release's artist.
+    pub artist: String,
+    /// The release's title.
+    pub album: String,
+    /// The items placed on tracks, in the release's order.
+    pub tracks: Vec<TrackChange>,
+    /// Items placed on no track, which keep their tags.
+    pub unmatched: Vec<PathBuf>,
+    /// Tracks of the release no item was placed on, in its order.
+    pub missing: Vec<MissingTrack>,
+}
+
+impl AlbumChanges {
+    /// The changes `matches` make to `items` as tracks of `release`.
+    /// Matches naming an item or track that isn't there are left out.
+    #[must_use]
+    pub fn new(items: &[Item], release: &Release, matches: &[TrackMatch]) -> Self {
+        let tracks = release.disc_tracks();
+        let mut item_placed = vec![false; items.len()];
+        let mut track_placed = vec![false; tracks.len()];
+        let mut changes = Vec::new();
+        for m in matches {
+            let (Some(item), Some(&(disc, track))) = (items.get(m.item), tracks.get(m.track))
+            else {
+                continue;
+            };
+            item_placed[m.item] = true;
+            track_placed[m.track] = true;
+            let change = TrackChange {
+                path: item.path.clone(),
+                old_title: item.title.clone(),
+                new_title: track.title.clone(),
+                old_position: (item.disc, item.track),
+                new_position: placed_position(item, disc, track),
+                similarity: m.similarity,
+            };
+            changes.push((m.track, change));
+        }
+        changes.sort_by_key(|(track, _)| *track);
+
+        Self {
+            artist: release.artist_name(),
+            album: release.title.clone(),
+            tracks: changes.into_iter().map(|(_, change)| change).collect(),
+            unmatched: items
+                .iter()
+                .zip(item_placed)
+                .filter(|(_, placed)| !placed)
+                .map(|(item, _)| item.path.clone())
+                .collect(),
+            missing: tracks
+                .iter()
+                .zip(track_placed)
+                .filter(|(_, placed)| !placed)
+                .map(|(&(disc, track), _)| MissingTrack {
+                    position: (Some(disc).filter(|&d| d > 0), Some(track.position)),
+                    title: track.title.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Whether importing changes no title or position and leaves nothing
+    /// over.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.unmatched.is_empty()
+            && self.missing.is_empty()
+            && self
+                .tracks
+                .iter()
+                .all(|track| !track.title_changed() && !track.position_changed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::musicbrainz::{Medium, Recording};
+    use crate::testutil;
+    use crate::AudioFormat;
+
+    fn item(title: &str, track: Option<u32>) -> Item {
+        Item {
+            path: format!("/in/{title}.flac").into(),
+            title: title.into(),
+            artist: "Black Sabbath".into(),
+            album: "Paranoid".into(),
+            track,
+            format: AudioFormat::Flac,
+            bitrate: 900,
+            length: 300.0,
+            ..testutil::item()
+        }
+    }
+
+    fn release(titles: &[&str]) -> Release {
+        let tracks = (1..)
+            .zip(titles)
+            .map(|(position, &title)| Track {
+                id: format!("track-{position}"),
+                position,
+                number: position.to_string(),
+                title: title.into(),
+                length: None,
+                recording: Recording {
+                    id: format!("recording-{position}"),
+                    title: title.into(),
+                    length: None,
+                },
+                artist_credit: Vec::new(),
+            })
+            .collect();
+        Release {
+            id: "paranoid".into(),
+            title: "Paranoid".into(),
+            disambiguation: String::new(),
+            date: None,
+            artist_credit: Vec::new(),
+            country: None,
+            media: vec![Medium {
+                position: 1,
+                format: None,
+                track_count: titles.len(),
+                tracks,
+            }],
+            label_info: Vec::new(),
+            release_group: None,
+            score: 100,
+        }
+    }
+
+    #[test]
+    fn test_changes_follow_the_release_order() {
+        let items = [item("Paranod", Some(2)), item("war pigs", None), item("Bonus", Some(9))];
+        let release = release(&["War Pigs", "Paranoid", "Planet Caravan"]);
+        let matches = [
+            TrackMatch {
+                item: 0,
+                track: 1,
+                similarity: 0.9,
+            },
+            TrackMatch {
+                item: 1,
+                track: 0,
+                similarity: 1.0,
+            },
+        ];
+        let changes = AlbumChanges::new(&items, &release, &matches);
+        assert!(!changes.is_empty());
+
+        let titles: Vec<(&str, &str)> = changes
+            .tracks
+            .iter()
+            .map(|track| (track.old_title.as_str(), track.new_title.as_str()))
+            .collect();
+        assert_eq!(titles, [("war pigs", "War Pigs"), ("Paranod", "Paranoid")]);
+        let war_pigs = &changes.tracks[0];
+        assert!(war_pigs.title_changed() && war_pigs.position_changed());
+        assert_eq!(war_pigs.new_position, (Some(1), Some(1)));
+        assert_eq!(changes.tracks[1].old_position, (None, Some(2)));
+
+        assert_eq!(changes.unmatched, [PathBuf::from("/in/Bonus.flac")]);
+        assert_eq!(
+            changes.missing,
+            [MissingTrack {
+                position: (Some(1), Some(3)),
+                title: "Planet Caravan".into(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_nothing_to_show_when_tags_already_agree() {
+        let mut tagged = item("War Pigs", Some(1));
+        tagged.disc = Some(1);
+        let release = release(&["War Pigs"]);
+        let matches = [TrackMatch {
+            item: 0,
+            track: 0,
+            similarity: 1.0,
+        }];
+        assert!(AlbumChanges::new(&[tagged.clone()], &release, &matches).is_empty());
+
+        // Out-of-range matches are dropped, leaving both sides over
+        let bogus = [TrackMatch {
+            item: 3,
+            track: 0,
+            similarity: 1.0,
+        }];
+        let changes = AlbumChanges::new(&[tagged], &release, &bogus);
+        assert!(changes.tracks.is_empty());
+        assert_eq!((changes.unmatched.len(), changes.missing.len()), (1, 1));
+    }
+}
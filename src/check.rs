@@ -0,0 +1,202 @@
+//! Consistency check between the database and the files on disk
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Duration, Utc};
+use rayon::prelude::*;
+
+use crate::exists::ExistenceCheck;
+use crate::import::audio_files;
+use crate::{Item, Result};
+
+/// Problems found by [`check_library`].
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    /// Items whose file no longer exists.
+    pub missing: Vec<Item>,
+    /// Items whose file changed since it was last read; candidates for `update`.
+    pub modified: Vec<Item>,
+    /// Audio files under the library directory that no item refers to.
+    pub untracked: Vec<PathBuf>,
+}
+
+impl CheckReport {
+    /// Total number of problems found.
+    #[must_use]
+    pub fn problem_count(&self) -> usize {
+        self.missing.len() + self.modified.len() + self.untracked.len()
+    }
+
+    #[must_use]
+    pub fn is_clean(&self) -> bool {
+        self.problem_count() == 0
+    }
+}
+
+/// Compare `items` against the filesystem and look for untracked audio files
+/// under `library_dir`, including files with `extra_extensions`.
+///
+/// Files are stat'ed in parallel. A file counts as modified when its mtime
+/// (to the second) or size differs from what was stored; items without a
+/// stored size are compared by mtime alone.
+///
+/// # Errors
+/// Returns an error if the existence check cannot run.
+pub fn check_library(
+    items: Vec<Item>,
+    library_dir: &Path,
+    extra_extensions: &[String],
+    existence: &ExistenceCheck,
+) -> Result<CheckReport> {
+    let tracked: HashSet<&Path> = items.iter().map(|item| item.path.as_path()).collect();
+    let untracked = audio_files(library_dir, extra_extensions)
+        .into_iter()
+        .filter(|path| !tracked.contains(path.as_path()))
+        .collect();
+
+    // Index by position: items not yet in the database have no id
+    let paths = (0..).zip(&items).map(|(i, item)| (i, item.path.clone())).collect();
+    let gone: HashSet<i64> = existence.missing(paths, || {})?.into_iter().collect();
+
+    let (missing, present): (Vec<_>, Vec<_>) =
+        (0..).zip(items).partition(|(i, _)| gone.contains(i));
+    let modified = present
+        .into_par_iter()
+        .map(|(_, item)| item)
+        .filter(is_modified)
+        .collect();
+
+    Ok(CheckReport {
+        missing: missing.into_iter().map(|(_, item)| item).collect(),
+        modified,
+        untracked,
+    })
+}
+
+/// How far a file's mtime may be past the stored one before `update` counts
+/// it as changed, for filesystems that keep mtimes to the second or coarser.
+const MTIME_SLACK_SECS: i64 = 1;
+
+/// What became of an item's file since it was last read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileState {
+    Unchanged,
+    Changed,
+    Missing,
+}
+
+/// The state of each of `items`' files, in order, from stat'ing them in
+/// parallel.
+///
+/// A file is unchanged while its size matches the stored one (if any) and
+/// its mtime is no more than [`MTIME_SLACK_SECS`] past the stored mtime. A
+/// file that can't be stat'ed for another reason than being gone counts as
+/// changed, so reading it reports what's wrong.
+#[must_use]
+pub fn file_states(items: &[Item]) -> Vec<FileState> {
+    items.par_iter().map(file_state).collect()
+}
+
+fn file_state(item: &Item) -> FileState {
+    let metadata = match std::fs::metadata(&item.path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return FileState::Missing,
+        Err(_) => return FileState::Changed,
+    };
+    if item.size.is_some_and(|size| size != metadata.len()) {
+        return FileState::Changed;
+    }
+    let slack = Duration::seconds(MTIME_SLACK_SECS);
+    match metadata.modified() {
+        Ok(mtime) if item.mtime + slack >= DateTime::<Utc>::from(mtime) => FileState::Unchanged,
+        _ => FileState::Changed,
+    }
+}
+
+fn is_modified(item: &Item) -> bool {
+    // Vanished since the existence check; `missing` will catch it next run
+    let Ok(metadata) = std::fs::metadata(&item.path) else {
+        return false;
+    };
+
+    let size_changed = item.size.is_some_and(|size| size != metadata.len());
+    let mtime_changed = metadata
+        .modified()
+        .is_ok_and(|mtime| DateTime::<Utc>::from(mtime).timestamp() != item.mtime.timestamp());
+
+    size_changed || mtime_changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tags::read_tags;
+    use crate::testutil::wav_bytes;
+
+    #[test]
+    fn test_check_library() {
+        let dir = std::env::temp_dir().join(format!("rsbts-check-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in ["ok", "gone", "changed", "untracked"] {
+            std::fs::write(dir.join(format!("{name}.wav")), wav_bytes(800)).unwrap();
+        }
+
+        let items: Vec<Item> = ["ok", "gone", "changed"]
+            .iter()
+            .map(|name| read_tags(&dir.join(format!("{name}.wav"))).unwrap())
+            .collect();
+        std::fs::remove_file(dir.join("gone.wav")).unwrap();
+        std::fs::write(dir.join("changed.wav"), wav_bytes(1600)).unwrap();
+
+        let report = check_library(items, &dir, &[], &ExistenceCheck::default()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(report.problem_count(), 3);
+        assert!(report.missing[0].path.ends_with("gone.wav"));
+        assert!(report.modified[0].path.ends_with("changed.wav"));
+        assert!(report.untracked[0].ends_with("untracked.wav"));
+    }
+
+    #[test]
+    fn test_file_states() {
+        let dir = std::env::temp_dir().join(format!("rsbts-states-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(format!("{name}.wav"));
+        for name in ["same", "coarse", "touched", "resized", "gone"] {
+            std::fs::write(path(name), wav_bytes(800)).unwrap();
+        }
+        let items: Vec<Item> = ["same", "coarse", "touched", "resized", "gone"]
+            .iter()
+            .map(|name| read_tags(&path(name)).unwrap())
+            .collect();
+
+        let set_mtime = |name: &str, mtime: DateTime<Utc>| {
+            let file = std::fs::File::options().write(true).open(path(name)).unwrap();
+            file.set_modified(mtime.into()).unwrap();
+        };
+        // Within the slack, as a filesystem rounding mtimes up might leave it
+        set_mtime("coarse", items[1].mtime + Duration::milliseconds(900));
+        set_mtime("touched", items[2].mtime + Duration::seconds(5));
+        std::fs::write(path("resized"), wav_bytes(1600)).unwrap();
+        std::fs::remove_file(path("gone")).unwrap();
+
+        let states = file_states(&items);
+        let mut unknown_size = items[2].clone();
+        unknown_size.size = None;
+        let touched = file_states(&[unknown_size]);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            states,
+            [
+                FileState::Unchanged,
+                FileState::Unchanged,
+                FileState::Changed,
+                FileState::Changed,
+                FileState::Missing,
+            ]
+        );
+        assert_eq!(touched, [FileState::Changed]);
+    }
+}
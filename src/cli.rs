@@ -1,185 +1,3973 @@
-use std::path::PathBuf;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 
 use anyhow::{Context, Result};
+use chrono::{DateTime, Datelike, Utc};
+use clap::builder::{PossibleValue, PossibleValuesParser};
+use clap::error::ErrorKind;
+use clap::{Command, CommandFactory};
+use clap_complete::Shell;
+use indicatif::ProgressBar;
+use log::{debug, info, warn, Level, LevelFilter};
 
-use rsbts::config::Config;
-use rsbts::db::Database;
-use rsbts::import::Action;
+use rsbts::archive;
+use rsbts::art::ArtLimits;
+use rsbts::beets;
+use rsbts::changes::{AlbumChanges, Position};
+use rsbts::config::{Config, ConfigLocation, Override};
+use rsbts::db::{AlbumStats, AttributeOwner, Database, FtsMode, StatsGroup};
+use rsbts::dedup::{self, AlbumCopy, FormatPreference, Ranking};
+use rsbts::exists::ExistenceCheck;
+use rsbts::export::{self, ExportFormat};
+use rsbts::external;
+use rsbts::fields::{
+    album_value, item_value, query_fields, AttributeEdit, Field, FieldChange, FieldEdit, Value,
+    ALBUM_COLUMNS, ALBUM_FIELDS, ATTRIBUTE_PREFIX, ITEM_FIELDS,
+};
+use rsbts::format::Formatter;
+use rsbts::genres::{Genres, Rule};
+use rsbts::hooks::{Event, Hooks};
+use rsbts::import::{
+    only_filter, Action, ConsoleProgress, ImportConfig, Importer, QuietFallback,
+    ReleasePreferences, ScanOptions, ScanReport,
+};
+use rsbts::lock::{LibraryLock, LockMode};
+use rsbts::playlist;
+use rsbts::prune::Pruner;
+use rsbts::query::Page;
+use rsbts::random::Amount;
+use rsbts::replaygain::{self, Analyzed};
+use rsbts::metadata_cache::MetadataCache;
+use rsbts::musicbrainz::{Client as MbClient, MetadataSource, NullSource};
+use rsbts::tags::{
+    analyze_file, embed_art, write_replaygain, write_tags, AnalyzeOptions, StdFileOps,
+};
+use rsbts::update::Corrected;
+use rsbts::{Album, AudioFormat, Item};
 
-use crate::Commands;
+use crate::{CacheCommands, Cli, Commands, DbCommands, QueryCommands};
+
+/// Conventional exit code for termination by SIGINT.
+const EXIT_INTERRUPTED: i32 = 130;
+
+/// How a command ended, which decides the process's exit status.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// Exit status 0.
+    Success,
+    /// 1: a problem none of the others covers, or `check` finding problems
+    /// it didn't fix.
+    Failed,
+    /// 3: the query matched nothing, with `--fail-on-empty`.
+    NothingMatched,
+    /// 4: `MusicBrainz`, the Cover Art Archive or `AcoustID` couldn't be used.
+    Network,
+    /// 5: an import finished, but some of its albums couldn't be imported.
+    PartialImport,
+    /// 6: another process holds the library lock.
+    Locked,
+    /// 7: a query or field assignment couldn't be parsed. Not 2, which the
+    /// argument parser exits with for a bad command line.
+    BadQuery,
+    /// An external command's own exit status.
+    External(u8),
+}
+
+impl Outcome {
+    /// The outcome of a command that stopped with `error`, going by the
+    /// first library error in its chain.
+    pub fn of_error(error: &anyhow::Error) -> Self {
+        match error.chain().find_map(|e| e.downcast_ref::<rsbts::Error>()) {
+            Some(rsbts::Error::Query(_)) => Self::BadQuery,
+            Some(rsbts::Error::MusicBrainz(_) | rsbts::Error::AcoustId(_)) => Self::Network,
+            Some(rsbts::Error::Locked(_)) => Self::Locked,
+            _ => Self::Failed,
+        }
+    }
+
+    /// The outcome of a command whose query matched `count` things.
+    const fn matched(count: u64, fail_on_empty: bool) -> Self {
+        if count == 0 && fail_on_empty {
+            Self::NothingMatched
+        } else {
+            Self::Success
+        }
+    }
+
+    /// The process exit status.
+    pub const fn code(self) -> u8 {
+        match self {
+            Self::Success => 0,
+            Self::Failed => 1,
+            Self::NothingMatched => 3,
+            Self::Network => 4,
+            Self::PartialImport => 5,
+            Self::Locked => 6,
+            Self::BadQuery => 7,
+            Self::External(code) => code,
+        }
+    }
+}
+
+impl From<Outcome> for ExitCode {
+    fn from(outcome: Outcome) -> Self {
+        Self::from(outcome.code())
+    }
+}
 
 // rusqlite::Connection is not Sync, so futures holding &Database aren't Send
 #[allow(clippy::future_not_send)]
-pub async fn run(command: Commands, config_path: Option<PathBuf>) -> Result<()> {
-    let config = Config::load(config_path.as_deref())?;
-    let db = Database::open(&config.library.database)?;
-    db.migrate()?;
+pub async fn run(
+    command: Commands,
+    location: ConfigLocation,
+    lock_mode: LockMode,
+    stable: bool,
+    no_hooks: bool,
+    quiet: bool,
+    sets: &[Override],
+) -> Result<Outcome> {
+    let command = match command {
+        // Writes the config file, so it mustn't need one
+        Commands::Init { library, force } => {
+            init(&location, library, force)?;
+            return Ok(Outcome::Success);
+        }
+        // Only describe the command line, so they work without a library
+        Commands::Completions { shell } => {
+            completions(shell, &mut std::io::stdout())?;
+            return Ok(Outcome::Success);
+        }
+        Commands::Fields { json } => {
+            fields(&mut std::io::stdout(), json)?;
+            return Ok(Outcome::Success);
+        }
+        // Passes the config on, but a mistyped name mustn't create a library
+        Commands::External(args) => return run_external(&location, sets, &args),
+        command => command,
+    };
+    let config = Config::load(&location, sets)?;
+    let hooks = if no_hooks {
+        Hooks::disabled()
+    } else {
+        Hooks::new(config.hooks.clone())
+    };
+
+    // Held until this function returns, including on error or panic
+    let _lock = if mutates_library(&command) {
+        let lock_path = LibraryLock::path_for(&config.library.database);
+        let lock = LibraryLock::acquire(&lock_path, lock_mode).await?;
+        // `watch` stops on Ctrl-C itself, once the album being imported is in
+        if !matches!(command, Commands::Watch { .. }) {
+            release_lock_on_interrupt(lock_path);
+        }
+        Some(lock)
+    } else {
+        None
+    };
+
+    let mut db = Database::open(&config.library.database)?;
+    if let Some(sort) = &config.library.default_sort {
+        db.set_default_sort(sort).context("Invalid library.default_sort")?;
+    }
+    db.set_path_mappings(config.library.path_mappings());
+    // `db migrate` decides itself how far to go
+    if !matches!(
+        command,
+        Commands::Db {
+            command: DbCommands::Migrate { .. }
+        }
+    ) {
+        db.migrate()?;
+        db.check_portable(config.library.root.is_some())?;
+        db.set_fts_mode(config.library.fts)?;
+    }
+    let fmt = if stable {
+        Formatter::stable()
+    } else {
+        Formatter::new(&config.ui)
+    };
 
     match command {
-        Commands::Import { paths, copy, r#move } => {
-            let action = if copy {
-                Action::Copy
-            } else if r#move {
-                Action::Move
+        Commands::Import {
+            paths,
+            copy,
+            r#move,
+            link,
+            hardlink,
+            reflink,
+            only,
+            format,
+            newer_than,
+            max_depth,
+            no_autotag,
+            keep_empty_dirs,
+            force_inside_library,
+            error_log,
+            log,
+            from_log,
+        } => {
+            let action = [
+                (copy, Action::Copy),
+                (r#move, Action::Move),
+                (link, Action::Link),
+                (hardlink, Action::HardLink),
+                (reflink, Action::Reflink),
+            ]
+            .into_iter()
+            .find_map(|(set, action)| set.then_some(action))
+            .unwrap_or(config.import.action);
+            let mut settings = import_config(&config, action)?;
+            settings.only = only.as_deref().map(only_filter).transpose()?;
+            settings.keep_empty_dirs = keep_empty_dirs;
+            settings.inside_library = force_inside_library;
+            settings.scan = scan_options(&format, newer_than.as_deref(), max_depth)?;
+            if quiet {
+                settings.confirm_merge = None;
+                settings.show_changes = None;
+            } else {
+                settings.quiet_fallback = QuietFallback::AsIs;
+            }
+            let mut paths = paths;
+            let mut files = Vec::new();
+            if let Some(from_log) = &from_log {
+                // Files logged one by one go in together, to make albums again
+                let (logged, dirs): (Vec<_>, Vec<_>) = read_skip_log(from_log)?
+                    .into_iter()
+                    .partition(|path| path.is_file() && !rsbts::bundle::is_bundle(path));
+                paths.extend(dirs);
+                files = logged;
+            }
+            let (error_log, log) = (error_log.as_deref(), log.as_deref());
+            if no_autotag {
+                // Without lookups there is nothing to fingerprint against either
+                settings.acoustid_api_key = None;
+                // ...nor anything to fall back from
+                settings.quiet_fallback = QuietFallback::AsIs;
+                let importer = Importer::with_source(&db, settings, NullSource)?
+                    .with_progress(ConsoleProgress::new())
+                    .with_hooks(hooks.clone());
+                return import(&db, &hooks, &importer, &paths, files, error_log, log).await;
+            }
+            let importer = Importer::new(&db, settings)?
+                .with_progress(ConsoleProgress::new())
+                .with_hooks(hooks.clone());
+            return import(&db, &hooks, &importer, &paths, files, error_log, log).await;
+        }
+        Commands::ImportBeets {
+            path,
+            move_into_library,
+        } => import_beets(&db, &config, &hooks, &path, move_into_library)?,
+        Commands::Watch { dir, once } => watch(&db, &config, &hooks, dir, once).await?,
+        Commands::Sessions => sessions(&db, &fmt)?,
+        Commands::Recent {
+            days,
+            since,
+            album,
+            group_by,
+            json,
+        } => {
+            let since = since.unwrap_or_else(|| format!("{}d", days.unwrap_or(14)));
+            let groups = recent(&db, rsbts::query::date_start(&since)?)?;
+            let mut out = std::io::stdout().lock();
+            if json {
+                print_recent_json(&mut out, &groups, group_by)?;
+            } else {
+                print_recent(&mut out, &fmt, &groups, album, group_by)?;
+            }
+        }
+        #[cfg(feature = "server")]
+        Commands::Serve { bind } => {
+            let bind = bind.unwrap_or_else(|| config.server.bind.clone());
+            let art_cache = dirs::cache_dir()
+                .unwrap_or_else(std::env::temp_dir)
+                .join("rsbts/art");
+            let queries = rsbts::server::Queries {
+                default_query: config.ui.default_query.clone(),
+                bookmarks: config.bookmarks.clone(),
+            };
+            let token = config.server.token.clone();
+            let router = rsbts::server::router(db, art_cache, token, queries);
+            rsbts::server::serve(router, &bind).await?;
+        }
+        // Without --session, clap has made sure of --last
+        Commands::Undo { session, yes, .. } => {
+            let session = match session {
+                Some(session) => session,
+                None => {
+                    let newest = db.import_runs()?.into_iter().next();
+                    newest.context("No import sessions recorded")?.run.id
+                }
+            };
+            let pruner = Pruner::new(&config.library.directory, &config.import.clutter);
+            undo(&db, &hooks, &pruner, &session, yes)?;
+        }
+        Commands::List {
+            query,
+            album,
+            long,
+            missing,
+            limit,
+            offset,
+            count,
+            no_default_query,
+            fail_on_empty,
+        } => {
+            let query = resolve_query(&config, query.as_deref(), !no_default_query)?;
+            let matched = if album {
+                list_albums(&db, &fmt, query.as_deref(), long)?
+            } else {
+                let missing = if missing {
+                    Some(missing_ids(&db, &config)?)
+                } else {
+                    None
+                };
+                let page = Page {
+                    limit,
+                    offset: offset.unwrap_or(0),
+                };
+                if count {
+                    let count = db.count_items(query.as_deref())?;
+                    println!("{count}");
+                    count
+                } else {
+                    let mut out = std::io::stdout().lock();
+                    list(&mut out, &db, &fmt, query.as_deref(), page, missing.as_ref())?
+                }
+            };
+            return Ok(Outcome::matched(matched, fail_on_empty));
+        }
+        Commands::Info { query } => {
+            let query = expand_query(&config, &query)?;
+            info(&db, &fmt, &query)?;
+        }
+        Commands::Stats {
+            query,
+            no_default_query,
+            json,
+            by,
+            verify,
+        } => {
+            let query = resolve_query(&config, query.as_deref(), !no_default_query)?;
+            if let Some(by) = by {
+                grouped_stats(&db, &fmt, query.as_deref(), by, json)?;
+            } else {
+                stats(&db, &config, &fmt, query.as_deref(), json, verify)?;
+            }
+        }
+        Commands::Genres {
+            query,
+            normalize,
+            write,
+            nowrite,
+            yes,
+        } => {
+            let query = query.map(|q| expand_query(&config, &q)).transpose()?;
+            if normalize {
+                let write = write || (config.import.write_tags && !nowrite);
+                let genres = config.genres.genres();
+                normalize_genres(&db, &hooks, &genres, query.as_deref(), write, yes)?;
+            } else {
+                list_genres(&db, &fmt, query.as_deref())?;
+            }
+        }
+        Commands::Export {
+            archive: Some(dir),
+            keep,
+            ..
+        } => export_archive(&db, &fmt, &dir, keep)?,
+        Commands::Export {
+            query,
+            format,
+            output,
+            fields,
+            strip_paths,
+            album,
+            no_default_query,
+            archive: None,
+            keep: _,
+        } => {
+            let format: ExportFormat = format.parse()?;
+            let mut out = output_file(output.as_deref())?;
+            if album {
+                let query = resolve_query(&config, query.as_deref(), !no_default_query)?;
+                let mut fields = export::select_fields(ALBUM_COLUMNS, fields.as_deref())?;
+                if strip_paths {
+                    fields = export::strip_paths(fields)?;
+                }
+                let albums = db.query_albums(query.as_deref())?;
+                export::write(&mut out, format, &fields, &albums, album_value)?;
+            } else {
+                let query = resolve_query(&config, query.as_deref(), !no_default_query)?;
+                let mut fields = export::select_fields(ITEM_FIELDS, fields.as_deref())?;
+                if strip_paths {
+                    fields = export::strip_paths(fields)?;
+                }
+                let items = db.query_items(query.as_deref())?;
+                export::write(&mut out, format, &fields, &items, item_value)?;
+            }
+            std::io::Write::flush(&mut out)?;
+        }
+        Commands::Playlist {
+            query,
+            output,
+            relative_to,
+            absolute: _,
+            limit,
+            random,
+            no_default_query,
+        } => {
+            let query = resolve_query(&config, query.as_deref(), !no_default_query)?;
+            let mut items = db.query_items(query.as_deref())?;
+            if random {
+                playlist::shuffle(&mut items, playlist::random_seed());
+            }
+            if let Some(limit) = limit {
+                items.truncate(limit);
+            }
+            let base = relative_to
+                .map(|dir| std::env::current_dir().map(|cwd| cwd.join(dir)))
+                .transpose()?;
+            let mut out = output_file(output.as_deref())?;
+            let not_utf8 = playlist::write(&mut out, &items, base.as_deref())?;
+            std::io::Write::flush(&mut out)?;
+            for path in not_utf8 {
+                warn!("Path is not valid UTF-8, written as raw bytes: {}", path.display());
+            }
+        }
+        Commands::Random {
+            query,
+            number,
+            time,
+            album,
+            playlist: path,
+            seed,
+            no_default_query,
+        } => {
+            let query = resolve_query(&config, query.as_deref(), !no_default_query)?;
+            let amount = match time {
+                Some(minutes) => Amount::Seconds(minutes * 60.0),
+                None => Amount::Count(number.unwrap_or(1)),
+            };
+            let items = random(&db, query.as_deref(), amount, album, seed)?;
+            if let Some(path) = path {
+                let mut out = output_file(Some(&path))?;
+                let not_utf8 = playlist::write(&mut out, &items, None)?;
+                std::io::Write::flush(&mut out)?;
+                for path in not_utf8 {
+                    warn!("Path is not valid UTF-8, written as raw bytes: {}", path.display());
+                }
+            } else {
+                for item in &items {
+                    println!("{}", list_line(&fmt, item));
+                }
+            }
+        }
+        Commands::Query { command } => match command {
+            QueryCommands::Explain {
+                query,
+                no_default_query,
+            } => {
+                explain(&db, &config, query.as_deref(), !no_default_query)?;
+            }
+        },
+        Commands::Db { command } => match command {
+            DbCommands::Reindex => reindex(&db)?,
+            DbCommands::Migrate { dry_run, to } => migrate(&db, dry_run, to)?,
+            DbCommands::Restore { file, verify } => restore(&db, &fmt, &file, verify)?,
+            DbCommands::Backup { path, force } => backup(&db, &fmt, &path, force)?,
+            DbCommands::Vacuum => vacuum(&db, &fmt, &config.library.database)?,
+            DbCommands::Check { rebuild_fts } => return db_check(&db, &fmt, rebuild_fts),
+            DbCommands::RewritePaths { from, to } => rewrite_paths(&db, &fmt, &from, &to)?,
+        },
+        Commands::Missing {
+            query,
+            count,
+            total,
+        } => {
+            let mode = if total {
+                MissingMode::Total
+            } else if count {
+                MissingMode::Count
+            } else {
+                MissingMode::Tracks
+            };
+            missing(&db, &config, &fmt, query.as_deref(), mode).await?;
+        }
+        Commands::Duplicates {
+            album,
+            delete,
+            format_preference,
+            yes,
+        } => {
+            let prefs: FormatPreference = format_preference.parse()?;
+            if album {
+                duplicate_albums(&db, &fmt, &hooks, &mut Terminal, &prefs, delete, yes)?;
+            } else {
+                let with_art = db
+                    .query_albums(None)?
+                    .into_iter()
+                    .filter(|album| album.artpath.is_some())
+                    .filter_map(|album| album.id)
+                    .collect();
+                let ranking = Ranking::new(config.dedupe.prefer.clone(), prefs).with_art(with_art);
+                duplicates(&db, &fmt, &hooks, &mut Terminal, &ranking, delete, yes)?;
+            }
+        }
+        Commands::Cache { command } => match command {
+            CacheCommands::Stats => cache_stats(&db, &fmt)?,
+            CacheCommands::Clear => {
+                let removed = db.clear_cache()?;
+                println!("Removed {} cached entries", fmt.count(removed as u64));
+            }
+        },
+        Commands::Update {
+            query,
+            force,
+            prune,
+            prefer_file,
+            write,
+        } => {
+            let corrected = if prefer_file {
+                Corrected::PreferFile
+            } else if write {
+                Corrected::Write
+            } else {
+                Corrected::Keep
+            };
+            let query = resolve_query(&config, query.as_deref(), false)?;
+            update(&db, &fmt, &hooks, query.as_deref(), force, prune, corrected)?;
+        }
+        Commands::Retag {
+            query,
+            force,
+            pretend,
+            write,
+            nowrite,
+        } => {
+            let query = resolve_query(&config, query.as_deref(), false)?;
+            let write = write || (config.import.write_tags && !nowrite);
+            retag(&db, &config, &hooks, query.as_deref(), force, pretend, write).await?;
+        }
+        Commands::Art {
+            query,
+            fetch,
+            extract,
+            embed,
+            show: _,
+            max_size,
+            max_bytes,
+            pretend,
+        } => {
+            let mode = if fetch {
+                ArtMode::Fetch
+            } else if extract {
+                ArtMode::Extract
+            } else if embed {
+                ArtMode::Embed(ArtLimits {
+                    max_edge: max_size,
+                    max_bytes,
+                })
             } else {
-                config.import.action
+                ArtMode::Show
             };
-            import(&db, &config, &paths, action).await?;
+            let query = resolve_query(&config, query.as_deref(), false)?;
+            art(&db, &config, query.as_deref(), mode, pretend).await?;
         }
-        Commands::List { query, album } => {
-            list(&db, query.as_deref(), album)?;
+        Commands::ReplayGain {
+            query,
+            write,
+            force,
+        } => {
+            let query = resolve_query(&config, query.as_deref(), false)?;
+            replaygain(&db, query.as_deref(), write, force)?;
+        }
+        Commands::Remove {
+            query,
+            delete,
+            keep_empty_dirs,
+            yes,
+            fail_on_empty,
+        } => {
+            let query = expand_query(&config, &query)?;
+            let pruner = Pruner::new(&config.library.directory, &config.import.clutter);
+            let delete = match (delete, keep_empty_dirs) {
+                (false, _) => Delete::No,
+                (true, true) => Delete::Files,
+                (true, false) => Delete::FilesAndDirs(&pruner),
+            };
+            let mut out = std::io::stdout();
+            let removed = remove(&mut out, &mut Terminal, &db, &hooks, &query, delete, yes)?;
+            return Ok(Outcome::matched(removed.matched as u64, fail_on_empty));
         }
-        Commands::Stats => {
-            stats(&db)?;
+        Commands::Modify {
+            query,
+            fields,
+            album,
+            write,
+            nowrite,
+            yes,
+            pretend,
+            force,
+            all,
+            fail_on_empty,
+        } => {
+            let options = ModifyOptions {
+                separator: &config.modify.separator,
+                write: write || (config.import.write_tags && !nowrite),
+                yes,
+                pretend,
+                all,
+                limit: (!force).then_some(config.safety.max_modify_without_force),
+            };
+            let query = expand_query(&config, &query)?;
+            let matched = if album {
+                modify_albums(&mut Terminal, &db, &config, &hooks, &query, &fields, &options)?
+            } else {
+                modify(&mut Terminal, &db, &hooks, &query, &fields, &options)?
+            };
+            return Ok(Outcome::matched(matched as u64, fail_on_empty));
         }
-        Commands::Update { query } => {
-            update(&db, query.as_deref())?;
+        Commands::Rate {
+            query,
+            value,
+            fail_on_empty,
+        } => {
+            let query = expand_query(&config, &query)?;
+            let matched = rate(&db, &hooks, &query, &value)?;
+            return Ok(Outcome::matched(matched as u64, fail_on_empty));
         }
-        Commands::Remove { query, delete } => {
-            remove(&db, &query, delete)?;
+        Commands::Played {
+            query,
+            fail_on_empty,
+        } => {
+            let query = expand_query(&config, &query)?;
+            let matched = played(&db, &query, Utc::now())?;
+            return Ok(Outcome::matched(matched as u64, fail_on_empty));
         }
-        Commands::Modify { query, fields } => {
-            modify(&db, &query, &fields)?;
+        Commands::Check {
+            fix_missing,
+            add_untracked,
+        } => {
+            let options = CheckOptions {
+                fix_missing,
+                add_untracked,
+                quiet,
+            };
+            return check(&db, &config, &fmt, &hooks, options).await;
         }
+        // Handled before the library is opened
+        Commands::Init { .. }
+        | Commands::Completions { .. }
+        | Commands::Fields { .. }
+        | Commands::External(_) => {}
     }
 
-    Ok(())
+    Ok(Outcome::Success)
+}
+
+/// Commands that move files or rewrite rows and so must not run concurrently.
+const fn mutates_library(command: &Commands) -> bool {
+    matches!(
+        command,
+        Commands::Import { .. }
+            | Commands::Update { .. }
+            | Commands::ReplayGain { .. }
+            | Commands::Retag { pretend: false, .. }
+            | Commands::Art {
+                pretend: false,
+                fetch: true,
+                ..
+            }
+            | Commands::Art {
+                pretend: false,
+                extract: true,
+                ..
+            }
+            | Commands::Art {
+                pretend: false,
+                embed: true,
+                ..
+            }
+            | Commands::ImportBeets { .. }
+            | Commands::Watch { .. }
+            | Commands::Undo { .. }
+            | Commands::Remove { .. }
+            | Commands::Modify { pretend: false, .. }
+            | Commands::Rate { .. }
+            | Commands::Played { .. }
+            | Commands::Genres {
+                normalize: true,
+                ..
+            }
+            | Commands::Db {
+                command: DbCommands::Restore { verify: false, .. }
+            }
+            | Commands::Db {
+                command: DbCommands::Migrate { dry_run: false, .. }
+            }
+            | Commands::Db {
+                command: DbCommands::Vacuum | DbCommands::Check { rebuild_fts: true }
+            }
+            | Commands::Db {
+                command: DbCommands::Reindex
+            }
+            | Commands::Db {
+                command: DbCommands::RewritePaths { .. }
+            }
+            | Commands::Duplicates { delete: true, .. }
+            | Commands::Check {
+                fix_missing: true,
+                ..
+            }
+            | Commands::Check {
+                add_untracked: true,
+                ..
+            }
+    )
+}
+
+/// Remove the lock file and exit if the user presses Ctrl-C.
+///
+/// Ctrl-C terminates the process without unwinding, so the lock guard's
+/// destructor would never run.
+fn release_lock_on_interrupt(lock_path: PathBuf) {
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            let _ = std::fs::remove_file(&lock_path);
+            eprintln!("Interrupted");
+            std::process::exit(EXIT_INTERRUPTED);
+        }
+    });
 }
 
 // rusqlite::Connection is not Sync, so futures holding &Database aren't Send
 #[allow(clippy::future_not_send)]
-async fn import(
+async fn watch(
     db: &Database,
     config: &Config,
-    paths: &[PathBuf],
-    action: Action,
+    hooks: &Hooks,
+    dir: Option<PathBuf>,
+    once: bool,
 ) -> Result<()> {
-    use rsbts::import::{ImportConfig, Importer};
-
-    let import_config = ImportConfig {
-        action,
-        fetch_art: config.import.fetch_art,
-        path_format: config.paths.format.clone(),
-        library_dir: config.library.directory.clone(),
+    let Some(dir) = dir.or_else(|| config.import.watch_directory.clone()) else {
+        anyhow::bail!("No directory to watch: give one or set import.watch_directory");
     };
+    let quiet = std::time::Duration::from_secs(config.import.watch_quiet_seconds);
+    // Nobody is there to answer questions or read what changes
+    let settings = ImportConfig {
+        confirm_merge: None,
+        show_changes: None,
+        ..import_config(config, config.import.action)?
+    };
+    rsbts::watch::watch(db, hooks, &dir, quiet, once, || settings.clone()).await?;
+    Ok(())
+}
 
-    let importer = Importer::new(db, import_config)?;
-
+// rusqlite::Connection is not Sync, so futures holding &Database aren't Send
+#[allow(clippy::future_not_send)]
+async fn import<S: MetadataSource + 'static>(
+    db: &Database,
+    hooks: &Hooks,
+    importer: &Importer<'_, S>,
+    paths: &[PathBuf],
+    files: Vec<PathBuf>,
+    error_log: Option<&Path>,
+    skip_log: Option<&Path>,
+) -> Result<Outcome> {
+    // Refuse before importing anything rather than part way through
+    for path in paths.iter().chain(&files) {
+        importer.check_source(path)?;
+    }
+    let mut report = ScanReport::default();
     for path in paths {
-        importer
-            .import(path)
-            .await
-            .with_context(|| format!("Failed to import {}", path.display()))?;
+        match importer.import(path).await {
+            Ok(path_report) => report.extend(path_report),
+            Err(e) => {
+                warn!("Failed to import {}: {e}", path.display());
+                report.failed_albums.push((path.display().to_string(), e));
+            }
+        }
+    }
+    if !files.is_empty() {
+        match importer.import_files(files).await {
+            Ok(files_report) => report.extend(files_report),
+            Err(e) => {
+                warn!("Failed to import the logged files: {e}");
+                report.failed_albums.push(("logged files".into(), e));
+            }
+        }
     }
 
-    Ok(())
+    log_cache_counts(importer);
+    print_scan_report(&report);
+    if db.import_run(importer.run_id())?.is_some() {
+        println!(
+            "\nImport session {0}; undo with `rsbts undo --session {0}`",
+            importer.run_id()
+        );
+        hooks.imported(db, importer.run_id())?;
+    }
+    if let Some(log_path) = error_log {
+        write_error_log(log_path, &report)
+            .with_context(|| format!("Failed to write error log {}", log_path.display()))?;
+    }
+    if let Some(log_path) = skip_log {
+        write_skip_log(log_path, &report)
+            .with_context(|| format!("Failed to write skip log {}", log_path.display()))?;
+    }
+
+    Ok(if report.failed_albums.is_empty() {
+        Outcome::Success
+    } else {
+        Outcome::PartialImport
+    })
 }
 
-fn list(db: &Database, query: Option<&str>, album: bool) -> Result<()> {
-    if album {
-        let albums = db.query_albums(query)?;
-        for album in albums {
-            let year = album.year.map_or_else(String::new, |y| format!(" ({y})"));
-            println!("{} - {}{}", album.albumartist, album.album, year);
+fn import_beets(
+    db: &Database,
+    config: &Config,
+    hooks: &Hooks,
+    path: &Path,
+    move_into_library: bool,
+) -> Result<()> {
+    let path_formats = config.paths.path_formats()?;
+    let relocation = move_into_library.then(|| beets::Relocation {
+        library_dir: &config.library.directory,
+        path_formats: &path_formats,
+    });
+    let report = beets::import(db, path, relocation)
+        .with_context(|| format!("Failed to import {}", path.display()))?;
+
+    println!("Imported {} tracks in {} albums", report.items, report.albums);
+    if report.moved > 0 {
+        println!("Moved {} files into the library", report.moved);
+    }
+    if report.existing > 0 {
+        println!("{} tracks were already in the library", report.existing);
+    }
+    if !report.missing.is_empty() {
+        println!("\n{} files no longer exist and were skipped:", report.missing.len());
+        for path in &report.missing {
+            println!("  {}", path.display());
         }
-    } else {
-        let items = db.query_items(query)?;
-        for item in items {
-            let duration = format_duration(item.length);
-            println!(
-                "{} - {} - {} [{}]",
-                item.artist, item.album, item.title, duration
-            );
+    }
+    if !report.failed.is_empty() {
+        println!("\n{} tracks could not be imported:", report.failed.len());
+        for (path, error) in &report.failed {
+            println!("  {}: {error}", path.display());
         }
     }
+    if !report.unmapped.is_empty() {
+        let fields: Vec<&str> = report.unmapped.iter().map(String::as_str).collect();
+        println!("\nbeets fields not imported: {}", fields.join(", "));
+    }
+    if let Some(run) = &report.run {
+        println!("\nImport session {run}; undo with `rsbts undo --session {run}`");
+        hooks.imported(db, run)?;
+    }
     Ok(())
 }
 
-fn stats(db: &Database) -> Result<()> {
-    let stats = db.stats()?;
-    println!("Tracks: {}", stats.tracks);
-    println!("Albums: {}", stats.albums);
-    println!("Artists: {}", stats.artists);
-    println!("Total time: {}", format_duration(stats.total_length));
-    println!("Total size: {}", format_size(stats.total_size));
+fn sessions(db: &Database, fmt: &Formatter) -> Result<()> {
+    let runs = db.import_runs()?;
+    if runs.is_empty() {
+        println!("No import sessions recorded");
+        return Ok(());
+    }
+    for summary in runs {
+        println!(
+            "{}  {}  {}: {} albums, {} tracks",
+            summary.run.id,
+            fmt.date(&summary.run.started),
+            summary.run.action.as_str(),
+            fmt.count(summary.albums),
+            fmt.count(summary.items)
+        );
+        for source in &summary.sources {
+            println!("  {}", source.display());
+        }
+    }
     Ok(())
 }
 
-fn update(db: &Database, query: Option<&str>) -> Result<()> {
-    let items = db.query_items(query)?;
-    let count = items.len();
+/// Tracks `recent` shows together: an album's, or those outside any album
+/// tagged with the same one.
+struct RecentGroup {
+    album: Option<Album>,
+    /// When the newest of the tracks was added
+    added: DateTime<Utc>,
+    items: Vec<Item>,
+}
 
-    for item in items {
-        if let Some(id) = item.id {
-            if let Ok(updated) = rsbts::tags::read_tags(&item.path) {
-                db.update_item(id, &updated)?;
-            }
-        }
+impl RecentGroup {
+    /// The group's album as `ls --album` shows it, or its tracks' tags.
+    fn name(&self) -> String {
+        self.album.as_ref().map_or_else(
+            || {
+                self.items.first().map_or_else(String::new, |item| {
+                    format!("{} - {}", item.effective_albumartist(), item.album)
+                })
+            },
+            album_line,
+        )
     }
+}
 
-    println!("Updated {count} items");
-    Ok(())
+/// What `recent --group-by` prints a header for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Period {
+    Day,
+    Week,
+    Month,
 }
 
-fn remove(db: &Database, query: &str, delete: bool) -> Result<()> {
-    let items = db.query_items(Some(query))?;
-    let count = items.len();
+impl Period {
+    /// The header of the period `date` is in: its day, the Monday its week
+    /// starts on or its month, in UTC as dates are stored.
+    fn header(self, date: &DateTime<Utc>) -> String {
+        let day = date.date_naive();
+        match self {
+            Self::Day => day.format("%Y-%m-%d").to_string(),
+            Self::Week => {
+                let into_week = u64::from(day.weekday().num_days_from_monday());
+                format!("Week of {}", (day - chrono::Days::new(into_week)).format("%Y-%m-%d"))
+            }
+            Self::Month => day.format("%Y-%m").to_string(),
+        }
+    }
+}
 
-    for item in &items {
-        if let Some(id) = item.id {
-            db.remove_item(id)?;
+/// The tracks added since `start`, grouped by album with the most recently
+/// added first, and each album's tracks in order.
+fn recent(db: &Database, start: DateTime<Utc>) -> Result<Vec<RecentGroup>> {
+    let query = format!("added:{}.. added-", start.format("%Y-%m-%d"));
+    let mut groups: indexmap::IndexMap<_, RecentGroup> = indexmap::IndexMap::new();
+    for item in db.query_items(Some(&query))? {
+        // Tracks outside any album go together by their tags
+        let key = match item.album_id {
+            Some(id) => (Some(id), String::new(), String::new()),
+            None => (None, item.effective_albumartist().to_string(), item.album.clone()),
+        };
+        if let Some(group) = groups.get_mut(&key) {
+            group.items.push(item);
+            continue;
         }
-        if delete {
-            if let Err(e) = std::fs::remove_file(&item.path) {
-                eprintln!("Warning: failed to delete {}: {e}", item.path.display());
+        let added = item.added;
+        groups.insert(key, RecentGroup { album: None, added, items: vec![item] });
+    }
+    let ids: Vec<i64> = groups.keys().filter_map(|(id, ..)| *id).collect();
+    let mut albums = db.get_albums(&ids)?;
+    let mut groups: Vec<RecentGroup> = groups
+        .into_iter()
+        .map(|((id, ..), group)| RecentGroup {
+            album: id.and_then(|id| albums.remove(&id)),
+            ..group
+        })
+        .collect();
+    for group in &mut groups {
+        group.items.sort_by(|a, b| {
+            (a.disc, a.track).cmp(&(b.disc, b.track)).then_with(|| a.title.cmp(&b.title))
+        });
+    }
+    Ok(groups)
+}
+
+/// Print what `recent` found: each album with when it was added and its
+/// tracks, or only the album with `albums`, under a header for each `period`.
+fn print_recent(
+    out: &mut impl std::io::Write,
+    fmt: &Formatter,
+    groups: &[RecentGroup],
+    albums: bool,
+    period: Option<Period>,
+) -> Result<()> {
+    if groups.is_empty() {
+        writeln!(out, "Nothing added")?;
+        return Ok(());
+    }
+    let mut current = None;
+    for group in groups {
+        if let Some(period) = period {
+            let header = period.header(&group.added);
+            if current.as_ref() != Some(&header) {
+                if current.is_some() {
+                    writeln!(out)?;
+                }
+                writeln!(out, "== {header} ==")?;
+                current = Some(header);
             }
         }
+        let added = fmt.date(&group.added);
+        if albums {
+            let tracks = group.items.len() as u64;
+            let noun = if tracks == 1 { "track" } else { "tracks" };
+            writeln!(out, "{added}  {}, {} {noun}", group.name(), fmt.count(tracks))?;
+            continue;
+        }
+        writeln!(out, "{added}  {}", group.name())?;
+        for item in &group.items {
+            writeln!(out, "  {} [{}]", item.title, fmt.duration(item.length))?;
+        }
     }
+    Ok(())
+}
 
-    println!("Removed {count} items");
+/// `recent --json`: the groups with their album, if any, and tracks as
+/// stored, and with `period` the header each comes under.
+fn print_recent_json(
+    out: &mut impl std::io::Write,
+    groups: &[RecentGroup],
+    period: Option<Period>,
+) -> Result<()> {
+    let groups: Vec<serde_json::Value> = groups
+        .iter()
+        .map(|group| {
+            let mut value = serde_json::json!({
+                "added": group.added,
+                "album": group.album,
+                "items": group.items,
+            });
+            if let Some(period) = period {
+                value["period"] = period.header(&group.added).into();
+            }
+            value
+        })
+        .collect();
+    writeln!(out, "{}", serde_json::to_string_pretty(&groups)?)?;
     Ok(())
 }
 
-fn modify(db: &Database, query: &str, fields: &[String]) -> Result<()> {
-    let items = db.query_items(Some(query))?;
-    let count = items.len();
+/// Take the import session `id` back out of the library, removing library
+/// directories it leaves empty with `pruner` and running the `item_removed`
+/// hook for each item.
+fn undo(db: &Database, hooks: &Hooks, pruner: &Pruner, id: &str, yes: bool) -> Result<()> {
+    if db.import_run(id)?.is_none() {
+        anyhow::bail!("No import session {id} (see `rsbts sessions`)");
+    }
+    let count = db.import_run_items(id)?.len();
+    let question = format!(
+        "Remove {count} items imported in session {id}, moving their files back to where \
+         they came from or deleting copies and links?"
+    );
+    if !yes && !Terminal.confirm(&question)? {
+        return Ok(());
+    }
 
-    for item in items {
-        if let Some(id) = item.id {
-            db.modify_item(id, fields)?;
+    let report = rsbts::runs::undo(db, id, Some(pruner), hooks)?;
+    println!("Removed {} items and {} albums", report.items, report.albums);
+    if report.restored > 0 {
+        println!("Moved {} files back to their sources", report.restored);
+    }
+    if report.deleted > 0 {
+        println!("Deleted {} copied or linked files", report.deleted);
+    }
+    if report.art > 0 {
+        println!("Deleted {} cover art files", report.art);
+    }
+    if report.cleared > 0 {
+        println!("Cleared {} album fields the session had filled in", report.cleared);
+    }
+    if !report.failed.is_empty() {
+        println!(
+            "\n{} files could not be undone; their tracks stay in the library:",
+            report.failed.len()
+        );
+        for (path, error) in &report.failed {
+            println!("  {}: {error}", path.display());
         }
     }
+    Ok(())
+}
 
-    println!("Modified {count} items");
+/// Where a command writes its output: the file at `path`, or stdout.
+fn output_file(path: Option<&Path>) -> Result<Box<dyn std::io::Write>> {
+    Ok(match path {
+        Some(path) => Box::new(std::io::BufWriter::new(
+            std::fs::File::create(path)
+                .with_context(|| format!("Failed to create {}", path.display()))?,
+        )),
+        None => Box::new(std::io::stdout().lock()),
+    })
+}
+
+fn import_config(config: &Config, action: Action) -> Result<ImportConfig> {
+    Ok(ImportConfig {
+        action,
+        fetch_art: config.import.fetch_art,
+        art_filenames: config.import.art_filenames.clone(),
+        prefer_caa_art: config.import.prefer_caa_art,
+        path_formats: config.paths.path_formats()?,
+        library_dir: config.library.directory.clone(),
+        min_match_score: config.musicbrainz.min_match_score,
+        preferences: ReleasePreferences {
+            countries: config.musicbrainz.preferred_countries.clone(),
+            media: config.musicbrainz.preferred_media.clone(),
+        },
+        musicbrainz: config.musicbrainz.client_settings(),
+        acoustid_api_key: config.acoustid.api_key.clone(),
+        concurrency: config.import.concurrency,
+        only: None,
+        merge_into_existing: config.import.merge_into_existing,
+        on_conflict: config.import.on_conflict,
+        quiet_fallback: config.import.quiet_fallback,
+        clutter: config.import.clutter.clone(),
+        keep_empty_dirs: false,
+        inside_library: false,
+        genres: config.genres.genres(),
+        scan: ScanOptions::default(),
+        extra_extensions: config.import.extra_extensions.clone(),
+        confirm_merge: Some(confirm_merge),
+        show_changes: Some(show_changes),
+    })
+}
+
+/// The files `import --format`, `--newer-than` and `--max-depth` let it read.
+fn scan_options(
+    formats: &[String],
+    newer_than: Option<&str>,
+    max_depth: Option<usize>,
+) -> Result<ScanOptions> {
+    let formats = formats
+        .iter()
+        .map(|name| AudioFormat::from_name(name).with_context(|| format!("Unknown format: {name}")))
+        .collect::<Result<_>>()?;
+    let newer_than = newer_than.map(rsbts::query::date_start).transpose()?;
+    Ok(ScanOptions {
+        formats,
+        newer_than,
+        max_depth,
+    })
+}
+
+/// Ask whether to add an album to an existing one; no when stdin isn't a
+/// terminal.
+fn confirm_merge(question: &str) -> bool {
+    Terminal.confirm(question).unwrap_or(false)
+}
+
+/// Print what matching an album changes in its tracks, `old -> new` where
+/// they differ (in color on a terminal), and what's left over. Nothing is
+/// printed when the tags already agree with the release.
+fn show_changes(changes: &AlbumChanges) {
+    use dialoguer::console::style;
+
+    if changes.is_empty() {
+        return;
+    }
+    println!("  Changes for {} - {}:", changes.artist, changes.album);
+    for track in &changes.tracks {
+        let position = if track.position_changed() {
+            let old = style(track_position(track.old_position)).red();
+            format!("{old} -> {}", style(track_position(track.new_position)).green())
+        } else {
+            track_position(track.new_position)
+        };
+        let title = if track.title_changed() {
+            format!(
+                "{} -> {} ({:.0}% alike)",
+                style(&track.old_title).red(),
+                style(&track.new_title).green(),
+                track.similarity * 100.0
+            )
+        } else {
+            track.new_title.clone()
+        };
+        println!("    {position} {title}");
+    }
+    for path in &changes.unmatched {
+        println!("    {} {}", style("Unmatched file:").yellow(), path.display());
+    }
+    for track in &changes.missing {
+        let position = track_position(track.position);
+        println!("    {} {position} {}", style("Missing track:").yellow(), track.title);
+    }
+}
+
+/// A disc and track number as `1-05`, just `05` without a disc, or `?`
+/// without a track number.
+fn track_position((disc, track): Position) -> String {
+    match (disc, track) {
+        (Some(disc), Some(track)) => format!("{disc}-{track:02}"),
+        (None, Some(track)) => format!("{track:02}"),
+        (_, None) => "?".into(),
+    }
+}
+
+fn log_cache_counts<S: MetadataSource + 'static>(importer: &Importer<'_, S>) {
+    let (hits, misses) = importer.cache_counts();
+    if hits + misses > 0 {
+        debug!(
+            "Metadata cache: {hits} hits, {misses} misses ({:.0}% hit rate)",
+            hits as f64 * 100.0 / (hits + misses) as f64
+        );
+    }
+}
+
+fn print_scan_report(report: &ScanReport) {
+    if !report.failures.is_empty() {
+        println!("\n{} files could not be read:", report.failures.len());
+        for (path, error) in &report.failures {
+            println!("  {}: {error}", path.display());
+        }
+    }
+
+    if !report.suspicious.is_empty() {
+        println!(
+            "\n{} files report no duration or bitrate and may be damaged:",
+            report.suspicious.len()
+        );
+        for path in &report.suspicious {
+            println!("  {}", path.display());
+        }
+    }
+
+    if !report.skipped.is_empty() {
+        println!("\n{} albums did not match --only and were skipped:", report.skipped.len());
+        for album in &report.skipped {
+            println!("  {album}");
+        }
+    }
+
+    if !report.unmatched.is_empty() {
+        println!("\n{} albums had no match and were skipped:", report.unmatched.len());
+        for (album, source) in &report.unmatched {
+            println!("  {album} ({})", source.display());
+        }
+    }
+
+    if !report.collisions.is_empty() {
+        println!("\n{} files had a destination that was taken:", report.collisions.len());
+        for collision in &report.collisions {
+            println!("  {collision}");
+        }
+    }
+
+    if !report.failed_albums.is_empty() {
+        println!("\n{} albums could not be imported:", report.failed_albums.len());
+        for (album, error) in &report.failed_albums {
+            println!("  {album}: {error}");
+        }
+    }
+}
+
+fn write_error_log(path: &Path, report: &ScanReport) -> Result<()> {
+    use std::fmt::Write as _;
+
+    let mut log = String::new();
+    for (file, error) in &report.failures {
+        let _ = writeln!(log, "unreadable\t{}\t{error}", file.display());
+    }
+    for file in &report.suspicious {
+        let _ = writeln!(log, "suspicious\t{}", file.display());
+    }
+    for (album, error) in &report.failed_albums {
+        let _ = writeln!(log, "failed\t{album}\t{error}");
+    }
+    std::fs::write(path, log)?;
+    Ok(())
+}
+
+/// The prefix of the lines of a skip log, each followed by a directory,
+/// archive or file that imports a skipped album again.
+const SKIP_LOG_PREFIX: &str = "skip\t";
+
+/// Write what imports the albums skipped for lack of a match again (see
+/// [`ScanReport::retry`]) to `path`, one per line, for `import --from-log`.
+fn write_skip_log(path: &Path, report: &ScanReport) -> Result<()> {
+    use std::fmt::Write as _;
+
+    let mut log = String::new();
+    for path in &report.retry {
+        let _ = writeln!(log, "{SKIP_LOG_PREFIX}{}", path.display());
+    }
+    std::fs::write(path, log)?;
     Ok(())
 }
 
-fn format_duration(seconds: f64) -> String {
-    // Negative durations are invalid; clamp to 0
-    let total_secs = seconds.max(0.0) as u64;
-    let hours = total_secs / 3600;
-    let mins = (total_secs % 3600) / 60;
-    let secs = total_secs % 60;
+/// The paths in a log written by [`write_skip_log`], ignoring other lines.
+fn read_skip_log(path: &Path) -> Result<Vec<PathBuf>> {
+    let log = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read skip log {}", path.display()))?;
+    Ok(log
+        .lines()
+        .filter_map(|line| line.strip_prefix(SKIP_LOG_PREFIX))
+        .map(PathBuf::from)
+        .collect())
+}
 
-    if hours > 0 {
-        format!("{hours}:{mins:02}:{secs:02}")
+/// Expand bookmarks in a query and, if requested, AND the configured default query onto it.
+fn resolve_query(
+    config: &Config,
+    query: Option<&str>,
+    use_default: bool,
+) -> Result<Option<String>> {
+    let default_query = if use_default {
+        config.ui.default_query.as_deref()
     } else {
-        format!("{mins}:{secs:02}")
+        None
+    };
+    Ok(rsbts::query::resolve(
+        query,
+        default_query,
+        &config.bookmarks,
+    )?)
+}
+
+/// Expand bookmarks in a required query argument.
+fn expand_query(config: &Config, query: &str) -> Result<String> {
+    Ok(rsbts::query::expand_bookmarks(query, &config.bookmarks)?)
+}
+
+fn explain(
+    db: &Database,
+    config: &Config,
+    query: Option<&str>,
+    use_default: bool,
+) -> Result<()> {
+    println!("Query:    {}", query.unwrap_or(""));
+    match config.ui.default_query.as_deref() {
+        Some(d) if use_default => {
+            println!("Default:  {d} (disable with --no-default-query)");
+        }
+        Some(d) => println!("Default:  {d} (disabled)"),
+        None => println!("Default:  (none)"),
     }
+
+    let resolved = resolve_query(config, query, use_default)?;
+    println!("Expanded: {}", resolved.as_deref().unwrap_or(""));
+
+    println!("SQL:      {}", db.items_sql(resolved.as_deref(), Page::default())?);
+    Ok(())
 }
 
-fn format_size(bytes: u64) -> String {
-    const KB: u64 = 1024;
-    const MB: u64 = KB * 1024;
-    const GB: u64 = MB * 1024;
+fn export_archive(db: &Database, fmt: &Formatter, dir: &Path, keep: usize) -> Result<()> {
+    let (path, manifest) = archive::write(db, dir, keep)?;
+    println!(
+        "Archived {} albums and {} tracks to {}",
+        fmt.count(manifest.albums),
+        fmt.count(manifest.items),
+        path.display()
+    );
+    Ok(())
+}
 
-    if bytes >= GB {
-        format!("{:.1} GB", bytes as f64 / GB as f64)
-    } else if bytes >= MB {
-        format!("{:.1} MB", bytes as f64 / MB as f64)
-    } else if bytes >= KB {
-        format!("{:.1} KB", bytes as f64 / KB as f64)
+fn restore(db: &Database, fmt: &Formatter, file: &Path, verify: bool) -> Result<()> {
+    let manifest = if verify {
+        archive::verify(db, file)?.manifest
     } else {
-        format!("{bytes} B")
+        archive::restore(db, file)?
+    };
+    println!(
+        "{} {} albums and {} tracks from {} (rsbts {}, schema version {})",
+        if verify { "Verified" } else { "Restored" },
+        fmt.count(manifest.albums),
+        fmt.count(manifest.items),
+        fmt.date(&manifest.created),
+        manifest.rsbts_version,
+        manifest.schema_version
+    );
+    Ok(())
+}
+
+fn reindex(db: &Database) -> Result<()> {
+    if !db.has_fts5() {
+        println!("This SQLite build does not include the FTS5 extension, so there is no");
+        println!("full-text index to rebuild. Bare-word searches use slower substring");
+        println!("matching instead; the index is created automatically once FTS5 is available.");
+        return Ok(());
+    }
+
+    db.rebuild_fts()?;
+    println!("Rebuilt full-text index");
+    Ok(())
+}
+
+/// Copy the database to `path` with `SQLite`'s online backup, replacing an
+/// existing file only with `force`.
+fn backup(db: &Database, fmt: &Formatter, path: &Path, force: bool) -> Result<()> {
+    if path.exists() {
+        if !force {
+            anyhow::bail!("{} already exists; use --force to replace it", path.display());
+        }
+        std::fs::remove_file(path)
+            .with_context(|| format!("Failed to replace {}", path.display()))?;
+    }
+    if let Err(e) = db.backup_to(path) {
+        // Don't leave a partial copy that looks like a backup
+        let _ = std::fs::remove_file(path);
+        return Err(e).with_context(|| format!("Backup to {} failed", path.display()));
+    }
+    let size = std::fs::metadata(path)?.len();
+    println!("Backed up the database to {} ({})", path.display(), fmt.size(size));
+    Ok(())
+}
+
+/// Vacuum the database at `path`, reporting the space reclaimed.
+fn vacuum(db: &Database, fmt: &Formatter, path: &Path) -> Result<()> {
+    let size = || std::fs::metadata(path).map_or(0, |m| m.len());
+    let before = size();
+    db.vacuum().context("Vacuum failed")?;
+    let after = size();
+    println!(
+        "Vacuumed the database: {} -> {} ({} reclaimed)",
+        fmt.size(before),
+        fmt.size(after),
+        fmt.size(before.saturating_sub(after))
+    );
+    Ok(())
+}
+
+/// Replace the stored path prefix `from` with `to` throughout the library.
+fn rewrite_paths(db: &Database, fmt: &Formatter, from: &Path, to: &Path) -> Result<()> {
+    let changed = db
+        .rewrite_paths(from, to)
+        .with_context(|| format!("Couldn't rewrite paths under {}", from.display()))?;
+    println!(
+        "Rewrote {} paths from {} to {}",
+        fmt.count(changed as u64),
+        from.display(),
+        to.display()
+    );
+    Ok(())
+}
+
+/// Run `SQLite`'s integrity check and compare the full-text index with the
+/// items table, with `rebuild_fts` repopulating an index that has drifted.
+fn db_check(db: &Database, fmt: &Formatter, rebuild_fts: bool) -> Result<Outcome> {
+    let mut healthy = true;
+    let problems = db.integrity_problems()?;
+    if problems.is_empty() {
+        println!("Integrity check: ok");
+    } else {
+        healthy = false;
+        println!("Integrity check: {} problems", fmt.count(problems.len() as u64));
+        for problem in &problems {
+            println!("  {problem}");
+        }
+        println!("Restore a backup (`rsbts db backup` makes them) or export what can be read");
+    }
+
+    match db.fts_drift()? {
+        None if db.fts_mode() == FtsMode::Disabled => {
+            println!("Full-text index: none (disabled by library.fts)");
+        }
+        None => println!("Full-text index: none (SQLite lacks FTS5)"),
+        Some(drift) if drift.is_clean() => println!("Full-text index: ok"),
+        Some(drift) => {
+            println!(
+                "Full-text index: {} items not indexed, {} entries for removed items{}",
+                fmt.count(drift.unindexed),
+                fmt.count(drift.stale),
+                if drift.consistent { "" } else { ", out of date" }
+            );
+            if rebuild_fts {
+                db.rebuild_fts()?;
+                if matches!(db.fts_drift()?, Some(drift) if drift.is_clean()) {
+                    println!("Rebuilt full-text index");
+                } else {
+                    healthy = false;
+                    println!("Rebuilt full-text index, but it still differs from the items");
+                }
+            } else {
+                healthy = false;
+                println!("Run `rsbts db check --rebuild-fts` to repopulate it");
+            }
+        }
+    }
+    Ok(if healthy { Outcome::Success } else { Outcome::Failed })
+}
+
+fn migrate(db: &Database, dry_run: bool, to: Option<u32>) -> Result<()> {
+    let current = db.migration_version()?;
+    let pending = db.pending_migrations(to)?;
+    if pending.is_empty() {
+        println!("Database is at version {current}; nothing to migrate");
+        return Ok(());
+    }
+
+    let versions: Vec<String> = pending.iter().map(|m| m.version.to_string()).collect();
+    if dry_run {
+        println!(
+            "Database is at version {current}; would apply {}",
+            versions.join(", ")
+        );
+        for migration in &pending {
+            println!();
+            println!("-- Version {}", migration.version);
+            print!("{}", migration.sql);
+        }
+        return Ok(());
+    }
+
+    db.migrate_to(to)?;
+    println!(
+        "Migrated database from version {current} to {} (applied {})",
+        db.migration_version()?,
+        versions.join(", ")
+    );
+    Ok(())
+}
+
+/// Bash completes a field name with a trailing space, which would end the
+/// query term; this wrapper leaves it off after a `:` or `=`.
+const BASH_FIELD_SUFFIX: &str = r#"
+_rsbts_fields() {
+    _rsbts "$@"
+    if [[ ${#COMPREPLY[@]} -eq 1 && ${COMPREPLY[0]} == *[:=] ]]; then
+        compopt -o nospace
+    fi
+}
+complete -F _rsbts_fields -o bashdefault -o default rsbts
+"#;
+
+/// Write a completion script for `shell` to `out`. Besides subcommands,
+/// aliases and options, query arguments complete field names followed by a
+/// colon, and `modify` assignments field names followed by `=`.
+fn completions(shell: Shell, out: &mut impl std::io::Write) -> Result<()> {
+    let queries: Vec<PossibleValue> = query_fields()
+        .map(|f| PossibleValue::new(format!("{}:", f.name)).help(f.ty.as_str()))
+        .collect();
+    let edits: Vec<PossibleValue> = ITEM_FIELDS
+        .iter()
+        .filter(|f| f.editable)
+        .map(|f| PossibleValue::new(format!("{}=", f.name)).help(f.ty.as_str()))
+        .collect();
+    let mut command = with_field_hints(Cli::command(), &queries, &edits);
+    clap_complete::generate(shell, &mut command, "rsbts", out);
+    if shell == Shell::Bash {
+        out.write_all(BASH_FIELD_SUFFIX.as_bytes())?;
+    }
+    Ok(())
+}
+
+/// `command` with `queries` offered for its `query` arguments and `edits`
+/// for `modify`'s assignments, in it and its subcommands. Only completion
+/// uses these; parsing still takes any value.
+fn with_field_hints(
+    command: Command,
+    queries: &[PossibleValue],
+    edits: &[PossibleValue],
+) -> Command {
+    let is_modify = command.get_name() == "modify";
+    command
+        .mut_args(|arg| {
+            if arg.get_id() == "query" {
+                arg.value_parser(PossibleValuesParser::new(queries.to_vec()))
+            } else if is_modify && arg.get_id() == "fields" {
+                arg.value_parser(PossibleValuesParser::new(edits.to_vec()))
+            } else {
+                arg
+            }
+        })
+        .mut_subcommands(|sub| with_field_hints(sub, queries, edits))
+}
+
+/// Print the fields queries can test, with their types, whether they can be
+/// cleared, and whether `modify` changes them in file tags, in the library
+/// only, or not at all. With `json`, as a JSON array.
+fn fields(out: &mut impl std::io::Write, json: bool) -> Result<()> {
+    let fields: Vec<&Field> = query_fields().collect();
+    if json {
+        writeln!(out, "{}", serde_json::to_string_pretty(&fields)?)?;
+        return Ok(());
+    }
+    let width = fields.iter().map(|f| f.name.len()).max().unwrap_or(0);
+    writeln!(out, "{:width$}  {:6}  {:8}  modify", "field", "type", "nullable")?;
+    for field in fields {
+        let modify = match (field.tag, field.editable) {
+            (true, _) => "tags",
+            (false, true) => "library",
+            (false, false) => "-",
+        };
+        let nullable = if field.nullable { "yes" } else { "no" };
+        writeln!(out, "{:width$}  {:6}  {nullable:8}  {modify}", field.name, field.ty.as_str())?;
+    }
+    Ok(())
+}
+
+fn init(location: &ConfigLocation, library: Option<PathBuf>, force: bool) -> Result<()> {
+    let Some(path) = location.path() else {
+        anyhow::bail!("No config directory found; give a config file with --config");
+    };
+    if path.exists() && !force {
+        anyhow::bail!("{} already exists; use --force to replace it", path.display());
+    }
+    let (directory, database) = match location {
+        // A portable library is wherever its root is
+        ConfigLocation::Portable(root) => {
+            if library.is_some() {
+                anyhow::bail!("A portable library is its root; leave out --library");
+            }
+            let root = std::path::absolute(root)?;
+            let database = root.join(rsbts::config::PORTABLE_DATABASE);
+            (root, database)
+        }
+        ConfigLocation::File(_) => {
+            let defaults = Config::default();
+            let directory = match library {
+                Some(dir) => std::path::absolute(dir)?,
+                None => defaults.library.directory,
+            };
+            (directory, defaults.library.database)
+        }
+    };
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(&path, Config::starter(&directory, &database))?;
+    println!("Wrote config file {}", path.display());
+
+    std::fs::create_dir_all(&directory)?;
+    println!("Library directory {}", directory.display());
+
+    let existed = database.exists();
+    if let Some(parent) = database.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let db = Database::open(&database)?;
+    db.migrate()?;
+    db.check_portable(matches!(location, ConfigLocation::Portable(_)))?;
+    let version = db.migration_version()?;
+    if existed {
+        println!("Database {} (existing, at schema version {version})", database.display());
+    } else {
+        println!("Created database {} (schema version {version})", database.display());
+    }
+    Ok(())
+}
+
+fn cache_stats(db: &Database, fmt: &Formatter) -> Result<()> {
+    let stats = db.cache_stats()?;
+    if stats.is_empty() {
+        println!("Metadata cache is empty");
+        return Ok(());
+    }
+
+    for entry in &stats {
+        println!(
+            "{}: {} entries, {}",
+            entry.kind,
+            fmt.count(entry.entries),
+            fmt.size(entry.bytes)
+        );
+    }
+    let bytes = stats.iter().map(|s| s.bytes).sum();
+    println!("Total: {}", fmt.size(bytes));
+    Ok(())
+}
+
+/// List albums, returning how many there were.
+fn list_albums(db: &Database, fmt: &Formatter, query: Option<&str>, long: bool) -> Result<u64> {
+    if long {
+        let albums = db.query_albums_with_stats(query)?;
+        for (album, stats) in &albums {
+            println!("{} {}", album_line(album), album_totals(fmt, stats));
+        }
+        return Ok(albums.len() as u64);
+    }
+    let albums = db.query_albums(query)?;
+    for album in &albums {
+        println!("{}", album_line(album));
+    }
+    Ok(albums.len() as u64)
+}
+
+/// How `ls --album` shows an album.
+fn album_line(album: &Album) -> String {
+    let year = album.year.map_or_else(String::new, |y| format!(" ({y})"));
+    format!("{} - {}{}", album.albumartist, album.album, year)
+}
+
+/// An album's totals as `ls --album --long` shows them, such as
+/// `[10 tracks, 42:03, 312.5 MB, FLAC/MP3, 320-1,411 kbps]`.
+fn album_totals(fmt: &Formatter, stats: &AlbumStats) -> String {
+    let mut parts = vec![
+        format!("{} tracks", fmt.count(stats.track_count)),
+        fmt.duration(stats.total_length),
+        fmt.size(stats.total_size),
+    ];
+    if !stats.formats.is_empty() {
+        let names: Vec<&str> = stats.formats.iter().map(|format| format.as_str()).collect();
+        parts.push(names.join("/"));
+    }
+    if let (Some(min), Some(max)) = (stats.min_bitrate, stats.max_bitrate) {
+        let (min, max) = (fmt.count(min.into()), fmt.count(max.into()));
+        if min == max {
+            parts.push(format!("{max} kbps"));
+        } else {
+            parts.push(format!("{min}-{max} kbps"));
+        }
+    }
+    format!("[{}]", parts.join(", "))
+}
+
+/// List items, restricted to the ids in `only` if given, returning how many
+/// were listed.
+fn list(
+    out: &mut impl std::io::Write,
+    db: &Database,
+    fmt: &Formatter,
+    query: Option<&str>,
+    page: Page,
+    only: Option<&HashSet<i64>>,
+) -> Result<u64> {
+    let mut listed = 0;
+    db.query_items_streamed(query, page, |item| {
+        if only.is_some_and(|ids| !item.id.is_some_and(|id| ids.contains(&id))) {
+            return Ok(());
+        }
+        writeln!(out, "{}", list_line(fmt, &item))?;
+        listed += 1;
+        Ok(())
+    })?;
+    Ok(listed)
+}
+
+/// How `ls` shows a track.
+fn list_line(fmt: &Formatter, item: &Item) -> String {
+    let duration = fmt.duration(item.length);
+    format!("{} - {} - {} [{}]", item.artist, item.album, item.title, duration)
+}
+
+/// The tracks `random` picks from those `query` matches. Picking a number of
+/// single tracks without a seed is left to `SQLite`; anything else shuffles
+/// all the matches by `seed`, or a new one.
+fn random(
+    db: &Database,
+    query: Option<&str>,
+    amount: Amount,
+    album: bool,
+    seed: Option<u64>,
+) -> Result<Vec<Item>> {
+    if let (Amount::Count(count), false, None) = (amount, album, seed) {
+        return Ok(db.random_items(query, count as u64)?);
+    }
+    let seed = seed.unwrap_or_else(playlist::random_seed);
+    Ok(rsbts::random::pick(db.query_items(query)?, amount, album, seed))
+}
+
+fn info(db: &Database, fmt: &Formatter, query: &str) -> Result<()> {
+    let items = db.query_items(Some(query))?;
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        let optional = |value: Option<String>| value.unwrap_or_else(|| "-".into());
+        println!("{}", item.path.display());
+        println!("  title: {}", item.title);
+        println!("  artist: {}", item.artist);
+        println!("  album: {}", item.album);
+        println!("  albumartist: {}", optional(item.albumartist.clone()));
+        println!("  genre: {}", optional(item.genre.clone()));
+        println!("  year: {}", optional(item.year.map(|y| y.to_string())));
+        println!("  track: {}", optional(item.track.map(|t| t.to_string())));
+        println!("  disc: {}", optional(item.disc.map(|d| d.to_string())));
+        println!("  format: {} ({} kbps)", item.format.as_str(), item.bitrate);
+        println!("  length: {}", fmt.duration(item.length));
+        println!("  mb_trackid: {}", optional(item.mb_trackid.clone()));
+        println!("  mb_albumid: {}", optional(item.mb_albumid.clone()));
+        println!("  mb_releasegroupid: {}", optional(item.mb_releasegroupid.clone()));
+        println!("  added: {}", fmt.date(&item.added));
+        println!(
+            "  source_path: {}",
+            optional(item.source_path.as_ref().map(|p| p.display().to_string()))
+        );
+        // The album's attributes, unless the item has its own
+        let mut attributes = match item.album_id {
+            Some(album_id) => db.attributes(AttributeOwner::Album(album_id))?,
+            None => BTreeMap::new(),
+        };
+        if let Some(id) = item.id {
+            attributes.extend(db.attributes(AttributeOwner::Item(id))?);
+        }
+        for (key, value) in attributes {
+            println!("  {ATTRIBUTE_PREFIX}{key}: {value}");
+        }
+    }
+    Ok(())
+}
+
+/// Ids of items whose files no longer exist, with a spinner while checking.
+fn missing_ids(db: &Database, config: &Config) -> Result<HashSet<i64>> {
+    let bar = ProgressBar::new_spinner();
+    bar.set_message("Checking files");
+    let ids = db.missing_item_ids(&ExistenceCheck::from_config(&config.library), || bar.inc(1))?;
+    bar.finish_and_clear();
+    Ok(ids.into_iter().collect())
+}
+
+fn stats(
+    db: &Database,
+    config: &Config,
+    fmt: &Formatter,
+    query: Option<&str>,
+    json: bool,
+    verify: bool,
+) -> Result<()> {
+    let mut stats = match query {
+        Some(q) => db.query_stats(q)?,
+        None => db.stats()?,
+    };
+    if verify {
+        let missing = missing_ids(db, config)?;
+        let count = match query {
+            Some(q) => db
+                .query_items(Some(q))?
+                .iter()
+                .filter(|item| item.id.is_some_and(|id| missing.contains(&id)))
+                .count(),
+            None => missing.len(),
+        };
+        stats.missing = Some(count as u64);
+    }
+
+    // JSON is for scripts: raw values, never localized
+    if json {
+        println!("{}", serde_json::to_string_pretty(&stats)?);
+        return Ok(());
+    }
+
+    println!("Tracks: {}", fmt.count(stats.tracks));
+    println!("Albums: {}", fmt.count(stats.albums));
+    println!("Artists: {}", fmt.count(stats.artists));
+    println!("Total time: {}", fmt.duration(stats.total_length));
+    println!("Total size: {}", fmt.size(stats.total_size));
+    if let Some(missing) = stats.missing {
+        println!("Missing files: {}", fmt.count(missing));
+    }
+    Ok(())
+}
+
+/// Print `stats --by`: the tracks, time and size of each group, in aligned
+/// columns.
+fn grouped_stats(
+    db: &Database,
+    fmt: &Formatter,
+    query: Option<&str>,
+    group: StatsGroup,
+    json: bool,
+) -> Result<()> {
+    let groups = db.grouped_stats(query, group)?;
+    if json {
+        let rows = groups
+            .iter()
+            .map(|(name, stats)| {
+                let mut row = serde_json::to_value(stats)?;
+                row[group.as_str()] = name.as_str().into();
+                Ok(row)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        println!("{}", serde_json::to_string_pretty(&rows)?);
+        return Ok(());
+    }
+
+    let mut heading = group.as_str().to_string();
+    heading[..1].make_ascii_uppercase();
+    let mut rows = vec![[heading, "Tracks".into(), "Time".into(), "Size".into()]];
+    rows.extend(groups.iter().map(|(name, stats)| {
+        [
+            name.clone(),
+            fmt.count(stats.tracks),
+            fmt.duration(stats.total_length),
+            fmt.size(stats.total_size),
+        ]
+    }));
+    let mut widths = [0; 4];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    for [name, tracks, time, size] in &rows {
+        let [name_width, tracks_width, time_width, size_width] = widths;
+        println!(
+            "{name:<name_width$}  {tracks:>tracks_width$}  {time:>time_width$}  \
+             {size:>size_width$}"
+        );
+    }
+    Ok(())
+}
+
+/// Print each genre of the items `query` matches and how many tracks have
+/// it, most first.
+fn list_genres(db: &Database, fmt: &Formatter, query: Option<&str>) -> Result<()> {
+    let genres = db.grouped_stats(query, StatsGroup::Genre)?;
+    let counts: Vec<String> = genres.iter().map(|(_, stats)| fmt.count(stats.tracks)).collect();
+    let width = counts.iter().map(|count| count.chars().count()).max().unwrap_or(0);
+    for ((genre, _), count) in genres.iter().zip(&counts) {
+        println!("{count:>width$}  {genre}");
+    }
+    Ok(())
+}
+
+/// Give the items `query` matches (all of them without one) the canonical
+/// names of their genres, after showing how many items each rule changes.
+fn normalize_genres(
+    db: &Database,
+    hooks: &Hooks,
+    genres: &Genres,
+    query: Option<&str>,
+    write: bool,
+    yes: bool,
+) -> Result<()> {
+    if genres.is_empty() {
+        println!("No genres would change: set [genres.aliases] or genres.title_case");
+        return Ok(());
+    }
+    let mut planned = Vec::new();
+    let mut counts: HashMap<Rule, usize> = HashMap::new();
+    for item in db.query_items(query)? {
+        let Some((genre, rule)) = item.genre.as_deref().and_then(|g| genres.normalize(g)) else {
+            continue;
+        };
+        *counts.entry(rule).or_default() += 1;
+        planned.push((item, genre));
+    }
+    if planned.is_empty() {
+        println!("No genres would change");
+        return Ok(());
+    }
+    let mut counts: Vec<(String, usize)> =
+        counts.into_iter().map(|(rule, count)| (rule.to_string(), count)).collect();
+    counts.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then_with(|| a.cmp(b)));
+    for (rule, count) in &counts {
+        println!("{rule}: {count} items");
+    }
+
+    let target = if write { "database and files" } else { "database only" };
+    let prompt = format!("Change the genre of {} items ({target})?", planned.len());
+    if !yes && !Terminal.confirm(&prompt)? {
+        return Ok(());
+    }
+
+    let mut count = 0;
+    let mut failed = 0;
+    for (item, genre) in planned {
+        let Some(id) = item.id else {
+            continue;
+        };
+        let edit: FieldEdit = format!("genre={genre}").parse()?;
+        if write {
+            if let Err(e) = write_tags(&item.path, std::slice::from_ref(&edit)) {
+                warn!("Skipping {}: {e}", item.path.display());
+                failed += 1;
+                continue;
+            }
+            // Keep `check` from reporting our own write as a modification
+            let metadata = std::fs::metadata(&item.path)?;
+            db.set_file_stat(id, metadata.modified()?.into(), metadata.len())?;
+        }
+        db.modify_item(id, &[FieldChange::Set(edit)])?;
+        if let Some(item) = db.get_item(id)? {
+            hooks.item(Event::ItemModified, &item);
+        }
+        count += 1;
+    }
+
+    println!("Normalized the genre of {count} items");
+    if failed > 0 {
+        println!("{failed} files could not be written and were left unchanged");
+    }
+    Ok(())
+}
+
+/// What the `missing` command prints.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum MissingMode {
+    /// Each missing track, grouped by album
+    Tracks,
+    /// One "N/M missing" line per album
+    Count,
+    /// Only the grand total
+    Total,
+}
+
+// rusqlite::Connection is not Sync, so futures holding &Database aren't Send
+#[allow(clippy::future_not_send)]
+async fn missing(
+    db: &Database,
+    config: &Config,
+    fmt: &Formatter,
+    query: Option<&str>,
+    mode: MissingMode,
+) -> Result<()> {
+    // Lookups share the MusicBrainz rate limit and the metadata cache
+    let client = MbClient::with_settings(&config.musicbrainz.client_settings())?;
+    let source = MetadataCache::new(client, db.connect()?);
+
+    let mut total_missing = 0u64;
+    let mut incomplete = 0u64;
+    let mut unmatched = 0u64;
+    for album in db.query_albums(query)? {
+        let name = format!("{} - {}", album.albumartist, album.album);
+        let (Some(id), Some(mbid)) = (album.id, album.mb_albumid.as_deref()) else {
+            unmatched += 1;
+            if mode != MissingMode::Total {
+                println!("{name}: unmatched");
+            }
+            continue;
+        };
+
+        let release = match source.lookup_release(mbid).await {
+            Ok(release) => release,
+            Err(e) => {
+                warn!("Failed to look up {name}: {e}");
+                continue;
+            }
+        };
+        let missing = rsbts::missing::missing_tracks(&release, &db.album_items(id)?);
+        if missing.is_empty() {
+            continue;
+        }
+        total_missing += missing.len() as u64;
+        incomplete += 1;
+
+        match mode {
+            MissingMode::Tracks => {
+                println!("{name}:");
+                for track in &missing {
+                    println!("  {:>5}  {}", track.position(), track.title);
+                }
+            }
+            MissingMode::Count => println!(
+                "{name}: {}/{} missing",
+                missing.len(),
+                rsbts::missing::tracklist_len(&release)
+            ),
+            MissingMode::Total => {}
+        }
+    }
+
+    if mode == MissingMode::Total {
+        println!(
+            "{} tracks missing from {} albums ({} unmatched)",
+            fmt.count(total_missing),
+            fmt.count(incomplete),
+            fmt.count(unmatched)
+        );
+    }
+    Ok(())
+}
+
+/// List duplicate tracks, best copy first. With `delete`, the extra copies
+/// are removed from the library and disk once `prompt` confirms (or right
+/// away with `yes`), and so are albums left empty.
+fn duplicates(
+    db: &Database,
+    fmt: &Formatter,
+    hooks: &Hooks,
+    prompt: &mut impl Prompt,
+    ranking: &Ranking,
+    delete: bool,
+    yes: bool,
+) -> Result<()> {
+    let groups = dedup::duplicate_items(db.query_items(None)?, ranking);
+    if groups.is_empty() {
+        println!("No duplicate tracks found");
+        return Ok(());
+    }
+
+    for group in &groups {
+        println!();
+        // The criterion that put the keeper ahead of the runner-up
+        let reason = match ranking.compare(&group[0], &group[1]) {
+            (_, Some(criterion)) => format!(" (kept: {criterion})"),
+            (_, None) => " (kept: tie)".to_string(),
+        };
+        for (i, item) in group.iter().enumerate() {
+            let marker = if i == 0 { "*" } else { " " };
+            println!(
+                "{marker} {} - {} [{}, {} kbps, {}] {}{}",
+                item.artist,
+                item.title,
+                item.format.as_str(),
+                item.bitrate,
+                fmt.duration(item.length),
+                item.path.display(),
+                if i == 0 { reason.as_str() } else { "" }
+            );
+        }
+    }
+
+    let extra: Vec<&Item> = groups.iter().flat_map(|g| &g[1..]).collect();
+    println!(
+        "\n{} duplicate groups, {} extra copies",
+        fmt.count(groups.len() as u64),
+        fmt.count(extra.len() as u64)
+    );
+
+    let count = extra.len();
+    if !delete {
+        return Ok(());
+    }
+    let question = format!("Delete {count} extra copies from disk?");
+    if !yes && !prompt.confirm(&question)? {
+        return Ok(());
+    }
+    for item in extra {
+        if let Some(id) = item.id {
+            db.remove_item(id)?;
+            hooks.item(Event::ItemRemoved, item);
+        }
+        if let Err(e) = std::fs::remove_file(&item.path) {
+            warn!("Failed to delete {}: {e}", item.path.display());
+        }
+    }
+    println!("Deleted {} items", fmt.count(count as u64));
+    let albums = prune_empty_albums(db, false)?;
+    if albums > 0 {
+        println!("Removed {} empty albums", fmt.count(albums));
+    }
+    Ok(())
+}
+
+/// List duplicate albums, best copy first, deleting the extra copies like
+/// [`duplicates`].
+fn duplicate_albums(
+    db: &Database,
+    fmt: &Formatter,
+    hooks: &Hooks,
+    prompt: &mut impl Prompt,
+    prefs: &FormatPreference,
+    delete: bool,
+    yes: bool,
+) -> Result<()> {
+    let mut items_by_album: HashMap<i64, Vec<Item>> = HashMap::new();
+    for item in db.query_items(None)? {
+        if let Some(album_id) = item.album_id {
+            items_by_album.entry(album_id).or_default().push(item);
+        }
+    }
+    let albums = db
+        .query_albums(None)?
+        .into_iter()
+        .map(|album| {
+            let items = album
+                .id
+                .and_then(|id| items_by_album.remove(&id))
+                .unwrap_or_default();
+            AlbumCopy { album, items }
+        })
+        .collect();
+
+    let groups = dedup::duplicate_albums(albums, prefs);
+    if groups.is_empty() {
+        println!("No duplicate albums found");
+        return Ok(());
+    }
+
+    for group in &groups {
+        println!();
+        for (i, copy) in group.iter().enumerate() {
+            let marker = if i == 0 { "*" } else { " " };
+            let formats: BTreeSet<&str> =
+                copy.items.iter().map(|item| item.format.as_str()).collect();
+            println!(
+                "{marker} {} - {} [{} tracks, {}]",
+                copy.album.albumartist,
+                copy.album.album,
+                copy.items.len(),
+                formats.into_iter().collect::<Vec<_>>().join("/")
+            );
+        }
+    }
+
+    let extra: Vec<&AlbumCopy> = groups.iter().flat_map(|g| &g[1..]).collect();
+    println!(
+        "\n{} duplicate groups, {} extra copies",
+        fmt.count(groups.len() as u64),
+        fmt.count(extra.len() as u64)
+    );
+
+    let count = extra.len();
+    if !delete {
+        return Ok(());
+    }
+    let question = format!("Delete {count} extra albums from disk?");
+    if !yes && !prompt.confirm(&question)? {
+        return Ok(());
+    }
+    for copy in &extra {
+        if let Some(id) = copy.album.id {
+            let items = db.album_items(id)?;
+            db.remove_album(id)?;
+            for item in &items {
+                hooks.item(Event::ItemRemoved, item);
+            }
+        }
+        for item in &copy.items {
+            if let Err(e) = std::fs::remove_file(&item.path) {
+                warn!("Failed to delete {}: {e}", item.path.display());
+            }
+        }
+    }
+    println!("Deleted {} albums", fmt.count(count as u64));
+    Ok(())
+}
+
+/// Ask a yes/no question on the terminal; anything but "y" or "yes" is no.
+fn confirm(prompt: &str) -> Result<bool> {
+    use std::io::Write as _;
+
+    print!("{prompt} [y/N] ");
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Source of answers to yes/no questions.
+trait Prompt {
+    fn confirm(&mut self, question: &str) -> Result<bool>;
+}
+
+/// Asks on the terminal. Refuses to guess when stdin isn't a terminal, so a
+/// script can't confirm something by accident.
+struct Terminal;
+
+impl Prompt for Terminal {
+    fn confirm(&mut self, question: &str) -> Result<bool> {
+        use std::io::IsTerminal as _;
+
+        if !std::io::stdin().is_terminal() {
+            anyhow::bail!("Can't ask for confirmation: stdin is not a terminal (pass --yes)");
+        }
+        confirm(question)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct CheckOptions {
+    fix_missing: bool,
+    add_untracked: bool,
+    quiet: bool,
+}
+
+// rusqlite::Connection is not Sync, so futures holding &Database aren't Send
+#[allow(clippy::future_not_send)]
+async fn check(
+    db: &Database,
+    config: &Config,
+    fmt: &Formatter,
+    hooks: &Hooks,
+    options: CheckOptions,
+) -> Result<Outcome> {
+    let report = rsbts::check::check_library(
+        db.query_items(None)?,
+        &config.library.directory,
+        &config.import.extra_extensions,
+        &ExistenceCheck::from_config(&config.library),
+    )?;
+
+    if options.quiet {
+        println!(
+            "{} missing, {} modified, {} untracked",
+            fmt.count(report.missing.len() as u64),
+            fmt.count(report.modified.len() as u64),
+            fmt.count(report.untracked.len() as u64)
+        );
+    } else {
+        for item in &report.missing {
+            println!("missing: {}", item.path.display());
+        }
+        for item in &report.modified {
+            println!("modified: {}", item.path.display());
+        }
+        for path in &report.untracked {
+            println!("untracked: {}", path.display());
+        }
+        if report.is_clean() {
+            println!("No problems found");
+        } else if !report.modified.is_empty() {
+            println!("\nRun `rsbts update` to re-read modified files");
+        }
+    }
+
+    let mut fixed = 0;
+    if options.fix_missing
+        && !report.missing.is_empty()
+        && confirm(&format!(
+            "Remove {} missing items from the database?",
+            report.missing.len()
+        ))?
+    {
+        for item in &report.missing {
+            if let Some(id) = item.id {
+                db.remove_item(id)?;
+                hooks.item(Event::ItemRemoved, item);
+            }
+        }
+        fixed += report.missing.len();
+        println!("Removed {} missing items", fmt.count(report.missing.len() as u64));
+        let albums = prune_empty_albums(db, false)?;
+        if albums > 0 {
+            println!("Removed {} empty albums", fmt.count(albums));
+        }
+    }
+
+    if options.add_untracked && !report.untracked.is_empty() {
+        // The files are already in the library; move them to their
+        // formatted location rather than copying them onto themselves
+        let importer = Importer::new(db, import_config(config, Action::Move)?)?
+            .with_progress(ConsoleProgress::new())
+            .with_hooks(hooks.clone());
+        let scan = importer.import_files(report.untracked.clone()).await?;
+        fixed += report.untracked.len() - scan.failures.len();
+        log_cache_counts(&importer);
+        print_scan_report(&scan);
+    }
+
+    if fixed < report.problem_count() {
+        Ok(Outcome::Failed)
+    } else {
+        Ok(Outcome::Success)
+    }
+}
+
+/// Re-read the tags of matching items whose files changed since they were
+/// last read, or of all of them with `force`, resolving differences from
+/// corrected values as `corrected` says. Missing files are reported, and with
+/// `prune` their items removed, running the `item_removed` hook for each.
+fn update(
+    db: &Database,
+    fmt: &Formatter,
+    hooks: &Hooks,
+    query: Option<&str>,
+    force: bool,
+    prune: bool,
+    corrected: Corrected,
+) -> Result<()> {
+    let report = rsbts::update::update(db, db.query_items(query)?, force, corrected)?;
+    for item in &report.missing {
+        println!("missing: {}", item.path.display());
+    }
+    for (path, e) in &report.failures {
+        warn!("Could not read {}: {e}", path.display());
+    }
+    for (path, diffs) in &report.kept {
+        let fields: Vec<String> = diffs
+            .iter()
+            .map(|d| format!("{} '{}' (file: '{}')", d.field, d.ours, d.theirs))
+            .collect();
+        warn!("Keeping corrected {} for {}", fields.join(", "), path.display());
+    }
+    if !report.kept.is_empty() {
+        warn!(
+            "{} files differ from corrected values; use --prefer-file to take their tags \
+             or --write to correct them",
+            report.kept.len()
+        );
+    }
+
+    println!(
+        "checked {}, re-read {}, changed {}, missing {}",
+        fmt.count(report.checked as u64),
+        fmt.count(report.reread as u64),
+        fmt.count(report.changed as u64),
+        fmt.count(report.missing.len() as u64)
+    );
+    if !report.written.is_empty() {
+        println!("Wrote corrected values to {} files", fmt.count(report.written.len() as u64));
+    }
+    if prune && !report.missing.is_empty() {
+        for item in &report.missing {
+            if let Some(id) = item.id {
+                db.remove_item(id)?;
+            }
+            hooks.item(Event::ItemRemoved, item);
+        }
+        println!("Removed {} missing items", fmt.count(report.missing.len() as u64));
+        let albums = prune_empty_albums(db, false)?;
+        if albums > 0 {
+            println!("Removed {} empty albums", fmt.count(albums));
+        }
+    } else if !report.missing.is_empty() {
+        println!("Run `rsbts update --prune` to remove missing items");
+    }
+    Ok(())
+}
+
+/// Measure matching items and store their `ReplayGain` values, with `write`
+/// also in their tags. A file that can't be measured or written is reported
+/// and left unchanged.
+fn replaygain(db: &Database, query: Option<&str>, write: bool, force: bool) -> Result<()> {
+    let items = db.query_items(query)?;
+    let (groups, skipped) = replaygain::groups(db, items, force)?;
+    let analyzed = replaygain::analyze(groups, &ConsoleProgress::new());
+
+    let mut count = 0;
+    let mut failed = 0;
+    for Analyzed { item, track, album } in analyzed {
+        let Some(id) = item.id else {
+            continue;
+        };
+        // Errors were already shown by the progress reporter
+        let track = track.ok();
+        if track.is_none() && album.is_none() {
+            failed += 1;
+            continue;
+        }
+        if write {
+            if let Err(e) = write_replaygain(&item.path, track, album) {
+                warn!("Skipping {}: {e}", item.path.display());
+                failed += 1;
+                continue;
+            }
+        }
+        db.set_replaygain(id, track, album)?;
+        if write {
+            // Keep `check` from reporting our own write as a modification
+            let metadata = std::fs::metadata(&item.path)?;
+            db.set_file_stat(id, metadata.modified()?.into(), metadata.len())?;
+        }
+        count += 1;
+    }
+
+    println!("Analyzed {count} items");
+    if skipped > 0 {
+        println!("{skipped} items already had values (use --force to redo them)");
+    }
+    if failed > 0 {
+        println!("{failed} items could not be analyzed and were left unchanged");
+    }
+    Ok(())
+}
+
+/// Items listed before asking whether to change or remove them.
+const PREVIEW: usize = 10;
+
+/// Print the first [`PREVIEW`] of `entries` as `show` does, then how many
+/// more there are.
+fn preview<T>(
+    out: &mut impl std::io::Write,
+    entries: &[T],
+    show: impl Fn(&T) -> String,
+) -> Result<()> {
+    for entry in entries.iter().take(PREVIEW) {
+        writeln!(out, "{}", show(entry))?;
+    }
+    if entries.len() > PREVIEW {
+        writeln!(out, "  ...and {} more", entries.len() - PREVIEW)?;
+    }
+    Ok(())
+}
+
+/// What [`remove`] deleted.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Removed {
+    /// Items the query matched.
+    matched: usize,
+    /// Item rows removed from the database.
+    rows: usize,
+    /// Files erased from disk.
+    files: usize,
+    /// Files that could not be erased.
+    failed: usize,
+    /// Albums left empty and removed.
+    albums: u64,
+    /// Directories left holding only clutter and removed.
+    dirs: usize,
+}
+
+/// What [`remove`] does with the files of the items it removes.
+#[derive(Debug, Clone, Copy)]
+enum Delete<'a> {
+    /// Keep them.
+    No,
+    /// Erase them, leaving their directories.
+    Files,
+    /// Erase them and prune the directories they leave empty.
+    FilesAndDirs(&'a Pruner),
+}
+
+/// Remove matching items, erasing their files as `delete` says.
+///
+/// The matches are previewed on `out` and nothing happens unless `yes` is set
+/// or `prompt` confirms. A file that can't be erased is counted and skipped.
+fn remove(
+    out: &mut impl std::io::Write,
+    prompt: &mut impl Prompt,
+    db: &Database,
+    hooks: &Hooks,
+    query: &str,
+    delete: Delete<'_>,
+    yes: bool,
+) -> Result<Removed> {
+    let items = db.query_items(Some(query))?;
+    if items.is_empty() {
+        writeln!(out, "No items matched")?;
+        return Ok(Removed::default());
+    }
+
+    preview(out, &items, |item| {
+        format!("  {} - {} - {}", item.artist, item.album, item.title)
+    })?;
+    let question = if matches!(delete, Delete::Files | Delete::FilesAndDirs(_)) {
+        format!(
+            "Remove {} items and permanently erase their files from disk?",
+            items.len()
+        )
+    } else {
+        format!("Remove {} items from the database (files are kept)?", items.len())
+    };
+    let mut removed = Removed {
+        matched: items.len(),
+        ..Removed::default()
+    };
+    if !yes && !prompt.confirm(&question)? {
+        return Ok(removed);
+    }
+
+    for item in &items {
+        if let Some(id) = item.id {
+            db.remove_item(id)?;
+            removed.rows += 1;
+        }
+        if !matches!(delete, Delete::No) {
+            match std::fs::remove_file(&item.path) {
+                Ok(()) => removed.files += 1,
+                Err(e) => {
+                    warn!("Failed to delete {}: {e}", item.path.display());
+                    removed.failed += 1;
+                }
+            }
+        }
+        hooks.item(Event::ItemRemoved, item);
+    }
+    removed.albums = prune_empty_albums(db, !matches!(delete, Delete::No))?;
+    if let Delete::FilesAndDirs(pruner) = delete {
+        let dirs = items.iter().filter_map(|item| item.path.parent());
+        removed.dirs = pruner.prune(dirs.map(Path::to_path_buf)).len();
+    }
+
+    writeln!(out, "Removed {} items from the database", removed.rows)?;
+    if !matches!(delete, Delete::No) {
+        writeln!(out, "Deleted {} files", removed.files)?;
+    }
+    if removed.failed > 0 {
+        writeln!(out, "{} files could not be deleted", removed.failed)?;
+    }
+    if removed.albums > 0 {
+        writeln!(out, "Removed {} empty albums", removed.albums)?;
+    }
+    if removed.dirs > 0 {
+        writeln!(out, "Removed {} empty directories", removed.dirs)?;
+    }
+    Ok(removed)
+}
+
+/// Delete albums left without items, and with `delete_art` their cover files.
+fn prune_empty_albums(db: &Database, delete_art: bool) -> Result<u64> {
+    let artpaths: Vec<PathBuf> = if delete_art {
+        db.empty_albums()?.into_iter().filter_map(|a| a.artpath).collect()
+    } else {
+        Vec::new()
+    };
+    let pruned = db.prune_empty_albums()?;
+    for path in artpaths {
+        if let Err(e) = std::fs::remove_file(&path) {
+            warn!("Failed to delete {}: {e}", path.display());
+        }
+    }
+    Ok(pruned)
+}
+
+/// Send log messages to stderr: warnings and errors always, progress unless
+/// `quiet`, and with a `verbosity` of 1 or more each file, with 2 or more
+/// each `MusicBrainz` request. Other crates only get to warn.
+pub fn init_logging(verbosity: u8, quiet: bool) {
+    use std::io::Write as _;
+
+    let level = match (quiet, verbosity) {
+        (true, _) => LevelFilter::Warn,
+        (false, 0) => LevelFilter::Info,
+        (false, 1) => LevelFilter::Debug,
+        (false, _) => LevelFilter::Trace,
+    };
+    env_logger::Builder::new()
+        .filter_level(LevelFilter::Warn)
+        .filter_module("rsbts", level)
+        .format(|out, record| match record.level() {
+            Level::Error => writeln!(out, "Error: {}", record.args()),
+            Level::Warn => writeln!(out, "Warning: {}", record.args()),
+            _ => writeln!(out, "{}", record.args()),
+        })
+        .init();
+}
+
+/// Print the external commands found on PATH.
+pub fn list_external() {
+    let found = external::discover(&std::env::var_os("PATH").unwrap_or_default());
+    if found.is_empty() {
+        println!("No external commands found (add {}<name> executables to PATH)", external::PREFIX);
+    }
+    for (name, path) in found {
+        println!("{name:<16} {}", path.display());
+    }
+}
+
+/// Run the external command named by `args[0]` with the remaining arguments,
+/// exiting with its status. Unknown names get clap's usual error before the
+/// config is loaded.
+fn run_external(
+    location: &ConfigLocation,
+    sets: &[Override],
+    args: &[OsString],
+) -> Result<Outcome> {
+    let Some((name, rest)) = args.split_first() else {
+        return Ok(Outcome::Failed);
+    };
+    let name = name.to_string_lossy();
+    let search_path = std::env::var_os("PATH").unwrap_or_default();
+    let Some(program) = external::find(&name, &search_path) else {
+        Cli::command()
+            .error(
+                ErrorKind::InvalidSubcommand,
+                format!("unrecognized subcommand '{name}'"),
+            )
+            .exit();
+    };
+
+    let config = Config::load(location, sets)?;
+    let config_path = location.path();
+    let status = external::command(&program, rest, &config, config_path.as_deref())
+        .status()
+        .with_context(|| format!("Failed to run {}", program.display()))?;
+    // Killed by a signal: report failure like a shell would
+    Ok(status
+        .code()
+        .and_then(|code| u8::try_from(code).ok())
+        .map_or(Outcome::Failed, Outcome::External))
+}
+
+/// How `modify` goes about changing what its query matches.
+struct ModifyOptions<'a> {
+    /// Joins the parts of a text field added to with `+=`, and splits them
+    /// for `-=`.
+    separator: &'a str,
+    /// Write tag changes into the files as well.
+    write: bool,
+    /// Don't ask for confirmation.
+    yes: bool,
+    /// Only show what would change.
+    pretend: bool,
+    /// Allow a query that matches everything.
+    all: bool,
+    /// Most items, or albums, changed without `--force`; `None` for any
+    /// number.
+    limit: Option<usize>,
+}
+
+impl ModifyOptions<'_> {
+    /// Refuse a query that leaves nothing out, such as an empty one, unless
+    /// `--all` says the whole library is meant.
+    fn check_query(&self, query: &str) -> Result<()> {
+        if !self.all && rsbts::query::is_unfiltered(&rsbts::query::parse(query)?) {
+            anyhow::bail!("The query matches everything; pass --all to modify the whole library");
+        }
+        Ok(())
+    }
+
+    /// Refuse to change `count` items or albums if that's over the limit.
+    fn check_count(&self, count: usize, what: &str) -> Result<()> {
+        if let Some(limit) = self.limit.filter(|&limit| count > limit) {
+            anyhow::bail!(
+                "Refusing to modify {count} {what}, more than \
+                 safety.max_modify_without_force ({limit}); pass --force to go ahead"
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Change fields on matching items and, with `write`, in their files' tags,
+/// after showing the first items' old and new values and asking `prompt`.
+/// Text fields may be added to or removed from (`field+=value`,
+/// `field-=value`), with parts joined by `separator`. Flexible attributes are
+/// set with `attr.key=value`. Nothing is changed with `pretend`, which shows
+/// every item's changes instead.
+///
+/// Items whose file can't be written are skipped, so the database never
+/// disagrees with a file it claims to have updated.
+fn modify(
+    prompt: &mut impl Prompt,
+    db: &Database,
+    hooks: &Hooks,
+    query: &str,
+    fields: &[String],
+    options: &ModifyOptions<'_>,
+) -> Result<usize> {
+    let (attributes, fields) = split_attributes(fields)?;
+    let changes = fields
+        .iter()
+        .map(|field| FieldChange::parse(field, options.separator))
+        .collect::<rsbts::Result<Vec<_>>>()?;
+    options.check_query(query)?;
+    let items = db.query_items(Some(query))?;
+    let matched = items.len();
+    if items.is_empty() {
+        println!("No items matched");
+        return Ok(matched);
+    }
+
+    // What each item would become, leaving out values it already has
+    let mut planned: Vec<Planned> = Vec::new();
+    for item in items {
+        let Some(id) = item.id else {
+            continue;
+        };
+        let edits: Vec<FieldEdit> = FieldChange::resolve(&changes, &item)?
+            .into_iter()
+            .filter(|edit| *edit.value() != item_value(&item, edit.field()))
+            .collect();
+        let mut attribute_edits = Vec::new();
+        for edit in &attributes {
+            let before = db.get_attribute(AttributeOwner::Item(id), &edit.key)?;
+            if before != edit.value {
+                attribute_edits.push((edit.clone(), before));
+            }
+        }
+        if !edits.is_empty() || !attribute_edits.is_empty() {
+            planned.push((id, item, edits, attribute_edits));
+        }
+    }
+    if planned.is_empty() {
+        println!("No items would change");
+        return Ok(matched);
+    }
+
+    // Ratings, play counts and attributes are kept in the library only
+    let tags = planned.iter().flat_map(|(_, _, edits, _)| edits).any(FieldEdit::is_tag);
+    let write = options.write && tags;
+    let names = field_names(planned.iter().flat_map(|(_, _, edits, attribute_edits)| {
+        let names = edits.iter().map(|edit| edit.field().to_string());
+        names.chain(attribute_edits.iter().map(|(edit, _)| edit.name()))
+    }));
+    let target = if write { "database and files" } else { "database only" };
+    if options.pretend {
+        for entry in &planned {
+            println!("{}", planned_changes(entry));
+        }
+        println!("Would change {names} on {} items ({target})", planned.len());
+        return Ok(matched);
+    }
+    options.check_count(planned.len(), "items")?;
+    preview(&mut std::io::stdout(), &planned, planned_changes)?;
+    let question = format!("Change {names} on {} items ({target})?", planned.len());
+    if !options.yes && !prompt.confirm(&question)? {
+        return Ok(matched);
+    }
+
+    let mut count = 0;
+    let mut failed = 0;
+    for (id, item, edits, attribute_edits) in planned {
+        let tag_edits: Vec<FieldEdit> = edits.into_iter().filter(FieldEdit::is_tag).collect();
+        let write = write && !tag_edits.is_empty();
+        if write {
+            if let Err(e) = write_tags(&item.path, &tag_edits) {
+                warn!("Skipping {}: {e}", item.path.display());
+                failed += 1;
+                continue;
+            }
+        }
+        db.modify_item(id, &changes)?;
+        let attribute_edits: Vec<AttributeEdit> =
+            attribute_edits.into_iter().map(|(edit, _)| edit).collect();
+        db.edit_attributes(AttributeOwner::Item(id), &attribute_edits)?;
+        if write {
+            // Keep `check` from reporting our own write as a modification
+            let metadata = std::fs::metadata(&item.path)?;
+            db.set_file_stat(id, metadata.modified()?.into(), metadata.len())?;
+        }
+        if let Some(item) = db.get_item(id)? {
+            hooks.item(Event::ItemModified, &item);
+        }
+        count += 1;
+    }
+
+    println!("Modified {count} items");
+    if failed > 0 {
+        println!("{failed} files could not be written and were left unchanged");
+    }
+    Ok(matched)
+}
+
+/// A track `modify` changes: its id, the track as it is, and the field and
+/// attribute edits, the latter with the values they replace.
+type Planned = (i64, Item, Vec<FieldEdit>, Vec<(AttributeEdit, Option<String>)>);
+
+/// A track's path and the changes `modify` would make to it, a line each.
+fn planned_changes((_, item, edits, attribute_edits): &Planned) -> String {
+    let mut lines = vec![item.path.display().to_string()];
+    for edit in edits {
+        let before = item_value(item, edit.field());
+        lines.push(format!("  {}: {} -> {}", edit.field(), shown(&before), shown(edit.value())));
+    }
+    for (edit, before) in attribute_edits {
+        let [before, after] = [before, &edit.value].map(|v| v.as_deref().unwrap_or("-"));
+        lines.push(format!("  {}: {before} -> {after}", edit.name()));
+    }
+    lines.join("\n")
+}
+
+/// Set the rating of the items `query` matches to `value`, from 0 to 5, or
+/// clear it if `value` is empty. Returns how many items matched.
+fn rate(db: &Database, hooks: &Hooks, query: &str, value: &str) -> Result<usize> {
+    let change = FieldChange::Set(format!("rating={value}").parse()?);
+    let items = db.query_items(Some(query))?;
+    if items.is_empty() {
+        println!("No items matched");
+        return Ok(0);
+    }
+    for id in items.iter().filter_map(|item| item.id) {
+        db.modify_item(id, std::slice::from_ref(&change))?;
+        if let Some(item) = db.get_item(id)? {
+            hooks.item(Event::ItemModified, &item);
+        }
+    }
+    println!("Rated {} items", items.len());
+    Ok(items.len())
+}
+
+/// Count a play at `when` of each item `query` matches. Silent unless
+/// nothing matches, as players run it on every change of track. Returns how
+/// many items matched.
+fn played(db: &Database, query: &str, when: DateTime<Utc>) -> Result<usize> {
+    let items = db.query_items(Some(query))?;
+    if items.is_empty() {
+        warn!("No items matched {query}");
+    }
+    for id in items.iter().filter_map(|item| item.id) {
+        db.record_play(id, when)?;
+    }
+    Ok(items.len())
+}
+
+/// Match the albums of the items `query` selects against `MusicBrainz`
+/// again, as import does, printing what changes for each. Unless `pretend`,
+/// the changes are made, and with `write` written into file tags too. Files
+/// aren't moved.
+// rusqlite::Connection is not Sync, so futures holding &Database aren't Send
+#[allow(clippy::future_not_send)]
+async fn retag(
+    db: &Database,
+    config: &Config,
+    hooks: &Hooks,
+    query: Option<&str>,
+    force: bool,
+    pretend: bool,
+    write: bool,
+) -> Result<()> {
+    let mut album_ids = Vec::new();
+    let mut seen = HashSet::new();
+    for item in db.query_items(query)? {
+        if let Some(id) = item.album_id.filter(|id| seen.insert(*id)) {
+            album_ids.push(id);
+        }
+    }
+
+    let importer =
+        Importer::new(db, import_config(config, config.import.action)?)?.with_hooks(hooks.clone());
+    let (mut changed, mut unmatched, mut skipped, mut failed) = (0, 0, 0, 0);
+    for id in album_ids {
+        let Some(album) = db.get_album(id)? else {
+            continue;
+        };
+        if album.mb_albumid.is_some() && !force {
+            skipped += 1;
+            continue;
+        }
+        info!("Looking up: {} - {}", album.albumartist, album.album);
+        let items = db.album_items(id)?;
+        let Some(rematch) = importer.rematch(&album, items.clone()).await? else {
+            unmatched += 1;
+            continue;
+        };
+        let album_edits = FieldEdit::diff(ALBUM_FIELDS, &album, &rematch.album, album_value);
+        let item_edits: Vec<(&Item, Vec<FieldEdit>)> = items
+            .iter()
+            .zip(&rematch.items)
+            .map(|(before, after)| {
+                (before, FieldEdit::diff(ITEM_FIELDS, before, after, item_value))
+            })
+            .filter(|(_, edits)| !edits.is_empty())
+            .collect();
+        if album_edits.is_empty() && item_edits.is_empty() && rematch.cover_art.is_none() {
+            continue;
+        }
+
+        changed += 1;
+        println!("{} - {}", album.albumartist, album.album);
+        for edit in &album_edits {
+            let before = album_value(&album, edit.field());
+            println!("  {}: {} -> {}", edit.field(), shown(&before), shown(edit.value()));
+        }
+        for (item, edits) in &item_edits {
+            // Album fields were shown once above
+            let edits: Vec<&FieldEdit> = edits
+                .iter()
+                .filter(|edit| !ALBUM_FIELDS.iter().any(|f| f.name == edit.field()))
+                .collect();
+            if edits.is_empty() {
+                continue;
+            }
+            println!("  {}", item.path.file_name().unwrap_or_default().to_string_lossy());
+            for edit in edits {
+                let before = item_value(item, edit.field());
+                println!("    {}: {} -> {}", edit.field(), shown(&before), shown(edit.value()));
+            }
+        }
+        if let Some((path, _)) = &rematch.cover_art {
+            println!("  cover art: {}", path.display());
+        }
+        if pretend {
+            continue;
+        }
+
+        db.modify_album(id, &album_edits)?;
+        for (item, edits) in &item_edits {
+            let Some(item_id) = item.id else {
+                continue;
+            };
+            let changes: Vec<FieldChange> = edits.iter().cloned().map(FieldChange::Set).collect();
+            db.modify_item(item_id, &changes)?;
+            if write {
+                if let Err(e) = write_tags(&item.path, edits) {
+                    warn!("Could not write tags to {}: {e}", item.path.display());
+                    failed += 1;
+                } else {
+                    let metadata = std::fs::metadata(&item.path)?;
+                    db.set_file_stat(item_id, metadata.modified()?.into(), metadata.len())?;
+                }
+            }
+            if let Some(item) = db.get_item(item_id)? {
+                hooks.item(Event::ItemModified, &item);
+            }
+        }
+        if let Some((path, art)) = &rematch.cover_art {
+            match std::fs::write(path, art) {
+                Ok(()) => db.set_album_artpath(id, path)?,
+                Err(e) => warn!("Could not save cover art to {}: {e}", path.display()),
+            }
+        }
+    }
+    log_cache_counts(&importer);
+
+    let verb = if pretend { "Would change" } else { "Changed" };
+    println!("{verb} {changed} albums");
+    if unmatched > 0 {
+        println!("{unmatched} albums had no good MusicBrainz match and were left as they are");
+    }
+    if skipped > 0 {
+        println!("{skipped} albums already have a MusicBrainz release; --force re-matches them");
+    }
+    if failed > 0 {
+        println!("{failed} files could not be written; run `rsbts update` to resync them");
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy)]
+enum ArtMode {
+    /// List albums with no art at all
+    Show,
+    /// Download art for albums without an art file
+    Fetch,
+    /// Save embedded art for albums without an art file
+    Extract,
+    /// Embed albums' art files into their tracks
+    Embed(ArtLimits),
+}
+
+/// Work on the art of the albums `query` selects, printing a line for each
+/// album acted on. With `pretend`, nothing is downloaded or written.
+// rusqlite::Connection is not Sync, so futures holding &Database aren't Send
+#[allow(clippy::future_not_send)]
+async fn art(
+    db: &Database,
+    config: &Config,
+    query: Option<&str>,
+    mode: ArtMode,
+    pretend: bool,
+) -> Result<()> {
+    // Lookups share the MusicBrainz rate limit and the metadata cache
+    let source = match mode {
+        ArtMode::Fetch if !pretend => {
+            let client = MbClient::with_settings(&config.musicbrainz.client_settings())?;
+            Some(MetadataCache::new(client, db.connect()?))
+        }
+        _ => None,
+    };
+    let (mut done, mut failed) = (0, 0);
+    for album in db.query_albums(query)? {
+        let Some(id) = album.id else {
+            continue;
+        };
+        let name = format!("{} - {}", album.albumartist, album.album);
+        let items = db.album_items(id)?;
+        let art_file = album.artpath.as_deref().filter(|path| path.exists());
+        match mode {
+            ArtMode::Show => {
+                if art_file.is_none() && embedded_art(&items).is_none() {
+                    println!("{name}");
+                    done += 1;
+                }
+            }
+            ArtMode::Fetch => {
+                if art_file.is_some() {
+                    continue;
+                }
+                let Some(mbid) = album.mb_albumid.as_deref() else {
+                    println!("{name}: no MusicBrainz release; `rsbts retag` can match one");
+                    continue;
+                };
+                let Some(source) = &source else {
+                    println!("{name}: would fetch art for release {mbid}");
+                    done += 1;
+                    continue;
+                };
+                match source.fetch_cover_art(mbid).await {
+                    Ok(Some(art)) if save_art(db, id, &name, &items, &art)? => done += 1,
+                    Ok(Some(_)) => failed += 1,
+                    Ok(None) => println!("{name}: the Cover Art Archive has no front cover"),
+                    Err(e) => {
+                        warn!("Could not fetch art for {name}: {e}");
+                        failed += 1;
+                    }
+                }
+            }
+            ArtMode::Extract => {
+                if art_file.is_some() {
+                    continue;
+                }
+                let Some(art) = embedded_art(&items) else {
+                    continue;
+                };
+                if pretend {
+                    println!("{name}: would save embedded art as {}", rsbts::art::file_name(&art));
+                    done += 1;
+                    continue;
+                }
+                if save_art(db, id, &name, &items, &art)? {
+                    done += 1;
+                } else {
+                    failed += 1;
+                }
+            }
+            ArtMode::Embed(limits) => {
+                let Some(path) = art_file else {
+                    continue;
+                };
+                let art = std::fs::read(path)
+                    .with_context(|| format!("Could not read {}", path.display()))?;
+                if let Some(reason) = limits.exceeded(&art) {
+                    println!("{name}: not embedded, {} is {reason}", path.display());
+                    continue;
+                }
+                if pretend {
+                    println!("{name}: would embed {} into {} tracks", path.display(), items.len());
+                    done += 1;
+                    continue;
+                }
+                let (embedded, not_embedded) = embed_album_art(db, &items, &art)?;
+                println!("{name}: embedded into {embedded} tracks");
+                done += 1;
+                failed += not_embedded;
+            }
+        }
+    }
+
+    let summary = match (mode, pretend) {
+        (ArtMode::Show, _) => "albums have no art",
+        (ArtMode::Fetch, false) => "albums had art fetched",
+        (ArtMode::Fetch, true) => "albums would have art fetched",
+        (ArtMode::Extract, false) => "albums had embedded art saved",
+        (ArtMode::Extract, true) => "albums would have embedded art saved",
+        (ArtMode::Embed(_), false) => "albums had art embedded",
+        (ArtMode::Embed(_), true) => "albums would have art embedded",
+    };
+    println!("{done} {summary}");
+    if failed > 0 {
+        println!("{failed} albums or files failed; see the warnings above");
+    }
+    Ok(())
+}
+
+/// The art embedded in the first of `items` that has any.
+fn embedded_art(items: &[Item]) -> Option<Vec<u8>> {
+    let options = AnalyzeOptions::TAGS | AnalyzeOptions::EMBEDDED_ART;
+    items
+        .iter()
+        .find_map(|item| analyze_file(&item.path, options, &StdFileOps).ok()?.embedded_art)
+}
+
+/// Save `art` in the directory of the album's tracks and make it the art of
+/// album `id`, named `name` in messages. Returns whether it was saved.
+fn save_art(db: &Database, id: i64, name: &str, items: &[Item], art: &[u8]) -> Result<bool> {
+    let Some(dir) = rsbts::art::album_dir(items) else {
+        return Ok(false);
+    };
+    let path = dir.join(rsbts::art::file_name(art));
+    if let Err(e) = std::fs::write(&path, art) {
+        warn!("Could not save art for {name} to {}: {e}", path.display());
+        return Ok(false);
+    }
+    db.set_album_artpath(id, &path)?;
+    println!("{name}: saved {}", path.display());
+    Ok(true)
+}
+
+/// Embed `art` into each of `items`' files, returning how many it was and
+/// wasn't embedded into.
+fn embed_album_art(db: &Database, items: &[Item], art: &[u8]) -> Result<(u64, u64)> {
+    let (mut embedded, mut failed) = (0, 0);
+    for item in items {
+        let Some(id) = item.id else {
+            continue;
+        };
+        if let Err(e) = embed_art(&item.path, art) {
+            warn!("Could not embed art in {}: {e}", item.path.display());
+            failed += 1;
+            continue;
+        }
+        // Keep `check` from reporting our own write as a modification
+        let metadata = std::fs::metadata(&item.path)?;
+        db.set_file_stat(id, metadata.modified()?.into(), metadata.len())?;
+        embedded += 1;
+    }
+    Ok((embedded, failed))
+}
+
+/// Set fields on matching albums, cascading them to the albums' items and,
+/// with `write`, to the items' file tags, once `prompt` confirms or with
+/// `pretend` only listing the albums. Flexible attributes (`attr.key=value`)
+/// are set on the albums alone, where their items' queries and path formats
+/// find them.
+///
+/// Files are not moved; items whose path format result changed are counted
+/// so the user knows their paths are stale.
+fn modify_albums(
+    prompt: &mut impl Prompt,
+    db: &Database,
+    config: &Config,
+    hooks: &Hooks,
+    query: &str,
+    fields: &[String],
+    options: &ModifyOptions<'_>,
+) -> Result<usize> {
+    let (attributes, fields) = split_attributes(fields)?;
+    let edits = fields
+        .iter()
+        .map(|field| FieldEdit::parse_album(field))
+        .collect::<rsbts::Result<Vec<_>>>()?;
+    options.check_query(query)?;
+    let albums = db.query_albums(Some(query))?;
+    if albums.is_empty() {
+        println!("No albums matched");
+        return Ok(0);
+    }
+
+    // Attributes are kept in the library only
+    let write = options.write && !edits.is_empty();
+    let names = edits.iter().map(|edit| edit.field().to_string());
+    let names = field_names(names.chain(attributes.iter().map(AttributeEdit::name)));
+    let target = if write { "database and files" } else { "database only" };
+    let show = |album: &Album| format!("  {}", album_line(album));
+    if options.pretend {
+        for album in &albums {
+            println!("{}", show(album));
+        }
+        println!("Would change {names} on {} albums ({target})", albums.len());
+        return Ok(albums.len());
+    }
+    options.check_count(albums.len(), "albums")?;
+    preview(&mut std::io::stdout(), &albums, show)?;
+    let question = format!("Change {names} on {} albums ({target})?", albums.len());
+    if !options.yes && !prompt.confirm(&question)? {
+        return Ok(albums.len());
+    }
+
+    let library_dir = &config.library.directory;
+    let formats = config.paths.path_formats()?;
+    let mut failed = 0;
+    let mut stale = 0;
+    for id in albums.iter().filter_map(|album| album.id) {
+        let before: HashMap<Option<i64>, Option<PathBuf>> = db
+            .album_items(id)?
+            .iter()
+            .map(|item| (item.id, formats.destination(library_dir, item, Some(db)).ok()))
+            .collect();
+        db.modify_album(id, &edits)?;
+        db.edit_attributes(AttributeOwner::Album(id), &attributes)?;
+
+        let items = db.album_items(id)?;
+        for item in &items {
+            let after = formats.destination(library_dir, item, Some(db)).ok();
+            if before.get(&item.id) != Some(&after) {
+                stale += 1;
+            }
+            let Some(item_id) = item.id.filter(|_| write) else {
+                continue;
+            };
+            if let Err(e) = write_tags(&item.path, &edits) {
+                warn!("Could not write tags to {}: {e}", item.path.display());
+                failed += 1;
+                continue;
+            }
+            let metadata = std::fs::metadata(&item.path)?;
+            db.set_file_stat(item_id, metadata.modified()?.into(), metadata.len())?;
+        }
+        for item in &items {
+            hooks.item(Event::ItemModified, item);
+        }
+    }
+
+    println!("Modified {} albums", albums.len());
+    if failed > 0 {
+        println!("{failed} files could not be written; run `rsbts update` to resync them");
+    }
+    if stale > 0 {
+        println!("{stale} files no longer match their path format and were not moved");
+    }
+    Ok(albums.len())
+}
+
+/// `modify` assignments split into those to flexible attributes
+/// (`attr.key=value`) and the rest.
+fn split_attributes(fields: &[String]) -> Result<(Vec<AttributeEdit>, Vec<&String>)> {
+    let (attributes, fields): (Vec<&String>, Vec<&String>) =
+        fields.iter().partition(|field| field.starts_with(ATTRIBUTE_PREFIX));
+    let attributes = attributes
+        .into_iter()
+        .map(|field| field.parse())
+        .collect::<rsbts::Result<_>>()?;
+    Ok((attributes, fields))
+}
+
+/// Distinct field names, for confirmation prompts.
+fn field_names(names: impl IntoIterator<Item = String>) -> String {
+    let names: BTreeSet<String> = names.into_iter().collect();
+    names.into_iter().collect::<Vec<_>>().join(", ")
+}
+
+/// `value` as shown in a preview of changes, where NULL is `-`.
+fn shown(value: &Value) -> String {
+    if *value == Value::Null {
+        "-".into()
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use clap::Parser;
+    use rsbts::AudioFormat;
+
+    /// A FLAC track added on 2024-03-09, to fill in with the fields a test
+    /// cares about.
+    fn library_item() -> Item {
+        Item {
+            id: None,
+            album_id: None,
+            path: "/music/track.flac".into(),
+            title: "Track".into(),
+            artist: "Black Sabbath".into(),
+            album: "Paranoid".into(),
+            albumartist: None,
+            artist_sort: None,
+            albumartist_sort: None,
+            genre: None,
+            year: None,
+            track: None,
+            disc: Some(1),
+            format: AudioFormat::Flac,
+            bitrate: 900,
+            length: 1234.5678,
+            samplerate: None,
+            channels: None,
+            bitdepth: None,
+            mb_trackid: None,
+            mb_albumid: None,
+            mb_releasegroupid: None,
+            added: Utc.with_ymd_and_hms(2024, 3, 9, 12, 0, 0).unwrap(),
+            mtime: Utc.with_ymd_and_hms(2024, 3, 9, 12, 0, 0).unwrap(),
+            size: None,
+            source_path: None,
+            import_run: None,
+            rg_track_gain: None,
+            rg_track_peak: None,
+            rg_album_gain: None,
+            rg_album_peak: None,
+            rating: None,
+            play_count: 0,
+            last_played: None,
+        }
+    }
+
+    fn library(tracks: &[(&str, &str, u32, &str)]) -> Database {
+        let db = Database::open_in_memory().unwrap();
+        db.migrate().unwrap();
+        for (artist, album, track, title) in tracks {
+            db.insert_item(&Item {
+                path: format!("/music/{artist}/{album}/{track} {title}.flac").into(),
+                title: (*title).into(),
+                artist: (*artist).into(),
+                album: (*album).into(),
+                track: Some(*track),
+                ..library_item()
+            })
+            .unwrap();
+        }
+        db
+    }
+
+    #[test]
+    fn test_stable_ls_ignores_insert_order() {
+        // Includes ties on artist, album, disc and track
+        let mut tracks = vec![
+            ("Black Sabbath", "Paranoid", 1, "War Pigs"),
+            ("Black Sabbath", "Paranoid", 1, "War Pigs (Live)"),
+            ("Black Sabbath", "Paranoid", 2, "Paranoid"),
+            ("Abba", "Arrival", 1, "When I Kissed the Teacher"),
+            ("Abba", "Arrival", 1, "Dancing Queen"),
+        ];
+        let mut outputs = Vec::new();
+        for _ in 0..2 {
+            let db = library(&tracks);
+            let mut out = Vec::new();
+            list(&mut out, &db, &Formatter::stable(), None, Page::default(), None).unwrap();
+            outputs.push(out);
+            tracks.reverse();
+        }
+
+        assert_eq!(outputs[0], outputs[1]);
+        let text = String::from_utf8(outputs.swap_remove(0)).unwrap();
+        assert_eq!(
+            text.lines().next(),
+            Some("Abba - Arrival - Dancing Queen [20:34]")
+        );
+    }
+
+    #[test]
+    fn test_ls_recent_imports_newest_first() {
+        let db = library(&[]);
+        let now = Utc::now();
+        for (title, days_ago) in [("War Pigs", 3), ("Paranoid", 1), ("Iron Man", 30)] {
+            let added = now - chrono::Duration::days(days_ago);
+            db.insert_item(&Item {
+                path: format!("/music/{title}.flac").into(),
+                title: title.into(),
+                added,
+                mtime: added,
+                ..library_item()
+            })
+            .unwrap();
+        }
+
+        let mut out = Vec::new();
+        let query = Some("added:-1w added-");
+        let listed = list(&mut out, &db, &Formatter::stable(), query, Page::default(), None);
+        assert_eq!(listed.unwrap(), 2);
+        let titles: Vec<&str> = std::str::from_utf8(&out)
+            .unwrap()
+            .lines()
+            .filter_map(|line| line.split(" - ").nth(2))
+            .collect();
+        assert_eq!(titles, ["Paranoid [20:34]", "War Pigs [20:34]"]);
+    }
+
+    #[test]
+    fn test_recent_groups_by_album_and_period() {
+        let db = library(&[]);
+        // 2024-03-04 and 2024-03-11 are Mondays
+        let day = |day| Utc.with_ymd_and_hms(2024, 3, day, 12, 0, 0).unwrap();
+        let album_id = db
+            .insert_album(&Album {
+                id: None,
+                album: "Paranoid".into(),
+                albumartist: "Black Sabbath".into(),
+                year: Some(1970),
+                artpath: None,
+                mb_albumid: None,
+                mb_releasegroupid: None,
+                added: day(4),
+                source_path: None,
+                import_run: None,
+                disambiguation: None,
+            })
+            .unwrap();
+        let tracks = [
+            (Some(album_id), "Paranoid", 2, day(4)),
+            (Some(album_id), "War Pigs", 1, day(4)),
+            (None, "Iron Man", 4, day(1)),
+            (None, "Dancing Queen", 1, day(11)),
+            (None, "Fairies Wear Boots", 8, day(1) - chrono::Duration::days(10)),
+        ];
+        for (album_id, title, track, added) in tracks {
+            db.insert_item(&Item {
+                album_id,
+                path: format!("/music/{title}.flac").into(),
+                title: title.into(),
+                artist: if title == "Dancing Queen" { "Abba" } else { "Black Sabbath" }.into(),
+                album: if title == "Dancing Queen" { "Arrival" } else { "Paranoid" }.into(),
+                track: Some(track),
+                added,
+                ..library_item()
+            })
+            .unwrap();
+        }
+
+        let groups = recent(&db, Utc.with_ymd_and_hms(2024, 3, 1, 0, 0, 0).unwrap()).unwrap();
+        let names: Vec<String> = groups.iter().map(RecentGroup::name).collect();
+        assert_eq!(
+            names,
+            ["Abba - Arrival", "Black Sabbath - Paranoid (1970)", "Black Sabbath - Paranoid"]
+        );
+        let titles: Vec<&str> = groups[1].items.iter().map(|item| item.title.as_str()).collect();
+        assert_eq!(titles, ["War Pigs", "Paranoid"]);
+
+        let mut out = Vec::new();
+        let fmt = Formatter::stable();
+        print_recent(&mut out, &fmt, &groups, true, Some(Period::Week)).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "== Week of 2024-03-11 ==\n\
+             2024-03-11T12:00:00Z  Abba - Arrival, 1 track\n\n\
+             == Week of 2024-03-04 ==\n\
+             2024-03-04T12:00:00Z  Black Sabbath - Paranoid (1970), 2 tracks\n\n\
+             == Week of 2024-02-26 ==\n\
+             2024-03-01T12:00:00Z  Black Sabbath - Paranoid, 1 track\n"
+        );
+
+        let mut out = Vec::new();
+        print_recent_json(&mut out, &groups, Some(Period::Month)).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(json[1]["album"]["year"], 1970);
+        assert_eq!(json[1]["items"][0]["title"], "War Pigs");
+        assert_eq!((&json[2]["album"], &json[2]["period"]), (&().into(), &"2024-03".into()));
+    }
+
+    /// `modify` options for a test: tags written if they could be, no
+    /// questions and no limit.
+    fn unasked() -> ModifyOptions<'static> {
+        ModifyOptions {
+            separator: "; ",
+            write: true,
+            yes: true,
+            pretend: false,
+            all: false,
+            limit: None,
+        }
+    }
+
+    /// Answers questions from a script and records them.
+    struct Scripted {
+        answers: Vec<bool>,
+        asked: Vec<String>,
+    }
+
+    impl Prompt for Scripted {
+        fn confirm(&mut self, question: &str) -> Result<bool> {
+            self.asked.push(question.to_string());
+            Ok(self.answers.remove(0))
+        }
+    }
+
+    #[test]
+    fn test_cli_definition() {
+        // Catches clashing short flags and the like, which clap only checks when built
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_groupings_are_checked_by_clap() {
+        let parse = |args: &[&str]| Cli::command().try_get_matches_from(args);
+        assert!(parse(&["rsbts", "recent", "--group-by", "week"]).is_ok());
+        let err = parse(&["rsbts", "recent", "--group-by", "fortnight"]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidValue);
+        assert!(parse(&["rsbts", "stats", "--by", "year"]).is_ok());
+        assert!(parse(&["rsbts", "stats", "--by", "album"]).is_err());
+    }
+
+    #[test]
+    fn test_rebuild_fts_is_reindex() {
+        for name in ["reindex", "rebuild-fts"] {
+            let cli = Cli::try_parse_from(["rsbts", "db", name]).unwrap();
+            assert!(matches!(
+                cli.command,
+                Commands::Db {
+                    command: DbCommands::Reindex
+                }
+            ));
+        }
+    }
+
+    #[test]
+    fn test_undo_import_is_undo() {
+        let cli = Cli::try_parse_from(["rsbts", "undo-import", "--last"]).unwrap();
+        assert!(matches!(cli.command, Commands::Undo { last: true, .. }));
+    }
+
+    #[test]
+    fn test_archive_keeps_at_least_one() {
+        let parse = |keep: &str| {
+            let args = ["rsbts", "export", "--archive", "backups", "--keep", keep];
+            Cli::command().try_get_matches_from(args)
+        };
+        assert!(parse("0").is_err());
+        assert!(parse("1").is_ok());
+    }
+
+    #[test]
+    fn test_completions_offer_fields_and_aliases() {
+        let script = |shell| {
+            let mut out = Vec::new();
+            completions(shell, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        let bash = script(Shell::Bash);
+        assert!(bash.contains("artist:"), "{bash}");
+        assert!(bash.contains("rating="));
+        assert!(bash.contains("remove"));
+        assert!(bash.ends_with(BASH_FIELD_SUFFIX));
+        let fish = script(Shell::Fish);
+        assert!(fish.contains("smart_artist:"), "{fish}");
+        assert!(!script(Shell::Zsh).is_empty());
+        assert!(!script(Shell::PowerShell).is_empty());
+    }
+
+    #[test]
+    fn test_fields_table_and_json() {
+        let mut out = Vec::new();
+        fields(&mut out, false).unwrap();
+        let table = String::from_utf8(out).unwrap();
+        let row = |name: &str| {
+            let line = table.lines().find(|l| l.split_whitespace().next() == Some(name));
+            line.unwrap().split_whitespace().collect::<Vec<_>>()
+        };
+        assert_eq!(row("title"), ["title", "string", "no", "tags"]);
+        assert_eq!(row("rating"), ["rating", "int", "yes", "library"]);
+        assert_eq!(row("smart_artist"), ["smart_artist", "string", "no", "-"]);
+
+        let mut out = Vec::new();
+        fields(&mut out, true).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        let last_played = json
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|f| f["name"] == "last_played")
+            .unwrap();
+        assert_eq!(last_played["type"], "date");
+        assert_eq!((&last_played["tag"], &last_played["editable"]), (&false.into(), &true.into()));
+    }
+
+    #[test]
+    fn test_rate_and_played() {
+        let db = library(&[
+            ("Black Sabbath", "Paranoid", 1, "War Pigs"),
+            ("Black Sabbath", "Paranoid", 2, "Paranoid"),
+            ("Abba", "Arrival", 1, "Dancing Queen"),
+        ]);
+        let hooks = Hooks::disabled();
+        assert_eq!(rate(&db, &hooks, "album:Paranoid", "4").unwrap(), 2);
+        assert!(rate(&db, &hooks, "album:Paranoid", "6").is_err());
+        assert_eq!(rate(&db, &hooks, "album:Nothing", "3").unwrap(), 0);
+
+        let when = Utc.with_ymd_and_hms(2024, 5, 1, 21, 30, 0).unwrap();
+        assert_eq!(played(&db, "title:Paranoid", when).unwrap(), 1);
+        assert_eq!(played(&db, "title:Paranoid", when).unwrap(), 1);
+        let item = db.query_items(Some("title:Paranoid")).unwrap().remove(0);
+        assert_eq!((item.rating, item.play_count, item.last_played), (Some(4), 2, Some(when)));
+
+        // Library-only fields never touch the files, which don't exist here
+        let fields = ["rating=".to_string(), "play_count=0".to_string()];
+        let modified = modify(&mut Terminal, &db, &hooks, "album:Paranoid", &fields, &unasked());
+        assert_eq!(modified.unwrap(), 2);
+        let item = db.query_items(Some("title:Paranoid")).unwrap().remove(0);
+        assert_eq!((item.rating, item.play_count), (None, 0));
+        assert_eq!(db.query_items(Some("rating:0..5")).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_modify_attributes() {
+        let db = library(&[
+            ("Black Sabbath", "Paranoid", 1, "War Pigs"),
+            ("Black Sabbath", "Paranoid", 2, "Paranoid"),
+            ("Abba", "Arrival", 1, "Dancing Queen"),
+        ]);
+        let hooks = Hooks::disabled();
+        let edit = |query: &str, fields: &[String], write| {
+            let options = ModifyOptions { write, ..unasked() };
+            modify(&mut Terminal, &db, &hooks, query, fields, &options)
+        };
+        // Attributes never touch the files, which don't exist here
+        let fields = ["attr.source=vinyl rip".to_string()];
+        assert_eq!(edit("album:Paranoid", &fields, true).unwrap(), 2);
+        let fields = ["attr.source=cassette".to_string(), "attr.mood=sunny".to_string()];
+        assert_eq!(edit("title:Queen", &fields, true).unwrap(), 1);
+        let titles = |query: &str| -> Vec<String> {
+            let items = db.query_items(Some(query)).unwrap();
+            items.into_iter().map(|item| item.title).collect()
+        };
+        assert_eq!(titles("attr.source:vinyl title+"), ["Paranoid", "War Pigs"]);
+        assert_eq!(titles("attr.mood:sunny attr.source:=cassette"), ["Dancing Queen"]);
+
+        let fields = ["attr.mood!".to_string(), "rating=3".to_string()];
+        assert_eq!(edit("title:Queen", &fields, false).unwrap(), 1);
+        assert!(titles("attr.mood:sunny").is_empty());
+        assert_eq!(titles("rating:3"), ["Dancing Queen"]);
+        let fields = ["attr.mood+=x".to_string()];
+        assert!(edit("title:Queen", &fields, false).is_err());
+    }
+
+    #[test]
+    fn test_modify_needs_all_and_force_for_broad_queries() {
+        let db = library(&[
+            ("Black Sabbath", "Paranoid", 1, "War Pigs"),
+            ("Black Sabbath", "Paranoid", 2, "Paranoid"),
+            ("Abba", "Arrival", 1, "Dancing Queen"),
+        ]);
+        let hooks = Hooks::disabled();
+        let fields = ["rating=4".to_string()];
+        let rated = || db.query_items(Some("rating:4")).unwrap().len();
+        let mut prompt = Scripted {
+            answers: vec![false, true],
+            asked: Vec::new(),
+        };
+
+        // A query that leaves nothing out needs --all
+        for query in ["", "  ", "title+"] {
+            let err = modify(&mut prompt, &db, &hooks, query, &fields, &unasked()).unwrap_err();
+            assert!(err.to_string().contains("pass --all"), "{err}");
+        }
+        // Over the limit nothing changes, even with --yes
+        let limited = ModifyOptions {
+            all: true,
+            limit: Some(2),
+            ..unasked()
+        };
+        let err = modify(&mut prompt, &db, &hooks, "", &fields, &limited).unwrap_err();
+        assert!(err.to_string().contains("Refusing to modify 3 items"), "{err}");
+        let pretend = ModifyOptions { pretend: true, ..limited };
+        assert_eq!(modify(&mut prompt, &db, &hooks, "", &fields, &pretend).unwrap(), 3);
+        assert_eq!(rated(), 0);
+
+        let asking = ModifyOptions { yes: false, ..limited };
+        for _ in 0..2 {
+            let paranoid = modify(&mut prompt, &db, &hooks, "album:Paranoid", &fields, &asking);
+            assert_eq!(paranoid.unwrap(), 2);
+        }
+        assert_eq!(rated(), 2);
+        assert_eq!(prompt.asked, ["Change rating on 2 items (database only)?"; 2]);
+        let forced = ModifyOptions { limit: None, ..limited };
+        assert_eq!(modify(&mut prompt, &db, &hooks, "", &fields, &forced).unwrap(), 3);
+        assert_eq!(rated(), 3);
+    }
+
+    #[test]
+    fn test_remove_confirms_and_counts_deleted_files() {
+        let db = library(&[
+            ("Black Sabbath", "Paranoid", 1, "War Pigs"),
+            ("Black Sabbath", "Paranoid", 2, "Paranoid"),
+            ("Abba", "Arrival", 1, "Dancing Queen"),
+        ]);
+        // One match whose file exists; the others' /music paths don't
+        let file = std::env::temp_dir().join(format!("rsbts-remove-{}.flac", std::process::id()));
+        std::fs::write(&file, b"").unwrap();
+        let mut item = db.query_items(Some("title:War")).unwrap().remove(0);
+        item.id = None;
+        item.path.clone_from(&file);
+        item.title = "Iron Man".into();
+        db.insert_item(&item).unwrap();
+
+        let mut prompt = Scripted {
+            answers: vec![false, true],
+            asked: Vec::new(),
+        };
+        let hooks = Hooks::disabled();
+        let mut out = Vec::new();
+        let declined =
+            remove(&mut out, &mut prompt, &db, &hooks, "album:Paranoid", Delete::Files, false)
+                .unwrap();
+        assert_eq!(
+            declined,
+            Removed {
+                matched: 3,
+                ..Removed::default()
+            }
+        );
+        assert_eq!(db.query_items(None).unwrap().len(), 4);
+        assert!(prompt.asked[0].contains("erase their files from disk"));
+        let preview = String::from_utf8(out).unwrap();
+        assert!(preview.contains("Black Sabbath - Paranoid - Iron Man"), "{preview}");
+
+        let removed = remove(
+            &mut Vec::new(),
+            &mut prompt,
+            &db,
+            &hooks,
+            "album:Paranoid",
+            Delete::Files,
+            false,
+        )
+        .unwrap();
+        let exists = file.exists();
+        let _ = std::fs::remove_file(&file);
+        assert_eq!(
+            removed,
+            Removed {
+                matched: 3,
+                rows: 3,
+                files: 1,
+                failed: 2,
+                albums: 0,
+                dirs: 0,
+            }
+        );
+        assert!(!exists);
+        assert_eq!(db.query_items(None).unwrap().len(), 1);
+
+        // --yes doesn't ask
+        let removed =
+            remove(&mut Vec::new(), &mut prompt, &db, &hooks, "artist:Abba", Delete::No, true)
+                .unwrap();
+        assert_eq!(removed.rows, 1);
+        assert_eq!(prompt.asked.len(), 2);
+    }
+
+    #[test]
+    fn test_skip_log_round_trip() {
+        let path = std::env::temp_dir().join(format!("rsbts-skip-log-{}", std::process::id()));
+        let report = ScanReport {
+            retry: vec!["/incoming/Arrival".into(), "/incoming/mixtape.zip".into()],
+            ..ScanReport::default()
+        };
+        write_skip_log(&path, &report).unwrap();
+        let mut log = std::fs::read_to_string(&path).unwrap();
+        // Lines of other logs are passed over
+        log.push_str("unreadable\t/incoming/broken.flac\tbad header\n");
+        std::fs::write(&path, log).unwrap();
+        let paths = read_skip_log(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            paths,
+            [PathBuf::from("/incoming/Arrival"), PathBuf::from("/incoming/mixtape.zip")]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_update_prune_runs_removed_hooks() {
+        let dir = std::env::temp_dir().join(format!("rsbts-update-prune-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let log = dir.join("log");
+        let db = library(&[]);
+        db.insert_item(&Item {
+            path: dir.join("Gone.flac"),
+            title: "Gone".into(),
+            ..library_item()
+        })
+        .unwrap();
+        let hooks = Hooks::new(HashMap::from([(
+            Event::ItemRemoved,
+            vec![
+                "sh".into(),
+                "-c".into(),
+                r#"echo "$1" >> "$2""#.into(),
+                "sh".into(),
+                "{title}".into(),
+                log.to_string_lossy().into_owned(),
+            ],
+        )]));
+
+        update(&db, &Formatter::stable(), &hooks, None, false, true, Corrected::Keep).unwrap();
+        let logged = std::fs::read_to_string(&log).unwrap();
+        let left = db.query_items(None).unwrap().len();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(logged, "Gone\n");
+        assert_eq!(left, 0);
+    }
+
+    #[test]
+    fn test_duplicates_delete_asks_and_prunes_emptied_albums() {
+        let dir = std::env::temp_dir().join(format!("rsbts-dup-delete-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let db = library(&[]);
+        for (album, format) in [
+            ("Paranoid", AudioFormat::Flac),
+            ("Singles", AudioFormat::Mp3),
+        ] {
+            let album_id = db
+                .insert_album(&Album {
+                    id: None,
+                    album: album.into(),
+                    albumartist: "Black Sabbath".into(),
+                    year: None,
+                    artpath: None,
+                    mb_albumid: None,
+                    mb_releasegroupid: None,
+                    added: Utc::now(),
+                    source_path: None,
+                    import_run: None,
+                    disambiguation: None,
+                })
+                .unwrap();
+            let path = dir.join(format!("{album}.{}", format.as_str().to_lowercase()));
+            std::fs::write(&path, b"").unwrap();
+            db.insert_item(&Item {
+                album_id: Some(album_id),
+                path,
+                title: "Paranoid".into(),
+                album: album.into(),
+                format,
+                ..library_item()
+            })
+            .unwrap();
+        }
+        let (fmt, hooks, ranking) = (Formatter::stable(), Hooks::disabled(), Ranking::default());
+
+        let mut prompt = Scripted {
+            answers: vec![false, true],
+            asked: Vec::new(),
+        };
+        duplicates(&db, &fmt, &hooks, &mut prompt, &ranking, true, false).unwrap();
+        assert_eq!(db.query_items(None).unwrap().len(), 2);
+        duplicates(&db, &fmt, &hooks, &mut prompt, &ranking, true, false).unwrap();
+        let items = db.query_items(None).unwrap();
+        let albums = db.query_albums(None).unwrap();
+        let mp3_left = dir.join("Singles.mp3").exists();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(prompt.asked, ["Delete 1 extra copies from disk?"; 2]);
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].format, AudioFormat::Flac);
+        assert!(!mp3_left);
+        assert_eq!(albums.len(), 1);
+        assert_eq!(albums[0].album, "Paranoid");
+    }
+
+    #[test]
+    fn test_rm_delete_prunes_emptied_dirs() {
+        let db = library(&[]);
+        let root = std::env::temp_dir().join(format!("rsbts-rm-prune-{}", std::process::id()));
+        let album = root.join("Black Sabbath/Paranoid");
+        std::fs::create_dir_all(&album).unwrap();
+        std::fs::write(album.join("Thumbs.db"), b"").unwrap();
+        std::fs::write(root.join("Black Sabbath/artist.jpg"), b"").unwrap();
+        for title in ["War Pigs", "Paranoid"] {
+            let path = album.join(format!("{title}.flac"));
+            std::fs::write(&path, b"").unwrap();
+            db.insert_item(&Item {
+                path,
+                title: title.into(),
+                ..library_item()
+            })
+            .unwrap();
+        }
+
+        let pruner = Pruner::new(&root, &["thumbs.db".to_string()]);
+        let mut prompt = Scripted {
+            answers: Vec::new(),
+            asked: Vec::new(),
+        };
+        let removed = remove(
+            &mut Vec::new(),
+            &mut prompt,
+            &db,
+            &Hooks::disabled(),
+            "album:Paranoid",
+            Delete::FilesAndDirs(&pruner),
+            true,
+        )
+        .unwrap();
+        let left = (album.exists(), root.join("Black Sabbath").exists());
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!((removed.files, removed.dirs), (2, 1));
+        assert_eq!(left, (false, true));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8_path_is_listed_and_removed() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let db = library(&[("Erik Satie", "Gymnopedies", 1, "Gymnopedie No. 1")]);
+        let dir = std::env::temp_dir().join(format!("rsbts-non-utf8-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // Latin-1 "café", as an old filesystem or archive might name it
+        let file = dir.join(OsStr::from_bytes(b"caf\xe9.flac"));
+        std::fs::write(&file, b"").unwrap();
+        let mut item = db.query_items(None).unwrap().remove(0);
+        item.id = None;
+        item.path.clone_from(&file);
+        item.title = "Cafe".into();
+        db.insert_item(&item).unwrap();
+
+        assert!(db.item_exists(&file).unwrap());
+        let mut out = Vec::new();
+        list(&mut out, &db, &Formatter::stable(), Some("title:cafe"), Page::default(), None)
+            .unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "Erik Satie - Gymnopedies - Cafe [20:34]\n");
+        assert_eq!(db.query_items(Some("title:cafe")).unwrap()[0].path, file);
+
+        let mut prompt = Scripted {
+            answers: Vec::new(),
+            asked: Vec::new(),
+        };
+        let hooks = Hooks::disabled();
+        let removed =
+            remove(&mut Vec::new(), &mut prompt, &db, &hooks, "title:cafe", Delete::Files, true)
+                .unwrap();
+        let exists = file.exists();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!((removed.rows, removed.files, removed.failed), (1, 1, 0));
+        assert!(!exists);
+        assert!(!db.item_exists(&file).unwrap());
+    }
+
+    #[test]
+    fn test_outcome_of_errors_and_empty_matches() {
+        use rsbts::musicbrainz::ApiError;
+
+        let query = anyhow::Error::from(rsbts::Error::Query("Unknown field: nope".into()));
+        assert_eq!(Outcome::of_error(&query), Outcome::BadQuery);
+        // Told apart from the argument parser's usage errors
+        let usage = Cli::command().try_get_matches_from(["rsbts", "--no-such-flag"]);
+        assert_ne!(i32::from(Outcome::BadQuery.code()), usage.unwrap_err().exit_code());
+        let status = reqwest::StatusCode::SERVICE_UNAVAILABLE;
+        let network = anyhow::Error::from(rsbts::Error::from(ApiError::Http(status)))
+            .context("Failed to look up Paranoid");
+        assert_eq!(Outcome::of_error(&network), Outcome::Network);
+        assert_eq!(Outcome::of_error(&anyhow::anyhow!("disk full")), Outcome::Failed);
+
+        let db = library(&[("Abba", "Arrival", 1, "Dancing Queen")]);
+        let mut out = Vec::new();
+        let none = list(
+            &mut out,
+            &db,
+            &Formatter::stable(),
+            Some("title:Waterloo"),
+            Page::default(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(Outcome::matched(none, true), Outcome::NothingMatched);
+        assert_eq!(Outcome::matched(none, false), Outcome::Success);
+        assert_eq!(Outcome::matched(1, true), Outcome::Success);
+        assert_eq!(
+            [Outcome::NothingMatched, Outcome::Network, Outcome::PartialImport].map(Outcome::code),
+            [3, 4, 5]
+        );
     }
 }
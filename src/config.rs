@@ -1,9 +1,64 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
+use chrono::format::{Item, StrftimeItems};
+use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
 
-use crate::import::Action;
-use crate::Result;
+use crate::db::FtsMode;
+use crate::dedup::Criterion;
+use crate::format::DurationStyle;
+use crate::genres::Genres;
+use crate::hooks::Event;
+use crate::import::{Action, ConflictPolicy, MergePolicy, QuietFallback};
+use crate::musicbrainz::ClientSettings;
+use crate::pathformat::{PathFormats, Replacements, DEFAULT_REPLACE};
+use crate::pathmap::PathMappings;
+use crate::{Error, Result};
+
+/// The commented example config shipped with the source.
+const EXAMPLE: &str = include_str!("../config.example.toml");
+
+/// The config file of a portable library, in its root.
+pub const PORTABLE_CONFIG: &str = "rsbts.toml";
+
+/// The database of a portable library, in its root.
+pub const PORTABLE_DATABASE: &str = "library.db";
+
+/// Where [`Config::load`] finds the config.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigLocation {
+    /// This file if given, otherwise the one [`Config::resolve_path`] finds.
+    File(Option<PathBuf>),
+    /// A portable library at this root: the config is [`PORTABLE_CONFIG`] in
+    /// it, and the music and [`PORTABLE_DATABASE`] are there too.
+    Portable(PathBuf),
+}
+
+impl ConfigLocation {
+    /// The location for the command line's `--config` and `--library-root`,
+    /// where `portable` (`RSBTS_PORTABLE=1`) makes the current directory the
+    /// library root unless either is given.
+    #[must_use]
+    pub fn new(config: Option<PathBuf>, library_root: Option<PathBuf>, portable: bool) -> Self {
+        match (config, library_root) {
+            (_, Some(root)) => Self::Portable(root),
+            (None, None) if portable => Self::Portable(PathBuf::from(".")),
+            (config, None) => Self::File(config),
+        }
+    }
+
+    /// The config file to read or write, if one can be found.
+    #[must_use]
+    pub fn path(&self) -> Option<PathBuf> {
+        match self {
+            Self::File(path) => Config::resolve_path(path.as_deref()),
+            Self::Portable(root) => Some(root.join(PORTABLE_CONFIG)),
+        }
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
@@ -11,28 +66,396 @@ pub struct Config {
     pub paths: PathsConfig,
     pub import: ImportConfig,
     pub musicbrainz: MusicBrainzConfig,
+    #[serde(default)]
+    pub acoustid: AcoustIdConfig,
+    #[serde(default)]
+    pub ui: UiConfig,
+    #[serde(default)]
+    pub dedupe: DedupeConfig,
+    #[serde(default)]
+    pub modify: ModifyConfig,
+    #[serde(default)]
+    pub safety: SafetyConfig,
+    #[serde(default)]
+    pub server: ServerConfig,
+    #[serde(default)]
+    pub genres: GenresConfig,
+    /// Saved queries, referenced as `@name` inside other queries.
+    #[serde(default)]
+    pub bookmarks: HashMap<String, String>,
+    /// Commands run when the library changes, as a program and its arguments.
+    #[serde(default)]
+    pub hooks: HashMap<Event, Vec<String>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LibraryConfig {
     pub directory: PathBuf,
     pub database: PathBuf,
+    /// Give up statting a file after this long and assume it exists, so a
+    /// stalled network mount doesn't report the whole library missing.
+    #[serde(default)]
+    pub stat_timeout_ms: Option<u64>,
+    /// Sort directives for queries that have none, such as
+    /// `smart_artist+ year+ album+`.
+    #[serde(default)]
+    pub default_sort: Option<String>,
+    /// Where this machine mounts the prefixes of stored paths, for a
+    /// database shared by machines that mount the library differently.
+    #[serde(default)]
+    pub path_mappings: Vec<PathMapping>,
+    /// How the full-text index is kept; disabling it speeds up bulk writes
+    /// to a large library that's searched by field anyway.
+    #[serde(default)]
+    pub fts: FtsMode,
+    /// The root of a portable library, which holds the config, database and
+    /// music, and which item paths are stored relative to. Never read from
+    /// the file: it's wherever the library was opened from.
+    #[serde(skip)]
+    pub root: Option<PathBuf>,
+}
+
+impl LibraryConfig {
+    /// This machine's path mappings, ready for the database: paths relative
+    /// to the root of a portable library, otherwise `path_mappings`.
+    #[must_use]
+    pub fn path_mappings(&self) -> PathMappings {
+        if let Some(root) = &self.root {
+            return PathMappings::relative_to(root.clone());
+        }
+        PathMappings::new(
+            self.path_mappings
+                .iter()
+                .map(|mapping| (mapping.prefix.clone(), mapping.replacement.clone())),
+        )
+    }
+}
+
+/// A `[[library.path_mappings]]` entry: stored paths starting with `prefix`
+/// are at `replacement` on this machine.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PathMapping {
+    pub prefix: PathBuf,
+    pub replacement: PathBuf,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PathsConfig {
     pub format: String,
+    /// Formats for items matching a query, tried in order before `format`.
+    #[serde(default)]
+    pub formats: Vec<ConditionalFormat>,
+    /// Regex patterns and their replacements, applied in order to each
+    /// directory and file name of a filled-in format.
+    #[serde(default = "default_replace")]
+    pub replace: IndexMap<String, String>,
+    /// Whether non-ASCII characters in paths are transliterated to ASCII.
+    #[serde(default)]
+    pub asciify: bool,
+}
+
+fn default_replace() -> IndexMap<String, String> {
+    DEFAULT_REPLACE
+        .iter()
+        .map(|&(pattern, replacement)| (pattern.into(), replacement.into()))
+        .collect()
+}
+
+/// A `[[paths.formats]]` entry: the path format for items matching `query`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConditionalFormat {
+    pub query: String,
+    pub format: String,
+}
+
+impl PathsConfig {
+    /// The configured formats and replacements, ready to choose and fill in
+    /// a format per item.
+    ///
+    /// # Errors
+    /// Returns an error if a format's query can't be parsed, or a
+    /// replacement's pattern isn't a valid regex.
+    pub fn path_formats(&self) -> Result<PathFormats> {
+        let rules = self.replace.iter().map(|(pattern, to)| (pattern.as_str(), to.as_str()));
+        let replacements = Replacements::new(rules, self.asciify)
+            .map_err(|e| Error::Config(format!("paths.replace: {e}")))?;
+        self.formats
+            .iter()
+            .try_fold(
+                PathFormats::new(&self.format).with_replacements(replacements),
+                |formats, conditional| {
+                    formats.with(&conditional.query, &conditional.format).map_err(|e| {
+                        Error::Config(format!(
+                            "paths.formats query \"{}\": {e}",
+                            conditional.query
+                        ))
+                    })
+                },
+            )
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ImportConfig {
     pub action: Action,
     pub fetch_art: bool,
+    /// Names (without extension) of image files next to an album's tracks
+    /// used as its art, most preferred first.
+    #[serde(default = "default_art_filenames")]
+    pub art_filenames: Vec<String>,
+    /// Whether the Cover Art Archive's art is used even when the files come
+    /// with their own.
+    #[serde(default)]
+    pub prefer_caa_art: bool,
+    /// Number of albums looked up on `MusicBrainz` concurrently.
+    #[serde(default = "default_concurrency")]
+    pub concurrency: usize,
+    /// Whether `modify` writes changed fields back into file tags.
+    #[serde(default = "default_write_tags")]
+    pub write_tags: bool,
+    /// Whether an album matching one in the library is added to it.
+    #[serde(default)]
+    pub merge_into_existing: MergePolicy,
+    /// What happens to a file whose destination is taken.
+    #[serde(default)]
+    pub on_conflict: ConflictPolicy,
+    /// What a quiet import (`-q`) does with an album without a good enough
+    /// match.
+    #[serde(default)]
+    pub quiet_fallback: QuietFallback,
+    /// Directory `watch` imports from when none is given.
+    #[serde(default)]
+    pub watch_directory: Option<PathBuf>,
+    /// Seconds files must stay unchanged before `watch` imports them.
+    #[serde(default = "default_watch_quiet_seconds")]
+    pub watch_quiet_seconds: u64,
+    /// Names of files removed along with the directories that moved or
+    /// deleted tracks leave holding nothing else.
+    #[serde(default = "default_clutter")]
+    pub clutter: Vec<String>,
+    /// File extensions imported as audio besides those of the formats rsbts
+    /// knows, such as `shn`; their format is stored as unknown.
+    #[serde(default)]
+    pub extra_extensions: Vec<String>,
+}
+
+const fn default_concurrency() -> usize {
+    crate::import::DEFAULT_CONCURRENCY
+}
+
+fn default_art_filenames() -> Vec<String> {
+    crate::import::DEFAULT_ART_FILENAMES.iter().map(|&name| name.into()).collect()
+}
+
+const fn default_write_tags() -> bool {
+    true
+}
+
+const fn default_watch_quiet_seconds() -> u64 {
+    crate::watch::DEFAULT_QUIET_SECONDS
+}
+
+fn default_clutter() -> Vec<String> {
+    crate::prune::DEFAULT_CLUTTER.iter().map(|&pattern| pattern.into()).collect()
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MusicBrainzConfig {
     pub search_limit: u32,
+    /// Minimum normalized (0..1) similarity for accepting a release match.
+    #[serde(default = "default_min_match_score")]
+    pub min_match_score: f64,
+    /// Release countries to prefer among near-equal matches, most preferred first.
+    #[serde(default)]
+    pub preferred_countries: Vec<String>,
+    /// Medium formats to prefer among near-equal matches, most preferred first.
+    #[serde(default)]
+    pub preferred_media: Vec<String>,
+    /// Seconds a request may take before it fails.
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+    /// Email address or URL added to the User-Agent, so `MusicBrainz` can
+    /// get in touch about this installation's requests.
+    #[serde(default)]
+    pub contact: Option<String>,
+}
+
+impl MusicBrainzConfig {
+    /// The client settings these options give.
+    #[must_use]
+    pub fn client_settings(&self) -> ClientSettings {
+        ClientSettings {
+            timeout: Duration::from_secs(self.timeout_secs),
+            contact: self.contact.clone(),
+        }
+    }
+}
+
+const fn default_min_match_score() -> f64 {
+    crate::import::DEFAULT_MIN_MATCH_SCORE
+}
+
+const fn default_timeout_secs() -> u64 {
+    crate::musicbrainz::DEFAULT_TIMEOUT_SECS
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AcoustIdConfig {
+    /// `AcoustID` application key; fingerprint lookup is disabled when unset.
+    #[serde(default)]
+    pub api_key: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DedupeConfig {
+    /// How `duplicates` picks the copy to keep: each criterion in turn,
+    /// later ones breaking ties.
+    #[serde(default = "crate::dedup::default_criteria")]
+    pub prefer: Vec<Criterion>,
+}
+
+impl Default for DedupeConfig {
+    fn default() -> Self {
+        Self {
+            prefer: crate::dedup::default_criteria(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModifyConfig {
+    /// Joins the parts of a text field that `modify field+=value` adds to,
+    /// and splits them for `field-=value`.
+    #[serde(default = "default_modify_separator")]
+    pub separator: String,
+}
+
+fn default_modify_separator() -> String {
+    "; ".into()
+}
+
+impl Default for ModifyConfig {
+    fn default() -> Self {
+        Self {
+            separator: default_modify_separator(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SafetyConfig {
+    /// Most tracks, or albums with `--album`, that `modify` changes at once
+    /// without `--force`.
+    #[serde(default = "default_max_modify_without_force")]
+    pub max_modify_without_force: usize,
+}
+
+const fn default_max_modify_without_force() -> usize {
+    1000
+}
+
+impl Default for SafetyConfig {
+    fn default() -> Self {
+        Self {
+            max_modify_without_force: default_max_modify_without_force(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerConfig {
+    /// Address and port `rsbts serve` listens on.
+    #[serde(default = "default_server_bind")]
+    pub bind: String,
+    /// Token clients of `rsbts serve` must send; without one, anyone who can
+    /// reach the server can browse the library and download its files.
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+fn default_server_bind() -> String {
+    "127.0.0.1:8337".into()
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: default_server_bind(),
+            token: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GenresConfig {
+    /// Canonical genre names by alias, matched ignoring case.
+    #[serde(default)]
+    pub aliases: IndexMap<String, String>,
+    /// Whether genres no alias matches are title-cased.
+    #[serde(default)]
+    pub title_case: bool,
+}
+
+impl GenresConfig {
+    /// The rules genres are normalized by on import and with
+    /// `genres --normalize`.
+    #[must_use]
+    pub fn genres(&self) -> Genres {
+        let aliases = self.aliases.iter().map(|(alias, name)| (alias.as_str(), name.as_str()));
+        Genres::new(aliases, self.title_case)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UiConfig {
+    /// Query implicitly `AND`ed onto every `ls` and `stats` invocation.
+    #[serde(default)]
+    pub default_query: Option<String>,
+    /// Separator between groups of thousands in console output (none by default).
+    #[serde(default)]
+    pub thousands_separator: String,
+    /// Decimal separator in console output.
+    #[serde(default = "default_decimal_separator")]
+    pub decimal_separator: String,
+    /// `strftime` pattern for dates in console output.
+    #[serde(default = "default_date_format")]
+    pub date_format: String,
+    #[serde(default)]
+    pub duration_style: DurationStyle,
+}
+
+impl UiConfig {
+    /// Check `date_format`, which would otherwise only fail, by panicking,
+    /// when the first date is printed.
+    fn check(&self) -> Result<()> {
+        if StrftimeItems::new(&self.date_format).any(|item| item == Item::Error) {
+            return Err(Error::Config(format!(
+                "ui.date_format: \"{}\" is not a valid strftime pattern",
+                self.date_format
+            )));
+        }
+        Ok(())
+    }
+}
+
+fn default_decimal_separator() -> String {
+    ".".into()
+}
+
+fn default_date_format() -> String {
+    "%Y-%m-%d".into()
+}
+
+impl Default for UiConfig {
+    fn default() -> Self {
+        Self {
+            default_query: None,
+            thousands_separator: String::new(),
+            decimal_separator: default_decimal_separator(),
+            date_format: default_date_format(),
+            duration_style: DurationStyle::default(),
+        }
+    }
 }
 
 impl Default for Config {
@@ -44,39 +467,121 @@ impl Default for Config {
             library: LibraryConfig {
                 directory: home.join("Music"),
                 database: data_dir.join("rsbts/library.db"),
+                stat_timeout_ms: None,
+                default_sort: None,
+                path_mappings: Vec::new(),
+                fts: FtsMode::Enabled,
+                root: None,
             },
             paths: PathsConfig {
                 format: "$albumartist/$album/$track - $title".into(),
+                formats: Vec::new(),
+                replace: default_replace(),
+                asciify: false,
             },
             import: ImportConfig {
                 action: Action::Copy,
                 fetch_art: true,
+                art_filenames: default_art_filenames(),
+                prefer_caa_art: false,
+                concurrency: default_concurrency(),
+                write_tags: default_write_tags(),
+                merge_into_existing: MergePolicy::Ask,
+                on_conflict: ConflictPolicy::Rename,
+                quiet_fallback: QuietFallback::AsIs,
+                watch_directory: None,
+                watch_quiet_seconds: default_watch_quiet_seconds(),
+                clutter: default_clutter(),
+                extra_extensions: Vec::new(),
             },
-            musicbrainz: MusicBrainzConfig { search_limit: 5 },
+            musicbrainz: MusicBrainzConfig {
+                search_limit: 5,
+                min_match_score: default_min_match_score(),
+                preferred_countries: Vec::new(),
+                preferred_media: Vec::new(),
+                timeout_secs: default_timeout_secs(),
+                contact: None,
+            },
+            acoustid: AcoustIdConfig::default(),
+            ui: UiConfig::default(),
+            dedupe: DedupeConfig::default(),
+            modify: ModifyConfig::default(),
+            safety: SafetyConfig::default(),
+            server: ServerConfig::default(),
+            genres: GenresConfig::default(),
+            bookmarks: HashMap::new(),
+            hooks: HashMap::new(),
         }
     }
 }
 
 impl Config {
-    /// Load configuration from the given path or the default config location.
-    ///
-    /// # Errors
-    /// Returns an error if the config file exists but cannot be read or parsed.
-    pub fn load(path: Option<&Path>) -> Result<Self> {
-        let config_path = path
-            .map(PathBuf::from)
-            .or_else(|| dirs::config_dir().map(|d| d.join("rsbts/config.toml")));
-
-        let config = if let Some(ref p) = config_path {
-            if p.exists() {
-                let content = std::fs::read_to_string(p)?;
-                toml::from_str(&content).map_err(|e| crate::Error::Config(e.to_string()))?
+    /// The config file used for `path`: the path itself if given, otherwise
+    /// the one named by `RSBTS_CONFIG`, then `rsbts.toml` in the current
+    /// directory if there is one, then the default config location.
+    #[must_use]
+    pub fn resolve_path(path: Option<&Path>) -> Option<PathBuf> {
+        Self::search(
+            path,
+            std::env::var_os("RSBTS_CONFIG"),
+            std::env::current_dir().ok().as_deref(),
+            dirs::config_dir().as_deref(),
+        )
+    }
+
+    /// [`Config::resolve_path`] given the environment's `RSBTS_CONFIG`, the
+    /// current directory and the user's config directory.
+    fn search(
+        path: Option<&Path>,
+        env: Option<OsString>,
+        cwd: Option<&Path>,
+        config_dir: Option<&Path>,
+    ) -> Option<PathBuf> {
+        path.map(PathBuf::from)
+            .or_else(|| env.filter(|p| !p.is_empty()).map(PathBuf::from))
+            .or_else(|| cwd.map(|d| d.join("rsbts.toml")).filter(|p| p.is_file()))
+            .or_else(|| config_dir.map(|d| d.join("rsbts/config.toml")))
+    }
+
+    /// A starter config file for `rsbts init`: the commented example config
+    /// with the library in `directory` and the database at `database`.
+    #[must_use]
+    pub fn starter(directory: &Path, database: &Path) -> String {
+        let quoted = |path: &Path| toml::Value::String(path.to_string_lossy().into()).to_string();
+        let mut text = String::with_capacity(EXAMPLE.len());
+        for line in EXAMPLE.lines() {
+            if line.starts_with("directory = ") {
+                text.push_str(&format!("directory = {}", quoted(directory)));
+            } else if line.starts_with("database = ") {
+                text.push_str(&format!("database = {}", quoted(database)));
             } else {
-                Self::default()
+                text.push_str(line);
             }
-        } else {
-            Self::default()
+            text.push('\n');
+        }
+        text
+    }
+
+    /// Load configuration from `location`, then apply `RSBTS_*` environment
+    /// variables and `sets` on top, in that order (see [`Override`]). A
+    /// portable library's directory and database are always its root and
+    /// [`PORTABLE_DATABASE`] in it, whatever the settings say.
+    ///
+    /// # Errors
+    /// Returns an error if the config file exists but cannot be read or parsed,
+    /// or an override gives a value the setting can't take.
+    pub fn load(location: &ConfigLocation, sets: &[Override]) -> Result<Self> {
+        let content = match location.path() {
+            Some(p) if p.exists() => Some(std::fs::read_to_string(p)?),
+            _ => None,
         };
+        let root = match location {
+            ConfigLocation::File(_) => None,
+            ConfigLocation::Portable(root) => Some(std::path::absolute(root)?),
+        };
+        let mut overrides = Override::from_env(std::env::vars_os());
+        overrides.extend_from_slice(sets);
+        let config = Self::from_layers(content.as_deref(), root.as_deref(), &overrides)?;
 
         // Ensure database directory exists
         if let Some(parent) = config.library.database.parent() {
@@ -85,4 +590,334 @@ impl Config {
 
         Ok(config)
     }
+
+    /// The config in `content` (the defaults without a file) with `overrides`
+    /// applied one after another, and made portable at `root` if given.
+    fn from_layers(
+        content: Option<&str>,
+        root: Option<&Path>,
+        overrides: &[Override],
+    ) -> Result<Self> {
+        let mut config = match (content, root) {
+            (Some(content), None) => {
+                toml::from_str(content).map_err(|e| Error::Config(e.to_string()))?
+            }
+            (Some(content), Some(_)) => Self::portable_file(content)?,
+            (None, _) => Self::default(),
+        };
+        if !overrides.is_empty() {
+            let mut value =
+                toml::Value::try_from(&config).map_err(|e| Error::Config(e.to_string()))?;
+            for o in overrides {
+                config = o.apply(&mut value)?;
+            }
+        }
+        if let Some(root) = root {
+            config.library.directory = root.to_path_buf();
+            config.library.database = root.join(PORTABLE_DATABASE);
+            config.library.root = Some(root.to_path_buf());
+        }
+        config.ui.check()?;
+        Ok(config)
+    }
+
+    /// The config in a portable library's `content`, which needn't say where
+    /// the library and database are: they're replaced by the root's anyway.
+    fn portable_file(content: &str) -> Result<Self> {
+        let mut table: toml::Table =
+            toml::from_str(content).map_err(|e| Error::Config(e.to_string()))?;
+        let library = table
+            .entry("library")
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+        if let Some(library) = library.as_table_mut() {
+            for key in ["directory", "database"] {
+                library.entry(key).or_insert_with(|| toml::Value::String(String::new()));
+            }
+        }
+        table.try_into().map_err(|e| Error::Config(e.to_string()))
+    }
+}
+
+/// A setting given outside the config file, as `RSBTS_SECTION__KEY=value`
+/// in the environment or `--set section.key=value`.
+///
+/// The value is read as a TOML value (`true`, `4`, `["a", "b"]`) where the
+/// setting takes one, and as a plain string otherwise, so strings need no
+/// quotes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Override {
+    /// Where it was given, for error messages.
+    origin: String,
+    /// Section names, then the key.
+    key: Vec<String>,
+    value: String,
+}
+
+impl Override {
+    /// The overrides among environment `vars`: those named `RSBTS_` then the
+    /// section and key separated by `__`, matched case-insensitively. Other
+    /// `RSBTS_` variables, which have no `__`, are left alone.
+    pub fn from_env(vars: impl IntoIterator<Item = (OsString, OsString)>) -> Vec<Self> {
+        let mut overrides: Vec<Self> = vars
+            .into_iter()
+            .filter_map(|(name, value)| {
+                let name = name.into_string().ok()?;
+                let key: Vec<String> = name
+                    .strip_prefix("RSBTS_")?
+                    .split("__")
+                    .map(str::to_lowercase)
+                    .collect();
+                if key.len() < 2 || key.iter().any(String::is_empty) {
+                    return None;
+                }
+                Some(Self {
+                    origin: name,
+                    key,
+                    value: value.into_string().ok()?,
+                })
+            })
+            .collect();
+        // The environment's order means nothing, so make it predictable
+        overrides.sort_by(|a, b| a.origin.cmp(&b.origin));
+        overrides
+    }
+
+    /// The override given by `--set section.key=value`.
+    ///
+    /// # Errors
+    /// Returns an error if `arg` isn't of that form.
+    pub fn from_set(arg: &str) -> Result<Self> {
+        let (key, value) = arg
+            .split_once('=')
+            .ok_or_else(|| Error::Config(format!("--set {arg}: expected section.key=value")))?;
+        let key: Vec<String> = key.trim().split('.').map(String::from).collect();
+        if key.len() < 2 || key.iter().any(String::is_empty) {
+            return Err(Error::Config(format!("--set {arg}: expected section.key=value")));
+        }
+        Ok(Self {
+            origin: format!("--set {}", key.join(".")),
+            key,
+            value: value.to_string(),
+        })
+    }
+
+    /// Set this in `value`, the config as TOML, returning the config that
+    /// results.
+    fn apply(&self, value: &mut toml::Value) -> Result<Config> {
+        let literal = toml::from_str::<toml::Table>(&format!("v = {}", self.value))
+            .ok()
+            .and_then(|mut table| table.remove("v"));
+        let mut first_error = None;
+        for candidate in literal.into_iter().chain([toml::Value::String(self.value.clone())]) {
+            self.set(value, candidate)?;
+            match value.clone().try_into::<Config>() {
+                Ok(config) => return Ok(config),
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        let reason = first_error.map(|e| e.to_string()).unwrap_or_default();
+        Err(Error::Config(format!(
+            "{}: invalid value {:?}: {}",
+            self.origin,
+            self.value,
+            reason.trim_end()
+        )))
+    }
+
+    fn set(&self, value: &mut toml::Value, to: toml::Value) -> Result<()> {
+        let Some((key, sections)) = self.key.split_last() else {
+            return Ok(());
+        };
+        let mut table = value.as_table_mut();
+        for section in sections {
+            table = table.and_then(|t| {
+                t.entry(section.as_str())
+                    .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+                    .as_table_mut()
+            });
+        }
+        let table = table.ok_or_else(|| {
+            Error::Config(format!("{}: {} is not a section", self.origin, sections.join(".")))
+        })?;
+        table.insert(key.clone(), to);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILE: &str = r#"
+[library]
+directory = "/home/me/Music"
+database = "/home/me/.local/share/rsbts/library.db"
+
+[paths]
+format = "$albumartist/$album/$track - $title"
+
+[import]
+action = "copy"
+fetch_art = true
+
+[musicbrainz]
+search_limit = 5
+"#;
+
+    fn env(vars: &[(&str, &str)]) -> Vec<Override> {
+        Override::from_env(vars.iter().map(|(name, value)| (name.into(), value.into())))
+    }
+
+    #[test]
+    fn test_overrides_apply_after_file_then_env_then_set() {
+        let mut overrides = env(&[
+            ("RSBTS_IMPORT__ACTION", "move"),
+            ("RSBTS_LIBRARY__DIRECTORY", "/srv/music"),
+            ("RSBTS_IMPORT__CONCURRENCY", "8"),
+        ]);
+        overrides.push(Override::from_set("import.action=link").unwrap());
+        overrides.push(Override::from_set(r#"hooks.item_imported=["notify", "{title}"]"#).unwrap());
+        let config = Config::from_layers(Some(FILE), None, &overrides).unwrap();
+        assert_eq!(config.import.action, Action::Link);
+        assert_eq!(config.library.directory, Path::new("/srv/music"));
+        assert_eq!(config.import.concurrency, 8);
+        assert!(config.import.fetch_art);
+        assert_eq!(config.import.art_filenames, ["cover", "folder", "front"]);
+        assert_eq!(config.musicbrainz.timeout_secs, 30);
+        assert_eq!(config.hooks[&Event::ItemImported], ["notify", "{title}"]);
+
+        let env_only = Config::from_layers(Some(FILE), None, &overrides[..3]).unwrap();
+        assert_eq!(env_only.import.action, Action::Move);
+        // Without a file, the defaults are overridden
+        let defaults = Config::from_layers(None, None, &overrides[..1]).unwrap();
+        assert_eq!(defaults.import.action, Action::Move);
+    }
+
+    #[test]
+    fn test_values_that_look_like_toml_can_be_strings() {
+        let overrides = [
+            Override::from_set("paths.format=2024").unwrap(),
+            Override::from_set("acoustid.api_key=12345").unwrap(),
+        ];
+        let config = Config::from_layers(Some(FILE), None, &overrides).unwrap();
+        assert_eq!(config.paths.format, "2024");
+        assert_eq!(config.acoustid.api_key.as_deref(), Some("12345"));
+    }
+
+    #[test]
+    fn test_invalid_date_format() {
+        let overrides = [Override::from_set("ui.date_format=%Y-%Q").unwrap()];
+        let err = Config::from_layers(Some(FILE), None, &overrides).unwrap_err();
+        assert!(err.to_string().contains("ui.date_format"), "{err}");
+        let overrides = [Override::from_set("ui.date_format=%d.%m.%Y").unwrap()];
+        assert!(Config::from_layers(Some(FILE), None, &overrides).is_ok());
+    }
+
+    #[test]
+    fn test_invalid_override_names_its_origin() {
+        let error = |overrides: &[Override]| {
+            Config::from_layers(Some(FILE), None, overrides).unwrap_err().to_string()
+        };
+        let message = error(&env(&[("RSBTS_IMPORT__CONCURRENCY", "lots")]));
+        assert!(message.contains("RSBTS_IMPORT__CONCURRENCY"), "{message}");
+        assert!(message.contains("\"lots\""), "{message}");
+        let message = error(&[Override::from_set("import.action=teleport").unwrap()]);
+        assert!(message.contains("--set import.action"), "{message}");
+        let message = error(&[Override::from_set("paths.format.x=1").unwrap()]);
+        assert!(message.contains("paths.format is not a section"), "{message}");
+    }
+
+    #[test]
+    fn test_override_syntax() {
+        // No section separator: not an override, such as RSBTS_EVENT for hooks
+        let found = env(&[
+            ("RSBTS_EVENT", "item_imported"),
+            ("HOME", "/home/me"),
+            ("RSBTS_IMPORT__", "x"),
+            ("RSBTS_UI__DEFAULT_QUERY", "genre:jazz"),
+        ]);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].key, ["ui", "default_query"]);
+        assert_eq!(found[0].value, "genre:jazz");
+
+        let set = Override::from_set("ui.default_query=year:1990..=1999").unwrap();
+        assert_eq!(set.value, "year:1990..=1999");
+        assert!(Override::from_set("import.action").is_err());
+        assert!(Override::from_set("action=move").is_err());
+        assert!(Override::from_set("import..action=move").is_err());
+    }
+
+    #[test]
+    fn test_config_search_order() {
+        let root = std::env::temp_dir().join(format!("rsbts-config-{}", std::process::id()));
+        let project = root.join("project");
+        let xdg = root.join("xdg");
+        std::fs::create_dir_all(&project).unwrap();
+        let search = |path: Option<&str>, env: Option<&str>| {
+            Config::search(path.map(Path::new), env.map(OsString::from), Some(&project), Some(&xdg))
+        };
+
+        let in_xdg = search(None, None);
+        let empty_env = search(None, Some(""));
+        std::fs::write(project.join("rsbts.toml"), FILE).unwrap();
+        let local = search(None, None);
+        let from_env = search(None, Some("/etc/rsbts.toml"));
+        let given = search(Some("/tmp/other.toml"), Some("/etc/rsbts.toml"));
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(in_xdg, Some(xdg.join("rsbts/config.toml")));
+        assert_eq!(empty_env, in_xdg);
+        assert_eq!(local, Some(project.join("rsbts.toml")));
+        assert_eq!(from_env, Some(PathBuf::from("/etc/rsbts.toml")));
+        assert_eq!(given, Some(PathBuf::from("/tmp/other.toml")));
+        assert_eq!(Config::search(None, None, None, None), None);
+    }
+
+    #[test]
+    fn test_path_replacements_keep_their_order() {
+        let defaults = Config::from_layers(Some(FILE), None, &[]).unwrap();
+        assert_eq!(defaults.paths.replace.len(), DEFAULT_REPLACE.len());
+        assert!(defaults.paths.path_formats().is_ok());
+
+        let file = format!("{FILE}\n[paths.replace]\n'/' = ' - '\n' +' = ' '\n'(' = ''\n");
+        let config = Config::from_layers(Some(&file), None, &[]).unwrap();
+        let patterns: Vec<&str> = config.paths.replace.keys().map(String::as_str).collect();
+        assert_eq!(patterns, ["/", " +", "("]);
+        let err = config.paths.path_formats().unwrap_err().to_string();
+        assert!(err.contains("paths.replace"), "{err}");
+    }
+
+    #[test]
+    fn test_portable_library_is_under_its_root() {
+        let file = FILE.replace("directory = \"/home/me/Music\"\n", "");
+        let root = Path::new("/media/drive");
+        let overrides = [Override::from_set("library.database=/tmp/other.db").unwrap()];
+        let config = Config::from_layers(Some(&file), Some(root), &overrides).unwrap();
+        assert_eq!(config.library.directory, root);
+        assert_eq!(config.library.database, root.join("library.db"));
+        assert_eq!(config.library.root.as_deref(), Some(root));
+        assert!(!config.library.path_mappings().is_empty());
+        assert!(Config::from_layers(Some(&file), None, &[]).is_err());
+
+        let portable = |config: Option<&str>, root: Option<&str>, env| {
+            ConfigLocation::new(config.map(PathBuf::from), root.map(PathBuf::from), env)
+        };
+        assert_eq!(portable(None, None, true), ConfigLocation::Portable(".".into()));
+        assert_eq!(portable(None, Some("/d"), false), ConfigLocation::Portable("/d".into()));
+        let given = portable(Some("/etc/rsbts.toml"), None, true);
+        assert_eq!(given.path(), Some(PathBuf::from("/etc/rsbts.toml")));
+        assert_eq!(portable(None, Some("/d"), false).path(), Some(PathBuf::from("/d/rsbts.toml")));
+    }
+
+    #[test]
+    fn test_starter_config_is_the_example_with_paths() {
+        let text = Config::starter(Path::new("/srv/\"music\""), Path::new("/srv/db/library.db"));
+        assert!(text.contains("# Path to your music library\n"));
+        let config: Config = toml::from_str(&text).unwrap();
+        assert_eq!(config.library.directory, Path::new("/srv/\"music\""));
+        assert_eq!(config.library.database, Path::new("/srv/db/library.db"));
+        assert_eq!(config.import.action, Action::Copy);
+    }
 }
@@ -0,0 +1,313 @@
+//! Cue sheets, which say where the tracks of an album ripped to one file
+//! start
+//!
+//! Importing doesn't split such rips into tracks: an item is a file, so
+//! tracks sharing one would need items addressing parts of it. For now
+//! [`sheet_for`] finds the sheet of a whole album in one file, so the
+//! importer can tell it from a one-track release and not match it against
+//! `MusicBrainz`. A [`CueSheet`]'s tracks carry where they start in the
+//! file, for splitting to build on.
+
+use std::path::{Path, PathBuf};
+
+use log::warn;
+
+use crate::{Error, Result};
+
+/// Frames per second in cue sheet times, as on a CD.
+const FRAMES_PER_SECOND: f64 = 75.0;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CueSheet {
+    pub performer: Option<String>,
+    pub title: Option<String>,
+    pub tracks: Vec<CueTrack>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CueTrack {
+    pub number: u32,
+    /// The file the track is in, as the sheet names it.
+    pub file: String,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    /// Seconds into the file the track's pre-gap (`INDEX 00`) starts, if it
+    /// has one.
+    pub pregap: Option<f64>,
+    /// Seconds into the file the track itself (`INDEX 01`) starts.
+    pub start: f64,
+}
+
+impl CueSheet {
+    /// Parse the text of a cue sheet. Commands other than `FILE`, `TRACK`,
+    /// `TITLE`, `PERFORMER` and `INDEX` (such as `REM`) are skipped.
+    ///
+    /// # Errors
+    /// Returns an error naming the line if a track comes before any file,
+    /// has a malformed number or index, or has no `INDEX 01`.
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut sheet = Self {
+            performer: None,
+            title: None,
+            tracks: Vec::new(),
+        };
+        let mut file = None;
+        // Whether the last track had its INDEX 01 yet
+        let mut started = true;
+        for (n, line) in text.lines().enumerate() {
+            let error = |what: &str| Error::Cue(format!("line {}: {what}", n + 1));
+            let line = line.trim();
+            let (command, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let rest = rest.trim();
+            match command.to_ascii_uppercase().as_str() {
+                "FILE" => file = Some(argument(rest).to_string()),
+                "TRACK" => {
+                    if !started {
+                        return Err(error("previous track has no INDEX 01"));
+                    }
+                    let number = rest.split_whitespace().next().and_then(|word| word.parse().ok());
+                    let number = number.ok_or_else(|| error("bad TRACK number"))?;
+                    let file = file.clone().ok_or_else(|| error("TRACK before any FILE"))?;
+                    sheet.tracks.push(CueTrack {
+                        number,
+                        file,
+                        title: None,
+                        performer: None,
+                        pregap: None,
+                        start: 0.0,
+                    });
+                    started = false;
+                }
+                "TITLE" | "PERFORMER" => {
+                    let value = Some(argument(rest).to_string());
+                    let title = command.eq_ignore_ascii_case("TITLE");
+                    match (sheet.tracks.last_mut(), title) {
+                        (Some(track), true) => track.title = value,
+                        (Some(track), false) => track.performer = value,
+                        (None, true) => sheet.title = value,
+                        (None, false) => sheet.performer = value,
+                    }
+                }
+                "INDEX" => {
+                    let mut words = rest.split_whitespace();
+                    let index = words.next().and_then(|index| index.parse::<u32>().ok());
+                    let time = words.next().and_then(parse_time);
+                    let (Some(index), Some(time)) = (index, time) else {
+                        return Err(error("bad INDEX"));
+                    };
+                    let track = sheet.tracks.last_mut();
+                    let track = track.ok_or_else(|| error("INDEX before any TRACK"))?;
+                    match index {
+                        0 => track.pregap = Some(time),
+                        1 => {
+                            track.start = time;
+                            started = true;
+                        }
+                        _ => {}
+                    }
+                }
+                _ => {}
+            }
+        }
+        if !started {
+            return Err(Error::Cue("last track has no INDEX 01".into()));
+        }
+        Ok(sheet)
+    }
+
+    /// Read and parse the cue sheet at `path`. Sheets that aren't UTF-8,
+    /// as older rippers wrote them, are read as far as they can be.
+    ///
+    /// # Errors
+    /// Returns an error if the file can't be read or parsed.
+    pub fn read(path: &Path) -> Result<Self> {
+        Self::from_bytes(&std::fs::read(path)?)
+    }
+
+    /// Parse a sheet read as `bytes`, as [`Self::read`] does.
+    fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Self::parse(String::from_utf8_lossy(bytes).trim_start_matches('\u{feff}'))
+    }
+
+    /// Whether all of the sheet's tracks are in one file, rather than one
+    /// file each as some rippers describe separate tracks.
+    #[must_use]
+    pub fn is_single_file(&self) -> bool {
+        self.tracks.windows(2).all(|pair| pair[0].file == pair[1].file)
+    }
+
+    /// Seconds of audio before the first track starts, where some CDs hide
+    /// a track; `None` if the first track starts right away.
+    #[must_use]
+    pub fn hidden_track(&self) -> Option<f64> {
+        self.tracks.first().map(|track| track.start).filter(|&start| start > 0.0)
+    }
+}
+
+/// The cue sheet describing `audio` as a whole album ripped to one file,
+/// with where it is.
+///
+/// The sheet is in `audio`'s directory, named after it or naming it,
+/// ignoring case and extension (rips are often converted from the WAV file
+/// a sheet was written for), and lists more than one track, all in that
+/// file. Sheets that can't be read are reported, and passed over.
+///
+/// The directory is read without blocking, as importing looks for sheets
+/// while lookups are running.
+pub async fn sheet_for(audio: &Path) -> Option<(PathBuf, CueSheet)> {
+    let dir = audio.parent()?;
+    let stem = lowercase_stem(audio)?;
+    let name = audio.file_name()?.to_string_lossy().to_lowercase();
+    let mut entries = tokio::fs::read_dir(dir).await.ok()?;
+    let mut sheets = Vec::new();
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let path = entry.path();
+        if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("cue")) {
+            sheets.push(path);
+        }
+    }
+    sheets.sort();
+    for path in sheets {
+        let read = tokio::fs::read(&path).await.map_err(Error::from);
+        let sheet = match read.and_then(|bytes| CueSheet::from_bytes(&bytes)) {
+            Ok(sheet) => sheet,
+            Err(e) => {
+                warn!("Could not read cue sheet {}: {e}", path.display());
+                continue;
+            }
+        };
+        let named_after = matches!(lowercase_stem(&path), Some(s) if s == stem || s == name);
+        let names = sheet.tracks.first().is_some_and(|track| {
+            lowercase_stem(Path::new(&track.file)).is_some_and(|s| s == stem)
+        });
+        let whole_album = sheet.tracks.len() > 1 && sheet.is_single_file();
+        if (named_after || names) && whole_album {
+            return Some((path, sheet));
+        }
+    }
+    None
+}
+
+fn lowercase_stem(path: &Path) -> Option<String> {
+    Some(path.file_stem()?.to_string_lossy().to_lowercase())
+}
+
+/// A command's first argument: what's between the quotes if it's quoted,
+/// else up to the first space.
+fn argument(rest: &str) -> &str {
+    rest.strip_prefix('"').map_or_else(
+        || rest.split_whitespace().next().unwrap_or(""),
+        |quoted| quoted.split_once('"').map_or(quoted, |(value, _)| value),
+    )
+}
+
+/// Seconds in a `mm:ss:ff` time, counting 75 frames a second.
+fn parse_time(time: &str) -> Option<f64> {
+    let mut parts = time.split(':').map(|part| part.parse::<u32>().ok());
+    let (Some(Some(minutes)), Some(Some(seconds)), Some(Some(frames)), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return None;
+    };
+    (seconds < 60 && f64::from(frames) < FRAMES_PER_SECOND).then(|| {
+        f64::from(minutes).mul_add(60.0, f64::from(seconds)) + f64::from(frames) / FRAMES_PER_SECOND
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// As Exact Audio Copy writes for a CD ripped to one file, with a track
+    /// hidden before the first.
+    const FIXTURE: &str = "\u{feff}REM GENRE Rock
+REM DATE 1970
+REM COMMENT \"ExactAudioCopy v1.6\"
+PERFORMER \"Black Sabbath\"
+TITLE \"Paranoid\"
+FILE \"Black Sabbath - Paranoid.wav\" WAVE
+  TRACK 01 AUDIO
+    TITLE \"War Pigs\"
+    PERFORMER \"Black Sabbath\"
+    INDEX 00 00:00:00
+    INDEX 01 00:12:00
+  TRACK 02 AUDIO
+    TITLE \"Paranoid\"
+    INDEX 00 07:55:00
+    INDEX 01 07:57:30
+  TRACK 03 AUDIO
+    TITLE \"Planet Caravan\"
+    INDEX 01 10:45:00
+";
+
+    #[test]
+    fn test_parse_fixture() {
+        let sheet = CueSheet::parse(FIXTURE.trim_start_matches('\u{feff}')).unwrap();
+        assert_eq!(sheet.performer.as_deref(), Some("Black Sabbath"));
+        assert_eq!(sheet.title.as_deref(), Some("Paranoid"));
+        assert!(sheet.is_single_file());
+        assert_eq!(sheet.hidden_track(), Some(12.0));
+
+        let tracks: Vec<(u32, Option<&str>, Option<f64>)> = sheet
+            .tracks
+            .iter()
+            .map(|track| (track.number, track.title.as_deref(), track.pregap))
+            .collect();
+        assert_eq!(
+            tracks,
+            [
+                (1, Some("War Pigs"), Some(0.0)),
+                (2, Some("Paranoid"), Some(475.0)),
+                (3, Some("Planet Caravan"), None),
+            ]
+        );
+        assert!((sheet.tracks[1].start - 477.4).abs() < 1e-9);
+        assert_eq!(sheet.tracks[2].file, "Black Sabbath - Paranoid.wav");
+        assert_eq!(sheet.tracks[0].performer.as_deref(), Some("Black Sabbath"));
+        assert_eq!(sheet.tracks[2].performer, None);
+    }
+
+    #[test]
+    fn test_parse_errors_name_the_line() {
+        let err = CueSheet::parse("TITLE \"x\"\nTRACK 01 AUDIO").unwrap_err();
+        assert!(err.to_string().contains("line 2: TRACK before any FILE"), "{err}");
+        let bad_time = "FILE a.wav WAVE\nTRACK 01 AUDIO\nINDEX 01 00:61:00";
+        let err = CueSheet::parse(bad_time).unwrap_err();
+        assert!(err.to_string().contains("line 3: bad INDEX"), "{err}");
+        let err = CueSheet::parse("FILE a.wav WAVE\nTRACK 01 AUDIO\nTRACK 02 AUDIO").unwrap_err();
+        assert!(err.to_string().contains("line 3: previous track has no INDEX 01"), "{err}");
+        assert!(CueSheet::parse("FILE a.wav WAVE\nTRACK 01 AUDIO\nINDEX 00 00:00:00").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sheet_for_whole_album_rips_only() {
+        let dir = std::env::temp_dir().join(format!("rsbts-cue-{}", std::process::id()));
+        let rip = dir.join("rip");
+        let tracks = dir.join("tracks");
+        std::fs::create_dir_all(&rip).unwrap();
+        std::fs::create_dir_all(&tracks).unwrap();
+        // Converted to FLAC, named unlike the WAV file the sheet names
+        std::fs::write(rip.join("CD.flac"), b"").unwrap();
+        std::fs::write(rip.join("cd.cue"), FIXTURE).unwrap();
+        std::fs::write(rip.join("Bonus.flac"), b"").unwrap();
+        // Ripped to separate tracks, described by one sheet
+        std::fs::write(tracks.join("01.flac"), b"").unwrap();
+        std::fs::write(
+            tracks.join("album.cue"),
+            "FILE \"01.flac\" WAVE\nTRACK 01 AUDIO\nINDEX 01 00:00:00\n\
+             FILE \"02.flac\" WAVE\nTRACK 02 AUDIO\nINDEX 01 00:00:00\n",
+        )
+        .unwrap();
+
+        let found = sheet_for(&rip.join("CD.flac")).await;
+        let bonus = sheet_for(&rip.join("Bonus.flac")).await;
+        let separate = sheet_for(&tracks.join("01.flac")).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let (path, sheet) = found.unwrap();
+        assert_eq!(path, rip.join("cd.cue"));
+        assert_eq!(sheet.tracks.len(), 3);
+        assert!(bonus.is_none());
+        assert!(separate.is_none());
+    }
+}
@@ -1,20 +1,182 @@
-use std::path::Path;
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
-use chrono::{DateTime, Utc};
-use rusqlite::{params, Connection};
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::warn;
+use rusqlite::functions::FunctionFlags;
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef};
+use rusqlite::{params, Connection, OptionalExtension, ToSql};
+use serde::{Deserialize, Serialize};
 
-use crate::{Album, AudioFormat, Item, Result};
+use crate::exists::ExistenceCheck;
+use crate::fields::{check_attribute_key, AttributeEdit, FieldChange, FieldEdit};
+use crate::metadata_cache::CacheStats;
+use crate::pathformat::Library;
+use crate::pathmap::PathMappings;
+use crate::query::{FieldOp, FullTextMode, Page, QueryTerm, DEFAULT_ORDER};
+use crate::replaygain::Gain;
+use crate::runs::{Fill, ImportRun, LogEntry, RunSummary};
+use crate::{Album, AudioFormat, Error, Item, Result};
 
 pub struct Database {
     conn: Connection,
+    path: PathBuf,
+    /// Whether `SQLite` supports FTS5; bare-word queries fall back to `LIKE` otherwise.
+    fts5: bool,
+    /// Set once the slow `LIKE` fallback warning has been shown.
+    fts_warned: Cell<bool>,
+    /// How the full-text index is kept, as recorded in the `meta` table.
+    fts_mode: Cell<FtsMode>,
+    /// `ORDER BY` list for queries without a sort directive.
+    default_order: String,
+    /// How stored paths map to where they are on this machine.
+    paths: PathMappings,
 }
 
+#[derive(Debug, Clone, Default, Serialize)]
 pub struct Stats {
     pub tracks: u64,
     pub albums: u64,
     pub artists: u64,
     pub total_length: f64,
     pub total_size: u64,
+    /// Tracks whose files no longer exist, when verified (`stats --verify`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub missing: Option<u64>,
+}
+
+/// Totals over the tracks of one album, for `ls --album --long`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct AlbumStats {
+    pub track_count: u64,
+    pub total_length: f64,
+    /// Bytes, estimated from bitrate and length for tracks with no recorded
+    /// size.
+    pub total_size: u64,
+    /// The tracks' formats, each once, in name order.
+    pub formats: Vec<AudioFormat>,
+    pub min_bitrate: Option<u32>,
+    pub max_bitrate: Option<u32>,
+}
+
+/// What `stats --by` breaks the library down by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum StatsGroup {
+    Format,
+    Genre,
+    Artist,
+    /// Release year, with tracks that have none under "unknown"
+    Year,
+}
+
+impl StatsGroup {
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Format => "format",
+            Self::Genre => "genre",
+            Self::Artist => "artist",
+            Self::Year => "year",
+        }
+    }
+
+    /// The SQL for an item's group.
+    const fn sql(self) -> &'static str {
+        match self {
+            Self::Format => "format",
+            Self::Genre => "COALESCE(NULLIF(genre, ''), 'unknown')",
+            Self::Artist => "artist",
+            Self::Year => "COALESCE(CAST(year AS TEXT), 'unknown')",
+        }
+    }
+}
+
+impl FromStr for StatsGroup {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "format" => Ok(Self::Format),
+            "genre" => Ok(Self::Genre),
+            "artist" => Ok(Self::Artist),
+            "year" => Ok(Self::Year),
+            _ => Err(Error::Config(format!(
+                "Unknown grouping '{s}' (expected format, genre, artist or year)"
+            ))),
+        }
+    }
+}
+
+/// How the full-text index over items is kept, as `library.fts` says.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum FtsMode {
+    /// Kept in sync by triggers as items change.
+    #[default]
+    Enabled,
+    /// No index: bare words are matched with `LIKE`, and writing items
+    /// costs nothing extra.
+    Disabled,
+    /// Kept without triggers, and refilled all at once by `db reindex`,
+    /// such as after a large import. Searches miss changes made since.
+    ExternalContentRebuild,
+}
+
+impl FtsMode {
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Enabled => "enabled",
+            Self::Disabled => "disabled",
+            Self::ExternalContentRebuild => "external_content_rebuild",
+        }
+    }
+}
+
+impl FromStr for FtsMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "enabled" => Ok(Self::Enabled),
+            "disabled" => Ok(Self::Disabled),
+            "external_content_rebuild" => Ok(Self::ExternalContentRebuild),
+            _ => Err(Error::Config(format!(
+                "Unknown full-text mode '{s}' (expected enabled, disabled or \
+                 external_content_rebuild)"
+            ))),
+        }
+    }
+}
+
+/// What a flexible attribute belongs to, by id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AttributeOwner {
+    Item(i64),
+    Album(i64),
+}
+
+/// A flexible attribute with its owner, as archives hold them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Attribute {
+    pub owner: AttributeOwner,
+    pub key: String,
+    pub value: String,
+}
+
+impl AttributeOwner {
+    /// The owner's attribute table, the column there holding owners' ids,
+    /// and its id.
+    const fn table(self) -> (&'static str, &'static str, i64) {
+        match self {
+            Self::Item(id) => ("item_attributes", "item_id", id),
+            Self::Album(id) => ("album_attributes", "album_id", id),
+        }
+    }
 }
 
 impl Database {
@@ -23,16 +185,440 @@ impl Database {
     /// # Errors
     /// Returns an error if the database cannot be opened.
     pub fn open(path: &Path) -> Result<Self> {
-        let conn = Connection::open(path)?;
-        Ok(Self { conn })
+        Self::with_connection(Connection::open(path)?, path.to_path_buf())
+    }
+
+    /// Open a private database that lives only in memory, for tests.
+    ///
+    /// # Errors
+    /// Returns an error if the database cannot be opened.
+    pub fn open_in_memory() -> Result<Self> {
+        Self::with_connection(Connection::open_in_memory()?, PathBuf::from(":memory:"))
+    }
+
+    fn with_connection(conn: Connection, path: PathBuf) -> Result<Self> {
+        configure(&conn)?;
+        register_regexp(&conn)?;
+        register_sort_name(&conn)?;
+        let fts5 = crate::migrations::fts5_available(&conn);
+        Ok(Self {
+            conn,
+            path,
+            fts5,
+            fts_warned: Cell::new(false),
+            fts_mode: Cell::new(FtsMode::default()),
+            default_order: DEFAULT_ORDER.to_string(),
+            paths: PathMappings::default(),
+        })
+    }
+
+    /// Open a second connection to the same database file.
+    ///
+    /// `Database` itself can't be shared between threads; this is for
+    /// components such as the metadata cache that run on worker tasks.
+    ///
+    /// # Errors
+    /// Returns an error if the database cannot be opened.
+    pub fn connect(&self) -> Result<Connection> {
+        let conn = Connection::open(&self.path)?;
+        configure(&conn)?;
+        Ok(conn)
+    }
+
+    /// Whether full-text search uses the FTS5 index.
+    pub const fn has_fts5(&self) -> bool {
+        self.fts5
+    }
+
+    /// How the full-text index is kept.
+    pub const fn fts_mode(&self) -> FtsMode {
+        self.fts_mode.get()
+    }
+
+    /// How bare-word query terms are translated to SQL for this database.
+    pub const fn full_text_mode(&self) -> FullTextMode {
+        if self.fts5 && !matches!(self.fts_mode.get(), FtsMode::Disabled) {
+            FullTextMode::Fts5
+        } else {
+            FullTextMode::Like
+        }
+    }
+
+    /// The `ORDER BY` list used for queries without a sort directive.
+    #[must_use]
+    pub fn default_order(&self) -> &str {
+        &self.default_order
+    }
+
+    /// Order queries without a sort directive by `sort`, sort directives
+    /// such as `smart_artist+ year+`.
+    ///
+    /// # Errors
+    /// Returns an error if `sort` isn't valid sort directives.
+    pub fn set_default_sort(&mut self, sort: &str) -> Result<()> {
+        self.default_order = crate::query::sort_order(sort)?;
+        Ok(())
+    }
+
+    /// Read and write paths through `mappings`, for a library mounted
+    /// somewhere else on this machine than the stored paths say.
+    pub fn set_path_mappings(&mut self, mappings: PathMappings) {
+        self.paths = mappings;
     }
 
     /// Run database migrations to create/update schema.
     ///
+    /// The full-text index is only created when FTS5 is available, and kept
+    /// as the [`FtsMode`] last set says.
+    ///
     /// # Errors
     /// Returns an error if migrations fail.
     pub fn migrate(&self) -> Result<()> {
-        crate::migrations::run_migrations(&self.conn)
+        self.migrate_to(None)
+    }
+
+    /// Run database migrations up to and including version `target`, or all
+    /// of them without one.
+    ///
+    /// # Errors
+    /// Returns an error if `target` can't be reached or a migration fails.
+    pub fn migrate_to(&self, target: Option<u32>) -> Result<()> {
+        crate::migrations::run_migrations_to(&self.conn, target)?;
+        let mode = self.stored_fts_mode()?;
+        self.fts_mode.set(mode);
+        if self.fts5 {
+            self.apply_fts_mode(mode)?;
+        }
+        Ok(())
+    }
+
+    /// Keep the full-text index as `mode` says from now on, recording it in
+    /// the `meta` table: create or drop the index, and its triggers. An
+    /// index going back to triggers is refilled, as it has missed changes.
+    ///
+    /// # Errors
+    /// Returns an error if the mode can't be recorded or the index changed.
+    pub fn set_fts_mode(&self, mode: FtsMode) -> Result<()> {
+        let previous = self.fts_mode.get();
+        if mode == previous {
+            return Ok(());
+        }
+        self.conn.execute(
+            "INSERT OR REPLACE INTO meta (key, value) VALUES ('fts', ?1)",
+            [mode.as_str()],
+        )?;
+        if self.fts5 {
+            self.apply_fts_mode(mode)?;
+            if previous == FtsMode::ExternalContentRebuild {
+                crate::migrations::rebuild_fts(&self.conn)?;
+            }
+        }
+        self.fts_mode.set(mode);
+        Ok(())
+    }
+
+    /// The mode recorded in the `meta` table, or the default before one is
+    /// or the table exists.
+    fn stored_fts_mode(&self) -> Result<FtsMode> {
+        let has_meta: bool = self.conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'meta')",
+            [],
+            |row| row.get(0),
+        )?;
+        if !has_meta {
+            return Ok(FtsMode::default());
+        }
+        let stored: Option<String> = self
+            .conn
+            .query_row("SELECT value FROM meta WHERE key = 'fts'", [], |row| row.get(0))
+            .optional()?;
+        stored.as_deref().map_or(Ok(FtsMode::Enabled), str::parse)
+    }
+
+    fn apply_fts_mode(&self, mode: FtsMode) -> Result<()> {
+        match mode {
+            FtsMode::Enabled => crate::migrations::ensure_fts(&self.conn, true),
+            FtsMode::ExternalContentRebuild => crate::migrations::ensure_fts(&self.conn, false),
+            FtsMode::Disabled => crate::migrations::drop_fts(&self.conn),
+        }
+    }
+
+    /// Check that a portable library's database, whose item paths are
+    /// relative to the library root, is opened as one (`portable`), and that
+    /// one storing absolute paths isn't. An empty database opened as portable
+    /// is marked as a portable library's from then on.
+    ///
+    /// # Errors
+    /// Returns an error if the database is opened the other way, or it can't
+    /// be read or marked.
+    pub fn check_portable(&self, portable: bool) -> Result<()> {
+        let marked: bool = self.conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM meta WHERE key = 'portable' AND value = '1')",
+            [],
+            |row| row.get(0),
+        )?;
+        if marked == portable {
+            return Ok(());
+        }
+        if marked {
+            return Err(Error::Config(format!(
+                "{} belongs to a portable library, whose paths are relative to its root; \
+                 give the root with --library-root, or run from it with RSBTS_PORTABLE=1",
+                self.path.display()
+            )));
+        }
+        let has_items: bool =
+            self.conn.query_row("SELECT EXISTS (SELECT 1 FROM items)", [], |row| row.get(0))?;
+        if has_items {
+            return Err(Error::Config(format!(
+                "{} stores absolute paths, so it can't be opened as a portable library",
+                self.path.display()
+            )));
+        }
+        self.conn
+            .execute("INSERT OR REPLACE INTO meta (key, value) VALUES ('portable', '1')", [])?;
+        Ok(())
+    }
+
+    /// Rebuild the full-text index from the items table, as an index kept
+    /// without triggers needs after changes.
+    ///
+    /// # Errors
+    /// Returns an error if there is no index or the rebuild fails.
+    pub fn rebuild_fts(&self) -> Result<()> {
+        self.require_fts()?;
+        crate::migrations::rebuild_fts(&self.conn)
+    }
+
+    /// Fail, saying why, if there is no full-text index.
+    fn require_fts(&self) -> Result<()> {
+        if !self.fts5 {
+            return Err(crate::Error::Query(
+                "SQLite was built without FTS5; no full-text index to rebuild".into(),
+            ));
+        }
+        if self.fts_mode.get() == FtsMode::Disabled {
+            return Err(crate::Error::Config(
+                "The full-text index is disabled by library.fts; no index to rebuild".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Copy the database to a new database file at `path` with `SQLite`'s
+    /// online backup, which stays consistent while other connections write:
+    /// the copy restarts if the source changes underneath it.
+    ///
+    /// # Errors
+    /// Returns an error if `path` can't be opened as a database or the copy
+    /// fails.
+    pub fn backup_to(&self, path: &Path) -> Result<()> {
+        let mut dest = Connection::open(path)?;
+        let backup = rusqlite::backup::Backup::new(&self.conn, &mut dest)?;
+        backup.run_to_completion(BACKUP_PAGES_PER_STEP, BACKUP_PAUSE, None)?;
+        Ok(())
+    }
+
+    /// Rebuild the database file to reclaim the space of deleted rows.
+    ///
+    /// # Errors
+    /// Returns an error if the database is busy or the rebuild fails.
+    pub fn vacuum(&self) -> Result<()> {
+        self.conn.execute_batch("VACUUM")?;
+        Ok(())
+    }
+
+    /// Replace the stored prefix `from` of every path with `to`, matching
+    /// whole components, returning how many paths changed. This moves a
+    /// library to new canonical paths, such as after the NAS share is
+    /// renamed; the path mappings of this connection don't apply.
+    ///
+    /// # Errors
+    /// Returns an error if a path would collide with another item's, leaving
+    /// every path as it was.
+    pub fn rewrite_paths(&self, from: &Path, to: &Path) -> Result<usize> {
+        const COLUMNS: [(&str, &str); 6] = [
+            ("items", "path"),
+            ("items", "source_path"),
+            ("albums", "artpath"),
+            ("albums", "source_path"),
+            ("import_log", "source_path"),
+            ("import_log", "dest_path"),
+        ];
+        let rewrite = PathMappings::new([(from.to_path_buf(), to.to_path_buf())]);
+        let tx = self.conn.unchecked_transaction()?;
+        let mut changed = 0;
+        for (table, column) in COLUMNS {
+            let rows: Vec<(i64, StoredPath)> = tx
+                .prepare(&format!("SELECT id, {column} FROM {table} WHERE {column} IS NOT NULL"))?
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<rusqlite::Result<_>>()?;
+            for (id, StoredPath(path)) in rows {
+                let rewritten = rewrite.local(path.clone());
+                if rewritten != path {
+                    tx.execute(
+                        &format!("UPDATE {table} SET {column} = ?1 WHERE id = ?2"),
+                        params![SqlPath(&rewritten), id],
+                    )?;
+                    changed += 1;
+                }
+            }
+        }
+        tx.commit()?;
+        Ok(changed)
+    }
+
+    /// Problems `PRAGMA integrity_check` finds, each as `SQLite` words it;
+    /// empty when the database is sound.
+    ///
+    /// # Errors
+    /// Returns an error if the check can't run.
+    pub fn integrity_problems(&self) -> Result<Vec<String>> {
+        let rows: Vec<String> = self
+            .conn
+            .prepare("PRAGMA integrity_check")?
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(rows.into_iter().filter(|row| row != "ok").collect())
+    }
+
+    /// How far the full-text index has drifted from the items table, or
+    /// `None` without FTS5 or with the index disabled.
+    ///
+    /// # Errors
+    /// Returns an error if the index can't be read or checked.
+    pub fn fts_drift(&self) -> Result<Option<FtsDrift>> {
+        if !self.fts5 || self.fts_mode.get() == FtsMode::Disabled {
+            return Ok(None);
+        }
+        // Every indexed row has a document size entry under its rowid
+        let count = |sql: &str| -> Result<u64> {
+            Ok(self.conn.query_row(sql, [], |row| row.get(0))?)
+        };
+        let unindexed = count(
+            "SELECT COUNT(*) FROM items
+             WHERE id NOT IN (SELECT id FROM items_fts_docsize)",
+        )?;
+        let stale = count(
+            "SELECT COUNT(*) FROM items_fts_docsize
+             WHERE id NOT IN (SELECT id FROM items)",
+        )?;
+        // Compares the indexed terms with the items' current values too,
+        // failing as corrupt if they differ
+        let consistent = match self.conn.execute(
+            "INSERT INTO items_fts(items_fts, rank) VALUES ('integrity-check', 1)",
+            [],
+        ) {
+            Ok(_) => true,
+            Err(rusqlite::Error::SqliteFailure(e, _))
+                if e.code == rusqlite::ErrorCode::DatabaseCorrupt =>
+            {
+                false
+            }
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Some(FtsDrift {
+            unindexed,
+            stale,
+            consistent,
+        }))
+    }
+
+    /// Entry counts and sizes of the metadata cache.
+    ///
+    /// # Errors
+    /// Returns an error if the query fails.
+    pub fn cache_stats(&self) -> Result<Vec<CacheStats>> {
+        crate::metadata_cache::stats(&self.conn)
+    }
+
+    /// Remove all cached metadata, returning the number of entries removed.
+    ///
+    /// # Errors
+    /// Returns an error if the delete fails.
+    pub fn clear_cache(&self) -> Result<usize> {
+        crate::metadata_cache::clear(&self.conn)
+    }
+
+    /// Record an import run as it adds its first album.
+    ///
+    /// # Errors
+    /// Returns an error if the insert fails.
+    pub fn record_import_run(&self, run: &ImportRun) -> Result<()> {
+        crate::runs::record(&self.conn, run)
+    }
+
+    /// Look up an import run by id.
+    ///
+    /// # Errors
+    /// Returns an error if the query fails.
+    pub fn import_run(&self, id: &str) -> Result<Option<ImportRun>> {
+        crate::runs::get(&self.conn, id)
+    }
+
+    /// Import runs with what each added, newest first.
+    ///
+    /// # Errors
+    /// Returns an error if the query fails.
+    pub fn import_runs(&self) -> Result<Vec<RunSummary>> {
+        let mut runs = crate::runs::list(&self.conn)?;
+        for run in &mut runs {
+            for source in &mut run.sources {
+                *source = self.paths.local(std::mem::take(source));
+            }
+        }
+        Ok(runs)
+    }
+
+    /// Write a file an import run put into the library to the import log.
+    ///
+    /// # Errors
+    /// Returns an error if the insert fails.
+    pub fn log_import(&self, entry: &LogEntry) -> Result<()> {
+        let entry = LogEntry {
+            source: entry.source.as_deref().map(|path| self.paths.stored(path).into_owned()),
+            dest: self.paths.stored(&entry.dest).into_owned(),
+            ..entry.clone()
+        };
+        crate::runs::log(&self.conn, &entry)
+    }
+
+    /// The import log of the run `id`: each file it put into the library.
+    ///
+    /// # Errors
+    /// Returns an error if the query fails.
+    pub fn import_log(&self, id: &str) -> Result<Vec<LogEntry>> {
+        let mut entries = crate::runs::log_entries(&self.conn, id)?;
+        for entry in &mut entries {
+            entry.source = entry.source.take().map(|path| self.paths.local(path));
+            entry.dest = self.paths.local(std::mem::take(&mut entry.dest));
+        }
+        Ok(entries)
+    }
+
+    /// Record the fields `edits` the run `run` filled in on album `album_id`.
+    ///
+    /// # Errors
+    /// Returns an error if an insert fails.
+    pub fn log_fills(&self, run: &str, album_id: i64, edits: &[FieldEdit]) -> Result<()> {
+        crate::runs::log_fills(&self.conn, run, album_id, edits)
+    }
+
+    /// The album fields the run `id` filled in.
+    ///
+    /// # Errors
+    /// Returns an error if the query fails.
+    pub fn import_fills(&self, id: &str) -> Result<Vec<Fill>> {
+        crate::runs::fills(&self.conn, id)
+    }
+
+    /// Remove what is left of an undone import run, returning the number of
+    /// albums removed.
+    ///
+    /// # Errors
+    /// Returns an error if a delete fails.
+    pub fn forget_import_run(&self, id: &str) -> Result<u64> {
+        crate::runs::forget(&self.conn, id)
     }
 
     /// Get the current migration version.
@@ -43,21 +629,39 @@ impl Database {
         crate::migrations::current_version(&self.conn)
     }
 
+    /// Migrations [`Self::migrate_to`] would run for `target`, in order.
+    ///
+    /// # Errors
+    /// Returns an error if `target` can't be reached or the query fails.
+    pub fn pending_migrations(
+        &self,
+        target: Option<u32>,
+    ) -> Result<Vec<&'static crate::migrations::Migration>> {
+        crate::migrations::pending(&self.conn, target)
+    }
+
     /// Insert an album and return its ID.
     ///
     /// # Errors
     /// Returns an error if the insert fails.
     pub fn insert_album(&self, album: &Album) -> Result<i64> {
+        let artpath = album.artpath.as_deref().map(|path| self.paths.stored(path));
+        let source_path = album.source_path.as_deref().map(|path| self.paths.stored(path));
         self.conn.execute(
-            "INSERT INTO albums (album, albumartist, year, artpath, mb_albumid, added)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            "INSERT INTO albums (album, albumartist, year, artpath, mb_albumid, added, source_path,
+                                 import_run, mb_releasegroupid, disambiguation)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
             params![
                 album.album,
                 album.albumartist,
                 album.year,
-                album.artpath.as_ref().map(|p| p.to_string_lossy().to_string()),
+                artpath.as_deref().map(SqlPath),
                 album.mb_albumid,
-                album.added.to_rfc3339(),
+                timestamp(&album.added),
+                source_path.as_deref().map(SqlPath),
+                album.import_run,
+                album.mb_releasegroupid,
+                album.disambiguation,
             ],
         )?;
         Ok(self.conn.last_insert_rowid())
@@ -68,13 +672,19 @@ impl Database {
     /// # Errors
     /// Returns an error if the insert fails.
     pub fn insert_item(&self, item: &Item) -> Result<i64> {
+        let source_path = item.source_path.as_deref().map(|path| self.paths.stored(path));
         self.conn.execute(
             "INSERT INTO items (album_id, path, title, artist, album, albumartist, genre, year,
-                               track, disc, format, bitrate, length, mb_trackid, mb_albumid, added, mtime)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17)",
+                               track, disc, format, bitrate, length, mb_trackid, mb_albumid, added, mtime,
+                               size, source_path, import_run, rg_track_gain, rg_track_peak,
+                               rg_album_gain, rg_album_peak, samplerate, channels, bitdepth,
+                               mb_releasegroupid, rating, play_count, last_played, artist_sort,
+                               albumartist_sort)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18,
+                     ?19, ?20, ?21, ?22, ?23, ?24, ?25, ?26, ?27, ?28, ?29, ?30, ?31, ?32, ?33)",
             params![
                 item.album_id,
-                item.path.to_string_lossy().to_string(),
+                SqlPath(&self.paths.stored(&item.path)),
                 item.title,
                 item.artist,
                 item.album,
@@ -88,13 +698,91 @@ impl Database {
                 item.length,
                 item.mb_trackid,
                 item.mb_albumid,
-                item.added.to_rfc3339(),
-                item.mtime.to_rfc3339(),
+                timestamp(&item.added),
+                timestamp(&item.mtime),
+                item.size,
+                source_path.as_deref().map(SqlPath),
+                item.import_run,
+                item.rg_track_gain,
+                item.rg_track_peak,
+                item.rg_album_gain,
+                item.rg_album_peak,
+                item.samplerate,
+                item.channels,
+                item.bitdepth,
+                item.mb_releasegroupid,
+                item.rating,
+                item.play_count,
+                item.last_played.as_ref().map(timestamp),
+                item.artist_sort,
+                item.albumartist_sort,
             ],
         )?;
         Ok(self.conn.last_insert_rowid())
     }
 
+    /// Load archived rows into an empty library in one transaction. Albums
+    /// and items get new ids, and the items and attributes are pointed at
+    /// them.
+    ///
+    /// # Errors
+    /// Returns an error if the library already has albums or items, or an
+    /// insert fails; nothing is written then.
+    pub fn restore(
+        &self,
+        runs: &[ImportRun],
+        albums: &[Album],
+        items: &[Item],
+        attributes: &[Attribute],
+    ) -> Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        let rows: i64 = tx.query_row(
+            "SELECT (SELECT COUNT(*) FROM albums) + (SELECT COUNT(*) FROM items)",
+            [],
+            |row| row.get(0),
+        )?;
+        if rows > 0 {
+            return Err(Error::Archive(
+                "The library isn't empty; restore into a new database".into(),
+            ));
+        }
+
+        for run in runs {
+            crate::runs::record(&tx, run)?;
+        }
+        let mut album_ids = HashMap::new();
+        for album in albums {
+            let id = self.insert_album(album)?;
+            if let Some(old) = album.id {
+                album_ids.insert(old, id);
+            }
+        }
+        let mut item_ids = HashMap::new();
+        for item in items {
+            let mut item = item.clone();
+            item.album_id = item.album_id.and_then(|id| album_ids.get(&id).copied());
+            let id = self.insert_item(&item)?;
+            if let Some(old) = item.id {
+                item_ids.insert(old, id);
+            }
+        }
+        for attribute in attributes {
+            // Owners get new ids like the rows they belong to
+            let owner = match attribute.owner {
+                AttributeOwner::Item(id) => item_ids.get(&id).copied().map(AttributeOwner::Item),
+                AttributeOwner::Album(id) => {
+                    album_ids.get(&id).copied().map(AttributeOwner::Album)
+                }
+            };
+            let owner = owner.ok_or_else(|| {
+                Error::Archive(format!("Attribute {} belongs to no row", attribute.key))
+            })?;
+            self.set_attribute(owner, &attribute.key, &attribute.value)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
     /// Update an existing item.
     ///
     /// # Errors
@@ -102,7 +790,9 @@ impl Database {
     pub fn update_item(&self, id: i64, item: &Item) -> Result<()> {
         self.conn.execute(
             "UPDATE items SET title=?1, artist=?2, album=?3, albumartist=?4, genre=?5,
-             year=?6, track=?7, disc=?8, bitrate=?9, length=?10, mtime=?11 WHERE id=?12",
+             year=?6, track=?7, disc=?8, bitrate=?9, length=?10, mtime=?11, size=?12,
+             samplerate=?13, channels=?14, bitdepth=?15, artist_sort=?16, albumartist_sort=?17
+             WHERE id=?18",
             params![
                 item.title,
                 item.artist,
@@ -114,7 +804,13 @@ impl Database {
                 item.disc,
                 item.bitrate,
                 item.length,
-                item.mtime.to_rfc3339(),
+                timestamp(&item.mtime),
+                item.size,
+                item.samplerate,
+                item.channels,
+                item.bitdepth,
+                item.artist_sort,
+                item.albumartist_sort,
                 id,
             ],
         )?;
@@ -130,87 +826,610 @@ impl Database {
         Ok(())
     }
 
-    /// Allowed fields for `modify_item` to prevent SQL injection.
-    const ALLOWED_ITEM_FIELDS: &[&'static str] = &[
-        "title",
-        "artist",
-        "album",
-        "albumartist",
-        "genre",
-        "year",
-        "track",
-        "disc",
-        "format",
-        "bitrate",
-        "length",
-        "mb_trackid",
-        "mb_albumid",
-    ];
-
-    /// Modify an item's fields.
-    ///
-    /// # Errors
-    /// Returns an error if the update fails or field is invalid.
-    pub fn modify_item(&self, id: i64, fields: &[String]) -> Result<()> {
-        for field in fields {
-            let Some((key, value)) = field.split_once('=') else {
-                continue;
-            };
+    /// Remove the item whose file is at `path` from the database, if any,
+    /// returning it.
+    ///
+    /// # Errors
+    /// Returns an error if the query or the delete fails.
+    pub fn remove_item_at(&self, path: &Path) -> Result<Option<Item>> {
+        let mut stmt = self.conn.prepare("SELECT * FROM items WHERE path = ?1")?;
+        let item = stmt
+            .query_map([SqlPath(&self.paths.stored(path))], |row| self.item_from_row(row))?
+            .next()
+            .transpose()?;
+        if let Some(id) = item.as_ref().and_then(|item| item.id) {
+            self.remove_item(id)?;
+        }
+        Ok(item)
+    }
 
-            if !Self::ALLOWED_ITEM_FIELDS.contains(&key) {
-                return Err(crate::Error::Query(format!("Invalid field: {key}")));
-            }
+    /// Remove an album and all of its items from the database.
+    ///
+    /// # Errors
+    /// Returns an error if the delete fails.
+    pub fn remove_album(&self, id: i64) -> Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        tx.execute("DELETE FROM items WHERE album_id = ?1", [id])?;
+        tx.execute("DELETE FROM albums WHERE id = ?1", [id])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Albums that no item belongs to any more.
+    ///
+    /// # Errors
+    /// Returns an error if the query fails.
+    pub fn empty_albums(&self) -> Result<Vec<Album>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT * FROM albums
+             WHERE id NOT IN (SELECT album_id FROM items WHERE album_id IS NOT NULL)
+             ORDER BY id",
+        )?;
+        let albums = stmt
+            .query_map([], |row| self.album_from_row(row))?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(albums)
+    }
+
+    /// Delete albums that no item belongs to any more, returning how many
+    /// were deleted.
+    ///
+    /// # Errors
+    /// Returns an error if the delete fails.
+    pub fn prune_empty_albums(&self) -> Result<u64> {
+        let pruned = self.conn.execute(
+            "DELETE FROM albums
+             WHERE id NOT IN (SELECT album_id FROM items WHERE album_id IS NOT NULL)",
+            [],
+        )?;
+        Ok(pruned as u64)
+    }
 
-            // Use match for safe SQL generation - each field maps to explicit SQL
-            let sql = match key {
+    /// Make field changes to an item, resolved against its stored values
+    /// (see [`FieldChange::resolve`]). Values are bound with their parsed
+    /// types, so integer columns get integers rather than text. An item
+    /// that doesn't exist is left at that.
+    ///
+    /// # Errors
+    /// Returns an error if a change can't be made to the item's value or
+    /// the update fails.
+    pub fn modify_item(&self, id: i64, changes: &[FieldChange]) -> Result<()> {
+        let Some(item) = self.get_item(id)? else {
+            return Ok(());
+        };
+        let edits = FieldChange::resolve(changes, &item)?;
+        let tx = self.conn.unchecked_transaction()?;
+        for edit in edits {
+            // Each whitelisted field maps to fixed SQL; values are always bound
+            let sql = match edit.field() {
                 "title" => "UPDATE items SET title = ?1 WHERE id = ?2",
                 "artist" => "UPDATE items SET artist = ?1 WHERE id = ?2",
                 "album" => "UPDATE items SET album = ?1 WHERE id = ?2",
                 "albumartist" => "UPDATE items SET albumartist = ?1 WHERE id = ?2",
+                "artist_sort" => "UPDATE items SET artist_sort = ?1 WHERE id = ?2",
+                "albumartist_sort" => "UPDATE items SET albumartist_sort = ?1 WHERE id = ?2",
                 "genre" => "UPDATE items SET genre = ?1 WHERE id = ?2",
                 "year" => "UPDATE items SET year = ?1 WHERE id = ?2",
                 "track" => "UPDATE items SET track = ?1 WHERE id = ?2",
                 "disc" => "UPDATE items SET disc = ?1 WHERE id = ?2",
-                "format" => "UPDATE items SET format = ?1 WHERE id = ?2",
-                "bitrate" => "UPDATE items SET bitrate = ?1 WHERE id = ?2",
-                "length" => "UPDATE items SET length = ?1 WHERE id = ?2",
                 "mb_trackid" => "UPDATE items SET mb_trackid = ?1 WHERE id = ?2",
                 "mb_albumid" => "UPDATE items SET mb_albumid = ?1 WHERE id = ?2",
-                _ => continue, // Should never reach here due to whitelist check above
+                "mb_releasegroupid" => "UPDATE items SET mb_releasegroupid = ?1 WHERE id = ?2",
+                "rating" => "UPDATE items SET rating = ?1 WHERE id = ?2",
+                "play_count" => "UPDATE items SET play_count = ?1 WHERE id = ?2",
+                "last_played" => "UPDATE items SET last_played = ?1 WHERE id = ?2",
+                _ => continue, // Changes only parse editable fields
             };
-            self.conn.execute(sql, params![value, id])?;
+            tx.execute(sql, params![edit.value(), id])?;
         }
+        tx.commit()?;
         Ok(())
     }
 
-    /// Query items matching the given query string.
+    /// Count a play of the item `id`, at `when`.
     ///
     /// # Errors
-    /// Returns an error if the query fails.
-    pub fn query_items(&self, query: Option<&str>) -> Result<Vec<Item>> {
-        let sql = query.map_or_else(
-            || "SELECT * FROM items ORDER BY artist, album, disc, track".into(),
-            |q| {
-                if q.contains(':') {
-                    crate::query::to_sql(q).unwrap_or_else(|_| {
-                        "SELECT * FROM items ORDER BY artist, album, disc, track".into()
-                    })
-                } else {
-                    format!(
-                        "SELECT i.* FROM items i JOIN items_fts f ON i.id = f.rowid WHERE items_fts MATCH '{}'",
-                        q.replace('\'', "''")
-                    )
-                }
-            },
-        );
-
-        let mut stmt = self.conn.prepare(&sql)?;
-        let items = stmt
-            .query_map([], row_to_item)?
+    /// Returns an error if the update fails.
+    pub fn record_play(&self, id: i64, when: DateTime<Utc>) -> Result<()> {
+        self.conn.execute(
+            "UPDATE items SET play_count = play_count + 1, last_played = ?1 WHERE id = ?2",
+            params![timestamp(&when), id],
+        )?;
+        Ok(())
+    }
+
+    /// Apply field edits to an album and cascade them to the album's items,
+    /// in one transaction.
+    ///
+    /// # Errors
+    /// Returns an error if an update fails; nothing is changed then.
+    pub fn modify_album(&self, id: i64, edits: &[FieldEdit]) -> Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        for edit in edits {
+            let (album_sql, items_sql) = match edit.field() {
+                "album" => (
+                    "UPDATE albums SET album = ?1 WHERE id = ?2",
+                    "UPDATE items SET album = ?1 WHERE album_id = ?2",
+                ),
+                "albumartist" => (
+                    "UPDATE albums SET albumartist = ?1 WHERE id = ?2",
+                    "UPDATE items SET albumartist = ?1 WHERE album_id = ?2",
+                ),
+                "year" => (
+                    "UPDATE albums SET year = ?1 WHERE id = ?2",
+                    "UPDATE items SET year = ?1 WHERE album_id = ?2",
+                ),
+                "mb_albumid" => (
+                    "UPDATE albums SET mb_albumid = ?1 WHERE id = ?2",
+                    "UPDATE items SET mb_albumid = ?1 WHERE album_id = ?2",
+                ),
+                "mb_releasegroupid" => (
+                    "UPDATE albums SET mb_releasegroupid = ?1 WHERE id = ?2",
+                    "UPDATE items SET mb_releasegroupid = ?1 WHERE album_id = ?2",
+                ),
+                _ => continue, // FieldEdit::parse_album only parses album fields
+            };
+            tx.execute(album_sql, params![edit.value(), id])?;
+            tx.execute(items_sql, params![edit.value(), id])?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// The flexible attribute `key` of `owner`, if it has one.
+    ///
+    /// # Errors
+    /// Returns an error if the query fails.
+    pub fn get_attribute(&self, owner: AttributeOwner, key: &str) -> Result<Option<String>> {
+        let (table, column, id) = owner.table();
+        let value = self
+            .conn
+            .query_row(
+                &format!("SELECT value FROM {table} WHERE {column} = ?1 AND key = ?2"),
+                params![id, key],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(value)
+    }
+
+    /// Every flexible attribute of `owner`, by key.
+    ///
+    /// # Errors
+    /// Returns an error if the query fails.
+    pub fn attributes(&self, owner: AttributeOwner) -> Result<BTreeMap<String, String>> {
+        let (table, column, id) = owner.table();
+        let mut stmt = self
+            .conn
+            .prepare(&format!("SELECT key, value FROM {table} WHERE {column} = ?1"))?;
+        let attributes = stmt
+            .query_map([id], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<std::result::Result<_, _>>()?;
+        Ok(attributes)
+    }
+
+    /// Every flexible attribute in the library: items' by item id, then
+    /// albums' by album id, each owner's by key.
+    ///
+    /// # Errors
+    /// Returns an error if the query fails.
+    pub fn all_attributes(&self) -> Result<Vec<Attribute>> {
+        let mut attributes = Vec::new();
+        for (table, column, owner) in [
+            ("item_attributes", "item_id", AttributeOwner::Item as fn(i64) -> AttributeOwner),
+            ("album_attributes", "album_id", AttributeOwner::Album),
+        ] {
+            let mut stmt = self.conn.prepare(&format!(
+                "SELECT {column}, key, value FROM {table} ORDER BY {column}, key"
+            ))?;
+            let rows = stmt.query_map([], |row| {
+                Ok(Attribute {
+                    owner: owner(row.get(0)?),
+                    key: row.get(1)?,
+                    value: row.get(2)?,
+                })
+            })?;
+            for row in rows {
+                attributes.push(row?);
+            }
+        }
+        Ok(attributes)
+    }
+
+    /// Set the flexible attribute `key` of `owner` to `value`, replacing the
+    /// one it had.
+    ///
+    /// # Errors
+    /// Returns an error if `key` isn't a valid attribute name, `owner`
+    /// doesn't exist, or the insert fails.
+    pub fn set_attribute(&self, owner: AttributeOwner, key: &str, value: &str) -> Result<()> {
+        check_attribute_key(key)?;
+        let (table, column, id) = owner.table();
+        self.conn.execute(
+            &format!(
+                "INSERT INTO {table} ({column}, key, value) VALUES (?1, ?2, ?3)
+                 ON CONFLICT ({column}, key) DO UPDATE SET value = excluded.value"
+            ),
+            params![id, key, value],
+        )?;
+        Ok(())
+    }
+
+    /// Remove the flexible attribute `key` of `owner`, returning whether it
+    /// had one.
+    ///
+    /// # Errors
+    /// Returns an error if the delete fails.
+    pub fn delete_attribute(&self, owner: AttributeOwner, key: &str) -> Result<bool> {
+        let (table, column, id) = owner.table();
+        let deleted = self.conn.execute(
+            &format!("DELETE FROM {table} WHERE {column} = ?1 AND key = ?2"),
+            params![id, key],
+        )?;
+        Ok(deleted > 0)
+    }
+
+    /// Make flexible attribute edits to `owner`, in one transaction.
+    ///
+    /// # Errors
+    /// Returns an error if an edit fails; nothing is changed then.
+    pub fn edit_attributes(&self, owner: AttributeOwner, edits: &[AttributeEdit]) -> Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        for edit in edits {
+            match &edit.value {
+                Some(value) => self.set_attribute(owner, &edit.key, value)?,
+                None => {
+                    self.delete_attribute(owner, &edit.key)?;
+                }
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Record a file's new mtime and size after rsbts rewrote it.
+    ///
+    /// # Errors
+    /// Returns an error if the update fails.
+    pub fn set_file_stat(&self, id: i64, mtime: DateTime<Utc>, size: u64) -> Result<()> {
+        self.conn.execute(
+            "UPDATE items SET mtime = ?1, size = ?2 WHERE id = ?3",
+            params![timestamp(&mtime), size, id],
+        )?;
+        Ok(())
+    }
+
+    /// Store an item's `ReplayGain` values; missing ones are cleared.
+    ///
+    /// # Errors
+    /// Returns an error if the update fails.
+    pub fn set_replaygain(
+        &self,
+        id: i64,
+        track: Option<Gain>,
+        album: Option<Gain>,
+    ) -> Result<()> {
+        self.conn.execute(
+            "UPDATE items SET rg_track_gain = ?1, rg_track_peak = ?2, rg_album_gain = ?3,
+             rg_album_peak = ?4 WHERE id = ?5",
+            params![
+                track.map(|g| g.gain),
+                track.map(|g| g.peak),
+                album.map(|g| g.gain),
+                album.map(|g| g.peak),
+                id,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Query items matching the given query string.
+    ///
+    /// # Errors
+    /// Returns an error if the query fails.
+    pub fn query_items(&self, query: Option<&str>) -> Result<Vec<Item>> {
+        self.query_items_page(query, Page::default())
+    }
+
+    /// Query `page` of the items matching the given query string.
+    ///
+    /// # Errors
+    /// Returns an error if the query fails.
+    pub fn query_items_page(&self, query: Option<&str>, page: Page) -> Result<Vec<Item>> {
+        let mut items = Vec::new();
+        self.query_items_streamed(query, page, |item| {
+            items.push(item);
+            Ok(())
+        })?;
+        Ok(items)
+    }
+
+    /// Call `f` with each item of `page` of those matching `query`, in order,
+    /// reading rows as it goes rather than loading them all first.
+    ///
+    /// # Errors
+    /// Returns an error if the query fails, or the first error `f` returns,
+    /// which stops the query.
+    pub fn query_items_streamed(
+        &self,
+        query: Option<&str>,
+        page: Page,
+        mut f: impl FnMut(Item) -> Result<()>,
+    ) -> Result<()> {
+        let terms = self.parse_query(query)?;
+        let sql = |mode| crate::query::terms_to_sql(&terms, mode, &self.default_order, page);
+        let mut started = false;
+        let result = self.stream_items(&sql(self.full_text_mode())?, &mut |item| {
+            started = true;
+            f(item)
+        });
+        match result {
+            Err(Error::Database(e)) if !started && is_fts5_error(&e) => {
+                warn_fts5_failed(&e);
+                self.stream_items(&sql(FullTextMode::Like)?, &mut f)
+            }
+            result => result,
+        }
+    }
+
+    fn stream_items(&self, sql: &str, f: &mut impl FnMut(Item) -> Result<()>) -> Result<()> {
+        let mut stmt = self.conn.prepare(sql)?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            f(self.item_from_row(row)?)?;
+        }
+        Ok(())
+    }
+
+    /// The SQL [`Self::query_items_page`] runs.
+    ///
+    /// # Errors
+    /// Returns an error if the query doesn't parse or names an unknown field.
+    pub fn items_sql(&self, query: Option<&str>, page: Page) -> Result<String> {
+        let terms = self.parse_query(query)?;
+        crate::query::terms_to_sql(&terms, self.full_text_mode(), &self.default_order, page)
+    }
+
+    /// Count the items matching `query`, ignoring any `limit:` in it,
+    /// without reading them.
+    ///
+    /// # Errors
+    /// Returns an error if the query fails.
+    pub fn count_items(&self, query: Option<&str>) -> Result<u64> {
+        let terms = self.parse_query(query)?;
+        let count = |mode| -> Result<u64> {
+            let where_clause = crate::query::where_sql(&terms, mode)?;
+            let sql = format!("SELECT COUNT(*) FROM items {where_clause}");
+            Ok(self.conn.query_row(&sql, [], |row| row.get(0))?)
+        };
+        match count(self.full_text_mode()) {
+            Err(Error::Database(e)) if is_fts5_error(&e) => {
+                warn_fts5_failed(&e);
+                count(FullTextMode::Like)
+            }
+            result => result,
+        }
+    }
+
+    /// `count` of the items matching `query` (all of them, if fewer do),
+    /// picked at random by `SQLite`. Sorting and any `limit:` in the query
+    /// are ignored.
+    ///
+    /// # Errors
+    /// Returns an error if the query fails.
+    pub fn random_items(&self, query: Option<&str>, count: u64) -> Result<Vec<Item>> {
+        let terms = self.parse_query(query)?;
+        let pick = |mode| -> Result<Vec<Item>> {
+            let where_clause = crate::query::where_sql(&terms, mode)?;
+            let sql = format!("SELECT * FROM items {where_clause} ORDER BY RANDOM() LIMIT {count}");
+            let mut items = Vec::new();
+            self.stream_items(&sql, &mut |item| {
+                items.push(item);
+                Ok(())
+            })?;
+            Ok(items)
+        };
+        match pick(self.full_text_mode()) {
+            Err(Error::Database(e)) if is_fts5_error(&e) => {
+                warn_fts5_failed(&e);
+                pick(FullTextMode::Like)
+            }
+            result => result,
+        }
+    }
+
+    fn parse_query(&self, query: Option<&str>) -> Result<Vec<QueryTerm>> {
+        let mut terms = query.map(crate::query::parse).transpose()?.unwrap_or_default();
+        if !self.fts5 && crate::query::uses_full_text(&terms) {
+            self.warn_no_fts();
+        }
+        if !self.paths.is_empty() {
+            self.store_query_paths(&mut terms);
+        }
+        Ok(terms)
+    }
+
+    /// Map this machine's paths in `path:` and `source_path:` terms to how
+    /// they are stored, so they match the rows they name.
+    fn store_query_paths(&self, terms: &mut [QueryTerm]) {
+        for term in terms {
+            match term {
+                QueryTerm::Field { name, op, .. } if name == "path" || name == "source_path" => {
+                    if let FieldOp::Substring(value) | FieldOp::Exact(value) = op {
+                        let stored = self.paths.stored(Path::new(value.as_str()));
+                        if let Cow::Owned(stored) = stored {
+                            *value = stored.to_string_lossy().into_owned();
+                        }
+                    }
+                }
+                QueryTerm::Group(inner) => self.store_query_paths(inner),
+                _ => {}
+            }
+        }
+    }
+
+    /// Call `f` with the id and path of every item, without loading whole rows.
+    ///
+    /// # Errors
+    /// Returns an error if the query fails.
+    pub fn iter_paths(&self, mut f: impl FnMut(i64, PathBuf)) -> Result<()> {
+        let mut stmt = self.conn.prepare("SELECT id, path FROM items")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let path: StoredPath = row.get(1)?;
+            f(row.get(0)?, self.paths.local(path.0));
+        }
+        Ok(())
+    }
+
+    /// Ids of items whose files no longer exist.
+    ///
+    /// `on_checked` is called once per item checked.
+    ///
+    /// # Errors
+    /// Returns an error if the query or the existence check fails.
+    pub fn missing_item_ids<F: Fn() + Sync>(
+        &self,
+        check: &ExistenceCheck,
+        on_checked: F,
+    ) -> Result<Vec<i64>> {
+        let mut paths = Vec::new();
+        self.iter_paths(|id, path| paths.push((id, path)))?;
+        check.missing(paths, on_checked)
+    }
+
+    /// Get the items belonging to an album, in track order.
+    ///
+    /// # Errors
+    /// Returns an error if the query fails.
+    pub fn album_items(&self, album_id: i64) -> Result<Vec<Item>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT * FROM items WHERE album_id = ?1 ORDER BY disc, track, path, id")?;
+        let items = stmt
+            .query_map([album_id], |row| self.item_from_row(row))?
             .collect::<std::result::Result<Vec<_>, _>>()?;
         Ok(items)
     }
 
+    /// Items added by the import run `id`.
+    ///
+    /// # Errors
+    /// Returns an error if the query fails.
+    pub fn import_run_items(&self, id: &str) -> Result<Vec<Item>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT * FROM items WHERE import_run = ?1 ORDER BY path, id")?;
+        let items = stmt
+            .query_map([id], |row| self.item_from_row(row))?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(items)
+    }
+
+    /// Albums added by the import run `id`.
+    ///
+    /// # Errors
+    /// Returns an error if the query fails.
+    pub fn import_run_albums(&self, id: &str) -> Result<Vec<Album>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT * FROM albums WHERE import_run = ?1 ORDER BY id")?;
+        let albums = stmt
+            .query_map([id], |row| self.album_from_row(row))?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(albums)
+    }
+
+    /// Get an item by id.
+    ///
+    /// # Errors
+    /// Returns an error if the query fails.
+    pub fn get_item(&self, id: i64) -> Result<Option<Item>> {
+        let mut stmt = self.conn.prepare("SELECT * FROM items WHERE id = ?1")?;
+        let item = stmt.query_map([id], |row| self.item_from_row(row))?.next().transpose()?;
+        Ok(item)
+    }
+
+    /// Get an album by id.
+    ///
+    /// # Errors
+    /// Returns an error if the query fails.
+    pub fn get_album(&self, id: i64) -> Result<Option<Album>> {
+        let mut stmt = self.conn.prepare("SELECT * FROM albums WHERE id = ?1")?;
+        let album = stmt.query_map([id], |row| self.album_from_row(row))?.next().transpose()?;
+        Ok(album)
+    }
+
+    /// The albums with the given ids, by id, in one query. Ids of no album
+    /// are left out.
+    ///
+    /// # Errors
+    /// Returns an error if the query fails.
+    pub fn get_albums(&self, ids: &[i64]) -> Result<HashMap<i64, Album>> {
+        // As a JSON array, however many there are
+        let ids: Vec<String> = ids.iter().map(i64::to_string).collect();
+        let ids = format!("[{}]", ids.join(","));
+        let mut stmt = self
+            .conn
+            .prepare("SELECT * FROM albums WHERE id IN (SELECT value FROM json_each(?1))")?;
+        let mut albums = HashMap::new();
+        for album in stmt.query_map([ids], |row| self.album_from_row(row))? {
+            let album = album?;
+            if let Some(id) = album.id {
+                albums.insert(id, album);
+            }
+        }
+        Ok(albums)
+    }
+
+    /// Album `id` and every other album with its name and album artist,
+    /// ignoring case, in the order they were added.
+    ///
+    /// # Errors
+    /// Returns an error if the query fails.
+    pub fn album_namesakes(&self, id: i64) -> Result<Vec<Album>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT albums.* FROM albums JOIN albums AS ours ON ours.id = ?1
+             WHERE albums.album = ours.album COLLATE NOCASE
+               AND albums.albumartist = ours.albumartist COLLATE NOCASE
+             ORDER BY albums.id",
+        )?;
+        let albums = stmt
+            .query_map([id], |row| self.album_from_row(row))?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(albums)
+    }
+
+    /// Record the cover art file of an album.
+    ///
+    /// # Errors
+    /// Returns an error if the update fails.
+    pub fn set_album_artpath(&self, id: i64, path: &Path) -> Result<()> {
+        self.conn.execute(
+            "UPDATE albums SET artpath = ?1 WHERE id = ?2",
+            params![SqlPath(&self.paths.stored(path)), id],
+        )?;
+        Ok(())
+    }
+
+    /// Take album `id`'s art away if it is the file at `path`.
+    ///
+    /// # Errors
+    /// Returns an error if the update fails.
+    pub fn clear_album_artpath(&self, id: i64, path: &Path) -> Result<()> {
+        self.conn.execute(
+            "UPDATE albums SET artpath = NULL WHERE id = ?1 AND artpath = ?2",
+            params![id, SqlPath(&self.paths.stored(path))],
+        )?;
+        Ok(())
+    }
+
+    fn warn_no_fts(&self) {
+        if !self.fts_warned.replace(true) {
+            warn!("SQLite lacks FTS5; searching with slower substring matching instead");
+        }
+    }
+
     /// Query albums matching the given query string.
     ///
     /// # Errors
@@ -220,61 +1439,196 @@ impl Database {
             None => {
                 let mut stmt = self
                     .conn
-                    .prepare("SELECT * FROM albums ORDER BY albumartist, year, album")?;
+                    .prepare("SELECT * FROM albums ORDER BY albumartist, year, album, id")?;
                 let albums = stmt
-                    .query_map([], row_to_album)?
+                    .query_map([], |row| self.album_from_row(row))?
                     .collect::<std::result::Result<Vec<_>, _>>()?;
                 Ok(albums)
             }
             Some(q) => {
-                let pattern = format!("%{q}%");
+                let pattern = like_pattern(q);
                 let mut stmt = self.conn.prepare(
-                    "SELECT * FROM albums WHERE album LIKE ?1 OR albumartist LIKE ?1 \
-                     ORDER BY albumartist, year, album",
+                    "SELECT * FROM albums WHERE album LIKE ?1 ESCAPE '\\' \
+                     OR albumartist LIKE ?1 ESCAPE '\\' ORDER BY albumartist, year, album, id",
                 )?;
                 let albums = stmt
-                    .query_map([&pattern], row_to_album)?
+                    .query_map([&pattern], |row| self.album_from_row(row))?
                     .collect::<std::result::Result<Vec<_>, _>>()?;
                 Ok(albums)
             }
         }
     }
 
+    /// Albums like [`Self::query_albums`] finds, each with totals over its
+    /// tracks, in a single query. Albums without tracks have zero totals.
+    ///
+    /// # Errors
+    /// Returns an error if the query fails.
+    pub fn query_albums_with_stats(&self, query: Option<&str>) -> Result<Vec<(Album, AlbumStats)>> {
+        let pattern = query.map(like_pattern);
+        let filter = if pattern.is_some() {
+            "WHERE albums.album LIKE ?1 ESCAPE '\\' OR albums.albumartist LIKE ?1 ESCAPE '\\'"
+        } else {
+            ""
+        };
+        let sql = format!(
+            "SELECT albums.*, COUNT(items.id) AS track_count, \
+             COALESCE(SUM(items.length), 0) AS total_length, \
+             COALESCE(SUM(COALESCE(items.size, items.bitrate * 1000 * items.length / 8)), 0) \
+             AS total_size, GROUP_CONCAT(DISTINCT items.format) AS formats, \
+             MIN(items.bitrate) AS min_bitrate, MAX(items.bitrate) AS max_bitrate \
+             FROM albums LEFT JOIN items ON items.album_id = albums.id {filter} \
+             GROUP BY albums.id \
+             ORDER BY albums.albumartist, albums.year, albums.album, albums.id"
+        );
+        let mut stmt = self.conn.prepare(&sql)?;
+        let rows = stmt.query_map(rusqlite::params_from_iter(&pattern), |row| {
+            let formats: Option<String> = row.get("formats")?;
+            let mut formats: Vec<AudioFormat> = formats
+                .iter()
+                .flat_map(|names| names.split(','))
+                .map(|name| AudioFormat::from_name(name).unwrap_or(AudioFormat::Unknown))
+                .collect();
+            formats.sort_by_key(|format| format.as_str());
+            formats.dedup();
+            let stats = AlbumStats {
+                track_count: row.get("track_count")?,
+                total_length: row.get("total_length")?,
+                total_size: row.get::<_, f64>("total_size")?.max(0.0) as u64,
+                formats,
+                min_bitrate: row.get("min_bitrate")?,
+                max_bitrate: row.get("max_bitrate")?,
+            };
+            Ok((self.album_from_row(row)?, stats))
+        })?;
+        Ok(rows.collect::<std::result::Result<Vec<_>, _>>()?)
+    }
+
+    /// The oldest album with the release id `mb_albumid`.
+    ///
+    /// # Errors
+    /// Returns an error if the query fails.
+    pub fn find_album_by_mbid(&self, mb_albumid: &str) -> Result<Option<Album>> {
+        let mut stmt =
+            self.conn.prepare("SELECT * FROM albums WHERE mb_albumid = ?1 ORDER BY id LIMIT 1")?;
+        let album =
+            stmt.query_map([mb_albumid], |row| self.album_from_row(row))?.next().transpose()?;
+        Ok(album)
+    }
+
+    /// The oldest album by the same album artist with the same title as
+    /// `album`, ignoring case, and from the same year where both have one.
+    /// One with a release id other than `album`'s doesn't count.
+    ///
+    /// # Errors
+    /// Returns an error if the query fails.
+    pub fn find_album_like(&self, album: &Album) -> Result<Option<Album>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT * FROM albums
+             WHERE albumartist = ?1 COLLATE NOCASE AND album = ?2 COLLATE NOCASE
+             AND (year IS NULL OR ?3 IS NULL OR year = ?3)
+             AND (mb_albumid IS NULL OR ?4 IS NULL OR mb_albumid = ?4)
+             ORDER BY id LIMIT 1",
+        )?;
+        let params = params![
+            album.albumartist.trim(),
+            album.album.trim(),
+            album.year,
+            album.mb_albumid
+        ];
+        let found = stmt.query_map(params, |row| self.album_from_row(row))?.next().transpose()?;
+        Ok(found)
+    }
+
     /// Get library statistics.
     ///
     /// # Errors
     /// Returns an error if the query fails.
     pub fn stats(&self) -> Result<Stats> {
-        let tracks: u64 = self
-            .conn
-            .query_row("SELECT COUNT(*) FROM items", [], |row| row.get(0))?;
-        let albums: u64 = self
-            .conn
-            .query_row("SELECT COUNT(*) FROM albums", [], |row| row.get(0))?;
-        let artists: u64 = self.conn.query_row(
-            "SELECT COUNT(DISTINCT artist) FROM items",
-            [],
-            |row| row.get(0),
-        )?;
-        let total_length: f64 = self
-            .conn
-            .query_row("SELECT COALESCE(SUM(length), 0) FROM items", [], |row| {
-                row.get(0)
-            })?;
+        Ok(self.aggregate(&[], None)?.pop().map(|(_, stats)| stats).unwrap_or_default())
+    }
 
-        let total_size: u64 = self.conn.query_row(
-            "SELECT COALESCE(SUM(bitrate * length / 8), 0) FROM items",
-            [],
-            |row| row.get::<_, f64>(0).map(|v| v.max(0.0) as u64),
-        )?;
+    /// Get statistics for the items matching a query.
+    ///
+    /// # Errors
+    /// Returns an error if the query fails.
+    pub fn query_stats(&self, query: &str) -> Result<Stats> {
+        let terms = self.parse_query(Some(query))?;
+        Ok(self.aggregate(&terms, None)?.pop().map(|(_, stats)| stats).unwrap_or_default())
+    }
 
-        Ok(Stats {
-            tracks,
-            albums,
-            artists,
-            total_length,
-            total_size,
-        })
+    /// Statistics for each `group` of the items matching `query`, most
+    /// tracks first.
+    ///
+    /// # Errors
+    /// Returns an error if the query fails.
+    pub fn grouped_stats(
+        &self,
+        query: Option<&str>,
+        group: StatsGroup,
+    ) -> Result<Vec<(String, Stats)>> {
+        let terms = self.parse_query(query)?;
+        self.aggregate(&terms, Some(group))
+    }
+
+    /// Sum up the items `terms` match, in one row per group or a single row
+    /// for all of them.
+    fn aggregate(
+        &self,
+        terms: &[QueryTerm],
+        group: Option<StatsGroup>,
+    ) -> Result<Vec<(String, Stats)>> {
+        match self.aggregate_in(terms, group, self.full_text_mode()) {
+            Err(Error::Database(e)) if is_fts5_error(&e) => {
+                warn_fts5_failed(&e);
+                self.aggregate_in(terms, group, FullTextMode::Like)
+            }
+            result => result,
+        }
+    }
+
+    fn aggregate_in(
+        &self,
+        terms: &[QueryTerm],
+        group: Option<StatsGroup>,
+        mode: FullTextMode,
+    ) -> Result<Vec<(String, Stats)>> {
+        let where_clause = crate::query::where_sql(terms, mode)?;
+        let (key, grouping) = group.map_or(("''", String::new()), |group| {
+            (group.sql(), "GROUP BY 1 ORDER BY 2 DESC, 1 COLLATE NOCASE".into())
+        });
+        // Albums rows can outlive their items; count the albums items are in
+        let sql = format!(
+            "SELECT {key}, COUNT(*), COUNT(DISTINCT album_id), COUNT(DISTINCT artist), \
+             COALESCE(SUM(length), 0), COALESCE(SUM(bitrate * length / 8), 0) \
+             FROM items {where_clause} {grouping}"
+        );
+        let mut stmt = self.conn.prepare(&sql)?;
+        let rows = stmt.query_map([], |row| {
+            let stats = Stats {
+                tracks: row.get(1)?,
+                albums: row.get(2)?,
+                artists: row.get(3)?,
+                total_length: row.get(4)?,
+                total_size: row.get::<_, f64>(5)?.max(0.0) as u64,
+                missing: None,
+            };
+            Ok((row.get(0)?, stats))
+        })?;
+        Ok(rows.collect::<std::result::Result<Vec<_>, _>>()?)
+    }
+
+    /// Whether an item was imported from the file at `path`.
+    ///
+    /// # Errors
+    /// Returns an error if the query fails.
+    pub fn imported_from(&self, path: &Path) -> Result<bool> {
+        let found: bool = self.conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM items WHERE source_path = ?1)",
+            [SqlPath(&self.paths.stored(path))],
+            |row| row.get(0),
+        )?;
+        Ok(found)
     }
 
     /// Check if an item with the given path exists.
@@ -284,13 +1638,127 @@ impl Database {
     pub fn item_exists(&self, path: &Path) -> Result<bool> {
         let count: i64 = self.conn.query_row(
             "SELECT COUNT(*) FROM items WHERE path = ?1",
-            [path.to_string_lossy().to_string()],
+            [SqlPath(&self.paths.stored(path))],
             |row| row.get(0),
         )?;
         Ok(count > 0)
     }
 }
 
+/// A `LIKE ... ESCAPE '\'` pattern matching `text` anywhere, with its
+/// wildcards taken literally.
+fn like_pattern(text: &str) -> String {
+    let mut pattern = String::with_capacity(text.len() + 2);
+    pattern.push('%');
+    for c in text.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('%');
+    pattern
+}
+
+/// Whether `e` is FTS5 rejecting a `MATCH` expression, which the same query
+/// can still run with `LIKE`.
+fn is_fts5_error(e: &rusqlite::Error) -> bool {
+    e.to_string().contains("fts5")
+}
+
+fn warn_fts5_failed(e: &rusqlite::Error) {
+    warn!("Full-text search failed ({e}); searching with substring matching instead");
+}
+
+/// Define `regexp(pattern, value)`, which SQLite calls for `value REGEXP
+/// pattern`, matching as [`crate::query::regex`] does. Each statement compiles
+/// its pattern once. A missing value gives NULL, like other comparisons.
+fn register_regexp(conn: &Connection) -> rusqlite::Result<()> {
+    conn.create_scalar_function(
+        "regexp",
+        2,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        |ctx| {
+            let regex = ctx.get_or_create_aux(0, |pattern| -> Result<regex::Regex> {
+                crate::query::regex(pattern.as_str().map_err(rusqlite::Error::from)?)
+            })?;
+            let text = match ctx.get_raw(1) {
+                ValueRef::Null => return Ok(None),
+                ValueRef::Integer(n) => n.to_string(),
+                ValueRef::Real(n) => crate::query::real_text(n),
+                ValueRef::Text(text) | ValueRef::Blob(text) => {
+                    String::from_utf8_lossy(text).into_owned()
+                }
+            };
+            Ok(Some(regex.is_match(&text)))
+        },
+    )
+}
+
+/// Define `sort_name(name)`, giving [`crate::fields::sort_name`] for the
+/// `smart_artist` sort key.
+fn register_sort_name(conn: &Connection) -> rusqlite::Result<()> {
+    conn.create_scalar_function(
+        "sort_name",
+        1,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        |ctx| {
+            let name = ctx.get::<Option<String>>(0)?;
+            Ok(name.map(|name| crate::fields::sort_name(&name).to_string()))
+        },
+    )
+}
+
+/// A path as it's stored: as TEXT when it's valid UTF-8, so queries see it
+/// as they would any text, and otherwise as its raw bytes in a BLOB, so it
+/// still names the file. A Windows path that isn't valid Unicode has no
+/// bytes to keep; it is stored with replacement characters, and a warning.
+pub(crate) struct SqlPath<'a>(pub &'a Path);
+
+impl ToSql for SqlPath<'_> {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(self.0.to_str().map_or_else(|| non_utf8_path(self.0), ToSqlOutput::from))
+    }
+}
+
+#[cfg(unix)]
+fn non_utf8_path(path: &Path) -> ToSqlOutput<'_> {
+    use std::os::unix::ffi::OsStrExt;
+
+    ToSqlOutput::Borrowed(ValueRef::Blob(path.as_os_str().as_bytes()))
+}
+
+#[cfg(not(unix))]
+fn non_utf8_path(path: &Path) -> ToSqlOutput<'_> {
+    warn!("{} isn't valid Unicode and is stored with replacement characters", path.display());
+    ToSqlOutput::from(path.to_string_lossy().into_owned())
+}
+
+/// A path read back from a column [`SqlPath`] wrote.
+pub(crate) struct StoredPath(pub PathBuf);
+
+impl FromSql for StoredPath {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value {
+            ValueRef::Text(bytes) | ValueRef::Blob(bytes) => Ok(Self(path_from_bytes(bytes))),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
+/// The path whose name is `bytes`.
+#[cfg(unix)]
+pub(crate) fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+
+    PathBuf::from(std::ffi::OsStr::from_bytes(bytes))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
+}
+
 /// Trait for converting database rows to domain types.
 trait FromRow: Sized {
     fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self>;
@@ -299,61 +1767,1292 @@ trait FromRow: Sized {
 impl FromRow for Item {
     fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
         let format_str: String = row.get("format")?;
-        let path_str: String = row.get("path")?;
+        let path: StoredPath = row.get("path")?;
         let added_str: String = row.get("added")?;
         let mtime_str: String = row.get("mtime")?;
         let albumartist: Option<String> = row.get("albumartist")?;
+        let source_path: Option<StoredPath> = row.get("source_path")?;
+        let last_played: Option<String> = row.get("last_played")?;
 
         Ok(Self {
             id: row.get("id")?,
             album_id: row.get("album_id")?,
-            path: path_str.into(),
+            path: path.0,
             title: row.get("title")?,
             artist: row.get("artist")?,
             album: row.get("album")?,
             albumartist,
+            artist_sort: row.get("artist_sort")?,
+            albumartist_sort: row.get("albumartist_sort")?,
             genre: row.get("genre")?,
             year: row.get("year")?,
             track: row.get("track")?,
             disc: row.get("disc")?,
-            format: AudioFormat::from_extension(&format_str),
+            format: AudioFormat::from_name(&format_str).unwrap_or(AudioFormat::Unknown),
             bitrate: row.get("bitrate")?,
             length: row.get("length")?,
+            samplerate: row.get("samplerate")?,
+            channels: row.get("channels")?,
+            bitdepth: row.get("bitdepth")?,
             mb_trackid: row.get("mb_trackid")?,
             mb_albumid: row.get("mb_albumid")?,
+            mb_releasegroupid: row.get("mb_releasegroupid")?,
             added: parse_datetime(&added_str),
             mtime: parse_datetime(&mtime_str),
+            size: row.get("size")?,
+            source_path: source_path.map(|path| path.0),
+            import_run: row.get("import_run")?,
+            rg_track_gain: row.get("rg_track_gain")?,
+            rg_track_peak: row.get("rg_track_peak")?,
+            rg_album_gain: row.get("rg_album_gain")?,
+            rg_album_peak: row.get("rg_album_peak")?,
+            rating: row.get("rating")?,
+            play_count: row.get("play_count")?,
+            last_played: last_played.as_deref().map(parse_datetime),
         })
     }
 }
 
+impl Library for Database {
+    fn namesakes(&self, album_id: i64) -> Result<Vec<Album>> {
+        self.album_namesakes(album_id)
+    }
+
+    fn attribute(&self, item: &Item, key: &str) -> Result<Option<String>> {
+        let own = match item.id {
+            Some(id) => self.get_attribute(AttributeOwner::Item(id), key)?,
+            None => None,
+        };
+        match (own, item.album_id) {
+            (None, Some(album_id)) => self.get_attribute(AttributeOwner::Album(album_id), key),
+            (own, _) => Ok(own),
+        }
+    }
+}
+
 impl FromRow for Album {
     fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
-        let artpath_str: Option<String> = row.get("artpath")?;
+        let artpath: Option<StoredPath> = row.get("artpath")?;
         let added_str: String = row.get("added")?;
+        let source_path: Option<StoredPath> = row.get("source_path")?;
 
         Ok(Self {
             id: row.get("id")?,
             album: row.get("album")?,
             albumartist: row.get("albumartist")?,
             year: row.get("year")?,
-            artpath: artpath_str.map(Into::into),
+            artpath: artpath.map(|path| path.0),
             mb_albumid: row.get("mb_albumid")?,
+            mb_releasegroupid: row.get("mb_releasegroupid")?,
             added: parse_datetime(&added_str),
+            source_path: source_path.map(|path| path.0),
+            import_run: row.get("import_run")?,
+            disambiguation: row.get("disambiguation")?,
         })
     }
 }
 
-fn row_to_item(row: &rusqlite::Row<'_>) -> rusqlite::Result<Item> {
-    Item::from_row(row)
+impl Database {
+    /// The item in `row`, with its paths as they are on this machine.
+    fn item_from_row(&self, row: &rusqlite::Row<'_>) -> rusqlite::Result<Item> {
+        let mut item = Item::from_row(row)?;
+        item.path = self.paths.local(item.path);
+        item.source_path = item.source_path.map(|path| self.paths.local(path));
+        Ok(item)
+    }
+
+    /// The album in `row`, with its paths as they are on this machine.
+    fn album_from_row(&self, row: &rusqlite::Row<'_>) -> rusqlite::Result<Album> {
+        let mut album = Album::from_row(row)?;
+        album.artpath = album.artpath.map(|path| self.paths.local(path));
+        album.source_path = album.source_path.map(|path| self.paths.local(path));
+        Ok(album)
+    }
+}
+
+/// How long a statement waits for another connection's lock before failing
+/// with "database is locked".
+const BUSY_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(5000);
+
+/// Set up a new connection to the library.
+///
+/// The write-ahead log lets readers such as a running `ls` carry on while an
+/// import writes; it is a property of the file, so this only switches it on
+/// the first time (in-memory databases keep their own journal). Foreign keys
+/// are enforced so an item can't point at an album that is gone: albums are
+/// only deleted once they have no items, or together with them.
+fn configure(conn: &Connection) -> Result<()> {
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    conn.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+    conn.pragma_update(None, "synchronous", "NORMAL")?;
+    conn.pragma_update(None, "foreign_keys", true)?;
+    Ok(())
 }
 
-fn row_to_album(row: &rusqlite::Row<'_>) -> rusqlite::Result<Album> {
-    Album::from_row(row)
+/// Pages [`Database::backup_to`] copies at a time, pausing between steps so
+/// writers get a turn.
+const BACKUP_PAGES_PER_STEP: std::ffi::c_int = 256;
+const BACKUP_PAUSE: std::time::Duration = std::time::Duration::from_millis(10);
+
+/// Differences between the full-text index and the items table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FtsDrift {
+    /// Items missing from the index, which searches can't find.
+    pub unindexed: u64,
+    /// Index entries for items that no longer exist.
+    pub stale: u64,
+    /// Whether FTS5's own integrity check, which also compares the indexed
+    /// terms with the items' values, passed.
+    pub consistent: bool,
+}
+
+impl FtsDrift {
+    /// Whether the index matches the items table.
+    #[must_use]
+    pub const fn is_clean(&self) -> bool {
+        self.unindexed == 0 && self.stale == 0 && self.consistent
+    }
+}
+
+/// `time` as the library stores it: RFC 3339 in UTC with a `Z` suffix and
+/// always six digits of fractional seconds, so timestamps sort and compare
+/// as text.
+#[must_use]
+pub fn timestamp(time: &DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Micros, true)
 }
 
 fn parse_datetime(s: &str) -> DateTime<Utc> {
-    DateTime::parse_from_rfc3339(s)
-        .map(|dt| dt.with_timezone(&Utc))
-        .unwrap_or(DateTime::UNIX_EPOCH)
+    DateTime::parse_from_rfc3339(s).map_or(DateTime::UNIX_EPOCH, |dt| dt.with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    fn test_db(fts5: bool) -> Database {
+        let mut db = Database::open_in_memory().unwrap();
+        // Force the capability flag regardless of the build's actual FTS5 support
+        db.fts5 = fts5;
+        db.migrate().unwrap();
+        db
+    }
+
+    fn insert_test_item(db: &Database, title: &str, artist: &str, genre: &str) {
+        db.insert_item(&Item {
+            path: format!("/{title}.mp3").into(),
+            title: title.into(),
+            artist: artist.into(),
+            album: "Paranoid".into(),
+            genre: Some(genre.into()),
+            year: Some(1970),
+            bitrate: 320,
+            length: 180.0,
+            ..testutil::item()
+        })
+        .unwrap();
+    }
+
+    fn fts_table_exists(db: &Database) -> bool {
+        db.conn
+            .query_row(
+                "SELECT COUNT(*) > 0 FROM sqlite_master WHERE name='items_fts'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn test_migrate_without_fts5_skips_index() {
+        let db = test_db(false);
+        assert!(!fts_table_exists(&db));
+        assert!(db.rebuild_fts().is_err());
+    }
+
+    #[test]
+    fn test_bare_word_query_without_fts5() {
+        let db = test_db(false);
+        insert_test_item(&db, "War Pigs", "Black Sabbath", "Metal");
+        insert_test_item(&db, "Help!", "The Beatles", "Rock");
+
+        let items = db.query_items(Some("sabbath")).unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].title, "War Pigs");
+
+        let items = db.query_items(Some("rock year:1970")).unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].title, "Help!");
+    }
+
+    #[test]
+    fn test_bare_word_queries_with_punctuation() {
+        let fts = Database::open_in_memory().unwrap();
+        fts.migrate().unwrap();
+        let like = test_db(false);
+        for db in [&fts, &like] {
+            insert_test_item(db, "Highway to Hell", "AC/DC", "Rock");
+            insert_test_item(db, "Don't Stop Me Now", "Queen", "Rock");
+            insert_test_item(db, "Paranoid (Live)", "Black Sabbath", "Metal");
+            insert_test_item(db, "Jóga", "Björk", "Electronic");
+            insert_test_item(db, "Heart of Hearts", "!!!", "Dance");
+        }
+
+        for (query, expected) in [
+            ("AC/DC", vec!["Highway to Hell"]),
+            ("don't", vec!["Don't Stop Me Now"]),
+            ("stop don't", vec!["Don't Stop Me Now"]),
+            ("(live)", vec!["Paranoid (Live)"]),
+            ("Jóga", vec!["Jóga"]),
+            ("!!!", vec!["Heart of Hearts"]),
+            ("hey-ho", vec![]),
+        ] {
+            for db in [&fts, &like] {
+                let titles: Vec<String> = db
+                    .query_items(Some(query))
+                    .unwrap()
+                    .into_iter()
+                    .map(|item| item.title)
+                    .collect();
+                assert_eq!(titles, expected, "{query}");
+                assert_eq!(db.count_items(Some(query)).unwrap(), expected.len() as u64);
+            }
+        }
+
+        // What the fallback catches
+        if fts.has_fts5() {
+            let err = fts
+                .conn
+                .query_row("SELECT COUNT(*) FROM items_fts WHERE items_fts MATCH 'AND'", [], |row| {
+                    row.get::<_, i64>(0)
+                })
+                .unwrap_err();
+            assert!(is_fts5_error(&err));
+        }
+    }
+
+    #[test]
+    fn test_modify_album_cascades_to_items() {
+        let db = test_db(false);
+        let album_id = db
+            .insert_album(&Album {
+                album: "Paranoid".into(),
+                albumartist: "Black Sabbath".into(),
+                year: Some(1970),
+                ..testutil::album()
+            })
+            .unwrap();
+        insert_test_item(&db, "War Pigs", "Black Sabbath", "Metal");
+        insert_test_item(&db, "Iron Man", "Black Sabbath", "Metal");
+        insert_test_item(&db, "Help!", "The Beatles", "Rock");
+        db.conn
+            .execute(
+                "UPDATE items SET album_id = ?1 WHERE artist = 'Black Sabbath'",
+                [album_id],
+            )
+            .unwrap();
+
+        let edits: Vec<FieldEdit> = ["album=Paranoid (Remaster)", "year=2009"]
+            .iter()
+            .map(|s| FieldEdit::parse_album(s).unwrap())
+            .collect();
+        db.modify_album(album_id, &edits).unwrap();
+
+        let album = &db.query_albums(None).unwrap()[0];
+        assert_eq!((album.album.as_str(), album.year), ("Paranoid (Remaster)", Some(2009)));
+        let items = db.album_items(album_id).unwrap();
+        assert_eq!(items.len(), 2);
+        assert!(items
+            .iter()
+            .all(|i| i.album == "Paranoid (Remaster)" && i.year == Some(2009)));
+        // Items of other albums are untouched
+        let other = db.query_items(Some("title:=Help!")).unwrap();
+        assert_eq!((other[0].album.as_str(), other[0].year), ("Paranoid", Some(1970)));
+    }
+
+    #[test]
+    fn test_album_stats_include_empty_albums() {
+        let db = test_db(false);
+        let paranoid = Album {
+            album: "Paranoid".into(),
+            albumartist: "Black Sabbath".into(),
+            year: Some(1970),
+            ..testutil::album()
+        };
+        let album_id = db.insert_album(&paranoid).unwrap();
+        db.insert_album(&Album {
+            album: "Master of Reality".into(),
+            year: Some(1971),
+            ..paranoid
+        })
+        .unwrap();
+        for title in ["War Pigs", "Iron Man", "Planet Caravan"] {
+            insert_test_item(&db, title, "Black Sabbath", "Metal");
+        }
+        db.conn
+            .execute("UPDATE items SET album_id = ?1, size = 4000000", [album_id])
+            .unwrap();
+        db.conn
+            .execute(
+                "UPDATE items SET format = 'FLAC', bitrate = 900, size = NULL \
+                 WHERE title = 'Iron Man'",
+                [],
+            )
+            .unwrap();
+
+        let albums = db.query_albums_with_stats(None).unwrap();
+        assert_eq!(albums.len(), 2);
+        let (album, stats) = &albums[0];
+        assert_eq!(album.album, "Paranoid");
+        assert_eq!(stats.track_count, 3);
+        assert!((stats.total_length - 540.0).abs() < f64::EPSILON);
+        // Iron Man's size is estimated from its bitrate, in kbps
+        assert_eq!(stats.total_size, 8_000_000 + 900 * 1000 * 180 / 8);
+        assert_eq!(stats.formats, [AudioFormat::Flac, AudioFormat::Mp3]);
+        assert_eq!((stats.min_bitrate, stats.max_bitrate), (Some(320), Some(900)));
+        assert_eq!(albums[1].1, AlbumStats::default());
+
+        let matched = db.query_albums_with_stats(Some("master")).unwrap();
+        assert_eq!(matched.len(), 1);
+        assert_eq!(matched[0].0.album, "Master of Reality");
+        // Wildcards in the search text match only themselves
+        assert!(db.query_albums_with_stats(Some("%")).unwrap().is_empty());
+        assert!(db.query_albums(Some("Para_oid")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_find_existing_album() {
+        let db = test_db(false);
+        let paranoid = Album {
+            album: "Paranoid".into(),
+            albumartist: "Black Sabbath".into(),
+            ..testutil::album()
+        };
+        let untagged = db.insert_album(&paranoid).unwrap();
+        let remaster = db
+            .insert_album(&Album {
+                year: Some(2009),
+                mb_albumid: Some("remaster".into()),
+                ..paranoid.clone()
+            })
+            .unwrap();
+
+        let found = |album: &Album| db.find_album_like(album).unwrap().and_then(|a| a.id);
+        assert_eq!(db.find_album_by_mbid("remaster").unwrap().unwrap().id, Some(remaster));
+        assert!(db.find_album_by_mbid("original").unwrap().is_none());
+        // The album without a year or release id goes with anything by its name
+        let original = Album {
+            album: "PARANOID ".into(),
+            year: Some(1970),
+            mb_albumid: Some("original".into()),
+            ..paranoid.clone()
+        };
+        assert_eq!(found(&original), Some(untagged));
+
+        db.conn.execute("DELETE FROM albums WHERE id = ?1", [untagged]).unwrap();
+        assert_eq!(found(&original), None);
+        let unknown_year = Album {
+            year: None,
+            ..original
+        };
+        assert_eq!(found(&unknown_year), None);
+        assert_eq!(found(&paranoid), Some(remaster));
+    }
+
+    #[test]
+    fn test_prune_empty_albums() {
+        let db = test_db(false);
+        let album_id = db
+            .insert_album(&Album {
+                album: "Paranoid".into(),
+                albumartist: "Black Sabbath".into(),
+                year: Some(1970),
+                ..testutil::album()
+            })
+            .unwrap();
+        insert_test_item(&db, "War Pigs", "Black Sabbath", "Metal");
+        db.conn
+            .execute("UPDATE items SET album_id = ?1", [album_id])
+            .unwrap();
+        assert_eq!(db.stats().unwrap().albums, 1);
+
+        let item_id = db.query_items(None).unwrap()[0].id.unwrap();
+        db.remove_item(item_id).unwrap();
+
+        // The albums row is left behind, but no longer counted
+        assert_eq!(db.query_albums(None).unwrap().len(), 1);
+        assert_eq!(db.stats().unwrap().albums, 0);
+        assert_eq!(db.empty_albums().unwrap()[0].id, Some(album_id));
+
+        assert_eq!(db.prune_empty_albums().unwrap(), 1);
+        assert!(db.query_albums(None).unwrap().is_empty());
+        assert_eq!(db.prune_empty_albums().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_attributes_are_queried_and_cascade() {
+        let db = test_db(false);
+        let album_id = db
+            .insert_album(&Album {
+                album: "Paranoid".into(),
+                albumartist: "Black Sabbath".into(),
+                year: Some(1970),
+                ..testutil::album()
+            })
+            .unwrap();
+        insert_test_item(&db, "War Pigs", "Black Sabbath", "Metal");
+        insert_test_item(&db, "Iron Man", "Black Sabbath", "Metal");
+        insert_test_item(&db, "Help!", "The Beatles", "Rock");
+        db.conn
+            .execute("UPDATE items SET album_id = ?1 WHERE artist = 'Black Sabbath'", [album_id])
+            .unwrap();
+        let id = |title: &str| db.query_items(Some(&format!("title:=\"{title}\""))).unwrap()[0].id;
+        let (war_pigs, help) = (id("War Pigs").unwrap(), id("Help!").unwrap());
+
+        let album = AttributeOwner::Album(album_id);
+        db.set_attribute(album, "source", "vinyl rip").unwrap();
+        db.set_attribute(AttributeOwner::Item(war_pigs), "source", "cassette").unwrap();
+        db.set_attribute(AttributeOwner::Item(help), "mood", "sunny").unwrap();
+        db.set_attribute(AttributeOwner::Item(help), "mood", "upbeat").unwrap();
+        assert!(db.set_attribute(AttributeOwner::Item(help), "bad key", "x").is_err());
+        assert_eq!(
+            db.get_attribute(AttributeOwner::Item(help), "mood").unwrap().as_deref(),
+            Some("upbeat")
+        );
+
+        // An item's own attribute beats its album's
+        let titles = |query: &str| -> Vec<String> {
+            let items = db.query_items(Some(query)).unwrap();
+            items.into_iter().map(|item| item.title).collect()
+        };
+        assert_eq!(titles("attr.source:vinyl"), ["Iron Man"]);
+        assert_eq!(titles("attr.source:= title+"), ["Help!"]);
+        assert_eq!(titles("attr.mood:up"), ["Help!"]);
+        // Like any field, a missing attribute matches neither a term nor its
+        // negation
+        assert_eq!(titles("^attr.source:tape title+"), ["Iron Man", "War Pigs"]);
+        assert_eq!(db.count_items(Some("attr.source:")).unwrap(), 2);
+        let item = &db.query_items(Some("title:=\"War Pigs\"")).unwrap()[0];
+        assert_eq!(db.attribute(item, "source").unwrap().as_deref(), Some("cassette"));
+        // Evaluated in memory, a query finds the same attributes
+        let terms = crate::query::parse("attr.source:cassette").unwrap();
+        assert!(crate::query::matches_item(&terms, item, Some(&db)).unwrap());
+
+        assert!(db.delete_attribute(AttributeOwner::Item(war_pigs), "source").unwrap());
+        assert!(!db.delete_attribute(AttributeOwner::Item(war_pigs), "source").unwrap());
+        assert_eq!(titles("attr.source:vinyl title+"), ["Iron Man", "War Pigs"]);
+
+        // Attributes go with their item or album
+        db.remove_item(help).unwrap();
+        db.remove_album(album_id).unwrap();
+        let rows: i64 = db
+            .conn
+            .query_row(
+                "SELECT (SELECT COUNT(*) FROM item_attributes)
+                      + (SELECT COUNT(*) FROM album_attributes)",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(rows, 0);
+    }
+
+    #[test]
+    fn test_modify_stores_typed_values() {
+        let db = test_db(false);
+        insert_test_item(&db, "War Pigs", "Black Sabbath", "Metal");
+        let id = db.query_items(None).unwrap()[0].id.unwrap();
+
+        let changes: Vec<FieldChange> = ["year=1999", "track=3", "genre="]
+            .iter()
+            .map(|s| FieldChange::parse(s, "; ").unwrap())
+            .collect();
+        db.modify_item(id, &changes).unwrap();
+
+        let items = db.query_items(Some("year:1999..1999")).unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].genre, None);
+        let types: (String, String) = db
+            .conn
+            .query_row("SELECT typeof(year), typeof(track) FROM items", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!(types, ("integer".into(), "integer".into()));
+    }
+
+    #[test]
+    fn test_modify_appends_and_removes() {
+        let db = test_db(false);
+        insert_test_item(&db, "War Pigs", "Black Sabbath", "Metal");
+        let id = db.query_items(None).unwrap()[0].id.unwrap();
+        let modify = |changes: &[&str]| {
+            let changes: Vec<FieldChange> =
+                changes.iter().map(|s| FieldChange::parse(s, "; ").unwrap()).collect();
+            db.modify_item(id, &changes).map(|()| db.get_item(id).unwrap().unwrap())
+        };
+
+        let item = modify(&["genre+=Rock", "genre+=Doom", "title+=Luke's Wall"]).unwrap();
+        assert_eq!(item.genre.as_deref(), Some("Metal; Rock; Doom"));
+        assert_eq!(item.title, "War Pigs; Luke's Wall");
+        // Parts are found however they were spaced, and missing ones ignored
+        let item = modify(&["genre=Metal;Rock ;Doom", "genre-=Rock", "genre-=Jazz"]).unwrap();
+        assert_eq!(item.genre.as_deref(), Some("Metal; Doom"));
+        let item = modify(&["genre-=Metal", "genre-=Doom"]).unwrap();
+        assert_eq!(item.genre, None);
+        let item = modify(&["genre+=Rock"]).unwrap();
+        assert_eq!(item.genre.as_deref(), Some("Rock"));
+
+        // A title can't be emptied; nothing changes
+        assert!(modify(&["genre!", "title=Iron Man", "title-=Iron Man"]).is_err());
+        let item = db.get_item(id).unwrap().unwrap();
+        assert_eq!(item.title, "War Pigs; Luke's Wall");
+        assert_eq!(item.genre.as_deref(), Some("Rock"));
+    }
+
+    fn fts_trigger_count(db: &Database) -> u32 {
+        db.conn
+            .query_row(
+                "SELECT COUNT(*) FROM sqlite_master
+                 WHERE type = 'trigger' AND name LIKE 'items_a_'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn test_fts_disabled_searches_without_index() {
+        let db = test_db(true);
+        insert_test_item(&db, "War Pigs", "Black Sabbath", "Metal");
+        db.set_fts_mode(FtsMode::Disabled).unwrap();
+        assert!(!fts_table_exists(&db));
+        assert_eq!(fts_trigger_count(&db), 0);
+        assert_eq!(db.full_text_mode(), FullTextMode::Like);
+
+        insert_test_item(&db, "Paranoid", "Black Sabbath", "Metal");
+        assert_eq!(db.query_items(Some("sabbath")).unwrap().len(), 2);
+        assert!(db.rebuild_fts().is_err());
+        assert!(db.fts_drift().unwrap().is_none());
+
+        // Migrating again goes by the recorded mode
+        db.migrate().unwrap();
+        assert!(!fts_table_exists(&db));
+        db.set_fts_mode(FtsMode::Enabled).unwrap();
+        assert_eq!(fts_trigger_count(&db), 3);
+        assert_eq!(db.query_items(Some("paranoid")).unwrap().len(), 1);
+        assert!(db.fts_drift().unwrap().unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_fts_external_content_rebuild_waits_for_reindex() {
+        let db = test_db(true);
+        insert_test_item(&db, "War Pigs", "Black Sabbath", "Metal");
+        db.set_fts_mode(FtsMode::ExternalContentRebuild).unwrap();
+        db.migrate().unwrap();
+        assert!(fts_table_exists(&db));
+        assert_eq!(fts_trigger_count(&db), 0);
+        assert_eq!(db.full_text_mode(), FullTextMode::Fts5);
+
+        insert_test_item(&db, "Paranoid", "Black Sabbath", "Metal");
+        assert!(db.query_items(Some("paranoid")).unwrap().is_empty());
+        assert_eq!(db.fts_drift().unwrap().unwrap().unindexed, 1);
+        db.rebuild_fts().unwrap();
+        assert_eq!(db.query_items(Some("paranoid")).unwrap().len(), 1);
+        assert!(db.fts_drift().unwrap().unwrap().is_clean());
+
+        // Going back to triggers catches up on what was missed
+        insert_test_item(&db, "Iron Man", "Black Sabbath", "Metal");
+        db.set_fts_mode(FtsMode::Enabled).unwrap();
+        assert_eq!(db.query_items(Some("sabbath")).unwrap().len(), 3);
+        insert_test_item(&db, "Planet Caravan", "Black Sabbath", "Metal");
+        assert_eq!(db.query_items(Some("caravan")).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_fts_drift_found_and_rebuilt() {
+        let db = test_db(true);
+        insert_test_item(&db, "War Pigs", "Black Sabbath", "Metal");
+        insert_test_item(&db, "Paranoid", "Black Sabbath", "Metal");
+        assert!(db.integrity_problems().unwrap().is_empty());
+        assert!(db.fts_drift().unwrap().unwrap().is_clean());
+
+        // Take one item out of the index, and index one that doesn't exist
+        db.conn
+            .execute(
+                "INSERT INTO items_fts(items_fts, rowid, title, artist, album, albumartist, genre)
+                 SELECT 'delete', id, title, artist, album, albumartist, genre
+                 FROM items WHERE title = 'Paranoid'",
+                [],
+            )
+            .unwrap();
+        db.conn
+            .execute(
+                "INSERT INTO items_fts(rowid, title, artist, album, albumartist, genre)
+                 VALUES (999, 'Ghost', 'Nobody', 'Nothing', NULL, NULL)",
+                [],
+            )
+            .unwrap();
+        let drift = db.fts_drift().unwrap().unwrap();
+        assert_eq!((drift.unindexed, drift.stale), (1, 1));
+        assert!(!drift.is_clean());
+        assert!(db.query_items(Some("paranoid")).unwrap().is_empty());
+        // A check that can't run is an error, not a verdict on the index
+        db.conn.pragma_update(None, "query_only", true).unwrap();
+        let unchecked = db.fts_drift().is_err();
+        db.conn.pragma_update(None, "query_only", false).unwrap();
+        assert!(unchecked);
+
+        db.rebuild_fts().unwrap();
+        assert!(db.fts_drift().unwrap().unwrap().is_clean());
+        assert_eq!(db.query_items(Some("paranoid")).unwrap().len(), 1);
+        assert!(test_db(false).fts_drift().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_backup_and_vacuum() {
+        let db = test_db(true);
+        insert_test_item(&db, "War Pigs", "Black Sabbath", "Metal");
+        let path = std::env::temp_dir().join(format!("rsbts-backup-{}.db", std::process::id()));
+        db.backup_to(&path).unwrap();
+        db.vacuum().unwrap();
+
+        let copy = Database::open(&path).unwrap();
+        let items = copy.query_items(None).unwrap();
+        let titles: Vec<String> = items.into_iter().map(|i| i.title).collect();
+        let version = copy.migration_version().unwrap();
+        drop(copy);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(titles, ["War Pigs"]);
+        assert_eq!(version, crate::migrations::latest_version());
+    }
+
+    #[test]
+    fn test_album_references_enforced() {
+        let db = test_db(false);
+        insert_test_item(&db, "War Pigs", "Black Sabbath", "Metal");
+        let err = db
+            .conn
+            .execute("UPDATE items SET album_id = 42", [])
+            .unwrap_err();
+        assert!(err.to_string().contains("FOREIGN KEY"), "{err}");
+    }
+
+    #[test]
+    fn test_reader_and_writer_interleave() {
+        let path = std::env::temp_dir().join(format!("rsbts-wal-{}.db", std::process::id()));
+        let writer = Database::open(&path).unwrap();
+        writer.migrate().unwrap();
+        let reader = Database::open(&path).unwrap();
+        insert_test_item(&writer, "War Pigs", "Black Sabbath", "Metal");
+
+        // A reader in the middle of a transaction doesn't block the writer,
+        // and keeps seeing the snapshot it started with
+        let tx = reader.conn.unchecked_transaction().unwrap();
+        assert_eq!(reader.query_items(None).unwrap().len(), 1);
+        insert_test_item(&writer, "Paranoid", "Black Sabbath", "Metal");
+        assert_eq!(reader.query_items(None).unwrap().len(), 1);
+        tx.commit().unwrap();
+        assert_eq!(reader.query_items(None).unwrap().len(), 2);
+
+        let mode: String = reader
+            .conn
+            .query_row("PRAGMA journal_mode", [], |row| row.get(0))
+            .unwrap();
+        drop((reader, writer));
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{suffix}", path.display()));
+        }
+        assert_eq!(mode, "wal");
+    }
+
+    #[test]
+    fn test_ratings_and_plays() {
+        let db = test_db(false);
+        insert_test_item(&db, "War Pigs", "Black Sabbath", "Metal");
+        insert_test_item(&db, "Paranoid", "Black Sabbath", "Metal");
+        let ids: Vec<i64> = db.query_items(None).unwrap().iter().filter_map(|i| i.id).collect();
+
+        db.modify_item(ids[0], &[FieldChange::Set("rating=5".parse().unwrap())]).unwrap();
+        db.modify_item(ids[1], &[FieldChange::Set("rating=2".parse().unwrap())]).unwrap();
+        let played = DateTime::parse_from_rfc3339("2024-05-01T21:30:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        db.record_play(ids[1], played).unwrap();
+        db.record_play(ids[1], played).unwrap();
+
+        let titles = |query: &str| -> Vec<String> {
+            let items = db.query_items(Some(query)).unwrap();
+            items.into_iter().map(|i| i.title).collect()
+        };
+        assert_eq!(titles("rating:4.."), ["War Pigs"]);
+        assert_eq!(titles("play_count:1.."), ["Paranoid"]);
+        assert_eq!(titles("last_played:2024-05-01.."), ["Paranoid"]);
+
+        let item = db.get_item(ids[1]).unwrap().unwrap();
+        assert_eq!((item.rating, item.play_count), (Some(2), 2));
+        assert_eq!(item.last_played, Some(played));
+        let unplayed = db.get_item(ids[0]).unwrap().unwrap();
+        assert_eq!((unplayed.play_count, unplayed.last_played), (0, None));
+    }
+
+    #[test]
+    fn test_empty_and_null_text_query_alike() {
+        let db = test_db(false);
+        insert_test_item(&db, "War Pigs", "Black Sabbath", "Metal");
+        insert_test_item(&db, "Paranoid", "Black Sabbath", "");
+        insert_test_item(&db, "Iron Man", "Black Sabbath", "");
+        db.conn
+            .execute("UPDATE items SET genre = NULL WHERE title = 'Iron Man'", [])
+            .unwrap();
+
+        let titles = |query: &str| -> Vec<String> {
+            let items = db.query_items(Some(query)).unwrap();
+            items.into_iter().map(|i| i.title).collect()
+        };
+        assert_eq!(titles("genre:= title+"), ["Iron Man", "Paranoid"]);
+        assert_eq!(titles("^genre:="), ["War Pigs"]);
+        assert_eq!(titles("genre:"), ["War Pigs"]);
+        assert_eq!(titles("genre::.*"), ["War Pigs"]);
+        assert_eq!(titles("genre:..m"), ["War Pigs"]);
+        assert_eq!(titles("genre:=Metal"), ["War Pigs"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_paths_round_trip_as_bytes() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let db = test_db(false);
+        insert_test_item(&db, "War Pigs", "Black Sabbath", "Metal");
+        let latin1 = PathBuf::from(OsStr::from_bytes(b"/music/caf\xe9/01.mp3"));
+        let mut item = db.query_items(None).unwrap().remove(0);
+        item.id = None;
+        item.path.clone_from(&latin1);
+        item.source_path = Some(latin1.with_extension("flac"));
+        item.title = "Cafe".into();
+        db.insert_item(&item).unwrap();
+        let album_id = db
+            .insert_album(&Album {
+                album: "Paranoid".into(),
+                albumartist: "Black Sabbath".into(),
+                artpath: Some(latin1.with_file_name("cover.jpg")),
+                ..testutil::album()
+            })
+            .unwrap();
+
+        let stored = db.query_items(Some("title:cafe")).unwrap().remove(0);
+        assert_eq!(stored.path, latin1);
+        assert_eq!(stored.source_path, Some(latin1.with_extension("flac")));
+        assert!(db.item_exists(&latin1).unwrap());
+        assert!(db.imported_from(&latin1.with_extension("flac")).unwrap());
+        let album = db.get_album(album_id).unwrap().unwrap();
+        assert_eq!(album.artpath, Some(latin1.with_file_name("cover.jpg")));
+        let mut paths = Vec::new();
+        db.iter_paths(|_, path| paths.push(path)).unwrap();
+        assert!(paths.contains(&latin1));
+
+        // Valid UTF-8 stays text, so queries on paths work as before
+        let types: Vec<String> = db
+            .conn
+            .prepare("SELECT typeof(path) FROM items ORDER BY id")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(types, ["text", "blob"]);
+        assert_eq!(db.query_items(Some("path:=\"/War Pigs.mp3\"")).unwrap().len(), 1);
+        assert_eq!(db.query_items(Some("path:music/caf")).unwrap().len(), 1);
+        let removed = db.remove_item_at(&latin1).unwrap().unwrap();
+        assert_eq!(removed.path, latin1);
+        assert!(db.remove_item_at(&latin1).unwrap().is_none());
+        assert!(!db.item_exists(&latin1).unwrap());
+    }
+
+    #[test]
+    fn test_in_memory_matching_agrees_with_sql() {
+        let db = test_db(false);
+        let base = Item {
+            artist: "Black Sabbath".into(),
+            album: "Paranoid".into(),
+            format: AudioFormat::Flac,
+            bitrate: 900,
+            length: 180.0,
+            ..testutil::item()
+        };
+        let items = [
+            Item {
+                title: "War Pigs".into(),
+                genre: Some("Metal".into()),
+                year: Some(1970),
+                track: Some(1),
+                length: 474.5,
+                ..base.clone()
+            },
+            Item {
+                title: "100% Pure_Love".into(),
+                artist: "Crystal Waters".into(),
+                album: "Storyteller".into(),
+                albumartist: Some(String::new()),
+                genre: Some(String::new()),
+                year: Some(1994),
+                format: AudioFormat::Mp3,
+                bitrate: 320,
+                rg_track_gain: Some(-7.25),
+                ..base.clone()
+            },
+            Item {
+                title: "\u{c9}t\u{e9} Indien".into(),
+                artist: "Joe Dassin".into(),
+                album: "L'Album de sa vie".into(),
+                albumartist: Some("The Joe Dassin Band".into()),
+                genre: Some("chanson".into()),
+                year: Some(1975),
+                track: Some(12),
+                disc: Some(2),
+                samplerate: Some(44_100),
+                added: Utc::now() - chrono::Duration::days(400),
+                ..base.clone()
+            },
+            Item {
+                title: "Planet Caravan".into(),
+                genre: Some("metal".into()),
+                length: 272.0,
+                mb_trackid: Some("a1b2".into()),
+                ..base
+            },
+        ];
+        for (i, item) in items.into_iter().enumerate() {
+            db.insert_item(&Item {
+                path: format!("/music/{i}.flac").into(),
+                ..item
+            })
+            .unwrap();
+        }
+
+        let all = db.query_items(None).unwrap();
+        let queries = [
+            "sabbath",
+            "SABBATH paranoid",
+            "\"pure_love\"",
+            "~\"war pigs\"",
+            "\u{e9}t\u{e9}",
+            "title:100%",
+            "title:e_l",
+            "^title:e_l",
+            "album:'",
+            "genre:metal",
+            "genre:=metal",
+            "^genre:=Metal",
+            "genre:",
+            "^genre:",
+            "genre:=",
+            "^genre:=",
+            "albumartist:=",
+            "genre::^m",
+            "^genre::^m",
+            "genre::.*",
+            "^genre::x*",
+            "genre:..m",
+            "genre:c..",
+            "^genre:a..z",
+            "year:1970..1979",
+            "^year:1970..1979",
+            "year:..1974",
+            "year:=1994",
+            "year:19",
+            "track:2..",
+            "disc:=",
+            "^disc:=",
+            "length:180",
+            "length:=180",
+            "length:.5",
+            "length::^180\\.0$",
+            "length:200..300",
+            "rg_track_gain:..-7",
+            "samplerate:>=44100",
+            "format:mp3",
+            "^format:flac",
+            "added:-1w",
+            "^added:-1w",
+            "added:..2000",
+            "smart_artist:joe",
+            "smart_artist:=Joe Dassin Band",
+            "mb_trackid:a1",
+            "( genre:metal year:1970 ) war",
+            "year:=1970 bitrate:900",
+            "path:2",
+            "id:3",
+        ];
+        for query in queries {
+            let terms = crate::query::parse(query).unwrap();
+            let in_sql: Vec<i64> = db
+                .query_items(Some(query))
+                .unwrap()
+                .into_iter()
+                .filter_map(|item| item.id)
+                .collect();
+            let in_memory: Vec<i64> = all
+                .iter()
+                .filter(|item| crate::query::matches(&terms, item))
+                .filter_map(|item| item.id)
+                .collect();
+            assert_eq!(in_sql, in_memory, "{query}");
+        }
+    }
+
+    #[test]
+    fn test_format_date_and_art_queries() {
+        let db = test_db(false);
+        let album = |artpath: Option<&str>| {
+            db.insert_album(&Album {
+                album: "Paranoid".into(),
+                albumartist: "Black Sabbath".into(),
+                year: Some(1970),
+                artpath: artpath.map(PathBuf::from),
+                ..testutil::album()
+            })
+            .unwrap()
+        };
+        let (with_art, without_art) = (album(Some("/music/cover.jpg")), album(None));
+        for (title, format, added, album_id) in [
+            ("War Pigs", "FLAC", "2024-01-01T00:00:00Z", Some(with_art)),
+            ("Paranoid", "Ogg Vorbis", "2024-06-30T23:59:59Z", Some(with_art)),
+            ("Planet Caravan", "MP3", "2024-07-01T00:00:00Z", Some(without_art)),
+            ("Iron Man", "MP3", "2023-12-31T23:59:59Z", None),
+        ] {
+            insert_test_item(&db, title, "Black Sabbath", "Metal");
+            db.conn
+                .execute(
+                    "UPDATE items SET format = ?1, added = ?2, album_id = ?3 WHERE title = ?4",
+                    params![format, added, album_id, title],
+                )
+                .unwrap();
+        }
+
+        let titles = |query: &str| -> Vec<String> {
+            let items = db.query_items(Some(&format!("{query} title+"))).unwrap();
+            items.into_iter().map(|i| i.title).collect()
+        };
+        assert_eq!(titles("format:flac"), ["War Pigs"]);
+        assert_eq!(titles("format:OGG"), ["Paranoid"]);
+        assert_eq!(titles(r#"format:="ogg vorbis""#), ["Paranoid"]);
+        assert_eq!(titles("^format:mp3"), ["Paranoid", "War Pigs"]);
+        assert!(db.query_items(Some("format:midi")).is_err());
+        let ogg = db.query_items(Some("format:ogg")).unwrap();
+        assert_eq!(ogg[0].format, AudioFormat::Ogg);
+
+        assert_eq!(titles("added:2024-01-01..2024-06-30"), ["Paranoid", "War Pigs"]);
+        assert_eq!(titles("added:2024-06"), ["Paranoid"]);
+        assert_eq!(titles("added:2024"), ["Paranoid", "Planet Caravan", "War Pigs"]);
+        assert_eq!(titles("added:<=2023"), ["Iron Man"]);
+        assert_eq!(titles("added:>=2024-07"), ["Planet Caravan"]);
+        for invalid in ["added:2024-13", "added:2024-02-30", "added:june", "added:2024-1-1-1"] {
+            assert!(db.query_items(Some(invalid)).is_err(), "{invalid}");
+        }
+
+        assert_eq!(titles("^artpath:"), ["Iron Man", "Planet Caravan"]);
+        assert_eq!(titles("artpath:"), ["Paranoid", "War Pigs"]);
+        assert_eq!(titles("artpath:cover.jpg"), ["Paranoid", "War Pigs"]);
+    }
+
+    #[test]
+    fn test_random_items() {
+        let db = test_db(false);
+        for title in ["War Pigs", "Paranoid", "Iron Man", "Planet Caravan"] {
+            insert_test_item(&db, title, "Black Sabbath", "Metal");
+        }
+        insert_test_item(&db, "Tomorrow Never Knows", "The Beatles", "Rock");
+
+        let picked = db.random_items(Some("artist:sabbath limit:1"), 3).unwrap();
+        assert_eq!(picked.len(), 3);
+        assert!(picked.iter().all(|item| item.artist == "Black Sabbath"));
+        let mut titles: Vec<String> = picked.into_iter().map(|item| item.title).collect();
+        titles.sort();
+        titles.dedup();
+        assert_eq!(titles.len(), 3);
+        assert_eq!(db.random_items(None, 10).unwrap().len(), 5);
+    }
+
+    #[test]
+    fn test_path_mappings_and_rewrites() {
+        let mut db = test_db(false);
+        insert_test_item(&db, "War Pigs", "Black Sabbath", "Metal");
+        assert_eq!(db.rewrite_paths(Path::new("/"), Path::new("/mnt/music")).unwrap(), 1);
+        assert_eq!(db.rewrite_paths(Path::new("/srv"), Path::new("/mnt")).unwrap(), 0);
+
+        db.set_path_mappings(PathMappings::new([("/mnt/music".into(), "/Volumes/music".into())]));
+        let local = Path::new("/Volumes/music/War Pigs.mp3");
+        assert_eq!(db.query_items(None).unwrap()[0].path, local);
+        assert!(db.item_exists(local).unwrap());
+        // Queries name paths as this machine sees them
+        assert_eq!(db.query_items(Some("path:/Volumes/music/War")).unwrap().len(), 1);
+        let exact = r#"path:="/Volumes/music/War Pigs.mp3""#;
+        assert_eq!(db.count_items(Some(exact)).unwrap(), 1);
+
+        insert_test_item(&db, "Iron Man", "Black Sabbath", "Metal");
+        let mut item = db.query_items(Some("title:iron")).unwrap().remove(0);
+        item.id = None;
+        item.path = "/Volumes/music/Iron Man.mp3".into();
+        db.insert_item(&item).unwrap();
+        let stored: StoredPath = db
+            .conn
+            .query_row("SELECT path FROM items ORDER BY id DESC LIMIT 1", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(stored.0, Path::new("/mnt/music/Iron Man.mp3"));
+    }
+
+    #[test]
+    fn test_portable_databases_are_told_apart() {
+        let mut db = test_db(false);
+        db.check_portable(false).unwrap();
+        db.check_portable(true).unwrap();
+        let err = db.check_portable(false).unwrap_err().to_string();
+        assert!(err.contains("--library-root"), "{err}");
+
+        // Test items are at the filesystem root, here the library's
+        db.set_path_mappings(PathMappings::relative_to("/".into()));
+        insert_test_item(&db, "War Pigs", "Black Sabbath", "Metal");
+        let stored: StoredPath =
+            db.conn.query_row("SELECT path FROM items", [], |row| row.get(0)).unwrap();
+        assert_eq!(stored.0, Path::new("War Pigs.mp3"));
+        assert_eq!(db.query_items(None).unwrap()[0].path, Path::new("/War Pigs.mp3"));
+        db.check_portable(true).unwrap();
+
+        let absolute = test_db(false);
+        insert_test_item(&absolute, "Iron Man", "Black Sabbath", "Metal");
+        let err = absolute.check_portable(true).unwrap_err().to_string();
+        assert!(err.contains("absolute paths"), "{err}");
+        absolute.check_portable(false).unwrap();
+    }
+
+    #[test]
+    fn test_namesake_albums_land_in_distinct_directories() {
+        let db = test_db(false);
+        let album = |albumartist: &str, year| {
+            let id = db
+                .insert_album(&Album {
+                    album: "Greatest Hits".into(),
+                    albumartist: albumartist.into(),
+                    year: Some(year),
+                    ..testutil::album()
+                })
+                .unwrap();
+            let mut item = db.query_items(Some("title:=Bohemian")).unwrap().remove(0);
+            item.album_id = Some(id);
+            item.albumartist = Some(albumartist.into());
+            item.album = "Greatest Hits".into();
+            item
+        };
+        insert_test_item(&db, "Bohemian", "Queen", "Rock");
+        let first = album("Queen", 1981);
+        let formats = crate::pathformat::PathFormats::new("$albumartist/$album%aunique{}/$title");
+        let dest = |item: &Item| formats.destination(Path::new("/music"), item, Some(&db)).unwrap();
+        assert_eq!(dest(&first), Path::new("/music/Queen/Greatest Hits/Bohemian.mp3"));
+
+        let second = album("QUEEN", 2011);
+        let other = album("ABBA", 1992);
+        assert_eq!(db.album_namesakes(first.album_id.unwrap()).unwrap().len(), 2);
+        assert_eq!(dest(&first), Path::new("/music/Queen/Greatest Hits (1981)/Bohemian.mp3"));
+        assert_eq!(dest(&second), Path::new("/music/QUEEN/Greatest Hits (2011)/Bohemian.mp3"));
+        assert_eq!(dest(&other), Path::new("/music/ABBA/Greatest Hits/Bohemian.mp3"));
+    }
+
+    #[test]
+    fn test_every_format_round_trips() {
+        let db = test_db(false);
+        for format in AudioFormat::ALL {
+            for ext in format.extensions() {
+                assert_eq!(AudioFormat::from_extension(&ext.to_uppercase()), format);
+            }
+            insert_test_item(&db, format.as_str(), "Black Sabbath", "Metal");
+            db.conn
+                .execute(
+                    "UPDATE items SET format = ?1 WHERE title = ?1",
+                    params![format.as_str()],
+                )
+                .unwrap();
+            let query = format!(r#"title:="{}""#, format.as_str());
+            let stored = db.query_items(Some(&query)).unwrap();
+            assert_eq!(stored[0].format, format, "{}", format.as_str());
+            assert_eq!(AudioFormat::from_name(format.as_str()), Some(format));
+        }
+        assert_eq!(AudioFormat::from_extension("shn"), AudioFormat::Unknown);
+    }
+
+    #[test]
+    fn test_regex_queries() {
+        let db = test_db(false);
+        insert_test_item(&db, "War Pigs", "Black Sabbath", "Metal");
+        insert_test_item(&db, "Paranoid", "Black Sabbath", "Heavy Metal");
+        insert_test_item(&db, "Fire", "Jimi Hendrix", "Rock");
+        insert_test_item(&db, "\u{c1}guas de Mar\u{e7}o", "Elis Regina", "MPB");
+
+        let titles = |query: &str| -> Vec<String> {
+            let items = db.query_items(Some(&format!("{query} title+"))).unwrap();
+            items.into_iter().map(|i| i.title).collect()
+        };
+        assert_eq!(titles("genre::^(rock|metal)$"), ["Fire", "War Pigs"]);
+        assert_eq!(titles("genre::metal$"), ["Paranoid", "War Pigs"]);
+        assert!(titles("genre::(?-i:^metal)").is_empty());
+        assert_eq!(titles("genre::(?-i:^Metal)"), ["War Pigs"]);
+        assert_eq!(titles("^genre::metal"), ["Fire", "\u{c1}guas de Mar\u{e7}o"]);
+        // Case folding and classes cover more than ASCII
+        assert_eq!(titles("title::^\u{e1}guas\\s\\w+\\smar\u{c7}o$"), ["\u{c1}guas de Mar\u{e7}o"]);
+        assert!(titles("title::o'").is_empty());
+
+        let err = db.query_items(Some("genre::(rock")).unwrap_err();
+        assert!(err.to_string().contains("Invalid regex \"(rock\""), "{err}");
+    }
+
+    #[test]
+    fn test_full_text_words_phrases_and_near() {
+        let db = Database::open_in_memory().unwrap();
+        // Nothing to test on SQLite builds without FTS5
+        if !db.fts5 {
+            return;
+        }
+        db.migrate().unwrap();
+        insert_test_item(&db, "Blue Train", "John Coltrane", "Jazz");
+        insert_test_item(&db, "Train to the Blue Sea", "Somebody", "Pop");
+        insert_test_item(&db, "Blue Monk", "Thelonious Monk", "Jazz");
+
+        let titles = |query: &str| -> Vec<String> {
+            let items = db.query_items(Some(query)).unwrap();
+            items.into_iter().map(|i| i.title).collect()
+        };
+        assert_eq!(titles("blue train title+"), ["Blue Train", "Train to the Blue Sea"]);
+        assert_eq!(titles(r#""blue train""#), ["Blue Train"]);
+        assert_eq!(titles(r#"~"blue train" title+"#), ["Blue Train", "Train to the Blue Sea"]);
+        assert_eq!(titles("blue genre:jazz mon*"), ["Blue Monk"]);
+        // FTS5 operators and quotes are searched for, not interpreted
+        assert!(titles(r#"blue OR "a""b" NEAR("#).is_empty());
+    }
+
+    #[test]
+    fn test_set_replaygain() {
+        let db = test_db(false);
+        insert_test_item(&db, "War Pigs", "Black Sabbath", "Metal");
+        insert_test_item(&db, "Paranoid", "Black Sabbath", "Metal");
+        let id = db.query_items(Some("title:Paranoid")).unwrap()[0].id.unwrap();
+
+        let track = Gain {
+            gain: -8.5,
+            peak: 0.98,
+        };
+        db.set_replaygain(id, Some(track), None).unwrap();
+        let items = db.query_items(Some("rg_track_gain:..-5")).unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].rg_track_gain, Some(-8.5));
+        assert_eq!(items[0].rg_track_peak, Some(0.98));
+        assert_eq!(items[0].rg_album_gain, None);
+        assert_eq!(db.query_items(Some("rg_track_gain:=")).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_sort_keys_and_default_sort() {
+        let mut db = test_db(false);
+        for artist in ["ZZ Top", "The Beatles", "a-ha", "Cream", "ABBA"] {
+            insert_test_item(&db, artist, artist, "Rock");
+        }
+        db.conn
+            .execute("UPDATE items SET albumartist = 'The Beatles' WHERE artist = 'Cream'", [])
+            .unwrap();
+        let artists = |db: &Database, query: Option<&str>| -> Vec<String> {
+            let items = db.query_items(query).unwrap();
+            items.into_iter().map(|i| i.artist).collect()
+        };
+
+        assert_eq!(
+            artists(&db, Some("artist+")),
+            ["a-ha", "ABBA", "Cream", "The Beatles", "ZZ Top"]
+        );
+        assert_eq!(
+            artists(&db, Some("smart_artist+ title-")),
+            ["a-ha", "ABBA", "The Beatles", "Cream", "ZZ Top"]
+        );
+        assert_eq!(artists(&db, Some("smart_artist:beatles")), ["Cream", "The Beatles"]);
+        assert!(db.query_items(Some("bogus+")).is_err());
+
+        db.set_default_sort("smart_artist- title+").unwrap();
+        assert_eq!(artists(&db, None), ["ZZ Top", "Cream", "The Beatles", "ABBA", "a-ha"]);
+        assert_eq!(artists(&db, Some("rock year+")).len(), 5);
+        assert_eq!(artists(&db, Some("genre:rock"))[0], "ZZ Top");
+        for invalid in ["", "bogus+", "artist:x year+"] {
+            assert!(db.set_default_sort(invalid).is_err());
+        }
+    }
+
+    #[test]
+    fn test_pages_and_counts() {
+        let db = test_db(false);
+        for title in ["e", "B", "d", "a", "C"] {
+            insert_test_item(&db, title, "Artist", "Rock");
+        }
+        let titles = |query: &str, limit, offset| -> Vec<String> {
+            let items = db.query_items_page(Some(query), Page { limit, offset }).unwrap();
+            items.into_iter().map(|i| i.title).collect()
+        };
+        assert_eq!(titles("title+", Some(2), 0), ["a", "B"]);
+        assert_eq!(titles("title+", Some(2), 2), ["C", "d"]);
+        assert_eq!(titles("title+", Some(2), 4), ["e"]);
+        assert_eq!(titles("title- limit:2", None, 1), ["d", "C"]);
+        assert_eq!(titles("title- limit:2", Some(1), 0), ["e"]);
+        assert!(titles("title+", None, 5).is_empty());
+
+        let mut seen = Vec::new();
+        let err = db
+            .query_items_streamed(Some("title+"), Page::default(), |item| {
+                seen.push(item.title);
+                if seen.len() == 2 {
+                    return Err(Error::Query("enough".into()));
+                }
+                Ok(())
+            })
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Query error: enough");
+        assert_eq!(seen, ["a", "B"]);
+
+        assert_eq!(db.count_items(None).unwrap(), 5);
+        assert_eq!(db.count_items(Some("title:=a limit:0")).unwrap(), 1);
+        assert!(db.count_items(Some("bogus:x")).is_err());
+    }
+
+    #[test]
+    fn test_grouped_stats() {
+        let db = test_db(false);
+        for (title, artist, genre, year) in [
+            ("So What", "Miles Davis", "Jazz", Some(1959)),
+            ("Blue in Green", "Miles Davis", "Jazz", Some(1959)),
+            ("Naima", "John Coltrane", "Jazz", None),
+            ("Paranoid", "Black Sabbath", "Metal", Some(1970)),
+        ] {
+            insert_test_item(&db, title, artist, genre);
+            db.conn
+                .execute("UPDATE items SET year = ?1 WHERE title = ?2", params![year, title])
+                .unwrap();
+        }
+        let groups = |query: Option<&str>, group| -> Vec<(String, u64)> {
+            let groups = db.grouped_stats(query, group).unwrap();
+            groups.into_iter().map(|(name, stats)| (name, stats.tracks)).collect()
+        };
+
+        assert_eq!(
+            groups(None, StatsGroup::Year),
+            [("1959".into(), 2), ("1970".into(), 1), ("unknown".into(), 1)]
+        );
+        assert_eq!(
+            groups(Some("genre:jazz"), StatsGroup::Artist),
+            [("Miles Davis".into(), 2), ("John Coltrane".into(), 1)]
+        );
+        assert_eq!(groups(Some("genre:polka"), StatsGroup::Genre), []);
+        let jazz = db.grouped_stats(None, StatsGroup::Genre).unwrap().remove(0);
+        assert_eq!((jazz.0.as_str(), jazz.1.artists), ("Jazz", 2));
+        assert_eq!(db.query_stats("genre:jazz").unwrap().tracks, 3);
+        assert_eq!(db.stats().unwrap().artists, 3);
+        assert!("album".parse::<StatsGroup>().is_err());
+    }
 }
@@ -0,0 +1,481 @@
+//! Finding duplicate tracks and albums already in the library
+//!
+//! Tracks are the same recording if they share a `MusicBrainz` track ID, or,
+//! lacking one, the same normalized artist and title with lengths within
+//! [`LENGTH_TOLERANCE`] seconds. Albums match on `MusicBrainz` album ID or
+//! normalized album artist and title. Each group is ordered best copy first,
+//! tracks by a configurable [`Ranking`].
+
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Album, AudioFormat, Error, Item};
+
+/// Maximum length difference, in seconds, for tracks without an MBID to match.
+pub const LENGTH_TOLERANCE: f64 = 2.0;
+
+/// Ranking of audio formats, most preferred first.
+///
+/// Parsed from a comma-separated list such as `flac,*,mp3`, where `*` stands
+/// for every format not listed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FormatPreference {
+    /// `None` is the `*` wildcard.
+    order: Vec<Option<AudioFormat>>,
+}
+
+impl Default for FormatPreference {
+    /// FLAC, then anything else, then MP3.
+    fn default() -> Self {
+        Self {
+            order: vec![Some(AudioFormat::Flac), None, Some(AudioFormat::Mp3)],
+        }
+    }
+}
+
+impl FromStr for FormatPreference {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let order = s
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(|name| match name {
+                "*" => Ok(None),
+                _ => match AudioFormat::from_extension(name) {
+                    AudioFormat::Unknown => Err(Error::Config(format!(
+                        "Unknown format '{name}' in format preference"
+                    ))),
+                    format => Ok(Some(format)),
+                },
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self { order })
+    }
+}
+
+impl FormatPreference {
+    /// Position of `format` in the ranking; lower is better.
+    ///
+    /// Formats neither listed nor covered by `*` rank last.
+    #[must_use]
+    pub fn rank(&self, format: AudioFormat) -> usize {
+        self.order
+            .iter()
+            .position(|f| *f == Some(format))
+            .or_else(|| self.order.iter().position(Option::is_none))
+            .unwrap_or(self.order.len())
+    }
+
+}
+
+/// A reason to prefer one copy of a track over another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Criterion {
+    /// Better ranked in the format preference.
+    Format,
+    /// Lossless rather than lossy.
+    Lossless,
+    /// Higher bitrate.
+    Bitrate,
+    /// Has `ReplayGain` values.
+    HasRg,
+    /// On an album with cover art.
+    HasArt,
+    /// Added to the library earlier.
+    Oldest,
+}
+
+impl fmt::Display for Criterion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Format => "format",
+            Self::Lossless => "lossless",
+            Self::Bitrate => "bitrate",
+            Self::HasRg => "has_rg",
+            Self::HasArt => "has_art",
+            Self::Oldest => "oldest",
+        })
+    }
+}
+
+/// Criteria applied by default: the format preference, then bitrate.
+#[must_use]
+pub fn default_criteria() -> Vec<Criterion> {
+    vec![Criterion::Format, Criterion::Bitrate]
+}
+
+/// How to pick the best of several copies of a track: each criterion in
+/// turn, later ones only breaking ties of earlier ones.
+#[derive(Debug, Clone)]
+pub struct Ranking {
+    criteria: Vec<Criterion>,
+    formats: FormatPreference,
+    /// Albums that have cover art.
+    with_art: HashSet<i64>,
+}
+
+impl Default for Ranking {
+    fn default() -> Self {
+        Self::new(default_criteria(), FormatPreference::default())
+    }
+}
+
+impl Ranking {
+    #[must_use]
+    pub fn new(criteria: Vec<Criterion>, formats: FormatPreference) -> Self {
+        Self {
+            criteria,
+            formats,
+            with_art: HashSet::new(),
+        }
+    }
+
+    /// Set the albums that have cover art, for [`Criterion::HasArt`].
+    #[must_use]
+    pub fn with_art(mut self, albums: HashSet<i64>) -> Self {
+        self.with_art = albums;
+        self
+    }
+
+    /// Order two tracks best first, with the criterion that told them
+    /// apart; `None` if none did.
+    #[must_use]
+    pub fn compare(&self, a: &Item, b: &Item) -> (Ordering, Option<Criterion>) {
+        self.criteria
+            .iter()
+            .map(|&criterion| (self.compare_by(criterion, a, b), Some(criterion)))
+            .find(|(order, _)| order.is_ne())
+            .unwrap_or((Ordering::Equal, None))
+    }
+
+    fn compare_by(&self, criterion: Criterion, a: &Item, b: &Item) -> Ordering {
+        match criterion {
+            Criterion::Format => by_format(&self.formats, a, b),
+            Criterion::Lossless => by_lossless(a, b),
+            Criterion::Bitrate => by_bitrate(a, b),
+            Criterion::HasRg => by_replaygain(a, b),
+            Criterion::HasArt => by_art(&self.with_art, a, b),
+            Criterion::Oldest => by_age(a, b),
+        }
+    }
+}
+
+fn by_format(formats: &FormatPreference, a: &Item, b: &Item) -> Ordering {
+    formats.rank(a.format).cmp(&formats.rank(b.format))
+}
+
+fn by_lossless(a: &Item, b: &Item) -> Ordering {
+    b.format.is_lossless().cmp(&a.format.is_lossless())
+}
+
+fn by_bitrate(a: &Item, b: &Item) -> Ordering {
+    b.bitrate.cmp(&a.bitrate)
+}
+
+fn by_replaygain(a: &Item, b: &Item) -> Ordering {
+    b.rg_track_gain.is_some().cmp(&a.rg_track_gain.is_some())
+}
+
+fn by_art(with_art: &HashSet<i64>, a: &Item, b: &Item) -> Ordering {
+    let has_art = |item: &Item| item.album_id.is_some_and(|id| with_art.contains(&id));
+    has_art(b).cmp(&has_art(a))
+}
+
+fn by_age(a: &Item, b: &Item) -> Ordering {
+    a.added.cmp(&b.added)
+}
+
+/// An album together with its tracks.
+#[derive(Debug, Clone)]
+pub struct AlbumCopy {
+    pub album: Album,
+    pub items: Vec<Item>,
+}
+
+impl AlbumCopy {
+    /// Best format rank among the album's tracks.
+    fn best_rank(&self, prefs: &FormatPreference) -> usize {
+        self.items
+            .iter()
+            .map(|item| prefs.rank(item.format))
+            .min()
+            .unwrap_or(usize::MAX)
+    }
+
+    fn average_bitrate(&self) -> f64 {
+        if self.items.is_empty() {
+            return 0.0;
+        }
+        self.items.iter().map(|i| f64::from(i.bitrate)).sum::<f64>() / self.items.len() as f64
+    }
+}
+
+/// Group duplicate tracks, best copy first. Groups with one member are dropped.
+#[must_use]
+pub fn duplicate_items(items: Vec<Item>, ranking: &Ranking) -> Vec<Vec<Item>> {
+    let mut by_mbid: HashMap<String, Vec<Item>> = HashMap::new();
+    let mut by_name: HashMap<(String, String), Vec<Item>> = HashMap::new();
+    for item in items {
+        match item.mb_trackid.as_deref().filter(|id| !id.is_empty()) {
+            Some(id) => by_mbid.entry(id.to_string()).or_default().push(item),
+            None => by_name
+                .entry((normalize(&item.artist), normalize(&item.title)))
+                .or_default()
+                .push(item),
+        }
+    }
+
+    let mut groups: Vec<Vec<Item>> = by_mbid.into_values().collect();
+    for same_name in by_name.into_values() {
+        groups.extend(split_by_length(same_name));
+    }
+
+    let mut groups: Vec<Vec<Item>> = groups.into_iter().filter(|g| g.len() > 1).collect();
+    for group in &mut groups {
+        group.sort_by(|a, b| ranking.compare(a, b).0);
+    }
+    groups.sort_by(|a, b| {
+        (&a[0].artist, &a[0].title, &a[0].path).cmp(&(&b[0].artist, &b[0].title, &b[0].path))
+    });
+    groups
+}
+
+/// Group duplicate albums, best copy first. Groups with one member are dropped.
+///
+/// The best copy has the most preferred format, then the highest average
+/// bitrate, then the most tracks.
+#[must_use]
+pub fn duplicate_albums(
+    albums: Vec<AlbumCopy>,
+    prefs: &FormatPreference,
+) -> Vec<Vec<AlbumCopy>> {
+    let mut by_key: HashMap<String, Vec<AlbumCopy>> = HashMap::new();
+    for copy in albums {
+        let key = match copy.album.mb_albumid.as_deref().filter(|id| !id.is_empty()) {
+            Some(id) => format!("mbid:{id}"),
+            None => format!(
+                "name:{}\u{1f}{}",
+                normalize(&copy.album.albumartist),
+                normalize(&copy.album.album)
+            ),
+        };
+        by_key.entry(key).or_default().push(copy);
+    }
+
+    let mut groups: Vec<Vec<AlbumCopy>> =
+        by_key.into_values().filter(|g| g.len() > 1).collect();
+    for group in &mut groups {
+        group.sort_by(|a, b| {
+            a.best_rank(prefs)
+                .cmp(&b.best_rank(prefs))
+                .then_with(|| b.average_bitrate().total_cmp(&a.average_bitrate()))
+                .then_with(|| b.items.len().cmp(&a.items.len()))
+        });
+    }
+    groups.sort_by(|a, b| {
+        (&a[0].album.albumartist, &a[0].album.album, a[0].album.id)
+            .cmp(&(&b[0].album.albumartist, &b[0].album.album, b[0].album.id))
+    });
+    groups
+}
+
+/// Split tracks with the same artist and title into runs of similar length.
+///
+/// Each run spans at most [`LENGTH_TOLERANCE`] seconds from its shortest track.
+fn split_by_length(mut items: Vec<Item>) -> Vec<Vec<Item>> {
+    items.sort_by(|a, b| a.length.total_cmp(&b.length));
+
+    let mut runs: Vec<Vec<Item>> = Vec::new();
+    for item in items {
+        match runs.last_mut() {
+            Some(run) if item.length - run[0].length <= LENGTH_TOLERANCE => run.push(item),
+            _ => runs.push(vec![item]),
+        }
+    }
+    runs
+}
+
+/// Lowercase and collapse whitespace so trivially different tags compare equal.
+pub(crate) fn normalize(s: &str) -> String {
+    s.split_whitespace()
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    fn item(title: &str, format: AudioFormat, bitrate: u32, length: f64) -> Item {
+        Item {
+            path: format!("/{title}-{bitrate}.{}", format.as_str()).into(),
+            title: title.into(),
+            artist: "Black Sabbath".into(),
+            album: "Paranoid".into(),
+            format,
+            bitrate,
+            length,
+            ..testutil::item()
+        }
+    }
+
+    fn album(name: &str, mbid: Option<&str>, items: Vec<Item>) -> AlbumCopy {
+        AlbumCopy {
+            album: Album {
+                album: name.into(),
+                albumartist: "Black Sabbath".into(),
+                year: Some(1970),
+                mb_albumid: mbid.map(Into::into),
+                ..testutil::album()
+            },
+            items,
+        }
+    }
+
+    #[test]
+    fn test_groups_by_name_within_length_tolerance() {
+        let items = vec![
+            item("War Pigs", AudioFormat::Mp3, 320, 475.0),
+            item("war  pigs", AudioFormat::Flac, 900, 476.5),
+            // Same title but a different (live) recording
+            item("War Pigs", AudioFormat::Flac, 900, 520.0),
+            item("Iron Man", AudioFormat::Mp3, 192, 356.0),
+        ];
+
+        let groups = duplicate_items(items, &Ranking::default());
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].len(), 2);
+        // FLAC ranks first
+        assert_eq!(groups[0][0].format, AudioFormat::Flac);
+        assert!((groups[0][0].length - 476.5).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn test_mbid_groups_regardless_of_tags() {
+        let mut a = item("War Pigs", AudioFormat::Mp3, 320, 475.0);
+        let mut b = item("War Pigs / Luke's Wall", AudioFormat::Mp3, 128, 478.0);
+        a.mb_trackid = Some("rec-1".into());
+        b.mb_trackid = Some("rec-1".into());
+
+        let groups = duplicate_items(vec![b, a], &Ranking::default());
+        assert_eq!(groups.len(), 1);
+        // Same format, so higher bitrate wins
+        assert_eq!(groups[0][0].bitrate, 320);
+    }
+
+    #[test]
+    fn test_format_preference_override() {
+        let prefs: FormatPreference = "mp3,*".parse().unwrap();
+        assert!(prefs.rank(AudioFormat::Mp3) < prefs.rank(AudioFormat::Flac));
+        assert_eq!(prefs.rank(AudioFormat::Flac), prefs.rank(AudioFormat::Ogg));
+
+        let items = vec![
+            item("Paranoid", AudioFormat::Flac, 900, 168.0),
+            item("Paranoid", AudioFormat::Mp3, 320, 168.0),
+        ];
+        let groups = duplicate_items(items, &Ranking::new(default_criteria(), prefs));
+        assert_eq!(groups[0][0].format, AudioFormat::Mp3);
+
+        assert!("flac,xyz".parse::<FormatPreference>().is_err());
+    }
+
+    #[test]
+    fn test_each_criterion_decides_in_turn() {
+        use Criterion::{Bitrate, Format, HasArt, HasRg, Lossless, Oldest};
+
+        let criteria = vec![Lossless, Bitrate, HasRg, HasArt, Format, Oldest];
+        let ranking = Ranking::new(criteria, "ogg,*".parse().unwrap())
+            .with_art(HashSet::from([1]));
+        let mp3 = || {
+            let mut item = item("Paranoid", AudioFormat::Mp3, 320, 168.0);
+            item.added = "2020-01-01T00:00:00Z".parse().unwrap();
+            item
+        };
+        let decided_by = |better: &Item, worse: &Item| {
+            assert_eq!(ranking.compare(worse, better).0, Ordering::Greater);
+            let (order, criterion) = ranking.compare(better, worse);
+            assert_eq!(order, Ordering::Less);
+            criterion
+        };
+
+        let wav = Item {
+            format: AudioFormat::Wav,
+            ..mp3()
+        };
+        assert_eq!(decided_by(&wav, &mp3()), Some(Lossless));
+        let low = Item {
+            bitrate: 256,
+            ..mp3()
+        };
+        assert_eq!(decided_by(&mp3(), &low), Some(Bitrate));
+        let analyzed = Item {
+            rg_track_gain: Some(-7.0),
+            ..mp3()
+        };
+        assert_eq!(decided_by(&analyzed, &mp3()), Some(HasRg));
+        let (with_art, without_art) = (
+            Item {
+                album_id: Some(1),
+                ..mp3()
+            },
+            Item {
+                album_id: Some(2),
+                ..mp3()
+            },
+        );
+        assert_eq!(decided_by(&with_art, &without_art), Some(HasArt));
+        let ogg = Item {
+            format: AudioFormat::Ogg,
+            ..mp3()
+        };
+        assert_eq!(decided_by(&ogg, &mp3()), Some(Format));
+        let older = Item {
+            added: "2019-01-01T00:00:00Z".parse().unwrap(),
+            ..mp3()
+        };
+        assert_eq!(decided_by(&older, &mp3()), Some(Oldest));
+
+        assert_eq!(ranking.compare(&mp3(), &mp3()), (Ordering::Equal, None));
+    }
+
+    #[test]
+    fn test_configured_ranking_picks_keeper() {
+        let config: crate::config::DedupeConfig =
+            toml::from_str(r#"prefer = ["oldest", "lossless"]"#).unwrap();
+        let mut old_mp3 = item("Paranoid", AudioFormat::Mp3, 128, 168.0);
+        old_mp3.added = "2001-01-01T00:00:00Z".parse().unwrap();
+        let items = vec![item("Paranoid", AudioFormat::Flac, 900, 168.0), old_mp3];
+
+        let ranking = Ranking::new(config.prefer, FormatPreference::default());
+        let groups = duplicate_items(items, &ranking);
+        assert_eq!(groups[0][0].format, AudioFormat::Mp3);
+
+        let invalid = toml::from_str::<crate::config::DedupeConfig>(r#"prefer = ["shiny"]"#);
+        assert!(invalid.is_err());
+    }
+
+    #[test]
+    fn test_duplicate_albums() {
+        let albums = vec![
+            album("Paranoid", None, vec![item("Paranoid", AudioFormat::Mp3, 320, 168.0)]),
+            album("PARANOID", None, vec![item("Paranoid", AudioFormat::Flac, 900, 168.0)]),
+            album("Master of Reality", Some("mor"), Vec::new()),
+            album("Master of Reality (Deluxe)", Some("mor"), Vec::new()),
+            album("Vol. 4", None, Vec::new()),
+        ];
+
+        let groups = duplicate_albums(albums, &FormatPreference::default());
+        assert_eq!(groups.len(), 2);
+        let paranoid = groups.iter().find(|g| g.len() == 2 && !g[0].items.is_empty()).unwrap();
+        assert_eq!(paranoid[0].album.album, "PARANOID");
+    }
+}
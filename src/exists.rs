@@ -0,0 +1,149 @@
+//! Parallel existence checks for stored paths
+//!
+//! `ls --missing`, `stats --verify` and `check` all need to know which of
+//! many thousands of stored paths are gone. [`ExistenceCheck`] stats them on
+//! a bounded rayon pool, optionally giving up on paths that take too long to
+//! stat (e.g. on a stalled network mount) and treating those as present.
+
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::Duration;
+
+use rayon::prelude::*;
+
+use crate::config::LibraryConfig;
+use crate::{Error, Result};
+
+/// Default number of threads statting files at once.
+pub const DEFAULT_STAT_THREADS: usize = 16;
+
+/// Settings for checking which paths still exist.
+#[derive(Debug, Clone, Copy)]
+pub struct ExistenceCheck {
+    threads: usize,
+    stat_timeout: Option<Duration>,
+}
+
+impl Default for ExistenceCheck {
+    fn default() -> Self {
+        Self::new(DEFAULT_STAT_THREADS, None)
+    }
+}
+
+impl ExistenceCheck {
+    /// Check with up to `threads` concurrent stats. Paths whose stat takes
+    /// longer than `stat_timeout` are assumed to exist.
+    #[must_use]
+    pub const fn new(threads: usize, stat_timeout: Option<Duration>) -> Self {
+        Self {
+            threads,
+            stat_timeout,
+        }
+    }
+
+    #[must_use]
+    pub fn from_config(config: &LibraryConfig) -> Self {
+        Self::new(
+            DEFAULT_STAT_THREADS,
+            config.stat_timeout_ms.map(Duration::from_millis),
+        )
+    }
+
+    /// Ids of the paths that no longer exist, in input order.
+    ///
+    /// `on_checked` is called once per path as it is checked, from worker
+    /// threads.
+    ///
+    /// # Errors
+    /// Returns an error if the thread pool cannot be created.
+    pub fn missing<F: Fn() + Sync>(
+        &self,
+        paths: Vec<(i64, PathBuf)>,
+        on_checked: F,
+    ) -> Result<Vec<i64>> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.threads.max(1))
+            .build()
+            .map_err(|e| Error::Io(std::io::Error::other(e)))?;
+
+        Ok(pool.install(|| {
+            paths
+                .into_par_iter()
+                .filter_map(|(id, path)| {
+                    let exists = self.exists(&path);
+                    on_checked();
+                    (!exists).then_some(id)
+                })
+                .collect()
+        }))
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        let Some(timeout) = self.stat_timeout else {
+            return exists_now(path);
+        };
+
+        // A stat stuck on a hung mount can't be cancelled; leave its thread
+        // behind and assume the file is there
+        let (tx, rx) = mpsc::channel();
+        let owned = path.to_path_buf();
+        std::thread::spawn(move || {
+            let _ = tx.send(exists_now(&owned));
+        });
+        rx.recv_timeout(timeout).unwrap_or(true)
+    }
+}
+
+/// Only a definite "not found" counts as missing; permission and other
+/// transient errors don't.
+fn exists_now(path: &Path) -> bool {
+    !matches!(std::fs::metadata(path), Err(e) if e.kind() == std::io::ErrorKind::NotFound)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Database;
+    use crate::tags::read_tags;
+    use crate::testutil::wav_bytes;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_deleted_files_are_missing_for_every_consumer() {
+        let dir = std::env::temp_dir().join(format!("rsbts-exists-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let db = Database::open_in_memory().unwrap();
+        db.migrate().unwrap();
+
+        let mut ids = Vec::new();
+        for i in 0..6 {
+            let path = dir.join(format!("{i}.wav"));
+            std::fs::write(&path, wav_bytes(800)).unwrap();
+            ids.push(db.insert_item(&read_tags(&path).unwrap()).unwrap());
+        }
+        std::fs::remove_file(dir.join("1.wav")).unwrap();
+        std::fs::remove_file(dir.join("4.wav")).unwrap();
+        let expected = vec![ids[1], ids[4]];
+
+        // ls --missing and stats --verify
+        let checked = AtomicUsize::new(0);
+        let check = ExistenceCheck::new(4, Some(Duration::from_secs(5)));
+        let mut missing = db
+            .missing_item_ids(&check, || {
+                checked.fetch_add(1, Ordering::Relaxed);
+            })
+            .unwrap();
+        missing.sort_unstable();
+        assert_eq!(missing, expected);
+        assert_eq!(checked.load(Ordering::Relaxed), 6);
+
+        // check
+        let items = db.query_items(None).unwrap();
+        let report = crate::check::check_library(items, &dir, &[], &check).unwrap();
+        let mut missing: Vec<i64> = report.missing.iter().filter_map(|i| i.id).collect();
+        missing.sort_unstable();
+        assert_eq!(missing, expected);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
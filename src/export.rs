@@ -0,0 +1,262 @@
+//! Exporting items and albums for external tools
+//!
+//! Columns and their names come from the field registry, so an export uses
+//! the same names as queries and `modify`. CSV cells hold the values as the
+//! database stores them; JSON objects are the serde form of [`Item`] and
+//! [`Album`] with their keys in column order.
+//!
+//! [`Item`]: crate::Item
+//! [`Album`]: crate::Album
+
+use std::io::Write;
+use std::str::FromStr;
+
+use serde::Serialize;
+
+use crate::fields::{Field, Value};
+use crate::{Error, Result};
+
+/// Output format of an export.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// Header row, then one record per row.
+    Csv,
+    /// One array of objects.
+    Json,
+    /// One object per line.
+    Jsonl,
+}
+
+impl FromStr for ExportFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "csv" => Ok(Self::Csv),
+            "json" => Ok(Self::Json),
+            "jsonl" => Ok(Self::Jsonl),
+            _ => Err(Error::Config(format!(
+                "Unknown export format '{s}' (expected csv, json or jsonl)"
+            ))),
+        }
+    }
+}
+
+/// The columns of `fields` named in the comma-separated `selection`, in that
+/// order, or all of them if there is no selection.
+///
+/// # Errors
+/// Returns an error if a name isn't one of `fields` or nothing is selected.
+pub fn select_fields(
+    fields: &'static [Field],
+    selection: Option<&str>,
+) -> Result<Vec<&'static Field>> {
+    let Some(selection) = selection else {
+        return Ok(fields.iter().collect());
+    };
+    let selected = selection
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            fields.iter().find(|f| f.name == name).ok_or_else(|| {
+                let names: Vec<&str> = fields.iter().map(|f| f.name).collect();
+                Error::Query(format!(
+                    "Invalid field: {name} (valid fields: {})",
+                    names.join(", ")
+                ))
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if selected.is_empty() {
+        return Err(Error::Query("No fields selected".into()));
+    }
+    Ok(selected)
+}
+
+/// Columns holding file paths, which [`strip_paths`] leaves out.
+const PATH_COLUMNS: &[&str] = &["path", "source_path", "artpath"];
+
+/// `fields` without the columns holding file paths, for an export that
+/// shouldn't show where the library or its sources are.
+///
+/// # Errors
+/// Returns an error if only paths were selected.
+pub fn strip_paths(mut fields: Vec<&'static Field>) -> Result<Vec<&'static Field>> {
+    fields.retain(|field| !PATH_COLUMNS.contains(&field.name));
+    if fields.is_empty() {
+        return Err(Error::Query("No fields selected besides paths".into()));
+    }
+    Ok(fields)
+}
+
+/// Write `rows` to `out` with the columns in `fields`.
+///
+/// `value` gives a row's stored value for a column, which CSV uses; JSON
+/// serializes the rows themselves. An empty CSV export is just the header.
+///
+/// # Errors
+/// Returns an error if writing to `out` fails.
+pub fn write<T: Serialize>(
+    out: &mut impl Write,
+    format: ExportFormat,
+    fields: &[&Field],
+    rows: &[T],
+    value: impl Fn(&T, &str) -> Value,
+) -> Result<()> {
+    match format {
+        ExportFormat::Csv => {
+            write_csv_record(out, fields.iter().map(|f| f.name))?;
+            for row in rows {
+                write_csv_record(out, fields.iter().map(|f| value(row, f.name).to_string()))?;
+            }
+        }
+        ExportFormat::Json => {
+            write!(out, "[")?;
+            for (i, row) in rows.iter().enumerate() {
+                write!(out, "{}\n  ", if i == 0 { "" } else { "," })?;
+                write_json_object(out, fields, row)?;
+            }
+            writeln!(out, "{}]", if rows.is_empty() { "" } else { "\n" })?;
+        }
+        ExportFormat::Jsonl => {
+            for row in rows {
+                write_json_object(out, fields, row)?;
+                writeln!(out)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// One CSV line, quoting cells that contain separators, quotes or newlines.
+fn write_csv_record<S: AsRef<str>>(
+    out: &mut impl Write,
+    cells: impl IntoIterator<Item = S>,
+) -> Result<()> {
+    for (i, cell) in cells.into_iter().enumerate() {
+        if i > 0 {
+            write!(out, ",")?;
+        }
+        let cell = cell.as_ref();
+        if cell.contains([',', '"', '\n', '\r']) {
+            write!(out, "\"{}\"", cell.replace('"', "\"\""))?;
+        } else {
+            write!(out, "{cell}")?;
+        }
+    }
+    writeln!(out)?;
+    Ok(())
+}
+
+/// `row` as a compact JSON object holding only `fields`, in their order.
+fn write_json_object<T: Serialize>(out: &mut impl Write, fields: &[&Field], row: &T) -> Result<()> {
+    let json = serde_json::to_value(row).map_err(std::io::Error::from)?;
+    write!(out, "{{")?;
+    for (i, field) in fields.iter().enumerate() {
+        let value = json.get(field.name).cloned().unwrap_or_default();
+        let key = serde_json::Value::from(field.name);
+        write!(out, "{}{key}:{value}", if i == 0 { "" } else { "," })?;
+    }
+    write!(out, "}}")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fields::{album_value, item_value, ALBUM_COLUMNS, ITEM_FIELDS};
+    use crate::testutil;
+    use crate::{Album, AudioFormat, Item};
+    use chrono::{TimeZone, Utc};
+
+    fn item(title: &str) -> Item {
+        let added = Utc.with_ymd_and_hms(2024, 3, 9, 12, 0, 0).unwrap();
+        Item {
+            id: Some(7),
+            path: "/music/a.flac".into(),
+            title: title.into(),
+            artist: "Black Sabbath".into(),
+            album: "Paranoid".into(),
+            year: Some(1970),
+            track: Some(1),
+            format: AudioFormat::Flac,
+            bitrate: 900,
+            length: 475.5,
+            added,
+            mtime: added,
+            ..testutil::item()
+        }
+    }
+
+    fn export(format: ExportFormat, selection: Option<&str>, rows: &[Item]) -> String {
+        let fields = select_fields(ITEM_FIELDS, selection).unwrap();
+        let mut out = Vec::new();
+        write(&mut out, format, &fields, rows, item_value).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_csv_quoting_and_empty_export() {
+        let rows = [item("War Pigs"), item("Hand of Doom, \"Live\"")];
+        assert_eq!(
+            export(ExportFormat::Csv, Some("title,year,genre"), &rows),
+            "title,year,genre\nWar Pigs,1970,\n\"Hand of Doom, \"\"Live\"\"\",1970,\n"
+        );
+
+        let all = export(ExportFormat::Csv, None, &[]);
+        assert_eq!(all.lines().count(), 1);
+        assert!(all.starts_with("id,album_id,path,title,"));
+    }
+
+    #[test]
+    fn test_json_keeps_field_order() {
+        let rows = [item("War Pigs"), item("Paranoid")];
+        let jsonl = export(ExportFormat::Jsonl, Some("year,title,genre"), &rows);
+        assert_eq!(
+            jsonl.lines().next(),
+            Some(r#"{"year":1970,"title":"War Pigs","genre":null}"#)
+        );
+
+        let json = export(ExportFormat::Json, None, &rows);
+        let parsed: Vec<Item> = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed[1].title, "Paranoid");
+        assert_eq!(export(ExportFormat::Json, None, &[]), "[]\n");
+    }
+
+    #[test]
+    fn test_strip_paths() {
+        let items = [Item {
+            source_path: Some("/incoming/a.flac".into()),
+            ..item("War Pigs")
+        }];
+        let albums = [Album {
+            album: "Paranoid".into(),
+            artpath: Some("/music/Paranoid/cover.jpg".into()),
+            source_path: Some("/incoming".into()),
+            ..testutil::album()
+        }];
+        for format in [ExportFormat::Csv, ExportFormat::Json, ExportFormat::Jsonl] {
+            let fields = strip_paths(select_fields(ITEM_FIELDS, None).unwrap()).unwrap();
+            let mut out = Vec::new();
+            write(&mut out, format, &fields, &items, item_value).unwrap();
+            let fields = strip_paths(select_fields(ALBUM_COLUMNS, None).unwrap()).unwrap();
+            write(&mut out, format, &fields, &albums, album_value).unwrap();
+            let out = String::from_utf8(out).unwrap();
+            assert!(out.contains("War Pigs") && out.contains("Paranoid"));
+            assert!(!out.contains("/music") && !out.contains("/incoming"));
+            assert!(!out.contains("path"), "{out}");
+        }
+
+        let only_paths = select_fields(ITEM_FIELDS, Some("path,source_path")).unwrap();
+        assert!(strip_paths(only_paths).is_err());
+    }
+
+    #[test]
+    fn test_select_unknown_field() {
+        let err = select_fields(ITEM_FIELDS, Some("title,bogus")).unwrap_err();
+        assert!(err.to_string().contains("Invalid field: bogus"));
+        assert!(select_fields(ITEM_FIELDS, Some(",")).is_err());
+        assert!("xml".parse::<ExportFormat>().is_err());
+    }
+}
@@ -0,0 +1,150 @@
+//! External subcommands
+//!
+//! Like git and cargo, `rsbts foo args...` runs an executable named
+//! `rsbts-foo` from `PATH` when `foo` isn't a built-in command. The library
+//! settings are passed in the environment so the tool can work on the same
+//! library:
+//!
+//! - `RSBTS_DB`: database file
+//! - `RSBTS_LIBRARY_DIR`: library directory
+//! - `RSBTS_CONFIG`: config file, if one was read
+
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::config::Config;
+
+/// File name prefix of external subcommands.
+pub const PREFIX: &str = "rsbts-";
+
+/// Path of the `rsbts-<name>` executable in `search_path` (a `PATH`-style
+/// list), taking the first match like a shell would.
+#[must_use]
+pub fn find(name: &str, search_path: &OsStr) -> Option<PathBuf> {
+    let file_name = format!("{PREFIX}{name}{}", std::env::consts::EXE_SUFFIX);
+    std::env::split_paths(search_path)
+        .map(|dir| dir.join(&file_name))
+        .find(|path| is_executable(path))
+}
+
+/// Names (without the prefix) and paths of all external subcommands in
+/// `search_path`, sorted by name. Earlier directories shadow later ones.
+#[must_use]
+pub fn discover(search_path: &OsStr) -> BTreeMap<String, PathBuf> {
+    let mut found = BTreeMap::new();
+    for dir in std::env::split_paths(search_path) {
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        for path in entries.filter_map(|entry| entry.ok().map(|e| e.path())) {
+            let Some(name) = path
+                .file_name()
+                .and_then(OsStr::to_str)
+                .and_then(|n| n.strip_prefix(PREFIX))
+                .map(|n| n.strip_suffix(std::env::consts::EXE_SUFFIX).unwrap_or(n))
+            else {
+                continue;
+            };
+            if !name.is_empty() && is_executable(&path) {
+                found.entry(name.to_string()).or_insert(path);
+            }
+        }
+    }
+    found
+}
+
+/// Command running `program` with `args` and the library settings from
+/// `config` in its environment.
+#[must_use]
+pub fn command(
+    program: &Path,
+    args: &[OsString],
+    config: &Config,
+    config_path: Option<&Path>,
+) -> Command {
+    let mut command = Command::new(program);
+    command
+        .args(args)
+        .env("RSBTS_DB", &config.library.database)
+        .env("RSBTS_LIBRARY_DIR", &config.library.directory);
+    if let Some(path) = config_path.filter(|p| p.exists()) {
+        command.env("RSBTS_CONFIG", path);
+    }
+    command
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    path.metadata()
+        .is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    /// A temp dir holding an `rsbts-sync` script that records its arguments
+    /// and environment, prepended to the real `PATH`.
+    fn stub_dir() -> (PathBuf, OsString) {
+        let dir = std::env::temp_dir().join(format!("rsbts-external-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let script = dir.join("rsbts-sync");
+        std::fs::write(
+            &script,
+            "#!/bin/sh\n\
+             printf '%s\\n' \"$*\" \"$RSBTS_DB\" \"$RSBTS_LIBRARY_DIR\" \"$RSBTS_CONFIG\" \
+             > \"$(dirname \"$0\")/out\"\n\
+             exit 7\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        // Not executable, so not a command
+        std::fs::write(dir.join("rsbts-notes"), "").unwrap();
+
+        let mut paths = vec![dir.clone()];
+        paths.extend(std::env::split_paths(&std::env::var_os("PATH").unwrap_or_default()));
+        (dir, std::env::join_paths(paths).unwrap())
+    }
+
+    #[test]
+    fn test_find_and_run_external_command() {
+        let (dir, search_path) = stub_dir();
+        let mut config = Config::default();
+        config.library.database = dir.join("library.db");
+        config.library.directory = dir.join("music");
+        let config_path = dir.join("config.toml");
+        std::fs::write(&config_path, "").unwrap();
+
+        let program = find("sync", &search_path).unwrap();
+        let args = [OsString::from("--dry-run"), OsString::from("phone")];
+        let status = command(&program, &args, &config, Some(&config_path))
+            .status()
+            .unwrap();
+        let out = std::fs::read_to_string(dir.join("out")).unwrap();
+
+        let discovered = discover(&search_path);
+        assert!(find("notes", &search_path).is_none());
+        assert!(find("nonexistent-command", &search_path).is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(status.code(), Some(7));
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines[0], "--dry-run phone");
+        assert_eq!(Path::new(lines[1]), config.library.database);
+        assert_eq!(Path::new(lines[2]), config.library.directory);
+        assert_eq!(Path::new(lines[3]), config_path);
+        assert_eq!(discovered.get("sync"), Some(&program));
+        assert!(!discovered.contains_key("notes"));
+    }
+}
@@ -0,0 +1,808 @@
+//! Item field registry
+//!
+//! The columns of the `items` table with their types, shared by the query
+//! parser (which only accepts known fields) and `modify` (which coerces each
+//! value to the column's type before storing it).
+
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use chrono::{DateTime, NaiveDate, Utc};
+use rusqlite::types::{ToSql, ToSqlOutput};
+use serde::Serialize;
+
+use crate::db::timestamp;
+use crate::{Album, Error, Item, Result};
+
+/// Type of an item field's values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldType {
+    String,
+    Int,
+    Float,
+    /// RFC 3339 timestamp; `YYYY-MM-DD` is accepted as midnight UTC.
+    Date,
+    Bool,
+}
+
+impl FieldType {
+    /// The type's short name, as `rsbts fields` shows it.
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::String => "string",
+            Self::Int => "int",
+            Self::Float => "float",
+            Self::Date => "date",
+            Self::Bool => "bool",
+        }
+    }
+}
+
+impl fmt::Display for FieldType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::String => "text",
+            Self::Int => "an integer",
+            Self::Float => "a number",
+            Self::Date => "a date (YYYY-MM-DD)",
+            Self::Bool => "true or false",
+        })
+    }
+}
+
+/// An item column.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct Field {
+    pub name: &'static str,
+    #[serde(rename = "type")]
+    pub ty: FieldType,
+    /// Whether the column may be NULL, i.e. cleared with `field=` or `field!`.
+    pub nullable: bool,
+    /// Whether the field is also stored in file tags, which `modify --write`
+    /// updates.
+    pub tag: bool,
+    /// Whether `modify` may change the field: the tag fields, and the
+    /// listening statistics kept only in the library.
+    pub editable: bool,
+}
+
+const fn field(name: &'static str, ty: FieldType, nullable: bool, tag: bool) -> Field {
+    Field {
+        name,
+        ty,
+        nullable,
+        tag,
+        editable: tag,
+    }
+}
+
+/// A field kept only in the library that `modify` may still change.
+const fn stat(name: &'static str, ty: FieldType, nullable: bool) -> Field {
+    Field {
+        name,
+        ty,
+        nullable,
+        tag: false,
+        editable: true,
+    }
+}
+
+/// The highest rating; ratings go from 0 to this.
+pub const MAX_RATING: u8 = 5;
+
+/// Every column of the `items` table.
+pub const ITEM_FIELDS: &[Field] = &[
+    field("id", FieldType::Int, false, false),
+    field("album_id", FieldType::Int, true, false),
+    field("path", FieldType::String, false, false),
+    field("title", FieldType::String, false, true),
+    field("artist", FieldType::String, false, true),
+    field("album", FieldType::String, false, true),
+    field("albumartist", FieldType::String, true, true),
+    field("artist_sort", FieldType::String, true, true),
+    field("albumartist_sort", FieldType::String, true, true),
+    field("genre", FieldType::String, true, true),
+    field("year", FieldType::Int, true, true),
+    field("track", FieldType::Int, true, true),
+    field("disc", FieldType::Int, true, true),
+    field("format", FieldType::String, false, false),
+    field("bitrate", FieldType::Int, false, false),
+    field("length", FieldType::Float, false, false),
+    field("samplerate", FieldType::Int, true, false),
+    field("channels", FieldType::Int, true, false),
+    field("bitdepth", FieldType::Int, true, false),
+    field("mb_trackid", FieldType::String, true, true),
+    field("mb_albumid", FieldType::String, true, true),
+    field("mb_releasegroupid", FieldType::String, true, true),
+    field("added", FieldType::Date, false, false),
+    field("mtime", FieldType::Date, false, false),
+    field("size", FieldType::Int, true, false),
+    field("source_path", FieldType::String, true, false),
+    field("import_run", FieldType::String, true, false),
+    field("rg_track_gain", FieldType::Float, true, false),
+    field("rg_track_peak", FieldType::Float, true, false),
+    field("rg_album_gain", FieldType::Float, true, false),
+    field("rg_album_peak", FieldType::Float, true, false),
+    stat("rating", FieldType::Int, true),
+    stat("play_count", FieldType::Int, false),
+    stat("last_played", FieldType::Date, true),
+];
+
+/// Columns of the `albums` table that `modify --album` can change. Each is
+/// also an item column of the same name, which the change cascades to.
+pub const ALBUM_FIELDS: &[Field] = &[
+    field("album", FieldType::String, false, true),
+    field("albumartist", FieldType::String, false, true),
+    field("year", FieldType::Int, true, true),
+    field("mb_albumid", FieldType::String, true, true),
+    field("mb_releasegroupid", FieldType::String, true, true),
+];
+
+/// Every column of the `albums` table.
+pub const ALBUM_COLUMNS: &[Field] = &[
+    field("id", FieldType::Int, false, false),
+    field("album", FieldType::String, false, true),
+    field("albumartist", FieldType::String, false, true),
+    field("year", FieldType::Int, true, true),
+    field("artpath", FieldType::String, true, false),
+    field("mb_albumid", FieldType::String, true, true),
+    field("mb_releasegroupid", FieldType::String, true, true),
+    field("added", FieldType::Date, false, false),
+    field("source_path", FieldType::String, true, false),
+    field("import_run", FieldType::String, true, false),
+    field("disambiguation", FieldType::String, true, false),
+];
+
+/// Fields a query can test that aren't item columns, with the SQL giving an
+/// item's value.
+pub const VIRTUAL_FIELDS: &[(Field, &str)] = &[
+    (
+        // The cover art of the item's album
+        field("artpath", FieldType::String, true, false),
+        "(SELECT artpath FROM albums WHERE albums.id = items.album_id)",
+    ),
+    (
+        // The album artist (or artist) as it's filed: by its sort name if
+        // there is one, else with "The Beatles" under B
+        field("smart_artist", FieldType::String, false, false),
+        "sort_name(COALESCE(NULLIF(albumartist_sort, ''), NULLIF(albumartist, ''), \
+         NULLIF(artist_sort, ''), artist))",
+    ),
+];
+
+/// `name` as it's filed for sorting, without a leading "The", "A" or "An".
+/// A name that is only the article keeps it.
+#[must_use]
+pub fn sort_name(name: &str) -> &str {
+    for article in ["the ", "a ", "an "] {
+        let Some(prefix) = name.get(..article.len()) else {
+            continue;
+        };
+        let rest = name[article.len()..].trim_start();
+        if prefix.eq_ignore_ascii_case(article) && !rest.is_empty() {
+            return rest;
+        }
+    }
+    name
+}
+
+/// Every field a query can test: the item columns, then [`VIRTUAL_FIELDS`].
+pub fn query_fields() -> impl Iterator<Item = &'static Field> {
+    ITEM_FIELDS
+        .iter()
+        .chain(VIRTUAL_FIELDS.iter().map(|(field, _)| field))
+}
+
+/// Look up an item field by name.
+#[must_use]
+pub fn item_field(name: &str) -> Option<&'static Field> {
+    ITEM_FIELDS.iter().find(|f| f.name == name)
+}
+
+/// Names of the fields in `fields` that `modify` can change, comma-separated
+/// for messages.
+#[must_use]
+pub fn tag_field_names(fields: &[Field]) -> String {
+    let names: Vec<&str> = fields.iter().filter(|f| f.editable).map(|f| f.name).collect();
+    names.join(", ")
+}
+
+/// The value `item` has for the field `name`, as the database would store
+/// it; NULL for unknown fields.
+#[must_use]
+pub fn item_value(item: &Item, name: &str) -> Value {
+    let text = |s: &str| Value::Text(s.to_string());
+    let optional_text = |s: Option<&str>| s.map_or(Value::Null, text);
+    let optional_int = |n: Option<i64>| n.map_or(Value::Null, Value::Int);
+    let optional_float = |n: Option<f64>| n.map_or(Value::Null, Value::Float);
+    match name {
+        "id" => optional_int(item.id),
+        "album_id" => optional_int(item.album_id),
+        "path" => text(&item.path.to_string_lossy()),
+        "title" => text(&item.title),
+        "artist" => text(&item.artist),
+        "album" => text(&item.album),
+        "albumartist" => optional_text(item.albumartist.as_deref()),
+        "artist_sort" => optional_text(item.artist_sort.as_deref()),
+        "albumartist_sort" => optional_text(item.albumartist_sort.as_deref()),
+        "smart_artist" => {
+            let name = [&item.albumartist_sort, &item.albumartist, &item.artist_sort]
+                .into_iter()
+                .find_map(|name| name.as_deref().filter(|name| !name.is_empty()));
+            text(sort_name(name.unwrap_or(&item.artist)))
+        }
+        "genre" => optional_text(item.genre.as_deref()),
+        "year" => optional_int(item.year.map(i64::from)),
+        "track" => optional_int(item.track.map(i64::from)),
+        "disc" => optional_int(item.disc.map(i64::from)),
+        "format" => text(item.format.as_str()),
+        "bitrate" => Value::Int(item.bitrate.into()),
+        "length" => Value::Float(item.length),
+        "samplerate" => optional_int(item.samplerate.map(i64::from)),
+        "channels" => optional_int(item.channels.map(i64::from)),
+        "bitdepth" => optional_int(item.bitdepth.map(i64::from)),
+        "mb_trackid" => optional_text(item.mb_trackid.as_deref()),
+        "mb_albumid" => optional_text(item.mb_albumid.as_deref()),
+        "mb_releasegroupid" => optional_text(item.mb_releasegroupid.as_deref()),
+        "added" => text(&timestamp(&item.added)),
+        "mtime" => text(&timestamp(&item.mtime)),
+        "size" => optional_int(item.size.and_then(|n| i64::try_from(n).ok())),
+        "source_path" => item
+            .source_path
+            .as_ref()
+            .map_or(Value::Null, |p| text(&p.to_string_lossy())),
+        "import_run" => optional_text(item.import_run.as_deref()),
+        "rg_track_gain" => optional_float(item.rg_track_gain),
+        "rg_track_peak" => optional_float(item.rg_track_peak),
+        "rg_album_gain" => optional_float(item.rg_album_gain),
+        "rg_album_peak" => optional_float(item.rg_album_peak),
+        "rating" => optional_int(item.rating.map(i64::from)),
+        "play_count" => Value::Int(item.play_count.into()),
+        "last_played" => item.last_played.map_or(Value::Null, |t| text(&timestamp(&t))),
+        _ => Value::Null,
+    }
+}
+
+/// The value `album` has for the column `name`, as the database would store
+/// it; NULL for unknown columns.
+#[must_use]
+pub fn album_value(album: &Album, name: &str) -> Value {
+    let text = |s: &str| Value::Text(s.to_string());
+    let optional_path = |p: Option<&Path>| p.map_or(Value::Null, |p| text(&p.to_string_lossy()));
+    match name {
+        "id" => album.id.map_or(Value::Null, Value::Int),
+        "album" => text(&album.album),
+        "albumartist" => text(&album.albumartist),
+        "year" => album.year.map_or(Value::Null, |y| Value::Int(y.into())),
+        "artpath" => optional_path(album.artpath.as_deref()),
+        "mb_albumid" => album.mb_albumid.as_deref().map_or(Value::Null, text),
+        "mb_releasegroupid" => album.mb_releasegroupid.as_deref().map_or(Value::Null, text),
+        "added" => text(&timestamp(&album.added)),
+        "source_path" => optional_path(album.source_path.as_deref()),
+        "import_run" => album.import_run.as_deref().map_or(Value::Null, text),
+        "disambiguation" => album.disambiguation.as_deref().map_or(Value::Null, text),
+        _ => Value::Null,
+    }
+}
+
+/// A field value coerced to its column type.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Text(String),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+}
+
+impl fmt::Display for Value {
+    /// Plain text, as in CSV: NULL is empty.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Null => Ok(()),
+            Self::Text(s) => f.write_str(s),
+            Self::Int(n) => write!(f, "{n}"),
+            Self::Float(n) => write!(f, "{n}"),
+            Self::Bool(b) => write!(f, "{b}"),
+        }
+    }
+}
+
+impl ToSql for Value {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        match self {
+            Self::Null => rusqlite::types::Null.to_sql(),
+            Self::Text(s) => s.to_sql(),
+            Self::Int(n) => n.to_sql(),
+            Self::Float(n) => n.to_sql(),
+            Self::Bool(b) => b.to_sql(),
+        }
+    }
+}
+
+impl Field {
+    /// Parse `raw` as a value of this field's type. An empty string is NULL
+    /// for nullable fields.
+    ///
+    /// # Errors
+    /// Returns an error if `raw` isn't a valid value for the field.
+    pub fn parse_value(&self, raw: &str) -> Result<Value> {
+        if raw.is_empty() {
+            return if self.nullable {
+                Ok(Value::Null)
+            } else {
+                Err(Error::Query(format!("{} can't be empty", self.name)))
+            };
+        }
+
+        let invalid = || {
+            Error::Query(format!(
+                "Invalid value for {}: '{raw}' (expected {})",
+                self.name, self.ty
+            ))
+        };
+        match self.ty {
+            FieldType::String => Ok(Value::Text(raw.to_string())),
+            FieldType::Int => raw.parse().ok().map(Value::Int).ok_or_else(invalid),
+            FieldType::Float => raw
+                .parse::<f64>()
+                .ok()
+                .filter(|n| n.is_finite())
+                .map(Value::Float)
+                .ok_or_else(invalid),
+            FieldType::Date => DateTime::parse_from_rfc3339(raw)
+                .map(|d| d.with_timezone(&Utc))
+                .ok()
+                .or_else(|| {
+                    NaiveDate::parse_from_str(raw, "%Y-%m-%d")
+                        .ok()
+                        .and_then(|d| d.and_hms_opt(0, 0, 0))
+                        .map(|d| d.and_utc())
+                })
+                .map(|d| Value::Text(timestamp(&d)))
+                .ok_or_else(invalid),
+            FieldType::Bool => match raw.to_lowercase().as_str() {
+                "true" | "yes" | "1" => Ok(Value::Bool(true)),
+                "false" | "no" | "0" => Ok(Value::Bool(false)),
+                _ => Err(invalid()),
+            },
+        }
+    }
+}
+
+/// A field two items have different values for (see [`Item::diff`]).
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldDiff {
+    pub field: &'static str,
+    /// The value of the item `diff` was called on.
+    pub ours: Value,
+    /// The value of the item it was compared with.
+    pub theirs: Value,
+}
+
+/// A validated `modify` assignment: `field=value`, `field=` or `field!`
+/// (both of which clear the field).
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldEdit {
+    field: &'static Field,
+    value: Value,
+}
+
+impl FieldEdit {
+    /// Parse an assignment to one of the album fields in [`ALBUM_FIELDS`].
+    ///
+    /// # Errors
+    /// Returns an error if the field isn't an album field or the value is
+    /// invalid for it.
+    pub fn parse_album(s: &str) -> Result<Self> {
+        Self::parse_in(s, ALBUM_FIELDS)
+    }
+
+    fn parse_in(s: &str, fields: &'static [Field]) -> Result<Self> {
+        let (key, raw) = match (s.split_once('='), s.strip_suffix('!')) {
+            (Some((key, raw)), _) => (key, raw),
+            (None, Some(key)) => (key, ""),
+            (None, None) => {
+                return Err(Error::Query(format!(
+                    "Expected field=value or field!, got '{s}'"
+                )));
+            }
+        };
+        let field = editable_field(fields, key)?;
+        let value = field.parse_value(raw)?;
+        let out_of_range = |n: i64| !(0..=i64::from(MAX_RATING)).contains(&n);
+        if field.name == "rating" && matches!(value, Value::Int(n) if out_of_range(n)) {
+            return Err(Error::Query(format!(
+                "Invalid value for rating: '{raw}' (expected 0 to {MAX_RATING})"
+            )));
+        }
+        Ok(Self { field, value })
+    }
+
+    /// The edits to the tag fields among `fields` that turn `before` into
+    /// `after`, whose values `value` (such as [`item_value`]) reads.
+    #[must_use]
+    pub fn diff<T>(
+        fields: &'static [Field],
+        before: &T,
+        after: &T,
+        value: impl Fn(&T, &str) -> Value,
+    ) -> Vec<Self> {
+        fields
+            .iter()
+            .filter(|field| field.tag)
+            .filter_map(|field| {
+                let new = value(after, field.name);
+                (value(before, field.name) != new).then_some(Self { field, value: new })
+            })
+            .collect()
+    }
+
+    #[must_use]
+    pub const fn field(&self) -> &'static str {
+        self.field.name
+    }
+
+    /// Whether the edit changes a field stored in file tags, rather than
+    /// one kept only in the library.
+    #[must_use]
+    pub const fn is_tag(&self) -> bool {
+        self.field.tag
+    }
+
+    #[must_use]
+    pub const fn value(&self) -> &Value {
+        &self.value
+    }
+}
+
+impl FromStr for FieldEdit {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::parse_in(s, ITEM_FIELDS)
+    }
+}
+
+/// The field of `fields` named `key`, if `modify` may change it.
+fn editable_field(fields: &'static [Field], key: &str) -> Result<&'static Field> {
+    fields.iter().find(|f| f.editable && f.name == key).ok_or_else(|| {
+        Error::Query(format!(
+            "Invalid field: {key} (valid fields: {})",
+            tag_field_names(fields)
+        ))
+    })
+}
+
+/// A `modify` assignment to items, which may depend on the value each item
+/// has: see [`FieldChange::resolve`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldChange {
+    /// `field=value`, `field=` or `field!`: the same value for every item.
+    Set(FieldEdit),
+    /// `field+=value`: `value` after the current one and `separator`, or on
+    /// its own if the field is empty.
+    Append {
+        field: &'static Field,
+        value: String,
+        separator: String,
+    },
+    /// `field-=value`: the first occurrence of `value` taken out of the
+    /// current value's parts, split at `separator`.
+    Remove {
+        field: &'static Field,
+        value: String,
+        separator: String,
+    },
+}
+
+impl FieldChange {
+    /// Parse an assignment to one of the item fields in [`ITEM_FIELDS`],
+    /// where text fields also take `field+=value` and `field-=value`, whose
+    /// parts are joined by `separator`.
+    ///
+    /// # Errors
+    /// Returns an error if the field isn't an item field, the value is
+    /// invalid for it, or it is added to or removed from a field that isn't
+    /// text.
+    pub fn parse(s: &str, separator: &str) -> Result<Self> {
+        let Some((key, value)) = s.split_once('=') else {
+            return s.parse().map(Self::Set);
+        };
+        let (key, append) = match (key.strip_suffix('+'), key.strip_suffix('-')) {
+            (Some(key), _) => (key, true),
+            (None, Some(key)) => (key, false),
+            (None, None) => return s.parse().map(Self::Set),
+        };
+        let field = editable_field(ITEM_FIELDS, key)?;
+        if field.ty != FieldType::String {
+            return Err(Error::Query(format!(
+                "Can't add to or remove from {key}, which isn't text"
+            )));
+        }
+        if value.is_empty() {
+            return Err(Error::Query(format!("Nothing to add to or remove from {key}")));
+        }
+        let (value, separator) = (value.to_string(), separator.to_string());
+        Ok(if append {
+            Self::Append {
+                field,
+                value,
+                separator,
+            }
+        } else {
+            Self::Remove {
+                field,
+                value,
+                separator,
+            }
+        })
+    }
+
+    #[must_use]
+    pub const fn field(&self) -> &'static str {
+        match self {
+            Self::Set(edit) => edit.field(),
+            Self::Append { field, .. } | Self::Remove { field, .. } => field.name,
+        }
+    }
+
+    /// The edits making `changes` to `item`, one for each field changed.
+    /// Changes to the same field apply in order, each to the value the one
+    /// before left.
+    ///
+    /// # Errors
+    /// Returns an error if a change leaves a field that can't be empty
+    /// without a value.
+    pub fn resolve(changes: &[Self], item: &Item) -> Result<Vec<FieldEdit>> {
+        let mut edits: Vec<FieldEdit> = Vec::new();
+        for change in changes {
+            let current = match edits.iter().position(|edit| edit.field() == change.field()) {
+                Some(i) => edits.remove(i).value,
+                None => item_value(item, change.field()),
+            };
+            edits.push(change.apply(&current)?);
+        }
+        Ok(edits)
+    }
+
+    /// The edit making this change to a field whose value is `current`.
+    fn apply(&self, current: &Value) -> Result<FieldEdit> {
+        // NULL shows as empty, like an empty string
+        let current = current.to_string();
+        let (field, raw) = match self {
+            Self::Set(edit) => return Ok(edit.clone()),
+            Self::Append { field, value, .. } if current.is_empty() => (*field, value.clone()),
+            Self::Append {
+                field,
+                value,
+                separator,
+            } => (*field, format!("{current}{separator}{value}")),
+            Self::Remove {
+                field,
+                value,
+                separator,
+            } => {
+                // Split at the separator without its spaces, so "Rock;Pop"
+                // and "Rock; Pop" come apart alike
+                let split = Some(separator.trim()).filter(|s| !s.is_empty()).unwrap_or(separator);
+                let mut parts: Vec<&str> = current.split(split).map(str::trim).collect();
+                match parts.iter().position(|part| *part == value.as_str()) {
+                    Some(i) => {
+                        parts.remove(i);
+                        (*field, parts.join(separator))
+                    }
+                    None => (*field, current.clone()),
+                }
+            }
+        };
+        let value = field.parse_value(&raw)?;
+        Ok(FieldEdit { field, value })
+    }
+}
+
+/// How queries and `modify` name a flexible attribute: `attr.source` is the
+/// attribute `source`.
+pub const ATTRIBUTE_PREFIX: &str = "attr.";
+
+/// The flexible attribute a field name such as `attr.source` names, if it
+/// names one.
+#[must_use]
+pub fn attribute_key(name: &str) -> Option<&str> {
+    name.strip_prefix(ATTRIBUTE_PREFIX)
+}
+
+/// Fail unless `key` can name a flexible attribute: it is letters, digits
+/// and `_`, so that path formats can use it as `$attr_<key>`.
+///
+/// # Errors
+/// Returns an error naming the key if it can't.
+pub fn check_attribute_key(key: &str) -> Result<()> {
+    if !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        Ok(())
+    } else {
+        Err(Error::Query(format!(
+            "Invalid attribute name: '{key}' (expected letters, digits and _)"
+        )))
+    }
+}
+
+/// A `modify` assignment to a flexible attribute: `attr.key=value`, or
+/// `attr.key=` or `attr.key!` to remove it. Attributes are text, kept in the
+/// library only.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AttributeEdit {
+    pub key: String,
+    /// The new value; `None` removes the attribute.
+    pub value: Option<String>,
+}
+
+impl AttributeEdit {
+    /// The attribute's name as queries and `modify` take it, such as
+    /// `attr.source`.
+    #[must_use]
+    pub fn name(&self) -> String {
+        format!("{ATTRIBUTE_PREFIX}{}", self.key)
+    }
+}
+
+impl FromStr for AttributeEdit {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (name, value) = match (s.split_once('='), s.strip_suffix('!')) {
+            (Some((name, value)), _) => (name, Some(value).filter(|value| !value.is_empty())),
+            (None, Some(name)) => (name, None),
+            (None, None) => {
+                return Err(Error::Query(format!(
+                    "Expected attr.key=value or attr.key!, got '{s}'"
+                )));
+            }
+        };
+        let key = attribute_key(name)
+            .ok_or_else(|| Error::Query(format!("Not an attribute: {name} (expected attr.key)")))?;
+        check_attribute_key(key)?;
+        Ok(Self {
+            key: key.to_string(),
+            value: value.map(str::to_string),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    #[test]
+    fn test_parse_field_edit() {
+        let edit: FieldEdit = "year=1999".parse().unwrap();
+        assert_eq!((edit.field(), edit.value()), ("year", &Value::Int(1999)));
+        let edit: FieldEdit = "albumartist!".parse().unwrap();
+        assert_eq!(edit.value(), &Value::Null);
+        let edit: FieldEdit = "genre=".parse().unwrap();
+        assert_eq!(edit.value(), &Value::Null);
+
+        let err = "year=banana".parse::<FieldEdit>().unwrap_err().to_string();
+        assert!(err.contains("expected an integer"), "{err}");
+        let err = "album_id=3".parse::<FieldEdit>().unwrap_err().to_string();
+        assert!(err.contains("valid fields: title, artist"), "{err}");
+        assert!("title=".parse::<FieldEdit>().is_err());
+        assert!("genre".parse::<FieldEdit>().is_err());
+
+        assert!(FieldEdit::parse_album("albumartist=Ozzy").is_ok());
+        assert!(FieldEdit::parse_album("albumartist!").is_err());
+        assert!(FieldEdit::parse_album("genre=Rock").is_err());
+    }
+
+    #[test]
+    fn test_parse_field_change() {
+        let parse = |s: &str| FieldChange::parse(s, ", ");
+        assert_eq!(parse("genre=Rock").unwrap(), FieldChange::Set("genre=Rock".parse().unwrap()));
+        assert_eq!(parse("genre!").unwrap(), FieldChange::Set("genre!".parse().unwrap()));
+        let append = parse("genre+=Doom Metal").unwrap();
+        assert_eq!(append.field(), "genre");
+        let expected = FieldChange::Append {
+            field: item_field("genre").unwrap(),
+            value: "Doom Metal".into(),
+            separator: ", ".into(),
+        };
+        assert_eq!(append, expected);
+        assert!(matches!(parse("artist-=Ozzy").unwrap(), FieldChange::Remove { .. }));
+        // Only the first `=` counts, so values may hold `+=`
+        let set = parse("title=a+=b").unwrap();
+        assert_eq!(set, FieldChange::Set("title=a+=b".parse().unwrap()));
+
+        let err = parse("year+=1").unwrap_err().to_string();
+        assert!(err.contains("isn't text"), "{err}");
+        assert!(parse("genre+=").is_err());
+        assert!(parse("path+=x").is_err());
+        assert!(parse("rating-=3").is_err());
+    }
+
+    #[test]
+    fn test_parse_attribute_edit() {
+        let edit: AttributeEdit = "attr.source=vinyl rip".parse().unwrap();
+        assert_eq!((edit.key.as_str(), edit.value.as_deref()), ("source", Some("vinyl rip")));
+        assert_eq!("attr.mood!".parse::<AttributeEdit>().unwrap().value, None);
+        assert_eq!("attr.mood=".parse::<AttributeEdit>().unwrap().value, None);
+
+        let err = "attr.bad-key=x".parse::<AttributeEdit>().unwrap_err().to_string();
+        assert!(err.contains("Invalid attribute name: 'bad-key'"), "{err}");
+        assert!("attr.=x".parse::<AttributeEdit>().is_err());
+        assert!("source=x".parse::<AttributeEdit>().is_err());
+        assert!("attr.source".parse::<AttributeEdit>().is_err());
+    }
+
+    #[test]
+    fn test_library_only_fields() {
+        for (edit, value) in [("rating=0", Value::Int(0)), ("rating=5", Value::Int(5))] {
+            let edit: FieldEdit = edit.parse().unwrap();
+            assert_eq!(edit.value(), &value);
+            assert!(!edit.is_tag());
+        }
+        assert_eq!("rating!".parse::<FieldEdit>().unwrap().value(), &Value::Null);
+        for bad in ["rating=6", "rating=-1", "rating=4.5"] {
+            assert!(bad.parse::<FieldEdit>().is_err(), "{bad}");
+        }
+        let err = "rating=9".parse::<FieldEdit>().unwrap_err().to_string();
+        assert!(err.contains("expected 0 to 5"), "{err}");
+
+        let edit: FieldEdit = "last_played=2024-05-01".parse().unwrap();
+        assert_eq!(
+            edit.value(),
+            &Value::Text("2024-05-01T00:00:00.000000Z".into())
+        );
+        assert!("play_count=".parse::<FieldEdit>().is_err());
+        assert!("title=x".parse::<FieldEdit>().unwrap().is_tag());
+        // Queries aren't limited to the range ratings are set in
+        assert!(item_field("rating").unwrap().parse_value("10").is_ok());
+    }
+
+    #[test]
+    fn test_diff() {
+        let before = Album {
+            id: Some(1),
+            album: "Kind of Blue".into(),
+            albumartist: "Miles Davis".into(),
+            ..testutil::album()
+        };
+        let after = Album {
+            year: Some(1959),
+            mb_albumid: Some("8e8a594f".into()),
+            artpath: Some("/music/cover.jpg".into()),
+            ..before.clone()
+        };
+        let edits = FieldEdit::diff(ALBUM_FIELDS, &before, &after, album_value);
+        let edits: Vec<_> = edits.iter().map(|e| (e.field(), e.value().to_string())).collect();
+        assert_eq!(edits, [("year", "1959".into()), ("mb_albumid", "8e8a594f".into())]);
+        assert!(FieldEdit::diff(ALBUM_FIELDS, &after, &after, album_value).is_empty());
+    }
+
+    #[test]
+    fn test_parse_value_types() {
+        let added = item_field("added").unwrap();
+        assert_eq!(
+            added.parse_value("2024-03-09").unwrap(),
+            Value::Text("2024-03-09T00:00:00.000000Z".into())
+        );
+        assert!(item_field("length").unwrap().parse_value("nan").is_err());
+        assert!(item_field("bitrate").unwrap().parse_value("").is_err());
+    }
+
+    #[test]
+    fn test_sort_name() {
+        assert_eq!(sort_name("The Beatles"), "Beatles");
+        assert_eq!(sort_name("the  the"), "the");
+        assert_eq!(sort_name("A Tribe Called Quest"), "Tribe Called Quest");
+        assert_eq!(sort_name("An Horse"), "Horse");
+        assert_eq!(sort_name("ABBA"), "ABBA");
+        assert_eq!(sort_name("Theatre of Tragedy"), "Theatre of Tragedy");
+        assert_eq!(sort_name("The"), "The");
+        assert_eq!(sort_name("A "), "A ");
+        assert_eq!(sort_name("Ä Band"), "Ä Band");
+    }
+}
@@ -0,0 +1,193 @@
+//! Human-readable formatting of numbers, sizes, durations and dates
+//!
+//! Console output goes through a [`Formatter`] built from the `[ui]` config.
+//! Machine-readable (`--json`) output never does: it always uses raw values.
+//! With `--stable`, [`Formatter::stable`] replaces the config so output is the
+//! same on every machine.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::config::UiConfig;
+
+const KB: u64 = 1024;
+const MB: u64 = KB * 1024;
+const GB: u64 = MB * 1024;
+
+/// How durations are rendered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DurationStyle {
+    /// `7:12:34`
+    #[default]
+    Short,
+    /// `7h 12m 34s`
+    Long,
+}
+
+/// Formats values for console output according to the user's preferences.
+#[derive(Debug, Clone)]
+pub struct Formatter {
+    thousands_separator: String,
+    decimal_separator: String,
+    date_format: String,
+    duration_style: DurationStyle,
+}
+
+impl Default for Formatter {
+    fn default() -> Self {
+        Self::new(&UiConfig::default())
+    }
+}
+
+impl Formatter {
+    #[must_use]
+    pub fn new(config: &UiConfig) -> Self {
+        Self {
+            thousands_separator: config.thousands_separator.clone(),
+            decimal_separator: config.decimal_separator.clone(),
+            date_format: config.date_format.clone(),
+            duration_style: config.duration_style,
+        }
+    }
+
+    /// Locale-independent formatting for `--stable` output: no thousands
+    /// separator, `.` for decimals, UTC ISO 8601 timestamps and short
+    /// durations, whatever the `[ui]` config says.
+    #[must_use]
+    pub fn stable() -> Self {
+        Self {
+            thousands_separator: String::new(),
+            decimal_separator: ".".into(),
+            date_format: "%Y-%m-%dT%H:%M:%SZ".into(),
+            duration_style: DurationStyle::Short,
+        }
+    }
+
+    /// Format an integer count, e.g. `12,345`.
+    #[must_use]
+    pub fn count(&self, n: u64) -> String {
+        let digits = n.to_string();
+        let mut out = String::with_capacity(digits.len() + digits.len() / 3);
+        for (i, c) in digits.chars().enumerate() {
+            if i > 0 && (digits.len() - i) % 3 == 0 {
+                out.push_str(&self.thousands_separator);
+            }
+            out.push(c);
+        }
+        out
+    }
+
+    /// Format a non-negative number with a fixed number of decimal places.
+    #[must_use]
+    pub fn decimal(&self, value: f64, places: usize) -> String {
+        let fixed = format!("{:.places$}", value.max(0.0));
+        let (int_part, frac_part) = fixed.split_once('.').unwrap_or((fixed.as_str(), ""));
+        let int_part = int_part
+            .parse::<u64>()
+            .map_or_else(|_| int_part.to_string(), |n| self.count(n));
+        if frac_part.is_empty() {
+            int_part
+        } else {
+            format!("{int_part}{}{frac_part}", self.decimal_separator)
+        }
+    }
+
+    /// Format a duration given in seconds.
+    #[must_use]
+    pub fn duration(&self, seconds: f64) -> String {
+        // Negative durations are invalid; clamp to 0
+        let total_secs = seconds.max(0.0) as u64;
+        let hours = total_secs / 3600;
+        let mins = (total_secs % 3600) / 60;
+        let secs = total_secs % 60;
+
+        match self.duration_style {
+            DurationStyle::Short if hours > 0 => {
+                format!("{}:{mins:02}:{secs:02}", self.count(hours))
+            }
+            DurationStyle::Short => format!("{mins}:{secs:02}"),
+            DurationStyle::Long if hours > 0 => {
+                format!("{}h {mins:02}m {secs:02}s", self.count(hours))
+            }
+            DurationStyle::Long => format!("{mins}m {secs:02}s"),
+        }
+    }
+
+    /// Format a byte count using binary units.
+    #[must_use]
+    pub fn size(&self, bytes: u64) -> String {
+        if bytes >= GB {
+            format!("{} GB", self.decimal(bytes as f64 / GB as f64, 1))
+        } else if bytes >= MB {
+            format!("{} MB", self.decimal(bytes as f64 / MB as f64, 1))
+        } else if bytes >= KB {
+            format!("{} KB", self.decimal(bytes as f64 / KB as f64, 1))
+        } else {
+            format!("{bytes} B")
+        }
+    }
+
+    /// Format a timestamp using the configured `strftime` pattern.
+    #[must_use]
+    pub fn date(&self, date: &DateTime<Utc>) -> String {
+        date.format(&self.date_format).to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn european() -> Formatter {
+        Formatter::new(&UiConfig {
+            thousands_separator: ".".into(),
+            decimal_separator: ",".into(),
+            date_format: "%d.%m.%Y".into(),
+            duration_style: DurationStyle::Long,
+            ..UiConfig::default()
+        })
+    }
+
+    #[test]
+    fn test_default_formatter() {
+        let f = Formatter::default();
+        assert_eq!(f.count(1_234_567), "1234567");
+        assert_eq!(f.size(1_325_598_105_600), "1234.6 GB");
+        assert_eq!(f.duration(25954.0), "7:12:34");
+        assert_eq!(f.duration(65.0), "1:05");
+        let date = Utc.with_ymd_and_hms(2024, 3, 9, 12, 0, 0).unwrap();
+        assert_eq!(f.date(&date), "2024-03-09");
+    }
+
+    #[test]
+    fn test_configured_formatter() {
+        let f = european();
+        assert_eq!(f.count(1_234_567), "1.234.567");
+        assert_eq!(f.size(1_325_598_105_600), "1.234,6 GB");
+        assert_eq!(f.duration(25954.0), "7h 12m 34s");
+        assert_eq!(f.duration(65.0), "1m 05s");
+        let date = Utc.with_ymd_and_hms(2024, 3, 9, 12, 0, 0).unwrap();
+        assert_eq!(f.date(&date), "09.03.2024");
+    }
+
+    #[test]
+    fn test_json_ignores_formatter() {
+        let stats = crate::db::Stats {
+            tracks: 1234,
+            albums: 5,
+            artists: 4,
+            total_length: 25954.0,
+            total_size: 1_325_598_105_600,
+            missing: None,
+        };
+        // JSON is rendered from raw values; no formatter is involved
+        let json = serde_json::to_string(&stats).unwrap();
+        assert_eq!(
+            json,
+            r#"{"tracks":1234,"albums":5,"artists":4,"total_length":25954.0,"total_size":1325598105600}"#
+        );
+        assert!(!json.contains(&european().count(1234)));
+    }
+}
@@ -0,0 +1,149 @@
+//! Canonical genre names
+//!
+//! The `[genres]` config section maps aliases such as `hiphop` or `Rap/Hip-Hop`
+//! to the name the library should use, matched ignoring case. Genres no alias
+//! matches can be title-cased, so `post-rock` and `Post-rock` both become
+//! `Post-Rock`. Imports apply the rules to the genres read from tags, and
+//! `genres --normalize` applies them to tracks already in the library.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::Item;
+
+/// Why a genre was changed, for reporting how often each rule applied.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Rule {
+    /// A configured alias, as written in the config, and its canonical name
+    Alias { alias: String, canonical: String },
+    /// A canonical name spelled with different case
+    Case(String),
+    /// Title-casing a genre nothing else matched
+    TitleCase,
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Alias { alias, canonical } => write!(f, "{alias} -> {canonical}"),
+            Self::Case(name) => write!(f, "{name} (case)"),
+            Self::TitleCase => f.write_str("title case"),
+        }
+    }
+}
+
+/// The genre rules of a config, ready for lookups.
+#[derive(Debug, Clone, Default)]
+pub struct Genres {
+    /// The canonical name and rule for each lowercased alias or canonical
+    /// name.
+    rules: HashMap<String, (String, Rule)>,
+    title_case: bool,
+}
+
+impl Genres {
+    /// Rules mapping each alias to its canonical name; canonical names also
+    /// match themselves in any case. With `title_case`, other genres are
+    /// title-cased.
+    #[must_use]
+    pub fn new<'a>(
+        aliases: impl IntoIterator<Item = (&'a str, &'a str)>,
+        title_case: bool,
+    ) -> Self {
+        let aliases: Vec<_> = aliases.into_iter().collect();
+        let mut rules = HashMap::new();
+        for &(alias, canonical) in &aliases {
+            let rule = Rule::Alias {
+                alias: alias.into(),
+                canonical: canonical.into(),
+            };
+            rules.insert(alias.trim().to_lowercase(), (canonical.to_string(), rule));
+        }
+        // An alias spelled like a canonical name wins over the name itself
+        for &(_, canonical) in &aliases {
+            rules
+                .entry(canonical.trim().to_lowercase())
+                .or_insert_with(|| (canonical.to_string(), Rule::Case(canonical.into())));
+        }
+        Self { rules, title_case }
+    }
+
+    /// Whether no genre would ever change.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty() && !self.title_case
+    }
+
+    /// The canonical name of `genre` and the rule that gave it, or `None`
+    /// if `genre` is already canonical or no rule applies.
+    #[must_use]
+    pub fn normalize(&self, genre: &str) -> Option<(String, Rule)> {
+        let trimmed = genre.trim();
+        if trimmed.is_empty() {
+            return None;
+        }
+        let (name, rule) = match self.rules.get(&trimmed.to_lowercase()) {
+            Some((name, rule)) => (name.clone(), rule.clone()),
+            None if self.title_case => (title_case(trimmed), Rule::TitleCase),
+            None => return None,
+        };
+        (name != genre).then_some((name, rule))
+    }
+
+    /// Give `item` the canonical name of its genre, returning the rule that
+    /// changed it.
+    pub fn normalize_item(&self, item: &mut Item) -> Option<Rule> {
+        let (name, rule) = self.normalize(item.genre.as_deref()?)?;
+        item.genre = Some(name);
+        Some(rule)
+    }
+}
+
+/// `genre` with the first letter of each word raised, words being separated
+/// by whitespace, hyphens and slashes. Other letters are left alone, so
+/// acronyms such as `EDM` keep their case.
+fn title_case(genre: &str) -> String {
+    let mut titled = String::with_capacity(genre.len());
+    let mut word_start = true;
+    for c in genre.chars() {
+        if word_start {
+            titled.extend(c.to_uppercase());
+        } else {
+            titled.push(c);
+        }
+        word_start = c.is_whitespace() || matches!(c, '-' | '/');
+    }
+    titled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn genres(title_case: bool) -> Genres {
+        Genres::new([("hiphop", "Hip Hop"), ("Rap/Hip-Hop", "Hip Hop")], title_case)
+    }
+
+    #[test]
+    fn test_aliases_match_ignoring_case() {
+        let genres = genres(false);
+        let (name, rule) = genres.normalize("HipHop").unwrap();
+        assert_eq!(name, "Hip Hop");
+        assert_eq!(rule.to_string(), "hiphop -> Hip Hop");
+        assert_eq!(genres.normalize(" rap/hip-hop").unwrap().0, "Hip Hop");
+        assert_eq!(genres.normalize("hip hop").unwrap().1, Rule::Case("Hip Hop".into()));
+        assert_eq!(genres.normalize("Hip Hop"), None);
+        assert_eq!(genres.normalize("post-rock"), None);
+        assert_eq!(genres.normalize(""), None);
+        assert!(Genres::default().is_empty());
+    }
+
+    #[test]
+    fn test_title_case_leaves_known_genres_to_aliases() {
+        let genres = genres(true);
+        assert_eq!(genres.normalize("post-rock"), Some(("Post-Rock".into(), Rule::TitleCase)));
+        assert_eq!(genres.normalize("drum and bass/EDM").unwrap().0, "Drum And Bass/EDM");
+        assert_eq!(genres.normalize("hiphop").unwrap().0, "Hip Hop");
+        assert_eq!(genres.normalize("Jazz"), None);
+    }
+}
@@ -0,0 +1,274 @@
+//! Commands run when the library changes
+//!
+//! The `[hooks]` config table maps an event to a command, given as the
+//! program followed by its arguments. It runs directly, not through a shell,
+//! so values need no quoting: `{path}`, `{album}`, `{artist}`, `{title}` and
+//! `{id}` in the arguments are replaced by those of the album or track the
+//! event is about, and other braces are left alone. The event name is in
+//! `RSBTS_EVENT`. Hooks run one at a time and are waited for; one that can't
+//! be started or exits with an error is reported, and the change that
+//! triggered it stands.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::process::Command;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::art::album_dir;
+use crate::db::Database;
+use crate::{Album, Item, Result};
+
+/// Something that happened to the library.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Event {
+    AlbumImported,
+    ItemImported,
+    ItemRemoved,
+    ItemModified,
+}
+
+impl Event {
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::AlbumImported => "album_imported",
+            Self::ItemImported => "item_imported",
+            Self::ItemRemoved => "item_removed",
+            Self::ItemModified => "item_modified",
+        }
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The configured hook commands.
+#[derive(Debug, Clone, Default)]
+pub struct Hooks {
+    commands: HashMap<Event, Vec<String>>,
+}
+
+impl Hooks {
+    /// Hooks running `commands`, each a program and its arguments. Empty
+    /// commands are ignored.
+    #[must_use]
+    pub fn new(commands: HashMap<Event, Vec<String>>) -> Self {
+        Self {
+            commands: commands.into_iter().filter(|(_, c)| !c.is_empty()).collect(),
+        }
+    }
+
+    /// Hooks that never run anything, as with `--no-hooks`.
+    #[must_use]
+    pub fn disabled() -> Self {
+        Self::default()
+    }
+
+    /// Run the hook for `event` about `item`, if there is one.
+    pub fn item(&self, event: Event, item: &Item) {
+        if !self.commands.contains_key(&event) {
+            return;
+        }
+        let path = item.path.to_string_lossy();
+        let id = item.id.map(|id| id.to_string()).unwrap_or_default();
+        self.run(
+            event,
+            &[
+                ("path", path.as_ref()),
+                ("album", item.album.as_str()),
+                ("artist", item.artist.as_str()),
+                ("title", item.title.as_str()),
+                ("id", id.as_str()),
+            ],
+        );
+    }
+
+    /// Run the hook for `event` about `album`, whose files are under `dir`,
+    /// if there is one.
+    pub fn album(&self, event: Event, album: &Album, dir: &Path) {
+        if !self.commands.contains_key(&event) {
+            return;
+        }
+        let path = dir.to_string_lossy();
+        let id = album.id.map(|id| id.to_string()).unwrap_or_default();
+        self.run(
+            event,
+            &[
+                ("path", path.as_ref()),
+                ("album", album.album.as_str()),
+                ("artist", album.albumartist.as_str()),
+                ("id", id.as_str()),
+            ],
+        );
+    }
+
+    /// Run the import hooks for what the import run `run` added: each track,
+    /// then each album.
+    ///
+    /// # Errors
+    /// Returns an error if looking up the run's albums or tracks fails.
+    pub fn imported(&self, db: &Database, run: &str) -> Result<()> {
+        if self.commands.contains_key(&Event::ItemImported) {
+            for item in db.import_run_items(run)? {
+                self.item(Event::ItemImported, &item);
+            }
+        }
+        if self.commands.contains_key(&Event::AlbumImported) {
+            for album in db.import_run_albums(run)? {
+                let Some(id) = album.id else {
+                    continue;
+                };
+                let items = db.album_items(id)?;
+                let dir = album_dir(&items).unwrap_or_default();
+                self.album(Event::AlbumImported, &album, &dir);
+            }
+        }
+        Ok(())
+    }
+
+    fn run(&self, event: Event, values: &[(&str, &str)]) {
+        let Some((program, args)) = self.commands.get(&event).and_then(|c| c.split_first())
+        else {
+            return;
+        };
+        let status = Command::new(program)
+            .args(args.iter().map(|arg| expand(arg, values)))
+            .env("RSBTS_EVENT", event.as_str())
+            .status();
+        match status {
+            Ok(status) if status.success() => {}
+            Ok(status) => warn!("{event} hook {program} failed ({status})"),
+            Err(e) => warn!("Could not run {event} hook {program}: {e}"),
+        }
+    }
+}
+
+/// `arg` with each `{name}` in `values` replaced by its value. Replacements
+/// aren't looked at again, so a title containing `{path}` stays as it is.
+#[must_use]
+pub fn expand(arg: &str, values: &[(&str, &str)]) -> String {
+    let mut out = String::with_capacity(arg.len());
+    let mut rest = arg;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let value = rest.find('}').and_then(|end| {
+            let (_, value) = values.iter().find(|(name, _)| *name == &rest[1..end])?;
+            Some((value, end))
+        });
+        if let Some((value, end)) = value {
+            out.push_str(value);
+            rest = &rest[end + 1..];
+        } else {
+            out.push('{');
+            rest = &rest[1..];
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+    use crate::AudioFormat;
+
+    fn item(path: &str, title: &str) -> Item {
+        Item {
+            path: path.into(),
+            title: title.into(),
+            artist: "Nina Simone".into(),
+            album: "Pastel Blues".into(),
+            albumartist: Some("Nina Simone".into()),
+            year: Some(1965),
+            format: AudioFormat::Flac,
+            bitrate: 900,
+            length: 600.0,
+            import_run: Some("run".into()),
+            ..testutil::item()
+        }
+    }
+
+    #[test]
+    fn test_expand_placeholders() {
+        let values = [("path", "/music/a {id}.flac"), ("id", "7")];
+        assert_eq!(expand("{id}", &values), "7");
+        assert_eq!(expand("--file={path}", &values), "--file=/music/a {id}.flac");
+        assert_eq!(expand("{nope} {id}} {", &values), "{nope} 7} {");
+        assert_eq!(expand("", &values), "");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_import_hooks_run_with_item_and_album_values() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("rsbts-hooks-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let script = dir.join("hook");
+        let log = dir.join("log");
+        std::fs::write(
+            &script,
+            "#!/bin/sh\nprintf '%s|' \"$RSBTS_EVENT\" \"$@\" >> \"$(dirname \"$0\")/log\"\n\
+             echo >> \"$(dirname \"$0\")/log\"\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let db = Database::open_in_memory().unwrap();
+        db.migrate().unwrap();
+        let album_id = db
+            .insert_album(&Album {
+                album: "Pastel Blues".into(),
+                albumartist: "Nina Simone".into(),
+                year: Some(1965),
+                import_run: Some("run".into()),
+                ..testutil::album()
+            })
+            .unwrap();
+        for (path, title) in [
+            ("/music/Nina Simone/Pastel Blues/01 Be My Husband.flac", "Be My Husband"),
+            ("/music/Nina Simone/Pastel Blues/09 Sinnerman.flac", "Sinnerman"),
+        ] {
+            let mut item = item(path, title);
+            item.album_id = Some(album_id);
+            db.insert_item(&item).unwrap();
+        }
+
+        let script = script.to_string_lossy().into_owned();
+        let hooks = Hooks::new(HashMap::from([
+            (
+                Event::ItemImported,
+                vec![script.clone(), "{id}".into(), "{title}".into()],
+            ),
+            (
+                Event::AlbumImported,
+                vec![script, "{artist} - {album}".into(), "{path}".into()],
+            ),
+            // Can't be started: reported, and the others still run
+            (Event::ItemRemoved, vec![dir.join("missing").to_string_lossy().into_owned()]),
+        ]));
+        hooks.imported(&db, "run").unwrap();
+        hooks.item(Event::ItemRemoved, &item("/music/x.flac", "x"));
+        // Not configured
+        hooks.item(Event::ItemModified, &item("/music/x.flac", "x"));
+        Hooks::disabled().imported(&db, "run").unwrap();
+
+        let log = std::fs::read_to_string(&log).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            log,
+            "item_imported|1|Be My Husband|\n\
+             item_imported|2|Sinnerman|\n\
+             album_imported|Nina Simone - Pastel Blues|/music/Nina Simone/Pastel Blues|\n"
+        );
+    }
+}
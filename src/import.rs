@@ -1,14 +1,22 @@
 //! Import workflow
 
-use std::collections::HashMap;
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::{ErrorKind, Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
 /// Constants for track matching and scoring algorithms.
 mod matching {
     /// Bonus score when track count matches release track count.
     pub const TRACK_COUNT_BONUS: f64 = 0.2;
-    /// Multiplier for converting similarity scores to integer comparison values.
-    pub const SCORE_MULTIPLIER: f64 = 100.0;
+    /// Highest possible raw score: artist + album similarity plus the track count bonus.
+    pub const MAX_RAW_SCORE: f64 = 2.0 + TRACK_COUNT_BONUS;
+    /// Largest tie-breaking bonus for a preferred release country (normalized scale).
+    pub const COUNTRY_PREFERENCE_BONUS: f64 = 0.02;
+    /// Largest tie-breaking bonus for a preferred medium format (normalized scale).
+    pub const MEDIA_PREFERENCE_BONUS: f64 = 0.02;
 
     /// Constants for track length comparison.
     pub mod length {
@@ -37,37 +45,280 @@ mod matching {
     }
 }
 
-use indicatif::{ProgressBar, ProgressStyle};
+use chrono::{DateTime, Utc};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use log::{debug, info, warn};
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
 use walkdir::WalkDir;
 
+use crate::acoustid::Client as AcoustIdClient;
+use crate::bundle::Bundle;
+use crate::changes::{placed_position, AlbumChanges, TrackMatch};
+use crate::cue;
 use crate::db::Database;
-use crate::musicbrainz::{Client as MbClient, Release};
-use crate::pathformat::format_path;
-use crate::tags::{is_audio_file, read_tags};
-use crate::{Album, Item, Result};
+use crate::fields::{album_value, item_field, FieldEdit, ALBUM_FIELDS};
+use crate::genres::Genres;
+use crate::hooks::{Event, Hooks};
+use crate::metadata_cache::MetadataCache;
+use crate::musicbrainz::{Client as MbClient, ClientSettings, MetadataSource, Release, Track};
+use crate::pathformat::PathFormats;
+use crate::prune::Pruner;
+use crate::query::{field_names, matches_item, QueryTerm};
+use crate::runs::{ImportRun, LogEntry};
+use crate::similarity::{normalize, similarity};
+use crate::tags::{
+    analyze_file, is_audio_file, is_readable, AnalyzeOptions, FileAnalysis, FileOps, StdFileOps,
+};
+use crate::{Album, AudioFormat, Error, Item, Result};
 
+/// Default minimum normalized score for accepting a `MusicBrainz` release match.
+pub const DEFAULT_MIN_MATCH_SCORE: f64 = 0.6;
+
+/// Default number of albums looked up concurrently during import.
+pub const DEFAULT_CONCURRENCY: usize = 3;
+
+/// Names (without extension) of image files used as an album's art when
+/// they are next to its tracks, most preferred first.
+pub const DEFAULT_ART_FILENAMES: &[&str] = &["cover", "folder", "front"];
+
+/// Number of tracks per album tried against `AcoustID` before giving up.
+const ACOUSTID_SAMPLE_TRACKS: usize = 3;
+
+/// Releases asked for per `MusicBrainz` search. Pressings of one album come
+/// back as separate releases, so this is well above [`CANDIDATE_LIMIT`].
+const SEARCH_LIMIT: u32 = 25;
+
+/// Candidates considered per album, each from a different release group.
+const CANDIDATE_LIMIT: usize = 5;
+
+/// Bytes read and written at a time when copying a file into the library.
+const COPY_CHUNK: usize = 256 * 1024;
+
+/// The album artist of compilations, as `MusicBrainz` credits them.
+pub const VARIOUS_ARTISTS: &str = "Various Artists";
+
+/// How imported files get into the library. The linking actions fall back
+/// to copying, with a warning, where the platform or filesystem can't link.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
 #[serde(rename_all = "lowercase")]
 pub enum Action {
     #[default]
     Copy,
     Move,
+    /// Symbolic link to the original.
     Link,
+    /// Hard link to the original; it must be on the same filesystem.
+    HardLink,
+    /// Copy-on-write clone, on filesystems that support it (Btrfs, XFS, APFS).
+    Reflink,
+}
+
+impl Action {
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Copy => "copy",
+            Self::Move => "move",
+            Self::Link => "link",
+            Self::HardLink => "hardlink",
+            Self::Reflink => "reflink",
+        }
+    }
+}
+
+/// Whether an imported album is added to a matching album already in the
+/// library, such as a second disc imported after the first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MergePolicy {
+    /// Ask with [`ImportConfig::confirm_merge`]; without it, don't merge.
+    #[default]
+    Ask,
+    Always,
+    Never,
+}
+
+/// What happens when a file's destination is already taken by another file
+/// or a library track.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictPolicy {
+    /// Leave the file where it is and don't import it.
+    Skip,
+    /// Add ` (1)`, ` (2)` and so on to the file name until it's free.
+    #[default]
+    Rename,
+    /// Replace the file, dropping the library track that was there.
+    Overwrite,
+}
+
+/// What a quiet import does with an album that has no good enough match.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum QuietFallback {
+    /// Import it with the metadata in its files' tags.
+    #[default]
+    AsIs,
+    /// Leave its files alone and report it.
+    Skip,
+}
+
+/// A file whose destination was taken.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Collision {
+    pub source: PathBuf,
+    /// Where the path format put it.
+    pub wanted: PathBuf,
+    pub resolution: Resolution,
+}
+
+impl fmt::Display for Collision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let source = self.source.display();
+        let wanted = self.wanted.display();
+        match &self.resolution {
+            Resolution::Renamed(dest) => write!(f, "{source}: renamed to {}", dest.display()),
+            Resolution::Skipped => write!(f, "{source}: skipped, {wanted} is taken"),
+            Resolution::Overwritten => write!(f, "{source}: replaced {wanted}"),
+        }
+    }
+}
+
+/// What was done about a [`Collision`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resolution {
+    /// Imported under this path instead.
+    Renamed(PathBuf),
+    Skipped,
+    Overwritten,
 }
 
+#[derive(Clone)]
 pub struct ImportConfig {
     pub action: Action,
     pub fetch_art: bool,
-    pub path_format: String,
+    /// Names of image files in an album's source directory used as its art
+    /// (see [`DEFAULT_ART_FILENAMES`]).
+    pub art_filenames: Vec<String>,
+    /// Fetch art even for albums that come with their own.
+    pub prefer_caa_art: bool,
+    pub path_formats: PathFormats,
     pub library_dir: PathBuf,
+    /// Minimum normalized (0..1) score for accepting a `MusicBrainz` match.
+    pub min_match_score: f64,
+    pub preferences: ReleasePreferences,
+    /// Timeout and User-Agent contact for `MusicBrainz` requests.
+    pub musicbrainz: ClientSettings,
+    /// `AcoustID` key enabling fingerprint lookup when tag search fails.
+    pub acoustid_api_key: Option<String>,
+    /// Maximum number of albums looked up concurrently.
+    pub concurrency: usize,
+    /// Only import albums whose first track matches these terms (see
+    /// [`only_filter`]).
+    pub only: Option<Vec<QueryTerm>>,
+    pub merge_into_existing: MergePolicy,
+    pub on_conflict: ConflictPolicy,
+    /// What happens to an album without a good enough match.
+    pub quiet_fallback: QuietFallback,
+    /// Names of files (see [`crate::prune::DEFAULT_CLUTTER`]) that don't
+    /// keep a directory emptied by moving its tracks from being removed.
+    pub clutter: Vec<String>,
+    /// Leave the directories moved tracks came from even when empty.
+    pub keep_empty_dirs: bool,
+    /// Import from paths inside `library_dir`, leaving out the files already
+    /// in the library, rather than refusing to.
+    pub inside_library: bool,
+    /// Extensions besides those of [`crate::AudioFormat`] imported as audio.
+    pub extra_extensions: Vec<String>,
+    /// Rules giving the genres read from tags their canonical names.
+    pub genres: Genres,
+    /// Which of the files found are read at all.
+    pub scan: ScanOptions,
+    /// Asks whether to add an album to the existing one, given the question.
+    pub confirm_merge: Option<fn(&str) -> bool>,
+    /// Shows what matching an album to its release changes in its tracks,
+    /// before they're imported.
+    pub show_changes: Option<fn(&AlbumChanges)>,
+}
+
+impl ImportConfig {
+    /// Refuse `path` if it is inside the library directory, where its files
+    /// are the library's own, unless `inside_library` allows it.
+    ///
+    /// # Errors
+    /// Returns an error if `path` is inside the library.
+    pub fn check_source(&self, path: &Path) -> Result<()> {
+        if self.inside_library || library_relative(path, &self.library_dir).is_none() {
+            return Ok(());
+        }
+        Err(Error::Import(format!(
+            "{} is inside the library directory {}; use `rsbts update` or `rsbts retag` for \
+             files already there, or pass --force-inside-library to import only those that \
+             aren't",
+            path.display(),
+            self.library_dir.display()
+        )))
+    }
+}
+
+/// Ordered preferences used to break ties between near-identical releases.
+#[derive(Debug, Clone, Default)]
+pub struct ReleasePreferences {
+    /// Release countries (e.g. `US`), most preferred first.
+    pub countries: Vec<String>,
+    /// Medium formats (e.g. `CD`), most preferred first.
+    pub media: Vec<String>,
+}
+
+/// A candidate release together with its normalized (0..1) similarity score.
+#[derive(Debug, Clone, Copy)]
+pub struct ReleaseMatch<'r> {
+    pub release: &'r Release,
+    pub score: f64,
 }
 
-pub struct Importer<'a> {
+/// Imports albums into the library, looking them up in a [`MetadataSource`]:
+/// `MusicBrainz` unless another source is given with
+/// [`Importer::with_source`].
+pub struct Importer<'a, S = MbClient> {
     db: &'a Database,
     config: ImportConfig,
-    mb: MbClient,
+    resolver: Arc<Resolver<S>>,
+    /// Recorded on every album and item this importer adds.
+    run: ImportRun,
+    /// Whether falling back to copying has been reported.
+    fallback_warned: Cell<bool>,
+    /// Whether the metadata source could be reached, once it has been
+    /// checked. Albums are imported from their tags alone when it couldn't.
+    online: Cell<Option<bool>>,
+    progress: Box<dyn ImportProgress>,
+    /// Run for items an overwritten file takes out of the library.
+    hooks: Hooks,
+}
+
+/// A library album as `MusicBrainz` has it, found by [`Importer::rematch`].
+#[derive(Debug)]
+pub struct Rematch {
+    /// The album with the release's title, artist, year and id.
+    pub album: Album,
+    /// The album's tracks in the order given, matched to the release's.
+    pub items: Vec<Item>,
+    /// Cover art for an album that has none, and where it goes: next to
+    /// the album's files, where no file is already.
+    pub cover_art: Option<(PathBuf, Vec<u8>)>,
+}
+
+/// Network-bound release lookup, shared by concurrent lookup tasks.
+struct Resolver<S> {
+    mb: MetadataCache<S>,
+    acoustid: Option<AcoustIdClient>,
+    fetch_art: bool,
+    prefer_caa_art: bool,
+    min_match_score: f64,
+    preferences: ReleasePreferences,
 }
 
 #[derive(Debug)]
@@ -75,97 +326,627 @@ struct AlbumCandidate {
     items: Vec<Item>,
     artist: String,
     album: String,
+    /// Probably a compilation: its artist is [`VARIOUS_ARTISTS`], as tagged
+    /// or because its tracks' artists differ.
+    compilation: bool,
+    /// The cue sheet of an album ripped to one file (see [`cue_rip`]).
+    cue_sheet: Option<CueRip>,
+}
+
+/// An album's audio file and the cue sheet listing its tracks, which goes
+/// into the library along with it.
+#[derive(Debug)]
+struct CueRip {
+    audio: PathBuf,
+    sheet: PathBuf,
+}
+
+impl AlbumCandidate {
+    /// The `MusicBrainz` release id all of the candidate's files are tagged
+    /// with, as by Picard; `None` if any lacks one or they differ.
+    fn tagged_release(&self) -> Option<&str> {
+        let (first, rest) = self.items.split_first()?;
+        let id = first.mb_albumid.as_deref()?;
+        rest.iter().all(|item| item.mb_albumid.as_deref() == Some(id)).then_some(id)
+    }
+
+    /// Deepest directory containing all of the candidate's files.
+    fn source_dir(&self) -> Option<PathBuf> {
+        let mut dirs = self.items.iter().filter_map(|item| item.path.parent());
+        let mut common = dirs.next()?.to_path_buf();
+        for dir in dirs {
+            while !dir.starts_with(&common) {
+                if !common.pop() {
+                    return None;
+                }
+            }
+        }
+        Some(absolute(&common))
+    }
+}
+
+/// What became of an album once its lookup was done.
+#[derive(Debug)]
+enum AlbumOutcome {
+    /// Imported, with the files whose destination was taken.
+    Imported(Vec<Collision>),
+    /// Left alone for lack of a good enough match, with where it is and
+    /// what imports it again (see [`ScanReport::retry`]).
+    Unmatched(PathBuf, Vec<PathBuf>),
+}
+
+/// Outcome of looking up one album candidate, ready to be written out.
+struct ResolvedAlbum {
+    candidate: AlbumCandidate,
+    release: Option<Release>,
+    cover_art: Option<Vec<u8>>,
+    /// Progress messages, printed once the album is written so output from
+    /// concurrent lookups doesn't interleave.
+    notes: Vec<String>,
 }
 
 impl<'a> Importer<'a> {
-    /// Create a new importer.
+    /// Create a new importer that looks albums up on `MusicBrainz`.
     ///
     /// # Errors
-    /// Returns an error if the HTTP client cannot be created.
+    /// Returns an error if the HTTP client or the metadata cache's database
+    /// connection cannot be created.
     pub fn new(db: &'a Database, config: ImportConfig) -> Result<Self> {
+        let client = MbClient::with_settings(&config.musicbrainz)?;
+        Self::with_source(db, config, client)
+    }
+}
+
+impl<'a, S: MetadataSource + 'static> Importer<'a, S> {
+    /// Create a new importer that looks albums up in `source`, through the
+    /// metadata cache. With [`NullSource`](crate::musicbrainz::NullSource),
+    /// albums are imported from their file tags alone.
+    ///
+    /// # Errors
+    /// Returns an error if the `AcoustID` client or the metadata cache's
+    /// database connection cannot be created.
+    pub fn with_source(db: &'a Database, config: ImportConfig, source: S) -> Result<Self> {
+        let acoustid = config
+            .acoustid_api_key
+            .as_deref()
+            .map(str::trim)
+            .filter(|key| !key.is_empty())
+            .map(AcoustIdClient::new)
+            .transpose()?;
+        let resolver = Resolver {
+            mb: MetadataCache::new(source, db.connect()?),
+            acoustid,
+            fetch_art: config.fetch_art,
+            prefer_caa_art: config.prefer_caa_art,
+            min_match_score: config.min_match_score,
+            preferences: config.preferences.clone(),
+        };
         Ok(Self {
             db,
+            run: ImportRun::new(config.action),
             config,
-            mb: MbClient::new()?,
+            resolver: Arc::new(resolver),
+            fallback_warned: Cell::new(false),
+            online: Cell::new(None),
+            progress: Box::new(NoProgress),
+            hooks: Hooks::default(),
         })
     }
 
-    /// Import audio files from the given path.
+    /// Report progress to `progress` instead of not at all.
+    #[must_use]
+    pub fn with_progress(mut self, progress: impl ImportProgress + 'static) -> Self {
+        self.progress = Box::new(progress);
+        self
+    }
+
+    /// Run `hooks` for library items whose files are replaced under
+    /// `on_conflict = overwrite`.
+    #[must_use]
+    pub fn with_hooks(mut self, hooks: Hooks) -> Self {
+        self.hooks = hooks;
+        self
+    }
+
+    /// Id of this importer's run, for `rsbts undo --session`.
+    pub fn run_id(&self) -> &str {
+        &self.run.id
+    }
+
+    /// Metadata lookups answered from the cache and from the network so far.
+    pub fn cache_counts(&self) -> (u64, u64) {
+        (self.resolver.mb.hits(), self.resolver.mb.misses())
+    }
+
+    /// Look a library album up again as importing it would, given its row
+    /// and its tracks, without changing anything. `None` if no release
+    /// matches well enough.
+    ///
+    /// # Errors
+    /// Returns an error if the lookup fails.
+    // rusqlite::Connection is not Sync, so futures holding &Database aren't Send
+    #[allow(clippy::future_not_send)]
+    pub async fn rematch(&self, album: &Album, items: Vec<Item>) -> Result<Option<Rematch>> {
+        let candidate = AlbumCandidate {
+            items,
+            compilation: is_various(&album.albumartist),
+            cue_sheet: None,
+            artist: album.albumartist.clone(),
+            album: album.album.clone(),
+        };
+        let mut notes = Vec::new();
+        let release = self.resolver.lookup_release(&candidate, &mut notes).await?;
+        for note in &notes {
+            info!("  {note}");
+        }
+        let Some(release) = release else {
+            return Ok(None);
+        };
+
+        let cover_art = match candidate.source_dir() {
+            Some(dir) if self.config.fetch_art && album.artpath.is_none() => {
+                let path = dir.join("cover.jpg");
+                // Not the album's art as far as the library knows, but not ours to replace
+                if path.exists() {
+                    info!("  Leaving {}, which isn't the album's art", path.display());
+                    None
+                } else {
+                    self.resolver.cover_art(&release).await.map(|art| (path, art))
+                }
+            }
+            _ => None,
+        };
+        let matched = Album {
+            album: release.title.clone(),
+            albumartist: release.artist_name(),
+            year: release.year().or(album.year),
+            mb_albumid: Some(release.id.clone()),
+            mb_releasegroupid: release.release_group_id().map(Into::into),
+            ..album.clone()
+        };
+        let items = match_tracks(candidate.items, &release)
+            .into_iter()
+            .map(|item| into_album(item, &matched))
+            .collect();
+        Ok(Some(Rematch {
+            album: matched,
+            items,
+            cover_art,
+        }))
+    }
+
+    /// Import audio files from the given path, and the albums in archives
+    /// there (see [`Importer::import_bundle`]). Moving files removes the
+    /// directories they leave empty below `path`, unless `keep_empty_dirs`.
+    /// Callers refuse a `path` inside the library first, with
+    /// [`Importer::check_source`].
+    ///
+    /// Release lookups run concurrently (up to `concurrency` at a time, sharing
+    /// the `MusicBrainz` rate limit), while database writes and file transfers
+    /// happen on this task as each lookup completes.
     ///
     /// # Errors
     /// Returns an error if scanning or importing fails.
     // rusqlite::Connection is not Sync, so futures holding &Database aren't Send
     #[allow(clippy::future_not_send)]
-    pub async fn import(&self, path: &Path) -> Result<()> {
-        let items = scan(path);
-        if items.is_empty() {
-            println!("No audio files found in {}", path.display());
-            return Ok(());
+    pub async fn import(&self, path: &Path) -> Result<ScanReport> {
+        let files = self.config.scan.files(path, &self.config.extra_extensions);
+        let bundles = crate::bundle::bundles(path);
+        if files.is_empty() && bundles.is_empty() {
+            warn!("No audio files found in {}", path.display());
+            return Ok(ScanReport::default());
+        }
+        let mut report = if files.is_empty() {
+            ScanReport::default()
+        } else {
+            self.import_from(files, None, Some(path)).await?
+        };
+        for bundle in bundles {
+            report.extend(self.import_bundle(&bundle).await?);
         }
+        Ok(report)
+    }
 
-        let candidates = group_into_albums(items);
+    /// Refuse `path` if it is inside the library directory (see
+    /// [`ImportConfig::check_source`]).
+    ///
+    /// # Errors
+    /// Returns an error if `path` is inside the library.
+    pub fn check_source(&self, path: &Path) -> Result<()> {
+        self.config.check_source(path)
+    }
 
-        for candidate in candidates {
-            self.process_candidate(candidate).await?;
+    /// Import the albums in the `.zip` or `.tar.gz` archive at `path`. Its
+    /// files are extracted and moved into the library whatever the action,
+    /// and a cover image in it is used instead of downloading one. An
+    /// archive that can't be extracted is reported as a failure.
+    ///
+    /// # Errors
+    /// Returns an error if importing fails.
+    // rusqlite::Connection is not Sync, so futures holding &Database aren't Send
+    #[allow(clippy::future_not_send)]
+    pub async fn import_bundle(&self, path: &Path) -> Result<ScanReport> {
+        let bundle = match Bundle::extract(path, &self.config.extra_extensions) {
+            Ok(bundle) => bundle,
+            Err(e) => {
+                return Ok(ScanReport {
+                    failures: vec![(path.to_path_buf(), e)],
+                    ..ScanReport::default()
+                });
+            }
+        };
+        if bundle.audio.is_empty() {
+            warn!("No audio files found in {}", path.display());
+            return Ok(ScanReport::default());
         }
+        info!("Extracted {} files from {}", bundle.audio.len(), path.display());
+        self.import_from(bundle.audio.clone(), Some(&bundle), None).await
+    }
 
-        Ok(())
+    /// Import the given audio files, grouping them into albums by their tags.
+    ///
+    /// # Errors
+    /// Returns an error if importing fails.
+    // rusqlite::Connection is not Sync, so futures holding &Database aren't Send
+    #[allow(clippy::future_not_send)]
+    pub async fn import_files(&self, files: Vec<PathBuf>) -> Result<ScanReport> {
+        self.import_from(files, None, None).await
     }
 
+    /// Import `files`, which were extracted from `bundle` if there is one,
+    /// pruning the directories moved files leave empty below `root`.
     // rusqlite::Connection is not Sync, so futures holding &Database aren't Send
     #[allow(clippy::future_not_send)]
-    async fn process_candidate(&self, candidate: AlbumCandidate) -> Result<()> {
-        println!(
-            "\nImporting: {} - {} ({} tracks)",
-            candidate.artist,
-            candidate.album,
-            candidate.items.len()
-        );
+    async fn import_from(
+        &self,
+        files: Vec<PathBuf>,
+        bundle: Option<&Bundle>,
+        root: Option<&Path>,
+    ) -> Result<ScanReport> {
+        let files = if self.config.inside_library {
+            self.new_files(files)?
+        } else {
+            files
+        };
+        let ScanResult {
+            mut items,
+            failures,
+            suspicious,
+        } = scan(files, &self.config.scan, &*self.progress);
+        for item in &mut items {
+            self.config.genres.normalize_item(item);
+        }
+        let (candidates, skipped) =
+            select_candidates(group_into_albums(items), self.config.only.as_deref())?;
+        let mut report = ScanReport {
+            failures,
+            suspicious,
+            skipped,
+            unmatched: Vec::new(),
+            retry: Vec::new(),
+            collisions: Vec::new(),
+            failed_albums: Vec::new(),
+        };
 
-        let release_info = self.lookup_release(&candidate).await?;
-        let album = Self::create_album(&candidate, release_info.as_ref());
-        let album_id = self.db.insert_album(&album)?;
+        let bundle_art = bundle.and_then(|b| b.cover.as_deref()).and_then(read_art);
+        let lookup = candidates.is_empty() || self.check_online().await?;
 
-        self.fetch_and_save_cover_art(&album, release_info.as_ref())
-            .await;
+        self.progress.on_albums_found(candidates.len());
+        let permits = Arc::new(Semaphore::new(self.config.concurrency.max(1)));
+        let mut lookups = JoinSet::new();
+        for mut candidate in candidates {
+            let local_art = bundle_art
+                .clone()
+                .or_else(|| local_art(&candidate, &self.config.art_filenames));
+            let fetch_art = !self.merges_into_art(&candidate)?;
+            let name = format!("{} - {}", candidate.artist, candidate.album);
+            candidate.cue_sheet = cue_rip(&candidate, &name).await;
+            let lookup = lookup && candidate.cue_sheet.is_none();
+            let resolver = Arc::clone(&self.resolver);
+            let permits = Arc::clone(&permits);
+            lookups.spawn(async move {
+                let resolved = async {
+                    let _permit = permits
+                        .acquire_owned()
+                        .await
+                        .map_err(|e| Error::Import(e.to_string()))?;
+                    resolver.resolve(candidate, local_art, lookup, fetch_art).await
+                }
+                .await;
+                (name, resolved)
+            });
+        }
 
-        let matched_items = Self::match_items_to_release(candidate.items, release_info.as_ref());
-        self.import_items(matched_items, album_id)?;
+        // Dropping the set on error aborts the lookups still in flight
+        let pruner = root
+            .filter(|_| self.config.action == Action::Move && !self.config.keep_empty_dirs)
+            .map(|root| Pruner::new(root, &self.config.clutter));
+        let mut position = 0;
+        while let Some(joined) = lookups.join_next().await {
+            let (name, resolved) = joined.map_err(|e| Error::Import(e.to_string()))?;
+            position += 1;
+            let written = resolved.and_then(|resolved| {
+                let bytes = resolved.candidate.items.iter().map(file_size).sum();
+                self.progress.on_album_start(position, &name, bytes);
+                self.process_resolved(resolved, bundle, pruner.as_ref())
+            });
+            self.progress.on_album_done();
+            match written {
+                Ok(AlbumOutcome::Imported(collisions)) => report.collisions.extend(collisions),
+                Ok(AlbumOutcome::Unmatched(source, retry)) => {
+                    report.unmatched.push((name, source));
+                    report.retry.extend(retry);
+                }
+                Err(e) => {
+                    warn!("Could not import {name}: {e}");
+                    report.failed_albums.push((name, e));
+                }
+            }
+        }
 
-        println!("  Imported successfully");
-        Ok(())
+        Ok(report)
     }
 
-    /// Look up release information from `MusicBrainz`.
+    /// Whether the metadata source can be reached, checking the first time
+    /// only. A source that can't be reached at all is reported once, and the
+    /// rest of the run goes without lookups rather than failing every album.
+    ///
+    /// # Errors
+    /// Returns an error if the check fails for another reason.
+    // rusqlite::Connection is not Sync, so futures holding &Database aren't Send
     #[allow(clippy::future_not_send)]
-    async fn lookup_release(&self, candidate: &AlbumCandidate) -> Result<Option<Release>> {
-        let releases = self
-            .mb
-            .search_release(&candidate.artist, &candidate.album, 5)
-            .await?;
+    async fn check_online(&self) -> Result<bool> {
+        if let Some(online) = self.online.get() {
+            return Ok(online);
+        }
+        let online = match self.resolver.mb.check_reachable().await {
+            Ok(()) => true,
+            Err(Error::MusicBrainz(e)) if e.is_unreachable() => {
+                warn!("MusicBrainz unreachable ({e}), importing without autotagging");
+                false
+            }
+            Err(e) => return Err(e),
+        };
+        self.online.set(Some(online));
+        Ok(online)
+    }
 
-        if releases.is_empty() {
-            println!("  No MusicBrainz matches found, importing as-is");
+    /// What imports `candidate` again and nothing else: `dir`, the directory
+    /// its files are in, when they are all it holds, or else its files.
+    fn retry_paths(&self, candidate: &AlbumCandidate, dir: &Path) -> Vec<PathBuf> {
+        let own: Vec<PathBuf> = candidate.items.iter().map(|item| absolute(&item.path)).collect();
+        let others = audio_files(dir, &self.config.extra_extensions)
+            .iter()
+            .any(|path| !own.contains(&absolute(path)));
+        if others || !crate::bundle::bundles(dir).is_empty() {
+            own
+        } else {
+            vec![dir.to_path_buf()]
+        }
+    }
+
+    /// `files` without those already in the library, which are left before
+    /// their tags are read so they can't make albums of their own.
+    fn new_files(&self, files: Vec<PathBuf>) -> Result<Vec<PathBuf>> {
+        let mut new = Vec::with_capacity(files.len());
+        for path in files {
+            if self.is_library_item(&path)? {
+                debug!("Already in the library: {}", path.display());
+            } else {
+                new.push(path);
+            }
+        }
+        Ok(new)
+    }
+
+    /// Whether the file at `path` is a library item, by this path, its
+    /// canonical one, or the one it has below `library_dir` as configured
+    /// (which may go through a symlink the canonical one doesn't).
+    fn is_library_item(&self, path: &Path) -> Result<bool> {
+        if self.db.item_exists(path)? || self.db.item_exists(&absolute(path))? {
+            return Ok(true);
+        }
+        library_relative(path, &self.config.library_dir).map_or(Ok(false), |relative| {
+            self.db.item_exists(&self.config.library_dir.join(relative))
+        })
+    }
+
+    fn process_resolved(
+        &self,
+        resolved: ResolvedAlbum,
+        bundle: Option<&Bundle>,
+        pruner: Option<&Pruner>,
+    ) -> Result<AlbumOutcome> {
+        let ResolvedAlbum {
+            candidate,
+            release,
+            cover_art,
+            notes,
+        } = resolved;
+
+        if release.is_none() && self.config.quiet_fallback == QuietFallback::Skip {
+            info!("Skipping: {} - {} (no match)", candidate.artist, candidate.album);
+            for note in &notes {
+                info!("  {note}");
+            }
+            let source = bundle
+                .map(|bundle| bundle.path.clone())
+                .or_else(|| candidate.source_dir());
+            let retry = match &source {
+                Some(archive) if bundle.is_some() => vec![archive.clone()],
+                Some(dir) => self.retry_paths(&candidate, dir),
+                // Nothing would import this album alone
+                None => Vec::new(),
+            };
+            return Ok(AlbumOutcome::Unmatched(source.unwrap_or_default(), retry));
+        }
+
+        info!(
+            "Importing: {} - {} ({} tracks)",
+            candidate.artist,
+            candidate.album,
+            candidate.items.len()
+        );
+        for note in &notes {
+            info!("  {note}");
+        }
+
+        let items = self.match_items_to_release(candidate.items.clone(), release.as_ref());
+        let (items, album_id, cover_art) =
+            match self.merge_target(&candidate, release.as_ref(), &items)? {
+                Some((existing, album_id)) => {
+                    self.db.record_import_run(&self.run)?;
+                    info!(
+                        "  Adding to existing album: {} - {}",
+                        existing.albumartist, existing.album
+                    );
+                    let new = self.create_album(&candidate, release.as_ref());
+                    let existing = self.fill_album(existing, album_id, &new)?;
+                    let items: Vec<Item> =
+                        items.into_iter().map(|item| into_album(item, &existing)).collect();
+                    // Art is only added to an album that has none
+                    let cover_art = cover_art.filter(|_| existing.artpath.is_none());
+                    (items, album_id, cover_art)
+                }
+                None => {
+                    let (album, album_id) = self.add_album(&candidate, release.as_ref(), bundle)?;
+                    // Keep a compilation's tracks together rather than under each artist
+                    let items = if candidate.compilation {
+                        items
+                            .into_iter()
+                            .map(|mut item| {
+                                item.albumartist
+                                    .get_or_insert_with(|| album.albumartist.clone());
+                                item
+                            })
+                            .collect()
+                    } else {
+                        items
+                    };
+                    (items, album_id, cover_art)
+                }
+            };
+        let rip = candidate.cue_sheet.as_ref();
+        let collisions = self.import_items(items, album_id, bundle, rip)?;
+        if let Some(art) = cover_art {
+            self.save_cover_art(album_id, &art)?;
+        }
+        // A rip's cue sheet that stayed behind is no clutter to prune
+        if let Some(pruner) = pruner.filter(|_| !rip.is_some_and(|rip| rip.sheet.exists())) {
+            let dirs = candidate.items.iter().filter_map(|item| item.path.parent());
+            pruner.prune(dirs.map(Path::to_path_buf));
+        }
+
+        info!("  Imported successfully");
+        Ok(AlbumOutcome::Imported(collisions))
+    }
+
+    /// The library album a candidate should be added to instead of becoming
+    /// an album of its own: the [`existing_album`](Self::existing_album)
+    /// like it, if the candidate repeats none of its tracks, as allowed by
+    /// the merge policy.
+    fn merge_target(
+        &self,
+        candidate: &AlbumCandidate,
+        release: Option<&Release>,
+        items: &[Item],
+    ) -> Result<Option<(Album, i64)>> {
+        if self.config.merge_into_existing == MergePolicy::Never {
             return Ok(None);
         }
+        let Some((existing, album_id)) = self
+            .existing_album(&self.create_album(candidate, release))?
+            .and_then(|album| album.id.map(|id| (album, id)))
+        else {
+            return Ok(None);
+        };
 
-        let Some(best) = pick_best_match(candidate, &releases) else {
+        let present = self.db.album_items(album_id)?;
+        if items.iter().any(|item| present.iter().any(|p| same_track(item, p))) {
+            info!(
+                "  Some tracks are already in {} - {}; importing as a separate album",
+                existing.albumartist, existing.album
+            );
             return Ok(None);
+        }
+
+        let merge = match self.config.merge_into_existing {
+            MergePolicy::Always => true,
+            MergePolicy::Never => false,
+            MergePolicy::Ask => self.config.confirm_merge.is_some_and(|confirm| {
+                confirm(&format!(
+                    "Add {} tracks to {} - {}, which has {} already?",
+                    items.len(),
+                    existing.albumartist,
+                    existing.album,
+                    present.len()
+                ))
+            }),
         };
+        Ok(merge.then_some((existing, album_id)))
+    }
 
-        println!(
-            "  Matched: {} - {} ({})",
-            best.artist_name(),
-            best.title,
-            best.year().map_or_else(|| "????".into(), |y| y.to_string())
-        );
+    /// The library album `new` is another part of: the one with its release
+    /// id, or else one by the same name (see [`Database::find_album_like`]).
+    fn existing_album(&self, new: &Album) -> Result<Option<Album>> {
+        if let Some(id) = &new.mb_albumid {
+            if let Some(album) = self.db.find_album_by_mbid(id)? {
+                return Ok(Some(album));
+            }
+        }
+        self.db.find_album_like(new)
+    }
 
-        let release = self.mb.lookup_release(&best.id).await?;
-        Ok(Some(release))
+    /// Whether a candidate will likely be added to an album that already
+    /// has art, going by its tags, so none need be fetched for it.
+    fn merges_into_art(&self, candidate: &AlbumCandidate) -> Result<bool> {
+        if self.config.merge_into_existing == MergePolicy::Never {
+            return Ok(false);
+        }
+        let existing = self.existing_album(&self.create_album(candidate, None))?;
+        Ok(existing.is_some_and(|album| album.artpath.is_some()))
+    }
+
+    /// Give album `album_id` the year and `MusicBrainz` ids of `new` that it
+    /// lacks, on its items too, returning it as it is then. What is filled
+    /// in is recorded, for undoing the run.
+    fn fill_album(&self, existing: Album, album_id: i64, new: &Album) -> Result<Album> {
+        let filled = Album {
+            year: existing.year.or(new.year),
+            mb_albumid: existing.mb_albumid.clone().or_else(|| new.mb_albumid.clone()),
+            mb_releasegroupid: existing
+                .mb_releasegroupid
+                .clone()
+                .or_else(|| new.mb_releasegroupid.clone()),
+            ..existing.clone()
+        };
+        let edits = FieldEdit::diff(ALBUM_FIELDS, &existing, &filled, album_value);
+        if !edits.is_empty() {
+            self.db.modify_album(album_id, &edits)?;
+            self.db.log_fills(&self.run.id, album_id, &edits)?;
+        }
+        Ok(filled)
+    }
+
+    /// Insert the album for a candidate, recording the run with its first
+    /// album. Albums from a bundle record the archive as their source.
+    fn add_album(
+        &self,
+        candidate: &AlbumCandidate,
+        release: Option<&Release>,
+        bundle: Option<&Bundle>,
+    ) -> Result<(Album, i64)> {
+        self.db.record_import_run(&self.run)?;
+        let mut album = self.create_album(candidate, release);
+        if let (Some(bundle), Some(dir)) = (bundle, &album.source_path) {
+            album.source_path = Some(bundle.source_of(dir));
+        }
+        let album_id = self.db.insert_album(&album)?;
+        Ok((album, album_id))
     }
 
     /// Create an Album struct from candidate and optional release info.
-    fn create_album(candidate: &AlbumCandidate, release: Option<&Release>) -> Album {
+    fn create_album(&self, candidate: &AlbumCandidate, release: Option<&Release>) -> Album {
         Album {
             id: None,
             album: release.map_or_else(|| candidate.album.clone(), |r| r.title.clone()),
@@ -173,131 +954,645 @@ impl<'a> Importer<'a> {
             year: release.and_then(Release::year),
             artpath: None,
             mb_albumid: release.map(|r| r.id.clone()),
+            mb_releasegroupid: release.and_then(Release::release_group_id).map(Into::into),
             added: chrono::Utc::now(),
+            source_path: candidate.source_dir(),
+            import_run: Some(self.run.id.clone()),
+            disambiguation: release
+                .map(|r| r.disambiguation.clone())
+                .filter(|disambiguation| !disambiguation.is_empty()),
         }
     }
 
-    /// Fetch and save cover art if configured and available.
-    #[allow(clippy::future_not_send)]
-    async fn fetch_and_save_cover_art(&self, album: &Album, release: Option<&Release>) {
-        if !self.config.fetch_art {
-            return;
-        }
-
-        let Some(release) = release else {
-            return;
-        };
-
-        let Ok(Some(art)) = self.mb.fetch_cover_art(&release.id).await else {
-            return;
+    /// Write cover art next to the files of album `album_id` in the library
+    /// and make it the album's art.
+    fn save_cover_art(&self, album_id: i64, art: &[u8]) -> Result<()> {
+        let Some(dir) = crate::art::album_dir(&self.db.album_items(album_id)?) else {
+            return Ok(());
         };
-
-        let art_path = self.config.library_dir.join(format!(
-            "{}/{}/cover.jpg",
-            album.albumartist, album.album
-        ));
-
-        if let Some(parent) = art_path.parent() {
-            if std::fs::create_dir_all(parent).is_err() {
-                return;
+        let art_path = dir.join(crate::art::file_name(art));
+        match std::fs::write(&art_path, art) {
+            Ok(()) => {
+                self.db.set_album_artpath(album_id, &art_path)?;
+                info!("  Saved cover art as {}", art_path.display());
+                let entry = LogEntry::new(&self.run, None, None, art_path);
+                self.db.log_import(&LogEntry {
+                    album_id: Some(album_id),
+                    ..entry
+                })?;
             }
+            Err(e) => warn!("Could not save cover art to {}: {e}", art_path.display()),
         }
-
-        if std::fs::write(&art_path, art).is_ok() {
-            println!("  Downloaded cover art");
-        }
+        Ok(())
     }
 
-    /// Match items to release tracks if release info is available.
-    fn match_items_to_release(items: Vec<Item>, release: Option<&Release>) -> Vec<Item> {
-        match release {
-            Some(rel) => match_tracks(items, rel),
-            None => items,
+    /// Match items to release tracks if release info is available, showing
+    /// what that changes first.
+    fn match_items_to_release(&self, items: Vec<Item>, release: Option<&Release>) -> Vec<Item> {
+        let Some(release) = release else {
+            return items;
+        };
+        let matches = assign_tracks(&items, release);
+        if let Some(show) = self.config.show_changes {
+            show(&AlbumChanges::new(&items, release, &matches));
         }
+        apply_matches(items, release, &matches)
     }
 
-    /// Import matched items into the database.
-    fn import_items(&self, items: Vec<Item>, album_id: i64) -> Result<()> {
-        for mut item in items {
+    /// Import matched items into the database, returning those whose
+    /// destination was taken and what was done about it. Files extracted
+    /// from a bundle are always moved, and record where they are in it as
+    /// their source.
+    fn import_items(
+        &self,
+        items: Vec<Item>,
+        album_id: i64,
+        bundle: Option<&Bundle>,
+        rip: Option<&CueRip>,
+    ) -> Result<Vec<Collision>> {
+        let action = if bundle.is_some() {
+            Action::Move
+        } else {
+            self.config.action
+        };
+        let mut placements = Vec::new();
+        for item in items {
             if self.db.item_exists(&item.path)? {
+                debug!("Already in the library: {}", item.path.display());
                 continue;
             }
+            let wanted = self.destination_path(&item)?;
+            placements.push((item, wanted.clone(), wanted));
+        }
+        disambiguate(&mut placements)?;
+        let mut claimed: HashSet<PathBuf> = placements.iter().map(|p| p.2.clone()).collect();
 
-            item.album_id = Some(album_id);
+        let mut collisions = Vec::new();
+        for (mut item, wanted, dest) in placements {
+            let Some(dest) = self.claim(&item.path, &wanted, dest, &mut claimed, &mut collisions)?
+            else {
+                continue;
+            };
+            if dest != wanted {
+                collisions.push(Collision {
+                    source: item.path.clone(),
+                    wanted,
+                    resolution: Resolution::Renamed(dest.clone()),
+                });
+            }
 
-            let dest = self.destination_path(&item)?;
+            item.album_id = Some(album_id);
+            item.import_run = Some(self.run.id.clone());
+            // Record provenance before the path is rewritten
+            item.source_path = Some(
+                bundle.map_or_else(|| absolute(&item.path), |bundle| bundle.source_of(&item.path)),
+            );
 
-            self.transfer_file(&item.path, &dest)?;
+            let done = if is_same_file(&item.path, &dest) {
+                debug!("Already in place: {}", dest.display());
+                None
+            } else {
+                let done = transfer_file(action, &item.path, &dest, &*self.progress)?;
+                debug!("{} {} -> {}", done.as_str(), item.path.display(), dest.display());
+                if done != action && !self.fallback_warned.replace(true) {
+                    warn!(
+                        "Could not {} files into the library; copying them instead \
+                         (-v for details)",
+                        self.config.action.as_str()
+                    );
+                }
+                Some(done)
+            };
+            if let (Some(done), Some(rip)) = (done, rip.filter(|rip| rip.audio == item.path)) {
+                self.transfer_cue_sheet(done, rip, &dest, album_id)?;
+            }
             item.path = dest;
+            // Copies get a fresh mtime; record what `check` will see later
+            if let Ok(metadata) = std::fs::metadata(&item.path) {
+                if let Ok(mtime) = metadata.modified() {
+                    item.mtime = mtime.into();
+                }
+                item.size = Some(metadata.len());
+            }
 
-            self.db.insert_item(&item)?;
+            let item_id = self.db.insert_item(&item)?;
+            if let Some(done) = done {
+                let entry = LogEntry::new(&self.run, Some(done), item.source_path, item.path);
+                self.db.log_import(&LogEntry {
+                    item_id: Some(item_id),
+                    album_id: Some(album_id),
+                    ..entry
+                })?;
+            }
         }
-        Ok(())
+        Ok(collisions)
     }
 
-    fn destination_path(&self, item: &Item) -> Result<PathBuf> {
-        let relative = format_path(&self.config.path_format, item)?;
-        let ext = item
-            .path
-            .extension()
-            .and_then(|e| e.to_str())
-            .unwrap_or("mp3");
-        Ok(self.config.library_dir.join(format!("{relative}.{ext}")))
+    /// Bring a rip's cue sheet along with its audio file, now at `audio`, as
+    /// `done` brought that, named after it so the sheet is still found.
+    fn transfer_cue_sheet(
+        &self,
+        done: Action,
+        rip: &CueRip,
+        audio: &Path,
+        album_id: i64,
+    ) -> Result<()> {
+        let dest = audio.with_extension("cue");
+        if dest.exists() {
+            warn!("Not replacing {} with cue sheet {}", dest.display(), rip.sheet.display());
+            return Ok(());
+        }
+        let done = match transfer_file(done, &rip.sheet, &dest, &NoProgress) {
+            Ok(done) => done,
+            Err(e) => {
+                warn!("Could not bring cue sheet {} along: {e}", rip.sheet.display());
+                return Ok(());
+            }
+        };
+        debug!("{} {} -> {}", done.as_str(), rip.sheet.display(), dest.display());
+        let entry = LogEntry::new(&self.run, Some(done), Some(absolute(&rip.sheet)), dest);
+        self.db.log_import(&LogEntry {
+            album_id: Some(album_id),
+            ..entry
+        })
     }
 
-    fn transfer_file(&self, src: &Path, dest: &Path) -> Result<()> {
-        if let Some(parent) = dest.parent() {
-            std::fs::create_dir_all(parent)?;
+    /// Where the file at `source` goes, given the path format's `wanted`
+    /// destination and `dest` after making the album's distinct. If `dest` is
+    /// taken by another file or a library item, the conflict policy decides;
+    /// `None` means the file is skipped. `claimed` holds the destinations of
+    /// the album's files.
+    fn claim(
+        &self,
+        source: &Path,
+        wanted: &Path,
+        dest: PathBuf,
+        claimed: &mut HashSet<PathBuf>,
+        collisions: &mut Vec<Collision>,
+    ) -> Result<Option<PathBuf>> {
+        let taken = |path: &Path| -> Result<bool> {
+            let exists = path.symlink_metadata().is_ok() && !is_same_file(source, path);
+            Ok(exists || self.db.item_exists(path)?)
+        };
+        if !taken(&dest)? {
+            return Ok(Some(dest));
         }
-
-        match self.config.action {
-            Action::Copy => {
-                std::fs::copy(src, dest)?;
+        let resolution = match self.config.on_conflict {
+            ConflictPolicy::Rename => {
+                let renamed = first_free(&dest, |path| Ok(claimed.contains(path) || taken(path)?))?;
+                claimed.insert(renamed.clone());
+                return Ok(Some(renamed));
             }
-            Action::Move => {
-                if std::fs::rename(src, dest).is_err() {
-                    std::fs::copy(src, dest)?;
-                    std::fs::remove_file(src)?;
+            ConflictPolicy::Skip => Resolution::Skipped,
+            ConflictPolicy::Overwrite => {
+                if let Some(item) = self.db.remove_item_at(&dest)? {
+                    self.hooks.item(Event::ItemRemoved, &item);
                 }
+                match std::fs::remove_file(&dest) {
+                    Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+                    _ => {}
+                }
+                Resolution::Overwritten
             }
-            Action::Link => {
-                #[cfg(unix)]
-                std::os::unix::fs::symlink(src, dest)?;
-                #[cfg(not(unix))]
-                std::fs::copy(src, dest)?;
-            }
+        };
+        let placed = (resolution == Resolution::Overwritten).then_some(dest);
+        collisions.push(Collision {
+            source: source.to_path_buf(),
+            wanted: wanted.to_path_buf(),
+            resolution,
+        });
+        Ok(placed)
+    }
+
+    fn destination_path(&self, item: &Item) -> Result<PathBuf> {
+        self.config.path_formats.destination(&self.config.library_dir, item, Some(self.db))
+    }
+}
+
+/// Give an album's items whose destinations (the third field, starting out
+/// as the second) coincide distinct ones, numbering all but the first by
+/// source path, so no file of an album replaces another.
+fn disambiguate(placements: &mut [(Item, PathBuf, PathBuf)]) -> Result<()> {
+    let mut order: Vec<usize> = (0..placements.len()).collect();
+    order.sort_by(|&a, &b| placements[a].0.path.cmp(&placements[b].0.path));
+    let mut taken: HashSet<PathBuf> = placements.iter().map(|p| p.1.clone()).collect();
+    let mut seen = HashSet::new();
+    for i in order {
+        let (_, wanted, dest) = &mut placements[i];
+        if seen.insert(wanted.clone()) {
+            continue;
         }
+        let renamed = first_free(wanted, |path| Ok(taken.contains(path)))?;
+        taken.insert(renamed.clone());
+        *dest = renamed;
+    }
+    Ok(())
+}
 
-        Ok(())
+/// The first of `path` numbered ` (1)`, ` (2)` and so on that isn't `taken`.
+fn first_free(path: &Path, mut taken: impl FnMut(&Path) -> Result<bool>) -> Result<PathBuf> {
+    let mut n = 1;
+    loop {
+        let candidate = numbered(path, n);
+        if !taken(&candidate)? {
+            return Ok(candidate);
+        }
+        n += 1;
     }
 }
 
-/// Trait for reporting scan progress.
-pub trait ScanProgress: Sync {
-    /// Called when files have been found.
+/// `path` with ` (n)` added before its extension.
+fn numbered(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.file_stem().unwrap_or_default().to_os_string();
+    name.push(format!(" ({n})"));
+    if let Some(extension) = path.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    path.with_file_name(name)
+}
+
+/// Whether `dest` is the file at `source` itself, as when importing files
+/// already in the library directory. A symlink to it doesn't count.
+fn is_same_file(source: &Path, dest: &Path) -> bool {
+    dest.symlink_metadata().is_ok_and(|m| !m.file_type().is_symlink())
+        && std::fs::canonicalize(source)
+            .is_ok_and(|source| std::fs::canonicalize(dest).is_ok_and(|dest| source == dest))
+}
+
+/// Put `src` at `dest` according to `action`, creating `dest`'s directory
+/// and reporting the bytes done to `progress`. Returns the action taken,
+/// which is `Copy` where linking wasn't possible.
+fn transfer_file(
+    action: Action,
+    src: &Path,
+    dest: &Path,
+    progress: &(impl ImportProgress + ?Sized),
+) -> Result<Action> {
+    if let Some(parent) = dest.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let linked = match action {
+        Action::Copy => {
+            copy_file(src, dest, progress)?;
+            return Ok(Action::Copy);
+        }
+        Action::Move => {
+            let size = std::fs::metadata(src).map_or(0, |m| m.len());
+            if std::fs::rename(src, dest).is_ok() {
+                progress.on_transferred(size);
+            } else {
+                copy_file(src, dest, progress)?;
+                std::fs::remove_file(src)?;
+            }
+            return Ok(Action::Move);
+        }
+        // A relative target would be resolved against the link's directory
+        Action::Link => symlink(&absolute(src), dest),
+        Action::HardLink => match std::fs::hard_link(src, dest) {
+            Err(e) if !matches!(e.kind(), ErrorKind::CrossesDevices | ErrorKind::Unsupported) => {
+                return Err(e.into());
+            }
+            linked => linked,
+        },
+        Action::Reflink => reflink_copy::reflink(src, dest),
+    };
+    match linked {
+        Ok(()) => {
+            progress.on_transferred(std::fs::metadata(src).map_or(0, |m| m.len()));
+            Ok(action)
+        }
+        Err(e) => {
+            debug!("Can't {} {}: {e}", action.as_str(), src.display());
+            copy_file(src, dest, progress)?;
+            Ok(Action::Copy)
+        }
+    }
+}
+
+/// Copy `src` to `dest` with its permissions, a chunk at a time so
+/// `progress` hears about each.
+fn copy_file(src: &Path, dest: &Path, progress: &(impl ImportProgress + ?Sized)) -> Result<()> {
+    let mut reader = std::fs::File::open(src)?;
+    let mut writer = std::fs::File::create(dest)?;
+    let mut buf = vec![0; COPY_CHUNK];
+    loop {
+        let read = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        writer.write_all(&buf[..read])?;
+        progress.on_transferred(read as u64);
+    }
+    writer.set_permissions(reader.metadata()?.permissions())?;
+    Ok(())
+}
+
+/// Size of an item's file, or 0 if it can't be read.
+fn file_size(item: &Item) -> u64 {
+    std::fs::metadata(&item.path).map_or(0, |m| m.len())
+}
+
+#[cfg(unix)]
+fn symlink(src: &Path, dest: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(src, dest)
+}
+
+/// Needs Developer Mode or the create-symlink privilege.
+#[cfg(windows)]
+fn symlink(src: &Path, dest: &Path) -> std::io::Result<()> {
+    std::os::windows::fs::symlink_file(src, dest)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn symlink(_src: &Path, _dest: &Path) -> std::io::Result<()> {
+    Err(ErrorKind::Unsupported.into())
+}
+
+impl<S: MetadataSource> Resolver<S> {
+    /// Look up the release for a candidate, and its cover art. `local_art`,
+    /// the art that came with the candidate's files, is used instead of
+    /// fetching any, unless the Cover Art Archive's is preferred and it has
+    /// some. Without `lookup`, the candidate goes by its tags and local art,
+    /// and without `fetch_art` no art is fetched, as for an album going into
+    /// one in the library with art.
+    async fn resolve(
+        &self,
+        candidate: AlbumCandidate,
+        local_art: Option<Vec<u8>>,
+        lookup: bool,
+        fetch_art: bool,
+    ) -> Result<ResolvedAlbum> {
+        let mut notes = Vec::new();
+        let release = if lookup {
+            self.lookup_release(&candidate, &mut notes).await?
+        } else {
+            None
+        };
+        let fetch_art = fetch_art && self.fetch_art;
+        let cover_art = match &release {
+            Some(r) if fetch_art && (local_art.is_none() || self.prefer_caa_art) => {
+                self.cover_art(r).await.or(local_art)
+            }
+            _ => local_art,
+        };
+
+        Ok(ResolvedAlbum {
+            candidate,
+            release,
+            cover_art,
+            notes,
+        })
+    }
+
+    /// The release's front cover, if it has one. Failing to fetch it is
+    /// reported, and gives none.
+    async fn cover_art(&self, release: &Release) -> Option<Vec<u8>> {
+        match self.mb.fetch_cover_art(&release.id).await {
+            Ok(art) => art,
+            Err(e) => {
+                warn!("Could not fetch cover art for release {}: {e}", release.id);
+                None
+            }
+        }
+    }
+
+    /// Look up release information from `MusicBrainz`: the release the
+    /// candidate's tracks are all tagged with, if they agree on one that can
+    /// be found, or else the best match a search turns up.
+    async fn lookup_release(
+        &self,
+        candidate: &AlbumCandidate,
+        notes: &mut Vec<String>,
+    ) -> Result<Option<Release>> {
+        if let Some(id) = candidate.tagged_release() {
+            match self.mb.lookup_release(id).await {
+                Ok(release) => {
+                    notes.push(format!(
+                        "Matched by tagged release id: {} - {} ({})",
+                        release.artist_name(),
+                        release.title,
+                        release.id
+                    ));
+                    return Ok(Some(release));
+                }
+                Err(e) => notes.push(format!("Tagged release {id} not found ({e}), searching")),
+            }
+        }
+
+        // Compilations are credited to many artists; search by title alone
+        let artist = if candidate.compilation { "" } else { &candidate.artist };
+        let releases = self
+            .mb
+            .search_release(artist, &candidate.album, SEARCH_LIMIT)
+            .await?;
+
+        if releases.is_empty() {
+            notes.push("No MusicBrainz matches found".into());
+            return self.lookup_release_by_fingerprint(candidate, notes).await;
+        }
+
+        let ranked = rank_matches(candidate, &releases, &self.preferences);
+        for m in &ranked {
+            debug!(
+                "Candidate {} - {} ({}) [score {:.2}]",
+                m.release.artist_name(),
+                m.release.title,
+                m.release.id,
+                m.score
+            );
+        }
+        let Some(&best) = ranked.first() else {
+            return Ok(None);
+        };
+
+        if best.score < self.min_match_score {
+            notes.push(format!(
+                "Best candidate scored {:.2}, below threshold {:.2}",
+                best.score, self.min_match_score
+            ));
+            return self.lookup_release_by_fingerprint(candidate, notes).await;
+        }
+
+        notes.push(format!(
+            "Matched: {} - {} ({}) [score {:.2}]",
+            best.release.artist_name(),
+            best.release.title,
+            best.release.year().map_or_else(|| "????".into(), |y| y.to_string()),
+            best.score
+        ));
+
+        let release = self.mb.lookup_release(&best.release.id).await?;
+        Ok(Some(release))
+    }
+
+    /// Identify the release by acoustic fingerprint, falling back to importing
+    /// as-is when `AcoustID` is not configured or fingerprinting fails.
+    async fn lookup_release_by_fingerprint(
+        &self,
+        candidate: &AlbumCandidate,
+        notes: &mut Vec<String>,
+    ) -> Result<Option<Release>> {
+        let Some(acoustid) = &self.acoustid else {
+            notes.push("Importing as-is".into());
+            return Ok(None);
+        };
+
+        for item in candidate.items.iter().take(ACOUSTID_SAMPLE_TRACKS) {
+            match acoustid.identify_release(&item.path).await {
+                Ok(Some(mbid)) => {
+                    notes.push(format!("Identified by AcoustID fingerprint: {mbid}"));
+                    let release = self.mb.lookup_release(&mbid).await?;
+                    return Ok(Some(release));
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    notes.push(format!("Warning: fingerprint lookup failed: {e}"));
+                    break;
+                }
+            }
+        }
+
+        notes.push("No AcoustID match found, importing as-is".into());
+        Ok(None)
+    }
+}
+
+/// Outcome of scanning a directory tree.
+#[derive(Debug)]
+pub struct ScanResult<T = Item> {
+    /// Files that were read successfully.
+    pub items: Vec<T>,
+    /// Files that could not be read, with the reason.
+    pub failures: Vec<(PathBuf, Error)>,
+    /// Files that were read but report zero duration or bitrate.
+    /// They are still imported.
+    pub suspicious: Vec<PathBuf>,
+}
+
+/// Problems found while scanning for an import, and albums left out.
+#[derive(Debug, Default)]
+pub struct ScanReport {
+    /// Files that could not be read, with the reason.
+    pub failures: Vec<(PathBuf, Error)>,
+    /// Files that were imported but report zero duration or bitrate.
+    pub suspicious: Vec<PathBuf>,
+    /// Albums (as "Artist - Album") left out by the `--only` filter.
+    pub skipped: Vec<String>,
+    /// Albums (as "Artist - Album") left alone for lack of a match (see
+    /// [`QuietFallback::Skip`]), with the directory or archive they are in.
+    pub unmatched: Vec<(String, PathBuf)>,
+    /// What imports the unmatched albums again and nothing else: each one's
+    /// directory or archive, or its files where the directory holds others
+    /// too.
+    pub retry: Vec<PathBuf>,
+    /// Files whose destination was taken, and what was done about them.
+    pub collisions: Vec<Collision>,
+    /// Albums (as "Artist - Album"), or whole paths, that could not be
+    /// imported, with the reason; the others were imported regardless.
+    pub failed_albums: Vec<(String, Error)>,
+}
+
+impl ScanReport {
+    /// Whether no problems were found; skipped albums aren't problems.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.failures.is_empty() && self.suspicious.is_empty() && self.failed_albums.is_empty()
+    }
+
+    /// Merge another report into this one.
+    pub fn extend(&mut self, other: Self) {
+        self.failures.extend(other.failures);
+        self.suspicious.extend(other.suspicious);
+        self.skipped.extend(other.skipped);
+        self.unmatched.extend(other.unmatched);
+        self.retry.extend(other.retry);
+        self.collisions.extend(other.collisions);
+        self.failed_albums.extend(other.failed_albums);
+    }
+}
+
+/// Trait for reporting scan progress.
+pub trait ScanProgress: Sync {
+    /// Called when files have been found.
     fn on_files_found(&self, count: usize);
     /// Called periodically during scanning.
     fn tick(&self);
+    /// Called when a file cannot be read.
+    fn on_error(&self, path: &Path, error: &Error);
     /// Called when scanning is complete.
     fn finish(&self, track_count: usize);
 }
 
-/// Console-based progress reporter using indicatif.
+/// Reporting an import's progress: the scan, then each album in turn and
+/// the bytes of its files as they are put into the library.
+pub trait ImportProgress: ScanProgress {
+    /// Called before the first album is written, with how many there are.
+    fn on_albums_found(&self, count: usize);
+    /// Called when an album starts being written, with its position (from
+    /// 1), its name as "Artist - Album" and the size of its files.
+    fn on_album_start(&self, position: usize, name: &str, bytes: u64);
+    /// Called as files are transferred, with the bytes done since the last
+    /// call. A file renamed or linked into place is done all at once.
+    fn on_transferred(&self, bytes: u64);
+    /// Called when an album has been imported, or has failed.
+    fn on_album_done(&self);
+}
+
+/// Console-based progress reporter using indicatif: a spinner while
+/// scanning, then a count of albums and a bar for the current one's bytes.
 pub struct ConsoleProgress {
-    bar: ProgressBar,
+    bars: MultiProgress,
+    /// The scanning spinner, replaced for each scan.
+    spinner: Mutex<ProgressBar>,
+    /// The album count and the current album's bytes, while importing.
+    albums: Mutex<Option<(ProgressBar, ProgressBar)>>,
 }
 
 impl ConsoleProgress {
     /// Create a new console progress reporter.
     #[must_use]
     pub fn new() -> Self {
+        let bars = MultiProgress::new();
+        let spinner = bars.add(Self::new_spinner());
+        Self {
+            bars,
+            spinner: Mutex::new(spinner),
+            albums: Mutex::new(None),
+        }
+    }
+
+    fn new_spinner() -> ProgressBar {
         let bar = ProgressBar::new_spinner();
         if let Ok(style) =
             ProgressStyle::default_spinner().template("{spinner:.green} Scanning: {msg}")
         {
             bar.set_style(style);
         }
-        Self { bar }
+        bar
+    }
+
+    /// The scanning spinner, a fresh one if the last scan's has finished.
+    fn spinner(&self) -> ProgressBar {
+        let Ok(mut spinner) = self.spinner.lock() else {
+            return ProgressBar::hidden();
+        };
+        if spinner.is_finished() {
+            *spinner = self.bars.add(Self::new_spinner());
+        }
+        spinner.clone()
+    }
+
+    /// Run `f` on the album count and byte bars, if an import is under way.
+    fn with_albums(&self, f: impl FnOnce(&ProgressBar, &ProgressBar)) {
+        if let Ok(albums) = self.albums.lock() {
+            if let Some((count, bytes)) = albums.as_ref() {
+                f(count, bytes);
+            }
+        }
     }
 }
 
@@ -309,107 +1604,602 @@ impl Default for ConsoleProgress {
 
 impl ScanProgress for ConsoleProgress {
     fn on_files_found(&self, count: usize) {
-        self.bar.set_message(format!("Found {count} files"));
+        self.spinner().set_message(format!("Found {count} files"));
     }
 
     fn tick(&self) {
-        self.bar.tick();
+        self.spinner().tick();
+    }
+
+    fn on_error(&self, path: &Path, error: &Error) {
+        let _ = self
+            .bars
+            .println(format!("Could not read {}: {error}", path.display()));
     }
 
     fn finish(&self, track_count: usize) {
-        self.bar
+        self.spinner()
             .finish_with_message(format!("Scanned {track_count} tracks"));
     }
 }
 
+impl ImportProgress for ConsoleProgress {
+    fn on_albums_found(&self, count: usize) {
+        let Ok(mut albums) = self.albums.lock() else {
+            return;
+        };
+        if let Some((count, bytes)) = albums.take() {
+            count.finish_and_clear();
+            bytes.finish_and_clear();
+        }
+        if count == 0 {
+            return;
+        }
+        let count = self.bars.add(ProgressBar::new(count as u64));
+        if let Ok(style) = ProgressStyle::default_bar().template("Album {pos}/{len}: {wide_msg}") {
+            count.set_style(style);
+        }
+        let bytes = self.bars.add(ProgressBar::new(0));
+        if let Ok(style) = ProgressStyle::default_bar()
+            .template("{bar:40.green} {bytes}/{total_bytes} ({binary_bytes_per_sec})")
+        {
+            bytes.set_style(style);
+        }
+        *albums = Some((count, bytes));
+    }
+
+    fn on_album_start(&self, position: usize, name: &str, total: u64) {
+        self.with_albums(|count, bytes| {
+            count.set_position(position as u64);
+            count.set_message(name.to_string());
+            bytes.reset();
+            bytes.set_length(total);
+        });
+    }
+
+    fn on_transferred(&self, done: u64) {
+        self.with_albums(|_, bytes| bytes.inc(done));
+    }
+
+    fn on_album_done(&self) {
+        self.with_albums(|count, bytes| {
+            bytes.set_position(bytes.length().unwrap_or_default());
+            if Some(count.position()) >= count.length() {
+                count.finish_and_clear();
+                bytes.finish_and_clear();
+            }
+        });
+    }
+}
+
 /// No-op progress reporter for testing or silent operation.
 pub struct NoProgress;
 
 impl ScanProgress for NoProgress {
     fn on_files_found(&self, _count: usize) {}
     fn tick(&self) {}
+    fn on_error(&self, _path: &Path, _error: &Error) {}
     fn finish(&self, _track_count: usize) {}
 }
 
-fn scan(path: &Path) -> Vec<Item> {
-    let progress = ConsoleProgress::new();
-    scan_with_progress(path, &progress)
+impl ImportProgress for NoProgress {
+    fn on_albums_found(&self, _count: usize) {}
+    fn on_album_start(&self, _position: usize, _name: &str, _bytes: u64) {}
+    fn on_transferred(&self, _bytes: u64) {}
+    fn on_album_done(&self) {}
 }
 
-fn scan_with_progress<P: ScanProgress>(path: &Path, progress: &P) -> Vec<Item> {
-    let files: Vec<PathBuf> = WalkDir::new(path)
-        .follow_links(true)
-        .into_iter()
+fn scan(
+    files: Vec<PathBuf>,
+    scan: &ScanOptions,
+    progress: &(impl ScanProgress + ?Sized),
+) -> ScanResult {
+    let result = scan_with_progress(
+        files,
+        AnalyzeOptions::TAGS | AnalyzeOptions::PROPERTIES,
+        scan,
+        &StdFileOps,
+        progress,
+    );
+    ScanResult {
+        items: result.items.into_iter().map(|analysis| analysis.item).collect(),
+        failures: result.failures,
+        suspicious: result.suspicious,
+    }
+}
+
+/// Which files an import reads the tags of, decided from their names and
+/// modification times alone so the files left out cost next to nothing.
+#[derive(Debug, Clone, Default)]
+pub struct ScanOptions {
+    /// Only files with these formats' extensions; any audio file if empty.
+    pub formats: Vec<AudioFormat>,
+    /// Only files modified at or after this time.
+    pub newer_than: Option<DateTime<Utc>>,
+    /// How many directories below the import path to look in; 0 for just
+    /// its own files, all of them if unset.
+    pub max_depth: Option<usize>,
+}
+
+impl ScanOptions {
+    /// The audio files under `path` no deeper than `max_depth` (see
+    /// [`audio_files`]).
+    #[must_use]
+    pub fn files(&self, path: &Path, extra_extensions: &[String]) -> Vec<PathBuf> {
+        walk_audio_files(path, extra_extensions, self.max_depth)
+    }
+
+    /// Whether the file at `path` is to be read. A file whose modification
+    /// time can't be had is read, so the error reading it is reported.
+    fn wants(&self, path: &Path) -> bool {
+        if !self.formats.is_empty() {
+            let extension = path.extension().and_then(|extension| extension.to_str());
+            let format = extension.map_or(AudioFormat::Unknown, AudioFormat::from_extension);
+            if !self.formats.contains(&format) {
+                return false;
+            }
+        }
+        let Some(newer_than) = self.newer_than else {
+            return true;
+        };
+        let modified = std::fs::metadata(path).and_then(|metadata| metadata.modified());
+        !matches!(modified, Ok(modified) if DateTime::<Utc>::from(modified) < newer_than)
+    }
+}
+
+/// All audio files under `path` (or `path` itself, if it is one), counting
+/// files with `extra_extensions` as audio (see [`is_audio_file`]).
+#[must_use]
+pub fn audio_files(path: &Path, extra_extensions: &[String]) -> Vec<PathBuf> {
+    walk_audio_files(path, extra_extensions, None)
+}
+
+fn walk_audio_files(
+    path: &Path,
+    extra_extensions: &[String],
+    max_depth: Option<usize>,
+) -> Vec<PathBuf> {
+    let mut walk = WalkDir::new(path).follow_links(true).sort_by_file_name();
+    if let Some(depth) = max_depth {
+        // The files directly in `path` are at depth 1
+        walk = walk.max_depth(depth.saturating_add(1));
+    }
+    walk.into_iter()
         .filter_map(std::result::Result::ok)
         .filter(|e| e.file_type().is_file())
-        .filter(|e| is_audio_file(e.path()))
+        .filter(|e| is_audio_file(e.path(), extra_extensions))
         .map(|e| e.path().to_path_buf())
-        .collect();
+        .collect()
+}
 
+/// Analyze every file `scan` wants in a single pass per file, leaving the
+/// others out before any are opened.
+fn scan_with_progress<F: FileOps, P: ScanProgress + ?Sized>(
+    mut files: Vec<PathBuf>,
+    options: AnalyzeOptions,
+    scan: &ScanOptions,
+    ops: &F,
+    progress: &P,
+) -> ScanResult<FileAnalysis> {
+    files.retain(|path| scan.wants(path));
     progress.on_files_found(files.len());
 
-    let items: Vec<Item> = files
-        .par_iter()
-        .filter_map(|p| {
+    let results: Vec<(PathBuf, Result<FileAnalysis>)> = files
+        .into_par_iter()
+        .map(|p| {
             progress.tick();
-            read_tags(p).ok()
+            let analysis = analyze_file(&p, options, ops);
+            if let Err(e) = &analysis {
+                progress.on_error(&p, e);
+            }
+            (p, analysis)
         })
         .collect();
 
-    progress.finish(items.len());
-    items
+    let mut scan = ScanResult {
+        items: Vec::new(),
+        failures: Vec::new(),
+        suspicious: Vec::new(),
+    };
+    for (path, analysis) in results {
+        match analysis {
+            Ok(analysis) => {
+                if options.contains(AnalyzeOptions::PROPERTIES) && is_suspicious(&analysis.item)
+                {
+                    scan.suspicious.push(path);
+                }
+                scan.items.push(analysis);
+            }
+            Err(e) => scan.failures.push((path, e)),
+        }
+    }
+
+    progress.finish(scan.items.len());
+    scan
+}
+
+/// The cue sheet of a candidate ripped to one file (or one per disc), and
+/// the file it describes, warning that it is one. Splitting such a file into its tracks isn't
+/// supported, and matching it as a one-track album would find the wrong
+/// release, so it is imported as tagged.
+async fn cue_rip(candidate: &AlbumCandidate, name: &str) -> Option<CueRip> {
+    let mut found = None;
+    for item in &candidate.items {
+        if let Some(sheet) = cue::sheet_for(&item.path).await {
+            found = Some((&item.path, sheet));
+            break;
+        }
+    }
+    let (audio, (path, sheet)) = found?;
+    let hidden = if sheet.hidden_track().is_some() {
+        ", and a hidden track before the first"
+    } else {
+        ""
+    };
+    warn!(
+        "{name} is one file with cue sheet {} listing {} tracks{hidden}; splitting it isn't \
+         supported, so it is imported as tagged, without looking it up",
+        path.display(),
+        sheet.tracks.len()
+    );
+    Some(CueRip {
+        audio: audio.clone(),
+        sheet: path,
+    })
+}
+
+/// Art that came with a candidate's files: the image in its source
+/// directory with the earliest of `names` (ignoring case), else a picture
+/// embedded in its first track.
+fn local_art(candidate: &AlbumCandidate, names: &[String]) -> Option<Vec<u8>> {
+    let file = candidate.source_dir().and_then(|dir| art_file(&dir, names));
+    file.and_then(|path| read_art(&path)).or_else(|| {
+        let first = candidate.items.first()?;
+        let options = AnalyzeOptions::TAGS | AnalyzeOptions::EMBEDDED_ART;
+        analyze_file(&first.path, options, &StdFileOps).ok()?.embedded_art
+    })
+}
+
+/// The JPEG or PNG file in `dir` whose name (without extension) comes
+/// earliest in `names`.
+fn art_file(dir: &Path, names: &[String]) -> Option<PathBuf> {
+    std::fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            let extension = path.extension()?.to_str()?.to_lowercase();
+            if !matches!(extension.as_str(), "jpg" | "jpeg" | "png") || !path.is_file() {
+                return None;
+            }
+            let stem = path.file_stem()?.to_str()?.to_lowercase();
+            let rank = names.iter().position(|name| name.to_lowercase() == stem)?;
+            Some((rank, path))
+        })
+        .min()
+        .map(|(_, path)| path)
+}
+
+/// The contents of the art file at `path`; one that can't be read is
+/// reported, and gives none.
+fn read_art(path: &Path) -> Option<Vec<u8>> {
+    match std::fs::read(path) {
+        Ok(art) => Some(art),
+        Err(e) => {
+            warn!("Could not read {}: {e}", path.display());
+            None
+        }
+    }
+}
+
+/// A readable file with no audio duration or bitrate is probably truncated or mislabeled.
+fn is_suspicious(item: &Item) -> bool {
+    is_readable(item.format) && (item.length <= 0.0 || item.bitrate == 0)
+}
+
+/// Canonical form of `path` for provenance, or `path` itself if it can't be
+/// resolved.
+fn absolute(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Where `path` is below `library_dir`, or `None` if it isn't inside it.
+/// Both are resolved first, so a symlink to either doesn't hide it. On Unix
+/// the directories above `path` are compared to the library by identity, as
+/// names alone can differ in case on a case-insensitive filesystem.
+fn library_relative(path: &Path, library_dir: &Path) -> Option<PathBuf> {
+    let path = std::fs::canonicalize(path).ok()?;
+    let library = std::fs::canonicalize(library_dir).ok()?;
+    if let Ok(relative) = path.strip_prefix(&library) {
+        return Some(relative.to_path_buf());
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let id = |dir: &Path| std::fs::metadata(dir).ok().map(|m| (m.dev(), m.ino()));
+        let library = id(&library)?;
+        if let Some(dir) = path.ancestors().find(|&dir| id(dir) == Some(library)) {
+            return path.strip_prefix(dir).ok().map(Path::to_path_buf);
+        }
+    }
+    None
 }
 
+/// Fields that have no value until an album has been matched and imported.
+const POST_MATCH_FIELDS: &[&str] = &[
+    "id",
+    "album_id",
+    "artpath",
+    "mb_trackid",
+    "mb_albumid",
+    "mb_releasegroupid",
+    "source_path",
+    "import_run",
+    "rg_track_gain",
+    "rg_track_peak",
+    "rg_album_gain",
+    "rg_album_peak",
+    "rating",
+    "play_count",
+    "last_played",
+];
+
+/// Parse an `import --only` query, which is matched against albums before
+/// they are looked up.
+///
+/// # Errors
+/// Returns an error if the query can't be parsed or uses a field that only
+/// has a value after import.
+pub fn only_filter(query: &str) -> Result<Vec<QueryTerm>> {
+    let terms = crate::query::parse(query)?;
+    for name in field_names(&terms) {
+        if item_field(name).is_none() {
+            return Err(Error::Query(format!("Unknown field: {name}")));
+        }
+        if POST_MATCH_FIELDS.contains(&name) {
+            return Err(Error::Query(format!(
+                "{name} has no value before import and can't be used with --only"
+            )));
+        }
+    }
+    Ok(terms)
+}
+
+/// Split candidates into those matching `only` and descriptions of the rest.
+/// Each album is judged by its first track.
+fn select_candidates(
+    candidates: Vec<AlbumCandidate>,
+    only: Option<&[QueryTerm]>,
+) -> Result<(Vec<AlbumCandidate>, Vec<String>)> {
+    let Some(terms) = only else {
+        return Ok((candidates, Vec::new()));
+    };
+    let mut selected = Vec::new();
+    let mut skipped = Vec::new();
+    for candidate in candidates {
+        // Not in the library yet, so without attributes of their own
+        if matches_item(terms, &candidate.items[0], None)? {
+            selected.push(candidate);
+        } else {
+            skipped.push(format!("{} - {}", candidate.artist, candidate.album));
+        }
+    }
+    Ok((selected, skipped))
+}
+
+/// Group items into albums by album artist and title, compared by their
+/// [`normalize`]d keys so spelling differences between tracks don't split
+/// an album.
+///
+/// Tracks without an album artist that share an album title and directory
+/// but not an artist are taken for a compilation, and grouped as one album
+/// by [`VARIOUS_ARTISTS`] rather than one per artist.
 fn group_into_albums(items: Vec<Item>) -> Vec<AlbumCandidate> {
-    let mut groups: HashMap<(String, String), Vec<Item>> = HashMap::new();
+    let compilations = compilation_dirs(&items);
+    let mut groups: HashMap<(String, String, Option<PathBuf>), Vec<Item>> = HashMap::new();
 
     for item in items {
-        let key = (
-            item.effective_albumartist().to_lowercase(),
-            item.album.to_lowercase(),
-        );
+        let album = normalize(&item.album);
+        let dir = item.path.parent().map(Path::to_path_buf);
+        let in_compilation =
+            item.albumartist.is_none() && compilations.contains(&(album.clone(), dir.clone()));
+        let key = if in_compilation {
+            (normalize(VARIOUS_ARTISTS), album, dir)
+        } else {
+            (normalize(item.effective_albumartist()), album, None)
+        };
         groups.entry(key).or_default().push(item);
     }
 
-    groups
+    // Process albums in path order, not hash order, so runs are reproducible
+    let mut candidates: Vec<AlbumCandidate> = groups
         .into_iter()
-        .map(|((artist, album), items)| AlbumCandidate {
-            artist: items
-                .first()
-                .map_or(artist, |i| i.effective_albumartist().to_string()),
-            album: items.first().map_or(album, |i| i.album.clone()),
-            items,
+        .map(|((artist, album, dir), mut items)| {
+            items.sort_by(|a, b| a.path.cmp(&b.path));
+            let artist = if dir.is_some() {
+                VARIOUS_ARTISTS.to_string()
+            } else {
+                items.first().map_or(artist, |i| i.effective_albumartist().to_string())
+            };
+            AlbumCandidate {
+                compilation: is_various(&artist),
+                cue_sheet: None,
+                artist,
+                album: items.first().map_or(album, |i| i.album.clone()),
+                items,
+            }
         })
+        .collect();
+    candidates.sort_by(|a, b| a.items[0].path.cmp(&b.items[0].path));
+    candidates
+}
+
+/// The [`normalize`]d album titles and directories of tracks without an
+/// album artist whose artists differ.
+fn compilation_dirs(items: &[Item]) -> HashSet<(String, Option<PathBuf>)> {
+    let mut artists: HashMap<(String, Option<PathBuf>), HashSet<String>> = HashMap::new();
+    for item in items.iter().filter(|item| item.albumartist.is_none()) {
+        let dir = item.path.parent().map(Path::to_path_buf);
+        artists
+            .entry((normalize(&item.album), dir))
+            .or_default()
+            .insert(normalize(&item.artist));
+    }
+    artists
+        .into_iter()
+        .filter(|(_, artists)| artists.len() > 1)
+        .map(|(key, _)| key)
         .collect()
 }
 
-fn pick_best_match<'b>(candidate: &AlbumCandidate, releases: &'b [Release]) -> Option<&'b Release> {
-    releases.iter().max_by_key(|r| {
-        let artist_sim = strsim::jaro_winkler(&candidate.artist, &r.artist_name());
-        let album_sim = strsim::jaro_winkler(&candidate.album, &r.title);
-        let track_count_match = if r.tracks().len() == candidate.items.len() {
-            matching::TRACK_COUNT_BONUS
-        } else {
-            0.0
-        };
-        (artist_sim + album_sim + track_count_match)
-            .mul_add(matching::SCORE_MULTIPLIER, 0.0)
-            .clamp(0.0, f64::from(u32::MAX)) as u32
-    })
+/// Whether `artist` is [`VARIOUS_ARTISTS`], however it is spelled.
+fn is_various(artist: &str) -> bool {
+    normalize(artist) == normalize(VARIOUS_ARTISTS)
+}
+
+/// The best-scoring release of each release group, best first, up to
+/// [`CANDIDATE_LIMIT`] of them. Releases without a group stand alone.
+///
+/// The score combines artist and album similarity with a bonus for matching
+/// track counts, normalized to 0..1. Preferred countries and media add small
+/// bonuses that only break ties; they are not included in the returned score.
+/// Releases that tie keep the order they came in.
+fn rank_matches<'r>(
+    candidate: &AlbumCandidate,
+    releases: &'r [Release],
+    preferences: &ReleasePreferences,
+) -> Vec<ReleaseMatch<'r>> {
+    let mut scored: Vec<(ReleaseMatch<'r>, f64)> = releases
+        .iter()
+        .map(|release| {
+            let m = ReleaseMatch {
+                release,
+                score: match_score(candidate, release),
+            };
+            (m, m.score + preference_bonus(release, preferences))
+        })
+        .collect();
+    scored.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+
+    let mut groups = HashSet::new();
+    scored
+        .into_iter()
+        .map(|(m, _)| m)
+        .filter(|m| groups.insert(m.release.release_group_id().unwrap_or(&m.release.id)))
+        .take(CANDIDATE_LIMIT)
+        .collect()
+}
+
+/// Tie-breaking bonus for a release's country and medium formats.
+///
+/// Earlier entries in each preference list earn a larger share of the bonus.
+/// The total always stays below the track count bonus.
+fn preference_bonus(release: &Release, preferences: &ReleasePreferences) -> f64 {
+    let country = release.country.as_deref().map_or(0.0, |c| {
+        rank_bonus(&preferences.countries, c, matching::COUNTRY_PREFERENCE_BONUS)
+    });
+    let media = release
+        .media
+        .iter()
+        .filter_map(|m| m.format.as_deref())
+        .map(|f| rank_bonus(&preferences.media, f, matching::MEDIA_PREFERENCE_BONUS))
+        .fold(0.0, f64::max);
+    country + media
+}
+
+fn rank_bonus(preferred: &[String], value: &str, max_bonus: f64) -> f64 {
+    preferred
+        .iter()
+        .position(|p| p.eq_ignore_ascii_case(value))
+        .map_or(0.0, |i| {
+            max_bonus * (preferred.len() - i) as f64 / preferred.len() as f64
+        })
+}
+
+fn match_score(candidate: &AlbumCandidate, release: &Release) -> f64 {
+    let artist_sim = similarity(&candidate.artist, &release.artist_name());
+    let album_sim = similarity(&candidate.album, &release.title);
+    let track_count_match = if release.track_count() == candidate.items.len() {
+        matching::TRACK_COUNT_BONUS
+    } else {
+        0.0
+    };
+    ((artist_sim + album_sim + track_count_match) / matching::MAX_RAW_SCORE).clamp(0.0, 1.0)
+}
+
+/// `items` placed on the tracks of `release` (see [`assign_tracks`]).
+fn match_tracks(items: Vec<Item>, release: &Release) -> Vec<Item> {
+    let matches = assign_tracks(&items, release);
+    apply_matches(items, release, &matches)
+}
+
+/// `items` placed on the tracks of `release` as `matches` say, with the
+/// sort names their tags lack taken from the artist credits: each track's
+/// own, then the release's for tracks by the release's artist.
+fn apply_matches(mut items: Vec<Item>, release: &Release, matches: &[TrackMatch]) -> Vec<Item> {
+    let tracks = release.disc_tracks();
+    for m in matches {
+        if let (Some(item), Some(&track)) = (items.get_mut(m.item), tracks.get(m.track)) {
+            place(item, track);
+        }
+    }
+    let release_sort = release.artist_sort_name();
+    let release_artist = release.artist_name();
+    for item in &mut items {
+        if item.albumartist_sort.is_none() {
+            item.albumartist_sort.clone_from(&release_sort);
+        }
+        if item.artist_sort.is_none() && item.artist == release_artist {
+            item.artist_sort.clone_from(&release_sort);
+        }
+    }
+    items
 }
 
-fn match_tracks(mut items: Vec<Item>, release: &Release) -> Vec<Item> {
-    let tracks = release.tracks();
-    if tracks.is_empty() {
-        return items;
+/// Which of `items` go on which tracks of `release`, in item order: those
+/// tagged with one of its recordings on that track, the rest wherever their
+/// titles and lengths fit best. Items or tracks may be left over.
+fn assign_tracks(items: &[Item], release: &Release) -> Vec<TrackMatch> {
+    let tracks = release.disc_tracks();
+    let matched = |item: usize, track: usize| TrackMatch {
+        item,
+        track,
+        similarity: strsim::jaro_winkler(
+            &normalize(&items[item].title),
+            &normalize(&tracks[track].1.title),
+        ),
+    };
+
+    // Items already tagged with one of the release's recordings go there;
+    // the rest are matched to the tracks left
+    let mut matches = Vec::new();
+    let mut placed = vec![false; tracks.len()];
+    let mut unplaced = Vec::new();
+    for (i, item) in items.iter().enumerate() {
+        let tagged = item.mb_trackid.as_deref().and_then(|id| {
+            (0..tracks.len()).find(|&j| !placed[j] && tracks[j].1.recording.id == id)
+        });
+        match tagged {
+            Some(j) => {
+                placed[j] = true;
+                matches.push(matched(i, j));
+            }
+            None => unplaced.push(i),
+        }
+    }
+    let left: Vec<usize> = (0..tracks.len()).filter(|&j| !placed[j]).collect();
+    if unplaced.is_empty() || left.is_empty() {
+        return matches;
     }
 
-    let n = items.len().max(tracks.len());
+    let n = unplaced.len().max(left.len());
     let mut matrix = vec![vec![0i64; n]; n];
+    let track_titles: Vec<String> =
+        left.iter().map(|&j| normalize(&tracks[j].1.title)).collect();
 
-    for (i, item) in items.iter().enumerate() {
-        for (j, track) in tracks.iter().enumerate() {
-            let title_dist = strsim::jaro_winkler(&item.title, &track.title);
+    for (i, item) in unplaced.iter().map(|&i| &items[i]).enumerate() {
+        let title = normalize(&item.title);
+        for (j, track) in left.iter().map(|&j| tracks[j].1).enumerate() {
+            let title_dist = strsim::jaro_winkler(&title, &track_titles[j]);
             let length_dist = track.length.map_or(matching::length::UNKNOWN_SCORE, |tl| {
                 // tl is track length in ms (u64→f64 precision loss acceptable for comparison)
                 let diff = item
@@ -435,17 +2225,1926 @@ fn match_tracks(mut items: Vec<Item>, release: &Release) -> Vec<Item> {
     }
 
     let Ok(matrix_obj) = pathfinding::matrix::Matrix::from_rows(matrix) else {
-        return items; // Return unmatched if matrix construction fails
+        return matches; // Leave the rest unmatched if matrix construction fails
     };
     let assignment = pathfinding::kuhn_munkres::kuhn_munkres_min(&matrix_obj);
 
-    for (item_idx, track_idx) in assignment.1.iter().enumerate() {
-        if item_idx < items.len() && *track_idx < tracks.len() {
-            let track = &tracks[*track_idx];
-            items[item_idx].title.clone_from(&track.title);
-            items[item_idx].mb_trackid = Some(track.recording.id.clone());
+    for (i, track_idx) in assignment.1.iter().enumerate() {
+        if i < unplaced.len() && *track_idx < left.len() {
+            matches.push(matched(unplaced[i], left[*track_idx]));
         }
     }
+    matches.sort_by_key(|m| m.item);
+    matches
+}
 
-    items
+/// Give `item` the title, recording and position of `track`, on medium
+/// `disc` of its release.
+fn place(item: &mut Item, (disc, track): (u32, &Track)) {
+    item.title.clone_from(&track.title);
+    item.mb_trackid = Some(track.recording.id.clone());
+    if item.artist_sort.is_none() {
+        item.artist_sort = track.artist_sort_name();
+    }
+    (item.disc, item.track) = placed_position(item, disc, track);
+}
+
+/// `item` with the album-level fields of `album`, so its destination is
+/// computed like those of the album's other tracks.
+fn into_album(mut item: Item, album: &Album) -> Item {
+    item.album.clone_from(&album.album);
+    item.albumartist = Some(album.albumartist.clone());
+    item.year = album.year;
+    item.mb_albumid.clone_from(&album.mb_albumid);
+    item.mb_releasegroupid.clone_from(&album.mb_releasegroupid);
+    item
+}
+
+/// Whether two tracks of an album are the same one: at the same position,
+/// or with the same title.
+fn same_track(a: &Item, b: &Item) -> bool {
+    let same_position = a.track.is_some()
+        && a.track == b.track
+        && a.disc.unwrap_or(1) == b.disc.unwrap_or(1);
+    same_position || normalize(&a.title) == normalize(&b.title)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::musicbrainz::{
+        ApiError, Artist, ArtistCredit, Medium, NullSource, Recording, ReleaseGroup,
+    };
+    use crate::tags::write_tags;
+    use crate::testutil::{self, wav_bytes};
+
+    fn test_item(title: &str) -> Item {
+        Item {
+            path: format!("/{title}.mp3").into(),
+            title: title.into(),
+            artist: "Black Sabbath".into(),
+            album: "Paranoid".into(),
+            bitrate: 320,
+            length: 180.0,
+            ..testutil::item()
+        }
+    }
+
+    fn test_release(id: &str, artist: &str, title: &str) -> Release {
+        Release {
+            id: id.into(),
+            title: title.into(),
+            disambiguation: String::new(),
+            date: None,
+            artist_credit: vec![ArtistCredit {
+                artist: Artist {
+                    id: "artist".into(),
+                    name: artist.into(),
+                    sort_name: String::new(),
+                },
+                joinphrase: String::new(),
+            }],
+            country: None,
+            media: Vec::new(),
+            label_info: Vec::new(),
+            release_group: None,
+            score: 100,
+        }
+    }
+
+    fn test_release_from(id: &str, country: &str, format: &str, track_count: usize) -> Release {
+        Release {
+            country: Some(country.into()),
+            media: vec![Medium {
+                position: 1,
+                format: Some(format.into()),
+                track_count,
+                tracks: Vec::new(),
+            }],
+            ..test_release(id, "Black Sabbath", "Paranoid")
+        }
+    }
+
+    fn test_preferences() -> ReleasePreferences {
+        ReleasePreferences {
+            countries: vec!["US".into(), "GB".into()],
+            media: vec!["CD".into(), "Digital Media".into()],
+        }
+    }
+
+    fn test_candidate() -> AlbumCandidate {
+        AlbumCandidate {
+            items: vec![test_item("War Pigs")],
+            artist: "Black Sabbath".into(),
+            album: "Paranoid".into(),
+            compilation: false,
+            cue_sheet: None,
+        }
+    }
+
+    #[test]
+    fn test_rank_matches_prefers_closest() {
+        let releases = vec![
+            test_release("a", "Some Band", "Live"),
+            test_release("b", "Black Sabbath", "Paranoid"),
+        ];
+        let ranked = rank_matches(&test_candidate(), &releases, &ReleasePreferences::default());
+        let best = ranked[0];
+        assert_eq!(best.release.id, "b");
+        assert!(best.score > 0.9 && best.score <= 1.0);
+    }
+
+    #[test]
+    fn test_match_score_threshold_boundary() {
+        let good = test_release("b", "Black Sabbath", "Paranoid");
+        let bad = test_release("a", "Zz Qq", "Live");
+        let candidate = test_candidate();
+
+        assert!(match_score(&candidate, &good) >= DEFAULT_MIN_MATCH_SCORE);
+        assert!(match_score(&candidate, &bad) < DEFAULT_MIN_MATCH_SCORE);
+    }
+
+    #[test]
+    fn test_matching_ignores_spelling_differences() {
+        let mut tracks = Vec::new();
+        for (artist, title) in [("Björk", "Jóga"), ("Bjork", "Hunter"), ("BJÖRK", "Bachelorette")] {
+            let mut item = test_item(title);
+            item.artist = artist.into();
+            item.album = "Homogenic".into();
+            tracks.push(item);
+        }
+        let candidates = group_into_albums(tracks);
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].items.len(), 3);
+
+        let stones = AlbumCandidate {
+            items: vec![test_item("Paint It Black")],
+            artist: "Rolling Stones, The".into(),
+            album: "Aftermath".into(),
+            compilation: false,
+            cue_sheet: None,
+        };
+        let release = test_release("a", "The Rolling Stones", "Aftermath");
+        assert!((match_score(&stones, &release) - 2.0 / matching::MAX_RAW_SCORE).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_compilations_grouped_by_directory() {
+        let track = |dir: &str, artist: &str, title: &str| Item {
+            path: format!("/{dir}/{title}.mp3").into(),
+            artist: artist.into(),
+            album: "Pure Moods".into(),
+            ..test_item(title)
+        };
+        let tracks = vec![
+            track("moods", "Enya", "Orinoco Flow"),
+            track("moods", "Enigma", "Sadeness"),
+            track("moods", "Mike Oldfield", "Tubular Bells"),
+            // A same-named album elsewhere, by one artist, is its own
+            track("other", "Yanni", "Aria"),
+            track("other", "Yanni", "Nice"),
+        ];
+        let candidates = group_into_albums(tracks);
+
+        assert_eq!(candidates.len(), 2);
+        assert_eq!(candidates[0].artist, VARIOUS_ARTISTS);
+        assert!(candidates[0].compilation);
+        assert_eq!(candidates[0].items.len(), 3);
+        assert_eq!(candidates[1].artist, "Yanni");
+        assert!(!candidates[1].compilation);
+
+        // Tagged album artists are left alone, and may say Various Artists
+        let mut tagged = track("tagged", "Enya", "Caribbean Blue");
+        tagged.albumartist = Some("various artists".into());
+        let candidates = group_into_albums(vec![tagged]);
+        assert!(candidates[0].compilation);
+    }
+
+    #[test]
+    fn test_rank_matches_empty() {
+        assert!(rank_matches(&test_candidate(), &[], &ReleasePreferences::default()).is_empty());
+    }
+
+    #[test]
+    fn test_preferences_break_ties() {
+        let releases = vec![
+            test_release_from("jp-vinyl", "JP", "12\" Vinyl", 1),
+            test_release_from("us-cd", "US", "CD", 1),
+        ];
+        let ranked = rank_matches(&test_candidate(), &releases, &test_preferences());
+        assert_eq!(ranked[0].release.id, "us-cd");
+    }
+
+    #[test]
+    fn test_track_count_dominates_preferences() {
+        let releases = vec![
+            test_release_from("us-cd", "US", "CD", 12),
+            test_release_from("jp-vinyl", "JP", "12\" Vinyl", 1),
+        ];
+        let ranked = rank_matches(&test_candidate(), &releases, &test_preferences());
+        assert_eq!(ranked[0].release.id, "jp-vinyl");
+    }
+
+    #[test]
+    fn test_rank_matches_keeps_one_release_per_group() {
+        let in_group = |id: &str, group: &str, country: &str, title: &str| Release {
+            title: title.into(),
+            country: Some(country.into()),
+            release_group: Some(ReleaseGroup {
+                id: group.into(),
+                title: title.into(),
+                primary_type: Some("Album".into()),
+            }),
+            ..test_release(id, "Black Sabbath", title)
+        };
+        let mut releases: Vec<Release> = (0..6)
+            .map(|i| in_group(&format!("jp-{i}"), "paranoid", "JP", "Paranoid"))
+            .collect();
+        releases.push(in_group("gb", "paranoid", "GB", "Paranoid"));
+        releases.push(in_group("deluxe", "deluxe", "GB", "Paranoid (Deluxe Edition)"));
+        releases.push(test_release("bootleg", "Black Sabbath", "Paranoid Live"));
+
+        let ranked = rank_matches(&test_candidate(), &releases, &test_preferences());
+        let ids: Vec<&str> = ranked.iter().map(|m| m.release.id.as_str()).collect();
+        assert_eq!(ids, ["gb", "bootleg", "deluxe"]);
+
+        let many: Vec<Release> = (0..8)
+            .map(|i| in_group(&format!("r{i}"), &format!("g{i}"), "GB", "Paranoid"))
+            .collect();
+        let ranked = rank_matches(&test_candidate(), &many, &test_preferences());
+        assert_eq!(ranked.len(), CANDIDATE_LIMIT);
+        assert_eq!(ranked[0].release.id, "r0");
+    }
+
+    struct CountingFileOps(std::sync::atomic::AtomicUsize);
+
+    impl FileOps for CountingFileOps {
+        fn open(&self, path: &Path) -> std::io::Result<std::fs::File> {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            std::fs::File::open(path)
+        }
+    }
+
+    #[test]
+    fn test_scan_opens_each_file_once() {
+        const FIXTURES: u32 = 300;
+
+        let dir = std::env::temp_dir().join(format!("rsbts-scan-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for i in 0..FIXTURES {
+            std::fs::write(dir.join(format!("{i:03}.wav")), wav_bytes(800 + i)).unwrap();
+        }
+
+        let ops = CountingFileOps(std::sync::atomic::AtomicUsize::new(0));
+        let files = audio_files(&dir, &[]);
+        let scan = scan_with_progress(
+            files,
+            AnalyzeOptions::all(),
+            &ScanOptions::default(),
+            &ops,
+            &NoProgress,
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let analyses = scan.items;
+        assert!(scan.failures.is_empty());
+        assert_eq!(analyses.len(), FIXTURES as usize);
+        assert_eq!(
+            ops.0.load(std::sync::atomic::Ordering::Relaxed),
+            FIXTURES as usize
+        );
+        assert!(analyses.iter().all(|a| a.content_hash.is_some()));
+        assert!(analyses.iter().all(|a| a.item.length > 0.0));
+    }
+
+    #[test]
+    fn test_scan_reports_unreadable_files() {
+        let dir = std::env::temp_dir().join(format!("rsbts-scan-errors-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("good.wav"), wav_bytes(800)).unwrap();
+        std::fs::write(dir.join("broken.flac"), b"not really a flac file").unwrap();
+
+        let scan = scan_with_progress(
+            audio_files(&dir, &[]),
+            AnalyzeOptions::all(),
+            &ScanOptions::default(),
+            &StdFileOps,
+            &NoProgress,
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(scan.items.len(), 1);
+        assert_eq!(scan.failures.len(), 1);
+        assert!(scan.failures[0].0.ends_with("broken.flac"));
+        assert!(scan.suspicious.is_empty());
+    }
+
+    /// Counts the files a scan reads.
+    struct CountingProgress(std::sync::atomic::AtomicUsize);
+
+    impl ScanProgress for CountingProgress {
+        fn on_files_found(&self, _count: usize) {}
+        fn tick(&self) {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        }
+        fn on_error(&self, _path: &Path, _error: &Error) {}
+        fn finish(&self, _track_count: usize) {}
+    }
+
+    #[test]
+    fn test_scan_options_leave_files_out_before_reading() {
+        let dir = std::env::temp_dir().join(format!("rsbts-scan-options-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("Artist/Album")).unwrap();
+        for name in ["a.wav", "b.flac", "c.wav", "Artist/d.wav", "Artist/Album/e.wav"] {
+            std::fs::write(dir.join(name), wav_bytes(800)).unwrap();
+        }
+        // 2020-01-01
+        let old = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_577_836_800);
+        let file = std::fs::File::options().write(true).open(dir.join("c.wav")).unwrap();
+        file.set_modified(old).unwrap();
+
+        let scanned = |options: &ScanOptions| {
+            let progress = CountingProgress(std::sync::atomic::AtomicUsize::new(0));
+            let files = options.files(&dir, &[]);
+            scan_with_progress(files, AnalyzeOptions::all(), options, &StdFileOps, &progress);
+            progress.0.load(std::sync::atomic::Ordering::Relaxed)
+        };
+        let wav = ScanOptions {
+            formats: vec![AudioFormat::Wav],
+            ..ScanOptions::default()
+        };
+        let shallow = ScanOptions {
+            max_depth: Some(1),
+            ..wav.clone()
+        };
+        let newer = ScanOptions {
+            newer_than: Some(crate::query::date_start("2024-01-01").unwrap()),
+            ..ScanOptions::default()
+        };
+        let counts = [
+            scanned(&ScanOptions::default()),
+            scanned(&wav),
+            scanned(&shallow),
+            scanned(&newer),
+        ];
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(counts, [5, 4, 3, 4]);
+    }
+
+    #[test]
+    fn test_import_records_source_path() {
+        let root = std::env::temp_dir().join(format!("rsbts-provenance-{}", std::process::id()));
+        let source = root.join("incoming/bandcamp/Paranoid");
+        let library = root.join("library");
+        std::fs::create_dir_all(&source).unwrap();
+        std::fs::write(source.join("01.wav"), wav_bytes(800)).unwrap();
+
+        let db = Database::open(&root.join("library.db")).unwrap();
+        db.migrate().unwrap();
+        let importer = Importer::new(
+            &db,
+            ImportConfig {
+                path_formats: PathFormats::new("$albumartist/$album/$title"),
+                ..testutil::import_config(library.clone())
+            },
+        )
+        .unwrap();
+
+        let mut item = test_item("War Pigs");
+        item.path = source.join("01.wav");
+        let candidate = AlbumCandidate {
+            artist: item.artist.clone(),
+            album: item.album.clone(),
+            compilation: false,
+            cue_sheet: None,
+            items: vec![item],
+        };
+        let (_, album_id) = importer.add_album(&candidate, None, None).unwrap();
+        importer.import_items(candidate.items, album_id, None, None).unwrap();
+
+        let source = std::fs::canonicalize(&source).unwrap();
+        let albums = db.query_albums(None).unwrap();
+        assert_eq!(albums[0].source_path.as_deref(), Some(source.as_path()));
+
+        let imported = db.query_items(Some("source_path:incoming/bandcamp")).unwrap();
+        assert_eq!(imported.len(), 1);
+        assert!(imported[0].path.starts_with(&library));
+        assert_eq!(imported[0].source_path, Some(source.join("01.wav")));
+
+        // Re-reading tags from the file's new location keeps the provenance
+        let id = imported[0].id.unwrap();
+        let reread = crate::tags::read_tags(&imported[0].path).unwrap();
+        db.update_item(id, &reread).unwrap();
+        let updated = db.album_items(album_id).unwrap();
+        assert_eq!(updated[0].source_path, Some(source.join("01.wav")));
+
+        drop(db);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_import_non_utf8_file_name() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let root = std::env::temp_dir().join(format!("rsbts-non-utf8-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("incoming")).unwrap();
+        let source = std::fs::canonicalize(root.join("incoming")).unwrap();
+        let file = source.join(OsStr::from_bytes(b"01 caf\xe9.wav"));
+        std::fs::write(&file, wav_bytes(800)).unwrap();
+
+        let db = Database::open(&root.join("library.db")).unwrap();
+        db.migrate().unwrap();
+        let importer = Importer::new(
+            &db,
+            ImportConfig {
+                path_formats: PathFormats::new("$albumartist/$album/$title"),
+                ..testutil::import_config(root.join("library"))
+            },
+        )
+        .unwrap();
+        let mut item = test_item("War Pigs");
+        item.path.clone_from(&file);
+        let candidate = AlbumCandidate {
+            artist: item.artist.clone(),
+            album: item.album.clone(),
+            compilation: false,
+            cue_sheet: None,
+            items: vec![item],
+        };
+        let (_, album_id) = importer.add_album(&candidate, None, None).unwrap();
+        importer.import_items(candidate.items, album_id, None, None).unwrap();
+
+        // The source is recorded byte for byte, so it's recognized again
+        let items = db.album_items(album_id).unwrap();
+        assert_eq!(items[0].source_path.as_deref(), Some(file.as_path()));
+        assert!(db.imported_from(&file).unwrap());
+        assert!(items[0].path.exists());
+
+        drop(db);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    /// Import one album of `titles` from wav files under `root` into
+    /// `root/library`, returning the collisions.
+    fn import_titles(
+        root: &Path,
+        db: &Database,
+        action: Action,
+        on_conflict: ConflictPolicy,
+        titles: &[&str],
+    ) -> Vec<Collision> {
+        import_titles_with_hooks(root, db, action, on_conflict, Hooks::disabled(), titles)
+    }
+
+    /// [`import_titles`], running `hooks`.
+    fn import_titles_with_hooks(
+        root: &Path,
+        db: &Database,
+        action: Action,
+        on_conflict: ConflictPolicy,
+        hooks: Hooks,
+        titles: &[&str],
+    ) -> Vec<Collision> {
+        let source = root.join("incoming");
+        std::fs::create_dir_all(&source).unwrap();
+        let importer = Importer::new(
+            db,
+            ImportConfig {
+                action,
+                path_formats: PathFormats::new("$albumartist/$album/$title"),
+                on_conflict,
+                ..testutil::import_config(root.join("library"))
+            },
+        )
+        .unwrap()
+        .with_hooks(hooks);
+        let items: Vec<Item> = titles
+            .iter()
+            .enumerate()
+            .map(|(i, title)| {
+                let mut item = test_item(title);
+                item.path = source.join(format!("{i:02}.wav"));
+                std::fs::write(&item.path, wav_bytes(800)).unwrap();
+                item
+            })
+            .collect();
+        let candidate = AlbumCandidate {
+            artist: items[0].artist.clone(),
+            album: items[0].album.clone(),
+            compilation: false,
+            cue_sheet: None,
+            items,
+        };
+        let (_, album_id) = importer.add_album(&candidate, None, None).unwrap();
+        importer.import_items(candidate.items, album_id, None, None).unwrap()
+    }
+
+    #[test]
+    fn test_numbered_paths() {
+        assert_eq!(numbered(Path::new("/m/Intro.flac"), 1), Path::new("/m/Intro (1).flac"));
+        assert_eq!(numbered(Path::new("/m/Intro"), 2), Path::new("/m/Intro (2)"));
+    }
+
+    #[test]
+    fn test_rename_on_conflict() {
+        let root = std::env::temp_dir().join(format!("rsbts-rename-{}", std::process::id()));
+        let db = Database::open_in_memory().unwrap();
+        db.migrate().unwrap();
+
+        // Two tracks of one album with the same destination
+        let first =
+            import_titles(&root, &db, Action::Copy, ConflictPolicy::Rename, &["Intro", "Intro"]);
+        let dir = root.join("library/Black Sabbath/Paranoid");
+        assert_eq!(first.len(), 1);
+        assert_eq!(first[0].source, root.join("incoming/01.wav"));
+        assert_eq!(first[0].resolution, Resolution::Renamed(dir.join("Intro (1).wav")));
+
+        // A file already there, in the library or not, is left alone
+        std::fs::write(dir.join("Outro.wav"), b"not ours").unwrap();
+        let second =
+            import_titles(&root, &db, Action::Copy, ConflictPolicy::Rename, &["Intro", "Outro"]);
+        let outro = std::fs::read(dir.join("Outro.wav")).unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        let renamed: Vec<_> = second.iter().map(|c| c.resolution.clone()).collect();
+        assert_eq!(
+            renamed,
+            [
+                Resolution::Renamed(dir.join("Intro (2).wav")),
+                Resolution::Renamed(dir.join("Outro (1).wav")),
+            ]
+        );
+        assert_eq!(outro, b"not ours");
+        assert_eq!(db.query_items(None).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_skip_on_conflict_leaves_moved_file() {
+        let root = std::env::temp_dir().join(format!("rsbts-skip-{}", std::process::id()));
+        let db = Database::open_in_memory().unwrap();
+        db.migrate().unwrap();
+        let dir = root.join("library/Black Sabbath/Paranoid");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("Intro.wav"), b"not ours").unwrap();
+
+        let collisions =
+            import_titles(&root, &db, Action::Move, ConflictPolicy::Skip, &["Intro", "Outro"]);
+        let source_left = root.join("incoming/00.wav").exists();
+        let intro = std::fs::read(dir.join("Intro.wav")).unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(
+            collisions,
+            [Collision {
+                source: root.join("incoming/00.wav"),
+                wanted: dir.join("Intro.wav"),
+                resolution: Resolution::Skipped,
+            }]
+        );
+        assert!(source_left);
+        assert_eq!(intro, b"not ours");
+        let items = db.query_items(None).unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].title, "Outro");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_overwrite_on_conflict_runs_removed_hook() {
+        let root = std::env::temp_dir().join(format!("rsbts-overwrite-{}", std::process::id()));
+        let db = Database::open_in_memory().unwrap();
+        db.migrate().unwrap();
+        let log = root.join("log");
+        let hooks = Hooks::new(HashMap::from([(
+            Event::ItemRemoved,
+            vec![
+                "sh".into(),
+                "-c".into(),
+                r#"echo "$1" >> "$2""#.into(),
+                "sh".into(),
+                "{title}".into(),
+                log.to_string_lossy().into_owned(),
+            ],
+        )]));
+
+        import_titles(&root, &db, Action::Copy, ConflictPolicy::Overwrite, &["Intro"]);
+        let collisions = import_titles_with_hooks(
+            &root,
+            &db,
+            Action::Copy,
+            ConflictPolicy::Overwrite,
+            hooks,
+            &["Intro"],
+        );
+        let removed = std::fs::read_to_string(&log).unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(collisions.len(), 1);
+        assert_eq!(collisions[0].resolution, Resolution::Overwritten);
+        assert_eq!(removed, "Intro\n");
+        assert_eq!(db.query_items(None).unwrap().len(), 1);
+    }
+
+    /// A directory under the temp dir holding a file to transfer.
+    fn transfer_source(name: &str) -> (PathBuf, PathBuf) {
+        let root = std::env::temp_dir().join(format!("rsbts-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let src = root.join("incoming.flac");
+        std::fs::write(&src, b"fLaC audio").unwrap();
+        (root, src)
+    }
+
+    /// Counts the bytes reported transferred.
+    #[derive(Default)]
+    struct Transferred(std::sync::atomic::AtomicU64);
+
+    impl Transferred {
+        fn bytes(&self) -> u64 {
+            self.0.load(std::sync::atomic::Ordering::Relaxed)
+        }
+    }
+
+    impl ScanProgress for Transferred {
+        fn on_files_found(&self, _count: usize) {}
+        fn tick(&self) {}
+        fn on_error(&self, _path: &Path, _error: &Error) {}
+        fn finish(&self, _track_count: usize) {}
+    }
+
+    impl ImportProgress for Transferred {
+        fn on_albums_found(&self, _count: usize) {}
+        fn on_album_start(&self, _position: usize, _name: &str, _bytes: u64) {}
+        fn on_transferred(&self, bytes: u64) {
+            self.0.fetch_add(bytes, std::sync::atomic::Ordering::Relaxed);
+        }
+        fn on_album_done(&self) {}
+    }
+
+    #[test]
+    fn test_transfer_copy_and_move() {
+        let (root, src) = transfer_source("transfer");
+        let progress = Transferred::default();
+        let copied = transfer_file(Action::Copy, &src, &root.join("library/copy.flac"), &progress);
+        let copy = std::fs::read(root.join("library/copy.flac")).unwrap();
+        let copied_bytes = progress.bytes();
+        let moved = transfer_file(Action::Move, &src, &root.join("library/moved.flac"), &progress);
+        let moved_file = std::fs::read(root.join("library/moved.flac")).unwrap();
+        let source_left = src.exists();
+
+        // Copied a chunk at a time
+        let big = root.join("big.flac");
+        std::fs::write(&big, vec![7; COPY_CHUNK * 2 + 1]).unwrap();
+        let chunked = Transferred::default();
+        transfer_file(Action::Copy, &big, &root.join("library/big.flac"), &chunked).unwrap();
+        let big_copy = std::fs::read(root.join("library/big.flac")).unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!((copied.unwrap(), moved.unwrap()), (Action::Copy, Action::Move));
+        assert_eq!(copy, b"fLaC audio");
+        assert_eq!(moved_file, b"fLaC audio");
+        assert!(!source_left);
+        // A rename counts as the whole file at once
+        assert_eq!((copied_bytes, progress.bytes()), (10, 20));
+        assert_eq!(chunked.bytes(), COPY_CHUNK as u64 * 2 + 1);
+        assert_eq!(big_copy.len(), COPY_CHUNK * 2 + 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_transfer_symlink_and_hardlink() {
+        use std::os::unix::fs::MetadataExt;
+
+        let (root, src) = transfer_source("transfer-links");
+        let symlink = root.join("library/symlink.flac");
+        let hardlink = root.join("library/hardlink.flac");
+        let linked = transfer_file(Action::Link, &src, &symlink, &NoProgress).unwrap();
+        let hardlinked = transfer_file(Action::HardLink, &src, &hardlink, &NoProgress).unwrap();
+
+        let target = std::fs::read_link(&symlink).unwrap();
+        let inodes = (
+            std::fs::metadata(&src).unwrap().ino(),
+            std::fs::metadata(&hardlink).unwrap().ino(),
+        );
+        let source = std::fs::canonicalize(&src).unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!((linked, hardlinked), (Action::Link, Action::HardLink));
+        assert_eq!(target, source);
+        assert_eq!(inodes.0, inodes.1);
+    }
+
+    #[test]
+    fn test_transfer_reflink_copies_where_unsupported() {
+        let (root, src) = transfer_source("transfer-reflink");
+        let dest = root.join("library/reflink.flac");
+        let done = transfer_file(Action::Reflink, &src, &dest, &NoProgress).unwrap();
+        let contents = std::fs::read(&dest).unwrap();
+        let source_left = src.exists();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        // Which one depends on the filesystem the temp dir is on
+        assert!(matches!(done, Action::Reflink | Action::Copy), "{done:?}");
+        assert_eq!(contents, b"fLaC audio");
+        assert!(source_left);
+    }
+
+    #[test]
+    fn test_undo_import_moves_files_back() {
+        let root = std::env::temp_dir().join(format!("rsbts-undo-{}", std::process::id()));
+        let mut sources = Vec::new();
+        for album in ["Paranoid", "Master of Reality"] {
+            let dir = root.join("incoming").join(album);
+            std::fs::create_dir_all(&dir).unwrap();
+            for track in 1..=2 {
+                let path = dir.join(format!("{track}.wav"));
+                std::fs::write(&path, wav_bytes(800)).unwrap();
+                let edits: Vec<FieldEdit> = [format!("album={album}"), format!("track={track}")]
+                    .iter()
+                    .map(|s| s.parse().unwrap())
+                    .collect();
+                write_tags(&path, &edits).unwrap();
+                sources.push(std::fs::canonicalize(&path).unwrap());
+            }
+        }
+
+        let db = Database::open(&root.join("library.db")).unwrap();
+        db.migrate().unwrap();
+        let importer = Importer::new(
+            &db,
+            ImportConfig {
+                path_formats: PathFormats::new("$album/$track"),
+                ..testutil::import_config(root.join("library"))
+            },
+        )
+        .unwrap();
+        let files = audio_files(&root.join("incoming"), &[]);
+        let items = scan(files, &ScanOptions::default(), &NoProgress).items;
+        for candidate in group_into_albums(items) {
+            let (_, album_id) = importer.add_album(&candidate, None, None).unwrap();
+            importer.import_items(candidate.items, album_id, None, None).unwrap();
+        }
+
+        let runs = db.import_runs().unwrap();
+        assert_eq!(runs.len(), 1);
+        assert_eq!((runs[0].albums, runs[0].items), (2, 4));
+        assert_eq!(runs[0].sources.len(), 2);
+        assert!(sources.iter().all(|path| !path.exists()));
+
+        let report = crate::runs::undo(&db, importer.run_id(), None, &Hooks::disabled()).unwrap();
+        let restored: Vec<bool> = sources.iter().map(|path| path.exists()).collect();
+        let library_files = audio_files(&root.join("library"), &[]).len();
+        let left = (
+            db.query_items(None).unwrap().len(),
+            db.query_albums(None).unwrap().len(),
+            db.import_runs().unwrap().len(),
+        );
+        drop(db);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!((report.items, report.albums, report.restored), (4, 2, 4));
+        assert!(report.failed.is_empty());
+        assert_eq!(restored, [true; 4]);
+        assert_eq!(library_files, 0);
+        assert_eq!(left, (0, 0, 0));
+    }
+
+    #[test]
+    fn test_placeholder_positions_stay_out_of_filenames() {
+        let root = std::env::temp_dir().join(format!("rsbts-positions-{}", std::process::id()));
+        let source = root.join("incoming");
+        std::fs::create_dir_all(&source).unwrap();
+        for title in ["War Pigs", "Iron Man"] {
+            let path = source.join(format!("{title}.wav"));
+            std::fs::write(&path, wav_bytes(800)).unwrap();
+            let edits: Vec<FieldEdit> = [
+                format!("title={title}"),
+                "album=Paranoid".into(),
+                "track=0".into(),
+                "disc=255".into(),
+            ]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+            write_tags(&path, &edits).unwrap();
+        }
+
+        let mut items = scan(audio_files(&source, &[]), &ScanOptions::default(), &NoProgress).items;
+        assert!(items.iter().all(|i| i.track.is_none() && i.disc.is_none()));
+
+        // Only Iron Man is on the matched release, as track 4 of disc 1
+        let mut release = test_release("paranoid", "Black Sabbath", "Paranoid");
+        release.media = vec![Medium {
+            position: 1,
+            format: None,
+            track_count: 1,
+            tracks: vec![Track {
+                id: "t4".into(),
+                position: 4,
+                number: "4".into(),
+                title: "Iron Man".into(),
+                length: None,
+                recording: Recording {
+                    id: "r4".into(),
+                    title: "Iron Man".into(),
+                    length: None,
+                },
+                artist_credit: Vec::new(),
+            }],
+        }];
+        let iron_man = items.iter().position(|i| i.title == "Iron Man").unwrap();
+        let matched = match_tracks(vec![items.remove(iron_man)], &release);
+        items.extend(matched);
+
+        let db = Database::open(&root.join("library.db")).unwrap();
+        db.migrate().unwrap();
+        let importer = Importer::new(
+            &db,
+            ImportConfig {
+                action: Action::Copy,
+                path_formats: PathFormats::new(
+                    "$album/%if{$disc,$disc-}%if{$track,$track-}$title",
+                ),
+                ..testutil::import_config(root.join("library"))
+            },
+        )
+        .unwrap();
+        let candidate = AlbumCandidate {
+            artist: "Black Sabbath".into(),
+            album: "Paranoid".into(),
+            compilation: false,
+            cue_sheet: None,
+            items,
+        };
+        let (_, album_id) = importer.add_album(&candidate, None, None).unwrap();
+        importer.import_items(candidate.items, album_id, None, None).unwrap();
+
+        let mut paths: Vec<PathBuf> = db
+            .album_items(album_id)
+            .unwrap()
+            .into_iter()
+            .map(|i| i.path.strip_prefix(root.join("library")).unwrap().to_path_buf())
+            .collect();
+        paths.sort();
+        drop(db);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(
+            paths,
+            [
+                PathBuf::from("Paranoid/1-04-Iron Man.wav"),
+                PathBuf::from("Paranoid/War Pigs.wav"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_later_disc_joins_existing_album() {
+        let root = std::env::temp_dir().join(format!("rsbts-merge-{}", std::process::id()));
+        let discs = [("1", ["War Pigs", "Paranoid"]), ("2", ["Iron Man", "Electric Funeral"])];
+        for (disc, titles) in discs {
+            let dir = root.join("incoming").join(disc);
+            std::fs::create_dir_all(&dir).unwrap();
+            for (track, title) in titles.iter().enumerate() {
+                let path = dir.join(format!("{}.wav", track + 1));
+                std::fs::write(&path, wav_bytes(800)).unwrap();
+                let edits: Vec<FieldEdit> = [
+                    format!("title={title}"),
+                    "album=Paranoid".into(),
+                    format!("track={}", track + 1),
+                    format!("disc={disc}"),
+                ]
+                .iter()
+                .map(|s| s.parse().unwrap())
+                .collect();
+                write_tags(&path, &edits).unwrap();
+            }
+        }
+
+        let db = Database::open(&root.join("library.db")).unwrap();
+        db.migrate().unwrap();
+        // Each disc is its own import, as when the second turns up later
+        for (disc, _) in discs {
+            let importer = Importer::new(
+                &db,
+                ImportConfig {
+                    action: Action::Copy,
+                    path_formats: PathFormats::new("$album/$disc-$track $title"),
+                    merge_into_existing: MergePolicy::Always,
+                    ..testutil::import_config(root.join("library"))
+                },
+            )
+            .unwrap();
+            let files = audio_files(&root.join("incoming").join(disc), &[]);
+            let items = scan(files, &ScanOptions::default(), &NoProgress).items;
+            for candidate in group_into_albums(items) {
+                importer
+                    .process_resolved(
+                        ResolvedAlbum {
+                            candidate,
+                            release: None,
+                            cover_art: None,
+                            notes: Vec::new(),
+                        },
+                        None,
+                        None,
+                    )
+                    .unwrap();
+            }
+        }
+
+        let albums = db.query_albums(None).unwrap();
+        let items = db.album_items(albums[0].id.unwrap()).unwrap();
+        let dirs: Vec<PathBuf> = items
+            .iter()
+            .map(|i| i.path.parent().unwrap().to_path_buf())
+            .collect();
+        let positions: Vec<(Option<u32>, Option<u32>)> =
+            items.iter().map(|i| (i.disc, i.track)).collect();
+        drop(db);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(albums.len(), 1);
+        assert_eq!(
+            positions,
+            [(Some(1), Some(1)), (Some(1), Some(2)), (Some(2), Some(1)), (Some(2), Some(2))]
+        );
+        assert!(dirs.iter().all(|dir| *dir == root.join("library/Paranoid")));
+    }
+
+    #[test]
+    fn test_later_half_fills_in_existing_album() {
+        let root = std::env::temp_dir().join(format!("rsbts-halves-{}", std::process::id()));
+        for (track, title) in [(1, "War Pigs"), (2, "Paranoid")] {
+            let dir = root.join("incoming").join(track.to_string());
+            std::fs::create_dir_all(&dir).unwrap();
+            let path = dir.join(format!("{track}.wav"));
+            std::fs::write(&path, wav_bytes(800)).unwrap();
+            let edits: Vec<FieldEdit> = [
+                format!("title={title}"),
+                "artist=Black Sabbath".into(),
+                "album=Paranoid".into(),
+                format!("track={track}"),
+            ]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+            write_tags(&path, &edits).unwrap();
+        }
+
+        let db = Database::open_in_memory().unwrap();
+        db.migrate().unwrap();
+        let config = ImportConfig {
+            action: Action::Copy,
+            merge_into_existing: MergePolicy::Always,
+            ..tagged_album_config(&root)
+        };
+        // The first half goes by its tags; the second, imported later, matches
+        let halves = [
+            ("1", None, b"\xff\xd8 first".to_vec()),
+            ("2", Some(scripted_release()), b"\xff\xd8 second".to_vec()),
+        ];
+        let mut merges_into_art = Vec::new();
+        let mut runs = Vec::new();
+        for (dir, release, art) in halves {
+            let importer = Importer::with_source(&db, config.clone(), NullSource).unwrap();
+            runs.push(importer.run_id().to_string());
+            let files = audio_files(&root.join("incoming").join(dir), &[]);
+            let items = scan(files, &ScanOptions::default(), &NoProgress).items;
+            for candidate in group_into_albums(items) {
+                merges_into_art.push(importer.merges_into_art(&candidate).unwrap());
+                let resolved = ResolvedAlbum {
+                    candidate,
+                    release: release.clone(),
+                    cover_art: Some(art.clone()),
+                    notes: Vec::new(),
+                };
+                importer.process_resolved(resolved, None, None).unwrap();
+            }
+        }
+
+        let albums = db.query_albums(None).unwrap();
+        let items = db.album_items(albums[0].id.unwrap()).unwrap();
+        let art = std::fs::read(albums[0].artpath.as_ref().unwrap()).unwrap();
+        // Undoing the second half takes back what it filled in
+        let report = crate::runs::undo(&db, &runs[1], None, &Hooks::disabled()).unwrap();
+        let undone = db.query_albums(None).unwrap();
+        let left = db.album_items(undone[0].id.unwrap()).unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(albums.len(), 1);
+        assert_eq!(albums[0].year, Some(1970));
+        assert_eq!(albums[0].mb_albumid.as_deref(), Some("release"));
+        assert_eq!(items.len(), 2);
+        assert!(items
+            .iter()
+            .all(|i| i.year == Some(1970) && i.mb_albumid.as_deref() == Some("release")));
+        // The album's art is kept, and none need be fetched for the second half
+        assert_eq!(art, b"\xff\xd8 first");
+        assert_eq!(merges_into_art, [false, true]);
+        assert_eq!((report.items, report.albums, report.cleared), (1, 0, 3));
+        assert_eq!(undone.len(), 1);
+        assert_eq!((undone[0].year, undone[0].mb_albumid.as_deref()), (None, None));
+        assert_eq!(left.len(), 1);
+        assert_eq!((left[0].year, left[0].mb_albumid.as_deref()), (None, None));
+    }
+
+    #[test]
+    fn test_same_track_by_position_or_title() {
+        let mut a = test_item("War Pigs");
+        a.track = Some(1);
+        let mut b = test_item("Iron Man");
+        b.track = Some(1);
+        b.disc = Some(2);
+        assert!(!same_track(&a, &b));
+        b.disc = Some(1);
+        assert!(same_track(&a, &b));
+
+        let mut c = test_item("war  pigs");
+        c.track = Some(5);
+        assert!(same_track(&a, &c));
+        assert!(!same_track(&test_item("Paranoid"), &test_item("Iron Man")));
+    }
+
+    #[test]
+    fn test_sort_names_filled_from_artist_credits() {
+        let credit = |name: &str, sort_name: &str, joinphrase: &str| ArtistCredit {
+            artist: Artist {
+                id: name.into(),
+                name: name.into(),
+                sort_name: sort_name.into(),
+            },
+            joinphrase: joinphrase.into(),
+        };
+        let mut release = scripted_release();
+        release.artist_credit = vec![credit("The Chemical Brothers", "Chemical Brothers, The", "")];
+        release.media[0].tracks[1].artist_credit = vec![
+            credit("The Chemical Brothers", "Chemical Brothers, The", " feat. "),
+            credit("Noel Gallagher", "Gallagher, Noel", ""),
+        ];
+        let mut items: Vec<Item> = ["War Pigs", "Paranoid"].map(test_item).into();
+        for item in &mut items {
+            item.artist = "The Chemical Brothers".into();
+        }
+        let mut tagged = test_item("Tagged");
+        tagged.artist_sort = Some("Sabbath".into());
+        items.push(tagged);
+
+        let matched = match_tracks(items, &release);
+        let sort_names: Vec<_> = matched
+            .iter()
+            .map(|item| (item.artist_sort.as_deref(), item.albumartist_sort.as_deref()))
+            .collect();
+        let album = Some("Chemical Brothers, The");
+        assert_eq!(
+            sort_names,
+            [
+                (album, album),
+                (Some("Chemical Brothers, The feat. Gallagher, Noel"), album),
+                (Some("Sabbath"), album),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_only_filter_selects_matching_album() {
+        let root = std::env::temp_dir().join(format!("rsbts-only-{}", std::process::id()));
+        for (artist, album) in [
+            ("John Coltrane", "Blue Train"),
+            ("John Coltrane", "Giant Steps"),
+            ("Miles Davis", "Kind of Blue"),
+        ] {
+            let dir = root.join(album);
+            std::fs::create_dir_all(&dir).unwrap();
+            for track in 1..=2 {
+                let path = dir.join(format!("{track}.wav"));
+                std::fs::write(&path, wav_bytes(800)).unwrap();
+                let edits: Vec<FieldEdit> = [
+                    format!("artist={artist}"),
+                    format!("album={album}"),
+                    format!("track={track}"),
+                ]
+                .iter()
+                .map(|s| s.parse().unwrap())
+                .collect();
+                write_tags(&path, &edits).unwrap();
+            }
+        }
+
+        let files = audio_files(&root, &[]);
+        let candidates =
+            group_into_albums(scan(files, &ScanOptions::default(), &NoProgress).items);
+        let only = only_filter("album:Blue Train").unwrap();
+        let (selected, skipped) = select_candidates(candidates, Some(&only)).unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(selected.len(), 1);
+        assert_eq!(selected[0].album, "Blue Train");
+        assert_eq!(selected[0].items.len(), 2);
+        assert_eq!(skipped, ["John Coltrane - Giant Steps", "Miles Davis - Kind of Blue"]);
+
+        let err = only_filter("mb_albumid:abc").unwrap_err();
+        assert!(err.to_string().contains("mb_albumid has no value before import"));
+        assert!(only_filter("bogus:x").is_err());
+    }
+
+    #[test]
+    fn test_zero_length_is_suspicious() {
+        let mut item = test_item("Silence");
+        assert!(!is_suspicious(&item));
+        item.length = 0.0;
+        assert!(is_suspicious(&item));
+    }
+
+    /// Answers every search with one canned release.
+    struct ScriptedSource {
+        release: Release,
+        cover_art: Option<Vec<u8>>,
+    }
+
+    impl MetadataSource for ScriptedSource {
+        async fn search_release(&self, _: &str, _: &str, _: u32) -> Result<Vec<Release>> {
+            Ok(vec![self.release.clone()])
+        }
+
+        async fn lookup_release(&self, mbid: &str) -> Result<Release> {
+            if mbid == self.release.id {
+                Ok(self.release.clone())
+            } else {
+                Err(ApiError::Other(format!("No release {mbid}")).into())
+            }
+        }
+
+        async fn lookup_recording(&self, mbid: &str) -> Result<Recording> {
+            Err(ApiError::Other(format!("No recording {mbid}")).into())
+        }
+
+        async fn fetch_cover_art(&self, _: &str) -> Result<Option<Vec<u8>>> {
+            Ok(self.cover_art.clone())
+        }
+    }
+
+    fn scripted_release() -> Release {
+        let track = |position: u32, title: &str| Track {
+            id: format!("track-{position}"),
+            position,
+            number: position.to_string(),
+            title: title.into(),
+            length: None,
+            recording: Recording {
+                id: format!("recording-{position}"),
+                title: title.into(),
+                length: None,
+            },
+            artist_credit: Vec::new(),
+        };
+        Release {
+            date: Some("1970-09-18".into()),
+            release_group: Some(ReleaseGroup {
+                id: "release-group".into(),
+                title: "Paranoid".into(),
+                primary_type: Some("Album".into()),
+            }),
+            media: vec![Medium {
+                position: 1,
+                format: Some("CD".into()),
+                track_count: 2,
+                tracks: vec![track(1, "War Pigs"), track(2, "Paranoid")],
+            }],
+            ..test_release("release", "Black Sabbath", "Paranoid")
+        }
+    }
+
+    /// Import two tagged files through `source`, returning the library's
+    /// albums and their tracks' titles, `MusicBrainz` IDs and paths in it.
+    async fn import_tagged_album<S: MetadataSource + 'static>(
+        name: &str,
+        source: S,
+    ) -> (Vec<Album>, Vec<(String, Option<String>, PathBuf)>) {
+        let root = std::env::temp_dir().join(format!("rsbts-{name}-{}", std::process::id()));
+        let source_dir = root.join("incoming");
+        write_tagged_album(&source_dir);
+
+        let db = Database::open(&root.join("library.db")).unwrap();
+        db.migrate().unwrap();
+        let importer = Importer::with_source(&db, tagged_album_config(&root), source).unwrap();
+        let report = importer.import(&source_dir).await.unwrap();
+        assert!(report.failures.is_empty());
+
+        let albums = db.query_albums(None).unwrap();
+        let items = db
+            .album_items(albums[0].id.unwrap())
+            .unwrap()
+            .into_iter()
+            .map(|i| {
+                let path = i.path.strip_prefix(root.join("library")).unwrap().to_path_buf();
+                (i.title, i.mb_trackid, path)
+            })
+            .collect();
+        drop(db);
+        std::fs::remove_dir_all(&root).unwrap();
+        (albums, items)
+    }
+
+    /// Write two tagged tracks of an album into `dir`.
+    fn write_tagged_album(dir: &Path) {
+        std::fs::create_dir_all(dir).unwrap();
+        for (track, title) in [(1, "war pigs (luke's wall)"), (2, "paranoid")] {
+            let path = dir.join(format!("{track}.wav"));
+            std::fs::write(&path, wav_bytes(800)).unwrap();
+            let edits: Vec<FieldEdit> = [
+                format!("title={title}"),
+                "artist=Black Sabbath".into(),
+                "album=Paranoid".into(),
+                format!("track={track}"),
+            ]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+            write_tags(&path, &edits).unwrap();
+        }
+    }
+
+    /// Moving into `root/library` as `$album/$track $title`.
+    fn tagged_album_config(root: &Path) -> ImportConfig {
+        ImportConfig {
+            fetch_art: true,
+            art_filenames: DEFAULT_ART_FILENAMES.iter().map(|&name| name.into()).collect(),
+            ..testutil::import_config(root.join("library"))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_move_prunes_emptied_source_dirs() {
+        let root = std::env::temp_dir().join(format!("rsbts-prune-import-{}", std::process::id()));
+        let incoming = root.join("incoming");
+        let album = incoming.join("Black Sabbath/Paranoid");
+        write_tagged_album(&album.join("CD1"));
+        std::fs::write(album.join("rip.log"), b"").unwrap();
+        std::fs::write(incoming.join("Black Sabbath/notes.txt"), b"").unwrap();
+        let kept = incoming.join("Master of Reality");
+        write_tagged_album(&kept);
+
+        let db = Database::open_in_memory().unwrap();
+        db.migrate().unwrap();
+        let config = ImportConfig {
+            clutter: vec!["*.LOG".into()],
+            ..tagged_album_config(&root)
+        };
+        let importer = Importer::with_source(&db, config.clone(), NullSource).unwrap();
+        importer.import(&incoming.join("Black Sabbath")).await.unwrap();
+        let pruned = (album.exists(), incoming.join("Black Sabbath").exists());
+        let config = ImportConfig {
+            keep_empty_dirs: true,
+            ..config
+        };
+        let importer = Importer::with_source(&db, config, NullSource).unwrap();
+        importer.import(&incoming).await.unwrap();
+        let kept_left = kept.exists();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        // The directory with an unknown file, the import root, stays
+        assert_eq!(pruned, (false, true));
+        assert!(kept_left);
+    }
+
+    #[tokio::test]
+    async fn test_import_normalizes_genres() {
+        let root = std::env::temp_dir().join(format!("rsbts-genres-{}", std::process::id()));
+        let incoming = root.join("incoming");
+        write_tagged_album(&incoming);
+        for (track, genre) in [(1, "heavy metal"), (2, "PROTO-PUNK")] {
+            let edits = [format!("genre={genre}").parse().unwrap()];
+            write_tags(&incoming.join(format!("{track}.wav")), &edits).unwrap();
+        }
+
+        let db = Database::open_in_memory().unwrap();
+        db.migrate().unwrap();
+        let config = ImportConfig {
+            genres: Genres::new([("Heavy Metal", "Metal")], true),
+            ..tagged_album_config(&root)
+        };
+        let importer = Importer::with_source(&db, config, NullSource).unwrap();
+        importer.import(&incoming).await.unwrap();
+        let genres: Vec<_> = db.query_items(None).unwrap().into_iter().map(|i| i.genre).collect();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(genres, [Some("Metal".into()), Some("PROTO-PUNK".into())]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_import_inside_symlinked_library() {
+        let root = std::env::temp_dir().join(format!("rsbts-inside-lib-{}", std::process::id()));
+        let music = root.join("music");
+        std::fs::create_dir_all(&music).unwrap();
+        std::fs::create_dir_all(root.join("music-2")).unwrap();
+        // Configured through a symlink, as the library directory often is
+        std::os::unix::fs::symlink(&music, root.join("library")).unwrap();
+        write_tagged_album(&root.join("incoming"));
+
+        let db = Database::open_in_memory().unwrap();
+        db.migrate().unwrap();
+        let importer = Importer::with_source(&db, tagged_album_config(&root), NullSource).unwrap();
+        importer.import(&root.join("incoming")).await.unwrap();
+        let refused: Vec<bool> = [
+            root.join("library"),
+            root.join("library/Paranoid"),
+            music.join("Paranoid"),
+            root.join("music/../library/."),
+            root.join("music-2"),
+            root.join("incoming"),
+        ]
+        .iter()
+        .map(|path| importer.check_source(path).is_err())
+        .collect();
+
+        let config = ImportConfig {
+            inside_library: true,
+            ..tagged_album_config(&root)
+        };
+        let importer = Importer::with_source(&db, config, NullSource).unwrap();
+        let report = importer.import(&music).await.unwrap();
+        let albums = db.query_albums(None).unwrap().len();
+        let items = db.query_items(None).unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(refused, [true, true, true, true, false, false]);
+        // The library's own files are left out before they can make an album
+        assert!(report.failed_albums.is_empty() && report.collisions.is_empty());
+        assert_eq!(albums, 1);
+        assert_eq!(items.len(), 2);
+        assert!(items.iter().all(|item| item.path.starts_with(root.join("library"))));
+    }
+
+    #[tokio::test]
+    async fn test_rematch_leaves_untracked_cover() {
+        let dir = std::env::temp_dir().join(format!("rsbts-rematch-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("cover.jpg"), b"\xff\xd8 mine").unwrap();
+        let album = Album {
+            id: Some(1),
+            album: "Paranoid".into(),
+            albumartist: "Black Sabbath".into(),
+            ..testutil::album()
+        };
+        let items: Vec<Item> = [(1, "War Pigs"), (2, "Paranoid")]
+            .into_iter()
+            .map(|(track, title)| Item {
+                path: dir.join(format!("{track}.flac")),
+                title: title.into(),
+                artist: "Black Sabbath".into(),
+                album: "Paranoid".into(),
+                albumartist: Some("Black Sabbath".into()),
+                track: Some(track),
+                album_id: Some(1),
+                ..testutil::item()
+            })
+            .collect();
+        let db = Database::open_in_memory().unwrap();
+        db.migrate().unwrap();
+        let source = ScriptedSource {
+            release: scripted_release(),
+            cover_art: Some(b"\xff\xd8 fetched".to_vec()),
+        };
+        let importer = Importer::with_source(&db, tagged_album_config(&dir), source).unwrap();
+
+        let kept = importer.rematch(&album, items.clone()).await.unwrap().unwrap();
+        std::fs::remove_file(dir.join("cover.jpg")).unwrap();
+        let fetched = importer.rematch(&album, items).await.unwrap().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(kept.album.mb_albumid.as_deref(), Some("release"));
+        assert!(kept.cover_art.is_none());
+        let (path, art) = fetched.cover_art.unwrap();
+        assert_eq!(path, dir.join("cover.jpg"));
+        assert_eq!(art, b"\xff\xd8 fetched");
+    }
+
+    #[tokio::test]
+    async fn test_import_matches_scripted_release() {
+        let source = ScriptedSource {
+            release: scripted_release(),
+            cover_art: None,
+        };
+        let (albums, items) = import_tagged_album("scripted", source).await;
+
+        assert_eq!(albums.len(), 1);
+        assert_eq!(albums[0].mb_albumid.as_deref(), Some("release"));
+        assert_eq!(albums[0].mb_releasegroupid.as_deref(), Some("release-group"));
+        assert_eq!(albums[0].year, Some(1970));
+        assert_eq!(
+            items,
+            [
+                ("War Pigs".into(), Some("recording-1".into()), "Paranoid/01 War Pigs.wav".into()),
+                ("Paranoid".into(), Some("recording-2".into()), "Paranoid/02 Paranoid.wav".into()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_quiet_fallback_skips_unmatched_albums() {
+        let root =
+            std::env::temp_dir().join(format!("rsbts-skip-unmatched-{}", std::process::id()));
+        let source_dir = root.join("incoming");
+        write_tagged_album(&source_dir);
+
+        let db = Database::open_in_memory().unwrap();
+        db.migrate().unwrap();
+        let config = ImportConfig {
+            quiet_fallback: QuietFallback::Skip,
+            ..tagged_album_config(&root)
+        };
+        let importer = Importer::with_source(&db, config, NullSource).unwrap();
+        let report = importer.import(&source_dir).await.unwrap();
+        let (left, source) = (audio_files(&source_dir, &[]).len(), absolute(&source_dir));
+
+        // Sharing the directory with another album, each is retried by its files
+        let other = source_dir.join("3.wav");
+        std::fs::write(&other, wav_bytes(800)).unwrap();
+        let edits = ["artist=Black Sabbath".parse().unwrap(), "album=Vol. 4".parse().unwrap()];
+        write_tags(&other, &edits).unwrap();
+        let shared = importer.import(&source_dir).await.unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(report.unmatched, [("Black Sabbath - Paranoid".to_string(), source.clone())]);
+        assert_eq!(report.retry, [source.clone()]);
+        let mut retry = shared.retry;
+        retry.sort();
+        assert_eq!(retry, ["1.wav", "2.wav", "3.wav"].map(|name| source.join(name)));
+        assert!(report.is_empty());
+        assert_eq!(left, 2);
+        assert!(db.query_items(None).unwrap().is_empty());
+        assert!(db.query_albums(None).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_import_offline_keeps_tags() {
+        let (albums, items) = import_tagged_album("offline", NullSource).await;
+
+        assert_eq!(albums.len(), 1);
+        assert_eq!(albums[0].mb_albumid, None);
+        assert_eq!(
+            items,
+            [
+                (
+                    "war pigs (luke's wall)".into(),
+                    None,
+                    "Paranoid/01 war pigs (luke's wall).wav".into()
+                ),
+                ("paranoid".into(), None, "Paranoid/02 paranoid.wav".into()),
+            ]
+        );
+    }
+
+    /// Finds releases only by id, as if searching found nothing.
+    struct LookupOnlySource(Release);
+
+    impl MetadataSource for LookupOnlySource {
+        async fn search_release(&self, _: &str, _: &str, _: u32) -> Result<Vec<Release>> {
+            Ok(Vec::new())
+        }
+
+        async fn lookup_release(&self, mbid: &str) -> Result<Release> {
+            if mbid == self.0.id {
+                Ok(self.0.clone())
+            } else {
+                Err(ApiError::Other(format!("No release {mbid}")).into())
+            }
+        }
+
+        async fn lookup_recording(&self, mbid: &str) -> Result<Recording> {
+            Err(ApiError::Other(format!("No recording {mbid}")).into())
+        }
+
+        async fn fetch_cover_art(&self, _: &str) -> Result<Option<Vec<u8>>> {
+            Ok(None)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_import_looks_up_tagged_ids() {
+        let root = std::env::temp_dir().join(format!("rsbts-tagged-ids-{}", std::process::id()));
+        let source_dir = root.join("incoming");
+        std::fs::create_dir_all(&source_dir).unwrap();
+        // Tagged by Picard, with titles that say nothing of which track is which
+        for (n, recording) in [(1, Some("recording-2")), (2, None)] {
+            let path = source_dir.join(format!("{n}.wav"));
+            std::fs::write(&path, wav_bytes(800)).unwrap();
+            let mut edits = vec![
+                format!("title=track {n}"),
+                "artist=Sabbath".into(),
+                "album=Untitled".into(),
+                "mb_albumid=release".into(),
+            ];
+            edits.extend(recording.map(|id| format!("mb_trackid={id}")));
+            let edits: Vec<FieldEdit> = edits.iter().map(|s| s.parse().unwrap()).collect();
+            write_tags(&path, &edits).unwrap();
+        }
+
+        let db = Database::open_in_memory().unwrap();
+        db.migrate().unwrap();
+        let source = LookupOnlySource(scripted_release());
+        let importer = Importer::with_source(&db, tagged_album_config(&root), source).unwrap();
+        let report = importer.import(&source_dir).await.unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert!(report.is_empty());
+        let albums = db.query_albums(None).unwrap();
+        assert_eq!(albums.len(), 1);
+        assert_eq!(albums[0].mb_albumid.as_deref(), Some("release"));
+        let mut items: Vec<(String, String, Option<String>)> = db
+            .query_items(None)
+            .unwrap()
+            .into_iter()
+            .map(|item| {
+                let source = item.source_path.unwrap();
+                let name = source.file_name().unwrap().to_string_lossy().into_owned();
+                (name, item.title, item.mb_trackid)
+            })
+            .collect();
+        items.sort();
+        assert_eq!(
+            items,
+            [
+                ("1.wav".into(), "Paranoid".into(), Some("recording-2".into())),
+                ("2.wav".into(), "War Pigs".into(), Some("recording-1".into())),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_import_cue_rip_goes_without_lookup() {
+        let root = std::env::temp_dir().join(format!("rsbts-cue-rip-{}", std::process::id()));
+        let source_dir = root.join("incoming");
+        std::fs::create_dir_all(&source_dir).unwrap();
+        // The whole album in one file, tagged with a release that has two
+        let path = source_dir.join("Paranoid.wav");
+        std::fs::write(&path, wav_bytes(800)).unwrap();
+        let edits: Vec<FieldEdit> = ["title=Paranoid", "album=Paranoid", "mb_albumid=release"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        write_tags(&path, &edits).unwrap();
+        std::fs::write(
+            source_dir.join("Paranoid.cue"),
+            "FILE \"Paranoid.wav\" WAVE\nTRACK 01 AUDIO\nINDEX 01 00:00:00\n\
+             TRACK 02 AUDIO\nINDEX 01 07:57:00\n",
+        )
+        .unwrap();
+
+        let db = Database::open_in_memory().unwrap();
+        db.migrate().unwrap();
+        let config = ImportConfig {
+            clutter: vec!["*.cue".into()],
+            ..tagged_album_config(&root)
+        };
+        let importer = Importer::with_source(&db, config, LookupOnlySource(scripted_release()))
+            .unwrap();
+        let report = importer.import(&source_dir).await.unwrap();
+        // The sheet moves along with the file, and the import undoes with it
+        let sheet_left = source_dir.join("Paranoid.cue").exists();
+        let items = db.query_items(None).unwrap();
+        let sheet_moved = items[0].path.with_extension("cue").exists();
+        crate::runs::undo(&db, importer.run_id(), None, &Hooks::disabled()).unwrap();
+        let sheet_back = source_dir.join("Paranoid.cue").exists();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert!(report.is_empty());
+        assert!(!sheet_left);
+        assert!(sheet_moved);
+        assert!(sheet_back);
+        let albums = db.query_albums(None).unwrap();
+        assert_eq!(albums.len(), 1);
+        assert_eq!(albums[0].mb_albumid, None);
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].title, "Paranoid");
+    }
+
+    /// Can't be reached, like `MusicBrainz` without a network.
+    struct UnreachableSource;
+
+    impl UnreachableSource {
+        fn error() -> Error {
+            ApiError::Connect("connection refused".into()).into()
+        }
+    }
+
+    impl MetadataSource for UnreachableSource {
+        async fn search_release(&self, _: &str, _: &str, _: u32) -> Result<Vec<Release>> {
+            Err(Self::error())
+        }
+
+        async fn lookup_release(&self, _: &str) -> Result<Release> {
+            Err(Self::error())
+        }
+
+        async fn lookup_recording(&self, _: &str) -> Result<Recording> {
+            Err(Self::error())
+        }
+
+        async fn fetch_cover_art(&self, _: &str) -> Result<Option<Vec<u8>>> {
+            Err(Self::error())
+        }
+
+        async fn check_reachable(&self) -> Result<()> {
+            Err(Self::error())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_import_unreachable_source_goes_without_lookups() {
+        let (albums, items) = import_tagged_album("unreachable", UnreachableSource).await;
+
+        assert_eq!(albums.len(), 1);
+        assert_eq!(albums[0].album, "Paranoid");
+        assert_eq!(albums[0].mb_albumid, None);
+        assert!(items.iter().all(|(_, mb_trackid, _)| mb_trackid.is_none()));
+    }
+
+    /// Knows no releases, and fails searches that name an artist.
+    struct TitleOnlySource;
+
+    impl MetadataSource for TitleOnlySource {
+        async fn search_release(&self, artist: &str, _: &str, _: u32) -> Result<Vec<Release>> {
+            if artist.is_empty() {
+                Ok(Vec::new())
+            } else {
+                Err(ApiError::Other(format!("Searched by artist {artist}")).into())
+            }
+        }
+
+        async fn lookup_release(&self, mbid: &str) -> Result<Release> {
+            Err(ApiError::Other(format!("No release {mbid}")).into())
+        }
+
+        async fn lookup_recording(&self, mbid: &str) -> Result<Recording> {
+            Err(ApiError::Other(format!("No recording {mbid}")).into())
+        }
+
+        async fn fetch_cover_art(&self, _: &str) -> Result<Option<Vec<u8>>> {
+            Ok(None)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_import_various_artists_directory() {
+        let root = std::env::temp_dir().join(format!("rsbts-various-{}", std::process::id()));
+        let incoming = root.join("incoming/Pure Moods");
+        std::fs::create_dir_all(&incoming).unwrap();
+        for (track, artist, title) in [
+            (1, "Enya", "Orinoco Flow"),
+            (2, "Enigma", "Sadeness"),
+            (3, "Mike Oldfield", "Tubular Bells"),
+        ] {
+            let path = incoming.join(format!("{track}.wav"));
+            std::fs::write(&path, wav_bytes(800)).unwrap();
+            let edits: Vec<FieldEdit> = [
+                format!("title={title}"),
+                format!("artist={artist}"),
+                "album=Pure Moods".into(),
+                format!("track={track}"),
+            ]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+            write_tags(&path, &edits).unwrap();
+        }
+
+        let db = Database::open(&root.join("library.db")).unwrap();
+        db.migrate().unwrap();
+        let config = ImportConfig {
+            path_formats: PathFormats::new("$albumartist/$album/$track $title"),
+            ..tagged_album_config(&root)
+        };
+        let importer = Importer::with_source(&db, config, TitleOnlySource).unwrap();
+        let report = importer.import(&incoming).await.unwrap();
+        let albums = db.query_albums(None).unwrap();
+        let items = db.album_items(albums[0].id.unwrap()).unwrap();
+        drop(db);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert!(report.failed_albums.is_empty(), "{:?}", report.failed_albums);
+        assert_eq!(albums.len(), 1);
+        assert_eq!(albums[0].albumartist, VARIOUS_ARTISTS);
+        let tracks: Vec<(&str, Option<&str>)> =
+            items.iter().map(|i| (i.artist.as_str(), i.albumartist.as_deref())).collect();
+        assert_eq!(
+            tracks,
+            [
+                ("Enya", Some(VARIOUS_ARTISTS)),
+                ("Enigma", Some(VARIOUS_ARTISTS)),
+                ("Mike Oldfield", Some(VARIOUS_ARTISTS)),
+            ]
+        );
+        let library = root.join("library/Various Artists/Pure Moods");
+        assert!(items.iter().all(|i| i.path.starts_with(&library)));
+    }
+
+    /// Fails to search for Paranoid, and knows no other album.
+    struct FlakySource;
+
+    impl MetadataSource for FlakySource {
+        async fn search_release(&self, _: &str, album: &str, _: u32) -> Result<Vec<Release>> {
+            if album == "Paranoid" {
+                Err(ApiError::Http(reqwest::StatusCode::SERVICE_UNAVAILABLE).into())
+            } else {
+                Ok(Vec::new())
+            }
+        }
+
+        async fn lookup_release(&self, mbid: &str) -> Result<Release> {
+            Err(ApiError::Other(format!("No release {mbid}")).into())
+        }
+
+        async fn lookup_recording(&self, mbid: &str) -> Result<Recording> {
+            Err(ApiError::Other(format!("No recording {mbid}")).into())
+        }
+
+        async fn fetch_cover_art(&self, _: &str) -> Result<Option<Vec<u8>>> {
+            Ok(None)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_album_does_not_stop_the_others() {
+        let root = std::env::temp_dir().join(format!("rsbts-flaky-{}", std::process::id()));
+        let incoming = root.join("incoming");
+        write_tagged_album(&incoming.join("paranoid"));
+        let other = incoming.join("master");
+        std::fs::create_dir_all(&other).unwrap();
+        let path = other.join("1.wav");
+        std::fs::write(&path, wav_bytes(800)).unwrap();
+        let edits: Vec<FieldEdit> = ["artist=Black Sabbath", "album=Master of Reality"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        write_tags(&path, &edits).unwrap();
+
+        let db = Database::open(&root.join("library.db")).unwrap();
+        db.migrate().unwrap();
+        let importer =
+            Importer::with_source(&db, tagged_album_config(&root), FlakySource).unwrap();
+        let report = importer.import(&incoming).await.unwrap();
+        let albums = db.query_albums(None).unwrap();
+        drop(db);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(report.failed_albums.len(), 1);
+        assert_eq!(report.failed_albums[0].0, "Black Sabbath - Paranoid");
+        assert!(report.failed_albums[0].1.to_string().contains("503"));
+        assert!(!report.is_empty());
+        assert_eq!(albums.len(), 1);
+        assert_eq!(albums[0].album, "Master of Reality");
+    }
+
+    #[tokio::test]
+    async fn test_import_bundle_moves_extracted_files() {
+        use flate2::write::GzEncoder;
+
+        let root = std::env::temp_dir().join(format!("rsbts-bundle-import-{}", std::process::id()));
+        let tracks = root.join("tracks");
+        write_tagged_album(&tracks);
+        let incoming = root.join("incoming");
+        std::fs::create_dir_all(&incoming).unwrap();
+        let archive = incoming.join("Paranoid.tar.gz");
+        let mut builder = tar::Builder::new(GzEncoder::new(
+            std::fs::File::create(&archive).unwrap(),
+            flate2::Compression::default(),
+        ));
+        builder.append_path_with_name(tracks.join("1.wav"), "Paranoid/1.wav").unwrap();
+        builder.append_path_with_name(tracks.join("2.wav"), "Paranoid/2.wav").unwrap();
+        std::fs::write(tracks.join("cover.jpg"), b"\xff\xd8 cover").unwrap();
+        builder.append_path_with_name(tracks.join("cover.jpg"), "Paranoid/cover.jpg").unwrap();
+        builder.into_inner().unwrap().finish().unwrap();
+        std::fs::write(incoming.join("Broken.tar.gz"), b"not an archive").unwrap();
+
+        let db = Database::open(&root.join("library.db")).unwrap();
+        db.migrate().unwrap();
+        let config = ImportConfig {
+            // Never a link to the extracted copy
+            action: Action::Link,
+            ..tagged_album_config(&root)
+        };
+        let importer = Importer::with_source(&db, config, NullSource).unwrap();
+        let report = importer.import(&incoming).await.unwrap();
+
+        let failed: Vec<&Path> = report.failures.iter().map(|(path, _)| path.as_path()).collect();
+        assert_eq!(failed, [incoming.join("Broken.tar.gz")]);
+        let archive = std::fs::canonicalize(&archive).unwrap();
+        let albums = db.query_albums(None).unwrap();
+        assert_eq!(albums.len(), 1);
+        assert_eq!(albums[0].source_path.as_deref(), Some(archive.join("Paranoid").as_path()));
+        let items = db.album_items(albums[0].id.unwrap()).unwrap();
+        assert_eq!(items.len(), 2);
+        for item in &items {
+            assert!(!item.path.symlink_metadata().unwrap().file_type().is_symlink());
+            assert!(item.source_path.as_ref().unwrap().starts_with(&archive));
+        }
+        let cover = root.join("library/Paranoid/cover.jpg");
+        assert_eq!(albums[0].artpath.as_deref(), Some(cover.as_path()));
+        assert_eq!(std::fs::read(cover).unwrap(), b"\xff\xd8 cover");
+        assert!(archive.exists());
+
+        // The archive still holds the files, so undoing deletes them
+        let undone = crate::runs::undo(&db, importer.run_id(), None, &Hooks::disabled()).unwrap();
+        assert_eq!((undone.items, undone.restored, undone.deleted, undone.art), (2, 0, 2, 1));
+        assert!(items.iter().all(|item| !item.path.exists()));
+        assert!(archive.exists());
+        drop(db);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_import_uses_local_art_before_fetching() {
+        let root = std::env::temp_dir().join(format!("rsbts-local-art-{}", std::process::id()));
+        for (name, prefer_caa_art, with_files, expected) in [
+            ("file", false, true, "cover"),
+            ("caa", true, true, "caa"),
+            ("embedded", false, false, "embedded"),
+        ] {
+            let dir = root.join(name);
+            let source_dir = dir.join("incoming");
+            write_tagged_album(&source_dir);
+            if with_files {
+                std::fs::write(source_dir.join("Folder.JPG"), "folder").unwrap();
+                std::fs::write(source_dir.join("cover.jpg"), "cover").unwrap();
+                std::fs::write(source_dir.join("scan.jpg"), "scan").unwrap();
+            } else {
+                for track in ["1.wav", "2.wav"] {
+                    crate::tags::embed_art(&source_dir.join(track), b"embedded").unwrap();
+                }
+            }
+
+            let db = Database::open(&dir.join("library.db")).unwrap();
+            db.migrate().unwrap();
+            let config = ImportConfig {
+                prefer_caa_art,
+                ..tagged_album_config(&dir)
+            };
+            let source = ScriptedSource {
+                release: scripted_release(),
+                cover_art: Some("caa".into()),
+            };
+            let importer = Importer::with_source(&db, config, source).unwrap();
+            importer.import(&source_dir).await.unwrap();
+
+            let albums = db.query_albums(None).unwrap();
+            let artpath = albums[0].artpath.clone().unwrap();
+            assert_eq!(artpath, dir.join("library/Paranoid/cover.jpg"), "{name}");
+            assert_eq!(std::fs::read(&artpath).unwrap(), expected.as_bytes(), "{name}");
+        }
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_undo_deletes_copies_and_art_but_not_changed_files() {
+        let root = std::env::temp_dir().join(format!("rsbts-undo-copy-{}", std::process::id()));
+        let source_dir = root.join("incoming");
+        write_tagged_album(&source_dir);
+        let db = Database::open_in_memory().unwrap();
+        db.migrate().unwrap();
+        let config = ImportConfig {
+            action: Action::Copy,
+            ..tagged_album_config(&root)
+        };
+        let source = ScriptedSource {
+            release: scripted_release(),
+            cover_art: Some(b"\xff\xd8 art".to_vec()),
+        };
+        let importer = Importer::with_source(&db, config, source).unwrap();
+        importer.import(&source_dir).await.unwrap();
+        let run = importer.run_id().to_string();
+        let actions: Vec<Option<Action>> =
+            db.import_log(&run).unwrap().iter().map(|entry| entry.action).collect();
+
+        let mut items = db.query_items(None).unwrap();
+        items.sort_by_key(|item| item.track);
+        let (changed, unchanged) = (items[0].path.clone(), items[1].path.clone());
+        // Retagged since, say
+        let mut bytes = std::fs::read(&changed).unwrap();
+        bytes.extend_from_slice(b"more");
+        std::fs::write(&changed, bytes).unwrap();
+
+        let report = crate::runs::undo(&db, &run, None, &Hooks::disabled()).unwrap();
+        let left: Vec<PathBuf> =
+            db.query_items(None).unwrap().into_iter().map(|item| item.path).collect();
+        let albums = db.query_albums(None).unwrap();
+        let files = (changed.exists(), unchanged.exists(), audio_files(&source_dir, &[]).len());
+        let sessions = db.import_runs().unwrap().len();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(actions, [Some(Action::Copy), Some(Action::Copy), None]);
+        assert_eq!((report.items, report.deleted, report.art, report.albums), (1, 1, 1, 0));
+        let failed: Vec<&Path> = report.failed.iter().map(|(path, _)| path.as_path()).collect();
+        assert_eq!(failed, [changed.as_path()]);
+        assert_eq!(left, [changed.clone()]);
+        // The album stays with the changed file, without the art
+        assert_eq!(albums.len(), 1);
+        assert_eq!(albums[0].artpath, None);
+        assert_eq!(files, (true, false, 2));
+        assert_eq!(sessions, 1);
+    }
 }
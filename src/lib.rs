@@ -2,14 +2,47 @@
 // Truncation is handled manually with clamp/max/round where needed.
 #![allow(clippy::cast_precision_loss, clippy::cast_possible_truncation, clippy::cast_sign_loss)]
 
+pub mod acoustid;
+pub mod archive;
+pub mod art;
+pub mod beets;
+pub mod bundle;
+pub mod changes;
+pub mod check;
 pub mod config;
+pub mod cue;
 pub mod db;
+pub mod dedup;
+pub mod exists;
+pub mod export;
+pub mod external;
+pub mod fields;
+pub mod format;
+pub mod genres;
+pub mod hooks;
 pub mod import;
+pub mod lock;
+pub mod metadata_cache;
 pub mod migrations;
+pub mod missing;
 pub mod musicbrainz;
 pub mod pathformat;
+pub mod pathmap;
+pub mod playlist;
+pub mod prune;
 pub mod query;
+pub mod random;
+pub mod replaygain;
+pub mod ratelimit;
+pub mod runs;
+#[cfg(feature = "server")]
+pub mod server;
+pub mod similarity;
 pub mod tags;
+pub mod update;
+pub mod watch;
+#[cfg(test)]
+mod testutil;
 
 use std::path::PathBuf;
 
@@ -26,25 +59,90 @@ pub enum AudioFormat {
     Alac,
     Wav,
     Aiff,
+    WavPack,
+    /// Monkey's Audio
+    Ape,
+    Wma,
+    /// DSD audio, as DSF or DSDIFF files
+    Dsd,
+    Musepack,
+    /// AAC audiobooks
+    M4b,
     Unknown,
 }
 
 impl AudioFormat {
+    /// Every format, with `Unknown` last.
+    pub const ALL: [Self; 15] = [
+        Self::Mp3,
+        Self::Flac,
+        Self::Ogg,
+        Self::Opus,
+        Self::Aac,
+        Self::Alac,
+        Self::Wav,
+        Self::Aiff,
+        Self::WavPack,
+        Self::Ape,
+        Self::Wma,
+        Self::Dsd,
+        Self::Musepack,
+        Self::M4b,
+        Self::Unknown,
+    ];
+
+    /// The lowercase file extensions of the format, the usual one first.
+    /// These decide which files are imported (see
+    /// [`tags::is_audio_file`]).
     #[must_use]
-    pub fn from_extension(ext: &str) -> Self {
-        match ext.to_lowercase().as_str() {
-            "mp3" => Self::Mp3,
-            "flac" => Self::Flac,
-            "ogg" | "oga" => Self::Ogg,
-            "opus" => Self::Opus,
-            "m4a" | "aac" => Self::Aac,
-            "alac" => Self::Alac,
-            "wav" => Self::Wav,
-            "aiff" | "aif" => Self::Aiff,
-            _ => Self::Unknown,
+    pub const fn extensions(self) -> &'static [&'static str] {
+        match self {
+            Self::Mp3 => &["mp3"],
+            Self::Flac => &["flac"],
+            Self::Ogg => &["ogg", "oga"],
+            Self::Opus => &["opus"],
+            Self::Aac => &["m4a", "aac"],
+            Self::Alac => &["alac"],
+            Self::Wav => &["wav"],
+            Self::Aiff => &["aiff", "aif"],
+            Self::WavPack => &["wv"],
+            Self::Ape => &["ape"],
+            Self::Wma => &["wma"],
+            Self::Dsd => &["dsf", "dff"],
+            Self::Musepack => &["mpc"],
+            Self::M4b => &["m4b"],
+            Self::Unknown => &[],
         }
     }
 
+    #[must_use]
+    pub fn from_extension(ext: &str) -> Self {
+        let ext = ext.to_lowercase();
+        Self::ALL
+            .into_iter()
+            .find(|format| format.extensions().contains(&ext.as_str()))
+            .unwrap_or(Self::Unknown)
+    }
+
+    /// The format called `name`, as displayed (`Ogg Vorbis`) or by a file
+    /// extension (`ogg`), ignoring case.
+    #[must_use]
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|format| format.as_str().eq_ignore_ascii_case(name))
+            .or_else(|| Some(Self::from_extension(name)).filter(|&f| f != Self::Unknown))
+    }
+
+    /// Whether the format keeps the audio exactly.
+    #[must_use]
+    pub const fn is_lossless(self) -> bool {
+        matches!(
+            self,
+            Self::Flac | Self::Alac | Self::Wav | Self::Aiff | Self::WavPack | Self::Ape | Self::Dsd
+        )
+    }
+
     #[must_use]
     pub const fn as_str(&self) -> &'static str {
         match self {
@@ -56,6 +154,12 @@ impl AudioFormat {
             Self::Alac => "ALAC",
             Self::Wav => "WAV",
             Self::Aiff => "AIFF",
+            Self::WavPack => "WavPack",
+            Self::Ape => "Monkey's Audio",
+            Self::Wma => "WMA",
+            Self::Dsd => "DSD",
+            Self::Musepack => "Musepack",
+            Self::M4b => "M4B",
             Self::Unknown => "Unknown",
         }
     }
@@ -70,6 +174,10 @@ pub struct Item {
     pub artist: String,
     pub album: String,
     pub albumartist: Option<String>,
+    /// `artist` as it's sorted, such as "Chemical Brothers, The".
+    pub artist_sort: Option<String>,
+    /// `albumartist` as it's sorted.
+    pub albumartist_sort: Option<String>,
     pub genre: Option<String>,
     pub year: Option<i32>,
     pub track: Option<u32>,
@@ -77,10 +185,40 @@ pub struct Item {
     pub format: AudioFormat,
     pub bitrate: u32,
     pub length: f64,
+    /// Samples per second; unknown for items read before it was recorded.
+    pub samplerate: Option<u32>,
+    pub channels: Option<u8>,
+    /// Bits per sample of lossless formats; lossy ones have none.
+    pub bitdepth: Option<u8>,
     pub mb_trackid: Option<String>,
     pub mb_albumid: Option<String>,
+    /// `MusicBrainz` release group: the album across all its releases.
+    pub mb_releasegroupid: Option<String>,
     pub added: DateTime<Utc>,
     pub mtime: DateTime<Utc>,
+    /// File size in bytes when the file was last read; unknown for items
+    /// imported before sizes were recorded.
+    pub size: Option<u64>,
+    /// Where the file was imported from, before it was moved into the library.
+    pub source_path: Option<PathBuf>,
+    /// Id of the import run that added the item (see [`runs`]).
+    pub import_run: Option<String>,
+    /// `ReplayGain` adjustment for the track, in dB (see [`replaygain`]).
+    pub rg_track_gain: Option<f64>,
+    /// Peak sample of the track, where 1.0 is full scale.
+    pub rg_track_peak: Option<f64>,
+    /// `ReplayGain` adjustment for the item's album as a whole, in dB.
+    pub rg_album_gain: Option<f64>,
+    /// Peak sample of the album.
+    pub rg_album_peak: Option<f64>,
+    /// The listener's rating, 0 to 5, kept in the library only.
+    pub rating: Option<u8>,
+    /// How many times `rsbts played` has recorded the track being played.
+    /// Archives written before it was kept read as 0.
+    #[serde(default)]
+    pub play_count: u32,
+    /// When the track was last recorded as played.
+    pub last_played: Option<DateTime<Utc>>,
 }
 
 impl Item {
@@ -88,6 +226,20 @@ impl Item {
     pub fn effective_albumartist(&self) -> &str {
         self.albumartist.as_deref().unwrap_or(&self.artist)
     }
+
+    /// The fields among `fields` that `self` and `other` have different
+    /// values for, in the order given, with both values as stored.
+    #[must_use]
+    pub fn diff(&self, other: &Self, fields: &[&'static str]) -> Vec<fields::FieldDiff> {
+        fields
+            .iter()
+            .filter_map(|&field| {
+                let ours = fields::item_value(self, field);
+                let theirs = fields::item_value(other, field);
+                (ours != theirs).then_some(fields::FieldDiff { field, ours, theirs })
+            })
+            .collect()
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -98,7 +250,16 @@ pub struct Album {
     pub year: Option<i32>,
     pub artpath: Option<PathBuf>,
     pub mb_albumid: Option<String>,
+    /// `MusicBrainz` release group: the album across all its releases.
+    pub mb_releasegroupid: Option<String>,
     pub added: DateTime<Utc>,
+    /// Directory the album was imported from.
+    pub source_path: Option<PathBuf>,
+    /// Id of the import run that added the album (see [`runs`]).
+    pub import_run: Option<String>,
+    /// What tells the release apart from others of the same name on
+    /// `MusicBrainz`, such as "Deluxe Edition".
+    pub disambiguation: Option<String>,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -119,13 +280,31 @@ pub enum Error {
     Import(String),
 
     #[error("MusicBrainz error: {0}")]
-    MusicBrainz(String),
+    MusicBrainz(#[from] musicbrainz::ApiError),
+
+    #[error("AcoustID error: {0}")]
+    AcoustId(String),
+
+    #[error("Album art error: {0}")]
+    Art(String),
 
     #[error("Path format error: {0}")]
     PathFormat(String),
 
     #[error("Query error: {0}")]
     Query(String),
+
+    #[error("Archive error: {0}")]
+    Archive(String),
+
+    #[error("Cue sheet error: {0}")]
+    Cue(String),
+
+    #[error("ReplayGain error: {0}")]
+    ReplayGain(String),
+
+    #[error("Library is locked by {0}; retry with --wait or --force-lock")]
+    Locked(lock::LockInfo),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,283 @@
+//! Cooperative library lock
+//!
+//! Commands that move files or rewrite many rows take a lock file next to the
+//! database so two of them can't interleave. The lock records the holder's
+//! pid and start time; a lock left behind by a dead process is taken over.
+
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+
+use crate::{Error, Result};
+
+/// How often `--wait` re-checks a held lock.
+const WAIT_POLL: Duration = Duration::from_millis(500);
+
+/// How long a lock file may go unreadable, as while its holder is writing
+/// it, before it is taken for one left by a crash.
+const UNREADABLE_GRACE: Duration = Duration::from_secs(10);
+
+/// What to do when another process holds the lock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LockMode {
+    /// Fail with [`Error::Locked`].
+    #[default]
+    Fail,
+    /// Block until the lock is released.
+    Wait,
+    /// Take the lock over regardless of the holder.
+    Force,
+}
+
+/// Holder of a library lock.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockInfo {
+    pub pid: u32,
+    pub started: DateTime<Utc>,
+}
+
+impl fmt::Display for LockInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "process {} (since {})",
+            self.pid,
+            self.started.format("%Y-%m-%d %H:%M:%S UTC")
+        )
+    }
+}
+
+/// Guard for a held lock; the lock file is removed when it is dropped,
+/// including during a panic unwind.
+#[derive(Debug)]
+pub struct LibraryLock {
+    path: PathBuf,
+}
+
+impl LibraryLock {
+    /// Path of the lock file guarding the given database.
+    #[must_use]
+    pub fn path_for(database: &Path) -> PathBuf {
+        let mut path = database.as_os_str().to_owned();
+        path.push(".lock");
+        path.into()
+    }
+
+    /// Acquire the lock at `path`, handling a live holder according to `mode`.
+    ///
+    /// # Errors
+    /// Returns [`Error::Locked`] if another live process holds the lock and
+    /// `mode` is [`LockMode::Fail`], or an IO error if the lock file cannot be
+    /// written.
+    pub async fn acquire(path: &Path, mode: LockMode) -> Result<Self> {
+        loop {
+            match Self::try_acquire(path) {
+                Err(e) if is_held(&e) && mode == LockMode::Force => {
+                    remove_lock_file(path)?;
+                }
+                Err(e) if is_held(&e) && mode == LockMode::Wait => {
+                    tokio::time::sleep(WAIT_POLL).await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Acquire the lock at `path`, taking over stale locks left by dead processes.
+    ///
+    /// # Errors
+    /// Returns [`Error::Locked`] if another live process holds the lock.
+    pub fn try_acquire(path: &Path) -> Result<Self> {
+        let info = LockInfo {
+            pid: std::process::id(),
+            started: Utc::now(),
+        };
+
+        match Self::create(path, &info) {
+            Err(Error::Locked(holder)) if !process_alive(holder.pid) => {
+                Self::take_over(path, Some(&holder), &info)
+            }
+            Err(Error::Io(e))
+                if e.kind() == std::io::ErrorKind::AlreadyExists && unreadable_for_long(path) =>
+            {
+                Self::take_over(path, None, &info)
+            }
+            result => result,
+        }
+    }
+
+    /// Replace the stale lock at `path`, left by `stale` (`None` if it can't
+    /// be read), with ours. It is read again first, and left alone if
+    /// another process has taken it over since. Should another process
+    /// create the lock between removing it and creating ours, that process
+    /// holds it.
+    fn take_over(path: &Path, stale: Option<&LockInfo>, info: &LockInfo) -> Result<Self> {
+        match read_lock(path) {
+            Some(holder) if Some(&holder) != stale => return Err(Error::Locked(holder)),
+            None if stale.is_some() => return Self::create(path, info),
+            _ => {}
+        }
+        remove_lock_file(path)?;
+        Self::create(path, info)
+    }
+
+    fn create(path: &Path, info: &LockInfo) -> Result<Self> {
+        match OpenOptions::new().write(true).create_new(true).open(path) {
+            Ok(mut file) => {
+                writeln!(file, "{}\n{}", info.pid, info.started.to_rfc3339())?;
+                Ok(Self {
+                    path: path.to_path_buf(),
+                })
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                read_lock(path).map_or_else(|| Err(e.into()), |holder| Err(Error::Locked(holder)))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl Drop for LibraryLock {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Read the current holder of a lock file, if it exists and is well-formed.
+#[must_use]
+pub fn read_lock(path: &Path) -> Option<LockInfo> {
+    let contents = std::fs::read_to_string(path).ok()?;
+    let mut lines = contents.lines();
+    let pid = lines.next()?.trim().parse().ok()?;
+    let started = DateTime::parse_from_rfc3339(lines.next()?.trim())
+        .ok()?
+        .with_timezone(&Utc);
+    Some(LockInfo { pid, started })
+}
+
+/// Whether `e` means another process holds the lock, readable or not.
+fn is_held(e: &Error) -> bool {
+    match e {
+        Error::Locked(_) => true,
+        Error::Io(e) => e.kind() == std::io::ErrorKind::AlreadyExists,
+        _ => false,
+    }
+}
+
+/// Whether the lock file at `path` can't be read and hasn't been written for
+/// [`UNREADABLE_GRACE`].
+fn unreadable_for_long(path: &Path) -> bool {
+    read_lock(path).is_none()
+        && std::fs::metadata(path)
+            .and_then(|m| m.modified())
+            .is_ok_and(|modified| modified.elapsed().is_ok_and(|age| age > UNREADABLE_GRACE))
+}
+
+fn remove_lock_file(path: &Path) -> Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Whether a process with the given pid is still running.
+#[cfg(target_os = "linux")]
+fn process_alive(pid: u32) -> bool {
+    Path::new(&format!("/proc/{pid}")).exists()
+}
+
+/// Whether a process with the given pid is still running.
+#[cfg(all(unix, not(target_os = "linux")))]
+fn process_alive(pid: u32) -> bool {
+    std::process::Command::new("kill")
+        .args(["-0", &pid.to_string()])
+        .stderr(std::process::Stdio::null())
+        .status()
+        .is_ok_and(|s| s.success())
+}
+
+/// Whether a process with the given pid is still running.
+///
+/// Without a cheap liveness check, assume the holder is alive.
+#[cfg(not(unix))]
+const fn process_alive(_pid: u32) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lock_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rsbts-{name}-{}.lock", std::process::id()))
+    }
+
+    #[tokio::test]
+    async fn test_second_import_refuses_while_locked() {
+        let path = lock_path("held");
+        let held = LibraryLock::try_acquire(&path).unwrap();
+
+        // A second "import" in this process sees a live holder (us)
+        let second = LibraryLock::acquire(&path, LockMode::Fail).await;
+        assert!(
+            matches!(&second, Err(Error::Locked(holder)) if holder.pid == std::process::id())
+        );
+
+        drop(held);
+        assert!(!path.exists());
+        assert!(LibraryLock::try_acquire(&path).is_ok());
+    }
+
+    #[test]
+    fn test_stale_lock_is_taken_over() {
+        let path = lock_path("stale");
+        // Pids are capped well below u32::MAX, so this process can't exist
+        std::fs::write(&path, format!("{}\n{}\n", u32::MAX, Utc::now().to_rfc3339())).unwrap();
+
+        let lock = LibraryLock::try_acquire(&path).unwrap();
+        assert_eq!(read_lock(&path).unwrap().pid, std::process::id());
+        drop(lock);
+    }
+
+    #[test]
+    fn test_take_over_rechecks_the_holder() {
+        let path = lock_path("recheck");
+        let stale = LockInfo {
+            pid: u32::MAX,
+            started: Utc::now(),
+        };
+        // Another process took the stale lock over after we read it
+        let other = LibraryLock::try_acquire(&path).unwrap();
+        let ours = LockInfo {
+            pid: std::process::id(),
+            started: Utc::now(),
+        };
+        let taken = LibraryLock::take_over(&path, Some(&stale), &ours);
+        assert!(matches!(taken, Err(Error::Locked(_))));
+        assert!(path.exists());
+        drop(other);
+
+        // An unreadable lock just written is held, not stale
+        std::fs::write(&path, "").unwrap();
+        let fresh = LibraryLock::try_acquire(&path);
+        assert!(fresh.as_ref().is_err_and(is_held));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_force_takes_over_live_lock() {
+        let path = lock_path("force");
+        let held = LibraryLock::try_acquire(&path).unwrap();
+        let forced = LibraryLock::acquire(&path, LockMode::Force).await.unwrap();
+        // The forced guard owns the file now; forget the old guard so it
+        // doesn't remove it
+        std::mem::forget(held);
+        assert!(path.exists());
+        drop(forced);
+        assert!(!path.exists());
+    }
+}
@@ -2,8 +2,13 @@
 // Truncation is handled manually with clamp/max/round where needed.
 #![allow(clippy::cast_precision_loss, clippy::cast_possible_truncation, clippy::cast_sign_loss)]
 
-use anyhow::Result;
-use clap::{Parser, Subcommand};
+use std::process::ExitCode;
+
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, Subcommand};
+use rsbts::config::{ConfigLocation, Override};
+use rsbts::db::StatsGroup;
+use rsbts::lock::LockMode;
 
 mod cli;
 
@@ -15,29 +20,230 @@ struct Cli {
     #[arg(short, long, global = true)]
     config: Option<std::path::PathBuf>,
 
+    /// Open the portable library at DIR: its config is DIR/rsbts.toml, its
+    /// database DIR/library.db, and paths are stored relative to DIR
+    /// (RSBTS_PORTABLE=1 does this for the current directory)
+    #[arg(long, global = true, value_name = "DIR", conflicts_with = "config")]
+    library_root: Option<std::path::PathBuf>,
+
+    /// Wait for another rsbts process to release the library lock
+    #[arg(long, global = true, conflicts_with = "force_lock")]
+    wait: bool,
+
+    /// Take the library lock even if another process holds it
+    #[arg(long, global = true)]
+    force_lock: bool,
+
+    /// Reproducible output for diffing: fixed ordering and locale-independent
+    /// formatting (see README)
+    #[arg(long, global = true)]
+    stable: bool,
+
+    /// Don't run the commands configured under [hooks]
+    #[arg(long, global = true)]
+    no_hooks: bool,
+
+    /// Override a config setting for this run, e.g. --set import.action=move
+    /// (repeatable; applied after the file and RSBTS_* variables)
+    #[arg(
+        long = "set",
+        global = true,
+        value_name = "SECTION.KEY=VALUE",
+        value_parser = Override::from_set
+    )]
+    set: Vec<Override>,
+
+    /// Show more detail: -v for each file, -vv for MusicBrainz requests
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Only show warnings and errors (and only counts from `check`); `import`
+    /// asks nothing and handles unmatched albums as `import.quiet_fallback` says
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// List external commands (rsbts-* executables on PATH)
+    #[arg(long)]
+    list_external: bool,
+
     #[command(subcommand)]
-    command: Commands,
+    command: Option<Commands>,
 }
 
 #[derive(Subcommand)]
 enum Commands {
+    /// Write a starter config file and create the library and database
+    Init {
+        /// Library directory (default: ~/Music)
+        #[arg(long, value_name = "DIR")]
+        library: Option<std::path::PathBuf>,
+
+        /// Replace an existing config file
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// Print a shell completion script, to be sourced or saved where the
+    /// shell looks for completions
+    Completions {
+        /// Shell to complete for
+        shell: clap_complete::Shell,
+    },
+
+    /// List the fields queries can test, with their types and whether
+    /// `modify` can change them
+    Fields {
+        /// Print them as JSON
+        #[arg(long)]
+        json: bool,
+    },
+
     /// Import music into library
     Import {
         /// Paths to import
-        #[arg(required = true)]
+        #[arg(required_unless_present = "from_log")]
         paths: Vec<std::path::PathBuf>,
 
         /// Copy files (don't move)
-        #[arg(short = 'C', long)]
+        #[arg(short = 'C', long, group = "action")]
         copy: bool,
 
         /// Move files
-        #[arg(short = 'M', long)]
+        #[arg(short = 'M', long, group = "action")]
         r#move: bool,
+
+        /// Symlink to the files where they are
+        #[arg(long, group = "action")]
+        link: bool,
+
+        /// Hardlink to the files where they are (copies across filesystems)
+        #[arg(long, group = "action")]
+        hardlink: bool,
+
+        /// Make copy-on-write clones of the files, or copy them where the
+        /// filesystem can't
+        #[arg(long, group = "action")]
+        reflink: bool,
+
+        /// Only import albums matching this query (checked against each
+        /// album's first track before lookup)
+        #[arg(long, value_name = "QUERY")]
+        only: Option<String>,
+
+        /// Only read files of these formats, such as flac,mp3
+        #[arg(long, value_name = "FORMATS", value_delimiter = ',')]
+        format: Vec<String>,
+
+        /// Only read files modified since this date (YYYY-MM-DD) or this
+        /// long ago (such as 7d, 2w or 1y)
+        #[arg(long, value_name = "DATE", allow_hyphen_values = true)]
+        newer_than: Option<String>,
+
+        /// Only look this many directories deep (0 for the path's own files)
+        #[arg(long, value_name = "N")]
+        max_depth: Option<usize>,
+
+        /// Import from file tags alone, without looking anything up
+        #[arg(long)]
+        no_autotag: bool,
+
+        /// Leave the directories moved files came from even when emptied
+        #[arg(long)]
+        keep_empty_dirs: bool,
+
+        /// Import from inside the library directory, leaving out the files
+        /// already in the library
+        #[arg(long)]
+        force_inside_library: bool,
+
+        /// Write files that could not be read to this log file
+        #[arg(long, value_name = "PATH")]
+        error_log: Option<std::path::PathBuf>,
+
+        /// Write the albums a quiet import skipped to this file
+        #[arg(long, value_name = "FILE")]
+        log: Option<std::path::PathBuf>,
+
+        /// Also import the albums skipped in a file written by --log
+        #[arg(long, value_name = "FILE")]
+        from_log: Option<std::path::PathBuf>,
+    },
+
+    /// Add the tracks of a beets library, keeping its metadata
+    ImportBeets {
+        /// The beets library database (library.db)
+        path: std::path::PathBuf,
+
+        /// Move the files into the library according to the path format
+        /// (by default they stay where beets put them)
+        #[arg(long)]
+        move_into_library: bool,
+    },
+
+    /// Import music as it arrives in a directory
+    Watch {
+        /// Directory to watch (default: import.watch_directory)
+        dir: Option<std::path::PathBuf>,
+
+        /// Import what is there now and exit
+        #[arg(long)]
+        once: bool,
+    },
+
+    /// List import sessions, newest first
+    #[command(visible_alias = "runs")]
+    Sessions,
+
+    /// Show what was added lately, album by album, newest first
+    Recent {
+        /// Go back this many days (default: 14)
+        #[arg(long, value_name = "N", conflicts_with = "since")]
+        days: Option<u32>,
+
+        /// Go back to this date, such as 2024-06 or 3w
+        #[arg(long, value_name = "DATE")]
+        since: Option<String>,
+
+        /// Show one line per album instead of its tracks
+        #[arg(short, long)]
+        album: bool,
+
+        /// Print a header for each day, week or month
+        #[arg(long, value_name = "PERIOD")]
+        group_by: Option<cli::Period>,
+
+        /// Print raw values as JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Serve a read-only HTTP API over the library, to browse and play it
+    /// from other devices
+    #[cfg(feature = "server")]
+    Serve {
+        /// Address and port to listen on (default: server.bind)
+        #[arg(long, value_name = "ADDR")]
+        bind: Option<String>,
+    },
+
+    /// Take back everything an import session added
+    #[command(alias = "undo-import")]
+    Undo {
+        /// Session id, as shown by `rsbts sessions`
+        #[arg(long, required_unless_present = "last", conflicts_with = "last")]
+        session: Option<String>,
+
+        /// Undo the most recent session
+        #[arg(long)]
+        last: bool,
+
+        /// Don't ask for confirmation
+        #[arg(short, long)]
+        yes: bool,
     },
 
     /// List items in library
-    #[command(name = "ls", alias = "list")]
+    #[command(name = "ls", visible_alias = "list")]
     List {
         /// Query string
         query: Option<String>,
@@ -45,19 +251,358 @@ enum Commands {
         /// Show albums instead of tracks
         #[arg(short, long)]
         album: bool,
+
+        /// With --album, also show each album's tracks, length, size and formats
+        #[arg(short, long, requires = "album")]
+        long: bool,
+
+        /// Only list tracks whose files no longer exist
+        #[arg(long, conflicts_with = "album")]
+        missing: bool,
+
+        /// List at most this many tracks (overrides a `limit:` in the query)
+        #[arg(long, value_name = "N", conflicts_with_all = ["album", "missing"])]
+        limit: Option<u64>,
+
+        /// Skip this many tracks first
+        #[arg(long, value_name = "N", conflicts_with_all = ["album", "missing"])]
+        offset: Option<u64>,
+
+        /// Print only the number of matching tracks
+        #[arg(long, conflicts_with_all = ["album", "missing", "limit", "offset"])]
+        count: bool,
+
+        /// Don't apply the configured default query
+        #[arg(long)]
+        no_default_query: bool,
+
+        /// Exit with status 3 if the query matches nothing
+        #[arg(long)]
+        fail_on_empty: bool,
+    },
+
+    /// Show all stored fields of matching items
+    Info {
+        /// Query to match items
+        query: String,
     },
 
     /// Show library statistics
-    Stats,
+    Stats {
+        /// Query to restrict statistics to
+        query: Option<String>,
+
+        /// Don't apply the configured default query
+        #[arg(long)]
+        no_default_query: bool,
+
+        /// Print raw, unlocalized values as JSON
+        #[arg(long)]
+        json: bool,
+
+        /// Break the statistics down by format, genre, artist or year
+        #[arg(long, value_name = "FIELD", conflicts_with = "verify")]
+        by: Option<StatsGroup>,
+
+        /// Also count tracks whose files no longer exist
+        #[arg(long)]
+        verify: bool,
+    },
+
+    /// List genres and how many tracks have each, or normalize them
+    Genres {
+        /// Query to restrict genres to
+        query: Option<String>,
+
+        /// Rename genres by the [genres] aliases and title_case setting
+        #[arg(long)]
+        normalize: bool,
+
+        /// Write changed genres into file tags (default: import.write_tags)
+        #[arg(long, requires = "normalize", conflicts_with = "nowrite")]
+        write: bool,
+
+        /// Only change the database
+        #[arg(long, requires = "normalize")]
+        nowrite: bool,
+
+        /// Don't ask for confirmation
+        #[arg(short, long, requires = "normalize")]
+        yes: bool,
+    },
+
+    /// Export matching items (or albums) as CSV or JSON
+    Export {
+        /// Query string
+        query: Option<String>,
+
+        /// Output format: csv, json or jsonl
+        #[arg(short, long, default_value = "csv")]
+        format: String,
+
+        /// Write to this file instead of stdout
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<std::path::PathBuf>,
+
+        /// Comma-separated columns to export, in order (default: all)
+        #[arg(long, value_name = "FIELDS")]
+        fields: Option<String>,
+
+        /// Leave out file paths: path and source_path of tracks, artpath and
+        /// source_path of albums
+        #[arg(long)]
+        strip_paths: bool,
+
+        /// Export albums instead of tracks
+        #[arg(short, long)]
+        album: bool,
+
+        /// Don't apply the configured default query
+        #[arg(long)]
+        no_default_query: bool,
+
+        /// Write a checksummed archive of the whole library into this directory
+        #[arg(
+            long,
+            value_name = "DIR",
+            conflicts_with_all = ["query", "output", "fields", "strip_paths", "album"]
+        )]
+        archive: Option<std::path::PathBuf>,
+
+        /// Number of archives to keep in the archive directory (at least 1)
+        #[arg(
+            long,
+            value_name = "N",
+            default_value_t = rsbts::archive::DEFAULT_KEEP,
+            value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+        )]
+        keep: usize,
+    },
+
+    /// Write matching tracks to an M3U playlist
+    Playlist {
+        /// Query string; sort directives set the playlist order
+        query: Option<String>,
+
+        /// Write to this file instead of stdout
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<std::path::PathBuf>,
+
+        /// Write paths relative to this directory
+        #[arg(long, value_name = "DIR", conflicts_with = "absolute")]
+        relative_to: Option<std::path::PathBuf>,
+
+        /// Write absolute paths (the default)
+        #[arg(long)]
+        absolute: bool,
+
+        /// Include at most this many tracks
+        #[arg(short, long, value_name = "N")]
+        limit: Option<usize>,
+
+        /// Shuffle the tracks (before --limit picks them)
+        #[arg(long)]
+        random: bool,
+
+        /// Don't apply the configured default query
+        #[arg(long)]
+        no_default_query: bool,
+    },
+
+    /// Pick random tracks or albums, such as an hour of music
+    Random {
+        /// Query to pick from
+        query: Option<String>,
+
+        /// Number of tracks (or albums) to pick [default: 1]
+        #[arg(short, long, value_name = "N", conflicts_with = "time")]
+        number: Option<usize>,
+
+        /// Pick tracks (or albums) until they last this many minutes
+        #[arg(
+            short,
+            long,
+            value_name = "MINUTES",
+            value_parser = rsbts::random::parse_minutes
+        )]
+        time: Option<f64>,
+
+        /// Pick whole albums
+        #[arg(short, long)]
+        album: bool,
+
+        /// Write the picks to this M3U playlist instead of listing them
+        #[arg(long, value_name = "FILE")]
+        playlist: Option<std::path::PathBuf>,
+
+        /// Seed the random choice, to pick the same again
+        #[arg(long)]
+        seed: Option<u64>,
+
+        /// Don't apply the configured default query
+        #[arg(long)]
+        no_default_query: bool,
+    },
+
+    /// Inspect queries
+    Query {
+        #[command(subcommand)]
+        command: QueryCommands,
+    },
+
+    /// Database maintenance
+    Db {
+        #[command(subcommand)]
+        command: DbCommands,
+    },
+
+    /// List tracks missing from albums, according to MusicBrainz
+    Missing {
+        /// Query to filter albums
+        query: Option<String>,
+
+        /// Print only a count of missing tracks per album
+        #[arg(long, conflicts_with = "total")]
+        count: bool,
+
+        /// Print only the total number of missing tracks
+        #[arg(short, long)]
+        total: bool,
+    },
+
+    /// Find duplicate tracks or albums in the library
+    #[command(name = "duplicates", visible_alias = "dup")]
+    Duplicates {
+        /// Find duplicate albums instead of tracks
+        #[arg(short, long)]
+        album: bool,
+
+        /// Remove all but the best copy from the database and disk
+        #[arg(short, long)]
+        delete: bool,
+
+        /// Format ranking, best first; `*` stands for unlisted formats
+        #[arg(long, value_name = "FORMATS", default_value = "flac,*,mp3")]
+        format_preference: String,
+
+        /// Don't ask for confirmation before deleting
+        #[arg(short, long, requires = "delete")]
+        yes: bool,
+    },
+
+    /// Inspect or clear the cached MusicBrainz metadata
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommands,
+    },
+
+    /// Check the library for missing, modified and untracked files
+    Check {
+        /// Remove database entries for files that no longer exist
+        #[arg(long)]
+        fix_missing: bool,
+
+        /// Import audio files in the library directory that aren't tracked
+        #[arg(long)]
+        add_untracked: bool,
+    },
 
     /// Update library (re-read tags)
     Update {
         /// Query to filter items
         query: Option<String>,
+
+        /// Re-read every matching file, not only those changed since the last read
+        #[arg(short, long)]
+        force: bool,
+
+        /// Remove items whose files are gone
+        #[arg(long)]
+        prune: bool,
+
+        /// Take file tags over values corrected from MusicBrainz at import
+        #[arg(long, conflicts_with = "write")]
+        prefer_file: bool,
+
+        /// Write values corrected from MusicBrainz back into files whose tags differ
+        #[arg(short, long)]
+        write: bool,
+    },
+
+    /// Match library albums against MusicBrainz again and update their tags
+    Retag {
+        /// Query to filter items; their albums are matched whole
+        query: Option<String>,
+
+        /// Also re-match albums that already have a MusicBrainz release
+        #[arg(short, long)]
+        force: bool,
+
+        /// Show what would change without changing anything
+        #[arg(short, long)]
+        pretend: bool,
+
+        /// Write changes into file tags (default: import.write_tags)
+        #[arg(long, conflicts_with = "nowrite")]
+        write: bool,
+
+        /// Only change the database
+        #[arg(long)]
+        nowrite: bool,
+    },
+
+    /// Manage album art: list albums without any (the default), fetch,
+    /// extract or embed it
+    Art {
+        /// Query to filter albums
+        query: Option<String>,
+
+        /// Download missing art from the Cover Art Archive
+        #[arg(long, group = "mode")]
+        fetch: bool,
+
+        /// Save art embedded in the tracks as the album's art file
+        #[arg(long, group = "mode")]
+        extract: bool,
+
+        /// Embed the album's art file into each of its tracks
+        #[arg(long, group = "mode")]
+        embed: bool,
+
+        /// List albums with neither an art file nor embedded art
+        #[arg(long, group = "mode")]
+        show: bool,
+
+        /// Don't embed art wider or taller than this
+        #[arg(long, value_name = "PIXELS", requires = "embed")]
+        max_size: Option<u32>,
+
+        /// Don't embed art larger than this many bytes
+        #[arg(long, value_name = "BYTES", requires = "embed")]
+        max_bytes: Option<u64>,
+
+        /// Show what would be done without changing anything
+        #[arg(short, long)]
+        pretend: bool,
+    },
+
+    /// Measure loudness and store ReplayGain values
+    #[command(name = "replaygain")]
+    ReplayGain {
+        /// Query to filter items; matching albums are analyzed whole
+        query: Option<String>,
+
+        /// Also write the values to the files' tags
+        #[arg(short, long)]
+        write: bool,
+
+        /// Analyze items that already have values
+        #[arg(short, long)]
+        force: bool,
     },
 
     /// Remove items from library
-    #[command(name = "rm", alias = "remove")]
+    #[command(name = "rm", visible_alias = "remove")]
     Remove {
         /// Query to match items
         query: String,
@@ -65,21 +610,201 @@ enum Commands {
         /// Also delete files from disk
         #[arg(short, long)]
         delete: bool,
+
+        /// Leave the directories of deleted files even when emptied
+        #[arg(long, requires = "delete")]
+        keep_empty_dirs: bool,
+
+        /// Don't ask for confirmation
+        #[arg(short, long)]
+        yes: bool,
+
+        /// Exit with status 3 if the query matches nothing
+        #[arg(long)]
+        fail_on_empty: bool,
     },
 
     /// Modify item metadata
     Modify {
-        /// Query to match items
+        /// Query to match items (or albums, with --album)
         query: String,
 
         /// Field=value pairs
         #[arg(required = true)]
         fields: Vec<String>,
+
+        /// Modify albums (and all their tracks) instead of tracks
+        #[arg(short, long)]
+        album: bool,
+
+        /// Write changes into file tags (default: import.write_tags)
+        #[arg(long, conflicts_with = "nowrite")]
+        write: bool,
+
+        /// Only change the database
+        #[arg(long)]
+        nowrite: bool,
+
+        /// Don't ask for confirmation
+        #[arg(short, long)]
+        yes: bool,
+
+        /// Only show what would change
+        #[arg(long)]
+        pretend: bool,
+
+        /// Go ahead with more changes than safety.max_modify_without_force
+        #[arg(long)]
+        force: bool,
+
+        /// Allow an empty query, which matches everything
+        #[arg(long)]
+        all: bool,
+
+        /// Exit with status 3 if the query matches nothing
+        #[arg(long)]
+        fail_on_empty: bool,
+    },
+
+    /// Rate items from 0 to 5 (kept in the library, not in tags)
+    Rate {
+        /// Query to match items
+        query: String,
+
+        /// Rating from 0 to 5; empty to clear it
+        value: String,
+
+        /// Exit with status 3 if the query matches nothing
+        #[arg(long)]
+        fail_on_empty: bool,
+    },
+
+    /// Count a play of items, for a player's song-change hook
+    Played {
+        /// Query to match items, such as path:/music/...
+        query: String,
+
+        /// Exit with status 3 if the query matches nothing
+        #[arg(long)]
+        fail_on_empty: bool,
+    },
+
+    /// Run `rsbts-<name>` from PATH
+    #[command(external_subcommand)]
+    External(Vec<std::ffi::OsString>),
+}
+
+#[derive(Subcommand)]
+enum QueryCommands {
+    /// Show how a query is expanded and translated to SQL
+    Explain {
+        /// Query string
+        query: Option<String>,
+
+        /// Don't apply the configured default query
+        #[arg(long)]
+        no_default_query: bool,
     },
 }
 
+#[derive(Subcommand)]
+enum DbCommands {
+    /// Rebuild the full-text search index from the tracks, as library.fts =
+    /// "external_content_rebuild" needs after changes
+    #[command(alias = "rebuild-fts")]
+    Reindex,
+    /// Bring the database schema up to date
+    Migrate {
+        /// Print the pending migrations' SQL without applying them
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Stop after the migration to this version
+        #[arg(long, value_name = "VERSION")]
+        to: Option<u32>,
+    },
+    /// Load an archive written by `export --archive` into an empty library
+    Restore {
+        /// The archive's manifest or payload file
+        file: std::path::PathBuf,
+
+        /// Only check the archive, without restoring it
+        #[arg(long)]
+        verify: bool,
+    },
+    /// Copy the database to a new file, safely even while another rsbts writes
+    Backup {
+        /// Where to write the copy
+        path: std::path::PathBuf,
+
+        /// Replace an existing file
+        #[arg(short, long)]
+        force: bool,
+    },
+    /// Rebuild the database file to reclaim space, such as after large removals
+    Vacuum,
+    /// Check the database and its full-text index for damage
+    Check {
+        /// Repopulate the full-text index if it has drifted from the items
+        #[arg(long)]
+        rebuild_fts: bool,
+    },
+    /// Change the start of every stored path, such as after moving the library
+    RewritePaths {
+        /// The stored prefix to replace
+        from: std::path::PathBuf,
+
+        /// What to replace it with
+        to: std::path::PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+enum CacheCommands {
+    /// Show entry counts and disk usage
+    Stats,
+    /// Remove all cached entries
+    Clear,
+}
+
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() -> ExitCode {
     let cli = Cli::parse();
-    cli::run(cli.command, cli.config).await
+    if cli.list_external {
+        cli::list_external();
+        return ExitCode::SUCCESS;
+    }
+    cli::init_logging(cli.verbose, cli.quiet);
+    let Some(command) = cli.command else {
+        Cli::command()
+            .error(ErrorKind::MissingSubcommand, "a subcommand is required")
+            .exit();
+    };
+
+    let lock_mode = if cli.force_lock {
+        LockMode::Force
+    } else if cli.wait {
+        LockMode::Wait
+    } else {
+        LockMode::Fail
+    };
+
+    let portable = std::env::var_os("RSBTS_PORTABLE").is_some_and(|value| value == "1");
+    let location = ConfigLocation::new(cli.config, cli.library_root, portable);
+    let quiet = cli.quiet;
+    let result =
+        cli::run(command, location, lock_mode, cli.stable, cli.no_hooks, quiet, &cli.set).await;
+    let outcome = match result {
+        Ok(outcome) => outcome,
+        Err(e) => {
+            let outcome = cli::Outcome::of_error(&e);
+            if outcome == cli::Outcome::Locked {
+                eprintln!("Error: {e}");
+            } else {
+                eprintln!("Error: {e:?}");
+            }
+            outcome
+        }
+    };
+    outcome.into()
 }
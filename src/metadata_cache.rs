@@ -0,0 +1,280 @@
+//! Persistent cache of `MusicBrainz` metadata
+//!
+//! [`MetadataCache`] wraps any [`MetadataSource`] and stores looked-up
+//! releases and recordings as JSON in the `metadata_cache` table, keyed by
+//! MBID. Entries expire after a per-kind TTL. Searches and cover art are
+//! passed straight through.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
+
+use chrono::{DateTime, Duration, Utc};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::db::timestamp;
+use crate::musicbrainz::{MetadataSource, Recording, Release};
+use crate::Result;
+
+/// Kind of cached entity; each has its own TTL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheKind {
+    Release,
+    Recording,
+}
+
+impl CacheKind {
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Release => "release",
+            Self::Recording => "recording",
+        }
+    }
+
+    /// How long an entry stays fresh. Releases gain corrections and cover art
+    /// more often than recordings change.
+    #[must_use]
+    pub const fn ttl(self) -> Duration {
+        match self {
+            Self::Release => Duration::days(30),
+            Self::Recording => Duration::days(90),
+        }
+    }
+}
+
+/// Entry count and size for one kind of cached entity.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheStats {
+    pub kind: String,
+    pub entries: u64,
+    pub bytes: u64,
+}
+
+/// A [`MetadataSource`] that answers lookups from the database when it can.
+pub struct MetadataCache<S> {
+    source: S,
+    conn: Mutex<Connection>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl<S: MetadataSource> MetadataCache<S> {
+    /// Layer a cache stored in `conn` over `source`.
+    pub const fn new(source: S, conn: Connection) -> Self {
+        Self {
+            source,
+            conn: Mutex::new(conn),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Lookups answered from the cache during this run.
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Lookups that went to the underlying source during this run.
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// Read a fresh entry. Unreadable entries count as misses.
+    fn get<T: DeserializeOwned>(&self, kind: CacheKind, key: &str) -> Option<T> {
+        let conn = self.conn.lock().unwrap_or_else(PoisonError::into_inner);
+        let row: Option<(String, String)> = conn
+            .query_row(
+                "SELECT data, fetched_at FROM metadata_cache WHERE kind = ?1 AND key = ?2",
+                params![kind.as_str(), key],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+            .ok()
+            .flatten();
+
+        let (data, fetched_at) = row?;
+        let fetched_at = DateTime::parse_from_rfc3339(&fetched_at).ok()?;
+        if Utc::now() - fetched_at.with_timezone(&Utc) > kind.ttl() {
+            return None;
+        }
+        serde_json::from_str(&data).ok()
+    }
+
+    /// Store an entry. Failing to cache never fails the lookup itself.
+    fn put<T: Serialize>(&self, kind: CacheKind, key: &str, value: &T) {
+        let Ok(data) = serde_json::to_string(value) else {
+            return;
+        };
+        let conn = self.conn.lock().unwrap_or_else(PoisonError::into_inner);
+        let _ = conn.execute(
+            "INSERT OR REPLACE INTO metadata_cache (kind, key, data, fetched_at)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![kind.as_str(), key, data, timestamp(&Utc::now())],
+        );
+    }
+
+    fn record(&self, hit: bool) {
+        let counter = if hit { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl<S: MetadataSource> MetadataSource for MetadataCache<S> {
+    async fn search_release(
+        &self,
+        artist: &str,
+        album: &str,
+        limit: u32,
+    ) -> Result<Vec<Release>> {
+        self.source.search_release(artist, album, limit).await
+    }
+
+    async fn lookup_release(&self, mbid: &str) -> Result<Release> {
+        if let Some(release) = self.get(CacheKind::Release, mbid) {
+            self.record(true);
+            return Ok(release);
+        }
+        self.record(false);
+        let release = self.source.lookup_release(mbid).await?;
+        self.put(CacheKind::Release, mbid, &release);
+        Ok(release)
+    }
+
+    async fn lookup_recording(&self, mbid: &str) -> Result<Recording> {
+        if let Some(recording) = self.get(CacheKind::Recording, mbid) {
+            self.record(true);
+            return Ok(recording);
+        }
+        self.record(false);
+        let recording = self.source.lookup_recording(mbid).await?;
+        self.put(CacheKind::Recording, mbid, &recording);
+        Ok(recording)
+    }
+
+    async fn fetch_cover_art(&self, mbid: &str) -> Result<Option<Vec<u8>>> {
+        self.source.fetch_cover_art(mbid).await
+    }
+
+    async fn check_reachable(&self) -> Result<()> {
+        self.source.check_reachable().await
+    }
+}
+
+/// Entry counts and stored bytes per kind.
+///
+/// # Errors
+/// Returns an error if the query fails.
+pub fn stats(conn: &Connection) -> Result<Vec<CacheStats>> {
+    let mut stmt = conn.prepare(
+        "SELECT kind, COUNT(*), COALESCE(SUM(LENGTH(data)), 0)
+         FROM metadata_cache GROUP BY kind ORDER BY kind",
+    )?;
+    let stats = stmt
+        .query_map([], |row| {
+            Ok(CacheStats {
+                kind: row.get(0)?,
+                entries: row.get(1)?,
+                bytes: row.get(2)?,
+            })
+        })?
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    Ok(stats)
+}
+
+/// Remove every cached entry, returning how many were removed.
+///
+/// # Errors
+/// Returns an error if the delete fails.
+pub fn clear(conn: &Connection) -> Result<usize> {
+    Ok(conn.execute("DELETE FROM metadata_cache", [])?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Database;
+    use crate::musicbrainz::{Artist, ArtistCredit};
+    use std::sync::atomic::AtomicUsize;
+
+    /// Source that serves a canned release and counts network-equivalent calls.
+    #[derive(Default)]
+    struct MockSource {
+        calls: AtomicUsize,
+    }
+
+    impl MetadataSource for MockSource {
+        async fn search_release(&self, _: &str, _: &str, _: u32) -> Result<Vec<Release>> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            Ok(Vec::new())
+        }
+
+        async fn lookup_release(&self, mbid: &str) -> Result<Release> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            Ok(Release {
+                id: mbid.into(),
+                title: "Paranoid".into(),
+                disambiguation: String::new(),
+                date: Some("1970-09-18".into()),
+                artist_credit: vec![ArtistCredit {
+                    artist: Artist {
+                        id: "artist".into(),
+                        name: "Black Sabbath".into(),
+                        sort_name: String::new(),
+                    },
+                    joinphrase: String::new(),
+                }],
+                country: Some("GB".into()),
+                media: Vec::new(),
+                label_info: Vec::new(),
+                release_group: None,
+                score: 100,
+            })
+        }
+
+        async fn lookup_recording(&self, mbid: &str) -> Result<Recording> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            Ok(Recording {
+                id: mbid.into(),
+                title: "War Pigs".into(),
+                length: Some(475_000),
+            })
+        }
+
+        async fn fetch_cover_art(&self, _: &str) -> Result<Option<Vec<u8>>> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            Ok(None)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_second_command_hits_cache() {
+        let path = std::env::temp_dir().join(format!("rsbts-cache-{}.db", std::process::id()));
+        let db = Database::open(&path).unwrap();
+        db.migrate().unwrap();
+
+        // First command (an import) fetches from the source
+        let import = MetadataCache::new(MockSource::default(), db.connect().unwrap());
+        let release = import.lookup_release("paranoid").await.unwrap();
+        import.lookup_recording("war-pigs").await.unwrap();
+        assert_eq!(import.source.calls.load(Ordering::Relaxed), 2);
+        assert_eq!((import.hits(), import.misses()), (0, 2));
+
+        // A later command sharing the database costs no source calls
+        let later = MetadataCache::new(MockSource::default(), db.connect().unwrap());
+        let cached = later.lookup_release("paranoid").await.unwrap();
+        later.lookup_recording("war-pigs").await.unwrap();
+        assert_eq!(later.source.calls.load(Ordering::Relaxed), 0);
+        assert_eq!((later.hits(), later.misses()), (2, 0));
+        assert_eq!(cached.title, release.title);
+        assert_eq!(cached.artist_name(), "Black Sabbath");
+
+        let stats = db.cache_stats().unwrap();
+        assert_eq!(stats.iter().map(|s| s.entries).sum::<u64>(), 2);
+        assert_eq!(db.clear_cache().unwrap(), 2);
+
+        drop(db);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -2,10 +2,13 @@
 //!
 //! This module handles versioned database migrations. Each migration is
 //! tracked in a `_migrations` table to ensure migrations run exactly once.
+//! The migration files are the only definition of the schema; a library
+//! created before they were versioned is taken to be at version 1.
 
+use log::info;
 use rusqlite::Connection;
 
-use crate::Result;
+use crate::{Error, Result};
 
 /// A database migration with a version number and SQL to execute.
 pub struct Migration {
@@ -16,16 +19,180 @@ pub struct Migration {
 }
 
 /// All available migrations, in version order.
-pub const MIGRATIONS: &[Migration] = &[Migration {
-    version: 1,
-    sql: include_str!("migrations/001_initial.sql"),
-}];
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        sql: include_str!("migrations/001_initial.sql"),
+    },
+    Migration {
+        version: 2,
+        sql: include_str!("migrations/002_metadata_cache.sql"),
+    },
+    Migration {
+        version: 3,
+        sql: include_str!("migrations/003_source_path.sql"),
+    },
+    Migration {
+        version: 4,
+        sql: include_str!("migrations/004_item_size.sql"),
+    },
+    Migration {
+        version: 5,
+        sql: include_str!("migrations/005_import_runs.sql"),
+    },
+    Migration {
+        version: 6,
+        sql: include_str!("migrations/006_null_empty_text.sql"),
+    },
+    Migration {
+        version: 7,
+        sql: include_str!("migrations/007_replaygain.sql"),
+    },
+    Migration {
+        version: 8,
+        sql: include_str!("migrations/008_audio_properties.sql"),
+    },
+    Migration {
+        version: 9,
+        sql: include_str!("migrations/009_release_group.sql"),
+    },
+    Migration {
+        version: 10,
+        sql: include_str!("migrations/010_play_stats.sql"),
+    },
+    Migration {
+        version: 11,
+        sql: include_str!("migrations/011_album_references.sql"),
+    },
+    Migration {
+        version: 12,
+        sql: include_str!("migrations/012_utc_timestamps.sql"),
+    },
+    Migration {
+        version: 13,
+        sql: include_str!("migrations/013_import_log.sql"),
+    },
+    Migration {
+        version: 14,
+        sql: include_str!("migrations/014_sort_names.sql"),
+    },
+    Migration {
+        version: 15,
+        sql: include_str!("migrations/015_meta.sql"),
+    },
+    Migration {
+        version: 16,
+        sql: include_str!("migrations/016_album_disambiguation.sql"),
+    },
+    Migration {
+        version: 17,
+        sql: include_str!("migrations/017_attributes.sql"),
+    },
+    Migration {
+        version: 18,
+        sql: include_str!("migrations/018_import_fills.sql"),
+    },
+    Migration {
+        version: 19,
+        sql: include_str!("migrations/019_fixed_precision_timestamps.sql"),
+    },
+];
+
+/// Full-text index and the triggers keeping it in sync with `items`.
+///
+/// Kept out of the versioned migrations because it depends on FTS5, which
+/// some `SQLite` builds lack.
+const FTS_SQL: &str = include_str!("migrations/fts.sql");
+
+/// Check whether the `SQLite` build supports FTS5 by creating a scratch table.
+pub fn fts5_available(conn: &Connection) -> bool {
+    conn.execute_batch(
+        "CREATE VIRTUAL TABLE temp._fts5_probe USING fts5(x);
+         DROP TABLE temp._fts5_probe;",
+    )
+    .is_ok()
+}
+
+/// The triggers [`FTS_SQL`] creates to keep the index in sync.
+const FTS_TRIGGERS: &[&str] = &["items_ai", "items_ad", "items_au"];
+
+/// Create the full-text index if it is missing, populating it from `items`.
+/// Without `triggers`, the ones keeping it in sync are dropped, leaving it
+/// to [`rebuild_fts`].
+///
+/// # Errors
+/// Returns an error if creating or populating the index fails.
+pub fn ensure_fts(conn: &Connection, triggers: bool) -> Result<()> {
+    let exists: bool = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type='table' AND name='items_fts'",
+        [],
+        |row| row.get(0),
+    )?;
+
+    conn.execute_batch(FTS_SQL)?;
+    if !triggers {
+        drop_fts_triggers(conn)?;
+    }
+
+    // Items added while FTS5 was unavailable are not indexed yet
+    if !exists {
+        rebuild_fts(conn)?;
+    }
+    Ok(())
+}
+
+/// Rebuild the full-text index from the `items` table.
+///
+/// # Errors
+/// Returns an error if the index does not exist or the rebuild fails.
+pub fn rebuild_fts(conn: &Connection) -> Result<()> {
+    conn.execute("INSERT INTO items_fts(items_fts) VALUES ('rebuild')", [])?;
+    Ok(())
+}
+
+/// Drop the triggers keeping the full-text index in sync with `items`.
+///
+/// # Errors
+/// Returns an error if dropping them fails.
+pub fn drop_fts_triggers(conn: &Connection) -> Result<()> {
+    for trigger in FTS_TRIGGERS {
+        conn.execute_batch(&format!("DROP TRIGGER IF EXISTS {trigger}"))?;
+    }
+    Ok(())
+}
+
+/// Drop the full-text index and its triggers.
+///
+/// # Errors
+/// Returns an error if dropping them fails.
+pub fn drop_fts(conn: &Connection) -> Result<()> {
+    drop_fts_triggers(conn)?;
+    conn.execute_batch("DROP TABLE IF EXISTS items_fts")?;
+    Ok(())
+}
 
 /// Run all pending migrations on the database connection.
 ///
 /// # Errors
 /// Returns an error if creating the migrations table or running a migration fails.
 pub fn run_migrations(conn: &Connection) -> Result<()> {
+    run_migrations_to(conn, None)
+}
+
+/// Run pending migrations up to and including version `target`, or all of
+/// them without one.
+///
+/// # Errors
+/// Returns an error if `target` is older than the database or newer than the
+/// latest migration, or if creating the migrations table or running a
+/// migration fails.
+pub fn run_migrations_to(conn: &Connection, target: Option<u32>) -> Result<()> {
+    run_from(conn, MIGRATIONS, target)
+}
+
+fn run_from(conn: &Connection, migrations: &[Migration], target: Option<u32>) -> Result<()> {
+    let legacy = is_legacy(conn);
+
     // Create migrations tracking table if it doesn't exist
     conn.execute(
         "CREATE TABLE IF NOT EXISTS _migrations (
@@ -35,17 +202,16 @@ pub fn run_migrations(conn: &Connection) -> Result<()> {
         [],
     )?;
 
-    // Get the current migration version
-    let current: u32 = conn
-        .query_row(
-            "SELECT COALESCE(MAX(version), 0) FROM _migrations",
-            [],
-            |row| row.get(0),
-        )
-        .unwrap_or(0);
+    // Its tables are those of version 1; later migrations alter them from there
+    if legacy {
+        info!("Library predates versioned migrations; recording it as version {LEGACY_VERSION}");
+        conn.execute(
+            "INSERT INTO _migrations (version) VALUES (?1)",
+            [LEGACY_VERSION],
+        )?;
+    }
 
-    // Run any pending migrations
-    for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+    for migration in pending_from(conn, migrations, target)? {
         conn.execute_batch(migration.sql)?;
         conn.execute(
             "INSERT INTO _migrations (version) VALUES (?1)",
@@ -56,22 +222,55 @@ pub fn run_migrations(conn: &Connection) -> Result<()> {
     Ok(())
 }
 
+/// Migrations not yet applied to the database, up to and including version
+/// `target` if given, in the order they would run.
+///
+/// # Errors
+/// Returns an error if `target` is older than the database or newer than the
+/// latest migration, or if reading the current version fails.
+pub fn pending(conn: &Connection, target: Option<u32>) -> Result<Vec<&'static Migration>> {
+    pending_from(conn, MIGRATIONS, target)
+}
+
+fn pending_from<'a>(
+    conn: &Connection,
+    migrations: &'a [Migration],
+    target: Option<u32>,
+) -> Result<Vec<&'a Migration>> {
+    let current = current_version(conn)?;
+    let latest = migrations.last().map_or(0, |m| m.version);
+    if let Some(target) = target {
+        if target > latest {
+            return Err(Error::Config(format!(
+                "No migration to version {target}; the latest is {latest}"
+            )));
+        }
+        if target < current {
+            return Err(Error::Config(format!(
+                "Database is at version {current}; migrations can't be undone to reach {target}"
+            )));
+        }
+    }
+    let target = target.unwrap_or(latest);
+    Ok(migrations
+        .iter()
+        .filter(|m| m.version > current && m.version <= target)
+        .collect())
+}
+
+/// The version the last migration brings the database to.
+#[must_use]
+pub fn latest_version() -> u32 {
+    MIGRATIONS.last().map_or(0, |m| m.version)
+}
+
 /// Get the current migration version.
 ///
 /// # Errors
 /// Returns an error if the query fails.
 pub fn current_version(conn: &Connection) -> Result<u32> {
-    // Check if migrations table exists
-    let table_exists: bool = conn
-        .query_row(
-            "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type='table' AND name='_migrations'",
-            [],
-            |row| row.get(0),
-        )
-        .unwrap_or(false);
-
-    if !table_exists {
-        return Ok(0);
+    if !table_exists(conn, "_migrations") {
+        return Ok(if is_legacy(conn) { LEGACY_VERSION } else { 0 });
     }
 
     let version: u32 = conn
@@ -85,6 +284,25 @@ pub fn current_version(conn: &Connection) -> Result<u32> {
     Ok(version)
 }
 
+/// The version of a library created before migrations were versioned: its
+/// tables were created directly, with the schema of the first migration.
+const LEGACY_VERSION: u32 = 1;
+
+/// Whether the library was created before migrations were versioned, i.e. it
+/// has tables but no record of migrations.
+fn is_legacy(conn: &Connection) -> bool {
+    !table_exists(conn, "_migrations") && table_exists(conn, "items")
+}
+
+fn table_exists(conn: &Connection, name: &str) -> bool {
+    conn.query_row(
+        "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type='table' AND name=?1",
+        [name],
+        |row| row.get(0),
+    )
+    .unwrap_or(false)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -107,7 +325,7 @@ mod tests {
 
         // Check migration was recorded
         let version = current_version(&conn).unwrap();
-        assert_eq!(version, 1);
+        assert_eq!(version, 19);
     }
 
     #[test]
@@ -118,8 +336,313 @@ mod tests {
         run_migrations(&conn).unwrap();
         run_migrations(&conn).unwrap();
 
-        // Should still be at version 1
+        // Should still be at the latest version
         let version = current_version(&conn).unwrap();
-        assert_eq!(version, 1);
+        assert_eq!(version, 19);
+    }
+
+    #[test]
+    fn test_empty_text_becomes_null() {
+        let conn = Connection::open_in_memory().unwrap();
+        for migration in &MIGRATIONS[..5] {
+            conn.execute_batch(migration.sql).unwrap();
+        }
+        conn.execute_batch(
+            "CREATE TABLE _migrations (version INTEGER PRIMARY KEY, applied_at TEXT);
+             INSERT INTO _migrations (version) VALUES (5);
+             INSERT INTO items (path, title, artist, album, genre, format, bitrate, length,
+                                added, mtime)
+             VALUES ('/a.mp3', 'a', 'x', 'y', '', 'mp3', 320, 1.0, '', ''),
+                    ('/b.mp3', 'b', 'x', 'y', NULL, 'mp3', 320, 1.0, '', ''),
+                    ('/c.mp3', 'c', 'x', 'y', 'Rock', 'mp3', 320, 1.0, '', '');",
+        )
+        .unwrap();
+
+        run_migrations(&conn).unwrap();
+        let genres: Vec<Option<String>> = conn
+            .prepare("SELECT genre FROM items ORDER BY path")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<std::result::Result<_, _>>()
+            .unwrap();
+        assert_eq!(genres, [None, None, Some("Rock".into())]);
+    }
+
+    #[test]
+    fn test_dangling_album_ids_cleared() {
+        let conn = Connection::open_in_memory().unwrap();
+        run_migrations_to(&conn, Some(10)).unwrap();
+        conn.execute_batch(
+            "INSERT INTO albums (id, album, albumartist, added)
+             VALUES (1, 'Paranoid', 'Black Sabbath', '');
+             INSERT INTO items (path, title, artist, album, format, bitrate, length, added,
+                                mtime, album_id)
+             VALUES ('/a.mp3', 'a', 'x', 'Paranoid', 'mp3', 320, 1.0, '', '', 1),
+                    ('/b.mp3', 'b', 'x', 'Gone', 'mp3', 320, 1.0, '', '', 7);",
+        )
+        .unwrap();
+
+        run_migrations(&conn).unwrap();
+        let album_ids: Vec<Option<i64>> = conn
+            .prepare("SELECT album_id FROM items ORDER BY path")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<std::result::Result<_, _>>()
+            .unwrap();
+        assert_eq!(album_ids, [Some(1), None]);
+    }
+
+    #[test]
+    fn test_timestamps_normalized_to_utc() {
+        let conn = Connection::open_in_memory().unwrap();
+        run_migrations_to(&conn, Some(11)).unwrap();
+        conn.execute_batch(
+            "INSERT INTO items (path, title, artist, album, format, bitrate, length, added,
+                                mtime, last_played)
+             VALUES ('/a.mp3', 'a', 'x', 'y', 'mp3', 320, 1.0, '2024-05-01T10:00:00+00:00',
+                     '2024-04-30T23:30:00.250+00:00', NULL),
+                    ('/b.mp3', 'b', 'x', 'y', 'mp3', 320, 1.0, '2024-05-01T01:30:00+02:00',
+                     '2024-05-01T10:00:00.123456789Z', '2024-05-02T08:00:00-05:00');",
+        )
+        .unwrap();
+
+        run_migrations(&conn).unwrap();
+        let rows: Vec<(String, String, Option<String>)> = conn
+            .prepare("SELECT added, mtime, last_played FROM items ORDER BY path")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<std::result::Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            rows,
+            [
+                (
+                    "2024-05-01T10:00:00.000000Z".into(),
+                    "2024-04-30T23:30:00.250000Z".into(),
+                    None
+                ),
+                (
+                    "2024-04-30T23:30:00.000000Z".into(),
+                    "2024-05-01T10:00:00.123456Z".into(),
+                    Some("2024-05-02T13:00:00.000000Z".into())
+                ),
+            ]
+        );
+    }
+
+    /// The schema as `sqlite_master` records it, one statement after another
+    /// in a stable order.
+    fn schema(conn: &Connection) -> String {
+        let sql: Vec<String> = conn
+            .prepare(
+                "SELECT sql FROM sqlite_master WHERE sql IS NOT NULL
+                 ORDER BY tbl_name, type DESC, name",
+            )
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<std::result::Result<_, _>>()
+            .unwrap();
+        sql.iter().map(|sql| format!("{};\n", normalize(sql))).collect()
+    }
+
+    /// Collapse whitespace, which `ALTER TABLE` leaves uneven, and put each of
+    /// a table's columns on a line of its own.
+    fn normalize(sql: &str) -> String {
+        let sql = sql
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .replace("( ", "(")
+            .replace(" )", ")")
+            .replace(" ,", ",");
+        if !sql.starts_with("CREATE TABLE") {
+            return sql;
+        }
+        let mut out = String::new();
+        let mut depth = 0;
+        for c in sql.chars() {
+            match c {
+                '(' => {
+                    depth += 1;
+                    out.push_str(if depth == 1 { "(\n    " } else { "(" });
+                }
+                ')' => {
+                    depth -= 1;
+                    out.push_str(if depth == 0 { "\n)" } else { ")" });
+                }
+                ',' if depth == 1 => out.push_str(",\n   "),
+                _ => out.push(c),
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn test_schema_snapshot() {
+        let conn = Connection::open_in_memory().unwrap();
+        run_migrations(&conn).unwrap();
+
+        let expected: String = include_str!("migrations/schema.sql")
+            .lines()
+            .filter(|line| !line.starts_with("--"))
+            .map(|line| format!("{line}\n"))
+            .collect();
+        let actual = schema(&conn);
+        assert_eq!(
+            actual,
+            expected.trim_start(),
+            "the migrated schema differs from src/migrations/schema.sql; \
+             if that is intended, update the file to:\n{actual}"
+        );
+    }
+
+    #[test]
+    fn test_pending_and_staged_upgrade() {
+        let conn = Connection::open_in_memory().unwrap();
+        let versions = |target| -> Vec<u32> {
+            pending(&conn, target)
+                .unwrap()
+                .iter()
+                .map(|m| m.version)
+                .collect()
+        };
+        assert_eq!(versions(None), (1..=latest_version()).collect::<Vec<_>>());
+        assert_eq!(versions(Some(2)), [1, 2]);
+
+        run_migrations_to(&conn, Some(3)).unwrap();
+        assert_eq!(current_version(&conn).unwrap(), 3);
+        assert!(versions(Some(3)).is_empty());
+        assert_eq!(versions(Some(5)), [4, 5]);
+        assert!(pending(&conn, Some(2)).is_err());
+        assert!(pending(&conn, Some(latest_version() + 1)).is_err());
+    }
+
+    /// A library as rsbts created it before migrations were versioned: the
+    /// tables of version 1, without `_migrations`.
+    fn legacy_library() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(include_str!("migrations/testdata/library_v1.sql"))
+            .unwrap();
+        conn.execute_batch("DROP TABLE _migrations").unwrap();
+        conn
+    }
+
+    #[test]
+    fn test_legacy_library_stamped_as_version_1() {
+        let conn = legacy_library();
+        assert_eq!(current_version(&conn).unwrap(), 1);
+        assert_eq!(pending(&conn, None).unwrap()[0].version, 2);
+
+        // Version 1 would fail if it ran again over the existing tables
+        let migrations = [
+            Migration {
+                version: 1,
+                sql: "CREATE TABLE items (id INTEGER PRIMARY KEY);",
+            },
+            Migration {
+                version: 2,
+                sql: "ALTER TABLE items ADD COLUMN mood TEXT;
+                      UPDATE items SET mood = 'heavy' WHERE artist = 'Black Sabbath';",
+            },
+        ];
+        run_from(&conn, &migrations, None).unwrap();
+
+        let versions: Vec<u32> = conn
+            .prepare("SELECT version FROM _migrations ORDER BY version")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<std::result::Result<_, _>>()
+            .unwrap();
+        assert_eq!(versions, [1, 2]);
+        let moods: Vec<(String, Option<String>)> = conn
+            .prepare("SELECT title, mood FROM items ORDER BY id")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<std::result::Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            moods,
+            [
+                ("War Pigs".into(), Some("heavy".into())),
+                ("Paranoid".into(), Some("heavy".into())),
+                ("So What".into(), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_upgrade_legacy_library() {
+        let conn = legacy_library();
+        run_migrations(&conn).unwrap();
+        assert_eq!(current_version(&conn).unwrap(), latest_version());
+
+        let fresh = Connection::open_in_memory().unwrap();
+        run_migrations(&fresh).unwrap();
+        assert_eq!(schema(&conn), schema(&fresh));
+        let count: u32 = conn
+            .query_row("SELECT COUNT(*) FROM items", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 3);
+    }
+
+    #[test]
+    fn test_upgrade_version_1_library() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(include_str!("migrations/testdata/library_v1.sql"))
+            .unwrap();
+        assert_eq!(current_version(&conn).unwrap(), 1);
+
+        run_migrations(&conn).unwrap();
+        assert_eq!(current_version(&conn).unwrap(), latest_version());
+
+        let fresh = Connection::open_in_memory().unwrap();
+        run_migrations(&fresh).unwrap();
+        assert_eq!(schema(&conn), schema(&fresh));
+
+        let items: Vec<(i64, String, Option<String>, Option<String>)> = conn
+            .prepare("SELECT album_id, title, genre, albumartist FROM items ORDER BY id")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))
+            .unwrap()
+            .collect::<std::result::Result<_, _>>()
+            .unwrap();
+        // Empty text from before version 6 is NULL now
+        assert_eq!(
+            items,
+            [
+                (
+                    1,
+                    "War Pigs".into(),
+                    Some("Heavy Metal".into()),
+                    Some("Black Sabbath".into())
+                ),
+                (1, "Paranoid".into(), None, Some("Black Sabbath".into())),
+                (2, "So What".into(), Some("Jazz".into()), None),
+            ]
+        );
+
+        let albums: Vec<(String, Option<String>)> = conn
+            .prepare("SELECT album, artpath FROM albums ORDER BY id")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<std::result::Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            albums,
+            [
+                (
+                    "Paranoid".into(),
+                    Some("/music/Black Sabbath/Paranoid/cover.jpg".into())
+                ),
+                ("Kind of Blue".into(), None),
+            ]
+        );
     }
 }
@@ -0,0 +1,9 @@
+-- Cached MusicBrainz entities, stored as the JSON the client deserialized
+
+CREATE TABLE IF NOT EXISTS metadata_cache (
+    kind TEXT NOT NULL,
+    key TEXT NOT NULL,
+    data TEXT NOT NULL,
+    fetched_at TEXT NOT NULL,
+    PRIMARY KEY (kind, key)
+);
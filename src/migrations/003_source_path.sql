@@ -0,0 +1,5 @@
+-- Provenance: where albums and items were imported from. Rows imported
+-- before this migration keep NULL.
+
+ALTER TABLE albums ADD COLUMN source_path TEXT;
+ALTER TABLE items ADD COLUMN source_path TEXT;
@@ -0,0 +1,4 @@
+-- File size at the time an item was last read, used to detect changes made
+-- outside rsbts. NULL for items imported before this migration.
+
+ALTER TABLE items ADD COLUMN size INTEGER;
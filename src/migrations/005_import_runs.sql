@@ -0,0 +1,15 @@
+-- Import runs: each `import` invocation, so everything it added can be
+-- listed and undone together. Rows imported before this migration have no
+-- run.
+
+CREATE TABLE import_runs (
+    id TEXT PRIMARY KEY,
+    started TEXT NOT NULL,
+    action TEXT NOT NULL
+);
+
+ALTER TABLE albums ADD COLUMN import_run TEXT;
+ALTER TABLE items ADD COLUMN import_run TEXT;
+
+CREATE INDEX idx_albums_import_run ON albums(import_run);
+CREATE INDEX idx_items_import_run ON items(import_run);
@@ -0,0 +1,10 @@
+-- Empty tag frames used to be stored as '' rather than NULL, so a field
+-- could be missing in two ways. Tags are read with empty values as NULL
+-- now; this converts what was stored before.
+
+UPDATE items SET albumartist = NULL WHERE albumartist = '';
+UPDATE items SET genre = NULL WHERE genre = '';
+UPDATE items SET mb_trackid = NULL WHERE mb_trackid = '';
+UPDATE items SET mb_albumid = NULL WHERE mb_albumid = '';
+UPDATE albums SET artpath = NULL WHERE artpath = '';
+UPDATE albums SET mb_albumid = NULL WHERE mb_albumid = '';
@@ -0,0 +1,7 @@
+-- ReplayGain values from `rsbts replaygain`: gains in dB, peaks where 1.0 is
+-- full scale. NULL for items that haven't been analyzed.
+
+ALTER TABLE items ADD COLUMN rg_track_gain REAL;
+ALTER TABLE items ADD COLUMN rg_track_peak REAL;
+ALTER TABLE items ADD COLUMN rg_album_gain REAL;
+ALTER TABLE items ADD COLUMN rg_album_peak REAL;
@@ -0,0 +1,7 @@
+-- Sample rate, channel count and bit depth read from the audio stream. NULL
+-- for items read before this migration (until `update` re-reads them) and,
+-- for bitdepth, for lossy formats.
+
+ALTER TABLE items ADD COLUMN samplerate INTEGER;
+ALTER TABLE items ADD COLUMN channels INTEGER;
+ALTER TABLE items ADD COLUMN bitdepth INTEGER;
@@ -0,0 +1,5 @@
+-- MusicBrainz release group of albums and their items: the album across its
+-- reissues and pressings. NULL until an album is matched (or retagged) again.
+
+ALTER TABLE albums ADD COLUMN mb_releasegroupid TEXT;
+ALTER TABLE items ADD COLUMN mb_releasegroupid TEXT;
@@ -0,0 +1,7 @@
+-- Listening statistics kept by rsbts itself: a 0-5 rating set with `rate`,
+-- and the play count and time of the last play recorded with `played`.
+-- They live only in the library, never in the files' tags.
+
+ALTER TABLE items ADD COLUMN rating INTEGER CHECK (rating BETWEEN 0 AND 5);
+ALTER TABLE items ADD COLUMN play_count INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE items ADD COLUMN last_played TEXT;
@@ -0,0 +1,6 @@
+-- Foreign keys are enforced from this version on. Items could be left
+-- pointing at albums that were deleted while they weren't; they belong to no
+-- album now, as if the reference were declared ON DELETE SET NULL.
+
+UPDATE items SET album_id = NULL
+WHERE album_id IS NOT NULL AND album_id NOT IN (SELECT id FROM albums);
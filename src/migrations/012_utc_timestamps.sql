@@ -0,0 +1,33 @@
+-- Timestamps are stored in UTC with a 'Z' suffix, so they sort and compare
+-- as text. They were written with a '+00:00' offset before; any other offset
+-- is converted to UTC, to the whole second.
+
+UPDATE items SET added = substr(added, 1, length(added) - 6) || 'Z'
+WHERE added LIKE '%+00:00';
+UPDATE items SET added = strftime('%Y-%m-%dT%H:%M:%SZ', added)
+WHERE added NOT LIKE '%Z' AND julianday(added) IS NOT NULL;
+
+UPDATE items SET mtime = substr(mtime, 1, length(mtime) - 6) || 'Z'
+WHERE mtime LIKE '%+00:00';
+UPDATE items SET mtime = strftime('%Y-%m-%dT%H:%M:%SZ', mtime)
+WHERE mtime NOT LIKE '%Z' AND julianday(mtime) IS NOT NULL;
+
+UPDATE items SET last_played = substr(last_played, 1, length(last_played) - 6) || 'Z'
+WHERE last_played LIKE '%+00:00';
+UPDATE items SET last_played = strftime('%Y-%m-%dT%H:%M:%SZ', last_played)
+WHERE last_played NOT LIKE '%Z' AND julianday(last_played) IS NOT NULL;
+
+UPDATE albums SET added = substr(added, 1, length(added) - 6) || 'Z'
+WHERE added LIKE '%+00:00';
+UPDATE albums SET added = strftime('%Y-%m-%dT%H:%M:%SZ', added)
+WHERE added NOT LIKE '%Z' AND julianday(added) IS NOT NULL;
+
+UPDATE import_runs SET started = substr(started, 1, length(started) - 6) || 'Z'
+WHERE started LIKE '%+00:00';
+UPDATE import_runs SET started = strftime('%Y-%m-%dT%H:%M:%SZ', started)
+WHERE started NOT LIKE '%Z' AND julianday(started) IS NOT NULL;
+
+UPDATE metadata_cache SET fetched_at = substr(fetched_at, 1, length(fetched_at) - 6) || 'Z'
+WHERE fetched_at LIKE '%+00:00';
+UPDATE metadata_cache SET fetched_at = strftime('%Y-%m-%dT%H:%M:%SZ', fetched_at)
+WHERE fetched_at NOT LIKE '%Z' AND julianday(fetched_at) IS NOT NULL;
@@ -0,0 +1,31 @@
+-- Import log: each file an import put into the library, where it came from
+-- and how, and its modification time and size then, so undoing the import
+-- can put each back as it was done and leave alone any changed since.
+-- Files imported before this migration are logged from their items, and
+-- cover art from their albums, without a time or size.
+
+CREATE TABLE import_log (
+    id INTEGER PRIMARY KEY,
+    import_run TEXT NOT NULL,
+    logged_at TEXT NOT NULL,
+    action TEXT NOT NULL,
+    source_path TEXT,
+    dest_path TEXT NOT NULL,
+    item_id INTEGER,
+    album_id INTEGER,
+    mtime TEXT,
+    size INTEGER
+);
+
+CREATE INDEX idx_import_log_run ON import_log(import_run);
+
+INSERT INTO import_log (import_run, logged_at, action, source_path, dest_path, item_id,
+                        album_id, mtime, size)
+SELECT items.import_run, import_runs.started, import_runs.action, items.source_path,
+       items.path, items.id, items.album_id, items.mtime, items.size
+FROM items JOIN import_runs ON import_runs.id = items.import_run;
+
+INSERT INTO import_log (import_run, logged_at, action, dest_path, album_id)
+SELECT albums.import_run, import_runs.started, 'write', albums.artpath, albums.id
+FROM albums JOIN import_runs ON import_runs.id = albums.import_run
+WHERE albums.artpath IS NOT NULL;
@@ -0,0 +1,6 @@
+-- Artist and album artist sort names, such as "Chemical Brothers, The", as
+-- tagged or credited on MusicBrainz. NULL when neither gives one; sorting
+-- falls back to the names themselves then.
+
+ALTER TABLE items ADD COLUMN artist_sort TEXT;
+ALTER TABLE items ADD COLUMN albumartist_sort TEXT;
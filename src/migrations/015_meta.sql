@@ -0,0 +1,7 @@
+-- Facts about the database itself, such as whether it belongs to a portable
+-- library whose item paths are stored relative to its root.
+
+CREATE TABLE meta (
+    key TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+);
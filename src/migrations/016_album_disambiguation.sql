@@ -0,0 +1,4 @@
+-- What tells a release apart from others of the same name on MusicBrainz,
+-- such as "Deluxe Edition", for `%aunique{}` in path formats.
+
+ALTER TABLE albums ADD COLUMN disambiguation TEXT;
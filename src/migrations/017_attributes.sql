@@ -0,0 +1,18 @@
+-- Flexible attributes: whatever else a user keeps about an item or album,
+-- such as where a record came from, as text under a key of their choosing.
+-- They go with the item or album they belong to.
+
+CREATE TABLE item_attributes (
+    item_id INTEGER NOT NULL REFERENCES items(id) ON DELETE CASCADE,
+    key TEXT NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY (item_id, key)
+);
+CREATE INDEX idx_item_attributes_key ON item_attributes(key, value);
+
+CREATE TABLE album_attributes (
+    album_id INTEGER NOT NULL REFERENCES albums(id) ON DELETE CASCADE,
+    key TEXT NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY (album_id, key)
+);
@@ -0,0 +1,13 @@
+-- Fields an import filled in on an album already in the library when it
+-- added tracks to it, such as a year the album lacked, with the value it
+-- gave them, so undoing the import can clear them again.
+
+CREATE TABLE import_fills (
+    id INTEGER PRIMARY KEY,
+    import_run TEXT NOT NULL,
+    album_id INTEGER NOT NULL REFERENCES albums(id) ON DELETE CASCADE,
+    field TEXT NOT NULL,
+    value TEXT NOT NULL
+);
+
+CREATE INDEX idx_import_fills_run ON import_fills(import_run);
@@ -0,0 +1,51 @@
+-- Timestamps are stored with exactly six digits of fractional seconds, so
+-- two from the same second sort as text in the order they happened. They
+-- had as many digits as they needed before, and none for whole seconds.
+
+UPDATE items SET added = substr(added, 1, 19) || '.'
+    || substr(substr(added, 21, length(added) - 21) || '000000', 1, 6) || 'Z'
+WHERE added GLOB '????-??-??T??:??:??.*Z';
+UPDATE items SET added = substr(added, 1, 19) || '.000000Z'
+WHERE added GLOB '????-??-??T??:??:??Z';
+
+UPDATE items SET mtime = substr(mtime, 1, 19) || '.'
+    || substr(substr(mtime, 21, length(mtime) - 21) || '000000', 1, 6) || 'Z'
+WHERE mtime GLOB '????-??-??T??:??:??.*Z';
+UPDATE items SET mtime = substr(mtime, 1, 19) || '.000000Z'
+WHERE mtime GLOB '????-??-??T??:??:??Z';
+
+UPDATE items SET last_played = substr(last_played, 1, 19) || '.'
+    || substr(substr(last_played, 21, length(last_played) - 21) || '000000', 1, 6) || 'Z'
+WHERE last_played GLOB '????-??-??T??:??:??.*Z';
+UPDATE items SET last_played = substr(last_played, 1, 19) || '.000000Z'
+WHERE last_played GLOB '????-??-??T??:??:??Z';
+
+UPDATE albums SET added = substr(added, 1, 19) || '.'
+    || substr(substr(added, 21, length(added) - 21) || '000000', 1, 6) || 'Z'
+WHERE added GLOB '????-??-??T??:??:??.*Z';
+UPDATE albums SET added = substr(added, 1, 19) || '.000000Z'
+WHERE added GLOB '????-??-??T??:??:??Z';
+
+UPDATE import_runs SET started = substr(started, 1, 19) || '.'
+    || substr(substr(started, 21, length(started) - 21) || '000000', 1, 6) || 'Z'
+WHERE started GLOB '????-??-??T??:??:??.*Z';
+UPDATE import_runs SET started = substr(started, 1, 19) || '.000000Z'
+WHERE started GLOB '????-??-??T??:??:??Z';
+
+UPDATE import_log SET logged_at = substr(logged_at, 1, 19) || '.'
+    || substr(substr(logged_at, 21, length(logged_at) - 21) || '000000', 1, 6) || 'Z'
+WHERE logged_at GLOB '????-??-??T??:??:??.*Z';
+UPDATE import_log SET logged_at = substr(logged_at, 1, 19) || '.000000Z'
+WHERE logged_at GLOB '????-??-??T??:??:??Z';
+
+UPDATE import_log SET mtime = substr(mtime, 1, 19) || '.'
+    || substr(substr(mtime, 21, length(mtime) - 21) || '000000', 1, 6) || 'Z'
+WHERE mtime GLOB '????-??-??T??:??:??.*Z';
+UPDATE import_log SET mtime = substr(mtime, 1, 19) || '.000000Z'
+WHERE mtime GLOB '????-??-??T??:??:??Z';
+
+UPDATE metadata_cache SET fetched_at = substr(fetched_at, 1, 19) || '.'
+    || substr(substr(fetched_at, 21, length(fetched_at) - 21) || '000000', 1, 6) || 'Z'
+WHERE fetched_at GLOB '????-??-??T??:??:??.*Z';
+UPDATE metadata_cache SET fetched_at = substr(fetched_at, 1, 19) || '.000000Z'
+WHERE fetched_at GLOB '????-??-??T??:??:??Z';
@@ -0,0 +1,24 @@
+-- Full-text search index over items; only created when SQLite has FTS5
+
+CREATE VIRTUAL TABLE IF NOT EXISTS items_fts USING fts5(
+    title, artist, album, albumartist, genre,
+    content='items',
+    content_rowid='id'
+);
+
+CREATE TRIGGER IF NOT EXISTS items_ai AFTER INSERT ON items BEGIN
+    INSERT INTO items_fts(rowid, title, artist, album, albumartist, genre)
+    VALUES (new.id, new.title, new.artist, new.album, new.albumartist, new.genre);
+END;
+
+CREATE TRIGGER IF NOT EXISTS items_ad AFTER DELETE ON items BEGIN
+    INSERT INTO items_fts(items_fts, rowid, title, artist, album, albumartist, genre)
+    VALUES ('delete', old.id, old.title, old.artist, old.album, old.albumartist, old.genre);
+END;
+
+CREATE TRIGGER IF NOT EXISTS items_au AFTER UPDATE ON items BEGIN
+    INSERT INTO items_fts(items_fts, rowid, title, artist, album, albumartist, genre)
+    VALUES ('delete', old.id, old.title, old.artist, old.album, old.albumartist, old.genre);
+    INSERT INTO items_fts(rowid, title, artist, album, albumartist, genre)
+    VALUES (new.id, new.title, new.artist, new.album, new.albumartist, new.genre);
+END;
@@ -0,0 +1,113 @@
+-- The schema every migration together produces, as dumped by the snapshot
+-- test in migrations.rs. Update it along with any new migration.
+
+CREATE TABLE _migrations (
+    version INTEGER PRIMARY KEY,
+    applied_at TEXT NOT NULL DEFAULT (datetime('now'))
+);
+CREATE TABLE album_attributes (
+    album_id INTEGER NOT NULL REFERENCES albums(id) ON DELETE CASCADE,
+    key TEXT NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY (album_id, key)
+);
+CREATE TABLE albums (
+    id INTEGER PRIMARY KEY,
+    album TEXT NOT NULL,
+    albumartist TEXT NOT NULL,
+    year INTEGER,
+    artpath TEXT,
+    mb_albumid TEXT,
+    added TEXT NOT NULL,
+    source_path TEXT,
+    import_run TEXT,
+    mb_releasegroupid TEXT,
+    disambiguation TEXT
+);
+CREATE INDEX idx_albums_import_run ON albums(import_run);
+CREATE TABLE import_fills (
+    id INTEGER PRIMARY KEY,
+    import_run TEXT NOT NULL,
+    album_id INTEGER NOT NULL REFERENCES albums(id) ON DELETE CASCADE,
+    field TEXT NOT NULL,
+    value TEXT NOT NULL
+);
+CREATE INDEX idx_import_fills_run ON import_fills(import_run);
+CREATE TABLE import_log (
+    id INTEGER PRIMARY KEY,
+    import_run TEXT NOT NULL,
+    logged_at TEXT NOT NULL,
+    action TEXT NOT NULL,
+    source_path TEXT,
+    dest_path TEXT NOT NULL,
+    item_id INTEGER,
+    album_id INTEGER,
+    mtime TEXT,
+    size INTEGER
+);
+CREATE INDEX idx_import_log_run ON import_log(import_run);
+CREATE TABLE import_runs (
+    id TEXT PRIMARY KEY,
+    started TEXT NOT NULL,
+    action TEXT NOT NULL
+);
+CREATE TABLE item_attributes (
+    item_id INTEGER NOT NULL REFERENCES items(id) ON DELETE CASCADE,
+    key TEXT NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY (item_id, key)
+);
+CREATE INDEX idx_item_attributes_key ON item_attributes(key, value);
+CREATE TABLE items (
+    id INTEGER PRIMARY KEY,
+    album_id INTEGER REFERENCES albums(id),
+    path TEXT NOT NULL UNIQUE,
+    title TEXT NOT NULL,
+    artist TEXT NOT NULL,
+    album TEXT NOT NULL,
+    albumartist TEXT,
+    genre TEXT,
+    year INTEGER,
+    track INTEGER,
+    disc INTEGER,
+    format TEXT NOT NULL,
+    bitrate INTEGER NOT NULL,
+    length REAL NOT NULL,
+    mb_trackid TEXT,
+    mb_albumid TEXT,
+    added TEXT NOT NULL,
+    mtime TEXT NOT NULL,
+    source_path TEXT,
+    size INTEGER,
+    import_run TEXT,
+    rg_track_gain REAL,
+    rg_track_peak REAL,
+    rg_album_gain REAL,
+    rg_album_peak REAL,
+    samplerate INTEGER,
+    channels INTEGER,
+    bitdepth INTEGER,
+    mb_releasegroupid TEXT,
+    rating INTEGER CHECK (rating BETWEEN 0 AND 5),
+    play_count INTEGER NOT NULL DEFAULT 0,
+    last_played TEXT,
+    artist_sort TEXT,
+    albumartist_sort TEXT
+);
+CREATE INDEX idx_items_album ON items(album);
+CREATE INDEX idx_items_artist ON items(artist);
+CREATE INDEX idx_items_genre ON items(genre);
+CREATE INDEX idx_items_import_run ON items(import_run);
+CREATE INDEX idx_items_path ON items(path);
+CREATE INDEX idx_items_year ON items(year);
+CREATE TABLE meta (
+    key TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+);
+CREATE TABLE metadata_cache (
+    kind TEXT NOT NULL,
+    key TEXT NOT NULL,
+    data TEXT NOT NULL,
+    fetched_at TEXT NOT NULL,
+    PRIMARY KEY (kind, key)
+);
@@ -0,0 +1,48 @@
+-- A library as rsbts left it at schema version 1, dumped with `.dump`
+
+BEGIN TRANSACTION;
+CREATE TABLE _migrations (
+            version INTEGER PRIMARY KEY,
+            applied_at TEXT NOT NULL DEFAULT (datetime('now'))
+        );
+INSERT INTO "_migrations" VALUES(1,'2023-04-02 18:31:07');
+CREATE TABLE albums (
+    id INTEGER PRIMARY KEY,
+    album TEXT NOT NULL,
+    albumartist TEXT NOT NULL,
+    year INTEGER,
+    artpath TEXT,
+    mb_albumid TEXT,
+    added TEXT NOT NULL
+);
+INSERT INTO "albums" VALUES(1,'Paranoid','Black Sabbath',1970,'/music/Black Sabbath/Paranoid/cover.jpg','','2023-04-02T18:31:07+00:00');
+INSERT INTO "albums" VALUES(2,'Kind of Blue','Miles Davis',1959,'',NULL,'2023-04-02T18:31:09+00:00');
+CREATE TABLE items (
+    id INTEGER PRIMARY KEY,
+    album_id INTEGER REFERENCES albums(id),
+    path TEXT NOT NULL UNIQUE,
+    title TEXT NOT NULL,
+    artist TEXT NOT NULL,
+    album TEXT NOT NULL,
+    albumartist TEXT,
+    genre TEXT,
+    year INTEGER,
+    track INTEGER,
+    disc INTEGER,
+    format TEXT NOT NULL,
+    bitrate INTEGER NOT NULL,
+    length REAL NOT NULL,
+    mb_trackid TEXT,
+    mb_albumid TEXT,
+    added TEXT NOT NULL,
+    mtime TEXT NOT NULL
+);
+INSERT INTO "items" VALUES(1,1,'/music/Black Sabbath/Paranoid/01 War Pigs.flac','War Pigs','Black Sabbath','Paranoid','Black Sabbath','Heavy Metal',1970,1,1,'flac',1021,475.2,'','','2023-04-02T18:31:07+00:00','2023-03-30T09:12:44+00:00');
+INSERT INTO "items" VALUES(2,1,'/music/Black Sabbath/Paranoid/02 Paranoid.flac','Paranoid','Black Sabbath','Paranoid','Black Sabbath','',1970,2,1,'flac',1034,168.0,NULL,NULL,'2023-04-02T18:31:07+00:00','2023-03-30T09:12:44+00:00');
+INSERT INTO "items" VALUES(3,2,'/music/Miles Davis/Kind of Blue/01 So What.mp3','So What','Miles Davis','Kind of Blue','','Jazz',1959,1,NULL,'mp3',320,562.4,NULL,NULL,'2023-04-02T18:31:09+00:00','2023-03-31T21:40:02+00:00');
+CREATE INDEX idx_items_artist ON items(artist);
+CREATE INDEX idx_items_album ON items(album);
+CREATE INDEX idx_items_year ON items(year);
+CREATE INDEX idx_items_genre ON items(genre);
+CREATE INDEX idx_items_path ON items(path);
+COMMIT;
@@ -0,0 +1,182 @@
+//! Comparing library albums against their `MusicBrainz` tracklists
+
+use crate::musicbrainz::Release;
+use crate::Item;
+
+/// A release track with no matching item in the library.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MissingTrack {
+    /// Medium position, or `None` for single-medium releases.
+    pub disc: Option<u32>,
+    /// Track number as printed on the release, e.g. `3` or `A2`.
+    pub number: String,
+    pub title: String,
+}
+
+impl MissingTrack {
+    /// Position for display, e.g. `3` or `2-03`.
+    #[must_use]
+    pub fn position(&self) -> String {
+        match self.disc {
+            Some(disc) => format!("{disc}-{}", self.number),
+            None => self.number.clone(),
+        }
+    }
+}
+
+/// Tracks on `release` not present among `items`.
+///
+/// Items are matched by `mb_trackid` (against the recording or track ID)
+/// first, then by case-insensitive title. Each item matches at most one track.
+#[must_use]
+pub fn missing_tracks(release: &Release, items: &[Item]) -> Vec<MissingTrack> {
+    let multi_disc = release.media.len() > 1;
+    let tracks: Vec<_> = release
+        .media
+        .iter()
+        .flat_map(|medium| medium.tracks.iter().map(move |track| (medium.position, track)))
+        .collect();
+
+    let mut used = vec![false; items.len()];
+    let mut matched = vec![false; tracks.len()];
+
+    for (t, (_, track)) in tracks.iter().enumerate() {
+        let by_id = items.iter().enumerate().position(|(i, item)| {
+            !used[i]
+                && item
+                    .mb_trackid
+                    .as_deref()
+                    .is_some_and(|id| id == track.recording.id || id == track.id)
+        });
+        if let Some(i) = by_id {
+            used[i] = true;
+            matched[t] = true;
+        }
+    }
+
+    for (t, (_, track)) in tracks.iter().enumerate() {
+        if matched[t] {
+            continue;
+        }
+        let title = track.title.to_lowercase();
+        let by_title = items
+            .iter()
+            .enumerate()
+            .position(|(i, item)| !used[i] && item.title.trim().to_lowercase() == title);
+        if let Some(i) = by_title {
+            used[i] = true;
+            matched[t] = true;
+        }
+    }
+
+    tracks
+        .into_iter()
+        .zip(matched)
+        .filter(|(_, matched)| !matched)
+        .map(|((disc, track), _)| MissingTrack {
+            disc: multi_disc.then_some(disc),
+            number: track.number.clone(),
+            title: track.title.clone(),
+        })
+        .collect()
+}
+
+/// Number of tracks on a release, counting the tracklist when present.
+#[must_use]
+pub fn tracklist_len(release: &Release) -> usize {
+    release.media.iter().map(|m| m.tracks.len()).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::musicbrainz::{Medium, Recording, Track};
+    use crate::testutil;
+
+    fn item(title: &str, mb_trackid: Option<&str>) -> Item {
+        Item {
+            album_id: Some(1),
+            path: format!("/{title}.flac").into(),
+            title: title.into(),
+            artist: "Black Sabbath".into(),
+            album: "Paranoid".into(),
+            format: crate::AudioFormat::Flac,
+            bitrate: 900,
+            length: 180.0,
+            mb_trackid: mb_trackid.map(Into::into),
+            ..testutil::item()
+        }
+    }
+
+    fn track(number: &str, title: &str, recording: &str) -> Track {
+        Track {
+            id: format!("track-{recording}"),
+            position: number.parse().unwrap_or(0),
+            number: number.into(),
+            title: title.into(),
+            length: None,
+            recording: Recording {
+                id: recording.into(),
+                title: title.into(),
+                length: None,
+            },
+            artist_credit: Vec::new(),
+        }
+    }
+
+    fn release(media: Vec<Vec<Track>>) -> Release {
+        Release {
+            id: "paranoid".into(),
+            title: "Paranoid".into(),
+            disambiguation: String::new(),
+            date: None,
+            artist_credit: Vec::new(),
+            country: None,
+            media: (1..)
+                .zip(media)
+                .map(|(position, tracks)| Medium {
+                    position,
+                    format: None,
+                    track_count: tracks.len(),
+                    tracks,
+                })
+                .collect(),
+            label_info: Vec::new(),
+            release_group: None,
+            score: 0,
+        }
+    }
+
+    #[test]
+    fn test_matches_by_id_then_title() {
+        let release = release(vec![vec![
+            track("1", "War Pigs", "rec-1"),
+            track("2", "Paranoid", "rec-2"),
+            track("3", "Planet Caravan", "rec-3"),
+            track("4", "Iron Man", "rec-4"),
+        ]]);
+        let items = vec![
+            // Retitled locally but still identified by its recording
+            item("War Pigs / Luke's Wall", Some("rec-1")),
+            item("paranoid", None),
+        ];
+
+        let missing = missing_tracks(&release, &items);
+        let titles: Vec<_> = missing.iter().map(|m| m.title.as_str()).collect();
+        assert_eq!(titles, ["Planet Caravan", "Iron Man"]);
+        assert_eq!(missing[0].position(), "3");
+        assert_eq!(tracklist_len(&release), 4);
+    }
+
+    #[test]
+    fn test_multi_disc_positions() {
+        let release = release(vec![
+            vec![track("1", "Intro", "rec-1")],
+            vec![track("1", "Intro", "rec-2")],
+        ]);
+        // One "Intro" only covers one of the two tracks with that title
+        let missing = missing_tracks(&release, &[item("Intro", None)]);
+        assert_eq!(missing.len(), 1);
+        assert_eq!(missing[0].position(), "2-1");
+    }
+}
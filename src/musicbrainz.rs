@@ -1,149 +1,316 @@
 //! `MusicBrainz` API client
 
 use std::fmt::Write;
-use std::time::{Duration, Instant};
+use std::future::Future;
+use std::time::Duration;
 
-use tokio::sync::Mutex;
+use log::trace;
+use serde::{Deserialize, Serialize};
 
-use serde::Deserialize;
-
-use crate::{Error, Result};
+use crate::ratelimit::RateLimiter;
+use crate::Result;
 
 const API_BASE: &str = "https://musicbrainz.org/ws/2";
-const USER_AGENT: &str = "rsbts/0.1.0 (https://github.com/user/rsbts)";
+const USER_AGENT: &str = "rsbts/0.1.0";
+const HOMEPAGE: &str = "https://github.com/user/rsbts";
 const RATE_LIMIT: Duration = Duration::from_secs(1);
+/// How long [`MetadataSource::check_reachable`] waits for an answer.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Default request timeout, in seconds.
+pub const DEFAULT_TIMEOUT_SECS: u64 = 30;
 
 pub struct Client {
     http: reqwest::Client,
-    last_request: Mutex<Option<Instant>>,
+    limiter: RateLimiter,
+}
+
+/// How the [`Client`] talks to `MusicBrainz`.
+#[derive(Debug, Clone)]
+pub struct ClientSettings {
+    /// How long a request may take before it fails.
+    pub timeout: Duration,
+    /// Contact address or URL added to the User-Agent, as `MusicBrainz` asks
+    /// of applications so they can be reached about their traffic.
+    pub contact: Option<String>,
+}
+
+impl Default for ClientSettings {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(DEFAULT_TIMEOUT_SECS),
+            contact: None,
+        }
+    }
+}
+
+impl ClientSettings {
+    /// The User-Agent header: the program and version, then its homepage and
+    /// the contact, if there is one.
+    #[must_use]
+    pub fn user_agent(&self) -> String {
+        match self.contact.as_deref().map(str::trim) {
+            Some(contact) if !contact.is_empty() => {
+                format!("{USER_AGENT} ({HOMEPAGE}; {contact})")
+            }
+            _ => format!("{USER_AGENT} ({HOMEPAGE})"),
+        }
+    }
+}
+
+/// Why a `MusicBrainz` or Cover Art Archive request failed.
+#[derive(Debug, thiserror::Error)]
+pub enum ApiError {
+    /// The server couldn't be reached, or didn't answer in time.
+    #[error("could not reach the server: {0}")]
+    Connect(String),
+    /// The server answered with an error status.
+    #[error("server returned {0}")]
+    Http(reqwest::StatusCode),
+    /// The answer wasn't what was asked for.
+    #[error("unreadable response: {0}")]
+    Decode(String),
+    #[error("{0}")]
+    Other(String),
+}
+
+impl ApiError {
+    /// Whether the server couldn't be reached at all, as when offline.
+    #[must_use]
+    pub const fn is_unreachable(&self) -> bool {
+        matches!(self, Self::Connect(_))
+    }
+}
+
+impl From<reqwest::Error> for ApiError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_connect() || e.is_timeout() {
+            Self::Connect(e.to_string())
+        } else if e.is_decode() || e.is_body() {
+            Self::Decode(e.to_string())
+        } else if let Some(status) = e.status() {
+            Self::Http(status)
+        } else {
+            Self::Other(e.to_string())
+        }
+    }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ReleaseSearchResult {
     pub releases: Vec<Release>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Release {
     pub id: String,
     pub title: String,
+    /// What tells the release apart from others of the same name, such as
+    /// "Deluxe Edition"; empty for most.
+    #[serde(default)]
+    pub disambiguation: String,
     #[serde(default)]
     pub date: Option<String>,
     #[serde(rename = "artist-credit", default)]
     pub artist_credit: Vec<ArtistCredit>,
     #[serde(default)]
+    pub country: Option<String>,
+    #[serde(default)]
     pub media: Vec<Medium>,
+    #[serde(rename = "label-info", default)]
+    pub label_info: Vec<LabelInfo>,
+    #[serde(rename = "release-group", default)]
+    pub release_group: Option<ReleaseGroup>,
     #[serde(default)]
     pub score: u32,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+/// The album a release is one edition of, shared by its reissues and
+/// pressings.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReleaseGroup {
+    pub id: String,
+    #[serde(default)]
+    pub title: String,
+    #[serde(rename = "primary-type", default)]
+    pub primary_type: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LabelInfo {
+    #[serde(rename = "catalog-number", default)]
+    pub catalog_number: Option<String>,
+    #[serde(default)]
+    pub label: Option<Label>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Label {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ArtistCredit {
     pub artist: Artist,
     #[serde(default)]
     pub joinphrase: String,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Artist {
     pub id: String,
     pub name: String,
+    /// The name as it's sorted, such as "Chemical Brothers, The"; empty in
+    /// entries cached before it was kept.
+    #[serde(rename = "sort-name", default)]
+    pub sort_name: String,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Medium {
     #[serde(default)]
     pub position: u32,
     #[serde(default)]
+    pub format: Option<String>,
+    /// Track count as reported by search results, which omit the track list.
+    #[serde(rename = "track-count", default)]
+    pub track_count: usize,
+    #[serde(default)]
     pub tracks: Vec<Track>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Track {
     pub id: String,
+    /// Position on the medium, counting from 1; 0 if unknown.
+    #[serde(default)]
+    pub position: u32,
     pub number: String,
     pub title: String,
     pub length: Option<u64>,
     pub recording: Recording,
+    /// The track's artists, which may differ from the release's, as with
+    /// guest artists and compilations.
+    #[serde(rename = "artist-credit", default)]
+    pub artist_credit: Vec<ArtistCredit>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Recording {
     pub id: String,
     pub title: String,
     pub length: Option<u64>,
 }
 
+/// Source of release metadata.
+///
+/// Implemented by the `MusicBrainz` [`Client`], by
+/// [`MetadataCache`](crate::metadata_cache::MetadataCache) layered over it,
+/// and by test doubles.
+pub trait MetadataSource: Send + Sync {
+    /// Search for releases matching artist and album, or only album if
+    /// `artist` is empty, as for compilations.
+    fn search_release(
+        &self,
+        artist: &str,
+        album: &str,
+        limit: u32,
+    ) -> impl Future<Output = Result<Vec<Release>>> + Send;
+
+    /// Look up a release, with recordings, by `MusicBrainz` ID.
+    fn lookup_release(&self, mbid: &str) -> impl Future<Output = Result<Release>> + Send;
+
+    /// Look up a recording by `MusicBrainz` ID.
+    fn lookup_recording(&self, mbid: &str) -> impl Future<Output = Result<Recording>> + Send;
+
+    /// Fetch front cover art for a release, if there is any.
+    fn fetch_cover_art(
+        &self,
+        mbid: &str,
+    ) -> impl Future<Output = Result<Option<Vec<u8>>>> + Send;
+
+    /// Check quickly that the source can be reached at all. Sources that
+    /// need no network always can.
+    fn check_reachable(&self) -> impl Future<Output = Result<()>> + Send {
+        async { Ok(()) }
+    }
+}
+
 impl Client {
-    /// Create a new `MusicBrainz` API client.
+    /// Create a new `MusicBrainz` API client with the default settings.
     ///
     /// # Errors
     /// Returns an error if the HTTP client cannot be built (should never happen).
     pub fn new() -> Result<Self> {
+        Self::with_settings(&ClientSettings::default())
+    }
+
+    /// Create a new `MusicBrainz` API client with `settings`.
+    ///
+    /// # Errors
+    /// Returns an error if the HTTP client cannot be built, as with a
+    /// contact that can't go in a header.
+    pub fn with_settings(settings: &ClientSettings) -> Result<Self> {
         let http = reqwest::Client::builder()
-            .user_agent(USER_AGENT)
-            .timeout(Duration::from_secs(30))
+            .user_agent(settings.user_agent())
+            .timeout(settings.timeout)
             .build()
-            .map_err(|e| Error::MusicBrainz(format!("Failed to create HTTP client: {e}")))?;
+            .map_err(|e| ApiError::Other(format!("Failed to create HTTP client: {e}")))?;
 
         Ok(Self {
             http,
-            last_request: Mutex::new(None),
+            limiter: RateLimiter::new(RATE_LIMIT),
         })
     }
 
-    async fn rate_limit(&self) {
-        let sleep_duration = {
-            let mut last = self.last_request.lock().await;
-            let duration = last.and_then(|last_time| {
-                let elapsed = last_time.elapsed();
-                RATE_LIMIT.checked_sub(elapsed)
-            });
-            *last = Some(Instant::now());
-            duration
-        };
-
-        if let Some(d) = sleep_duration {
-            tokio::time::sleep(d).await;
-        }
+    /// Send a GET request, logging it and the response status at trace level.
+    async fn get(&self, url: &str) -> Result<reqwest::Response> {
+        trace!("GET {url}");
+        let response = self.http.get(url).send().await.map_err(ApiError::from)?;
+        trace!(
+            "{} for {url} ({} bytes)",
+            response.status(),
+            response.content_length().map_or_else(|| "?".into(), |n| n.to_string())
+        );
+        Ok(response)
     }
+}
 
+impl MetadataSource for Client {
     /// Search for releases matching artist and album.
     ///
+    /// Search results always carry country, media formats, label info and
+    /// release group; the search endpoint does not accept `inc` parameters.
+    ///
     /// # Errors
     /// Returns an error if the API request fails.
-    pub async fn search_release(
+    async fn search_release(
         &self,
         artist: &str,
         album: &str,
         limit: u32,
     ) -> Result<Vec<Release>> {
-        self.rate_limit().await;
+        self.limiter.acquire().await;
 
-        let query = format!("artist:{artist} AND release:{album}");
+        let query = if artist.is_empty() {
+            format!("release:{album}")
+        } else {
+            format!("artist:{artist} AND release:{album}")
+        };
         let url = format!(
             "{API_BASE}/release?query={}&limit={limit}&fmt=json",
             urlencoding::encode(&query)
         );
 
-        let response = self
-            .http
-            .get(&url)
-            .send()
-            .await
-            .map_err(|e| Error::MusicBrainz(e.to_string()))?;
+        let response = self.get(&url).await?;
 
         if !response.status().is_success() {
-            return Err(Error::MusicBrainz(format!(
-                "API error: {}",
-                response.status()
-            )));
+            return Err(ApiError::Http(response.status()).into());
         }
 
-        let result: ReleaseSearchResult = response
-            .json()
-            .await
-            .map_err(|e| Error::MusicBrainz(e.to_string()))?;
+        let result: ReleaseSearchResult = response.json().await.map_err(ApiError::from)?;
+        trace!("{} releases found for {query}", result.releases.len());
 
         Ok(result.releases)
     }
@@ -152,65 +319,103 @@ impl Client {
     ///
     /// # Errors
     /// Returns an error if the API request fails.
-    pub async fn lookup_release(&self, mbid: &str) -> Result<Release> {
-        self.rate_limit().await;
+    async fn lookup_release(&self, mbid: &str) -> Result<Release> {
+        self.limiter.acquire().await;
 
-        let url = format!("{API_BASE}/release/{mbid}?inc=recordings+artist-credits&fmt=json");
+        let url = format!(
+            "{API_BASE}/release/{mbid}?inc=recordings+artist-credits+labels+release-groups&fmt=json"
+        );
 
-        let response = self
-            .http
-            .get(&url)
-            .send()
-            .await
-            .map_err(|e| Error::MusicBrainz(e.to_string()))?;
+        let response = self.get(&url).await?;
 
         if !response.status().is_success() {
-            return Err(Error::MusicBrainz(format!(
-                "API error: {}",
-                response.status()
-            )));
+            return Err(ApiError::Http(response.status()).into());
         }
 
-        response
-            .json()
-            .await
-            .map_err(|e| Error::MusicBrainz(e.to_string()))
+        Ok(response.json().await.map_err(ApiError::from)?)
+    }
+
+    /// Look up a recording by `MusicBrainz` ID.
+    ///
+    /// # Errors
+    /// Returns an error if the API request fails.
+    async fn lookup_recording(&self, mbid: &str) -> Result<Recording> {
+        self.limiter.acquire().await;
+
+        let url = format!("{API_BASE}/recording/{mbid}?fmt=json");
+
+        let response = self.get(&url).await?;
+
+        if !response.status().is_success() {
+            return Err(ApiError::Http(response.status()).into());
+        }
+
+        Ok(response.json().await.map_err(ApiError::from)?)
     }
 
     /// Fetch cover art for a release.
     ///
     /// # Errors
     /// Returns an error if the API request fails.
-    pub async fn fetch_cover_art(&self, mbid: &str) -> Result<Option<Vec<u8>>> {
-        self.rate_limit().await;
+    async fn fetch_cover_art(&self, mbid: &str) -> Result<Option<Vec<u8>>> {
+        self.limiter.acquire().await;
 
         let url = format!("https://coverartarchive.org/release/{mbid}/front");
 
-        let response = self
-            .http
-            .get(&url)
-            .send()
-            .await
-            .map_err(|e| Error::MusicBrainz(e.to_string()))?;
+        let response = self.get(&url).await?;
 
         if response.status().as_u16() == 404 {
             return Ok(None);
         }
 
         if !response.status().is_success() {
-            return Err(Error::MusicBrainz(format!(
-                "Cover art error: {}",
-                response.status()
-            )));
+            return Err(ApiError::Http(response.status()).into());
         }
 
-        let bytes = response
-            .bytes()
-            .await
-            .map_err(|e| Error::MusicBrainz(e.to_string()))?;
+        let bytes = response.bytes().await.map_err(ApiError::from)?;
 
         Ok(Some(bytes.to_vec()))
     }
+
+    /// Ask the API root for its headers, giving up after a few seconds.
+    ///
+    /// # Errors
+    /// Returns an error if the server can't be reached; any answer, even an
+    /// error status, means it can.
+    async fn check_reachable(&self) -> Result<()> {
+        self.limiter.acquire().await;
+
+        trace!("HEAD {API_BASE}");
+        self.http
+            .head(API_BASE)
+            .timeout(PROBE_TIMEOUT)
+            .send()
+            .await
+            .map_err(ApiError::from)?;
+        Ok(())
+    }
+}
+
+/// A source that knows no releases, for importing from file tags alone
+/// without touching the network.
+pub struct NullSource;
+
+impl MetadataSource for NullSource {
+    async fn search_release(&self, _: &str, _: &str, _: u32) -> Result<Vec<Release>> {
+        Ok(Vec::new())
+    }
+
+    async fn lookup_release(&self, mbid: &str) -> Result<Release> {
+        Err(ApiError::Other(format!("Release {mbid} not looked up (offline)")).into())
+    }
+
+    async fn lookup_recording(&self, mbid: &str) -> Result<Recording> {
+        Err(ApiError::Other(format!("Recording {mbid} not looked up (offline)")).into())
+    }
+
+    async fn fetch_cover_art(&self, _: &str) -> Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
 }
 
 impl Release {
@@ -222,6 +427,12 @@ impl Release {
         })
     }
 
+    /// The release's artists as they're sorted (see [`credit_sort_name`]).
+    #[must_use]
+    pub fn artist_sort_name(&self) -> Option<String> {
+        credit_sort_name(&self.artist_credit)
+    }
+
     #[must_use]
     pub fn year(&self) -> Option<i32> {
         self.date
@@ -230,8 +441,69 @@ impl Release {
             .and_then(|y| y.parse().ok())
     }
 
+    /// The release group's id, if the release came with one.
+    #[must_use]
+    pub fn release_group_id(&self) -> Option<&str> {
+        self.release_group.as_ref().map(|group| group.id.as_str())
+    }
+
     #[must_use]
     pub fn tracks(&self) -> Vec<&Track> {
         self.media.iter().flat_map(|m| &m.tracks).collect()
     }
+
+    /// [`Release::tracks`], each with the position of its medium.
+    #[must_use]
+    pub fn disc_tracks(&self) -> Vec<(u32, &Track)> {
+        self.media
+            .iter()
+            .flat_map(|medium| medium.tracks.iter().map(move |track| (medium.position, track)))
+            .collect()
+    }
+
+    /// Total number of tracks, falling back to reported counts when track
+    /// lists are absent (as in search results).
+    #[must_use]
+    pub fn track_count(&self) -> usize {
+        self.media
+            .iter()
+            .map(|m| {
+                if m.tracks.is_empty() {
+                    m.track_count
+                } else {
+                    m.tracks.len()
+                }
+            })
+            .sum()
+    }
+
+    #[must_use]
+    pub fn label_names(&self) -> Vec<&str> {
+        self.label_info
+            .iter()
+            .filter_map(|li| li.label.as_ref().map(|l| l.name.as_str()))
+            .collect()
+    }
+}
+
+impl Track {
+    /// The track's artists as they're sorted (see [`credit_sort_name`]).
+    #[must_use]
+    pub fn artist_sort_name(&self) -> Option<String> {
+        credit_sort_name(&self.artist_credit)
+    }
+}
+
+/// The sort names of `credits` joined like their names are, such as
+/// "Chemical Brothers, The feat. Gallagher, Noel"; `None` without credits or
+/// if one has no sort name.
+#[must_use]
+pub fn credit_sort_name(credits: &[ArtistCredit]) -> Option<String> {
+    if credits.is_empty() || credits.iter().any(|credit| credit.artist.sort_name.is_empty()) {
+        return None;
+    }
+    Some(credits.iter().fold(String::new(), |mut acc, credit| {
+        let _ = write!(acc, "{}{}", credit.artist.sort_name, credit.joinphrase);
+        acc
+    }))
 }
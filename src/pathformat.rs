@@ -4,16 +4,234 @@
 //!   `$field` - Variable substitution
 //!   `%func{arg}` - Function call
 //!
-//! Variables: albumartist, artist, album, year, track, title, disc, genre
-//! Functions: upper, lower, if, left, right
+//! Variables: albumartist, artist, album, year, track, title, disc, genre,
+//! and artist_sort and albumartist_sort, which are the artist and album
+//! artist where there's no sort name, and `attr_<key>` for the flexible
+//! attribute `key` of the item or else its album, empty when neither has it
+//! Functions: upper, lower, if, left, right, aunique
+//!
+//! [`PathFormats`] picks a template by query, so that classical music can be
+//! filed differently from everything else. Each directory and file name of
+//! the filled-in template is then rewritten by [`Replacements`].
+
+use std::path::{Path, PathBuf};
+
+use deunicode::deunicode_with_tofu;
+use regex::Regex;
+
+use crate::query::{matches_item, QueryTerm};
+use crate::{Album, Error, Item, Result};
+
+/// The rules of [`Replacements::default`]: characters that separate paths or
+/// aren't allowed in file names on some systems become `_`, and whitespace
+/// at either end of a name is removed.
+pub const DEFAULT_REPLACE: &[(&str, &str)] = &[
+    (r#"[\\/:*?"<>|\x00]"#, "_"),
+    (r"^\s+", ""),
+    (r"\s+$", ""),
+];
+
+/// Stands for a path separator inside a substituted value while the path is
+/// split into names, so only the template's own separators split it.
+const VALUE_SEPARATOR: &str = "\u{e000}";
+
+/// Regex rules rewriting each directory and file name of a path, compiled
+/// once and applied in order.
+#[derive(Debug, Clone)]
+pub struct Replacements {
+    rules: Vec<(Regex, String)>,
+    /// Whether non-ASCII characters are transliterated before the rules run.
+    asciify: bool,
+}
+
+impl Replacements {
+    /// Compile `rules`, each a pattern and what its matches are replaced
+    /// with (`$1` for a group, `$$` for a dollar sign).
+    ///
+    /// # Errors
+    /// Returns an error naming the pattern if one isn't a valid regex.
+    pub fn new<'r>(
+        rules: impl IntoIterator<Item = (&'r str, &'r str)>,
+        asciify: bool,
+    ) -> Result<Self> {
+        let rules = rules
+            .into_iter()
+            .map(|(pattern, replacement)| {
+                let regex = Regex::new(pattern).map_err(|e| {
+                    Error::PathFormat(format!("Invalid replacement pattern {pattern:?}: {e}"))
+                })?;
+                Ok((regex, replacement.to_string()))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { rules, asciify })
+    }
+
+    /// `name` with the rules applied. A path separator left in it, or put
+    /// there by a rule, becomes `_`, so one name never turns into several.
+    fn apply(&self, name: &str) -> String {
+        let mut name = name.replace(VALUE_SEPARATOR, "/");
+        if self.asciify {
+            name = deunicode_with_tofu(&name, "_");
+        }
+        for (pattern, replacement) in &self.rules {
+            name = pattern.replace_all(&name, replacement.as_str()).into_owned();
+        }
+        name.replace(std::path::is_separator, "_")
+    }
+
+    /// `path`, a filled-in template, with each of its names rewritten.
+    fn apply_to_path(&self, path: &str) -> String {
+        path.split('/').map(|name| self.apply(name)).collect::<Vec<_>>().join("/")
+    }
+}
+
+impl Default for Replacements {
+    fn default() -> Self {
+        // The default patterns are known to compile
+        let rules = DEFAULT_REPLACE
+            .iter()
+            .filter_map(|&(pattern, replacement)| {
+                Some((Regex::new(pattern).ok()?, replacement.to_string()))
+            })
+            .collect();
+        Self {
+            rules,
+            asciify: false,
+        }
+    }
+}
+
+/// The library as path formats see it: its albums, for `%aunique{}`, and
+/// flexible attributes, for `$attr_<key>`.
+pub trait Library {
+    /// Album `album_id` and every other album with the same name and album
+    /// artist.
+    ///
+    /// # Errors
+    /// Returns an error if the albums can't be read.
+    fn namesakes(&self, album_id: i64) -> Result<Vec<Album>>;
 
-use crate::{Error, Item, Result};
+    /// The flexible attribute `key` of `item`, or else of its album.
+    ///
+    /// # Errors
+    /// Returns an error if the attributes can't be read.
+    fn attribute(&self, item: &Item, key: &str) -> Result<Option<String>>;
+}
+
+/// Path templates chosen per item: the first conditional template whose
+/// query matches the item, or the default one.
+#[derive(Debug, Clone)]
+pub struct PathFormats {
+    /// Each query as written, parsed, and its template, in order.
+    conditional: Vec<(String, Vec<QueryTerm>, String)>,
+    default: String,
+    replacements: Replacements,
+}
+
+impl PathFormats {
+    /// Formats using `default` for every item.
+    #[must_use]
+    pub fn new(default: &str) -> Self {
+        Self {
+            conditional: Vec::new(),
+            default: default.to_string(),
+            replacements: Replacements::default(),
+        }
+    }
+
+    /// Rewrite the names of filled-in paths with `replacements` rather than
+    /// the default ones.
+    #[must_use]
+    pub fn with_replacements(mut self, replacements: Replacements) -> Self {
+        self.replacements = replacements;
+        self
+    }
+
+    /// Use `template` for items matching `query` that no earlier query
+    /// matched.
+    ///
+    /// # Errors
+    /// Returns an error if `query` can't be parsed.
+    pub fn with(mut self, query: &str, template: &str) -> Result<Self> {
+        let terms = crate::query::parse(query)?;
+        self.conditional
+            .push((query.to_string(), terms, template.to_string()));
+        Ok(self)
+    }
+
+    /// The template for `item`, whose attributes the queries find in
+    /// `library`.
+    ///
+    /// # Errors
+    /// Returns an error if a query names an unknown field or has an invalid
+    /// pattern, or `library` can't be read.
+    pub fn template(&self, item: &Item, library: Option<&dyn Library>) -> Result<&str> {
+        for (query, terms, template) in &self.conditional {
+            let matched = matches_item(terms, item, library)
+                .map_err(|e| Error::Config(format!("Path format query \"{query}\": {e}")))?;
+            if matched {
+                return Ok(template);
+            }
+        }
+        Ok(&self.default)
+    }
+
+    /// Where `item` belongs under `library_dir`, by its template, with
+    /// `%aunique{}` telling its album apart from the others in `library`
+    /// and attributes read from there.
+    ///
+    /// # Errors
+    /// Returns an error if choosing or filling in the template fails.
+    pub fn destination(
+        &self,
+        library_dir: &Path,
+        item: &Item,
+        library: Option<&dyn Library>,
+    ) -> Result<PathBuf> {
+        destination(library_dir, self.template(item, library)?, item, &self.replacements, library)
+    }
+}
 
-/// Format a path template with item metadata.
+/// Where `item` belongs under `library_dir` according to `template`, keeping
+/// its file extension.
 ///
 /// # Errors
 /// Returns an error if the template contains unknown variables or functions.
-pub fn format_path(template: &str, item: &Item) -> Result<String> {
+pub fn destination(
+    library_dir: &Path,
+    template: &str,
+    item: &Item,
+    replacements: &Replacements,
+    library: Option<&dyn Library>,
+) -> Result<PathBuf> {
+    let relative = format_path(template, item, replacements, library)?;
+    let ext = item
+        .path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("mp3");
+    Ok(library_dir.join(format!("{relative}.{ext}")))
+}
+
+/// Format a path template with item metadata, rewriting each name in it
+/// with `replacements`. Without `library`, `%aunique{}` and `$attr_<key>`
+/// are always empty.
+///
+/// # Errors
+/// Returns an error if the template contains unknown variables or functions,
+/// or `library` can't be read.
+pub fn format_path(
+    template: &str,
+    item: &Item,
+    replacements: &Replacements,
+    library: Option<&dyn Library>,
+) -> Result<String> {
+    Ok(replacements.apply_to_path(&expand(template, item, library)?))
+}
+
+/// `template` filled in, with path separators in the values it substitutes
+/// marked as [`VALUE_SEPARATOR`].
+fn expand(template: &str, item: &Item, library: Option<&dyn Library>) -> Result<String> {
     let mut result = String::new();
     let mut chars = template.chars().peekable();
 
@@ -21,16 +239,16 @@ pub fn format_path(template: &str, item: &Item) -> Result<String> {
         match c {
             '$' => {
                 let var = collect_identifier(&mut chars);
-                let value = get_variable(&var, item)?;
-                result.push_str(&sanitize(&value));
+                let value = get_variable(&var, item, library)?;
+                result.push_str(&protect(&value));
             }
             '%' => {
                 let func = collect_identifier(&mut chars);
                 if chars.peek() == Some(&'{') {
                     chars.next();
                     let arg = collect_until_close(&mut chars);
-                    let value = apply_function(&func, &arg, item)?;
-                    result.push_str(&sanitize(&value));
+                    let value = apply_function(&func, &arg, item, library)?;
+                    result.push_str(&protect(&value));
                 } else {
                     return Err(Error::PathFormat(format!("Expected '{{' after %{func}")));
                 }
@@ -76,12 +294,24 @@ fn collect_until_close(chars: &mut std::iter::Peekable<std::str::Chars<'_>>) ->
     content
 }
 
-fn get_variable(name: &str, item: &Item) -> Result<String> {
+fn get_variable(name: &str, item: &Item, library: Option<&dyn Library>) -> Result<String> {
+    if let Some(key) = name.strip_prefix("attr_") {
+        return Ok(match library {
+            Some(library) => library.attribute(item, key)?.unwrap_or_default(),
+            None => String::new(),
+        });
+    }
     Ok(match name {
         "title" => item.title.clone(),
         "artist" => item.artist.clone(),
         "album" => item.album.clone(),
         "albumartist" => item.effective_albumartist().to_string(),
+        "artist_sort" => item.artist_sort.as_deref().unwrap_or(&item.artist).to_string(),
+        "albumartist_sort" => item
+            .albumartist_sort
+            .as_deref()
+            .unwrap_or_else(|| item.effective_albumartist())
+            .to_string(),
         "genre" => item.genre.clone().unwrap_or_default(),
         "year" => item.year.map_or_else(String::new, |y| y.to_string()),
         "track" => item.track.map_or_else(String::new, |t| format!("{t:02}")),
@@ -90,8 +320,13 @@ fn get_variable(name: &str, item: &Item) -> Result<String> {
     })
 }
 
-fn apply_function(func: &str, arg: &str, item: &Item) -> Result<String> {
-    let expanded = format_path(arg, item)?;
+fn apply_function(
+    func: &str,
+    arg: &str,
+    item: &Item,
+    library: Option<&dyn Library>,
+) -> Result<String> {
+    let expanded = expand(arg, item, library)?;
 
     Ok(match func {
         "upper" => expanded.to_uppercase(),
@@ -102,7 +337,7 @@ fn apply_function(func: &str, arg: &str, item: &Item) -> Result<String> {
                 let n: usize = n
                     .parse()
                     .map_err(|e| Error::PathFormat(format!("Invalid number: {e}")))?;
-                let val = format_path(rest.trim(), item)?;
+                let val = expand(rest.trim(), item, library)?;
                 val.chars().take(n).collect()
             } else {
                 expanded
@@ -113,7 +348,7 @@ fn apply_function(func: &str, arg: &str, item: &Item) -> Result<String> {
                 let n: usize = n
                     .parse()
                     .map_err(|e| Error::PathFormat(format!("Invalid number: {e}")))?;
-                let val = format_path(rest.trim(), item)?;
+                let val = expand(rest.trim(), item, library)?;
                 let len = val.chars().count();
                 val.chars().skip(len.saturating_sub(n)).collect()
             } else {
@@ -123,11 +358,11 @@ fn apply_function(func: &str, arg: &str, item: &Item) -> Result<String> {
         "if" => {
             let parts: Vec<&str> = arg.splitn(3, ',').collect();
             if parts.len() >= 2 {
-                let condition = format_path(parts[0].trim(), item)?;
+                let condition = expand(parts[0].trim(), item, library)?;
                 if !condition.is_empty() {
-                    format_path(parts[1].trim(), item)?
+                    expand(parts[1].trim(), item, library)?
                 } else if parts.len() == 3 {
-                    format_path(parts[2].trim(), item)?
+                    expand(parts[2].trim(), item, library)?
                 } else {
                     String::new()
                 }
@@ -135,10 +370,39 @@ fn apply_function(func: &str, arg: &str, item: &Item) -> Result<String> {
                 expanded
             }
         }
+        "aunique" => aunique(item, library)?,
         _ => return Err(Error::PathFormat(format!("Unknown function: {func}"))),
     })
 }
 
+/// What `%aunique{}` gives `item`: nothing unless another album has the
+/// name and album artist of its album, otherwise the first of its year, its
+/// `MusicBrainz` disambiguation and its id that none of those albums share,
+/// in parentheses, such as " (2011)" or " (Deluxe Edition)".
+fn aunique(item: &Item, library: Option<&dyn Library>) -> Result<String> {
+    let (Some(album_id), Some(library)) = (item.album_id, library) else {
+        return Ok(String::new());
+    };
+    let namesakes = library.namesakes(album_id)?;
+    let (ours, others): (Vec<&Album>, Vec<&Album>) =
+        namesakes.iter().partition(|album| album.id == Some(album_id));
+    let Some(album) = ours.first() else {
+        return Ok(String::new());
+    };
+    if others.is_empty() {
+        return Ok(String::new());
+    }
+    let year = album
+        .year
+        .filter(|year| others.iter().all(|other| other.year != Some(*year)))
+        .map(|year| year.to_string());
+    let disambiguation = album.disambiguation.clone().filter(|disambiguation| {
+        others.iter().all(|other| other.disambiguation.as_ref() != Some(disambiguation))
+    });
+    let key = year.or(disambiguation).unwrap_or_else(|| album_id.to_string());
+    Ok(format!(" ({key})"))
+}
+
 fn to_title_case(s: &str) -> String {
     s.split_whitespace()
         .map(|word| {
@@ -153,56 +417,235 @@ fn to_title_case(s: &str) -> String {
         .join(" ")
 }
 
-fn sanitize(s: &str) -> String {
-    s.chars()
-        .map(|c| match c {
-            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' | '\0' => '_',
-            _ => c,
-        })
-        .collect::<String>()
-        .trim()
-        .to_string()
+/// `value` with path separators marked, to be restored once the path is split.
+fn protect(value: &str) -> String {
+    value.replace(std::path::is_separator, VALUE_SEPARATOR)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use chrono::Utc;
+    use crate::testutil;
 
     fn test_item() -> Item {
         Item {
-            id: None,
-            album_id: None,
             path: "/test.mp3".into(),
             title: "Help!".into(),
             artist: "The Beatles".into(),
             album: "Help!".into(),
-            albumartist: None,
             genre: Some("Rock".into()),
             year: Some(1965),
             track: Some(1),
             disc: Some(1),
-            format: crate::AudioFormat::Mp3,
             bitrate: 320,
             length: 180.0,
-            mb_trackid: None,
-            mb_albumid: None,
-            added: Utc::now(),
-            mtime: Utc::now(),
+            ..testutil::item()
         }
     }
 
     #[test]
     fn test_simple_template() {
         let item = test_item();
-        let result = format_path("$artist/$album/$track - $title", &item).unwrap();
+        let result =
+            format_path("$artist/$album/$track - $title", &item, &Replacements::default(), None);
+        let result = result.unwrap();
         assert_eq!(result, "The Beatles/Help!/01 - Help!");
     }
 
+    #[test]
+    fn test_sort_names_fall_back_to_names() {
+        let template = "$albumartist_sort/$artist_sort - $title";
+        let path = format_path(template, &test_item(), &Replacements::default(), None).unwrap();
+        assert_eq!(path, "The Beatles/The Beatles - Help!");
+        let item = Item {
+            artist_sort: Some("Beatles, The".into()),
+            albumartist_sort: Some("Beatles, The".into()),
+            ..test_item()
+        };
+        let path = format_path(template, &item, &Replacements::default(), None).unwrap();
+        assert_eq!(path, "Beatles, The/Beatles, The - Help!");
+    }
+
     #[test]
     fn test_functions() {
         let item = test_item();
-        let result = format_path("%upper{$artist}", &item).unwrap();
-        assert_eq!(result, "THE BEATLES");
+        let result = format_path("%upper{$artist}", &item, &Replacements::default(), None);
+        assert_eq!(result.unwrap(), "THE BEATLES");
+    }
+
+    /// Albums by id, all of the same name and album artist.
+    struct Namesakes(Vec<Album>);
+
+    impl Library for Namesakes {
+        fn namesakes(&self, _album_id: i64) -> Result<Vec<Album>> {
+            Ok(self.0.clone())
+        }
+
+        fn attribute(&self, _item: &Item, _key: &str) -> Result<Option<String>> {
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn test_aunique_tells_namesake_albums_apart() {
+        let album = |id, year: Option<i32>, disambiguation: Option<&str>| Album {
+            id: Some(id),
+            album: "Greatest Hits".into(),
+            albumartist: "Queen".into(),
+            year,
+            disambiguation: disambiguation.map(Into::into),
+            ..testutil::album()
+        };
+        let albums = Namesakes(vec![
+            album(1, Some(1981), None),
+            album(2, Some(2011), None),
+            album(3, Some(2011), Some("Deluxe Edition")),
+            album(4, Some(2011), None),
+        ]);
+        let template = "$albumartist/$album%aunique{}/$title";
+        let path = |album_id| {
+            let item = Item {
+                album_id: Some(album_id),
+                ..test_item()
+            };
+            format_path(template, &item, &Replacements::default(), Some(&albums)).unwrap()
+        };
+        assert_eq!(path(1), "The Beatles/Help! (1981)/Help!");
+        assert_eq!(path(3), "The Beatles/Help! (Deluxe Edition)/Help!");
+        // Nothing but the id is its own
+        assert_eq!(path(2), "The Beatles/Help! (2)/Help!");
+
+        // Alone, without an album, or without the library: nothing
+        let alone = Namesakes(vec![album(1, Some(1981), None)]);
+        let item = Item {
+            album_id: Some(1),
+            ..test_item()
+        };
+        let unique = |item: &Item, library: Option<&dyn Library>| {
+            format_path(template, item, &Replacements::default(), library).unwrap()
+        };
+        assert_eq!(unique(&item, Some(&alone)), "The Beatles/Help!/Help!");
+        assert_eq!(unique(&test_item(), Some(&albums)), "The Beatles/Help!/Help!");
+        assert_eq!(unique(&item, None), "The Beatles/Help!/Help!");
+    }
+
+    /// One flexible attribute, `source`, of every item.
+    struct Source(&'static str);
+
+    impl Library for Source {
+        fn namesakes(&self, _album_id: i64) -> Result<Vec<Album>> {
+            Ok(Vec::new())
+        }
+
+        fn attribute(&self, _item: &Item, key: &str) -> Result<Option<String>> {
+            Ok((key == "source").then(|| self.0.to_string()))
+        }
+    }
+
+    #[test]
+    fn test_attribute_variables() {
+        let item = test_item();
+        let template = "$albumartist/%if{$attr_source,$attr_source/}$album/$attr_mood$title";
+        let path = |library: Option<&dyn Library>| {
+            format_path(template, &item, &Replacements::default(), library).unwrap()
+        };
+        assert_eq!(path(Some(&Source("vinyl rip"))), "The Beatles/vinyl rip/Help!/Help!");
+        // A separator in an attribute doesn't make a directory
+        assert_eq!(path(Some(&Source("LP/2"))), "The Beatles/LP_2/Help!/Help!");
+        // Unset, or without the library: empty
+        assert_eq!(path(None), "The Beatles/Help!/Help!");
+        // Path format queries see them too
+        let formats = PathFormats::new("$title").with("attr.source:vinyl", "Vinyl/$title").unwrap();
+        assert_eq!(formats.template(&item, Some(&Source("vinyl rip"))).unwrap(), "Vinyl/$title");
+        assert_eq!(formats.template(&item, None).unwrap(), "$title");
+    }
+
+    #[test]
+    fn test_default_replacements() {
+        let item = Item {
+            artist: "AC/DC".into(),
+            album: "Live: At Donington ".into(),
+            title: "Who Made Who?".into(),
+            ..test_item()
+        };
+        let path = format_path("$artist/$album/$title", &item, &Replacements::default(), None);
+        assert_eq!(path.unwrap(), "AC_DC/Live_ At Donington/Who Made Who_");
+        assert_eq!(Replacements::default().rules.len(), DEFAULT_REPLACE.len());
+    }
+
+    #[test]
+    fn test_replacement_order_matters() {
+        let item = Item {
+            artist: "AC/DC".into(),
+            ..test_item()
+        };
+        let path = |rules: &[(&str, &str)]| {
+            let replacements = Replacements::new(rules.iter().copied(), false).unwrap();
+            format_path("$artist", &item, &replacements, None).unwrap()
+        };
+        assert_eq!(path(&[("/", "-"), ("-", " ")]), "AC DC");
+        assert_eq!(path(&[("-", " "), ("/", "-")]), "AC-DC");
+        assert!(Replacements::new([("(", "")], false).is_err());
+    }
+
+    #[test]
+    fn test_replacements_cannot_add_separators() {
+        let item = Item {
+            artist: "Simon & Garfunkel".into(),
+            album: "AC/DC".into(),
+            ..test_item()
+        };
+        let replacements = Replacements::new([("&", "/"), ("^The ", "")], false).unwrap();
+        let path = format_path("$artist/$album/$title", &item, &replacements, None).unwrap();
+        // Separators from values and from rules stay within their name
+        assert_eq!(path, "Simon _ Garfunkel/AC_DC/Help!");
+        // Rules see each name whole, with the template's text around values
+        let path = format_path("$albumartist - $title", &test_item(), &replacements, None);
+        assert_eq!(path.unwrap(), "Beatles - Help!");
+    }
+
+    #[test]
+    fn test_asciify() {
+        let item = Item {
+            artist: "Sigur Rós".into(),
+            album: "Ágætis byrjun".into(),
+            ..test_item()
+        };
+        let replacements = Replacements::new(DEFAULT_REPLACE.iter().copied(), true).unwrap();
+        let path = format_path("$artist/$album", &item, &replacements, None).unwrap();
+        assert_eq!(path, "Sigur Ros/Agaetis byrjun");
+    }
+
+    #[test]
+    fn test_first_matching_format_wins() {
+        let formats = PathFormats::new("$albumartist/$album/$track $title")
+            .with("genre:classical", "Classical/$album/$disc-$track $title")
+            .unwrap()
+            .with("genre:rock year:..1969", "Oldies/$artist - $title")
+            .unwrap()
+            .with("genre:rock", "Rock/$artist/$title")
+            .unwrap();
+        let mut item = test_item();
+        assert_eq!(formats.template(&item, None).unwrap(), "Oldies/$artist - $title");
+        item.year = Some(1999);
+        assert_eq!(formats.template(&item, None).unwrap(), "Rock/$artist/$title");
+        item.genre = Some("Classical".into());
+        let dest = formats.destination(Path::new("/music"), &item, None).unwrap();
+        assert_eq!(dest, Path::new("/music/Classical/Help!/1-01 Help!.mp3"));
+    }
+
+    #[test]
+    fn test_default_format_when_nothing_matches() {
+        let formats = PathFormats::new("$artist/$title")
+            .with("genre:jazz", "Jazz/$title")
+            .unwrap();
+        let item = test_item();
+        assert_eq!(formats.template(&item, None).unwrap(), "$artist/$title");
+        assert_eq!(PathFormats::new("$title").template(&item, None).unwrap(), "$title");
+
+        let formats = PathFormats::new("$title").with("bogus:x", "$album").unwrap();
+        let err = formats.template(&item, None).unwrap_err().to_string();
+        assert!(err.contains("bogus:x"), "{err}");
+        assert!(PathFormats::new("$title").with(")", "$album").is_err());
     }
 }
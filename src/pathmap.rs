@@ -0,0 +1,112 @@
+//! Stored paths on machines that mount the library elsewhere
+//!
+//! The database keeps one canonical form of every path, such as
+//! `/mnt/music/...` as seen from the NAS's Linux clients. Each machine's
+//! `library.path_mappings` maps the start of those paths to where that
+//! machine mounts them, such as `/Volumes/music` on a Mac: paths read from
+//! the database are mapped to this machine's, and its paths mapped back
+//! before they are stored or looked up.
+//!
+//! A portable library goes further and stores paths relative to its root,
+//! wherever the drive it's on is mounted.
+
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+
+/// Pairs of a stored path prefix and where that prefix is on this machine.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PathMappings {
+    mappings: Vec<(PathBuf, PathBuf)>,
+}
+
+impl PathMappings {
+    /// Mappings of each stored `prefix` to its `replacement`, the first
+    /// matching one applying to a path.
+    #[must_use]
+    pub fn new(mappings: impl IntoIterator<Item = (PathBuf, PathBuf)>) -> Self {
+        Self {
+            mappings: mappings.into_iter().collect(),
+        }
+    }
+
+    /// Paths under `root` stored relative to it, for a portable library.
+    /// Relative stored paths are taken as under `root`; paths elsewhere are
+    /// stored whole.
+    #[must_use]
+    pub fn relative_to(root: PathBuf) -> Self {
+        // Every path starts with the empty path, and joining an absolute
+        // path to `root` leaves it as it is
+        Self::new([(PathBuf::new(), root)])
+    }
+
+    /// Whether there are no mappings, so paths are stored as they are.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.mappings.is_empty()
+    }
+
+    /// Where the stored `path` is on this machine.
+    #[must_use]
+    pub fn local(&self, path: PathBuf) -> PathBuf {
+        let mapped = self.mappings.iter().find_map(|(prefix, replacement)| {
+            path.strip_prefix(prefix).ok().map(|rest| join(replacement, rest))
+        });
+        mapped.unwrap_or(path)
+    }
+
+    /// How this machine's `path` is stored.
+    #[must_use]
+    pub fn stored<'a>(&self, path: &'a Path) -> Cow<'a, Path> {
+        let mapped = self.mappings.iter().find_map(|(prefix, replacement)| {
+            path.strip_prefix(replacement).ok().map(|rest| join(prefix, rest))
+        });
+        mapped.map_or(Cow::Borrowed(path), Cow::Owned)
+    }
+}
+
+/// `base` followed by `rest`, without a trailing separator when `rest` is
+/// empty.
+fn join(base: &Path, rest: &Path) -> PathBuf {
+    if rest.as_os_str().is_empty() {
+        base.to_path_buf()
+    } else {
+        base.join(rest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_paths_map_both_ways_by_whole_components() {
+        let mappings = PathMappings::new([
+            ("/mnt/music".into(), "/Volumes/music".into()),
+            ("/mnt".into(), "/Volumes/other".into()),
+        ]);
+        let stored = Path::new("/mnt/music/Can/Tago Mago/01.flac");
+        let local = mappings.local(stored.to_path_buf());
+        assert_eq!(local, Path::new("/Volumes/music/Can/Tago Mago/01.flac"));
+        assert_eq!(mappings.stored(&local), stored);
+        assert_eq!(mappings.local("/mnt/music".into()), Path::new("/Volumes/music"));
+
+        // The first mapping that applies wins; partial names don't match
+        assert_eq!(mappings.local("/mnt/musical".into()), Path::new("/Volumes/other/musical"));
+        assert_eq!(mappings.local("/srv/music".into()), Path::new("/srv/music"));
+        assert!(matches!(mappings.stored(Path::new("/home/me/x.mp3")), Cow::Borrowed(_)));
+        assert!(PathMappings::default().is_empty());
+    }
+
+    #[test]
+    fn test_portable_paths_are_relative_to_the_root() {
+        let mappings = PathMappings::relative_to("/media/drive".into());
+        let local = Path::new("/media/drive/Can/Tago Mago/01.flac");
+        assert_eq!(mappings.stored(local), Path::new("Can/Tago Mago/01.flac"));
+        assert_eq!(mappings.local("Can/Tago Mago/01.flac".into()), local);
+
+        // Paths off the drive, such as where an import came from, stay whole
+        let source = Path::new("/home/me/Downloads/01.flac");
+        assert_eq!(mappings.stored(source), source);
+        assert_eq!(mappings.local(source.to_path_buf()), source);
+    }
+}
@@ -0,0 +1,175 @@
+//! Playlists of query results
+//!
+//! Playlists are extended M3U: an `#EXTINF` line with each track's length
+//! and "artist - title", then its path, absolute or relative to a directory
+//! (such as the root of a player being synced). Lines are UTF-8, as `.m3u8`
+//! requires; a path that isn't valid UTF-8 is written as its raw bytes so
+//! it still names the file, and reported so the caller can warn.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+
+use crate::{Item, Result};
+
+/// Write `items` to `out` as an extended M3U playlist, in order.
+///
+/// With `relative_to`, paths are written relative to that directory (both
+/// must be absolute); otherwise as stored. Returns the paths that aren't
+/// valid UTF-8.
+///
+/// # Errors
+/// Returns an error if writing to `out` fails.
+pub fn write(
+    out: &mut impl Write,
+    items: &[Item],
+    relative_to: Option<&Path>,
+) -> Result<Vec<PathBuf>> {
+    let mut not_utf8 = Vec::new();
+    writeln!(out, "#EXTM3U")?;
+    for item in items {
+        writeln!(
+            out,
+            "#EXTINF:{},{} - {}",
+            item.length.round() as i64,
+            item.artist,
+            item.title
+        )?;
+        let path =
+            relative_to.map_or_else(|| item.path.clone(), |base| relative(&item.path, base));
+        if path.to_str().is_none() {
+            not_utf8.push(item.path.clone());
+        }
+        write_path(out, &path)?;
+        writeln!(out)?;
+    }
+    Ok(not_utf8)
+}
+
+#[cfg(unix)]
+fn write_path(out: &mut impl Write, path: &Path) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    out.write_all(path.as_os_str().as_bytes())
+}
+
+#[cfg(not(unix))]
+fn write_path(out: &mut impl Write, path: &Path) -> std::io::Result<()> {
+    out.write_all(path.to_string_lossy().as_bytes())
+}
+
+/// `path` relative to the directory `base`, going up with `..` as needed.
+/// Paths with nothing in common (such as on another drive) stay as they are.
+fn relative(path: &Path, base: &Path) -> PathBuf {
+    let path: Vec<Component<'_>> = path.components().collect();
+    let base: Vec<Component<'_>> = base.components().collect();
+    let common = path.iter().zip(&base).take_while(|(a, b)| a == b).count();
+    if common == 0 {
+        return path.iter().collect();
+    }
+    let mut relative: PathBuf = base[common..].iter().map(|_| Component::ParentDir).collect();
+    relative.extend(&path[common..]);
+    relative
+}
+
+/// Shuffle `items` in place; the same seed gives the same order.
+pub fn shuffle<T>(items: &mut [T], seed: u64) {
+    let mut state = seed;
+    for i in (1..items.len()).rev() {
+        // splitmix64
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        items.swap(i, (z % (i as u64 + 1)) as usize);
+    }
+}
+
+/// A seed for [`shuffle`] that differs between runs.
+#[must_use]
+pub fn random_seed() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+    use crate::AudioFormat;
+
+    fn item(path: &str, title: &str, length: f64) -> Item {
+        Item {
+            path: path.into(),
+            title: title.into(),
+            artist: "Brian Eno".into(),
+            album: "Ambient 1".into(),
+            genre: Some("Ambient".into()),
+            year: Some(1978),
+            format: AudioFormat::Flac,
+            bitrate: 900,
+            length,
+            ..testutil::item()
+        }
+    }
+
+    fn playlist(items: &[Item], relative_to: Option<&Path>) -> String {
+        let mut out = Vec::new();
+        assert!(write(&mut out, items, relative_to).unwrap().is_empty());
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_extended_m3u() {
+        let items = [
+            item("/music/Brian Eno/Ambient 1/1-1.flac", "1/1", 1040.6),
+            item("/music/Brian Eno/Ambient 1/2-1.flac", "2/1", 535.0),
+        ];
+        assert_eq!(
+            playlist(&items, None),
+            "#EXTM3U\n\
+             #EXTINF:1041,Brian Eno - 1/1\n/music/Brian Eno/Ambient 1/1-1.flac\n\
+             #EXTINF:535,Brian Eno - 2/1\n/music/Brian Eno/Ambient 1/2-1.flac\n"
+        );
+        assert_eq!(playlist(&[], None), "#EXTM3U\n");
+    }
+
+    #[test]
+    fn test_relative_paths() {
+        let items = [item("/music/Brian Eno/Ambient 1/1-1.flac", "1/1", 1040.0)];
+        let path = |base: &str| {
+            let text = playlist(&items, Some(Path::new(base)));
+            text.lines().nth(2).unwrap().to_string()
+        };
+        assert_eq!(path("/music"), "Brian Eno/Ambient 1/1-1.flac");
+        assert_eq!(path("/music/playlists"), "../Brian Eno/Ambient 1/1-1.flac");
+        assert_eq!(path("/media/player"), "../../music/Brian Eno/Ambient 1/1-1.flac");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8_path_is_kept_and_reported() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let mut latin1 = item("", "Caf\u{e9}", 60.0);
+        latin1.path = PathBuf::from(OsStr::from_bytes(b"/music/caf\xe9.flac"));
+        let mut out = Vec::new();
+        let not_utf8 = write(&mut out, &[latin1], None).unwrap();
+        assert_eq!(not_utf8.len(), 1);
+        assert!(out.ends_with(b"\n/music/caf\xe9.flac\n"));
+    }
+
+    #[test]
+    fn test_shuffle_is_a_seeded_permutation() {
+        let mut a: Vec<u32> = (0..50).collect();
+        let mut b = a.clone();
+        shuffle(&mut a, 7);
+        shuffle(&mut b, 7);
+        assert_eq!(a, b);
+        assert_ne!(a, (0..50).collect::<Vec<_>>());
+        a.sort_unstable();
+        assert_eq!(a, (0..50).collect::<Vec<_>>());
+    }
+}
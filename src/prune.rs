@@ -0,0 +1,223 @@
+//! Removing directories that moved or deleted files leave behind
+//!
+//! A directory counts as empty when all it holds is clutter: files whose
+//! names match one of the configured patterns (see [`DEFAULT_CLUTTER`]),
+//! such as rip logs and playlists that mean nothing once the audio is gone.
+//! Any other file keeps its directory, and with it every directory above.
+
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use log::{debug, warn};
+
+/// Name patterns of the files a directory may hold and still be removed;
+/// `*` stands for any run of characters and `?` for any one.
+pub const DEFAULT_CLUTTER: &[&str] = &["*.log", "*.cue", "Thumbs.db", "*.m3u"];
+
+/// Removes directories holding only clutter below a root directory, which
+/// is never removed itself.
+#[derive(Debug, Clone)]
+pub struct Pruner {
+    root: PathBuf,
+    /// Lowercased, as names match them regardless of case.
+    clutter: Vec<String>,
+}
+
+impl Pruner {
+    #[must_use]
+    pub fn new(root: impl Into<PathBuf>, clutter: &[String]) -> Self {
+        Self {
+            root: root.into(),
+            clutter: clutter.iter().map(|pattern| pattern.to_lowercase()).collect(),
+        }
+    }
+
+    /// Whether a file named `name` is clutter.
+    #[must_use]
+    pub fn is_clutter(&self, name: &str) -> bool {
+        let name = name.to_lowercase();
+        self.clutter.iter().any(|pattern| wildcard_match(pattern, &name))
+    }
+
+    /// Remove each of `dirs` holding nothing but clutter, and then each
+    /// directory above it that is left the same way, stopping below the
+    /// root. Directories outside the root are left alone, and ones that
+    /// can't be removed are reported. Returns the directories removed.
+    pub fn prune(&self, dirs: impl IntoIterator<Item = PathBuf>) -> Vec<PathBuf> {
+        let mut dirs: Vec<PathBuf> = dirs.into_iter().collect();
+        // Deepest first, so a directory's emptied subdirectories are gone by
+        // the time it is looked at
+        dirs.sort_by(|a, b| {
+            b.components().count().cmp(&a.components().count()).then_with(|| a.cmp(b))
+        });
+        dirs.dedup();
+
+        let mut removed = Vec::new();
+        for dir in &dirs {
+            let mut dir = dir.as_path();
+            while dir != self.root && dir.starts_with(&self.root) {
+                match self.remove_if_empty(dir) {
+                    Ok(true) => {
+                        debug!("Removed empty directory {}", dir.display());
+                        removed.push(dir.to_path_buf());
+                    }
+                    Ok(false) => break,
+                    Err(e) => {
+                        warn!("Could not remove {}: {e}", dir.display());
+                        break;
+                    }
+                }
+                let Some(parent) = dir.parent() else {
+                    break;
+                };
+                dir = parent;
+            }
+        }
+        removed
+    }
+
+    /// Remove `dir` with its clutter if that is all it holds, returning
+    /// whether it was removed. One already gone is left at that.
+    fn remove_if_empty(&self, dir: &Path) -> std::io::Result<bool> {
+        match self.only_clutter(dir) {
+            Ok(true) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+            other => return other,
+        }
+        self.clear(dir)?;
+        Ok(true)
+    }
+
+    /// Whether everything in `dir`, down through its subdirectories, is
+    /// clutter. A symlink is judged by its name, like a file.
+    fn only_clutter(&self, dir: &Path) -> std::io::Result<bool> {
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let only_clutter = if entry.file_type()?.is_dir() {
+                self.only_clutter(&entry.path())?
+            } else {
+                entry.file_name().to_str().is_some_and(|name| self.is_clutter(name))
+            };
+            if !only_clutter {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Delete the clutter in `dir` and its subdirectories, then the
+    /// directories themselves. A file that appeared since `dir` was looked
+    /// at is left, and removing its directory fails.
+    fn clear(&self, dir: &Path) -> std::io::Result<()> {
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                self.clear(&entry.path())?;
+            } else if entry.file_name().to_str().is_some_and(|name| self.is_clutter(name)) {
+                std::fs::remove_file(entry.path())?;
+            }
+        }
+        std::fs::remove_dir(dir)
+    }
+}
+
+/// Whether `name` matches `pattern`, where `*` stands for any run of
+/// characters and `?` for any one.
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Where the last `*` was, and how much of the name it has taken so far
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    star = Some((star_p, star_n + 1));
+                    p = star_p + 1;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pruner(root: &Path) -> Pruner {
+        let clutter: Vec<String> = DEFAULT_CLUTTER.iter().map(|&p| p.into()).collect();
+        Pruner::new(root, &clutter)
+    }
+
+    fn touch(path: &Path) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, b"").unwrap();
+    }
+
+    #[test]
+    fn test_clutter_patterns() {
+        let pruner = pruner(Path::new("/music"));
+        assert!(pruner.is_clutter("rip.log"));
+        assert!(pruner.is_clutter("Paranoid.CUE"));
+        assert!(pruner.is_clutter("thumbs.db"));
+        assert!(!pruner.is_clutter("cover.jpg"));
+        assert!(!pruner.is_clutter("rip.log.bak"));
+        assert!(wildcard_match("cd?.m3u", "cd1.m3u"));
+        assert!(wildcard_match("*a*b", "xaxab"));
+        assert!(!wildcard_match("cd?.m3u", "cd.m3u"));
+    }
+
+    #[test]
+    fn test_prune_nested_directories() {
+        let root = std::env::temp_dir().join(format!("rsbts-prune-{}", std::process::id()));
+        let album = root.join("Black Sabbath/Paranoid");
+        touch(&album.join("CD1/rip.log"));
+        touch(&album.join("CD2/Thumbs.db"));
+        touch(&album.join("Paranoid.cue"));
+        // A neighbour with a file that isn't clutter
+        touch(&root.join("Black Sabbath/Master of Reality/cover.jpg"));
+
+        let removed = pruner(&root).prune([album.join("CD1"), album.join("CD2")]);
+        let artist_left = root.join("Black Sabbath").exists();
+        let root_left = root.exists();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(removed, [album.join("CD1"), album]);
+        assert!(artist_left && root_left);
+    }
+
+    #[test]
+    fn test_prune_keeps_unknown_files_and_root() {
+        let root = std::env::temp_dir().join(format!("rsbts-prune-keep-{}", std::process::id()));
+        let album = root.join("Paranoid");
+        touch(&album.join("rip.log"));
+        touch(&album.join("scans/booklet.pdf"));
+        touch(&root.join("incoming.m3u"));
+        let outside = std::env::temp_dir().join(format!("rsbts-outside-{}", std::process::id()));
+        std::fs::create_dir_all(&outside).unwrap();
+
+        let pruner = pruner(&root);
+        let kept = pruner.prune([album.clone(), outside.clone()]);
+        std::fs::remove_file(album.join("scans/booklet.pdf")).unwrap();
+        let removed = pruner.prune([album.join("scans"), root.clone()]);
+        let left = (album.exists(), root.exists(), outside.exists());
+        std::fs::remove_dir_all(&root).unwrap();
+        std::fs::remove_dir(&outside).unwrap();
+
+        assert!(kept.is_empty());
+        assert_eq!(removed, [album.join("scans"), album]);
+        assert_eq!(left, (false, true, true));
+    }
+}
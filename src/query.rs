@@ -2,29 +2,113 @@
 //!
 //! Syntax:
 //!   keyword                   - FTS search
+//!   `"blue train"`            - FTS phrase: the words together, in order
+//!   `~"blue train"`           - FTS NEAR: the words within a few of each other
 //!   `artist:beatles`          - Field substring
 //!   `title:=Help!`            - Exact match
-//!   `genre::^rock`            - Regex/glob
+//!   `genre::^(rock|metal)$`   - Regular expression, ignoring case
 //!   `year:1960..1969`         - Range
 //!   `^genre:jazz`             - Negation
+//!   `attr.source:vinyl`       - Flexible attribute, the item's or else its album's
+//!   `( a b )`                 - Group
+//!   `@name`                   - Saved query (bookmark), expanded as a group
+//!   `year- album+`            - Sort, descending or ascending
+//!   `limit:50`                - At most this many results
+//!
+//! Values with spaces are double-quoted (`title:"Let It Be"`), as may be a
+//! whole field term (`"title:Let It Be"`); `field:""` matches an empty or
+//! missing value. A backslash makes the next character plain, such as a quote
+//! inside quotes or a colon in a bare word, except in regular expressions,
+//! which have their own escapes. A value runs from the first colon, so it can
+//! contain more (`album:"Live: At Leeds"`).
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+
+use chrono::{DateTime, Months, NaiveDate, Utc};
+
+use regex::{Regex, RegexBuilder};
+
+use crate::fields::{
+    attribute_key, check_attribute_key, item_field, item_value, Field, FieldType, Value,
+    VIRTUAL_FIELDS,
+};
+use crate::pathformat::Library;
+use crate::{AudioFormat, Error, Item, Result};
+
+/// Sort directives used when a query has none and `library.default_sort`
+/// isn't set.
+pub const DEFAULT_SORT: &str = "artist+ album+ disc+ track+ title+";
 
-use crate::Result;
+/// The `ORDER BY` list for [`DEFAULT_SORT`]. Ends in the unique `path` and
+/// `id` so results never depend on insertion or index order.
+pub const DEFAULT_ORDER: &str = "COALESCE(NULLIF(artist_sort, ''), artist) COLLATE NOCASE ASC, \
+                                 album COLLATE NOCASE ASC, disc ASC, track ASC, \
+                                 title COLLATE NOCASE ASC, path, id";
+
+/// Appended to explicit sort directives to make the order total.
+const TIEBREAK_ORDER: &str = "path, id";
+
+/// Maximum nesting depth when expanding `@name` bookmarks.
+const MAX_BOOKMARK_DEPTH: usize = 16;
+
+/// Words of a `~"..."` search must be at most this many words apart.
+pub const NEAR_DISTANCE: usize = 5;
 
 /// A parsed query term in the AST.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum QueryTerm {
     /// Full-text search term
     FullText(String),
+    /// Full-text search for words next to each other, in order
+    Phrase(String),
+    /// Full-text search for words close to each other, in any order
+    Near(String),
     /// Field-based filter
     Field {
         negated: bool,
         name: String,
         op: FieldOp,
     },
+    /// Parenthesized group of terms, all of which must match
+    Group(Vec<Self>),
     /// Sort directive
     Sort { field: String, ascending: bool },
+    /// Maximum number of results, wherever it appears
+    Limit(u64),
+}
+
+/// Which of a query's results to return: `limit` of them (all if `None`),
+/// after skipping `offset`. A page's limit replaces one given in the query.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Page {
+    pub limit: Option<u64>,
+    pub offset: u64,
 }
 
+/// How bare-word terms are matched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FullTextMode {
+    /// Match against the `items_fts` FTS5 index
+    #[default]
+    Fts5,
+    /// Substring match across the indexed columns, for `SQLite` builds without FTS5
+    Like,
+}
+
+/// How flexible attributes are matched: as text, which may be missing.
+const ATTRIBUTE_FIELD: Field = Field {
+    name: "attr",
+    ty: FieldType::String,
+    nullable: true,
+    tag: false,
+    editable: false,
+};
+
+/// Columns covered by full-text search.
+const FULL_TEXT_COLUMNS: &[&str] = &["title", "artist", "album", "albumartist", "genre"];
+
 /// Field operation types.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum FieldOp {
@@ -32,73 +116,336 @@ pub enum FieldOp {
     Substring(String),
     /// Exact match: field = 'value'
     Exact(String),
-    /// Regex/glob match: field GLOB 'pattern'
-    Regex(String),
+    /// Regex match: field REGEXP 'pattern'
+    Regex(Pattern),
     /// Range match: field BETWEEN start AND end
     Range {
         start: Option<String>,
         end: Option<String>,
     },
-    /// Relative date: added >= 'date'
+    /// Relative date on a date field, such as `added:-1w`: field >= 'date'
     RelativeDate(String),
+    /// Dates from `from` up to but not including `until`, as `YYYY-MM-DD`:
+    /// field >= 'from' AND field < 'until'
+    Span {
+        from: Option<String>,
+        until: Option<String>,
+    },
+}
+
+/// The pattern of a `field::pattern` term, compiled once when the query is
+/// parsed rather than for every item it's matched against. A pattern that
+/// doesn't compile fails the query where it's used.
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    source: String,
+    compiled: std::result::Result<Regex, String>,
+}
+
+impl Pattern {
+    /// Compile `source` as [`regex`] does.
+    #[must_use]
+    pub fn new(source: impl Into<String>) -> Self {
+        let source = source.into();
+        let compiled = build_regex(&source);
+        Self { source, compiled }
+    }
+
+    /// The pattern as written.
+    #[must_use]
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    /// The compiled pattern.
+    ///
+    /// # Errors
+    /// Returns an error naming the pattern if it isn't a valid regex.
+    pub fn regex(&self) -> Result<&Regex> {
+        self.compiled.as_ref().map_err(|e| Error::Query(e.clone()))
+    }
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
 }
 
+impl Eq for Pattern {}
+
 /// Parse a query string into AST terms.
 ///
 /// # Errors
 /// Returns an error if parsing fails.
 pub fn parse(query: &str) -> Result<Vec<QueryTerm>> {
+    parse_group(&mut tokenize(query)?.into_iter(), 0)
+}
+
+/// Whether `terms` leave out nothing: they only sort, if there are any.
+#[must_use]
+pub fn is_unfiltered(terms: &[QueryTerm]) -> bool {
+    terms.iter().all(|term| match term {
+        QueryTerm::Sort { .. } => true,
+        QueryTerm::Group(terms) => is_unfiltered(terms),
+        _ => false,
+    })
+}
+
+/// Split a query at whitespace outside double quotes, so a quoted phrase
+/// stays in one token along with anything before it (`~`, `title:`). An
+/// escaped quote or space doesn't count.
+fn tokenize(query: &str) -> Result<Vec<&str>> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in query.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c.is_whitespace() && !quoted {
+            if let Some(start) = start.take() {
+                tokens.push(&query[start..i]);
+            }
+        } else {
+            start.get_or_insert(i);
+            match c {
+                '"' => quoted = !quoted,
+                '\\' => escaped = true,
+                _ => {}
+            }
+        }
+    }
+    if quoted {
+        return Err(Error::Query("Unclosed '\"' in query".into()));
+    }
+    if let Some(start) = start {
+        tokens.push(&query[start..]);
+    }
+    Ok(tokens)
+}
+
+/// The text inside `s` if it is wrapped in double quotes, still escaped.
+fn unquote(s: &str) -> Option<&str> {
+    let inner = s.strip_prefix('"')?.strip_suffix('"')?;
+    // An odd number of backslashes escapes the closing quote
+    let backslashes = inner.len() - inner.trim_end_matches('\\').len();
+    (backslashes % 2 == 0).then_some(inner)
+}
+
+/// `s` with each backslash escape replaced by the character it escapes. A
+/// trailing backslash escapes nothing and is kept.
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        out.push(if c == '\\' {
+            chars.next().unwrap_or('\\')
+        } else {
+            c
+        });
+    }
+    out
+}
+
+/// `term` split at its first colon outside quotes and escapes, into whether
+/// it is negated with `^`, the field name and the value.
+fn split_field(term: &str) -> Option<(bool, &str, &str)> {
+    let (negated, term) = term
+        .strip_prefix('^')
+        .map_or((false, term), |rest| (true, rest));
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in term.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => quoted = !quoted,
+            ':' if !quoted => return Some((negated, &term[..i], &term[i + 1..])),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Parse terms until the closing `)` of the current group (or end of input at depth 0).
+fn parse_group<'a>(
+    tokens: &mut impl Iterator<Item = &'a str>,
+    depth: usize,
+) -> Result<Vec<QueryTerm>> {
     let mut terms = Vec::new();
 
-    for term in query.split_whitespace() {
-        // Sort directive (ascending)
-        if let Some(rest) = term.strip_suffix('+') {
-            terms.push(QueryTerm::Sort {
-                field: rest.to_string(),
-                ascending: true,
-            });
-            continue;
+    while let Some(token) = tokens.next() {
+        match token {
+            "(" => terms.push(QueryTerm::Group(parse_group(tokens, depth + 1)?)),
+            ")" if depth == 0 => return Err(Error::Query("Unexpected ')' in query".into())),
+            ")" => return Ok(terms),
+            // Terms are always ANDed; an explicit conjunction is accepted and ignored
+            _ if token.eq_ignore_ascii_case("and") => {}
+            _ => terms.push(parse_term(token)),
         }
+    }
+
+    if depth > 0 {
+        return Err(Error::Query("Unclosed '(' in query".into()));
+    }
+    Ok(terms)
+}
+
+/// The field term for the parts [`split_field`] found, or the limit for
+/// `limit:N`.
+fn field_term((negated, name, value): (bool, &str, &str)) -> QueryTerm {
+    if let (false, "limit", Ok(limit)) = (negated, name, value.parse::<u64>()) {
+        return QueryTerm::Limit(limit);
+    }
+    QueryTerm::Field {
+        negated,
+        name: name.to_string(),
+        op: parse_field_op(name, value),
+    }
+}
 
-        // Sort directive (descending)
-        if let Some(rest) = term.strip_suffix('-') {
-            terms.push(QueryTerm::Sort {
-                field: rest.to_string(),
-                ascending: false,
-            });
-            continue;
+/// Parse a single whitespace-delimited term.
+fn parse_term(term: &str) -> QueryTerm {
+    if let Some(inner) = unquote(term) {
+        // A field term quoted whole, as the shell's quotes might have been
+        // meant; other quoted text is a phrase
+        let known = |(_, name, _): &(bool, &str, &str)| {
+            item_field(name).is_some() || attribute_key(name).is_some()
+        };
+        if let Some(field) = split_field(inner).filter(known) {
+            return field_term(field);
         }
+        return QueryTerm::Phrase(unescape(inner));
+    }
+    if let Some(words) = term.strip_prefix('~').and_then(unquote) {
+        return QueryTerm::Near(unescape(words));
+    }
+    if let Some(field) = split_field(term) {
+        return field_term(field);
+    }
+
+    // Sort directive (ascending)
+    if let Some(rest) = term.strip_suffix('+') {
+        return QueryTerm::Sort {
+            field: rest.to_string(),
+            ascending: true,
+        };
+    }
 
-        // Negation prefix
-        let (negated, term) = term
-            .strip_prefix('^')
-            .map_or((false, term), |rest| (true, rest));
-
-        if let Some((field, value)) = term.split_once(':') {
-            let op = parse_field_op(field, value);
-            terms.push(QueryTerm::Field {
-                negated,
-                name: field.to_string(),
-                op,
-            });
+    // Sort directive (descending)
+    if let Some(rest) = term.strip_suffix('-') {
+        return QueryTerm::Sort {
+            field: rest.to_string(),
+            ascending: false,
+        };
+    }
+
+    // Negation only applies to field terms
+    QueryTerm::FullText(unescape(term.strip_prefix('^').unwrap_or(term)))
+}
+
+/// Expand `@name` references to saved queries.
+///
+/// Each reference is replaced by its saved query wrapped in a parenthesized
+/// group. Saved queries may themselves reference other bookmarks.
+///
+/// # Errors
+/// Returns an error for unknown bookmark names or when expansion nests too
+/// deeply (usually a bookmark that references itself).
+pub fn expand_bookmarks<S: BuildHasher>(
+    query: &str,
+    bookmarks: &HashMap<String, String, S>,
+) -> Result<String> {
+    expand_bookmarks_at(query, bookmarks, 0)
+}
+
+fn expand_bookmarks_at<S: BuildHasher>(
+    query: &str,
+    bookmarks: &HashMap<String, String, S>,
+    depth: usize,
+) -> Result<String> {
+    if depth > MAX_BOOKMARK_DEPTH {
+        return Err(Error::Query(format!(
+            "Bookmark expansion nested more than {MAX_BOOKMARK_DEPTH} levels (recursive bookmark?)"
+        )));
+    }
+
+    let mut tokens = Vec::new();
+    for token in tokenize(query)? {
+        if let Some(name) = token.strip_prefix('@') {
+            let saved = bookmarks
+                .get(name)
+                .ok_or_else(|| Error::Query(format!("Unknown bookmark: @{name}")))?;
+            let expanded = expand_bookmarks_at(saved, bookmarks, depth + 1)?;
+            tokens.push(format!("( {expanded} )"));
         } else {
-            terms.push(QueryTerm::FullText(term.to_string()));
+            tokens.push(token.to_string());
         }
     }
 
-    Ok(terms)
+    Ok(tokens.join(" "))
+}
+
+/// Build the effective query for a command.
+///
+/// The default query, if any, is `AND`ed onto the user's query as a group, and
+/// bookmarks are expanded in both. Returns `None` when there is nothing to
+/// filter on.
+///
+/// # Errors
+/// Returns an error if bookmark expansion fails.
+pub fn resolve<S: BuildHasher>(
+    query: Option<&str>,
+    default_query: Option<&str>,
+    bookmarks: &HashMap<String, String, S>,
+) -> Result<Option<String>> {
+    let query = query.map(str::trim).filter(|q| !q.is_empty());
+    let default_query = default_query.map(str::trim).filter(|q| !q.is_empty());
+
+    let combined = match (default_query, query) {
+        (Some(d), Some(q)) => format!("( {d} ) {q}"),
+        (Some(d), None) => d.to_string(),
+        (None, Some(q)) => q.to_string(),
+        (None, None) => return Ok(None),
+    };
+
+    expand_bookmarks(&combined, bookmarks).map(Some)
 }
 
-/// Parse a field operation from the value string.
+/// Parse a field operation from the value string. Text operands may be
+/// quoted to include spaces: `artist:="black sabbath"`.
 fn parse_field_op(field: &str, value: &str) -> FieldOp {
+    let text = |operand: &str| unescape(unquote(operand).unwrap_or(operand));
+
+    // Quoted nothing: the value is empty or missing
+    if unquote(value) == Some("") {
+        return FieldOp::Exact(String::new());
+    }
+
     // Exact match
     if let Some(exact) = value.strip_prefix('=') {
-        return FieldOp::Exact(exact.to_string());
+        return FieldOp::Exact(text(exact));
     }
 
-    // Regex/glob match
+    // Regex match; backslashes are the pattern's own escapes
     if let Some(pattern) = value.strip_prefix(':') {
-        return FieldOp::Regex(pattern.to_string());
+        return FieldOp::Regex(Pattern::new(unquote(pattern).unwrap_or(pattern)));
+    }
+
+    // Comparisons, shorthand for ranges open at one end
+    let bound = |b: &str| (!b.is_empty()).then(|| b.to_string());
+    if let Some(start) = value.strip_prefix(">=") {
+        return FieldOp::Range {
+            start: bound(start),
+            end: None,
+        };
+    }
+    if let Some(end) = value.strip_prefix("<=") {
+        return FieldOp::Range {
+            start: None,
+            end: bound(end),
+        };
     }
 
     // Range match
@@ -120,74 +467,445 @@ fn parse_field_op(field: &str, value: &str) -> FieldOp {
     }
 
     // Relative date
-    if field == "added" && value.starts_with('-') {
+    let is_date = item_field(field).is_some_and(|f| f.ty == FieldType::Date);
+    if is_date && value.starts_with('-') {
         if let Some(date) = parse_relative_date(value) {
             return FieldOp::RelativeDate(date);
         }
     }
 
     // Substring match (default)
-    FieldOp::Substring(value.to_string())
+    FieldOp::Substring(text(value))
 }
 
-/// Convert AST terms to SQL.
+/// Convert AST terms to SQL selecting `page` of the results, ordered by
+/// `default_order` (an `ORDER BY` list such as [`sort_order`] gives) when
+/// they have no sort directive.
 ///
 /// # Errors
-/// Returns an error if SQL generation fails.
-pub fn terms_to_sql(terms: &[QueryTerm]) -> Result<String> {
+/// Returns an error if a term names an unknown field, or a numeric field's
+/// range bound isn't a number.
+pub fn terms_to_sql(
+    terms: &[QueryTerm],
+    mode: FullTextMode,
+    default_order: &str,
+    page: Page,
+) -> Result<String> {
     let mut conditions = Vec::new();
     let mut order_by = Vec::new();
+    let mut limit = None;
+    collect_sql(terms, mode, &mut conditions, &mut order_by, &mut limit)?;
+    let where_clause = where_clause(&conditions);
+
+    let order_clause = if order_by.is_empty() {
+        format!("ORDER BY {default_order}")
+    } else {
+        format!("ORDER BY {}, {TIEBREAK_ORDER}", order_by.join(", "))
+    };
+
+    // SQLite only takes an offset after a limit, where -1 means none
+    let limit_clause = match (page.limit.or(limit), page.offset) {
+        (None, 0) => String::new(),
+        (Some(limit), 0) => format!(" LIMIT {limit}"),
+        (limit, offset) => format!(" LIMIT {} OFFSET {offset}", limit.map_or(-1, i128::from)),
+    };
+
+    Ok(format!("SELECT * FROM items {where_clause} {order_clause}{limit_clause}"))
+}
+
+/// The `WHERE` clause selecting the items `terms` match, or nothing if they
+/// all do, for queries other than listing items such as counts. Sorting and
+/// limits are left out.
+///
+/// # Errors
+/// Returns an error as [`terms_to_sql`] does.
+pub fn where_sql(terms: &[QueryTerm], mode: FullTextMode) -> Result<String> {
+    let mut conditions = Vec::new();
+    collect_sql(terms, mode, &mut conditions, &mut Vec::new(), &mut None)?;
+    Ok(where_clause(&conditions))
+}
+
+fn where_clause(conditions: &[String]) -> String {
+    if conditions.is_empty() {
+        String::new()
+    } else {
+        format!("WHERE {}", conditions.join(" AND "))
+    }
+}
+
+/// The `ORDER BY` list for `sort`, sort directives such as `smart_artist+
+/// year-`, with `path` and `id` added to make it total.
+///
+/// # Errors
+/// Returns an error if `sort` is empty, holds anything but sort directives,
+/// or sorts on an unknown field.
+pub fn sort_order(sort: &str) -> Result<String> {
+    let terms = parse(sort)?;
+    if terms.is_empty() {
+        return Err(Error::Query("No sort directives given".into()));
+    }
+    let mut order_by = Vec::new();
+    for term in &terms {
+        let QueryTerm::Sort { field, ascending } = term else {
+            return Err(Error::Query(format!(
+                "Expected sort directives like \"year-\", got: {sort}"
+            )));
+        };
+        order_by.push(sort_key(field, *ascending)?);
+    }
+    Ok(format!("{}, {TIEBREAK_ORDER}", order_by.join(", ")))
+}
+
+/// The `ORDER BY` key for a sort directive. Text sorts ignore case, so "a-ha"
+/// comes before "ZZ Top", and artists sort by their sort names where they
+/// have them.
+fn sort_key(name: &str, ascending: bool) -> Result<String> {
+    let direction = if ascending { "ASC" } else { "DESC" };
+    if let Some(key) = attribute_key(name) {
+        check_attribute_key(key)?;
+        return Ok(format!("{} COLLATE NOCASE {direction}", attribute_sql(key)));
+    }
+    let field = known_field(name)?;
+    let collate = if field.ty == FieldType::String { " COLLATE NOCASE" } else { "" };
+    let key = match field.name {
+        "artist" => "COALESCE(NULLIF(artist_sort, ''), artist)",
+        "albumartist" => "COALESCE(NULLIF(albumartist_sort, ''), albumartist)",
+        _ => column(field),
+    };
+    Ok(format!("{key}{collate} {direction}"))
+}
+
+/// Collect WHERE conditions, ORDER BY keys and the limit for a list of terms.
+///
+/// Sort directives and limits inside groups apply to the whole query; the
+/// last limit wins.
+fn collect_sql(
+    terms: &[QueryTerm],
+    mode: FullTextMode,
+    conditions: &mut Vec<String>,
+    order_by: &mut Vec<String>,
+    limit: &mut Option<u64>,
+) -> Result<()> {
+    // All full-text terms at this level are searched at once
+    let full_text: Vec<&QueryTerm> = terms.iter().filter(|term| is_full_text(term)).collect();
+    conditions.extend(full_text_to_sql(&full_text, mode));
 
     for term in terms {
         match term {
-            QueryTerm::FullText(text) => {
-                conditions.push(format!(
-                    "id IN (SELECT rowid FROM items_fts WHERE items_fts MATCH '{}')",
-                    text.replace('\'', "''")
-                ));
-            }
+            QueryTerm::FullText(_) | QueryTerm::Phrase(_) | QueryTerm::Near(_) => {}
             QueryTerm::Field { negated, name, op } => {
-                let condition = field_op_to_sql(name, op);
+                check_pattern(op)?;
+                let condition = if let Some(key) = attribute_key(name) {
+                    check_attribute_key(key)?;
+                    field_op_to_sql(&attribute_sql(key), FieldType::String, op)
+                } else {
+                    let field = known_field(name)?;
+                    // Text compares lexically, so only numbers are checked
+                    if let (FieldOp::Range { start, end }, FieldType::Int | FieldType::Float) =
+                        (op, field.ty)
+                    {
+                        for bound in [start, end].into_iter().flatten() {
+                            field.parse_value(bound)?;
+                        }
+                    }
+                    field_op_to_sql(column(field), field.ty, &normalize(field, op)?)
+                };
                 if *negated {
                     conditions.push(format!("NOT ({condition})"));
                 } else {
                     conditions.push(condition);
                 }
             }
-            QueryTerm::Sort { field, ascending } => {
-                let direction = if *ascending { "ASC" } else { "DESC" };
-                order_by.push(format!("{field} {direction}"));
+            QueryTerm::Group(inner) => {
+                let mut group = Vec::new();
+                collect_sql(inner, mode, &mut group, order_by, limit)?;
+                if !group.is_empty() {
+                    conditions.push(format!("({})", group.join(" AND ")));
+                }
             }
+            QueryTerm::Sort { field, ascending } => order_by.push(sort_key(field, *ascending)?),
+            QueryTerm::Limit(n) => *limit = Some(*n),
         }
     }
+    Ok(())
+}
 
-    let where_clause = if conditions.is_empty() {
-        String::new()
-    } else {
-        format!("WHERE {}", conditions.join(" AND "))
+/// Compile the pattern of a `field::pattern` term. Matching ignores case, as
+/// the rest of the query language does, unless the pattern turns that off
+/// with `(?-i)` or `(?-i:...)`.
+///
+/// # Errors
+/// Returns an error naming the pattern if it isn't a valid regex.
+pub fn regex(pattern: &str) -> Result<Regex> {
+    build_regex(pattern).map_err(Error::Query)
+}
+
+/// [`regex`], failing with the message for its error.
+fn build_regex(pattern: &str) -> std::result::Result<Regex, String> {
+    RegexBuilder::new(pattern)
+        .case_insensitive(true)
+        .build()
+        .map_err(|e| format!("Invalid regex {pattern:?}: {e}"))
+}
+
+/// Fail on a regex term whose pattern doesn't compile, before it's used.
+fn check_pattern(op: &FieldOp) -> Result<()> {
+    if let FieldOp::Regex(pattern) = op {
+        pattern.regex()?;
+    }
+    Ok(())
+}
+
+/// Look up a field named in a query; only known columns ever reach the SQL.
+fn known_field(name: &str) -> Result<&'static Field> {
+    item_field(name)
+        .or_else(|| VIRTUAL_FIELDS.iter().map(|(field, _)| field).find(|f| f.name == name))
+        .ok_or_else(|| Error::Query(format!("Unknown field: {name}")))
+}
+
+/// The SQL for `field`'s value: its column, or the expression for a virtual
+/// field.
+fn column(field: &Field) -> &'static str {
+    VIRTUAL_FIELDS
+        .iter()
+        .find(|(virtual_field, _)| virtual_field == field)
+        .map_or(field.name, |(_, sql)| *sql)
+}
+
+/// The SQL for an item's flexible attribute `key`, which
+/// [`check_attribute_key`] has passed: the item's own, or else its album's.
+fn attribute_sql(key: &str) -> String {
+    format!(
+        "COALESCE((SELECT value FROM item_attributes WHERE item_id = items.id AND key = '{key}'), \
+         (SELECT value FROM album_attributes WHERE album_id = items.album_id AND key = '{key}'))"
+    )
+}
+
+/// `op` on `field` in the terms the column stores: formats by their stored
+/// name, whichever name they're given by, and dates as the span of days
+/// they cover, so `added:2024-06` is all of June.
+///
+/// # Errors
+/// Returns an error for an unknown format, or a date that isn't `YYYY`,
+/// `YYYY-MM` or `YYYY-MM-DD`.
+fn normalize(field: &Field, op: &FieldOp) -> Result<FieldOp> {
+    Ok(match op {
+        FieldOp::Substring(value) | FieldOp::Exact(value) if value.is_empty() => op.clone(),
+        FieldOp::Substring(value) | FieldOp::Exact(value) if field.name == "format" => {
+            let format = AudioFormat::from_name(value).ok_or_else(|| {
+                let names: Vec<&str> = AudioFormat::ALL
+                    .iter()
+                    .filter_map(|format| format.extensions().first().copied())
+                    .collect();
+                Error::Query(format!("Unknown format: {value} (expected {})", names.join(", ")))
+            })?;
+            FieldOp::Exact(format.as_str().to_string())
+        }
+        FieldOp::Substring(value) | FieldOp::Exact(value) if field.ty == FieldType::Date => {
+            let (from, until) = date_span(value)?;
+            FieldOp::Span {
+                from: Some(from),
+                until: Some(until),
+            }
+        }
+        FieldOp::Range { start, end } if field.ty == FieldType::Date => FieldOp::Span {
+            from: start.as_deref().map(date_span).transpose()?.map(|(from, _)| from),
+            until: end.as_deref().map(date_span).transpose()?.map(|(_, until)| until),
+        },
+        _ => op.clone(),
+    })
+}
+
+/// The first day of the year, month or day `value` names and the first day
+/// after it, as `YYYY-MM-DD`. A relative date such as `-1w` names a day.
+fn date_span(value: &str) -> Result<(String, String)> {
+    let relative = value.starts_with('-').then(|| parse_relative_date(value)).flatten();
+    let span = || {
+        let value = relative.as_deref().unwrap_or(value);
+        let mut parts = value.split('-');
+        let year: i32 = parts.next()?.parse().ok()?;
+        let month: Option<u32> = parts.next().map(str::parse).transpose().ok()?;
+        let day: Option<u32> = parts.next().map(str::parse).transpose().ok()?;
+        if parts.next().is_some() {
+            return None;
+        }
+        match (month, day) {
+            (None, _) => Some((
+                NaiveDate::from_ymd_opt(year, 1, 1)?,
+                NaiveDate::from_ymd_opt(year + 1, 1, 1)?,
+            )),
+            (Some(month), None) => {
+                let from = NaiveDate::from_ymd_opt(year, month, 1)?;
+                Some((from, from.checked_add_months(Months::new(1))?))
+            }
+            (Some(month), Some(day)) => {
+                let from = NaiveDate::from_ymd_opt(year, month, day)?;
+                Some((from, from.succ_opt()?))
+            }
+        }
     };
+    let (from, until) = span().ok_or_else(|| {
+        Error::Query(format!(
+            "Invalid date: {value} (expected YYYY, YYYY-MM, YYYY-MM-DD or a relative date \
+             such as -1w)"
+        ))
+    })?;
+    let format = |date: NaiveDate| date.format("%Y-%m-%d").to_string();
+    Ok((format(from), format(until)))
+}
 
-    let order_clause = if order_by.is_empty() {
-        "ORDER BY artist, album, disc, track".to_string()
-    } else {
-        format!("ORDER BY {}", order_by.join(", "))
+/// The start of the year, month or day `value` names, as in date queries;
+/// a time ago such as `7d` may leave out its `-`.
+///
+/// # Errors
+/// Returns an error if `value` isn't a date.
+pub fn date_start(value: &str) -> Result<DateTime<Utc>> {
+    let ago = format!("-{}", value.trim_start_matches('-'));
+    let value = if parse_relative_date(&ago).is_some() { &ago } else { value };
+    let (from, _) = date_span(value)?;
+    NaiveDate::parse_from_str(&from, "%Y-%m-%d")
+        .ok()
+        .and_then(|day| day.and_hms_opt(0, 0, 0))
+        .map(|start| start.and_utc())
+        .ok_or_else(|| Error::Query(format!("Invalid date: {value}")))
+}
+
+const fn is_full_text(term: &QueryTerm) -> bool {
+    matches!(
+        term,
+        QueryTerm::FullText(_) | QueryTerm::Phrase(_) | QueryTerm::Near(_)
+    )
+}
+
+/// One condition requiring every full-text term in `terms` to match; `None`
+/// when there are none. With FTS5 that is a single `items_fts` lookup, but
+/// terms without a letter or digit, such as "!!!", are matched with `LIKE`:
+/// FTS5 indexes nothing else, so they would match nothing there.
+fn full_text_to_sql(terms: &[&QueryTerm], mode: FullTextMode) -> Option<String> {
+    let (indexed, unindexed): (Vec<&QueryTerm>, Vec<&QueryTerm>) = match mode {
+        FullTextMode::Fts5 => terms.iter().copied().partition(|term| has_words(term)),
+        FullTextMode::Like => (Vec::new(), terms.to_vec()),
     };
+    let mut conditions = Vec::new();
+    if let Some(expression) = fts_expression(&indexed) {
+        conditions.push(format!(
+            "id IN (SELECT rowid FROM items_fts WHERE items_fts MATCH '{}')",
+            expression.replace('\'', "''")
+        ));
+    }
+    for term in unindexed {
+        match term {
+            QueryTerm::Near(words) => conditions.extend(words.split_whitespace().map(like_any)),
+            QueryTerm::FullText(text) | QueryTerm::Phrase(text) => conditions.push(like_any(text)),
+            _ => {}
+        }
+    }
+    (!conditions.is_empty()).then(|| conditions.join(" AND "))
+}
+
+/// Whether a full-text term has anything FTS5 would index.
+fn has_words(term: &QueryTerm) -> bool {
+    match term {
+        QueryTerm::FullText(text) | QueryTerm::Phrase(text) | QueryTerm::Near(text) => {
+            text.chars().any(char::is_alphanumeric)
+        }
+        _ => false,
+    }
+}
+
+/// A condition matching items with `text` in any full-text column.
+fn like_any(text: &str) -> String {
+    let pattern = like_contains(text);
+    let columns: Vec<String> = FULL_TEXT_COLUMNS
+        .iter()
+        .map(|c| format!("{c} LIKE {pattern}"))
+        .collect();
+    format!("({})", columns.join(" OR "))
+}
+
+/// A quoted `LIKE` pattern for values containing `text`. Its `%` and `_`
+/// are taken literally, escaped only when there are any.
+fn like_contains(text: &str) -> String {
+    let text = text.replace('\'', "''");
+    if !text.contains(['%', '_', '\\']) {
+        return format!("'%{text}%'");
+    }
+    let mut escaped = String::with_capacity(text.len() + 4);
+    for c in text.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    format!("'%{escaped}%' ESCAPE '\\'")
+}
+
+/// The FTS5 `MATCH` expression for full-text terms, all of which must match.
+/// Every word and phrase is quoted, so nothing typed can be read as FTS5
+/// syntax; only a trailing `*` (a prefix search) is kept.
+fn fts_expression(terms: &[&QueryTerm]) -> Option<String> {
+    let parts: Vec<String> = terms
+        .iter()
+        .filter_map(|term| match term {
+            QueryTerm::FullText(text) | QueryTerm::Phrase(text) => fts_string(text),
+            QueryTerm::Near(words) => {
+                let words: Vec<String> = words.split_whitespace().filter_map(fts_string).collect();
+                match words.len() {
+                    0 => None,
+                    1 => words.into_iter().next(),
+                    _ => Some(format!("NEAR({}, {NEAR_DISTANCE})", words.join(" "))),
+                }
+            }
+            _ => None,
+        })
+        .collect();
+    (!parts.is_empty()).then(|| parts.join(" AND "))
+}
 
-    Ok(format!("SELECT * FROM items {where_clause} {order_clause}"))
+/// `text` as an FTS5 string, which matches its words as a phrase.
+fn fts_string(text: &str) -> Option<String> {
+    let (text, prefix) = text.strip_suffix('*').map_or((text, ""), |text| (text, "*"));
+    let text = text.trim();
+    (!text.is_empty()).then(|| format!("\"{}\"{prefix}", text.replace('"', "\"\"")))
 }
 
-/// Convert a field operation to SQL.
-fn field_op_to_sql(field: &str, op: &FieldOp) -> String {
+/// Whether any term (including inside groups) is a full-text search.
+pub fn uses_full_text(terms: &[QueryTerm]) -> bool {
+    terms.iter().any(|term| match term {
+        QueryTerm::FullText(_) | QueryTerm::Phrase(_) | QueryTerm::Near(_) => true,
+        QueryTerm::Group(inner) => uses_full_text(inner),
+        QueryTerm::Field { .. } | QueryTerm::Sort { .. } | QueryTerm::Limit(_) => false,
+    })
+}
+
+/// Convert a field operation on `column`, the SQL for a value of type `ty`,
+/// to SQL.
+///
+/// An empty string counts as a missing value, like NULL: `field:=` matches
+/// both, `field:` neither, and operations that would match an empty string
+/// skip it.
+fn field_op_to_sql(column: &str, ty: FieldType, op: &FieldOp) -> String {
     match op {
-        FieldOp::Substring(value) => {
-            format!("{field} LIKE '%{}%'", value.replace('\'', "''"))
+        FieldOp::Exact(value) if value.is_empty() => {
+            return format!("NULLIF({column}, '') IS NULL");
+        }
+        FieldOp::Substring(value) if value.is_empty() => {
+            return format!("NULLIF({column}, '') IS NOT NULL");
         }
+        _ => {}
+    }
+    let field = if ty == FieldType::String && matches_empty(op) {
+        format!("NULLIF({column}, '')")
+    } else {
+        column.to_string()
+    };
+    match op {
+        FieldOp::Substring(value) => format!("{field} LIKE {}", like_contains(value)),
         FieldOp::Exact(value) => {
             format!("{field} = '{}'", value.replace('\'', "''"))
         }
         FieldOp::Regex(pattern) => {
-            let glob = regex_to_glob(pattern);
-            format!("{field} GLOB '{glob}'")
+            format!("{field} REGEXP '{}'", pattern.as_str().replace('\'', "''"))
         }
         FieldOp::Range { start, end } => match (start, end) {
             (Some(s), Some(e)) => format!("{field} BETWEEN '{s}' AND '{e}'"),
@@ -198,6 +916,193 @@ fn field_op_to_sql(field: &str, op: &FieldOp) -> String {
         FieldOp::RelativeDate(date) => {
             format!("{field} >= '{date}'")
         }
+        FieldOp::Span { from, until } => match (from, until) {
+            (Some(f), Some(u)) => format!("{field} >= '{f}' AND {field} < '{u}'"),
+            (Some(f), None) => format!("{field} >= '{f}'"),
+            (None, Some(u)) => format!("{field} < '{u}'"),
+            (None, None) => format!("{field} IS NOT NULL"),
+        },
+    }
+}
+
+/// Whether a text operation would match an empty string.
+fn matches_empty(op: &FieldOp) -> bool {
+    match op {
+        FieldOp::Substring(value) | FieldOp::Exact(value) => value.is_empty(),
+        FieldOp::Regex(pattern) => pattern.regex().is_ok_and(|re| re.is_match("")),
+        FieldOp::Range { start, .. } => start.as_deref().unwrap_or_default().is_empty(),
+        FieldOp::RelativeDate(_) | FieldOp::Span { .. } => false,
+    }
+}
+
+/// Names of all fields referenced by `terms`, including in groups and sort
+/// directives.
+#[must_use]
+pub fn field_names(terms: &[QueryTerm]) -> Vec<&str> {
+    let mut names = Vec::new();
+    for term in terms {
+        match term {
+            QueryTerm::Field { name, .. } => names.push(name.as_str()),
+            QueryTerm::Sort { field, .. } => names.push(field.as_str()),
+            QueryTerm::Group(inner) => names.extend(field_names(inner)),
+            QueryTerm::FullText(_)
+            | QueryTerm::Phrase(_)
+            | QueryTerm::Near(_)
+            | QueryTerm::Limit(_) => {}
+        }
+    }
+    names
+}
+
+/// Whether `item` matches `terms`, evaluated in memory as [`matches_item`]
+/// does. A query that can't be evaluated, such as one naming an unknown
+/// field, matches nothing.
+#[must_use]
+pub fn matches(terms: &[QueryTerm], item: &Item) -> bool {
+    matches_item(terms, item, None).unwrap_or(false)
+}
+
+/// Evaluate `terms` against an item in memory, for items not in the
+/// database yet. Follows the SQL semantics of [`terms_to_sql`] with the LIKE
+/// fallback for bare words: a comparison with a missing (NULL) value is
+/// false, negated or not, and text matching ignores ASCII case only, as
+/// `LIKE` does. `artpath` belongs to the album, so it is always missing here.
+/// Flexible attributes are read from `library`, and missing without it.
+///
+/// # Errors
+/// Returns an error if a term names an unknown field, or `library` can't be
+/// read.
+pub fn matches_item(
+    terms: &[QueryTerm],
+    item: &Item,
+    library: Option<&dyn Library>,
+) -> Result<bool> {
+    for term in terms {
+        if eval_term(term, item, library)? != Some(true) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Whether any full-text column of `item` contains `text`, ignoring case.
+fn contains_text(item: &Item, text: &str) -> bool {
+    FULL_TEXT_COLUMNS.iter().any(|column| {
+        value_text(&item_value(item, column))
+            .is_some_and(|value| contains_ignoring_case(&value, text))
+    })
+}
+
+/// Whether `text` contains `needle`, ignoring ASCII case as `LIKE` does.
+fn contains_ignoring_case(text: &str, needle: &str) -> bool {
+    text.to_ascii_lowercase().contains(&needle.to_ascii_lowercase())
+}
+
+/// SQL-style three-valued result of one term: `None` is NULL.
+fn eval_term(
+    term: &QueryTerm,
+    item: &Item,
+    library: Option<&dyn Library>,
+) -> Result<Option<bool>> {
+    Ok(match term {
+        QueryTerm::FullText(text) | QueryTerm::Phrase(text) => Some(contains_text(item, text)),
+        QueryTerm::Near(words) => Some(words.split_whitespace().all(|w| contains_text(item, w))),
+        QueryTerm::Field { negated, name, op } => {
+            check_pattern(op)?;
+            let result = if let Some(key) = attribute_key(name) {
+                check_attribute_key(key)?;
+                let value = match library {
+                    Some(library) => library.attribute(item, key)?.map_or(Value::Null, Value::Text),
+                    None => Value::Null,
+                };
+                eval_field_op(&ATTRIBUTE_FIELD, &value, op)
+            } else {
+                let field = known_field(name)?;
+                eval_field_op(field, &item_value(item, name), &normalize(field, op)?)
+            };
+            if *negated {
+                result.map(|b| !b)
+            } else {
+                result
+            }
+        }
+        QueryTerm::Group(inner) => Some(matches_item(inner, item, library)?),
+        QueryTerm::Sort { field, ascending } => {
+            sort_key(field, *ascending)?;
+            Some(true)
+        }
+        QueryTerm::Limit(_) => Some(true),
+    })
+}
+
+/// `op` on a value, as [`field_op_to_sql`]'s condition would evaluate it.
+fn eval_field_op(field: &Field, value: &Value, op: &FieldOp) -> Option<bool> {
+    let text = value_text(value);
+    let missing = text.as_ref().filter(|text| !text.is_empty()).is_none();
+    match op {
+        FieldOp::Exact(expected) if expected.is_empty() => return Some(missing),
+        FieldOp::Substring(needle) if needle.is_empty() => return Some(!missing),
+        _ => {}
+    }
+    // An empty string is missing only to operations that would match it
+    if missing && field.ty == FieldType::String && matches_empty(op) {
+        return None;
+    }
+    let text = text?;
+    let numeric = matches!(field.ty, FieldType::Int | FieldType::Float);
+    // Numeric columns compare numerically against numeric-looking operands
+    let compare = |bound: &str| match (numeric, text.parse::<f64>(), bound.parse::<f64>()) {
+        (true, Ok(a), Ok(b)) => a.partial_cmp(&b),
+        _ => Some(text.as_str().cmp(bound)),
+    };
+    Some(match op {
+        FieldOp::Substring(needle) => contains_ignoring_case(&text, needle),
+        FieldOp::Exact(expected) => compare(expected)? == Ordering::Equal,
+        FieldOp::Regex(pattern) => pattern.regex().ok()?.is_match(&text),
+        FieldOp::Range { start, end } => {
+            let after_start = match start {
+                Some(start) => compare(start)? != Ordering::Less,
+                None => true,
+            };
+            let before_end = match end {
+                Some(end) => compare(end)? != Ordering::Greater,
+                None => true,
+            };
+            after_start && before_end
+        }
+        FieldOp::RelativeDate(date) => text.as_str() >= date.as_str(),
+        FieldOp::Span { from, until } => {
+            let after_from = match from {
+                Some(from) => text.as_str() >= from.as_str(),
+                None => true,
+            };
+            let before_until = match until {
+                Some(until) => text.as_str() < until.as_str(),
+                None => true,
+            };
+            after_from && before_until
+        }
+    })
+}
+
+/// A value as SQL would see it in a text comparison; `None` for NULL.
+fn value_text(value: &Value) -> Option<String> {
+    match value {
+        Value::Null => None,
+        Value::Text(s) => Some(s.clone()),
+        Value::Int(n) => Some(n.to_string()),
+        Value::Float(n) => Some(real_text(*n)),
+        Value::Bool(b) => Some(u8::from(*b).to_string()),
+    }
+}
+
+/// `n` as `SQLite` writes a REAL as text: whole numbers keep a `.0`, so a
+/// length of 180 reads "180.0".
+pub(crate) fn real_text(n: f64) -> String {
+    if n.fract().abs() < f64::EPSILON && n.abs() < 1e15 {
+        format!("{n:.1}")
+    } else {
+        n.to_string()
     }
 }
 
@@ -205,16 +1110,9 @@ fn field_op_to_sql(field: &str, op: &FieldOp) -> String {
 ///
 /// # Errors
 /// Returns an error if the query cannot be parsed.
-pub fn to_sql(query: &str) -> Result<String> {
+pub fn to_sql(query: &str, mode: FullTextMode) -> Result<String> {
     let terms = parse(query)?;
-    terms_to_sql(&terms)
-}
-
-fn regex_to_glob(pattern: &str) -> String {
-    pattern
-        .replace(".*", "*")
-        .replace('.', "?")
-        .replace(['^', '$'], "")
+    terms_to_sql(&terms, mode, DEFAULT_ORDER, Page::default())
 }
 
 fn parse_relative_date(value: &str) -> Option<String> {
@@ -231,35 +1129,213 @@ fn parse_relative_date(value: &str) -> Option<String> {
         return None;
     };
 
-    let date = chrono::Utc::now() - chrono::Duration::days(num);
+    let date = now() - chrono::Duration::days(num);
     Some(date.format("%Y-%m-%d").to_string())
 }
 
+/// The time relative dates count back from, which tests set.
+fn now() -> DateTime<Utc> {
+    #[cfg(test)]
+    if let Some(now) = tests::NOW.with(std::cell::Cell::get) {
+        return now;
+    }
+    Utc::now()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testutil;
+    use chrono::TimeZone;
+    use std::cell::Cell;
+
+    thread_local! {
+        pub(super) static NOW: Cell<Option<DateTime<Utc>>> = const { Cell::new(None) };
+    }
 
     #[test]
     fn test_simple_query() {
-        let sql = to_sql("artist:beatles").unwrap();
+        let sql = to_sql("artist:beatles", FullTextMode::Fts5).unwrap();
         assert!(sql.contains("artist LIKE '%beatles%'"));
     }
 
     #[test]
     fn test_exact_match() {
-        let sql = to_sql("title:=Help!").unwrap();
+        let sql = to_sql("title:=Help!", FullTextMode::Fts5).unwrap();
         assert!(sql.contains("title = 'Help!'"));
+        let sql = to_sql("genre:=", FullTextMode::Fts5).unwrap();
+        assert!(sql.contains("NULLIF(genre, '') IS NULL"));
     }
 
     #[test]
     fn test_range() {
-        let sql = to_sql("year:1960..1969").unwrap();
+        let sql = to_sql("year:1960..1969", FullTextMode::Fts5).unwrap();
         assert!(sql.contains("year BETWEEN '1960' AND '1969'"));
+        let sql = to_sql("samplerate:>=88200 bitdepth:<=16", FullTextMode::Fts5).unwrap();
+        assert!(sql.contains("samplerate >= '88200'"));
+        assert!(sql.contains("bitdepth <= '16'"));
+        assert!(to_sql("channels:>=two", FullTextMode::Fts5).is_err());
+    }
+
+    #[test]
+    fn test_field_names_and_range_types_are_checked() {
+        let err = to_sql("album_name:x", FullTextMode::Fts5).unwrap_err();
+        assert!(err.to_string().contains("Unknown field: album_name"));
+        assert!(to_sql("bogus-", FullTextMode::Fts5).is_err());
+        assert!(to_sql("year:banana..2000", FullTextMode::Fts5).is_err());
+        assert!(to_sql("added:2024-01..", FullTextMode::Fts5).is_ok());
+    }
+
+    #[test]
+    fn test_relative_dates() {
+        NOW.with(|now| now.set(Utc.with_ymd_and_hms(2024, 3, 9, 12, 0, 0).single()));
+        let sql = to_sql("mtime:-1w last_played:-2w", FullTextMode::Fts5).unwrap();
+        assert!(sql.contains("mtime >= '2024-03-02'"), "{sql}");
+        assert!(sql.contains("last_played >= '2024-02-24'"), "{sql}");
+
+        // As range bounds, each names the day it falls on
+        let sql = to_sql("added:-2w..-1w", FullTextMode::Fts5).unwrap();
+        assert!(sql.contains("added >= '2024-02-24' AND added < '2024-03-03'"), "{sql}");
+        assert!(to_sql("added:-1x..", FullTextMode::Fts5).is_err());
+        // Only date fields take them
+        let sql = to_sql("title:-1w", FullTextMode::Fts5).unwrap();
+        assert!(sql.contains("title LIKE '%-1w%'"), "{sql}");
+
+        let start = |value| date_start(value).unwrap().format("%Y-%m-%d %H:%M").to_string();
+        assert_eq!(start("2024-03"), "2024-03-01 00:00");
+        assert_eq!(start("2w"), "2024-02-24 00:00");
+        assert_eq!(start("-2w"), start("2w"));
+        assert!(date_start("7x").is_err());
+    }
+
+    #[test]
+    fn test_order_is_total() {
+        let sql = to_sql("artist:x", FullTextMode::Fts5).unwrap();
+        assert!(sql.ends_with(&format!("ORDER BY {DEFAULT_ORDER}")));
+        let sql = to_sql("year-", FullTextMode::Fts5).unwrap();
+        assert!(sql.ends_with("ORDER BY year DESC, path, id"));
+    }
+
+    #[test]
+    fn test_limit_and_page() {
+        let sql = to_sql("year- limit:50", FullTextMode::Fts5).unwrap();
+        assert!(sql.ends_with("ORDER BY year DESC, path, id LIMIT 50"));
+        let terms = parse("( artist:x limit:5 ) limit:2").unwrap();
+        let sql = where_sql(&terms, FullTextMode::Fts5).unwrap();
+        assert_eq!(sql, "WHERE (artist LIKE '%x%')");
+
+        let page = |limit, offset| {
+            let page = Page { limit, offset };
+            terms_to_sql(&terms, FullTextMode::Fts5, DEFAULT_ORDER, page).unwrap()
+        };
+        assert!(page(None, 0).ends_with("path, id LIMIT 2"));
+        assert!(page(Some(10), 0).ends_with("path, id LIMIT 10"));
+        assert!(page(None, 20).ends_with("path, id LIMIT 2 OFFSET 20"));
+        assert!(to_sql("artist:x", FullTextMode::Fts5).unwrap().ends_with("path, id"));
+        let sql = terms_to_sql(&[], FullTextMode::Fts5, "id", Page { limit: None, offset: 3 });
+        assert!(sql.unwrap().ends_with("ORDER BY id LIMIT -1 OFFSET 3"));
+
+        // Anything but a count is an unknown field
+        assert!(to_sql("limit:many", FullTextMode::Fts5).is_err());
+        assert!(to_sql("^limit:5", FullTextMode::Fts5).is_err());
+    }
+
+    #[test]
+    fn test_sort_keys() {
+        let sql = to_sql("year- album+", FullTextMode::Fts5).unwrap();
+        assert!(sql.ends_with("ORDER BY year DESC, album COLLATE NOCASE ASC, path, id"));
+        let sql = to_sql("smart_artist+", FullTextMode::Fts5).unwrap();
+        assert!(sql.contains("ORDER BY sort_name(COALESCE(NULLIF(albumartist_sort, ''), \
+                              NULLIF(albumartist, ''), NULLIF(artist_sort, ''), artist))"));
+        let sql = to_sql("albumartist-", FullTextMode::Fts5).unwrap();
+        assert!(sql.ends_with(
+            "ORDER BY COALESCE(NULLIF(albumartist_sort, ''), albumartist) COLLATE NOCASE DESC, \
+             path, id"
+        ));
+        let err = to_sql("bogus+", FullTextMode::Fts5).unwrap_err();
+        assert_eq!(err.to_string(), "Query error: Unknown field: bogus");
+
+        assert_eq!(sort_order(DEFAULT_SORT).unwrap(), DEFAULT_ORDER);
+        assert_eq!(sort_order("added-").unwrap(), "added DESC, path, id");
+        assert!(sort_order("").is_err());
+        assert!(sort_order("year+ beatles").is_err());
+    }
+
+    #[test]
+    fn test_matches_item() {
+        let item = Item {
+            path: "/incoming/Blue Train/01.flac".into(),
+            title: "Blue Train".into(),
+            artist: "John Coltrane".into(),
+            album: "Blue Train".into(),
+            year: Some(1957),
+            track: Some(1),
+            format: crate::AudioFormat::Flac,
+            bitrate: 900,
+            length: 643.0,
+            samplerate: Some(96_000),
+            channels: Some(2),
+            bitdepth: Some(24),
+            ..testutil::item()
+        };
+        let matches = |q: &str| matches_item(&parse(q).unwrap(), &item, None).unwrap();
+
+        assert!(matches("album:blue year:1950..1959"));
+        assert!(matches("coltrane ( title::Blue* )"));
+        assert!(matches("year:=1957 length:600.."));
+        assert!(matches("samplerate:>=88200 channels:=2 bitdepth:24"));
+        assert!(!matches("samplerate:<=48000"));
+        assert!(!matches("^artist:coltrane"));
+        assert!(!matches("year:1958.."));
+        // Like SQL, a missing value matches neither a term nor its negation
+        assert!(!matches("genre:jazz"));
+        assert!(!matches("^genre:jazz"));
+        // An empty value is missing too
+        assert!(matches("genre:= albumartist:="));
+        assert!(!matches("^genre:="));
+        // Without the library there are no attributes
+        assert!(matches("attr.source:="));
+        assert!(!matches("attr.source:vinyl") && !matches("^attr.source:vinyl"));
+        assert!(matches_item(&parse("bogus:x").unwrap(), &item, None).is_err());
+        assert!(!super::matches(&parse("bogus:x").unwrap(), &item));
+    }
+
+    #[test]
+    fn test_regex() {
+        let sql = to_sql("genre::^(rock|metal)$", FullTextMode::Fts5).unwrap();
+        assert!(sql.contains("genre REGEXP '^(rock|metal)$'"));
+        let err = to_sql("genre::[a-", FullTextMode::Fts5).unwrap_err();
+        assert!(err.to_string().contains("Invalid regex \"[a-\""), "{err}");
+        assert!(regex("^ROCK$").unwrap().is_match("Rock"));
+        assert!(!regex("(?-i)^ROCK$").unwrap().is_match("Rock"));
+        assert!(Pattern::new("^rock$").regex().unwrap().is_match("Rock"));
+        assert_eq!(Pattern::new("[a-"), Pattern::new("[a-"));
+        assert!(Pattern::new("[a-").regex().is_err());
+    }
+
+    #[test]
+    fn test_attribute_terms() {
+        let attribute = "COALESCE((SELECT value FROM item_attributes \
+                         WHERE item_id = items.id AND key = 'source'), \
+                         (SELECT value FROM album_attributes \
+                         WHERE album_id = items.album_id AND key = 'source'))";
+        let sql = to_sql("attr.source:vinyl", FullTextMode::Fts5).unwrap();
+        assert!(sql.contains(&format!("WHERE {attribute} LIKE '%vinyl%'")), "{sql}");
+        let sql = to_sql("\"attr.source:vinyl rip\" attr.source+", FullTextMode::Fts5).unwrap();
+        assert!(sql.contains(&format!("{attribute} LIKE '%vinyl rip%'")), "{sql}");
+        assert!(sql.ends_with(&format!("ORDER BY {attribute} COLLATE NOCASE ASC, path, id")));
+        let sql = to_sql("^attr.source:=", FullTextMode::Fts5).unwrap();
+        assert!(sql.contains(&format!("NOT (NULLIF({attribute}, '') IS NULL)")), "{sql}");
+
+        // Keys are written into the SQL, so only safe ones are taken
+        let err = to_sql("attr.x'--:y", FullTextMode::Fts5).unwrap_err();
+        assert!(err.to_string().contains("Invalid attribute name"), "{err}");
+        assert!(to_sql("attr.:y", FullTextMode::Fts5).is_err());
     }
 
     #[test]
     fn test_negation() {
-        let sql = to_sql("^genre:jazz").unwrap();
+        let sql = to_sql("^genre:jazz", FullTextMode::Fts5).unwrap();
         assert!(sql.contains("NOT (genre LIKE '%jazz%')"));
     }
 
@@ -295,5 +1371,188 @@ mod tests {
                 ascending: true
             } if field == "year"
         ));
+        assert!(is_unfiltered(&terms));
+        for query in ["", "  ", "( title+ ) artist-"] {
+            assert!(is_unfiltered(&parse(query).unwrap()), "{query:?}");
+        }
+        for query in ["a", "year+ limit:5", "( title:x )"] {
+            assert!(!is_unfiltered(&parse(query).unwrap()), "{query:?}");
+        }
+    }
+
+    fn bookmarks() -> HashMap<String, String> {
+        HashMap::from([
+            ("favorites".to_string(), "genre:rock @recent".to_string()),
+            ("recent".to_string(), "year:2020..".to_string()),
+            ("loop".to_string(), "@loop".to_string()),
+        ])
+    }
+
+    #[test]
+    fn test_nested_bookmark_expansion() {
+        let expanded = expand_bookmarks("@favorites and artist:x", &bookmarks()).unwrap();
+        assert_eq!(expanded, "( genre:rock ( year:2020.. ) ) and artist:x");
+
+        let sql = to_sql(&expanded, FullTextMode::Fts5).unwrap();
+        assert!(sql.contains("(genre LIKE '%rock%' AND (year >= '2020')) AND artist LIKE '%x%'"));
+    }
+
+    #[test]
+    fn test_unknown_bookmark() {
+        let err = expand_bookmarks("@nope", &bookmarks()).unwrap_err();
+        assert!(err.to_string().contains("Unknown bookmark: @nope"));
+    }
+
+    #[test]
+    fn test_recursive_bookmark() {
+        assert!(expand_bookmarks("@loop", &bookmarks()).is_err());
+    }
+
+    #[test]
+    fn test_resolve_default_query() {
+        let with_default = resolve(Some("artist:x"), Some("^genre:jazz"), &bookmarks()).unwrap();
+        assert_eq!(with_default.as_deref(), Some("( ^genre:jazz ) artist:x"));
+
+        // Opting out of the default query leaves the user's query untouched
+        let without = resolve(Some("artist:x"), None, &bookmarks()).unwrap();
+        assert_eq!(without.as_deref(), Some("artist:x"));
+
+        assert_eq!(resolve(None, None, &bookmarks()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_unbalanced_group() {
+        assert!(parse("( artist:x").is_err());
+        assert!(parse("artist:x )").is_err());
+    }
+
+    fn fts_subqueries(sql: &str) -> usize {
+        sql.matches("items_fts MATCH").count()
+    }
+
+    #[test]
+    fn test_full_text_terms_share_one_match() {
+        let sql = to_sql("blue train", FullTextMode::Fts5).unwrap();
+        assert_eq!(fts_subqueries(&sql), 1);
+        assert!(sql.contains(r#"MATCH '"blue" AND "train"'"#));
+
+        let sql = to_sql(r#""blue train" coltrane*"#, FullTextMode::Fts5).unwrap();
+        assert_eq!(fts_subqueries(&sql), 1);
+        assert!(sql.contains(r#"MATCH '"blue train" AND "coltrane"*'"#));
+
+        let sql = to_sql(r#"~"blue train""#, FullTextMode::Fts5).unwrap();
+        assert!(sql.contains(r#"MATCH 'NEAR("blue" "train", 5)'"#));
+
+        let sql = to_sql("blue year:1957 train artist:coltrane", FullTextMode::Fts5).unwrap();
+        assert_eq!(fts_subqueries(&sql), 1);
+        assert!(sql.contains(r#"MATCH '"blue" AND "train"') AND year LIKE '%1957%'"#));
+        // Groups get their own
+        let sql = to_sql("blue ( train )", FullTextMode::Fts5).unwrap();
+        assert_eq!(fts_subqueries(&sql), 2);
+    }
+
+    #[test]
+    fn test_full_text_input_is_quoted() {
+        let sql = to_sql(r#"rock'n'roll OR NEAR( a"b"c -live"#, FullTextMode::Fts5).unwrap();
+        assert!(sql.contains(
+            r#"MATCH '"rock''n''roll" AND "OR" AND "NEAR(" AND "a""b""c" AND "-live"'"#
+        ));
+        assert!(parse(r#"title:"blue train"#).is_err());
+    }
+
+    #[test]
+    fn test_full_text_without_words_uses_like() {
+        let sql = to_sql("!!! blue", FullTextMode::Fts5).unwrap();
+        assert!(sql.contains(r#"MATCH '"blue"'"#));
+        assert!(sql.contains("title LIKE '%!!!%'"));
+        assert!(!sql.contains(r#""!!!""#));
+
+        let sql = to_sql(r#"~"?? !!""#, FullTextMode::Fts5).unwrap();
+        assert_eq!(fts_subqueries(&sql), 0);
+        assert!(sql.contains("title LIKE '%??%'") && sql.contains("title LIKE '%!!%'"));
+    }
+
+    #[test]
+    fn test_parse_quotes() {
+        assert_eq!(
+            parse(r#"~"blue  train" "moment's notice" artist:="john coltrane""#).unwrap(),
+            [
+                QueryTerm::Near("blue  train".into()),
+                QueryTerm::Phrase("moment's notice".into()),
+                QueryTerm::Field {
+                    negated: false,
+                    name: "artist".into(),
+                    op: FieldOp::Exact("john coltrane".into()),
+                },
+            ]
+        );
+        let expanded = expand_bookmarks(r#""@recent" @recent"#, &bookmarks()).unwrap();
+        assert_eq!(expanded, r#""@recent" ( year:2020.. )"#);
+    }
+
+    fn field(name: &str, op: FieldOp) -> QueryTerm {
+        QueryTerm::Field {
+            negated: false,
+            name: name.into(),
+            op,
+        }
+    }
+
+    #[test]
+    fn test_parse_quoted_values() {
+        let substring = |v: &str| FieldOp::Substring(v.into());
+        assert_eq!(
+            parse(r#"title:"Let It Be" "title:Let It Be" album:"Live: At Leeds""#).unwrap(),
+            [
+                field("title", substring("Let It Be")),
+                field("title", substring("Let It Be")),
+                field("album", substring("Live: At Leeds")),
+            ]
+        );
+        // Only the first colon separates the field
+        assert_eq!(parse("album:Live:1969").unwrap(), [field("album", substring("Live:1969"))]);
+        assert_eq!(
+            parse(r#"title:-live "title:=Help!" "Live: At Leeds""#).unwrap(),
+            [
+                field("title", substring("-live")),
+                field("title", FieldOp::Exact("Help!".into())),
+                QueryTerm::Phrase("Live: At Leeds".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_escapes() {
+        assert_eq!(
+            parse(r#"title:"say \"hi\"" artist:AC\:DC \"quoted\" a\ b"#).unwrap(),
+            [
+                field("title", FieldOp::Substring(r#"say "hi""#.into())),
+                field("artist", FieldOp::Substring("AC:DC".into())),
+                QueryTerm::FullText(r#""quoted""#.into()),
+                QueryTerm::FullText("a b".into()),
+            ]
+        );
+        assert_eq!(parse(r"C\:\\music").unwrap(), [QueryTerm::FullText(r"C:\music".into())]);
+        // A trailing backslash escapes nothing and stays
+        let back = FieldOp::Substring(r"back\".into());
+        assert_eq!(parse(r"title:back\").unwrap(), [field("title", back)]);
+        // ...but inside quotes it escapes the closing one
+        assert!(parse(r#"title:"back\""#).is_err());
+    }
+
+    #[test]
+    fn test_quoted_empty_value_matches_missing() {
+        assert_eq!(parse(r#"genre:"""#).unwrap(), [field("genre", FieldOp::Exact(String::new()))]);
+        let sql = to_sql(r#"genre:"""#, FullTextMode::Fts5).unwrap();
+        assert!(sql.contains("NULLIF(genre, '') IS NULL"));
+    }
+
+    #[test]
+    fn test_full_text_like_fallback() {
+        let sql = to_sql("paranoid artist:sabbath", FullTextMode::Like).unwrap();
+        assert!(!sql.contains("items_fts"));
+        assert!(sql.contains("title LIKE '%paranoid%' OR artist LIKE '%paranoid%'"));
+        assert!(uses_full_text(&parse("( paranoid ) year:1970").unwrap()));
+        assert!(!uses_full_text(&parse("artist:sabbath").unwrap()));
     }
 }
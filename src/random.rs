@@ -0,0 +1,147 @@
+//! Random picks of query results, for `random`
+//!
+//! Tracks, or whole albums, are shuffled with [`crate::playlist::shuffle`]
+//! so a seed picks the same again, then taken until there are enough of
+//! them or they last long enough.
+
+use std::collections::HashMap;
+
+use crate::playlist::shuffle;
+use crate::{Error, Item, Result};
+
+/// How much [`pick`] takes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Amount {
+    /// This many tracks, or albums
+    Count(usize),
+    /// Tracks, or albums, until they last at least this many seconds. Only
+    /// the last one picked goes over.
+    Seconds(f64),
+}
+
+/// Parse a `--time` in minutes, which must be a positive, finite number.
+///
+/// # Errors
+/// Returns an error if `arg` isn't one.
+pub fn parse_minutes(arg: &str) -> Result<f64> {
+    match arg.trim().parse::<f64>() {
+        Ok(minutes) if minutes.is_finite() && minutes > 0.0 => Ok(minutes),
+        _ => Err(Error::Config(format!("expected a positive number of minutes, got '{arg}'"))),
+    }
+}
+
+/// Pick `amount` of `items` at random, in the order `seed` shuffles them
+/// into. With `albums`, whole albums are picked and their tracks listed in
+/// disc and track order; items that aren't on an album are left out.
+#[must_use]
+pub fn pick(items: Vec<Item>, amount: Amount, albums: bool, seed: u64) -> Vec<Item> {
+    let mut groups: Vec<Vec<Item>> = if albums {
+        let mut order = Vec::new();
+        let mut by_album: HashMap<i64, Vec<Item>> = HashMap::new();
+        for item in items {
+            let Some(album_id) = item.album_id else {
+                continue;
+            };
+            by_album
+                .entry(album_id)
+                .or_insert_with(|| {
+                    order.push(album_id);
+                    Vec::new()
+                })
+                .push(item);
+        }
+        order.iter().filter_map(|id| by_album.remove(id)).collect()
+    } else {
+        items.into_iter().map(|item| vec![item]).collect()
+    };
+    shuffle(&mut groups, seed);
+
+    let mut picked = Vec::new();
+    let mut length = 0.0;
+    for (count, mut group) in groups.into_iter().enumerate() {
+        let enough = match amount {
+            Amount::Count(wanted) => count >= wanted,
+            Amount::Seconds(wanted) => length >= wanted,
+        };
+        if enough {
+            break;
+        }
+        group.sort_by_key(|item| (item.disc, item.track));
+        length += group.iter().map(|item| item.length).sum::<f64>();
+        picked.extend(group);
+    }
+    picked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+    use crate::AudioFormat;
+
+    fn item(album_id: Option<i64>, track: u32, length: f64) -> Item {
+        Item {
+            album_id,
+            path: format!("/music/{album_id:?}/{track}.flac").into(),
+            title: format!("Track {track}"),
+            artist: "Can".into(),
+            album: "Tago Mago".into(),
+            year: Some(1971),
+            track: Some(track),
+            format: AudioFormat::Flac,
+            bitrate: 900,
+            length,
+            ..testutil::item()
+        }
+    }
+
+    fn tracks(items: &[Item]) -> Vec<(Option<i64>, Option<u32>)> {
+        items.iter().map(|item| (item.album_id, item.track)).collect()
+    }
+
+    #[test]
+    fn test_pick_count_is_seeded() {
+        let items: Vec<Item> = (1..=20).map(|track| item(None, track, 60.0)).collect();
+        let picked = pick(items.clone(), Amount::Count(5), false, 3);
+        assert_eq!(picked.len(), 5);
+        assert_eq!(tracks(&picked), tracks(&pick(items.clone(), Amount::Count(5), false, 3)));
+        assert_eq!(pick(items, Amount::Count(50), false, 3).len(), 20);
+    }
+
+    #[test]
+    fn test_pick_time_goes_over_by_one_track_at_most() {
+        let items: Vec<Item> = (1..=20).map(|track| item(None, track, 150.0)).collect();
+        // 10 minutes: four 2.5-minute tracks make it exactly
+        assert_eq!(pick(items.clone(), Amount::Seconds(600.0), false, 9).len(), 4);
+        assert_eq!(pick(items.clone(), Amount::Seconds(601.0), false, 9).len(), 5);
+        assert!(pick(items, Amount::Seconds(0.0), false, 9).is_empty());
+    }
+
+    #[test]
+    fn test_parse_minutes() {
+        assert_eq!(parse_minutes("90").ok(), Some(90.0));
+        assert_eq!(parse_minutes("2.5").ok(), Some(2.5));
+        for bad in ["0", "-5", "NaN", "inf", "", "an hour"] {
+            assert!(parse_minutes(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn test_pick_whole_albums_in_track_order() {
+        let mut items = Vec::new();
+        for album in 1..=4 {
+            items.extend((1..=3).rev().map(|track| item(Some(album), track, 300.0)));
+        }
+        items.push(item(None, 1, 300.0));
+
+        let picked = pick(items.clone(), Amount::Count(2), true, 5);
+        assert_eq!(picked.len(), 6);
+        let album = picked[0].album_id;
+        assert_eq!(tracks(&picked[..3]), [(album, Some(1)), (album, Some(2)), (album, Some(3))]);
+        assert_ne!(picked[3].album_id, album);
+
+        // 20 minutes: the second 15-minute album goes over
+        assert_eq!(pick(items.clone(), Amount::Seconds(1200.0), true, 5).len(), 6);
+        assert_eq!(pick(items, Amount::Count(9), true, 5).len(), 12);
+    }
+}
@@ -0,0 +1,104 @@
+//! Request rate limiting shared across concurrent tasks
+
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Spaces out requests so that at most one starts per `interval`.
+///
+/// Each caller reserves the next free slot before sleeping, so concurrent
+/// callers queue up behind each other instead of all waking at once.
+pub struct RateLimiter {
+    interval: Duration,
+    next_slot: Mutex<Option<Instant>>,
+}
+
+impl RateLimiter {
+    #[must_use]
+    pub const fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            next_slot: Mutex::new(None),
+        }
+    }
+
+    /// Wait until the caller may issue its next request.
+    pub async fn acquire(&self) {
+        let slot = {
+            let mut next = self.next_slot.lock().unwrap_or_else(PoisonError::into_inner);
+            let now = Instant::now();
+            let slot = next.map_or(now, |n| n.max(now));
+            *next = Some(slot + self.interval);
+            slot
+        };
+
+        tokio::time::sleep_until(slot).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    /// Stand-in for an API client that records when each request was sent.
+    struct MockClient {
+        limiter: RateLimiter,
+        sent: Mutex<Vec<Instant>>,
+    }
+
+    impl MockClient {
+        async fn request(&self) {
+            self.limiter.acquire().await;
+            self.sent.lock().unwrap().push(Instant::now());
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_requests_are_spaced() {
+        const INTERVAL: Duration = Duration::from_millis(50);
+
+        let client = Arc::new(MockClient {
+            limiter: RateLimiter::new(INTERVAL),
+            sent: Mutex::new(Vec::new()),
+        });
+
+        let start = Instant::now();
+        let mut tasks = tokio::task::JoinSet::new();
+        for _ in 0..5 {
+            let client = Arc::clone(&client);
+            tasks.spawn(async move { client.request().await });
+        }
+        while tasks.join_next().await.is_some() {}
+
+        let mut sent = client.sent.lock().unwrap().clone();
+        sent.sort();
+        assert_eq!(sent.len(), 5);
+        // The n-th request can't start before n full intervals have passed
+        for (n, at) in (0u32..).zip(&sent) {
+            assert!(*at >= start + INTERVAL * n);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_poisoned_limiter_keeps_spacing_requests() {
+        const INTERVAL: Duration = Duration::from_millis(50);
+
+        let limiter = Arc::new(RateLimiter::new(INTERVAL));
+        let poisoner = Arc::clone(&limiter);
+        let panicked = std::thread::spawn(move || {
+            let _slot = poisoner.next_slot.lock().unwrap();
+            // Unwinds like a failed request task, without the panic! lint
+            std::panic::resume_unwind(Box::new("request task failed while holding the limiter"));
+        })
+        .join();
+        assert!(panicked.is_err());
+        assert!(limiter.next_slot.is_poisoned());
+
+        let start = Instant::now();
+        limiter.acquire().await;
+        limiter.acquire().await;
+        assert!(Instant::now() >= start + INTERVAL);
+    }
+}
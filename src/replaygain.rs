@@ -0,0 +1,560 @@
+//! `ReplayGain` analysis
+//!
+//! Loudness is measured as EBU R128 / ITU-R BS.1770 integrated loudness:
+//! each channel is K-weighted, the mean square energy is taken over 400 ms
+//! blocks overlapping by 300 ms, and blocks quieter than -70 LUFS, then
+//! those more than 10 LU below the loudness of the rest, are gated out. An
+//! album's loudness gates the blocks of all its tracks together. Gains are
+//! relative to the `ReplayGain` 2.0 reference of -18 LUFS; peaks are sample
+//! peaks.
+//!
+//! Files are decoded by `ffmpeg`, or in-process when built with the
+//! `symphonia` feature.
+
+use std::collections::BTreeSet;
+use std::f64::consts::PI;
+use std::path::Path;
+
+use rayon::prelude::*;
+
+use crate::db::Database;
+use crate::import::ScanProgress;
+use crate::{Error, Item, Result};
+
+/// Loudness that a gain of 0 dB corresponds to, in LUFS.
+pub const REFERENCE_LOUDNESS: f64 = -18.0;
+
+/// Blocks quieter than this, in LUFS, are never counted.
+const ABSOLUTE_GATE: f64 = -70.0;
+/// Blocks this many LU below the loudness of the ungated blocks are ignored.
+const RELATIVE_GATE: f64 = -10.0;
+/// Blocks are this many 100 ms segments long.
+const SEGMENTS_PER_BLOCK: usize = 4;
+
+/// A gain adjustment and the peak it applies to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Gain {
+    /// Adjustment in dB to reach the reference loudness.
+    pub gain: f64,
+    /// Largest absolute sample value, where 1.0 is full scale.
+    pub peak: f64,
+}
+
+/// The measured loudness of a track, or of several combined.
+#[derive(Debug, Clone, Default)]
+pub struct Loudness {
+    /// Mean square energy of each block, channel weights applied.
+    blocks: Vec<f64>,
+    /// Largest absolute sample value.
+    pub peak: f64,
+}
+
+impl Loudness {
+    /// The loudness of `tracks` played one after another, as for an album.
+    pub fn combine<'a>(tracks: impl IntoIterator<Item = &'a Self>) -> Self {
+        tracks.into_iter().fold(Self::default(), |mut all, track| {
+            all.blocks.extend(&track.blocks);
+            all.peak = all.peak.max(track.peak);
+            all
+        })
+    }
+
+    /// Gated integrated loudness in LUFS; `None` for silence, or audio
+    /// shorter than a block.
+    #[must_use]
+    pub fn integrated(&self) -> Option<f64> {
+        let mean_above = |threshold: f64| {
+            let (sum, count) = self
+                .blocks
+                .iter()
+                .filter(|&&energy| loudness(energy) > threshold)
+                .fold((0.0, 0_usize), |(sum, count), energy| (sum + energy, count + 1));
+            (count > 0).then(|| sum / count as f64)
+        };
+        let ungated = mean_above(ABSOLUTE_GATE)?;
+        let threshold = (loudness(ungated) + RELATIVE_GATE).max(ABSOLUTE_GATE);
+        mean_above(threshold).map(loudness)
+    }
+
+    /// The gain bringing this to the reference loudness.
+    #[must_use]
+    pub fn gain(&self) -> Option<Gain> {
+        self.integrated().map(|lufs| Gain {
+            gain: REFERENCE_LOUDNESS - lufs,
+            peak: self.peak,
+        })
+    }
+}
+
+/// Loudness of a block with the given weighted mean square energy, in LUFS.
+fn loudness(energy: f64) -> f64 {
+    10.0_f64.mul_add(energy.log10(), -0.691)
+}
+
+/// A second order IIR filter.
+#[derive(Debug, Clone, Copy)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    state: [f64; 2],
+}
+
+impl Biquad {
+    const fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        Self {
+            b,
+            a,
+            state: [0.0; 2],
+        }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0].mul_add(x, self.state[0]);
+        self.state[0] = self.b[1].mul_add(x, (-self.a[0]).mul_add(y, self.state[1]));
+        self.state[1] = self.b[2].mul_add(x, -self.a[1] * y);
+        y
+    }
+}
+
+/// The BS.1770 K-weighting filter for `sample_rate`: a high shelf modelling
+/// the head, then a high pass. The coefficients are derived from the analog
+/// prototypes so any sample rate works, not only 48 kHz.
+fn k_weighting(sample_rate: f64) -> [Biquad; 2] {
+    let k = (PI * 1_681.974_450_955_53 / sample_rate).tan();
+    let q = 0.707_175_236_955_42;
+    let high_gain = 10.0_f64.powf(3.999_843_853_973_35 / 20.0);
+    let band_gain = high_gain.powf(0.499_666_774_154_542);
+    let a0 = k.mul_add(k, 1.0 + k / q);
+    let shelf = Biquad::new(
+        [
+            k.mul_add(k, high_gain + band_gain * k / q) / a0,
+            2.0 * k.mul_add(k, -high_gain) / a0,
+            k.mul_add(k, high_gain - band_gain * k / q) / a0,
+        ],
+        [2.0 * k.mul_add(k, -1.0) / a0, k.mul_add(k, 1.0 - k / q) / a0],
+    );
+
+    let k = (PI * 38.135_470_876_024_4 / sample_rate).tan();
+    let q = 0.500_327_037_323_877;
+    let a0 = k.mul_add(k, 1.0 + k / q);
+    let high_pass = Biquad::new(
+        [1.0, -2.0, 1.0],
+        [2.0 * k.mul_add(k, -1.0) / a0, k.mul_add(k, 1.0 - k / q) / a0],
+    );
+    [shelf, high_pass]
+}
+
+/// BS.1770 channel weights. Mono counts as both speakers of a stereo pair,
+/// as players output it; in 5.1 the LFE channel is left out and the
+/// surrounds weigh more.
+fn channel_weights(channels: usize) -> Vec<f64> {
+    match channels {
+        1 => vec![2.0],
+        6 => vec![1.0, 1.0, 1.0, 0.0, 1.41, 1.41],
+        n => vec![1.0; n],
+    }
+}
+
+/// Measures loudness from interleaved samples fed to it in any chunks.
+#[derive(Debug)]
+pub struct Meter {
+    filters: Vec<[Biquad; 2]>,
+    weights: Vec<f64>,
+    /// Channel of the next sample.
+    channel: usize,
+    /// Frames in a 100 ms segment.
+    segment_frames: usize,
+    /// Frames so far in the current segment, and their weighted energy.
+    frames: usize,
+    energy: f64,
+    /// Weighted energy of each complete segment.
+    segments: Vec<f64>,
+    peak: f64,
+}
+
+impl Meter {
+    #[must_use]
+    pub fn new(sample_rate: u32, channels: usize) -> Self {
+        let channels = channels.max(1);
+        Self {
+            filters: vec![k_weighting(f64::from(sample_rate)); channels],
+            weights: channel_weights(channels),
+            channel: 0,
+            segment_frames: (sample_rate as usize).div_ceil(10).max(1),
+            frames: 0,
+            energy: 0.0,
+            segments: Vec::new(),
+            peak: 0.0,
+        }
+    }
+
+    /// Feed interleaved samples, continuing where the last call stopped.
+    pub fn push(&mut self, samples: &[f32]) {
+        for &sample in samples {
+            let x = f64::from(sample);
+            self.peak = self.peak.max(x.abs());
+            let [shelf, high_pass] = &mut self.filters[self.channel];
+            let y = high_pass.process(shelf.process(x));
+            self.energy = (self.weights[self.channel] * y).mul_add(y, self.energy);
+
+            self.channel += 1;
+            if self.channel == self.filters.len() {
+                self.channel = 0;
+                self.frames += 1;
+                if self.frames == self.segment_frames {
+                    self.segments.push(self.energy);
+                    self.frames = 0;
+                    self.energy = 0.0;
+                }
+            }
+        }
+    }
+
+    /// The loudness of everything pushed. A trailing partial segment is
+    /// left out.
+    #[must_use]
+    pub fn finish(self) -> Loudness {
+        let block_frames = (SEGMENTS_PER_BLOCK * self.segment_frames) as f64;
+        Loudness {
+            blocks: self
+                .segments
+                .windows(SEGMENTS_PER_BLOCK)
+                .map(|window| window.iter().sum::<f64>() / block_frames)
+                .collect(),
+            peak: self.peak,
+        }
+    }
+}
+
+/// Items measured together: the tracks of an album, or a track on its own.
+#[derive(Debug)]
+pub struct Group {
+    pub items: Vec<Item>,
+    /// Whether the items are an album and get album gain.
+    pub album: bool,
+}
+
+/// Group the albums of `items`, with all their tracks since album gain
+/// covers the whole album, and the items without an album on their own.
+/// Unless `force` is set, albums whose tracks all have album gain and
+/// single items with track gain are left out. Returns the groups and the
+/// number of items left out.
+///
+/// # Errors
+/// Returns an error if an album's tracks can't be loaded.
+pub fn groups(db: &Database, items: Vec<Item>, force: bool) -> Result<(Vec<Group>, usize)> {
+    let mut groups = Vec::new();
+    let mut albums = BTreeSet::new();
+    let mut skipped = 0;
+    for item in items {
+        match item.album_id {
+            Some(album_id) => {
+                albums.insert(album_id);
+            }
+            None if force || item.rg_track_gain.is_none() => groups.push(Group {
+                items: vec![item],
+                album: false,
+            }),
+            None => skipped += 1,
+        }
+    }
+    for album_id in albums {
+        let items = db.album_items(album_id)?;
+        if force || items.iter().any(|item| item.rg_album_gain.is_none()) {
+            groups.push(Group { items, album: true });
+        } else {
+            skipped += items.len();
+        }
+    }
+    Ok((groups, skipped))
+}
+
+/// What analysis found for one item.
+#[derive(Debug)]
+pub struct Analyzed {
+    pub item: Item,
+    /// The track's gain, or why it has none.
+    pub track: Result<Gain>,
+    /// The album's gain, when the item is on an album and every track of
+    /// it could be decoded.
+    pub album: Option<Gain>,
+}
+
+/// Measure every item of `groups`, decoding files in parallel.
+pub fn analyze(groups: Vec<Group>, progress: &impl ScanProgress) -> Vec<Analyzed> {
+    let items: Vec<&Item> = groups.iter().flat_map(|group| &group.items).collect();
+    progress.on_files_found(items.len());
+    let measured: Vec<Result<Loudness>> = items
+        .par_iter()
+        .map(|item| {
+            let loudness = measure_file(&item.path);
+            progress.tick();
+            loudness
+        })
+        .collect();
+
+    let mut measured = measured.into_iter();
+    let mut analyzed = Vec::new();
+    for group in groups {
+        let loudness: Vec<_> = measured.by_ref().take(group.items.len()).collect();
+        let album = if group.album && loudness.iter().all(Result::is_ok) {
+            Loudness::combine(loudness.iter().flatten()).gain()
+        } else {
+            None
+        };
+        for (item, loudness) in group.items.into_iter().zip(loudness) {
+            let track = loudness.and_then(|loudness| {
+                loudness.gain().ok_or_else(|| {
+                    Error::ReplayGain(format!(
+                        "{} is silent or too short to measure",
+                        item.path.display()
+                    ))
+                })
+            });
+            if let Err(e) = &track {
+                progress.on_error(&item.path, e);
+            }
+            analyzed.push(Analyzed { item, track, album });
+        }
+    }
+    progress.finish(analyzed.len());
+    analyzed
+}
+
+/// Decode the file at `path` and measure its loudness.
+///
+/// # Errors
+/// Returns an error if the file can't be decoded.
+#[cfg(feature = "symphonia")]
+pub fn measure_file(path: &Path) -> Result<Loudness> {
+    use symphonia::core::audio::SampleBuffer;
+    use symphonia::core::codecs::DecoderOptions;
+    use symphonia::core::errors::Error as DecodeError;
+    use symphonia::core::formats::FormatOptions;
+    use symphonia::core::io::{MediaSourceStream, MediaSourceStreamOptions};
+    use symphonia::core::meta::MetadataOptions;
+    use symphonia::core::probe::Hint;
+
+    let failed = |e: DecodeError| {
+        Error::ReplayGain(format!("Can't decode {}: {e}", path.display()))
+    };
+    let file = std::fs::File::open(path)?;
+    let stream = MediaSourceStream::new(Box::new(file), MediaSourceStreamOptions::default());
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|e| e.to_str()) {
+        hint.with_extension(extension);
+    }
+    let mut format = symphonia::default::get_probe()
+        .format(&hint, stream, &FormatOptions::default(), &MetadataOptions::default())
+        .map_err(failed)?
+        .format;
+    let track = format.default_track().ok_or_else(|| {
+        Error::ReplayGain(format!("{} has no audio track", path.display()))
+    })?;
+    let track_id = track.id;
+    let mut decoder = symphonia::default::get_codecs()
+        .make(&track.codec_params, &DecoderOptions::default())
+        .map_err(failed)?;
+
+    let mut meter = None;
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(DecodeError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                break;
+            }
+            Err(e) => return Err(failed(e)),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            // A corrupt packet is skipped, as players do
+            Err(DecodeError::DecodeError(_)) => continue,
+            Err(e) => return Err(failed(e)),
+        };
+        let spec = *decoded.spec();
+        let mut samples = SampleBuffer::<f32>::new(decoded.capacity() as u64, spec);
+        samples.copy_interleaved_ref(decoded);
+        meter
+            .get_or_insert_with(|| Meter::new(spec.rate, spec.channels.count()))
+            .push(samples.samples());
+    }
+    meter
+        .map(Meter::finish)
+        .ok_or_else(|| Error::ReplayGain(format!("{} has no audio", path.display())))
+}
+
+/// Program used to decode files.
+#[cfg(not(feature = "symphonia"))]
+const FFMPEG: &str = "ffmpeg";
+
+/// Decode the file at `path` with `ffmpeg` and measure its loudness.
+///
+/// # Errors
+/// Returns an error if `ffmpeg` is missing or can't decode the file.
+#[cfg(not(feature = "symphonia"))]
+pub fn measure_file(path: &Path) -> Result<Loudness> {
+    use std::process::{Command, Stdio};
+
+    let mut child = Command::new(FFMPEG)
+        .args(["-v", "error", "-nostdin", "-i"])
+        .arg(path)
+        .args(["-vn", "-f", "wav", "-acodec", "pcm_f32le", "-"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| {
+            if e.kind() == std::io::ErrorKind::NotFound {
+                Error::ReplayGain(format!(
+                    "{FFMPEG} not found; install it or build with the symphonia feature"
+                ))
+            } else {
+                Error::ReplayGain(format!("Failed to run {FFMPEG}: {e}"))
+            }
+        })?;
+    let measured = child.stdout.take().map(measure_wav);
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(Error::ReplayGain(format!(
+            "{FFMPEG} failed on {}: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    match measured {
+        Some(Ok(loudness)) => Ok(loudness),
+        Some(Err(e)) => Err(Error::ReplayGain(format!(
+            "Unexpected {FFMPEG} output for {}: {e}",
+            path.display()
+        ))),
+        None => Err(Error::ReplayGain(format!("No output from {FFMPEG}"))),
+    }
+}
+
+/// Measure a WAV stream of 32-bit float samples, as `ffmpeg` writes it to
+/// a pipe: the data chunk's size is unknown, so samples are read to the end.
+#[cfg(not(feature = "symphonia"))]
+fn measure_wav(mut reader: impl std::io::Read) -> std::io::Result<Loudness> {
+    use std::io::{Error as IoError, ErrorKind};
+
+    let mut riff = [0; 12];
+    reader.read_exact(&mut riff)?;
+    if &riff[..4] != b"RIFF" || &riff[8..] != b"WAVE" {
+        return Err(IoError::new(ErrorKind::InvalidData, "not a WAV stream"));
+    }
+    let mut format = None;
+    loop {
+        let mut header = [0; 8];
+        reader.read_exact(&mut header)?;
+        let size = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        if &header[..4] == b"data" {
+            break;
+        }
+        // Chunks are padded to an even length
+        let mut chunk = vec![0; size as usize + size as usize % 2];
+        reader.read_exact(&mut chunk)?;
+        if &header[..4] == b"fmt " && chunk.len() >= 8 {
+            let channels = u16::from_le_bytes([chunk[2], chunk[3]]);
+            let rate = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]);
+            format = Some((rate, usize::from(channels)));
+        }
+    }
+    let (rate, channels) =
+        format.ok_or_else(|| IoError::new(ErrorKind::InvalidData, "no fmt chunk"))?;
+
+    let mut meter = Meter::new(rate, channels);
+    let mut buf = vec![0; 1 << 16];
+    let mut filled = 0;
+    let mut samples = Vec::new();
+    loop {
+        let read = reader.read(&mut buf[filled..])?;
+        if read == 0 {
+            break;
+        }
+        filled += read;
+        let whole = filled - filled % 4;
+        samples.clear();
+        samples.extend(
+            buf[..whole]
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+        );
+        meter.push(&samples);
+        buf.copy_within(whole..filled, 0);
+        filled -= whole;
+    }
+    Ok(meter.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `seconds` of a 1 kHz sine with peak `amplitude` in every channel.
+    fn sine(sample_rate: u32, channels: usize, amplitude: f32, seconds: f32) -> Vec<f32> {
+        let frames = (sample_rate as f32 * seconds) as usize;
+        (0..frames)
+            .flat_map(|n| {
+                let t = n as f32 / sample_rate as f32;
+                let sample = amplitude * (2.0 * std::f32::consts::PI * 1000.0 * t).sin();
+                vec![sample; channels]
+            })
+            .collect()
+    }
+
+    fn measure(sample_rate: u32, channels: usize, samples: &[f32]) -> Loudness {
+        let mut meter = Meter::new(sample_rate, channels);
+        // Chunks that split frames mustn't matter
+        for chunk in samples.chunks(1001) {
+            meter.push(chunk);
+        }
+        meter.finish()
+    }
+
+    fn assert_near(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 0.1, "{actual} is not {expected}");
+    }
+
+    #[test]
+    fn test_sine_matches_reference_loudness() {
+        // EBU Tech 3341 case 1: a stereo 1 kHz sine at -23 dBFS is -23 LUFS
+        let amplitude = 10_f32.powf(-23.0 / 20.0);
+        for rate in [44_100, 48_000, 96_000] {
+            let loudness = measure(rate, 2, &sine(rate, 2, amplitude, 5.0));
+            assert_near(loudness.integrated().unwrap(), -23.0);
+            let gain = loudness.gain().unwrap();
+            assert_near(gain.gain, 5.0);
+            assert!((gain.peak - f64::from(amplitude)).abs() < 1e-3);
+        }
+
+        // Mono plays on both speakers
+        let mono = measure(48_000, 1, &sine(48_000, 1, amplitude, 5.0));
+        assert_near(mono.integrated().unwrap(), -23.0);
+    }
+
+    #[test]
+    fn test_album_gates_blocks_of_all_tracks() {
+        let loud = measure(48_000, 2, &sine(48_000, 2, 10_f32.powf(-23.0 / 20.0), 5.0));
+        let quiet = measure(48_000, 2, &sine(48_000, 2, 10_f32.powf(-33.0 / 20.0), 5.0));
+        let album = Loudness::combine([&loud, &quiet]);
+        // The quiet track is within 10 LU of the mean, so both count:
+        // 10 * log10((1 + 0.1) / 2) below the loud one
+        assert_near(album.integrated().unwrap(), -25.6);
+        assert!((album.peak - loud.peak).abs() < f64::EPSILON);
+
+        let quieter = measure(48_000, 2, &sine(48_000, 2, 10_f32.powf(-50.0 / 20.0), 5.0));
+        let album = Loudness::combine([&loud, &quieter]);
+        // Too quiet relative to the rest, so gated out
+        assert_near(album.integrated().unwrap(), -23.0);
+    }
+
+    #[test]
+    fn test_silence_and_short_audio_have_no_gain() {
+        assert!(measure(48_000, 2, &vec![0.0; 48_000 * 2 * 3]).gain().is_none());
+        let short = sine(48_000, 2, 0.5, 0.3);
+        assert!(measure(48_000, 2, &short).gain().is_none());
+        assert!(Loudness::default().integrated().is_none());
+    }
+}
@@ -0,0 +1,542 @@
+//! Import runs
+//!
+//! Every `import` is a run (shown to users as a session) with a generated
+//! id, recorded on each album and item it adds. Each file it puts into the
+//! library is written to the import log: where it came from, how, and the
+//! size and modification time it had then. `rsbts sessions` lists the runs
+//! and `rsbts undo` takes one back out of the library: its rows are
+//! removed, files it moved are moved back to where they were imported from,
+//! and copies, links and the cover art it saved are deleted. A file changed
+//! since it was imported is left alone, with its item. Fields the run filled
+//! in on albums already in the library are recorded too, and cleared again.
+
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use crate::db::{timestamp, Database, SqlPath, StoredPath};
+use crate::fields::{album_value, FieldEdit};
+use crate::hooks::{Event, Hooks};
+use crate::import::Action;
+use crate::prune::Pruner;
+use crate::{Error, Result};
+
+/// One invocation of `import`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImportRun {
+    pub id: String,
+    pub started: DateTime<Utc>,
+    /// How files were transferred, which decides how they are undone.
+    pub action: Action,
+}
+
+impl ImportRun {
+    /// A run starting now, with an id made from the start time.
+    #[must_use]
+    pub fn new(action: Action) -> Self {
+        let started = Utc::now();
+        // The library lock keeps two imports from starting together; the
+        // pid tells apart runs started within the same second anyway
+        let id = format!(
+            "{}-{:04x}",
+            started.format("%Y%m%d-%H%M%S"),
+            std::process::id() & 0xffff
+        );
+        Self {
+            id,
+            started,
+            action,
+        }
+    }
+}
+
+/// A run with what it added, for listing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunSummary {
+    pub run: ImportRun,
+    pub albums: u64,
+    pub items: u64,
+    /// Directories the run's albums were imported from.
+    pub sources: Vec<PathBuf>,
+}
+
+/// A file an import run put into the library, as the import log has it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogEntry {
+    pub run: String,
+    pub logged: DateTime<Utc>,
+    /// How the file got there: `None` for one the import wrote itself, such
+    /// as cover art.
+    pub action: Option<Action>,
+    /// Where it came from, or where in an archive.
+    pub source: Option<PathBuf>,
+    pub dest: PathBuf,
+    pub item_id: Option<i64>,
+    pub album_id: Option<i64>,
+    /// The file's modification time and size once imported, to tell
+    /// whether it has changed since.
+    pub mtime: Option<DateTime<Utc>>,
+    pub size: Option<u64>,
+}
+
+impl LogEntry {
+    /// An entry for the file `run` has just put at `dest`, as it is now.
+    #[must_use]
+    pub fn new(
+        run: &ImportRun,
+        action: Option<Action>,
+        source: Option<PathBuf>,
+        dest: PathBuf,
+    ) -> Self {
+        let metadata = std::fs::metadata(&dest).ok();
+        Self {
+            run: run.id.clone(),
+            logged: Utc::now(),
+            action,
+            source,
+            item_id: None,
+            album_id: None,
+            mtime: metadata.as_ref().and_then(|m| m.modified().ok()).map(Into::into),
+            size: metadata.map(|m| m.len()),
+            dest,
+        }
+    }
+
+    /// Whether the file at `dest` differs from the one imported, going by
+    /// size and modification time to the second. One that can't be read
+    /// counts as changed.
+    fn changed(&self) -> bool {
+        let Ok(metadata) = std::fs::metadata(&self.dest) else {
+            return true;
+        };
+        let mtime = metadata.modified().ok().map(DateTime::<Utc>::from);
+        self.size.is_some_and(|size| size != metadata.len())
+            || self
+                .mtime
+                .is_some_and(|logged| mtime.map(|m| m.timestamp()) != Some(logged.timestamp()))
+    }
+}
+
+/// A field an import run filled in on an album it added tracks to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fill {
+    pub album_id: i64,
+    pub field: String,
+    /// The value it was given, as text.
+    pub value: String,
+}
+
+/// What [`undo`] did.
+#[derive(Debug, Default)]
+pub struct UndoReport {
+    /// Item rows removed.
+    pub items: u64,
+    /// Album rows removed.
+    pub albums: u64,
+    /// Files moved back to their source paths.
+    pub restored: u64,
+    /// Copies and links deleted from the library.
+    pub deleted: u64,
+    /// Cover art files the run saved, deleted.
+    pub art: u64,
+    /// Album fields the run filled in, cleared again.
+    pub cleared: u64,
+    /// Files that could not be restored or deleted, with the reason. Their
+    /// items stay in the library, so the undo can be retried.
+    pub failed: Vec<(PathBuf, String)>,
+}
+
+/// Record `run` unless it already is. Called as each album is added, so a
+/// run that imports nothing leaves no trace.
+///
+/// # Errors
+/// Returns an error if the insert fails.
+pub fn record(conn: &Connection, run: &ImportRun) -> Result<()> {
+    conn.execute(
+        "INSERT OR IGNORE INTO import_runs (id, started, action) VALUES (?1, ?2, ?3)",
+        params![run.id, timestamp(&run.started), run.action.as_str()],
+    )?;
+    Ok(())
+}
+
+/// Look up a run by id.
+///
+/// # Errors
+/// Returns an error if the query fails.
+pub fn get(conn: &Connection, id: &str) -> Result<Option<ImportRun>> {
+    let row = conn
+        .query_row(
+            "SELECT id, started, action FROM import_runs WHERE id = ?1",
+            [id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .optional()?;
+    row.map(|(id, started, action): (String, String, String)| {
+        run_from_row(id, &started, &action)
+    })
+    .transpose()
+}
+
+/// All runs with their album and item counts, newest first.
+///
+/// # Errors
+/// Returns an error if the query fails.
+pub fn list(conn: &Connection) -> Result<Vec<RunSummary>> {
+    let mut stmt = conn.prepare(
+        "SELECT r.id, r.started, r.action,
+                (SELECT COUNT(*) FROM albums WHERE import_run = r.id),
+                (SELECT COUNT(*) FROM items WHERE import_run = r.id)
+         FROM import_runs r ORDER BY r.started DESC, r.id DESC",
+    )?;
+    let rows = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get(3)?,
+                row.get(4)?,
+            ))
+        })?
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+
+    let mut sources = conn.prepare(
+        "SELECT DISTINCT source_path FROM albums
+         WHERE import_run = ?1 AND source_path IS NOT NULL ORDER BY source_path",
+    )?;
+    rows.into_iter()
+        .map(|(id, started, action, albums, items)| {
+            let paths = sources
+                .query_map([&id], |row| row.get::<_, StoredPath>(0))?
+                .map(|path| path.map(|path| path.0))
+                .collect::<std::result::Result<Vec<_>, _>>()?;
+            Ok(RunSummary {
+                run: run_from_row(id, &started, &action)?,
+                albums,
+                items,
+                sources: paths,
+            })
+        })
+        .collect()
+}
+
+/// Record a file `entry.run` put into the library.
+///
+/// # Errors
+/// Returns an error if the insert fails.
+pub fn log(conn: &Connection, entry: &LogEntry) -> Result<()> {
+    conn.execute(
+        "INSERT INTO import_log (import_run, logged_at, action, source_path, dest_path, item_id,
+                                 album_id, mtime, size)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        params![
+            entry.run,
+            timestamp(&entry.logged),
+            entry.action.map_or(WRITTEN, Action::as_str),
+            entry.source.as_deref().map(SqlPath),
+            SqlPath(&entry.dest),
+            entry.item_id,
+            entry.album_id,
+            entry.mtime.as_ref().map(timestamp),
+            entry.size,
+        ],
+    )?;
+    Ok(())
+}
+
+/// Record the fields `edits` run `run` filled in on album `album_id`.
+///
+/// # Errors
+/// Returns an error if an insert fails.
+pub fn log_fills(conn: &Connection, run: &str, album_id: i64, edits: &[FieldEdit]) -> Result<()> {
+    let mut stmt = conn.prepare(
+        "INSERT INTO import_fills (import_run, album_id, field, value) VALUES (?1, ?2, ?3, ?4)",
+    )?;
+    for edit in edits {
+        stmt.execute(params![run, album_id, edit.field(), edit.value().to_string()])?;
+    }
+    Ok(())
+}
+
+/// The album fields the run `id` filled in, in the order it did.
+///
+/// # Errors
+/// Returns an error if the query fails.
+pub fn fills(conn: &Connection, id: &str) -> Result<Vec<Fill>> {
+    let mut stmt = conn.prepare(
+        "SELECT album_id, field, value FROM import_fills WHERE import_run = ?1 ORDER BY id",
+    )?;
+    let fills = stmt
+        .query_map([id], |row| {
+            Ok(Fill {
+                album_id: row.get(0)?,
+                field: row.get(1)?,
+                value: row.get(2)?,
+            })
+        })?
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    Ok(fills)
+}
+
+/// The files the run `id` put into the library, in the order it did.
+///
+/// # Errors
+/// Returns an error if the query fails.
+pub fn log_entries(conn: &Connection, id: &str) -> Result<Vec<LogEntry>> {
+    let mut stmt = conn.prepare(
+        "SELECT import_run, logged_at, action, source_path, dest_path, item_id, album_id, mtime,
+                size
+         FROM import_log WHERE import_run = ?1 ORDER BY id",
+    )?;
+    let rows = stmt
+        .query_map([id], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, Option<StoredPath>>(3)?,
+                row.get::<_, StoredPath>(4)?,
+                row.get(5)?,
+                row.get(6)?,
+                row.get::<_, Option<String>>(7)?,
+                row.get(8)?,
+            ))
+        })?
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    rows.into_iter()
+        .map(|(run, logged, action, source, dest, item_id, album_id, mtime, size)| {
+            let action = if action == WRITTEN {
+                None
+            } else {
+                Some(parse_action(&run, &action)?)
+            };
+            Ok(LogEntry {
+                logged: parse_time(&logged),
+                action,
+                source: source.map(|path| path.0),
+                dest: dest.0,
+                item_id,
+                album_id,
+                mtime: mtime.as_deref().map(parse_time),
+                size,
+                run,
+            })
+        })
+        .collect()
+}
+
+/// Take the run `id` back out of the library.
+///
+/// Files the run moved are moved back to their source paths, never over an
+/// existing file. Copies and links it made are deleted, as long as what
+/// they were made from is still there, and so is the cover art it saved.
+/// A file that has changed since it was imported is left, as are files
+/// imported where they already were. Items whose file can't be handled
+/// keep their rows, as do their albums and the run itself. Fields the run
+/// filled in on an album it added tracks to are cleared once none of those
+/// tracks are left, unless they have been changed since. With `pruner`,
+/// library directories left empty are removed. `hooks` are run for each
+/// item taken out of the library.
+///
+/// # Errors
+/// Returns an error if there is no such run or the database can't be
+/// updated.
+pub fn undo(
+    db: &Database,
+    id: &str,
+    pruner: Option<&Pruner>,
+    hooks: &Hooks,
+) -> Result<UndoReport> {
+    if db.import_run(id)?.is_none() {
+        return Err(Error::Import(format!("No import session {id} (see `rsbts sessions`)")));
+    }
+    let log = db.import_log(id)?;
+
+    let mut report = UndoReport::default();
+    let mut dirs = Vec::new();
+    let mut kept_albums = Vec::new();
+    for item in db.import_run_items(id)? {
+        let Some(item_id) = item.id else {
+            continue;
+        };
+        let entry = log.iter().find(|entry| entry.item_id == Some(item_id));
+        match entry.map_or(Ok(FileUndo::Left), undo_file) {
+            Ok(FileUndo::Restored) => report.restored += 1,
+            Ok(FileUndo::Deleted) => report.deleted += 1,
+            Ok(FileUndo::Left) => {}
+            Err(e) => {
+                kept_albums.extend(item.album_id);
+                report.failed.push((item.path, e.to_string()));
+                continue;
+            }
+        }
+        db.remove_item(item_id)?;
+        hooks.item(Event::ItemRemoved, &item);
+        report.items += 1;
+        dirs.extend(item.path.parent().map(Path::to_path_buf));
+    }
+
+    // Cover art the run saved, and cue sheets it brought along with rips
+    for entry in log.iter().filter(|entry| entry.item_id.is_none()) {
+        match undo_file(entry) {
+            Ok(FileUndo::Deleted) if entry.action.is_none() => {
+                report.art += 1;
+                if let Some(album_id) = entry.album_id {
+                    db.clear_album_artpath(album_id, &entry.dest)?;
+                }
+                dirs.extend(entry.dest.parent().map(Path::to_path_buf));
+            }
+            Ok(FileUndo::Left) => {}
+            Ok(_) => dirs.extend(entry.dest.parent().map(Path::to_path_buf)),
+            Err(e) => report.failed.push((entry.dest.clone(), e.to_string())),
+        }
+    }
+
+    for fill in db.import_fills(id)? {
+        if kept_albums.contains(&fill.album_id) {
+            continue;
+        }
+        let Some(album) = db.get_album(fill.album_id)? else {
+            continue;
+        };
+        if album_value(&album, &fill.field).to_string() == fill.value {
+            let clear = FieldEdit::parse_album(&format!("{}=", fill.field))?;
+            db.modify_album(fill.album_id, &[clear])?;
+            report.cleared += 1;
+        }
+    }
+
+    report.albums = db.forget_import_run(id)?;
+    if let Some(pruner) = pruner {
+        pruner.prune(dirs);
+    }
+    Ok(report)
+}
+
+/// Remove the run's albums that no longer have items, and the run itself
+/// with its log and fills once nothing of it is left. Returns the number of albums
+/// removed.
+///
+/// # Errors
+/// Returns an error if a delete fails.
+pub fn forget(conn: &Connection, id: &str) -> Result<u64> {
+    let tx = conn.unchecked_transaction()?;
+    let albums = tx.execute(
+        "DELETE FROM albums WHERE import_run = ?1
+         AND id NOT IN (SELECT album_id FROM items WHERE album_id IS NOT NULL)",
+        [id],
+    )?;
+    let forgotten = tx.execute(
+        "DELETE FROM import_runs WHERE id = ?1
+         AND NOT EXISTS (SELECT 1 FROM items WHERE import_run = ?1)
+         AND NOT EXISTS (SELECT 1 FROM albums WHERE import_run = ?1)",
+        [id],
+    )?;
+    if forgotten > 0 {
+        tx.execute("DELETE FROM import_log WHERE import_run = ?1", [id])?;
+        tx.execute("DELETE FROM import_fills WHERE import_run = ?1", [id])?;
+    }
+    tx.commit()?;
+    Ok(albums as u64)
+}
+
+/// How the import log marks files the import wrote rather than transferred.
+const WRITTEN: &str = "write";
+
+enum FileUndo {
+    Restored,
+    Deleted,
+    /// Nothing to do: a file already gone, or one with nowhere to go back to.
+    Left,
+}
+
+/// Put back the file `entry` logged: move it back to its source if it was
+/// moved there, and otherwise delete it, unless it is the only copy.
+fn undo_file(entry: &LogEntry) -> std::io::Result<FileUndo> {
+    let Ok(metadata) = std::fs::symlink_metadata(&entry.dest) else {
+        return Ok(FileUndo::Left);
+    };
+    // Removing a link loses nothing, however its target has changed
+    if metadata.file_type().is_symlink() {
+        std::fs::remove_file(&entry.dest)?;
+        return Ok(FileUndo::Deleted);
+    }
+    // Imported where it already was, so there is nothing to put back
+    let dest = std::fs::canonicalize(&entry.dest)?;
+    let source = entry.source.as_deref().and_then(|source| std::fs::canonicalize(source).ok());
+    if source == Some(dest) {
+        return Ok(FileUndo::Left);
+    }
+    if entry.changed() {
+        return Err(std::io::Error::other("changed since it was imported"));
+    }
+    let deletable = match (entry.action, entry.source.as_deref()) {
+        // A file imported from an archive is still in it, like a copy's source
+        (Some(Action::Move), Some(source)) if !in_archive(source) => {
+            move_file(&entry.dest, source)?;
+            return Ok(FileUndo::Restored);
+        }
+        (Some(Action::Move), None) => return Ok(FileUndo::Left),
+        // Cover art, fetched or read from the source, is only in the library
+        (None, _) => true,
+        (Some(_), source) => source.is_some_and(|source| source.exists() || in_archive(source)),
+    };
+    if !deletable {
+        return Err(std::io::Error::other(
+            "what it was imported from is gone, so this is the only copy",
+        ));
+    }
+    std::fs::remove_file(&entry.dest)?;
+    Ok(FileUndo::Deleted)
+}
+
+/// Whether `source` names a file inside an archive, as the sources of
+/// files imported from one do: some directory above it is a file.
+fn in_archive(source: &Path) -> bool {
+    source.ancestors().skip(1).any(Path::is_file)
+}
+
+/// Move a file, creating the destination's directory but never replacing an
+/// existing file.
+pub(crate) fn move_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    if to.exists() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("{} already exists", to.display()),
+        ));
+    }
+    if let Some(parent) = to.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    if std::fs::rename(from, to).is_err() {
+        std::fs::copy(from, to)?;
+        std::fs::remove_file(from)?;
+    }
+    Ok(())
+}
+
+fn run_from_row(id: String, started: &str, action: &str) -> Result<ImportRun> {
+    let action = parse_action(&id, action)?;
+    Ok(ImportRun {
+        started: parse_time(started),
+        id,
+        action,
+    })
+}
+
+fn parse_action(run: &str, action: &str) -> Result<Action> {
+    match action {
+        "copy" => Ok(Action::Copy),
+        "move" => Ok(Action::Move),
+        "link" => Ok(Action::Link),
+        "hardlink" => Ok(Action::HardLink),
+        "reflink" => Ok(Action::Reflink),
+        _ => Err(Error::Import(format!("Run {run} has unknown action '{action}'"))),
+    }
+}
+
+fn parse_time(s: &str) -> DateTime<Utc> {
+    DateTime::parse_from_rfc3339(s).map_or(DateTime::UNIX_EPOCH, |dt| dt.with_timezone(&Utc))
+}
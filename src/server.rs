@@ -0,0 +1,366 @@
+//! A read-only HTTP API over the library, to browse and play it from another
+//! device (`rsbts serve`, with the `server` feature)
+//!
+//! - `GET /items?query=...&limit=N&offset=N`: matching tracks, as `ls` finds
+//!   them
+//! - `GET /items/{id}`: one track
+//! - `GET /items/{id}/file`: the track's audio file; `Range` requests get the
+//!   part asked for, so a player can seek
+//! - `GET /albums?query=...`: matching albums, as `ls --album` finds them
+//! - `GET /albums/{id}/art?size=small|medium|orig`: the album's art, through
+//!   an [`ArtCache`]
+//! - `GET /stats?query=...`: totals, as `stats --json` prints them
+//!
+//! Tracks, albums and stats are JSON; errors are `{"error": "..."}`. With a
+//! token, every request must carry it as `Authorization: Bearer <token>`, or
+//! as `?token=` where no header can be set, as in an `<audio>` tag's URL.
+//!
+//! [`Database`] holds one `SQLite` connection, which two threads can't use at
+//! once. The server keeps it behind a mutex rather than opening a connection
+//! per request: a `Database` carries settings (default sort, path mappings,
+//! full-text mode) each new connection would have to be given again, and
+//! queries over a local library are short. Each runs on a blocking thread
+//! while holding the lock. Files are sent after it's released, so a slow
+//! download never holds up other requests.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use axum::body::Body;
+use axum::extract::{Path, Query, Request, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use log::info;
+use serde::Deserialize;
+use tower_http::services::ServeFile;
+
+use crate::art::{ArtCache, ArtSize, DEFAULT_MAX_RESIZES};
+use crate::db::{Database, Stats};
+use crate::query::Page;
+use crate::{Album, Error, Item, Result};
+
+/// What every request shares.
+struct Server {
+    db: Mutex<Database>,
+    art: ArtCache,
+    token: Option<String>,
+    queries: Queries,
+}
+
+/// What completes a request's query, as `ls` completes one on the command
+/// line.
+#[derive(Debug, Clone, Default)]
+pub struct Queries {
+    /// Query `AND`ed onto every request's, as `ui.default_query`.
+    pub default_query: Option<String>,
+    /// `@name` bookmarks, expanded in queries.
+    pub bookmarks: HashMap<String, String>,
+}
+
+impl Server {
+    /// The request's query with the default query and bookmarks applied.
+    fn resolve(&self, query: Option<&str>) -> ApiResult<Option<String>> {
+        let queries = &self.queries;
+        let default_query = queries.default_query.as_deref();
+        Ok(crate::query::resolve(query, default_query, &queries.bookmarks)?)
+    }
+
+    /// Run `f` on a blocking thread with the database to itself.
+    async fn with_db<T, F>(self: &Arc<Self>, f: F) -> std::result::Result<T, ApiError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Database) -> Result<T> + Send + 'static,
+    {
+        let server = Arc::clone(self);
+        tokio::task::spawn_blocking(move || {
+            // A panic while it was held left nothing half-written: all
+            // requests only read
+            let db = server.db.lock().unwrap_or_else(PoisonError::into_inner);
+            f(&db).map_err(ApiError::from)
+        })
+        .await
+        .map_err(|e| ApiError::Internal(e.to_string()))?
+    }
+}
+
+type Shared = Arc<Server>;
+
+/// Query string parameters, each used by some of the endpoints.
+#[derive(Debug, Default, Deserialize)]
+struct Params {
+    query: Option<String>,
+    limit: Option<u64>,
+    #[serde(default)]
+    offset: u64,
+    size: Option<String>,
+    token: Option<String>,
+}
+
+/// Why a request failed, which decides the status it's answered with.
+#[derive(Debug)]
+enum ApiError {
+    NotFound,
+    Unauthorized,
+    BadRequest(String),
+    Internal(String),
+}
+
+impl From<Error> for ApiError {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Query(_) => Self::BadRequest(e.to_string()),
+            Error::Io(io) if io.kind() == std::io::ErrorKind::NotFound => Self::NotFound,
+            _ => Self::Internal(e.to_string()),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let (status, message) = match self {
+            Self::NotFound => (StatusCode::NOT_FOUND, "Not found".to_string()),
+            Self::Unauthorized => (StatusCode::UNAUTHORIZED, "Missing or wrong token".to_string()),
+            Self::BadRequest(message) => (StatusCode::BAD_REQUEST, message),
+            Self::Internal(message) => (StatusCode::INTERNAL_SERVER_ERROR, message),
+        };
+        (status, Json(serde_json::json!({ "error": message }))).into_response()
+    }
+}
+
+type ApiResult<T> = std::result::Result<T, ApiError>;
+
+/// The API's routes over `db`, with thumbnails of album art cached in
+/// `art_cache` and, with a `token`, only for requests that carry it.
+/// Queries are completed by `queries`.
+pub fn router(db: Database, art_cache: PathBuf, token: Option<String>, queries: Queries) -> Router {
+    let server = Arc::new(Server {
+        db: Mutex::new(db),
+        art: ArtCache::new(art_cache, DEFAULT_MAX_RESIZES),
+        token,
+        queries,
+    });
+    Router::new()
+        .route("/items", get(items))
+        .route("/items/{id}", get(item))
+        .route("/items/{id}/file", get(item_file))
+        .route("/albums", get(albums))
+        .route("/albums/{id}/art", get(album_art))
+        .route("/stats", get(stats))
+        .route_layer(middleware::from_fn_with_state(Arc::clone(&server), authorize))
+        .with_state(server)
+}
+
+/// Serve `router` on `bind`, an address and port, until Ctrl-C.
+///
+/// # Errors
+/// Returns an error if `bind` can't be listened on.
+pub async fn serve(router: Router, bind: &str) -> Result<()> {
+    let listener = tokio::net::TcpListener::bind(bind).await?;
+    info!("Serving the library on http://{}", listener.local_addr()?);
+    axum::serve(listener, router)
+        .with_graceful_shutdown(async {
+            let _ = tokio::signal::ctrl_c().await;
+        })
+        .await?;
+    Ok(())
+}
+
+/// Turn away requests without the token, if there is one.
+async fn authorize(State(server): State<Shared>, request: Request, next: Next) -> Response {
+    let Some(token) = server.token.as_deref() else {
+        return next.run(request).await;
+    };
+    let bearer = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let param = Query::<Params>::try_from_uri(request.uri()).ok().and_then(|q| q.0.token);
+    let matches = |given: Option<&str>| given.is_some_and(|given| same_token(given, token));
+    if matches(bearer) || matches(param.as_deref()) {
+        next.run(request).await
+    } else {
+        ApiError::Unauthorized.into_response()
+    }
+}
+
+/// Whether `given` is `token`, compared in time that doesn't depend on where
+/// they first differ, so the token can't be guessed a byte at a time.
+fn same_token(given: &str, token: &str) -> bool {
+    let diff = given.bytes().zip(token.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b));
+    std::hint::black_box(diff) == 0 && given.len() == token.len()
+}
+
+async fn items(
+    State(server): State<Shared>,
+    Query(params): Query<Params>,
+) -> ApiResult<Json<Vec<Item>>> {
+    let page = Page {
+        limit: params.limit,
+        offset: params.offset,
+    };
+    let query = server.resolve(params.query.as_deref())?;
+    let items = server.with_db(move |db| db.query_items_page(query.as_deref(), page));
+    Ok(Json(items.await?))
+}
+
+async fn item(State(server): State<Shared>, Path(id): Path<i64>) -> ApiResult<Json<Item>> {
+    let item = server.with_db(move |db| db.get_item(id)).await?;
+    item.map(Json).ok_or(ApiError::NotFound)
+}
+
+/// The track's file, or the part of it a `Range` header asks for, with its
+/// type guessed from the extension.
+async fn item_file(
+    State(server): State<Shared>,
+    Path(id): Path<i64>,
+    request: Request,
+) -> ApiResult<Response> {
+    let item = server.with_db(move |db| db.get_item(id)).await?;
+    let item = item.ok_or(ApiError::NotFound)?;
+    let response = ServeFile::new(&item.path)
+        .try_call(request)
+        .await
+        .map_err(|e| ApiError::from(Error::Io(e)))?;
+    Ok(response.map(Body::new))
+}
+
+async fn albums(
+    State(server): State<Shared>,
+    Query(params): Query<Params>,
+) -> ApiResult<Json<Vec<Album>>> {
+    let query = server.resolve(params.query.as_deref())?;
+    let albums = server.with_db(move |db| db.query_albums(query.as_deref()));
+    Ok(Json(albums.await?))
+}
+
+/// The album's art in the size asked for, or `304 Not Modified` if the
+/// client's copy, named by `If-None-Match`, is current.
+async fn album_art(
+    State(server): State<Shared>,
+    Path(id): Path<i64>,
+    Query(params): Query<Params>,
+    headers: HeaderMap,
+) -> ApiResult<Response> {
+    let size = params.size.as_deref().map(str::parse::<ArtSize>).transpose();
+    let size = size.map_err(|e| ApiError::BadRequest(e.to_string()))?.unwrap_or_default();
+    let album = server.with_db(move |db| db.get_album(id)).await?;
+    let artpath = album.and_then(|album| album.artpath).ok_or(ApiError::NotFound)?;
+    let art = server.art.get(&artpath, size).await?;
+
+    let if_none_match = headers.get(header::IF_NONE_MATCH).and_then(|v| v.to_str().ok());
+    if if_none_match.is_some_and(|tags| art.matches(tags)) {
+        return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, art.etag)]).into_response());
+    }
+    let headers = [(header::CONTENT_TYPE, art.content_type.to_string()), (header::ETAG, art.etag)];
+    Ok((headers, art.bytes).into_response())
+}
+
+async fn stats(
+    State(server): State<Shared>,
+    Query(params): Query<Params>,
+) -> ApiResult<Json<Stats>> {
+    let query = server.resolve(params.query.as_deref())?;
+    let stats = server.with_db(move |db| {
+        query.as_deref().map_or_else(|| db.stats(), |query| db.query_stats(query))
+    });
+    Ok(Json(stats.await?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+    use crate::AudioFormat;
+
+    fn track(title: &str, path: PathBuf) -> Item {
+        Item {
+            path,
+            title: title.into(),
+            artist: "Black Sabbath".into(),
+            album: "Paranoid".into(),
+            year: Some(1970),
+            format: AudioFormat::Flac,
+            bitrate: 900,
+            length: 180.0,
+            ..testutil::item()
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_requests() {
+        let dir = std::env::temp_dir().join(format!("rsbts-server-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("War Pigs.flac");
+        std::fs::write(&file, b"0123456789").unwrap();
+        let db = Database::open_in_memory().unwrap();
+        db.migrate().unwrap();
+        let id = db.insert_item(&track("War Pigs", file)).unwrap();
+        for n in 0..20 {
+            let path = dir.join(format!("{n}.flac"));
+            db.insert_item(&track(&format!("Track {n}"), path)).unwrap();
+        }
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let queries = Queries {
+            default_query: None,
+            bookmarks: HashMap::from([("war".into(), "title:war".into())]),
+        };
+        let app = router(db, dir.join("art"), Some("secret".into()), queries);
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let client = reqwest::Client::new();
+        let get = |path: &str| client.get(format!("{base}{path}")).bearer_auth("secret");
+
+        let unauthorized = client.get(format!("{base}/stats")).send().await.unwrap();
+        assert_eq!(unauthorized.status(), reqwest::StatusCode::UNAUTHORIZED);
+        let wrong = client.get(format!("{base}/stats")).bearer_auth("secreT").send();
+        assert_eq!(wrong.await.unwrap().status(), reqwest::StatusCode::UNAUTHORIZED);
+
+        // Queries take turns at the database without failing or mixing up
+        let requests = (0..64).map(|n| {
+            let request = match n % 4 {
+                0 => get("/items").query(&[("query", "@war")]),
+                1 => get("/stats"),
+                2 => get(&format!("/items/{id}")),
+                _ => get("/items").query(&[("limit", "5"), ("offset", "3")]),
+            };
+            async move {
+                let response = request.send().await.unwrap();
+                assert!(response.status().is_success(), "{}", response.status());
+                (n % 4, response.json::<serde_json::Value>().await.unwrap())
+            }
+        });
+        let handles: Vec<_> = requests.map(tokio::spawn).collect();
+        for handle in handles {
+            let (kind, json) = handle.await.unwrap();
+            match kind {
+                0 => assert_eq!(json[0]["title"], "War Pigs"),
+                1 => assert_eq!(json["tracks"], 21),
+                2 => assert_eq!(json["id"], id),
+                _ => assert_eq!(json.as_array().map(Vec::len), Some(5)),
+            }
+        }
+
+        let url = format!("{base}/items/{id}/file?token=secret");
+        let part = client.get(&url).header("Range", "bytes=2-5").send().await.unwrap();
+        assert_eq!(part.status(), reqwest::StatusCode::PARTIAL_CONTENT);
+        assert_eq!(part.bytes().await.unwrap().as_ref(), b"2345");
+        let missing = get("/items/999").send().await.unwrap();
+        assert_eq!(missing.status(), reqwest::StatusCode::NOT_FOUND);
+        let bad = get("/items").query(&[("query", "( artist:x")]).send().await.unwrap();
+        assert_eq!(bad.status(), reqwest::StatusCode::BAD_REQUEST);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    #[test]
+    fn test_same_token() {
+        assert!(same_token("secret", "secret"));
+        assert!(!same_token("secreT", "secret"));
+        assert!(!same_token("secret2", "secret"));
+        assert!(!same_token("", "secret"));
+    }
+}
@@ -0,0 +1,132 @@
+//! Comparing names from tags with names from `MusicBrainz`
+//!
+//! The same name is often spelled differently on each side: accents dropped
+//! ("Bjork"), the article moved to the end ("Rolling Stones, The"), "&" for
+//! "and", typographic quotes, full-width letters. [`normalize`] reduces a
+//! string to a key without those differences, and [`similarity`] scores two
+//! strings by their keys. Keys are only compared; the original strings are
+//! what gets stored.
+
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+/// Articles dropped from the start of a name, or from the end after a comma.
+const ARTICLES: &[&str] = &["the", "a", "an"];
+
+/// `s` as a key for comparing: compatibility-decomposed with accents
+/// removed, lowercased, "&" read as "and", apostrophes dropped and other
+/// punctuation and whitespace collapsed to single spaces, without a leading
+/// article or one after a final comma. A string that would have no key left,
+/// such as "!!!", is only lowercased and trimmed.
+#[must_use]
+pub fn normalize(s: &str) -> String {
+    let folded: String = s
+        .nfkd()
+        .filter(|c| !is_combining_mark(*c))
+        .flat_map(char::to_lowercase)
+        .collect();
+    let name = match folded.rsplit_once(',') {
+        Some((head, tail)) if ARTICLES.contains(&tail.trim()) => head,
+        _ => folded.as_str(),
+    };
+
+    let mut words: Vec<String> = Vec::new();
+    let mut word = String::new();
+    for c in name.chars() {
+        match c {
+            c if c.is_alphanumeric() => word.push(c),
+            '\'' | '\u{2018}' | '\u{2019}' | '\u{02bc}' | '`' => {}
+            '&' => {
+                end_word(&mut words, &mut word);
+                words.push("and".into());
+            }
+            _ => end_word(&mut words, &mut word),
+        }
+    }
+    end_word(&mut words, &mut word);
+
+    if words.len() > 1 && ARTICLES.contains(&words[0].as_str()) {
+        words.remove(0);
+    }
+    if words.is_empty() {
+        return s.trim().to_lowercase();
+    }
+    words.join(" ")
+}
+
+fn end_word(words: &mut Vec<String>, word: &mut String) {
+    if !word.is_empty() {
+        words.push(std::mem::take(word));
+    }
+}
+
+/// Jaro-Winkler similarity (0..1) of the keys of `a` and `b`.
+#[must_use]
+pub fn similarity(a: &str, b: &str) -> f64 {
+    strsim::jaro_winkler(&normalize(a), &normalize(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn same(a: &str, b: &str) -> bool {
+        normalize(a) == normalize(b)
+    }
+
+    #[test]
+    fn test_accents_and_case() {
+        assert!(same("Björk", "Bjork"));
+        assert!(same("Motörhead", "MOTORHEAD"));
+        assert!(same("Sigur Rós", "sigur ros"));
+        assert_eq!(normalize("Beyoncé"), "beyonce");
+    }
+
+    #[test]
+    fn test_articles() {
+        assert!(same("The Rolling Stones", "Rolling Stones, The"));
+        assert!(same("The Rolling Stones", "Rolling Stones"));
+        assert!(same("A Tribe Called Quest", "Tribe Called Quest, A"));
+        // A name that is only an article keeps it
+        assert_eq!(normalize("The The"), "the");
+        assert_eq!(normalize("The"), "the");
+        // Only a leading article goes
+        assert_eq!(normalize("Under the Bridge"), "under the bridge");
+    }
+
+    #[test]
+    fn test_ampersand_and_punctuation() {
+        assert!(same("Simon & Garfunkel", "Simon and Garfunkel"));
+        assert!(same("Simon&Garfunkel", "simon and garfunkel"));
+        assert!(same("Don’t Stop Me Now", "Don't Stop Me Now"));
+        assert!(same("“Heroes”", "\"Heroes\""));
+        assert!(same("Sgt. Pepper's  Lonely Hearts", "Sgt Peppers Lonely Hearts"));
+        assert!(same("Live/Dead", "Live - Dead"));
+        assert_eq!(normalize("  AC/DC  "), "ac dc");
+    }
+
+    #[test]
+    fn test_full_width_and_compatibility_forms() {
+        assert!(same("ＹＥＬＬＯＷ ＭＡＧＩＣ", "Yellow Magic"));
+        assert!(same("Ｒａｄｉｏｈｅａｄ", "Radiohead"));
+        assert!(same("ﬁnal ﬂight", "final flight"));
+        // Scripts without case or accents are kept as they are
+        assert_eq!(normalize("坂本龍一"), "坂本龍一");
+    }
+
+    #[test]
+    fn test_nothing_left_falls_back_to_lowercase() {
+        assert_eq!(normalize("!!!"), "!!!");
+        assert_eq!(normalize(" ?! "), "?!");
+        assert_eq!(normalize(""), "");
+    }
+
+    #[test]
+    fn test_similarity_uses_keys() {
+        assert!((similarity("Björk", "Bjork") - 1.0).abs() < f64::EPSILON);
+        assert!((similarity("The Beatles", "Beatles, The") - 1.0).abs() < f64::EPSILON);
+        let raw = strsim::jaro_winkler("Motörhead", "Motorhead");
+        assert!(similarity("Motörhead", "Motorhead") > raw);
+        assert!(similarity("Paranoid", "Master of Reality") < 0.7);
+    }
+}
@@ -1,29 +1,186 @@
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::ops::BitOr;
 use std::path::Path;
 
 use chrono::Utc;
-use lofty::file::{AudioFile, TaggedFileExt};
+use lofty::config::{ParseOptions, WriteOptions};
+use lofty::error::ErrorKind;
+use lofty::file::{AudioFile, FileType, TaggedFileExt};
+use lofty::picture::{MimeType, Picture, PictureType};
 use lofty::probe::Probe;
-use lofty::tag::Accessor;
+use lofty::properties::FileProperties;
+use lofty::tag::{Accessor, ItemKey, Tag};
+use log::warn;
 
+use crate::fields::{FieldEdit, Value};
+use crate::replaygain::Gain;
 use crate::{AudioFormat, Item, Result};
 
+/// Number of leading bytes covered by the partial content hash.
+const CONTENT_HASH_BYTES: u64 = 64 * 1024;
+
+/// Track numbers above this are encoder junk rather than real positions.
+const MAX_TRACK: u32 = 999;
+/// Disc numbers above this are placeholders, such as 255.
+const MAX_DISC: u32 = 99;
+
+/// The tag keys `MusicBrainz` ids are read from, with the TXXX description
+/// some taggers use for each that lofty doesn't map to the key.
+const MB_ID_KEYS: [(ItemKey, &str); 3] = [
+    (ItemKey::MusicBrainzRecordingId, "MusicBrainz Track Id"),
+    (ItemKey::MusicBrainzReleaseId, "MUSICBRAINZ_ALBUMID"),
+    (ItemKey::MusicBrainzReleaseGroupId, "MUSICBRAINZ_RELEASEGROUPID"),
+];
+
+/// Abstraction over opening files, so callers can observe or redirect file access.
+pub trait FileOps: Sync {
+    /// Open a file for reading.
+    ///
+    /// # Errors
+    /// Returns an error if the file cannot be opened.
+    fn open(&self, path: &Path) -> std::io::Result<File>;
+}
+
+/// File access backed directly by the filesystem.
+pub struct StdFileOps;
+
+impl FileOps for StdFileOps {
+    fn open(&self, path: &Path) -> std::io::Result<File> {
+        File::open(path)
+    }
+}
+
+/// Set of features to extract in a single [`analyze_file`] pass.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct AnalyzeOptions(u8);
+
+impl AnalyzeOptions {
+    /// Read metadata tags (title, artist, album, ...).
+    pub const TAGS: Self = Self(1);
+    /// Read audio properties (bitrate, duration).
+    pub const PROPERTIES: Self = Self(1 << 1);
+    /// Extract embedded cover art.
+    pub const EMBEDDED_ART: Self = Self(1 << 2);
+    /// Hash the leading bytes of the file.
+    pub const CONTENT_HASH: Self = Self(1 << 3);
+
+    #[must_use]
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    #[must_use]
+    pub const fn all() -> Self {
+        Self(Self::TAGS.0 | Self::PROPERTIES.0 | Self::EMBEDDED_ART.0 | Self::CONTENT_HASH.0)
+    }
+
+    #[must_use]
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for AnalyzeOptions {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// Everything extracted from one audio file in a single open.
+#[derive(Debug, Clone)]
+pub struct FileAnalysis {
+    /// Item built from tags and properties; fields not requested keep their fallbacks.
+    pub item: Item,
+    /// Embedded cover art bytes, preferring the front cover.
+    pub embedded_art: Option<Vec<u8>>,
+    /// FNV-1a hash of the first 64 KiB of the file.
+    pub content_hash: Option<u64>,
+}
+
+impl FileAnalysis {
+    #[must_use]
+    pub const fn has_embedded_art(&self) -> bool {
+        self.embedded_art.is_some()
+    }
+}
+
 /// Read audio metadata tags from a file.
 ///
 /// # Errors
 /// Returns an error if the file cannot be read or probed for tags.
 pub fn read_tags(path: &Path) -> Result<Item> {
-    let tagged_file = Probe::open(path)?.read()?;
+    analyze_file(
+        path,
+        AnalyzeOptions::TAGS | AnalyzeOptions::PROPERTIES,
+        &StdFileOps,
+    )
+    .map(|analysis| analysis.item)
+}
+
+/// Open a file once and extract every feature requested in `options`.
+///
+/// # Errors
+/// Returns an error if the file cannot be opened, read, or probed.
+pub fn analyze_file<F: FileOps + ?Sized>(
+    path: &Path,
+    options: AnalyzeOptions,
+    ops: &F,
+) -> Result<FileAnalysis> {
+    let file = ops.open(path)?;
+    let metadata = file.metadata()?;
+    let mtime = metadata.modified()?.into();
+    let size = metadata.len();
+    let mut reader = BufReader::new(file);
 
-    let properties = tagged_file.properties();
-    let tag = tagged_file.primary_tag().or_else(|| tagged_file.first_tag());
+    let content_hash = if options.contains(AnalyzeOptions::CONTENT_HASH) {
+        let hash = partial_hash(&mut reader)?;
+        reader.seek(SeekFrom::Start(0))?;
+        Some(hash)
+    } else {
+        None
+    };
+
+    let parse_options = ParseOptions::new()
+        .read_tags(options.contains(AnalyzeOptions::TAGS))
+        .read_properties(options.contains(AnalyzeOptions::PROPERTIES))
+        .read_cover_art(options.contains(AnalyzeOptions::EMBEDDED_ART));
+
+    let probe = match FileType::from_path(path) {
+        Some(file_type) => Probe::with_file_type(reader, file_type),
+        None => Probe::new(reader).guess_file_type()?,
+    };
+    // Files lofty can't parse, such as WMA, fall back to names below
+    let tagged_file = match probe.options(parse_options).read() {
+        Ok(tagged_file) => Some(tagged_file),
+        Err(e) if matches!(e.kind(), ErrorKind::UnknownFormat) => None,
+        Err(e) => return Err(e.into()),
+    };
+
+    let no_properties = FileProperties::default();
+    let properties = tagged_file.as_ref().map_or(&no_properties, AudioFile::properties);
+    let tag = tagged_file.as_ref().and_then(|f| f.primary_tag().or_else(|| f.first_tag()));
+
+    let embedded_art = if options.contains(AnalyzeOptions::EMBEDDED_ART) {
+        tagged_file.iter().flat_map(TaggedFileExt::tags).find_map(|tag| {
+            let pictures = tag.pictures();
+            pictures
+                .iter()
+                .find(|p| p.pic_type() == PictureType::CoverFront)
+                .or_else(|| pictures.first())
+                .map(|p| p.data().to_vec())
+        })
+    } else {
+        None
+    };
 
     let format = path
         .extension()
         .and_then(|e| e.to_str())
         .map_or(AudioFormat::Unknown, AudioFormat::from_extension);
 
-    let mtime = std::fs::metadata(path)?.modified()?.into();
-
     let (title, artist, album, albumartist, genre, year, track, disc) = tag.map_or_else(
         || {
             (
@@ -42,8 +199,10 @@ pub fn read_tags(path: &Path) -> Result<Item> {
                 tag.title().map(|s| s.to_string()).unwrap_or_default(),
                 tag.artist().map(|s| s.to_string()).unwrap_or_default(),
                 tag.album().map(|s| s.to_string()).unwrap_or_default(),
-                tag.get_string(&lofty::tag::ItemKey::AlbumArtist).map(String::from),
-                tag.genre().map(|s| s.to_string()),
+                tag.get_string(&lofty::tag::ItemKey::AlbumArtist)
+                    .filter(|s| !s.is_empty())
+                    .map(String::from),
+                tag.genre().filter(|s| !s.is_empty()).map(|s| s.to_string()),
                 tag.year(),
                 tag.track(),
                 tag.disk(),
@@ -51,31 +210,48 @@ pub fn read_tags(path: &Path) -> Result<Item> {
         },
     );
 
-    // Use filename as title if missing
-    let title = if title.is_empty() {
-        path.file_stem()
-            .and_then(|s| s.to_str())
-            .unwrap_or("Unknown")
-            .to_string()
+    // Fill in what the tags lack from the file and directory names
+    let dir = path
+        .parent()
+        .and_then(Path::file_name)
+        .and_then(|s| s.to_str())
+        .and_then(guess_from_dir_name);
+    let (dir_artist, dir_album, dir_year) =
+        dir.map_or((None, None, None), |d| (Some(d.artist), Some(d.album), d.year));
+    let (title, track, name_artist) = if title.is_empty() {
+        let name = path.file_stem().and_then(|s| s.to_str()).map(guess_from_file_name);
+        name.map_or_else(
+            || ("Unknown".to_string(), track, None),
+            |name| (name.title, track.or(name.track), name.artist),
+        )
     } else {
-        title
+        (title, track, None)
     };
+    let artist = Some(artist)
+        .filter(|s| !s.is_empty())
+        .or(name_artist)
+        .or(dir_artist)
+        .unwrap_or_else(|| "Unknown Artist".to_string());
+    let album = Some(album)
+        .filter(|s| !s.is_empty())
+        .or(dir_album)
+        .unwrap_or_else(|| "Unknown Album".to_string());
 
-    let artist = if artist.is_empty() {
-        "Unknown Artist".to_string()
-    } else {
-        artist
-    };
+    let [artist_sort, albumartist_sort] =
+        [ItemKey::TrackArtistSortOrder, ItemKey::AlbumArtistSortOrder].map(|key| {
+            let sort = tag.and_then(|tag| tag.get_string(&key)).map(str::trim);
+            sort.filter(|s| !s.is_empty()).map(String::from)
+        });
 
-    let album = if album.is_empty() {
-        "Unknown Album".to_string()
-    } else {
-        album
-    };
+    // As written by Picard, or by rsbts itself after a match
+    let [mb_trackid, mb_albumid, mb_releasegroupid] =
+        MB_ID_KEYS.map(|(key, fallback)| tag.and_then(|tag| mb_id(tag, &key, fallback)));
 
-    let year = year.map(|y| i32::try_from(y).unwrap_or(0));
+    let year = year.map(|y| i32::try_from(y).unwrap_or(0)).or(dir_year);
+    let track = position(track, MAX_TRACK, "track", path);
+    let disc = position(disc, MAX_DISC, "disc", path);
 
-    Ok(Item {
+    let item = Item {
         id: None,
         album_id: None,
         path: path.to_path_buf(),
@@ -83,6 +259,8 @@ pub fn read_tags(path: &Path) -> Result<Item> {
         artist,
         album,
         albumartist,
+        artist_sort,
+        albumartist_sort,
         genre,
         year,
         track,
@@ -90,21 +268,593 @@ pub fn read_tags(path: &Path) -> Result<Item> {
         format,
         bitrate: properties.audio_bitrate().unwrap_or(0),
         length: properties.duration().as_secs_f64(),
-        mb_trackid: None,
-        mb_albumid: None,
+        samplerate: properties.sample_rate(),
+        channels: properties.channels(),
+        bitdepth: properties.bit_depth(),
+        mb_trackid,
+        mb_albumid,
+        mb_releasegroupid,
         added: Utc::now(),
         mtime,
+        size: Some(size),
+        source_path: None,
+        import_run: None,
+        rg_track_gain: None,
+        rg_track_peak: None,
+        rg_album_gain: None,
+        rg_album_peak: None,
+        rating: None,
+        play_count: 0,
+        last_played: None,
+    };
+
+    Ok(FileAnalysis {
+        item,
+        embedded_art,
+        content_hash,
+    })
+}
+
+/// The `MusicBrainz` id `tag` has under `key`, or else in a TXXX frame
+/// described as `fallback`.
+fn mb_id(tag: &Tag, key: &ItemKey, fallback: &str) -> Option<String> {
+    tag.get_string(key)
+        .or_else(|| tag.get_string(&ItemKey::Unknown(fallback.into())))
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(String::from)
+}
+
+/// What the name of an untagged file says about it.
+#[derive(Debug, PartialEq, Eq)]
+struct FileNameGuess {
+    title: String,
+    track: Option<u32>,
+    artist: Option<String>,
+}
+
+/// Split a file name (without extension) like `07 - Title`, `07. Title`,
+/// `07_Title`, `07 Title` or `Artist - 07 - Title` into its parts. Names
+/// that don't look like that are the title as they are.
+fn guess_from_file_name(stem: &str) -> FileNameGuess {
+    let stem = stem.trim();
+    if let [artist, number, title] = stem.splitn(3, " - ").collect::<Vec<_>>()[..] {
+        if let Some(track) = track_number(number) {
+            if !artist.trim().is_empty() && !title.trim().is_empty() {
+                return FileNameGuess {
+                    title: clean_title(title),
+                    track: Some(track),
+                    artist: Some(artist.trim().to_string()),
+                };
+            }
+        }
+    }
+    match split_track_prefix(stem) {
+        Some((track, title)) => FileNameGuess {
+            title: clean_title(title),
+            track: Some(track),
+            artist: None,
+        },
+        None => FileNameGuess {
+            title: stem.to_string(),
+            track: None,
+            artist: None,
+        },
+    }
+}
+
+/// A leading track number and the title after it. The separator must
+/// include `-`, `.` or `_`, or be a single space after a zero-padded
+/// number, so titles such as "99 Luftballons" or "2112" stay whole. Without
+/// a space, a digit can't follow it either ("1.5 Hours").
+fn split_track_prefix(stem: &str) -> Option<(u32, &str)> {
+    let digits = stem.bytes().take_while(u8::is_ascii_digit).count();
+    let (number, rest) = stem.split_at(digits);
+    let track = track_number(number)?;
+    let title = rest.trim_start_matches([' ', '-', '.', '_']);
+    let separator = &rest[..rest.len() - title.len()];
+    let accepted = if separator.contains(['-', '.', '_']) {
+        separator.contains(' ') || !title.starts_with(|c: char| c.is_ascii_digit())
+    } else {
+        separator == " " && number.len() > 1 && number.starts_with('0')
+    };
+    (accepted && !title.trim().is_empty()).then_some((track, title))
+}
+
+/// A track number of one to three digits, other than 0.
+fn track_number(s: &str) -> Option<u32> {
+    let s = s.trim();
+    if s.is_empty() || s.len() > 3 || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    s.parse().ok().filter(|&n| n > 0)
+}
+
+/// `title` trimmed, with underscores read as spaces if it has no spaces.
+fn clean_title(title: &str) -> String {
+    let title = title.trim();
+    if title.contains(' ') {
+        title.to_string()
+    } else {
+        title.replace('_', " ").trim().to_string()
+    }
+}
+
+/// What the name of the directory holding a file says about its album.
+#[derive(Debug, PartialEq, Eq)]
+struct DirGuess {
+    artist: String,
+    album: String,
+    year: Option<i32>,
+}
+
+/// Split a directory name like `Artist - Album`, `Artist - Album (1997)`,
+/// `Artist - Album [1997]` or `Artist - 1997 - Album` into its parts.
+fn guess_from_dir_name(name: &str) -> Option<DirGuess> {
+    let (artist, rest) = name.split_once(" - ")?;
+    let rest = rest.trim();
+    let leading_year = rest
+        .split_once(" - ")
+        .and_then(|(year, album)| Some((album.trim(), Some(year_number(year)?))));
+    let (album, year) = leading_year.unwrap_or_else(|| split_year_suffix(rest));
+    let artist = artist.trim();
+    (!artist.is_empty() && !album.is_empty()).then(|| DirGuess {
+        artist: artist.to_string(),
+        album: album.to_string(),
+        year,
     })
 }
 
+/// `s` without a trailing `(1997)` or `[1997]`, and the year in it.
+fn split_year_suffix(s: &str) -> (&str, Option<i32>) {
+    for (open, close) in [('(', ')'), ('[', ']')] {
+        let Some((album, year)) = s.strip_suffix(close).and_then(|s| s.rsplit_once(open)) else {
+            continue;
+        };
+        if let Some(year) = year_number(year) {
+            return (album.trim(), Some(year));
+        }
+    }
+    (s, None)
+}
+
+/// A four-digit year.
+fn year_number(s: &str) -> Option<i32> {
+    let s = s.trim();
+    if s.len() == 4 && s.bytes().all(|b| b.is_ascii_digit()) {
+        s.parse().ok()
+    } else {
+        None
+    }
+}
+
+/// Write `edits` into the file's primary tag, creating the tag if the file
+/// has none. Cleared fields are removed from the tag.
+///
+/// # Errors
+/// Returns an error if the file cannot be read, or the tag cannot be written.
+pub fn write_tags(path: &Path, edits: &[FieldEdit]) -> Result<()> {
+    let mut tagged_file = Probe::open(path)?.read()?;
+    let tag_type = tagged_file.primary_tag_type();
+    let mut tag = tagged_file
+        .remove(tag_type)
+        .unwrap_or_else(|| Tag::new(tag_type));
+
+    for edit in edits {
+        let key = match edit.field() {
+            "albumartist" => ItemKey::AlbumArtist,
+            "artist_sort" => ItemKey::TrackArtistSortOrder,
+            "albumartist_sort" => ItemKey::AlbumArtistSortOrder,
+            "mb_trackid" => ItemKey::MusicBrainzRecordingId,
+            "mb_albumid" => ItemKey::MusicBrainzReleaseId,
+            "mb_releasegroupid" => ItemKey::MusicBrainzReleaseGroupId,
+            field => {
+                set_accessor(&mut tag, field, edit.value());
+                continue;
+            }
+        };
+        match edit.value() {
+            Value::Text(text) => {
+                tag.insert_text(key, text.clone());
+            }
+            _ => tag.remove_key(&key),
+        }
+    }
+
+    tagged_file.insert_tag(tag);
+    tagged_file.save_to_path(path, WriteOptions::default())?;
+    Ok(())
+}
+
+/// Make `art` the front cover in the file's primary tag, in place of any
+/// it had, creating the tag if the file has none.
+///
+/// # Errors
+/// Returns an error if the file cannot be read, or the tag cannot be written.
+pub fn embed_art(path: &Path, art: &[u8]) -> Result<()> {
+    let mut tagged_file = Probe::open(path)?.read()?;
+    let tag_type = tagged_file.primary_tag_type();
+    let mut tag = tagged_file
+        .remove(tag_type)
+        .unwrap_or_else(|| Tag::new(tag_type));
+
+    let mime_type = match crate::art::content_type(art) {
+        "image/png" => MimeType::Png,
+        _ => MimeType::Jpeg,
+    };
+    tag.remove_picture_type(PictureType::CoverFront);
+    tag.push_picture(Picture::new_unchecked(
+        PictureType::CoverFront,
+        Some(mime_type),
+        None,
+        art.to_vec(),
+    ));
+
+    tagged_file.insert_tag(tag);
+    tagged_file.save_to_path(path, WriteOptions::default())?;
+    Ok(())
+}
+
+/// Write `ReplayGain` values into the file's primary tag, creating the tag
+/// if the file has none. Missing values are removed from the tag.
+///
+/// # Errors
+/// Returns an error if the file cannot be read, or the tag cannot be written.
+pub fn write_replaygain(path: &Path, track: Option<Gain>, album: Option<Gain>) -> Result<()> {
+    let mut tagged_file = Probe::open(path)?.read()?;
+    let tag_type = tagged_file.primary_tag_type();
+    let mut tag = tagged_file
+        .remove(tag_type)
+        .unwrap_or_else(|| Tag::new(tag_type));
+
+    for (value, gain_key, peak_key) in [
+        (track, ItemKey::ReplayGainTrackGain, ItemKey::ReplayGainTrackPeak),
+        (album, ItemKey::ReplayGainAlbumGain, ItemKey::ReplayGainAlbumPeak),
+    ] {
+        match value {
+            Some(Gain { gain, peak }) => {
+                tag.insert_text(gain_key, format!("{gain:.2} dB"));
+                tag.insert_text(peak_key, format!("{peak:.6}"));
+            }
+            None => {
+                tag.remove_key(&gain_key);
+                tag.remove_key(&peak_key);
+            }
+        }
+    }
+
+    tagged_file.insert_tag(tag);
+    tagged_file.save_to_path(path, WriteOptions::default())?;
+    Ok(())
+}
+
+/// A track or disc number from a tag. Some encoders write 0 for "unknown"
+/// and placeholders like disc 255; both become `None`, the latter with a
+/// warning.
+fn position(n: Option<u32>, max: u32, what: &str, path: &Path) -> Option<u32> {
+    match n {
+        Some(n) if n > max => {
+            warn!("Ignoring {what} number {n} in {}", path.display());
+            None
+        }
+        n => n.filter(|&n| n > 0),
+    }
+}
+
+/// Set or remove a field that lofty's [`Accessor`] covers.
+fn set_accessor(tag: &mut Tag, field: &str, value: &Value) {
+    // Out-of-range numbers were accepted by the registry but can't be tagged
+    let number = |n: i64| u32::try_from(n).unwrap_or_default();
+    match (field, value) {
+        ("title", Value::Text(text)) => tag.set_title(text.clone()),
+        ("artist", Value::Text(text)) => tag.set_artist(text.clone()),
+        ("album", Value::Text(text)) => tag.set_album(text.clone()),
+        ("genre", Value::Text(text)) => tag.set_genre(text.clone()),
+        ("genre", _) => tag.remove_genre(),
+        ("year", Value::Int(n)) => tag.set_year(number(*n)),
+        ("year", _) => tag.remove_year(),
+        ("track", Value::Int(n)) => tag.set_track(number(*n)),
+        ("track", _) => tag.remove_track(),
+        ("disc", Value::Int(n)) => tag.set_disk(number(*n)),
+        ("disc", _) => tag.remove_disk(),
+        _ => {}
+    }
+}
+
+/// FNV-1a over the first [`CONTENT_HASH_BYTES`] of the reader.
+fn partial_hash<R: Read>(reader: &mut R) -> std::io::Result<u64> {
+    let mut buf = Vec::new();
+    reader.take(CONTENT_HASH_BYTES).read_to_end(&mut buf)?;
+    Ok(fnv1a(&buf))
+}
+
+/// 64-bit FNV-1a hash: fast, stable across runs, not cryptographic.
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0100_0000_01b3;
+
+    bytes.iter().fold(OFFSET_BASIS, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(PRIME)
+    })
+}
+
+/// Whether `path` has the extension of an [`AudioFormat`] or one of
+/// `extra_extensions`, which are compared ignoring case and a leading dot.
 #[must_use]
-pub fn is_audio_file(path: &Path) -> bool {
-    path.extension()
-        .and_then(|e| e.to_str())
-        .is_some_and(|ext| {
-            matches!(
-                ext.to_lowercase().as_str(),
-                "mp3" | "flac" | "ogg" | "oga" | "opus" | "m4a" | "aac" | "wav" | "aiff" | "aif"
-            )
-        })
+pub fn is_audio_file(path: &Path, extra_extensions: &[String]) -> bool {
+    path.extension().and_then(|e| e.to_str()).is_some_and(|ext| {
+        AudioFormat::from_extension(ext) != AudioFormat::Unknown
+            || extra_extensions
+                .iter()
+                .any(|extra| extra.trim_start_matches('.').eq_ignore_ascii_case(ext))
+    })
+}
+
+/// Whether the tags and audio properties of `format`'s files can be read.
+/// Other files are imported by their file and directory names alone.
+#[must_use]
+pub const fn is_readable(format: AudioFormat) -> bool {
+    !matches!(format, AudioFormat::Wma | AudioFormat::Dsd | AudioFormat::Unknown)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{flac_bytes, wav_bytes};
+
+    #[test]
+    fn test_write_tags_round_trip() {
+        let path = std::env::temp_dir().join(format!("rsbts-write-{}.wav", std::process::id()));
+        std::fs::write(&path, wav_bytes(800)).unwrap();
+
+        let edits: Vec<FieldEdit> = ["title=Iron Man", "genre=Rock", "year=1970", "track=4"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        write_tags(&path, &edits).unwrap();
+        let item = read_tags(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(item.title, "Iron Man");
+        assert_eq!(item.genre.as_deref(), Some("Rock"));
+        assert_eq!(item.year, Some(1970));
+        assert_eq!(item.track, Some(4));
+
+        // Cleared fields are removed from the tag
+        std::fs::write(&path, wav_bytes(800)).unwrap();
+        write_tags(&path, &edits).unwrap();
+        write_tags(&path, &["genre!".parse().unwrap(), "year=".parse().unwrap()]).unwrap();
+        let item = read_tags(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(item.title, "Iron Man");
+        assert_eq!((item.genre, item.year), (None, None));
+    }
+
+    #[test]
+    fn test_sort_names_round_trip() {
+        let edits: Vec<FieldEdit> =
+            ["artist_sort=Chemical Brothers, The", "albumartist_sort=Various Artists"]
+                .iter()
+                .map(|s| s.parse().unwrap())
+                .collect();
+        for (ext, bytes) in [("wav", wav_bytes(800)), ("flac", flac_bytes(44_100, 2, 16))] {
+            let path =
+                std::env::temp_dir().join(format!("rsbts-sort-{}.{ext}", std::process::id()));
+            std::fs::write(&path, bytes).unwrap();
+            let untagged = read_tags(&path).unwrap();
+            write_tags(&path, &edits).unwrap();
+            let item = read_tags(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+
+            assert_eq!((untagged.artist_sort, untagged.albumartist_sort), (None, None));
+            assert_eq!(item.artist_sort.as_deref(), Some("Chemical Brothers, The"), "{ext}");
+            assert_eq!(item.albumartist_sort.as_deref(), Some("Various Artists"), "{ext}");
+        }
+    }
+
+    #[test]
+    fn test_musicbrainz_ids_read_from_tags() {
+        let edits: Vec<FieldEdit> = [
+            "mb_trackid=8f8b8a0a-recording",
+            "mb_albumid=4d9c5f2e-release",
+            "mb_releasegroupid=2b61ac7b-group",
+        ]
+        .iter()
+        .map(|s| s.parse().unwrap())
+        .collect();
+        // As Picard writes them: UFID and TXXX frames, and Vorbis comments
+        for (ext, bytes) in [("wav", wav_bytes(800)), ("flac", flac_bytes(44_100, 2, 16))] {
+            let path =
+                std::env::temp_dir().join(format!("rsbts-mbids-{}.{ext}", std::process::id()));
+            std::fs::write(&path, bytes).unwrap();
+            write_tags(&path, &edits).unwrap();
+            let item = read_tags(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+
+            assert_eq!(item.mb_trackid.as_deref(), Some("8f8b8a0a-recording"), "{ext}");
+            assert_eq!(item.mb_albumid.as_deref(), Some("4d9c5f2e-release"), "{ext}");
+            assert_eq!(item.mb_releasegroupid.as_deref(), Some("2b61ac7b-group"), "{ext}");
+        }
+
+        // A recording id in a TXXX frame rather than a UFID one
+        let path =
+            std::env::temp_dir().join(format!("rsbts-mbids-txxx-{}.wav", std::process::id()));
+        std::fs::write(&path, wav_bytes(800)).unwrap();
+        let mut id3 = lofty::id3::v2::Id3v2Tag::new();
+        id3.insert_user_text("MusicBrainz Track Id".into(), " 8f8b8a0a-recording ".into());
+        lofty::tag::TagExt::save_to_path(&id3, &path, WriteOptions::default()).unwrap();
+        let item = read_tags(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(item.mb_trackid.as_deref(), Some("8f8b8a0a-recording"));
+        assert_eq!(item.mb_albumid, None);
+    }
+
+    #[test]
+    fn test_embed_art_replaces_front_cover() {
+        let path = std::env::temp_dir().join(format!("rsbts-embed-{}.flac", std::process::id()));
+        std::fs::write(&path, flac_bytes(44_100, 2, 16)).unwrap();
+        let art = |path: &Path| {
+            analyze_file(path, AnalyzeOptions::TAGS | AnalyzeOptions::EMBEDDED_ART, &StdFileOps)
+                .unwrap()
+                .embedded_art
+        };
+
+        assert_eq!(art(&path), None);
+        embed_art(&path, b"\xff\xd8\xff first").unwrap();
+        embed_art(&path, b"\x89PNG\r\n\x1a\n second").unwrap();
+        let embedded = art(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(embedded.as_deref(), Some(&b"\x89PNG\r\n\x1a\n second"[..]));
+    }
+
+    #[test]
+    fn test_audio_properties() {
+        let dir = std::env::temp_dir().join(format!("rsbts-props-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let read = |name: &str, bytes: Vec<u8>| {
+            let path = dir.join(name);
+            std::fs::write(&path, bytes).unwrap();
+            let item = read_tags(&path).unwrap();
+            (item.samplerate, item.channels, item.bitdepth)
+        };
+        let cd = read("cd.flac", flac_bytes(44_100, 2, 16));
+        let hires = read("hires.flac", flac_bytes(96_000, 2, 24));
+        let mono = read("mono.wav", wav_bytes(800));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(cd, (Some(44_100), Some(2), Some(16)));
+        assert_eq!(hires, (Some(96_000), Some(2), Some(24)));
+        assert_eq!(mono, (Some(8000), Some(1), Some(16)));
+    }
+
+    #[test]
+    fn test_guess_from_file_name() {
+        // File name, then the title, track and artist it gives
+        let cases: &[(&str, &str, Option<u32>, Option<&str>)] = &[
+            ("07 - Paranoid Android", "Paranoid Android", Some(7), None),
+            ("07. Paranoid Android", "Paranoid Android", Some(7), None),
+            ("07.Paranoid Android", "Paranoid Android", Some(7), None),
+            ("07_Paranoid_Android", "Paranoid Android", Some(7), None),
+            ("07-Paranoid Android", "Paranoid Android", Some(7), None),
+            ("07 Paranoid Android", "Paranoid Android", Some(7), None),
+            ("07_-_Paranoid_Android", "Paranoid Android", Some(7), None),
+            ("7 - Paranoid Android", "Paranoid Android", Some(7), None),
+            ("7. Paranoid Android", "Paranoid Android", Some(7), None),
+            ("  05 - Lucky  ", "Lucky", Some(5), None),
+            ("107 - Hidden Track", "Hidden Track", Some(107), None),
+            ("01 - 1979", "1979", Some(1), None),
+            ("01. 1979", "1979", Some(1), None),
+            ("03 - Track_One (Live)", "Track_One (Live)", Some(3), None),
+            ("01 - Karma Police - Live", "Karma Police - Live", Some(1), None),
+            ("Radiohead - 07 - Paranoid Android", "Paranoid Android", Some(7), Some("Radiohead")),
+            ("Nina Simone - 3 - Sinnerman", "Sinnerman", Some(3), Some("Nina Simone")),
+            ("Sigur Rós - 01 - Svefn-g-englar", "Svefn-g-englar", Some(1), Some("Sigur Rós")),
+            // Not a track number
+            ("Radiohead - Paranoid Android", "Radiohead - Paranoid Android", None, None),
+            ("Radiohead - 0 - Airbag", "Radiohead - 0 - Airbag", None, None),
+            ("00 - Intro", "00 - Intro", None, None),
+            ("1000 - Miles", "1000 - Miles", None, None),
+            ("99 Luftballons", "99 Luftballons", None, None),
+            ("7 Seconds", "7 Seconds", None, None),
+            ("2112", "2112", None, None),
+            ("1.5 Hours", "1.5 Hours", None, None),
+            ("1-800-Suicide", "1-800-Suicide", None, None),
+            ("007", "007", None, None),
+            ("Paranoid Android", "Paranoid Android", None, None),
+            ("Paranoid_Android", "Paranoid_Android", None, None),
+            ("Track 07", "Track 07", None, None),
+        ];
+        for &(name, title, track, artist) in cases {
+            let guess = guess_from_file_name(name);
+            let expected = FileNameGuess {
+                title: title.into(),
+                track,
+                artist: artist.map(Into::into),
+            };
+            assert_eq!(guess, expected, "{name:?}");
+        }
+    }
+
+    #[test]
+    fn test_guess_from_dir_name() {
+        let cases: &[(&str, Option<(&str, &str, Option<i32>)>)] = &[
+            ("Radiohead - OK Computer (1997)", Some(("Radiohead", "OK Computer", Some(1997)))),
+            ("Radiohead - OK Computer [1997]", Some(("Radiohead", "OK Computer", Some(1997)))),
+            ("Radiohead - OK Computer", Some(("Radiohead", "OK Computer", None))),
+            ("Radiohead - 1997 - OK Computer", Some(("Radiohead", "OK Computer", Some(1997)))),
+            ("Blur - 13 (1999)", Some(("Blur", "13", Some(1999)))),
+            ("Prince - 1999", Some(("Prince", "1999", None))),
+            ("Sigur Rós - ( ) (2002)", Some(("Sigur Rós", "( )", Some(2002)))),
+            ("Nirvana - Nevermind (Remastered)", Some(("Nirvana", "Nevermind (Remastered)", None))),
+            ("OK Computer", None),
+            ("Radiohead - ", None),
+            ("incoming", None),
+        ];
+        for &(name, expected) in cases {
+            let expected = expected.map(|(artist, album, year)| DirGuess {
+                artist: artist.into(),
+                album: album.into(),
+                year,
+            });
+            assert_eq!(guess_from_dir_name(name), expected, "{name:?}");
+        }
+    }
+
+    #[test]
+    fn test_untagged_file_named_by_path() {
+        let root = std::env::temp_dir().join(format!("rsbts-names-{}", std::process::id()));
+        let dir = root.join("Radiohead - OK Computer (1997)");
+        std::fs::create_dir_all(&dir).unwrap();
+        let untagged = dir.join("07 - Paranoid Android.wav");
+        std::fs::write(&untagged, wav_bytes(800)).unwrap();
+        let tagged = dir.join("01 - Airbag.wav");
+        std::fs::write(&tagged, wav_bytes(800)).unwrap();
+        let edits: Vec<FieldEdit> = ["title=Let Down", "track=5", "year=1998", "album=Other"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        write_tags(&tagged, &edits).unwrap();
+
+        let untagged = read_tags(&untagged).unwrap();
+        let tagged = read_tags(&tagged).unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(
+            (untagged.title.as_str(), untagged.track, untagged.year),
+            ("Paranoid Android", Some(7), Some(1997))
+        );
+        assert_eq!(
+            (untagged.artist.as_str(), untagged.album.as_str()),
+            ("Radiohead", "OK Computer")
+        );
+        // Tag values always win; only the missing artist comes from the path
+        assert_eq!(
+            (tagged.title.as_str(), tagged.track, tagged.year),
+            ("Let Down", Some(5), Some(1998))
+        );
+        assert_eq!((tagged.artist.as_str(), tagged.album.as_str()), ("Radiohead", "Other"));
+    }
+
+    #[test]
+    fn test_unreadable_formats_named_by_path() {
+        let dir = std::env::temp_dir().join(format!("rsbts-wma-{}", std::process::id()));
+        let dir = dir.join("Can - Tago Mago (1971)");
+        std::fs::create_dir_all(&dir).unwrap();
+        let wma = dir.join("02 - Mushroom.WMA");
+        std::fs::write(&wma, b"not something lofty reads").unwrap();
+        let shn = dir.join("03 - Oh Yeah.shn");
+        std::fs::write(&shn, b"nor this").unwrap();
+
+        let audio = (is_audio_file(&wma, &[]), is_audio_file(&shn, &[]));
+        let extra = is_audio_file(&shn, &[".SHN".into()]);
+        let item = read_tags(&wma).unwrap();
+        std::fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+
+        assert_eq!(audio, (true, false));
+        assert!(extra);
+        assert_eq!(item.format, AudioFormat::Wma);
+        assert!(!is_readable(item.format));
+        assert_eq!((item.title.as_str(), item.track), ("Mushroom", Some(2)));
+        assert_eq!((item.artist.as_str(), item.album.as_str()), ("Can", "Tago Mago"));
+    }
 }
@@ -0,0 +1,139 @@
+//! Fixtures shared by unit tests
+
+use std::path::PathBuf;
+
+use chrono::Utc;
+
+use crate::genres::Genres;
+use crate::import::{
+    Action, ConflictPolicy, ImportConfig, MergePolicy, QuietFallback, ReleasePreferences,
+    ScanOptions, DEFAULT_CONCURRENCY, DEFAULT_MIN_MATCH_SCORE,
+};
+use crate::musicbrainz::ClientSettings;
+use crate::pathformat::PathFormats;
+use crate::{Album, AudioFormat, Item};
+
+/// Minimal 16-bit mono PCM WAV with `samples` frames of silence.
+pub fn wav_bytes(samples: u32) -> Vec<u8> {
+    let data_len = samples * 2;
+    let mut buf = Vec::new();
+    buf.extend_from_slice(b"RIFF");
+    buf.extend_from_slice(&(36 + data_len).to_le_bytes());
+    buf.extend_from_slice(b"WAVEfmt ");
+    buf.extend_from_slice(&16u32.to_le_bytes());
+    buf.extend_from_slice(&1u16.to_le_bytes());
+    buf.extend_from_slice(&1u16.to_le_bytes());
+    buf.extend_from_slice(&8000u32.to_le_bytes());
+    buf.extend_from_slice(&16000u32.to_le_bytes());
+    buf.extend_from_slice(&2u16.to_le_bytes());
+    buf.extend_from_slice(&16u16.to_le_bytes());
+    buf.extend_from_slice(b"data");
+    buf.extend_from_slice(&data_len.to_le_bytes());
+    buf.resize(buf.len() + data_len as usize, 0);
+    buf
+}
+
+/// FLAC stream with only a STREAMINFO block describing one second of audio.
+pub fn flac_bytes(sample_rate: u32, channels: u8, bits_per_sample: u8) -> Vec<u8> {
+    let mut buf = b"fLaC".to_vec();
+    // Last metadata block, type 0 (STREAMINFO), 34 bytes long
+    buf.extend_from_slice(&[0x80, 0, 0, 34]);
+    buf.extend_from_slice(&4096u16.to_be_bytes());
+    buf.extend_from_slice(&4096u16.to_be_bytes());
+    buf.extend_from_slice(&[0; 6]);
+    let packed = u64::from(sample_rate) << 44
+        | u64::from(channels - 1) << 41
+        | u64::from(bits_per_sample - 1) << 36
+        | u64::from(sample_rate);
+    buf.extend_from_slice(&packed.to_be_bytes());
+    buf.extend_from_slice(&[0; 16]);
+    buf
+}
+
+/// An item with nothing but what every item has, for tests to fill in the
+/// fields they care about with struct update syntax.
+pub fn item() -> Item {
+    Item {
+        id: None,
+        album_id: None,
+        path: PathBuf::new(),
+        title: String::new(),
+        artist: String::new(),
+        album: String::new(),
+        albumartist: None,
+        artist_sort: None,
+        albumartist_sort: None,
+        genre: None,
+        year: None,
+        track: None,
+        disc: None,
+        format: AudioFormat::Mp3,
+        bitrate: 0,
+        length: 0.0,
+        samplerate: None,
+        channels: None,
+        bitdepth: None,
+        mb_trackid: None,
+        mb_albumid: None,
+        mb_releasegroupid: None,
+        added: Utc::now(),
+        mtime: Utc::now(),
+        size: None,
+        source_path: None,
+        import_run: None,
+        rg_track_gain: None,
+        rg_track_peak: None,
+        rg_album_gain: None,
+        rg_album_peak: None,
+        rating: None,
+        play_count: 0,
+        last_played: None,
+    }
+}
+
+/// An album with nothing but what every album has, as [`item`] is.
+pub fn album() -> Album {
+    Album {
+        id: None,
+        album: String::new(),
+        albumartist: String::new(),
+        year: None,
+        artpath: None,
+        mb_albumid: None,
+        mb_releasegroupid: None,
+        added: Utc::now(),
+        source_path: None,
+        import_run: None,
+        disambiguation: None,
+    }
+}
+
+/// Importing by moving files into `library_dir` as `$album/$track $title`,
+/// with nothing fetched and every policy at its default.
+pub fn import_config(library_dir: PathBuf) -> ImportConfig {
+    ImportConfig {
+        action: Action::Move,
+        fetch_art: false,
+        art_filenames: Vec::new(),
+        prefer_caa_art: false,
+        path_formats: PathFormats::new("$album/$track $title"),
+        library_dir,
+        min_match_score: DEFAULT_MIN_MATCH_SCORE,
+        preferences: ReleasePreferences::default(),
+        musicbrainz: ClientSettings::default(),
+        acoustid_api_key: None,
+        concurrency: DEFAULT_CONCURRENCY,
+        only: None,
+        merge_into_existing: MergePolicy::Never,
+        on_conflict: ConflictPolicy::Rename,
+        quiet_fallback: QuietFallback::AsIs,
+        clutter: Vec::new(),
+        keep_empty_dirs: false,
+        inside_library: false,
+        extra_extensions: Vec::new(),
+        genres: Genres::default(),
+        scan: ScanOptions::default(),
+        confirm_merge: None,
+        show_changes: None,
+    }
+}
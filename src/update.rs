@@ -0,0 +1,279 @@
+//! Re-reading library files that changed on disk
+//!
+//! `update` stats each item's file and reads the tags of those that changed
+//! since they were last read (see [`file_states`]). An item matched to
+//! `MusicBrainz` may hold values the importer corrected while its file still
+//! has the old tags; when such a file is read again, [`Corrected`] decides
+//! which side wins for the fields that differ.
+
+use std::path::PathBuf;
+
+use log::warn;
+
+use crate::check::{file_states, FileState};
+use crate::db::Database;
+use crate::fields::{item_value, FieldChange, FieldDiff, FieldEdit, ITEM_FIELDS};
+use crate::tags::{read_tags, write_tags};
+use crate::{Error, Item, Result};
+
+/// Columns `update` takes from the file, besides its mtime and size.
+pub const REREAD_FIELDS: &[&str] = &[
+    "title",
+    "artist",
+    "album",
+    "albumartist",
+    "artist_sort",
+    "albumartist_sort",
+    "genre",
+    "year",
+    "track",
+    "disc",
+    "bitrate",
+    "length",
+    "samplerate",
+    "channels",
+    "bitdepth",
+];
+
+/// The fields of [`REREAD_FIELDS`] the importer sets from `MusicBrainz`.
+const CORRECTED_FIELDS: &[&str] = &[
+    "title",
+    "artist",
+    "album",
+    "albumartist",
+    "artist_sort",
+    "albumartist_sort",
+    "genre",
+    "year",
+    "track",
+    "disc",
+];
+
+/// What to do when a re-read file's tags differ from values the importer
+/// corrected, i.e. for items with a `MusicBrainz` track id.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Corrected {
+    /// Keep the library's values and report the difference.
+    #[default]
+    Keep,
+    /// Take the file's values, as for items never matched.
+    PreferFile,
+    /// Write the library's values back into the file's tags.
+    Write,
+}
+
+/// What [`update`] did.
+#[derive(Debug, Default)]
+pub struct UpdateReport {
+    /// Items whose files were stat'ed.
+    pub checked: usize,
+    /// Items whose files were read again.
+    pub reread: usize,
+    /// Items whose stored values changed.
+    pub changed: usize,
+    /// Items whose files are gone; they are left in the library.
+    pub missing: Vec<Item>,
+    /// Items that kept corrected values their files' tags differ from, with
+    /// the differences (the library's value first).
+    pub kept: Vec<(PathBuf, Vec<FieldDiff>)>,
+    /// Items whose corrected values were written back into their files.
+    pub written: Vec<PathBuf>,
+    /// Files that could not be read, with the reason.
+    pub failures: Vec<(PathBuf, Error)>,
+}
+
+/// Read the files of `items` that changed since they were last read, or all
+/// of them with `force`, and store their tags and audio properties.
+/// Differences in corrected fields are resolved as `corrected` says.
+///
+/// # Errors
+/// Returns an error if storing an item fails. Files that can't be read are
+/// reported instead; a file that can't be written keeps the library's values.
+pub fn update(
+    db: &Database,
+    items: Vec<Item>,
+    force: bool,
+    corrected: Corrected,
+) -> Result<UpdateReport> {
+    let states = file_states(&items);
+    let mut report = UpdateReport {
+        checked: items.len(),
+        ..UpdateReport::default()
+    };
+    for (item, state) in items.into_iter().zip(states) {
+        match state {
+            FileState::Missing => {
+                report.missing.push(item);
+                continue;
+            }
+            FileState::Unchanged if !force => continue,
+            FileState::Unchanged | FileState::Changed => {}
+        }
+        let Some(id) = item.id else {
+            continue;
+        };
+        report.reread += 1;
+        let updated = match read_tags(&item.path) {
+            Ok(updated) => updated,
+            Err(e) => {
+                report.failures.push((item.path, e));
+                continue;
+            }
+        };
+
+        let diffs = item.diff(&updated, REREAD_FIELDS);
+        let conflicts: Vec<FieldDiff> = if item.mb_trackid.is_some() {
+            diffs
+                .iter()
+                .filter(|d| CORRECTED_FIELDS.contains(&d.field))
+                .cloned()
+                .collect()
+        } else {
+            Vec::new()
+        };
+        if diffs.len() > conflicts.len()
+            || (corrected == Corrected::PreferFile && !conflicts.is_empty())
+        {
+            report.changed += 1;
+        }
+        if conflicts.is_empty() || corrected == Corrected::PreferFile {
+            db.update_item(id, &updated)?;
+            continue;
+        }
+
+        // The edits turning the file's values back into the library's
+        let keep: Vec<FieldEdit> = FieldEdit::diff(ITEM_FIELDS, &updated, &item, item_value)
+            .into_iter()
+            .filter(|edit| conflicts.iter().any(|d| d.field == edit.field()))
+            .collect();
+        if corrected == Corrected::Write {
+            match write_tags(&item.path, &keep).and_then(|()| read_tags(&item.path)) {
+                Ok(rewritten) => {
+                    db.update_item(id, &rewritten)?;
+                    report.written.push(item.path);
+                    continue;
+                }
+                Err(e) => warn!("Could not write tags to {}: {e}", item.path.display()),
+            }
+        }
+        db.update_item(id, &updated)?;
+        let keep: Vec<FieldChange> = keep.into_iter().map(FieldChange::Set).collect();
+        db.modify_item(id, &keep)?;
+        report.kept.push((item.path, conflicts));
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fields::Value;
+    use crate::testutil::wav_bytes;
+
+    /// A library with one item whose file is tagged "paranoid" by "black
+    /// sabbath", stored as corrected from `MusicBrainz` unless `matched` is
+    /// false. The file is newer than what was stored, as after retagging.
+    fn library(name: &str, matched: bool) -> (Database, PathBuf) {
+        let path = std::env::temp_dir()
+            .join(format!("rsbts-update-{name}-{}.wav", std::process::id()));
+        std::fs::write(&path, wav_bytes(800)).unwrap();
+        let edits: Vec<FieldEdit> = ["title=paranoid", "artist=black sabbath", "year=1970"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        write_tags(&path, &edits).unwrap();
+
+        let db = Database::open_in_memory().unwrap();
+        db.migrate().unwrap();
+        let mut item = read_tags(&path).unwrap();
+        item.title = "Paranoid".into();
+        item.artist = "Black Sabbath".into();
+        item.mtime -= chrono::Duration::seconds(60);
+        if matched {
+            item.mb_trackid = Some("f64bd9ae".into());
+        }
+        db.insert_item(&item).unwrap();
+        (db, path)
+    }
+
+    fn stored(db: &Database) -> Item {
+        db.query_items(None).unwrap().remove(0)
+    }
+
+    #[test]
+    fn test_item_diff() {
+        let (db, path) = library("diff", true);
+        let file = read_tags(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let diffs = stored(&db).diff(&file, REREAD_FIELDS);
+        let fields: Vec<&str> = diffs.iter().map(|d| d.field).collect();
+        assert_eq!(fields, ["title", "artist"]);
+        assert_eq!(diffs[0].ours, Value::Text("Paranoid".into()));
+        assert_eq!(diffs[0].theirs, Value::Text("paranoid".into()));
+        assert!(file.diff(&file, REREAD_FIELDS).is_empty());
+    }
+
+    #[test]
+    fn test_keep_corrected_values() {
+        let (db, path) = library("keep", true);
+        let report = update(&db, db.query_items(None).unwrap(), false, Corrected::Keep).unwrap();
+        let file = read_tags(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!((report.checked, report.reread, report.changed), (1, 1, 0));
+        let (kept_path, diffs) = &report.kept[0];
+        assert_eq!(kept_path, &path);
+        assert_eq!(diffs.len(), 2);
+        let item = stored(&db);
+        assert_eq!((item.title.as_str(), item.artist.as_str()), ("Paranoid", "Black Sabbath"));
+        // The file is left alone, and its new mtime stored so it isn't read again
+        assert_eq!(file.title, "paranoid");
+        assert_eq!(item.mtime, file.mtime);
+    }
+
+    #[test]
+    fn test_prefer_file_values() {
+        let (db, path) = library("prefer", true);
+        let items = db.query_items(None).unwrap();
+        let report = update(&db, items, false, Corrected::PreferFile).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(report.changed, 1);
+        assert!(report.kept.is_empty());
+        let item = stored(&db);
+        assert_eq!((item.title.as_str(), item.artist.as_str()), ("paranoid", "black sabbath"));
+        assert_eq!(item.mb_trackid.as_deref(), Some("f64bd9ae"));
+    }
+
+    #[test]
+    fn test_write_corrected_values_to_file() {
+        let (db, path) = library("write", true);
+        let report = update(&db, db.query_items(None).unwrap(), false, Corrected::Write).unwrap();
+        let file = read_tags(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(report.written, [path]);
+        assert_eq!((report.changed, report.kept.len()), (0, 0));
+        assert_eq!((file.title.as_str(), file.artist.as_str()), ("Paranoid", "Black Sabbath"));
+        assert_eq!(file.year, Some(1970));
+        let item = stored(&db);
+        assert_eq!(item.title, "Paranoid");
+        assert_eq!((item.mtime, item.size), (file.mtime, file.size));
+    }
+
+    #[test]
+    fn test_unmatched_items_take_file_values() {
+        let (db, path) = library("unmatched", false);
+        let report = update(&db, db.query_items(None).unwrap(), false, Corrected::Keep).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(report.changed, 1);
+        assert!(report.kept.is_empty());
+        assert_eq!(stored(&db).title, "paranoid");
+
+        // Unchanged files aren't read again unless forced; gone ones are reported
+        let report = update(&db, db.query_items(None).unwrap(), false, Corrected::Keep).unwrap();
+        assert_eq!((report.reread, report.missing.len()), (0, 1));
+    }
+}
@@ -0,0 +1,342 @@
+//! Importing music as it arrives in a directory
+//!
+//! Each top-level entry of the watched directory (an album directory, or a
+//! file on its own) is imported once none of the audio files under it have
+//! appeared or changed size for a quiet period, so downloads in progress and
+//! albums arriving file by file are left alone until they are complete.
+//! Files are only ever imported once: those recorded as an item's source
+//! path are skipped, as are any this session already handled.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use notify::{RecursiveMode, Watcher};
+use tokio::sync::{mpsc, Notify};
+
+use crate::db::Database;
+use crate::hooks::Hooks;
+use crate::import::{audio_files, ImportConfig, Importer};
+use crate::{Error, Result};
+
+/// Default time files must stay unchanged before they are imported.
+pub const DEFAULT_QUIET_SECONDS: u64 = 30;
+
+/// How often a directory that is still settling is looked at again.
+const TICK: Duration = Duration::from_secs(1);
+
+/// Audio files under a directory entry, with their sizes.
+type Files = BTreeMap<PathBuf, u64>;
+
+/// Tracks directory entries until their files stop changing.
+#[derive(Debug)]
+pub struct Settler {
+    quiet: Duration,
+    pending: HashMap<PathBuf, (Files, Instant)>,
+}
+
+impl Settler {
+    #[must_use]
+    pub fn new(quiet: Duration) -> Self {
+        Self {
+            quiet,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Record the files seen at `now`. An entry whose files differ from last
+    /// time starts its quiet period over; entries that are gone are dropped.
+    pub fn observe(&mut self, snapshot: HashMap<PathBuf, Files>, now: Instant) {
+        self.pending.retain(|entry, _| snapshot.contains_key(entry));
+        for (entry, files) in snapshot {
+            match self.pending.get_mut(&entry) {
+                Some((seen, _)) if *seen == files => {}
+                Some(pending) => *pending = (files, now),
+                None => {
+                    self.pending.insert(entry, (files, now));
+                }
+            }
+        }
+    }
+
+    /// Entries that have been quiet long enough, with their files, in path
+    /// order. They are no longer tracked.
+    pub fn take_settled(&mut self, now: Instant) -> Vec<(PathBuf, Vec<PathBuf>)> {
+        let mut settled: Vec<PathBuf> = self
+            .pending
+            .iter()
+            .filter(|(_, (_, changed))| now.duration_since(*changed) >= self.quiet)
+            .map(|(entry, _)| entry.clone())
+            .collect();
+        settled.sort();
+        settled
+            .into_iter()
+            .filter_map(|entry| {
+                let (files, _) = self.pending.remove(&entry)?;
+                Some((entry, files.into_keys().collect()))
+            })
+            .collect()
+    }
+
+    /// Whether anything is waiting to settle.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+/// Audio files under `dir` (see [`audio_files`]) that `skip` doesn't
+/// exclude, grouped by the top-level entry of `dir` they are in.
+pub fn snapshot(
+    dir: &Path,
+    extra_extensions: &[String],
+    skip: impl Fn(&Path) -> bool,
+) -> HashMap<PathBuf, Files> {
+    let mut entries: HashMap<PathBuf, Files> = HashMap::new();
+    for path in audio_files(dir, extra_extensions) {
+        if skip(&path) {
+            continue;
+        }
+        // Files still being written may vanish or be unreadable; next time
+        let Ok(metadata) = std::fs::metadata(&path) else {
+            continue;
+        };
+        let Some(top) = path.strip_prefix(dir).ok().and_then(|p| p.components().next()) else {
+            continue;
+        };
+        entries
+            .entry(dir.join(top))
+            .or_default()
+            .insert(path, metadata.len());
+    }
+    entries
+}
+
+/// Import what arrives in `dir`, each settled entry as a run of its own
+/// with the settings `settings` returns, running the import `hooks` after
+/// each. With `once`, import what is there now and return; otherwise watch
+/// until Ctrl-C, finishing the entry being imported first.
+///
+/// # Errors
+/// Returns an error if `dir` can't be watched or is inside the library
+/// (see [`ImportConfig::check_source`]). Failed imports are reported and the
+/// entry skipped.
+// rusqlite::Connection is not Sync, so futures holding &Database aren't Send
+#[allow(clippy::future_not_send)]
+pub async fn watch(
+    db: &Database,
+    hooks: &Hooks,
+    dir: &Path,
+    quiet: Duration,
+    once: bool,
+    settings: impl Fn() -> ImportConfig,
+) -> Result<()> {
+    let dir = std::fs::canonicalize(dir)
+        .map_err(|e| Error::Config(format!("Can't watch {}: {e}", dir.display())))?;
+    settings().check_source(&dir)?;
+    let mut done: HashSet<PathBuf> = HashSet::new();
+    let extra_extensions = settings().extra_extensions;
+
+    if once {
+        let snapshot = snapshot(&dir, &extra_extensions, |path| already_imported(db, path));
+        let mut entries: Vec<_> = snapshot.into_iter().collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        for (entry, files) in entries {
+            let files = files.into_keys().collect();
+            import_entry(db, hooks, settings(), &entry, files, &mut done).await;
+        }
+        return Ok(());
+    }
+
+    let (events_tx, mut events) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if event.is_ok() {
+            let _ = events_tx.send(());
+        }
+    })
+    .map_err(|e| Error::Config(format!("Can't watch {}: {e}", dir.display())))?;
+    watcher
+        .watch(&dir, RecursiveMode::Recursive)
+        .map_err(|e| Error::Config(format!("Can't watch {}: {e}", dir.display())))?;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let stopped = Arc::new(Notify::new());
+    {
+        let (stop, stopped) = (Arc::clone(&stop), Arc::clone(&stopped));
+        tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                eprintln!("Stopping after the current import");
+                stop.store(true, Ordering::SeqCst);
+                stopped.notify_one();
+            }
+        });
+    }
+
+    log(&format!("Watching {}", dir.display()));
+    let mut settler = Settler::new(quiet);
+    while !stop.load(Ordering::SeqCst) {
+        let snapshot = snapshot(&dir, &extra_extensions, |path| {
+            done.contains(path) || already_imported(db, path)
+        });
+        settler.observe(snapshot, Instant::now());
+        for (entry, files) in settler.take_settled(Instant::now()) {
+            if stop.load(Ordering::SeqCst) {
+                break;
+            }
+            import_entry(db, hooks, settings(), &entry, files, &mut done).await;
+        }
+
+        // Sleep until something changes, checking back while entries settle
+        tokio::select! {
+            _ = events.recv() => {}
+            () = tokio::time::sleep(TICK), if !settler.is_empty() => {}
+            () = stopped.notified() => {}
+        }
+        // A copy in progress sends a burst of events; one look covers them
+        while events.try_recv().is_ok() {}
+    }
+    Ok(())
+}
+
+// rusqlite::Connection is not Sync, so futures holding &Database aren't Send
+#[allow(clippy::future_not_send)]
+async fn import_entry(
+    db: &Database,
+    hooks: &Hooks,
+    settings: ImportConfig,
+    entry: &Path,
+    files: Vec<PathBuf>,
+    done: &mut HashSet<PathBuf>,
+) {
+    log(&format!("Importing {} ({} files)", entry.display(), files.len()));
+    done.extend(files.iter().cloned());
+    let result = async {
+        let importer = Importer::new(db, settings)?.with_hooks(hooks.clone());
+        let report = importer.import_files(files).await?;
+        Ok::<_, Error>((importer.run_id().to_string(), report))
+    }
+    .await;
+    match result {
+        Ok((run, report)) => {
+            log(&format!("Imported {} as run {run}", entry.display()));
+            for (path, error) in &report.failures {
+                log(&format!("  Could not read {}: {error}", path.display()));
+            }
+            for collision in &report.collisions {
+                log(&format!("  {collision}"));
+            }
+            for (album, source) in &report.unmatched {
+                log(&format!("  Skipped {album} ({}): no match", source.display()));
+            }
+            for (album, error) in &report.failed_albums {
+                log(&format!("  Could not import {album}: {error}"));
+            }
+            if let Err(e) = hooks.imported(db, &run) {
+                log(&format!("  Could not run hooks: {e}"));
+            }
+        }
+        Err(e) => log(&format!("Failed to import {}: {e}", entry.display())),
+    }
+}
+
+/// Whether an item was imported from `path`. A failed lookup counts as no,
+/// so the file is tried and the import itself reports the problem.
+fn already_imported(db: &Database, path: &Path) -> bool {
+    db.imported_from(path).unwrap_or(false)
+}
+
+fn log(message: &str) {
+    println!("[{}] {message}", chrono::Local::now().format("%Y-%m-%d %H:%M:%S"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    fn files(sizes: &[(&str, u64)]) -> Files {
+        sizes.iter().map(|(p, s)| (PathBuf::from(p), *s)).collect()
+    }
+
+    #[test]
+    fn test_entries_settle_after_quiet_period() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut settler = Settler::new(Duration::from_secs(30));
+
+        let album = PathBuf::from("/in/Paranoid");
+        let single = PathBuf::from("/in/single.mp3");
+        settler.observe(
+            HashMap::from([
+                (album.clone(), files(&[("/in/Paranoid/1.flac", 100)])),
+                (single.clone(), files(&[("/in/single.mp3", 5)])),
+            ]),
+            at(0),
+        );
+        // The album's first file is still growing and a second one arrives
+        settler.observe(
+            HashMap::from([
+                (
+                    album.clone(),
+                    files(&[("/in/Paranoid/1.flac", 900), ("/in/Paranoid/2.flac", 10)]),
+                ),
+                (single.clone(), files(&[("/in/single.mp3", 5)])),
+            ]),
+            at(20),
+        );
+
+        let settled = settler.take_settled(at(30));
+        assert_eq!(settled, [(single, vec![PathBuf::from("/in/single.mp3")])]);
+        assert!(settler.take_settled(at(49)).is_empty());
+        let settled = settler.take_settled(at(50));
+        assert_eq!(settled.len(), 1);
+        assert_eq!(settled[0].0, album);
+        assert_eq!(settled[0].1.len(), 2);
+        assert!(settler.is_empty());
+    }
+
+    #[test]
+    fn test_snapshot_groups_audio_by_top_level_entry() {
+        let dir = std::env::temp_dir().join(format!("rsbts-watch-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("Paranoid/CD2")).unwrap();
+        for (name, bytes) in [
+            ("Paranoid/1.flac", 3),
+            ("Paranoid/CD2/1.flac", 4),
+            ("Paranoid/cover.jpg", 5),
+            ("single.mp3", 6),
+            ("notes.txt", 7),
+        ] {
+            std::fs::write(dir.join(name), vec![0; bytes]).unwrap();
+        }
+
+        let all = snapshot(&dir, &[], |_| false);
+        let skipped = snapshot(&dir, &[], |path| path.ends_with("single.mp3"));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(all.len(), 2);
+        let album = &all[&dir.join("Paranoid")];
+        assert_eq!(album.values().copied().collect::<Vec<_>>(), [3, 4]);
+        assert_eq!(all[&dir.join("single.mp3")].len(), 1);
+        assert_eq!(skipped.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_refuses_watching_inside_library() {
+        let root = std::env::temp_dir().join(format!("rsbts-watch-lib-{}", std::process::id()));
+        let inside = root.join("library/incoming");
+        std::fs::create_dir_all(&inside).unwrap();
+        let db = Database::open_in_memory().unwrap();
+        db.migrate().unwrap();
+        let hooks = Hooks::default();
+        let settings = || testutil::import_config(root.join("library"));
+        let quiet = Duration::ZERO;
+        let refused = watch(&db, &hooks, &inside, quiet, true, settings).await.is_err();
+        let outside = watch(&db, &hooks, &root, quiet, true, settings).await.is_ok();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert!(refused);
+        assert!(outside);
+    }
+}